@@ -0,0 +1,123 @@
+//! Scanner hot paths: trial decryption of a transaction with every merchant's keys, and
+//! matching decrypted outputs to pending invoices. Run with `cargo bench --bench scanner`;
+//! criterion compares each run with the last, so run it on the base branch first.
+//!
+//! The crate is a binary, so the modules under test are compiled in here by path, as the
+//! integration tests do with the fixtures. Cargo builds benches with `cfg(test)`, so the
+//! imports and helpers of their unit tests are compiled too; the `scanner` and `invoices`
+//! re-exports give them the paths they use in the server.
+
+#![allow(dead_code, unused_imports)]
+
+#[path = "../src/scanner/decrypt.rs"]
+mod decrypt;
+#[path = "../src/scanner/fixtures.rs"]
+mod fixtures;
+#[path = "../src/invoices/matching.rs"]
+mod matching;
+
+mod scanner {
+    pub(crate) use super::{decrypt, fixtures};
+}
+
+mod invoices {
+    pub(crate) use super::{matching, Invoice};
+
+    pub fn test_invoice() -> Invoice {
+        Invoice { memo_code: String::new(), orchard_receiver_hex: None, transparent_receiver_hex: None }
+    }
+}
+
+/// The fields of the server's `Invoice` row that matching reads.
+pub struct Invoice {
+    pub memo_code: String,
+    pub orchard_receiver_hex: Option<String>,
+    pub transparent_receiver_hex: Option<String>,
+}
+
+impl Invoice {
+    pub fn is_settlement(&self) -> bool {
+        self.memo_code.starts_with("SETTLE-")
+    }
+}
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use fixtures::Output;
+use matching::PendingIndex;
+
+/// Merchants the scanner holds keys for: every mempool transaction is trial-decrypted
+/// with each of them.
+const MERCHANT_COUNTS: [usize; 3] = [1, 10, 50];
+/// Pending invoices in the scan cycle's index.
+const PENDING_COUNTS: [usize; 3] = [100, 1_000, 10_000];
+
+fn bench_decrypt(c: &mut Criterion) {
+    let keys: Vec<decrypt::CachedKeys> = (1..=*MERCHANT_COUNTS.iter().max().unwrap() as u8)
+        .map(|seed| decrypt::prepare_keys(&fixtures::test_ufvk(seed)).unwrap())
+        .collect();
+    // A two-output payment to merchant 1, and a transaction for a wallet nobody watches.
+    let paying = hex::encode(fixtures::transaction(
+        &[Output::to_wallet(1, 3, 50_000_000, "CP-0000002A"), Output::to_wallet(200, 0, 1_000, "")],
+        1,
+    ));
+    let foreign = hex::encode(fixtures::transaction(&[Output::to_wallet(200, 0, 50_000_000, "")], 2));
+
+    let mut group = c.benchmark_group("try_decrypt_with_keys");
+    group.bench_function("hit", |b| {
+        b.iter(|| decrypt::try_decrypt_with_keys(black_box(&paying), &keys[0]).unwrap())
+    });
+    group.bench_function("miss", |b| {
+        b.iter(|| decrypt::try_decrypt_with_keys(black_box(&foreign), &keys[0]).unwrap())
+    });
+    for merchants in MERCHANT_COUNTS {
+        group.bench_with_input(BenchmarkId::new("tx_all_merchants", merchants), &merchants, |b, &n| {
+            b.iter(|| {
+                keys[..n]
+                    .iter()
+                    .map(|k| decrypt::try_decrypt_with_keys(black_box(&paying), k).unwrap().len())
+                    .sum::<usize>()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn pending(count: usize) -> Vec<Invoice> {
+    (0..count)
+        .map(|i| Invoice {
+            memo_code: format!("CP-{:08X}", i),
+            orchard_receiver_hex: Some(format!("{:086x}", i)),
+            transparent_receiver_hex: (i % 10 == 0).then(|| format!("{:040x}", i)),
+        })
+        .collect()
+}
+
+fn bench_matching(c: &mut Criterion) {
+    let mut group = c.benchmark_group("matching");
+    for count in PENDING_COUNTS {
+        let invoices = pending(count);
+        let last = count - 1;
+        let receiver = format!("{:086x}", last);
+        let memo = format!("Order CP-{:08X}, thanks", last);
+
+        group.bench_with_input(BenchmarkId::new("index_build", count), &invoices, |b, invoices| {
+            b.iter(|| PendingIndex::new(black_box(invoices)))
+        });
+        let index = PendingIndex::new(&invoices);
+        group.bench_with_input(BenchmarkId::new("index_by_address", count), &receiver, |b, receiver| {
+            b.iter(|| index.by_address(black_box(receiver)).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("index_by_memo", count), &memo, |b, memo| {
+            b.iter(|| index.by_memo(black_box(memo)).unwrap())
+        });
+        // The linear search the index replaced, as a baseline.
+        group.bench_with_input(BenchmarkId::new("linear", count), &receiver, |b, receiver| {
+            b.iter(|| matching::find_matching_invoice(black_box(&invoices), receiver, "").unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_decrypt, bench_matching);
+criterion_main!(benches);
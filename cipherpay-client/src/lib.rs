@@ -0,0 +1,257 @@
+//! Rust client for the CipherPay API.
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), cipherpay_client::Error> {
+//! use cipherpay_client::{Client, CreateInvoice};
+//!
+//! let client = Client::new("https://api.cipherpay.app").with_api_key("cpay_sk_...");
+//! let invoice = client.create_invoice(&CreateInvoice::new(65.0).product_name("T-Shirt")).await?;
+//!
+//! let mut stream = client.subscribe(&invoice.invoice_id, None).await?;
+//! while let Some(event) = stream.next_event().await {
+//!     if let Some(update) = event?.status() {
+//!         println!("{:?}", update.status);
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Webhooks are verified with [`webhook::verify`].
+
+mod sse;
+mod types;
+pub mod webhook;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+pub use sse::{InvoiceStream, StreamEvent};
+pub use types::*;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    /// The API answered with a non-2xx status. `field` is set for validation errors.
+    #[error("API error {status}: {message}")]
+    Api {
+        status: u16,
+        message: String,
+        field: Option<String>,
+    },
+    #[error("invalid response: {0}")]
+    Decode(#[from] serde_json::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    api_key: Option<String>,
+}
+
+impl Client {
+    /// `base_url` is the server root, e.g. `http://localhost:3080` (without `/api`).
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            api_key: None,
+        }
+    }
+
+    /// Authenticate merchant endpoints with an API key (`cpay_sk_...`).
+    pub fn with_api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    /// Use a preconfigured HTTP client (timeouts, proxies, TLS settings).
+    pub fn with_http_client(mut self, http: reqwest::Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// Paths are pinned to `/api/v1`, the version these types describe.
+    fn url(&self, path: &str) -> String {
+        format!("{}/api/v1{}", self.base_url, path)
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> reqwest::RequestBuilder {
+        let req = self.http.request(method, self.url(path));
+        match &self.api_key {
+            Some(key) => req.bearer_auth(key),
+            None => req,
+        }
+    }
+
+    async fn send<T: DeserializeOwned>(&self, req: reqwest::RequestBuilder) -> Result<T> {
+        let resp = check(req.send().await?).await?;
+        Ok(serde_json::from_slice(&resp.bytes().await?)?)
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        self.send(self.request(reqwest::Method::GET, path)).await
+    }
+
+    async fn post<B: Serialize + ?Sized, T: DeserializeOwned>(&self, path: &str, body: &B) -> Result<T> {
+        self.send(self.request(reqwest::Method::POST, path).json(body)).await
+    }
+
+    /// `POST /api/v1/merchants`. The returned credentials are shown only once.
+    pub async fn register_merchant(&self, req: &CreateMerchant) -> Result<MerchantCredentials> {
+        self.post("/merchants", req).await
+    }
+
+    /// `POST /api/v1/invoices` (API key).
+    pub async fn create_invoice(&self, req: &CreateInvoice) -> Result<CreatedInvoice> {
+        self.post("/invoices", req).await
+    }
+
+    /// `GET /api/v1/products/{id}/public`, keeping only the checkout token.
+    pub async fn checkout_token(&self, product_id: &str) -> Result<CheckoutToken> {
+        self.get(&format!("/products/{}/public", product_id)).await
+    }
+
+    /// `POST /api/v1/checkout`: buyer-side invoice for a product, priced by the server.
+    /// Needs a `checkout_token` from [`Client::checkout_token`].
+    /// Servers that require proof of work refuse it unless a solved [`Client::pow_challenge`]
+    /// is attached with [`Client::checkout_with_pow`].
+    pub async fn checkout(&self, req: &Checkout) -> Result<CreatedInvoice> {
+        self.post("/checkout", req).await
+    }
+
+    /// `GET /api/v1/pow/challenge`.
+    pub async fn pow_challenge(&self) -> Result<PowChallenge> {
+        self.get("/pow/challenge").await
+    }
+
+    /// [`Client::checkout`] with a solution from [`PowChallenge::solve`].
+    pub async fn checkout_with_pow(&self, req: &Checkout, solution: &str) -> Result<CreatedInvoice> {
+        self.send(
+            self.request(reqwest::Method::POST, "/checkout")
+                .header("X-CipherPay-PoW", solution)
+                .json(req),
+        )
+        .await
+    }
+
+    /// `GET /api/v1/invoices/{id}` by ID or memo code. With the owning merchant's API key the
+    /// merchant fields (`merchant_id`, `refund_address`, `payments`, ...) are filled in.
+    pub async fn get_invoice(&self, id_or_memo: &str) -> Result<Invoice> {
+        self.get(&format!("/invoices/{}", id_or_memo)).await
+    }
+
+    /// `GET /api/v1/invoices` (API key): the merchant's 50 most recent invoices.
+    pub async fn list_invoices(&self) -> Result<Vec<Invoice>> {
+        self.get("/invoices").await
+    }
+
+    /// `GET /api/v1/invoices?status=expired&received_gt=0` (API key): recent expired invoices
+    /// that received partial payments, which need refunding.
+    pub async fn list_expired_with_payments(&self) -> Result<Vec<Invoice>> {
+        let req = self.request(reqwest::Method::GET, "/invoices")
+            .query(&[("status", "expired"), ("received_gt", "0")]);
+        self.send(req).await
+    }
+
+    /// `GET /api/v1/invoices/{id}/status`: lightweight status for polling.
+    pub async fn invoice_status(&self, id: &str) -> Result<InvoiceStatusInfo> {
+        self.get(&format!("/invoices/{}/status", id)).await
+    }
+
+    /// `GET /api/v1/invoices/{id}/events` (API key): the invoice timeline, oldest first.
+    pub async fn invoice_events(&self, id: &str) -> Result<Vec<InvoiceEvent>> {
+        let timeline: Timeline = self.get(&format!("/invoices/{}/events", id)).await?;
+        Ok(timeline.events)
+    }
+
+    /// `POST /api/v1/invoices/{id}/cancel` (API key): cancel a pending invoice, e.g. an
+    /// abandoned order.
+    pub async fn cancel_invoice(&self, id: &str) -> Result<()> {
+        self.post::<_, serde::de::IgnoredAny>(&format!("/invoices/{}/cancel", id), &serde_json::json!({})).await?;
+        Ok(())
+    }
+
+    /// `POST /api/v1/invoices/{id}/refund` (API key): mark a confirmed or paid_late invoice
+    /// refunded without registering a transaction.
+    pub async fn mark_refunded(&self, id: &str) -> Result<()> {
+        self.post::<_, serde::de::IgnoredAny>(&format!("/invoices/{}/refund", id), &serde_json::json!({})).await?;
+        Ok(())
+    }
+
+    /// `POST /api/v1/invoices/{id}/refund-txid` (API key): register a refund sent to the
+    /// buyer. `amount` is in ZEC and defaults to the full received amount.
+    pub async fn register_refund(&self, id: &str, txid: &str, amount: Option<f64>) -> Result<RefundSubmitted> {
+        let body = serde_json::json!({ "txid": txid, "amount": amount });
+        self.post(&format!("/invoices/{}/refund-txid", id), &body).await
+    }
+
+    /// `POST /api/v1/invoices/{id}/simulate-detect` (API key, testnet servers only): a payment
+    /// of `amount_zec` reaches the mempool and the invoice becomes detected or underpaid.
+    pub async fn simulate_detect(&self, id: &str, sim: &Simulation) -> Result<SimulationResult> {
+        self.post(&format!("/invoices/{}/simulate-detect", id), sim).await
+    }
+
+    /// `POST /api/v1/invoices/{id}/simulate-confirm` (API key, testnet only).
+    pub async fn simulate_confirm(&self, id: &str, sim: &Simulation) -> Result<SimulationResult> {
+        self.post(&format!("/invoices/{}/simulate-confirm", id), sim).await
+    }
+
+    /// `POST /api/v1/invoices/{id}/simulate-expire` (API key, testnet only): the invoice runs
+    /// out of time and is expired or requoted.
+    pub async fn simulate_expire(&self, id: &str, sim: &Simulation) -> Result<SimulationResult> {
+        self.post(&format!("/invoices/{}/simulate-expire", id), sim).await
+    }
+
+    /// `GET /api/v1/rates`: current ZEC prices.
+    pub async fn rates(&self) -> Result<Rates> {
+        self.get("/rates").await
+    }
+
+    /// `GET /api/v1/rates?at=`: the rate in effect at an RFC 3339 time, e.g. an invoice's
+    /// `confirmed_at`.
+    pub async fn rates_at(&self, at: &str) -> Result<Rates> {
+        let req = self.request(reqwest::Method::GET, "/rates").query(&[("at", at)]);
+        self.send(req).await
+    }
+
+    /// `GET /api/v1/rates?range=`: recorded rates over `24h` or `7d` (hourly averages).
+    pub async fn rate_history(&self, range: &str) -> Result<RateHistory> {
+        let req = self.request(reqwest::Method::GET, "/rates").query(&[("range", range)]);
+        self.send(req).await
+    }
+
+    /// `GET /api/v1/webhooks/signing-info`: signing schemes and a worked example.
+    pub async fn signing_info(&self) -> Result<serde_json::Value> {
+        self.get("/webhooks/signing-info").await
+    }
+
+    /// Open `GET /api/v1/invoices/{id}/stream`. Pass the last event ID seen to resume after a
+    /// dropped connection; the server replays every transition since.
+    pub async fn subscribe(&self, id: &str, last_event_id: Option<i64>) -> Result<InvoiceStream> {
+        let mut req = self.http.get(self.url(&format!("/invoices/{}/stream", id)))
+            .header(reqwest::header::ACCEPT, "text/event-stream");
+        if let Some(last) = last_event_id {
+            req = req.header("Last-Event-ID", last.to_string());
+        }
+        Ok(InvoiceStream::new(check(req.send().await?).await?))
+    }
+}
+
+/// Turn a non-2xx response into `Error::Api` using the server's `{"error", "field"}` body.
+async fn check(resp: reqwest::Response) -> Result<reqwest::Response> {
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    let body: serde_json::Value = resp.json().await.unwrap_or_default();
+    Err(Error::Api {
+        status: status.as_u16(),
+        message: body["error"].as_str().unwrap_or(status.as_str()).to_string(),
+        field: body["field"].as_str().map(str::to_string),
+    })
+}
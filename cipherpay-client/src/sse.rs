@@ -0,0 +1,110 @@
+//! Reader for the invoice event stream (`text/event-stream`).
+
+use crate::{Error, Requote, Result, StatusUpdate};
+
+/// One server-sent event.
+#[derive(Debug, Clone)]
+pub struct StreamEvent {
+    /// Timeline event ID; pass the last one to `Client::subscribe` to resume.
+    pub id: Option<i64>,
+    /// `status` or `requoted`.
+    pub event: String,
+    pub data: String,
+}
+
+impl StreamEvent {
+    pub fn status(&self) -> Option<StatusUpdate> {
+        (self.event == "status").then(|| serde_json::from_str(&self.data).ok()).flatten()
+    }
+
+    pub fn requote(&self) -> Option<Requote> {
+        (self.event == "requoted").then(|| serde_json::from_str(&self.data).ok()).flatten()
+    }
+}
+
+/// An open invoice stream. The server closes it once the invoice reaches a final status.
+pub struct InvoiceStream {
+    resp: reqwest::Response,
+    buf: String,
+    last_event_id: Option<i64>,
+}
+
+impl InvoiceStream {
+    pub(crate) fn new(resp: reqwest::Response) -> Self {
+        Self { resp, buf: String::new(), last_event_id: None }
+    }
+
+    /// ID of the last event received, for resuming with `Client::subscribe`.
+    pub fn last_event_id(&self) -> Option<i64> {
+        self.last_event_id
+    }
+
+    /// Wait for the next event. `None` when the server closed the stream.
+    pub async fn next_event(&mut self) -> Option<Result<StreamEvent>> {
+        loop {
+            while let Some(block) = take_block(&mut self.buf) {
+                if let Some(event) = parse_block(&block) {
+                    if event.id.is_some() {
+                        self.last_event_id = event.id;
+                    }
+                    return Some(Ok(event));
+                }
+            }
+            match self.resp.chunk().await {
+                Ok(Some(chunk)) => self.buf.push_str(&String::from_utf8_lossy(&chunk).replace("\r\n", "\n")),
+                Ok(None) => return None,
+                Err(e) => return Some(Err(Error::Http(e))),
+            }
+        }
+    }
+}
+
+/// Split off the first complete event (terminated by a blank line).
+fn take_block(buf: &mut String) -> Option<String> {
+    let end = buf.find("\n\n")?;
+    let block = buf[..end].to_string();
+    buf.drain(..end + 2);
+    Some(block)
+}
+
+/// Parse one event block. Comment-only blocks (keep-alives) yield `None`.
+fn parse_block(block: &str) -> Option<StreamEvent> {
+    let mut event = None;
+    let mut id = None;
+    let mut data: Vec<&str> = Vec::new();
+    for line in block.lines() {
+        let (field, value) = line.split_once(':').unwrap_or((line, ""));
+        let value = value.strip_prefix(' ').unwrap_or(value);
+        match field {
+            "event" => event = Some(value.to_string()),
+            "data" => data.push(value),
+            "id" => id = value.parse().ok(),
+            _ => {}
+        }
+    }
+    if event.is_none() && data.is_empty() {
+        return None;
+    }
+    Some(StreamEvent {
+        id,
+        event: event.unwrap_or_else(|| "message".into()),
+        data: data.join("\n"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_events() {
+        let mut buf = ": keep-alive\n\nevent: status\nid: 42\ndata: {\"status\":\"detected\",\"txid\":\"ab\",\"received_zatoshis\":5,\"price_zatoshis\":5}\n\nevent: requo".to_string();
+
+        assert!(parse_block(&take_block(&mut buf).unwrap()).is_none());
+        let event = parse_block(&take_block(&mut buf).unwrap()).unwrap();
+        assert_eq!(event.id, Some(42));
+        assert_eq!(event.status().unwrap().status, crate::InvoiceStatus::Detected);
+        assert!(take_block(&mut buf).is_none());
+        assert_eq!(buf, "event: requo");
+    }
+}
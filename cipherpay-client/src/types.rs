@@ -0,0 +1,368 @@
+//! Request and response bodies, mirroring the server's handlers.
+
+use serde::{Deserialize, Serialize};
+
+/// Body of `POST /api/v1/merchants`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CreateMerchant {
+    /// Unified full viewing key of the wallet receiving payments.
+    pub ufvk: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook_url: Option<String>,
+    /// Recovery email for dashboard access.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    /// Trial-decrypt this many recent blocks with the UFVK after registering (at most
+    /// 1000). The result is shown in the dashboard.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verify_blocks: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MerchantCredentials {
+    pub merchant_id: String,
+    pub api_key: String,
+    pub dashboard_token: String,
+    pub webhook_secret: String,
+}
+
+/// Body of `POST /api/v1/invoices`. `price_eur` is the invoice total in `currency`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CreateInvoice {
+    pub price_eur: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub product_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub product_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quantity: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refund_address: Option<String>,
+    /// `expire` (default) or `requote`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_expiry: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_currency: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+    /// Ask for a claim code the buyer can redeem on the hosted site.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub claim_code: bool,
+}
+
+impl CreateInvoice {
+    pub fn new(price: f64) -> Self {
+        Self { price_eur: price, ..Default::default() }
+    }
+
+    pub fn currency(mut self, currency: impl Into<String>) -> Self {
+        self.currency = Some(currency.into());
+        self
+    }
+
+    pub fn product_name(mut self, name: impl Into<String>) -> Self {
+        self.product_name = Some(name.into());
+        self
+    }
+
+    pub fn refund_address(mut self, address: impl Into<String>) -> Self {
+        self.refund_address = Some(address.into());
+        self
+    }
+
+    pub fn requote_on_expiry(mut self) -> Self {
+        self.on_expiry = Some("requote".into());
+        self
+    }
+
+    pub fn with_claim_code(mut self) -> Self {
+        self.claim_code = true;
+        self
+    }
+}
+
+/// Body of `POST /api/v1/checkout`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Checkout {
+    pub product_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quantity: Option<i64>,
+    /// Buyer's ISO 3166-1 alpha-2 country, for tax.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refund_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_currency: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+    /// From [`Client::checkout_token`](crate::Client::checkout_token); required.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checkout_token: Option<String>,
+    /// Answers to the product's checkout fields, by field name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub custom_fields: Option<std::collections::HashMap<String, String>>,
+}
+
+/// Checkout token from a product's public endpoint.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CheckoutToken {
+    /// `None` once this IP has been issued its hourly share.
+    pub checkout_token: Option<String>,
+    pub checkout_token_expires_at: Option<String>,
+}
+
+/// Proof-of-work challenge from `GET /api/v1/pow/challenge`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PowChallenge {
+    /// False when the server does not ask for proof of work; the other fields are then empty.
+    pub required: bool,
+    #[serde(default)]
+    pub challenge: String,
+    #[serde(default)]
+    pub difficulty: u32,
+    pub expires_at: Option<String>,
+}
+
+impl PowChallenge {
+    /// Find a nonce and return the `X-CipherPay-PoW` header value, or `None` if not required.
+    pub fn solve(&self) -> Option<String> {
+        use sha2::{Digest, Sha256};
+
+        if !self.required {
+            return None;
+        }
+        (0u64..)
+            .map(|nonce| format!("{}:{}", self.challenge, nonce))
+            .find(|solution| {
+                let hash = Sha256::digest(solution.as_bytes());
+                let zeros = hash.iter().take_while(|b| **b == 0).count() as u32;
+                let bits = zeros * 8 + hash.get(zeros as usize).map_or(0, |b| b.leading_zeros());
+                bits >= self.difficulty
+            })
+    }
+}
+
+/// Response of invoice creation and checkout.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CreatedInvoice {
+    pub invoice_id: String,
+    pub memo_code: String,
+    pub price_eur: f64,
+    pub price_usd: f64,
+    pub price_zec: f64,
+    pub zec_rate: f64,
+    pub payment_address: String,
+    pub zcash_uri: String,
+    /// Present when the invoice was created with [`CreateInvoice::with_claim_code`].
+    #[serde(default)]
+    pub claim_code: Option<String>,
+    pub expires_at: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InvoiceStatus {
+    Pending,
+    Underpaid,
+    Detected,
+    Confirmed,
+    Expired,
+    PaidLate,
+    Refunded,
+    /// A status added to the server after this client was built.
+    #[serde(other)]
+    Unknown,
+}
+
+impl InvoiceStatus {
+    /// No further transitions are expected without merchant action.
+    pub fn is_final(self) -> bool {
+        matches!(self, Self::Confirmed | Self::Expired | Self::PaidLate | Self::Refunded)
+    }
+}
+
+/// An invoice as returned by `GET /api/v1/invoices/{id}`. The merchant fields are `None`
+/// on the public view.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Invoice {
+    pub id: String,
+    pub memo_code: String,
+    pub product_name: Option<String>,
+    pub size: Option<String>,
+    pub quantity: i64,
+    pub price_eur: f64,
+    pub price_usd: Option<f64>,
+    pub currency: Option<String>,
+    pub price_zec: f64,
+    pub zec_rate_at_creation: f64,
+    pub payment_address: String,
+    pub zcash_uri: String,
+    /// Merchant output only, for wallets that cannot pay multi-output ZIP-321 URIs.
+    #[serde(default)]
+    pub zcash_uri_simple: String,
+    /// TEX address for exchange payments, when the merchant opted in.
+    #[serde(default)]
+    pub tex: Option<TexPayment>,
+    pub status: InvoiceStatus,
+    pub detected_txid: Option<String>,
+    pub detected_at: Option<String>,
+    pub confirmed_at: Option<String>,
+    pub refunded_at: Option<String>,
+    pub expires_at: String,
+    pub on_expiry: String,
+    pub created_at: String,
+    pub received_zec: f64,
+    pub price_zatoshis: i64,
+    pub received_zatoshis: i64,
+    /// Still to pay; 0 once the price is covered.
+    #[serde(default)]
+    pub remaining_zatoshis: i64,
+    /// Of the latest mined payment; `None` until one is mined.
+    #[serde(default)]
+    pub confirmations: Option<i64>,
+    pub overpaid: bool,
+    #[serde(default)]
+    pub merchant_id: Option<String>,
+    #[serde(default)]
+    pub refund_address: Option<String>,
+    #[serde(default)]
+    pub requote_count: Option<i64>,
+    #[serde(default)]
+    pub payments: Option<Vec<Payment>>,
+    /// Whether what is still owed equals the buyer's miner fee (merchant view).
+    #[serde(default)]
+    pub shortfall_is_fee: Option<bool>,
+    /// The buyer's answers to the product's checkout fields (merchant view).
+    #[serde(default)]
+    pub custom_fields: Option<serde_json::Value>,
+}
+
+/// A transparent (TEX) way to pay an invoice. Show `warning` with it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TexPayment {
+    pub address: String,
+    pub zcash_uri: String,
+    pub warning: String,
+}
+
+/// One transaction counted towards an invoice.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Payment {
+    pub txid: String,
+    pub amount_zatoshis: i64,
+    pub block_height: Option<i64>,
+    pub seen_at: String,
+    /// Miner fee of the transaction; absent when it spent transparent funds.
+    #[serde(default)]
+    pub fee_zatoshis: Option<i64>,
+    #[serde(default)]
+    pub tx_outputs: Option<i64>,
+}
+
+/// `GET /api/v1/invoices/{id}/status`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct InvoiceStatusInfo {
+    pub invoice_id: String,
+    pub status: InvoiceStatus,
+    pub detected_txid: Option<String>,
+    pub received_zatoshis: i64,
+    pub price_zatoshis: i64,
+    #[serde(default)]
+    pub remaining_zatoshis: i64,
+    #[serde(default)]
+    pub confirmations: Option<i64>,
+}
+
+/// One entry of the invoice timeline.
+#[derive(Debug, Clone, Deserialize)]
+pub struct InvoiceEvent {
+    /// Also the SSE event ID, usable as `last_event_id` when resubscribing.
+    pub id: i64,
+    pub event_type: String,
+    pub txid: Option<String>,
+    pub block_height: Option<i64>,
+    pub detail: Option<serde_json::Value>,
+    pub created_at: String,
+}
+
+/// `POST /api/v1/invoices/{id}/refund-txid` response.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RefundSubmitted {
+    /// `refund_pending` until the scanner has verified the transaction.
+    pub status: String,
+    pub refund_txid: String,
+    pub refund_zec: f64,
+}
+
+/// Options for the testnet simulation endpoints.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Simulation {
+    /// ZEC paid (detect only). Defaults to what is still owed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount_zec: Option<f64>,
+    /// Apply the change after this many seconds (at most 300).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delay_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SimulationResult {
+    /// The invoice's status afterwards, or `scheduled` when delayed.
+    pub status: String,
+    pub txid: Option<String>,
+    #[serde(default)]
+    pub received_zatoshis: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Timeline {
+    pub events: Vec<InvoiceEvent>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Rates {
+    pub zec_eur: f64,
+    pub zec_usd: f64,
+    pub updated_at: String,
+    /// `feed`, or `operator` when the server's operator set the rate by hand.
+    #[serde(default)]
+    pub source: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateHistory {
+    pub range: String,
+    /// Oldest first.
+    pub points: Vec<Rates>,
+}
+
+/// `status` event on the invoice stream.
+#[derive(Debug, Clone, Deserialize)]
+pub struct StatusUpdate {
+    pub status: InvoiceStatus,
+    pub txid: Option<String>,
+    pub received_zatoshis: i64,
+    pub price_zatoshis: i64,
+    #[serde(default)]
+    pub remaining_zatoshis: i64,
+    #[serde(default)]
+    pub confirmations: Option<i64>,
+}
+
+/// `requoted` event on the invoice stream: the invoice was repriced instead of expiring.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Requote {
+    pub price_zec: f64,
+    pub price_zatoshis: i64,
+    pub zcash_uri: String,
+    pub expires_at: String,
+}
@@ -0,0 +1,233 @@
+//! Signing and verification of CipherPay webhooks. The server signs deliveries with these
+//! functions, so merchant code verifying with them always agrees with it.
+//!
+//! ```no_run
+//! use cipherpay_client::webhook::{self, WebhookHeaders};
+//!
+//! # fn handle(secret: &str, event_id: &str, timestamp: &str, signatures: &str, body: &[u8]) {
+//! let headers = WebhookHeaders { event_id, timestamp, signatures: Some(signatures), signature: None };
+//! match webhook::verify(secret, &headers, body) {
+//!     Ok(event) => println!("{} for {}", event.event, event.invoice_id),
+//!     Err(e) => eprintln!("rejected: {}", e),
+//! }
+//! # }
+//! ```
+
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Webhooks whose timestamp is further than this from the receiver's clock are rejected.
+pub const TIMESTAMP_TOLERANCE_SECS: i64 = 300;
+
+/// Signature schemes, oldest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scheme {
+    /// HMAC-SHA256 over `{timestamp}.{body}`, also sent alone in `X-CipherPay-Signature`.
+    V1,
+    /// HMAC-SHA256 over `{event_id}.{timestamp}.{body}`. Binding the event ID stops a
+    /// captured body from being replayed under a different delivery.
+    V2,
+}
+
+impl Scheme {
+    pub const ALL: [Scheme; 2] = [Scheme::V1, Scheme::V2];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scheme::V1 => "v1",
+            Scheme::V2 => "v2",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|scheme| scheme.as_str() == s)
+    }
+
+    fn message(self, event_id: &str, timestamp: &str, body: &[u8]) -> Vec<u8> {
+        let prefix = match self {
+            Scheme::V1 => format!("{}.", timestamp),
+            Scheme::V2 => format!("{}.{}.", event_id, timestamp),
+        };
+        let mut message = prefix.into_bytes();
+        message.extend_from_slice(body);
+        message
+    }
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+fn mac(secret: &str, message: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(message);
+    mac
+}
+
+/// Hex signature of a delivery under one scheme.
+pub fn sign(scheme: Scheme, secret: &str, event_id: &str, timestamp: &str, body: &[u8]) -> String {
+    hex::encode(mac(secret, &scheme.message(event_id, timestamp, body)).finalize().into_bytes())
+}
+
+/// `X-CipherPay-Signatures` value: every scheme as `version=hex`, comma-separated, so new
+/// algorithms can be added without breaking receivers that check an older one.
+pub fn signatures_header(secret: &str, event_id: &str, timestamp: &str, body: &[u8]) -> String {
+    Scheme::ALL
+        .iter()
+        .map(|s| format!("{}={}", s, sign(*s, secret, event_id, timestamp, body)))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// The signature headers of a delivery, as received.
+#[derive(Debug, Clone, Copy)]
+pub struct WebhookHeaders<'a> {
+    /// `X-CipherPay-Event-Id`: stable across retries; deduplicate on it.
+    pub event_id: &'a str,
+    /// `X-CipherPay-Timestamp`.
+    pub timestamp: &'a str,
+    /// `X-CipherPay-Signatures` (`v1=...,v2=...`).
+    pub signatures: Option<&'a str>,
+    /// `X-CipherPay-Signature`: the v1 signature alone, for receivers of older deliveries.
+    pub signature: Option<&'a str>,
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum VerifyError {
+    #[error("no signature header")]
+    MissingSignature,
+    #[error("signature does not match")]
+    InvalidSignature,
+    #[error("timestamp is malformed")]
+    InvalidTimestamp,
+    #[error("timestamp is outside the tolerance window")]
+    StaleTimestamp,
+    #[error("body is not a webhook payload: {0}")]
+    InvalidBody(String),
+}
+
+/// A verified webhook payload. Fields not sent for an event type are `None`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookEvent {
+    /// `created`, `confirmed`, `expired`, `cancelled`, `paid_late`, `requoted`, `refund_confirmed`, ...
+    /// or a catalog event: `product.created`, `product.updated`, `product.deactivated`.
+    pub event: String,
+    /// Empty for catalog events.
+    #[serde(default)]
+    pub invoice_id: String,
+    pub timestamp: String,
+    /// Per-merchant, increasing in event order; ignore events older than the last processed.
+    pub sequence: i64,
+    pub txid: Option<String>,
+    pub quantity: Option<i64>,
+    pub price_zec: Option<f64>,
+    pub received_zec: Option<f64>,
+    /// On `expired`: what the buyer sent before the invoice ran out.
+    pub received_zatoshis: Option<i64>,
+    /// On `expired`: whether the buyer left a refund address for those funds.
+    pub has_refund_address: Option<bool>,
+    pub overpaid: Option<bool>,
+    pub expires_at: Option<String>,
+    /// On `created`: what the buyer needs to pay.
+    pub memo_code: Option<String>,
+    pub payment_address: Option<String>,
+    pub zcash_uri: Option<String>,
+    pub tex_address: Option<String>,
+    /// The buyer's answers to the product's checkout fields, when it has any.
+    pub custom_fields: Option<serde_json::Value>,
+    /// On catalog events: the product as the products API returns it, after the change.
+    pub product_id: Option<String>,
+    pub product: Option<serde_json::Value>,
+}
+
+/// Check the signature and timestamp of a delivery against the current time, then parse it.
+pub fn verify(secret: &str, headers: &WebhookHeaders, body: &[u8]) -> Result<WebhookEvent, VerifyError> {
+    verify_at(secret, headers, body, Utc::now())
+}
+
+/// Like [`verify`], with an explicit clock.
+pub fn verify_at(
+    secret: &str,
+    headers: &WebhookHeaders,
+    body: &[u8],
+    now: DateTime<Utc>,
+) -> Result<WebhookEvent, VerifyError> {
+    verify_signature(secret, headers, body, now)?;
+    serde_json::from_slice(body).map_err(|e| VerifyError::InvalidBody(e.to_string()))
+}
+
+/// Check timestamp and signature without parsing the body. The strongest scheme present
+/// is the one checked, and the one returned; unknown versions are skipped. Comparison is
+/// constant-time.
+pub fn verify_signature(
+    secret: &str,
+    headers: &WebhookHeaders,
+    body: &[u8],
+    now: DateTime<Utc>,
+) -> Result<Scheme, VerifyError> {
+    let signed_at = DateTime::parse_from_rfc3339(headers.timestamp)
+        .map_err(|_| VerifyError::InvalidTimestamp)?;
+    if (now - signed_at.with_timezone(&Utc)).num_seconds().abs() > TIMESTAMP_TOLERANCE_SECS {
+        return Err(VerifyError::StaleTimestamp);
+    }
+
+    let mut candidates: Vec<(Scheme, &str)> = headers.signatures
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|part| {
+            let (version, sig) = part.trim().split_once('=')?;
+            Some((Scheme::parse(version)?, sig))
+        })
+        .collect();
+    if let Some(v1) = headers.signature {
+        candidates.push((Scheme::V1, v1));
+    }
+    let (scheme, signature) = candidates.into_iter()
+        .max_by_key(|(scheme, _)| *scheme)
+        .ok_or(VerifyError::MissingSignature)?;
+
+    let expected = hex::decode(signature.trim()).map_err(|_| VerifyError::InvalidSignature)?;
+    mac(secret, &scheme.message(headers.event_id, headers.timestamp, body))
+        .verify_slice(&expected)
+        .map_err(|_| VerifyError::InvalidSignature)?;
+    Ok(scheme)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_schemes() {
+        let secret = "whsec_test";
+        let body = br#"{"event":"confirmed","invoice_id":"inv","sequence":1,"timestamp":"2025-01-01T00:00:00Z","txid":"ab"}"#;
+        let ts = "2025-01-01T00:00:00Z";
+        let now = DateTime::parse_from_rfc3339("2025-01-01T00:01:00Z").unwrap().with_timezone(&Utc);
+        let all = signatures_header(secret, "evt", ts, body);
+        let v1 = sign(Scheme::V1, secret, "evt", ts, body);
+
+        let headers = WebhookHeaders { event_id: "evt", timestamp: ts, signatures: Some(&all), signature: None };
+        assert_eq!(verify_signature(secret, &headers, body, now), Ok(Scheme::V2));
+        assert_eq!(verify_at(secret, &headers, body, now).unwrap().sequence, 1);
+
+        // v2 binds the event ID; v1 alone does not.
+        let replayed = WebhookHeaders { event_id: "other", ..headers };
+        assert_eq!(verify_signature(secret, &replayed, body, now), Err(VerifyError::InvalidSignature));
+        let legacy = WebhookHeaders { event_id: "other", timestamp: ts, signatures: None, signature: Some(&v1) };
+        assert_eq!(verify_signature(secret, &legacy, body, now), Ok(Scheme::V1));
+
+        let later = now + chrono::Duration::minutes(10);
+        assert_eq!(verify_signature(secret, &headers, body, later), Err(VerifyError::StaleTimestamp));
+        assert_eq!(verify_signature("wrong", &headers, body, now), Err(VerifyError::InvalidSignature));
+        let unknown = WebhookHeaders { signatures: Some("v9=00"), ..headers };
+        assert_eq!(verify_signature(secret, &unknown, body, now), Err(VerifyError::MissingSignature));
+    }
+}
@@ -0,0 +1,330 @@
+//! Scanner load test. Runs the release server against a throwaway database and a stand-in
+//! CipherScan, registers `--merchants` merchants with `--invoices` pending invoices between
+//! them, then publishes a mempool holding a payment for `--paid` of those invoices plus
+//! unrelated traffic, and reports how long the scanner takes to detect every payment.
+//!
+//! ```text
+//! cargo build --release
+//! cargo run --release --example loadtest -- --merchants 50 --invoices 5000 --paid 500
+//! ```
+//!
+//! Unrelated traffic is `--noise` synthetic transactions, or a mempool captured from a real
+//! CipherScan with `--record <api url> --capture mempool.json` and replayed with
+//! `--capture mempool.json`. Captures are a JSON array of `{"txid", "hex"}` objects.
+
+#![allow(dead_code)]
+
+#[path = "../src/scanner/fixtures.rs"]
+mod fixtures;
+
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+use fixtures::Output;
+
+/// Test wallet seeds 1..=254 are merchants; this one receives the synthetic noise.
+const NOISE_WALLET: u8 = 255;
+/// Concurrent API requests while setting up.
+const SETUP_CONCURRENCY: usize = 32;
+
+struct Args {
+    merchants: usize,
+    invoices: usize,
+    paid: usize,
+    noise: usize,
+    capture: Option<PathBuf>,
+    record: Option<String>,
+    server: PathBuf,
+    timeout: Duration,
+}
+
+impl Args {
+    fn parse() -> anyhow::Result<Self> {
+        let mut args = Args {
+            merchants: 10,
+            invoices: 1_000,
+            paid: 100,
+            noise: 200,
+            capture: None,
+            record: None,
+            // Built next to this example: target/<profile>/examples/loadtest.
+            server: std::env::current_exe()?
+                .parent()
+                .and_then(|examples| examples.parent())
+                .map(|profile| profile.join("cipherpay"))
+                .unwrap_or_else(|| "target/release/cipherpay".into()),
+            timeout: Duration::from_secs(300),
+        };
+        let mut argv = std::env::args().skip(1);
+        while let Some(flag) = argv.next() {
+            let value = argv.next().ok_or_else(|| anyhow::anyhow!("{} needs a value", flag))?;
+            match flag.as_str() {
+                "--merchants" => args.merchants = value.parse()?,
+                "--invoices" => args.invoices = value.parse()?,
+                "--paid" => args.paid = value.parse()?,
+                "--noise" => args.noise = value.parse()?,
+                "--capture" => args.capture = Some(value.into()),
+                "--record" => args.record = Some(value),
+                "--server" => args.server = value.into(),
+                "--timeout" => args.timeout = Duration::from_secs(value.parse()?),
+                _ => anyhow::bail!("unknown option {}", flag),
+            }
+        }
+        if !(1..NOISE_WALLET as usize).contains(&args.merchants) {
+            anyhow::bail!("--merchants must be between 1 and {}", NOISE_WALLET - 1);
+        }
+        if args.paid > args.invoices {
+            anyhow::bail!("--paid cannot exceed --invoices");
+        }
+        Ok(args)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct CapturedTx {
+    txid: String,
+    hex: String,
+}
+
+/// CipherScan stand-in serving a fixed chain tip and, once published, the mempool.
+#[derive(Clone, Default)]
+struct Replay {
+    mempool: Arc<OnceLock<HashMap<String, String>>>,
+}
+
+impl Respond for Replay {
+    fn respond(&self, req: &Request) -> ResponseTemplate {
+        let path = req.url.path();
+        let ok = |body: serde_json::Value| ResponseTemplate::new(200).set_body_json(body);
+        if path == "/api/blockchain-info" {
+            return ok(json!({ "blocks": 100 }));
+        }
+        if path == "/api/mempool" {
+            let txids: Vec<_> = self
+                .mempool
+                .get()
+                .map(|txs| txs.keys().map(|txid| json!({ "txid": txid })).collect())
+                .unwrap_or_default();
+            return ok(json!({ "transactions": txids }));
+        }
+        if path.starts_with("/api/block/") {
+            return ok(json!({ "tx": [] }));
+        }
+        if let Some(txid) = path.strip_prefix("/api/tx/").and_then(|rest| rest.strip_suffix("/raw")) {
+            return match self.mempool.get().and_then(|txs| txs.get(txid)) {
+                Some(hex) => ok(json!({ "hex": hex })),
+                None => ResponseTemplate::new(404),
+            };
+        }
+        if path.starts_with("/api/tx/") {
+            return ok(json!({}));
+        }
+        ResponseTemplate::new(404)
+    }
+}
+
+/// Save the current mempool of the CipherScan at `api_url`.
+async fn record(api_url: &str, out: &PathBuf) -> anyhow::Result<()> {
+    let http = reqwest::Client::new();
+    let mempool: serde_json::Value = http.get(format!("{}/api/mempool", api_url)).send().await?.json().await?;
+    let txids: Vec<String> = mempool["transactions"]
+        .as_array()
+        .map(|txs| txs.iter().filter_map(|tx| tx["txid"].as_str().map(String::from)).collect())
+        .unwrap_or_default();
+
+    let captured: Vec<CapturedTx> = stream::iter(txids)
+        .map(|txid| {
+            let http = &http;
+            async move {
+                let raw: serde_json::Value =
+                    http.get(format!("{}/api/tx/{}/raw", api_url, txid)).send().await.ok()?.json().await.ok()?;
+                Some(CapturedTx { hex: raw["hex"].as_str()?.to_string(), txid })
+            }
+        })
+        .buffer_unordered(8)
+        .filter_map(|tx| async { tx })
+        .collect()
+        .await;
+    std::fs::write(out, serde_json::to_vec_pretty(&captured)?)?;
+    println!("recorded {} mempool transactions to {}", captured.len(), out.display());
+    Ok(())
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// A distinct client address per request, so the per-IP rate limits don't throttle setup.
+fn client_ip(n: usize) -> String {
+    format!("10.{}.{}.{}", (n >> 16) & 0xff, (n >> 8) & 0xff, n & 0xff)
+}
+
+#[derive(Deserialize)]
+struct Registered {
+    api_key: String,
+}
+
+#[derive(Deserialize)]
+struct Created {
+    payment_address: String,
+    price_zec: f64,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse()?;
+    if let Some(api_url) = &args.record {
+        let out = args.capture.clone().ok_or_else(|| anyhow::anyhow!("--record needs --capture <file>"))?;
+        return record(api_url.trim_end_matches('/'), &out).await;
+    }
+    if !args.server.exists() {
+        anyhow::bail!("server binary {} not found; run `cargo build --release` first", args.server.display());
+    }
+
+    let dir = std::env::temp_dir().join(format!("cipherpay-loadtest-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let db_path = dir.join("load.db");
+    let port = free_port();
+    let base_url = format!("http://127.0.0.1:{}", port);
+
+    let replay = Replay::default();
+    let cipherscan = MockServer::start().await;
+    Mock::given(wiremock::matchers::any()).respond_with(replay.clone()).mount(&cipherscan).await;
+    let mut server = Command::new(&args.server)
+        .env("DATABASE_URL", format!("sqlite:{}?mode=rwc", db_path.display()))
+        .env("API_HOST", "127.0.0.1")
+        .env("API_PORT", port.to_string())
+        .env("NETWORK", "testnet")
+        .env("CIPHERSCAN_API_URL", cipherscan.uri())
+        .env("FIXED_ZEC_EUR", "40")
+        .env("FIXED_ZEC_USD", "44")
+        .env("MEMPOOL_POLL_INTERVAL_SECS", "1")
+        .env("TRUSTED_PROXIES", "127.0.0.1")
+        .env("MAX_OPEN_INVOICES_PER_MERCHANT", "0")
+        .env("MAX_INVOICES_PER_MERCHANT_PER_HOUR", "0")
+        .env("MEDIA_DIR", dir.join("media"))
+        .env("ENCRYPTION_KEY", "")
+        .env("RUST_LOG", "error")
+        .stdout(Stdio::null())
+        .spawn()?;
+    let result = run(&args, &base_url, &db_path, &replay).await;
+    let _ = server.kill();
+    let _ = server.wait();
+    let _ = std::fs::remove_dir_all(&dir);
+    result
+}
+
+async fn run(
+    args: &Args,
+    base_url: &str,
+    db_path: &std::path::Path,
+    replay: &Replay,
+) -> anyhow::Result<()> {
+    let http = reqwest::Client::new();
+    for _ in 0..100 {
+        if http.get(format!("{}/api/health", base_url)).send().await.is_ok_and(|r| r.status().is_success()) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let setup = Instant::now();
+    let api_keys: Vec<String> = stream::iter(1..=args.merchants)
+        .map(|seed| {
+            let http = &http;
+            async move {
+                let resp = http
+                    .post(format!("{}/api/v1/merchants", base_url))
+                    .header("X-Forwarded-For", client_ip(seed))
+                    .json(&json!({ "ufvk": fixtures::test_ufvk(seed as u8), "name": format!("Load {}", seed) }))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                Ok::<_, anyhow::Error>(resp.json::<Registered>().await?.api_key)
+            }
+        })
+        .buffered(SETUP_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<_, _>>()?;
+
+    let invoices: Vec<Created> = stream::iter(0..args.invoices)
+        .map(|n| {
+            let (http, api_key) = (&http, &api_keys[n % api_keys.len()]);
+            async move {
+                let resp = http
+                    .post(format!("{}/api/v1/invoices", base_url))
+                    .bearer_auth(api_key)
+                    .header("X-Forwarded-For", client_ip(args.merchants + n))
+                    .json(&json!({ "price_eur": 5.0 + (n % 200) as f64 }))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                Ok::<_, anyhow::Error>(resp.json::<Created>().await?)
+            }
+        })
+        .buffer_unordered(SETUP_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<_, _>>()?;
+    let setup_secs = setup.elapsed().as_secs_f64();
+    println!(
+        "setup: {} merchants, {} pending invoices in {:.1}s ({:.0} invoices/s)",
+        args.merchants, invoices.len(), setup_secs, invoices.len() as f64 / setup_secs,
+    );
+
+    let mut txs: HashMap<String, String> = HashMap::new();
+    for (n, invoice) in invoices.iter().take(args.paid).enumerate() {
+        let zatoshis = (invoice.price_zec * 100_000_000.0).round() as u64;
+        let tx = fixtures::transaction(&[Output::to_address(&invoice.payment_address, zatoshis, "")], n as u64);
+        txs.insert(fixtures::txid(&tx), hex::encode(tx));
+    }
+    match &args.capture {
+        Some(path) => {
+            let captured: Vec<CapturedTx> = serde_json::from_slice(&std::fs::read(path)?)?;
+            txs.extend(captured.into_iter().map(|tx| (tx.txid, tx.hex)));
+        }
+        None => {
+            for n in 0..args.noise {
+                let tx = fixtures::transaction(&[Output::to_wallet(NOISE_WALLET, n as u32, 100_000, "")], 1_000_000 + n as u64);
+                txs.insert(fixtures::txid(&tx), hex::encode(tx));
+            }
+        }
+    }
+    let mempool_size = txs.len();
+    let other = mempool_size - args.paid;
+
+    let db = sqlx::SqlitePool::connect(&format!("sqlite:{}?mode=ro", db_path.display())).await?;
+    let _ = replay.mempool.set(txs);
+    let started = Instant::now();
+    let mut detected = 0;
+    while started.elapsed() < args.timeout {
+        detected = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM invoices WHERE status = 'detected'")
+            .fetch_one(&db)
+            .await? as usize;
+        if detected >= args.paid {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    let scan_secs = started.elapsed().as_secs_f64();
+    println!(
+        "scan: {} mempool txs ({} payments, {} other), {}/{} payments detected in {:.1}s ({:.0} txs/s, includes up to 1s poll interval)",
+        mempool_size, args.paid, other, detected, args.paid, scan_secs, mempool_size as f64 / scan_secs,
+    );
+    if detected < args.paid {
+        anyhow::bail!("timed out with {} of {} payments detected", detected, args.paid);
+    }
+    Ok(())
+}
@@ -0,0 +1,286 @@
+//! Anti-abuse for the public endpoints that create or read invoices without an API key
+//! (`POST /api/checkout`, `GET /api/invoices/lookup/{memo}`). Every checkout burns a
+//! diversifier index and adds an address the scanner trial-decrypts against, so these are
+//! capped per IP, optionally gated behind a hashcash-style proof of work, and an IP that
+//! keeps hitting the limits is banned for a while.
+//!
+//! State is in memory: bans and counters reset on restart, which is fine for limits
+//! measured in minutes.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+
+/// Request header carrying a proof-of-work solution.
+pub const POW_HEADER: &str = "X-CipherPay-PoW";
+
+/// How long a proof-of-work challenge can be solved and spent.
+const CHALLENGE_TTL_SECS: u64 = 300;
+/// Window in which strikes add up to a ban.
+const STRIKE_WINDOW: Duration = Duration::from_secs(3600);
+const LOOKUP_WINDOW: Duration = Duration::from_secs(60);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub struct Challenge {
+    pub challenge: String,
+    /// Leading zero bits required of `SHA-256("{challenge}:{nonce}")`.
+    pub difficulty: u32,
+    pub expires_at: String,
+}
+
+/// Why a proof of work was refused.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PowError {
+    Missing,
+    Invalid,
+    Expired,
+    Reused,
+}
+
+struct Window {
+    started: Instant,
+    count: u32,
+}
+
+impl Window {
+    fn new(now: Instant) -> Self {
+        Self { started: now, count: 0 }
+    }
+
+    /// Count one hit, starting a new window once `length` has passed. Returns the count.
+    fn hit(&mut self, now: Instant, length: Duration) -> u32 {
+        if now.duration_since(self.started) >= length {
+            *self = Window::new(now);
+        }
+        self.count += 1;
+        self.count
+    }
+}
+
+#[derive(Default)]
+struct State {
+    lookups: HashMap<IpAddr, Window>,
+    strikes: HashMap<IpAddr, Window>,
+    bans: HashMap<IpAddr, Instant>,
+    /// Spent challenges, until they expire.
+    spent: HashMap<String, u64>,
+    last_prune: Option<Instant>,
+}
+
+pub struct AbuseGuard {
+    secret: [u8; 32],
+    pow_difficulty: u32,
+    lookup_limit_per_minute: u32,
+    ban_strikes: u32,
+    ban_duration: Duration,
+    state: Mutex<State>,
+}
+
+impl AbuseGuard {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            secret: rand::random(),
+            pow_difficulty: config.pow_difficulty,
+            lookup_limit_per_minute: config.lookup_ip_limit_per_minute,
+            ban_strikes: config.abuse_ban_strikes,
+            ban_duration: Duration::from_secs(config.abuse_ban_minutes * 60),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub fn pow_required(&self) -> bool {
+        self.pow_difficulty > 0
+    }
+
+    /// Time left on the IP's ban, if it has one.
+    pub fn banned(&self, ip: IpAddr) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        let until = *state.bans.get(&ip)?;
+        let now = Instant::now();
+        if until <= now {
+            state.bans.remove(&ip);
+            return None;
+        }
+        Some(until - now)
+    }
+
+    /// Record misbehaviour (a limit hit, a bad token or proof, a guessed memo code).
+    /// `ABUSE_BAN_STRIKES` within an hour bans the IP for `ABUSE_BAN_MINUTES`.
+    pub fn strike(&self, ip: IpAddr) {
+        if self.ban_strikes == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let strikes = state.strikes.entry(ip).or_insert_with(|| Window::new(now)).hit(now, STRIKE_WINDOW);
+        if strikes >= self.ban_strikes {
+            state.strikes.remove(&ip);
+            state.bans.insert(ip, now + self.ban_duration);
+            tracing::warn!(ip = %ip, minutes = self.ban_duration.as_secs() / 60, "Temporarily banned abusive client");
+        }
+    }
+
+    /// Count a memo lookup; false once the IP is over its per-minute limit.
+    pub fn allow_lookup(&self, ip: IpAddr) -> bool {
+        if self.lookup_limit_per_minute == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.prune(now);
+        let count = state.lookups.entry(ip).or_insert_with(|| Window::new(now)).hit(now, LOOKUP_WINDOW);
+        count <= self.lookup_limit_per_minute
+    }
+
+    /// A fresh challenge. Challenges are signed rather than stored, so handing them out
+    /// costs nothing; only spent ones are remembered.
+    pub fn challenge(&self) -> Challenge {
+        let expires = unix_now() + CHALLENGE_TTL_SECS;
+        let payload = format!("{}.{}", expires, hex::encode(rand::random::<[u8; 16]>()));
+        let challenge = format!("{}.{}", payload, self.sign(&payload));
+        Challenge {
+            challenge,
+            difficulty: self.pow_difficulty,
+            expires_at: chrono::DateTime::from_timestamp(expires as i64, 0)
+                .unwrap_or_default()
+                .format("%Y-%m-%dT%H:%M:%SZ")
+                .to_string(),
+        }
+    }
+
+    /// Check and spend a solution of the form `{challenge}:{nonce}`.
+    pub fn verify_pow(&self, solution: Option<&str>) -> Result<(), PowError> {
+        let solution = solution.ok_or(PowError::Missing)?;
+        let (challenge, _nonce) = solution.rsplit_once(':').ok_or(PowError::Invalid)?;
+        let (payload, mac) = challenge.rsplit_once('.').ok_or(PowError::Invalid)?;
+        if self.sign(payload) != mac {
+            return Err(PowError::Invalid);
+        }
+        let expires: u64 = payload.split('.').next().and_then(|e| e.parse().ok()).ok_or(PowError::Invalid)?;
+        if expires <= unix_now() {
+            return Err(PowError::Expired);
+        }
+        if leading_zero_bits(&Sha256::digest(solution.as_bytes())) < self.pow_difficulty {
+            return Err(PowError::Invalid);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.prune(Instant::now());
+        if state.spent.insert(challenge.to_string(), expires).is_some() {
+            return Err(PowError::Reused);
+        }
+        Ok(())
+    }
+
+    fn sign(&self, payload: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        mac.update(payload.as_bytes());
+        hex::encode(&mac.finalize().into_bytes()[..16])
+    }
+}
+
+impl State {
+    fn prune(&mut self, now: Instant) {
+        if self.last_prune.is_some_and(|last| now.duration_since(last) < PRUNE_INTERVAL) {
+            return;
+        }
+        self.last_prune = Some(now);
+        self.lookups.retain(|_, w| now.duration_since(w.started) < LOOKUP_WINDOW);
+        self.strikes.retain(|_, w| now.duration_since(w.started) < STRIKE_WINDOW);
+        self.bans.retain(|_, until| *until > now);
+        let unix = unix_now();
+        self.spent.retain(|_, expires| *expires > unix);
+    }
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        if *byte == 0 {
+            bits += 8;
+        } else {
+            return bits + byte.leading_zeros();
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard(pow_difficulty: u32) -> AbuseGuard {
+        AbuseGuard {
+            secret: [7; 32],
+            pow_difficulty,
+            lookup_limit_per_minute: 2,
+            ban_strikes: 3,
+            ban_duration: Duration::from_secs(60),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    fn guard_with_secret(secret: [u8; 32]) -> AbuseGuard {
+        AbuseGuard { secret, ..guard(8) }
+    }
+
+    fn solve(challenge: &Challenge) -> String {
+        (0u64..)
+            .map(|nonce| format!("{}:{}", challenge.challenge, nonce))
+            .find(|s| leading_zero_bits(&Sha256::digest(s.as_bytes())) >= challenge.difficulty)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0x00, 0x00, 0x80]), 16);
+        assert_eq!(leading_zero_bits(&[0x00, 0x0f]), 12);
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+    }
+
+    #[test]
+    fn test_pow_round_trip() {
+        let guard = guard(8);
+        let challenge = guard.challenge();
+        let solution = solve(&challenge);
+        assert_eq!(guard.verify_pow(Some(&solution)), Ok(()));
+        assert_eq!(guard.verify_pow(Some(&solution)), Err(PowError::Reused));
+        assert_eq!(guard.verify_pow(None), Err(PowError::Missing));
+
+        // A challenge signed by another server, or tampered with, is refused.
+        let forged = solve(&guard_with_secret([8; 32]).challenge());
+        assert_eq!(guard.verify_pow(Some(&forged)), Err(PowError::Invalid));
+        let expired = format!("1.00.{}:0", guard.sign("1.00"));
+        assert_eq!(guard.verify_pow(Some(&expired)), Err(PowError::Expired));
+    }
+
+    #[test]
+    fn test_strikes_ban_and_lookup_limit() {
+        let guard = guard(0);
+        let ip: IpAddr = "203.0.113.9".parse().unwrap();
+        assert!(guard.allow_lookup(ip));
+        assert!(guard.allow_lookup(ip));
+        assert!(!guard.allow_lookup(ip));
+
+        guard.strike(ip);
+        guard.strike(ip);
+        assert!(guard.banned(ip).is_none());
+        guard.strike(ip);
+        assert!(guard.banned(ip).is_some());
+        assert!(guard.banned("203.0.113.10".parse().unwrap()).is_none());
+    }
+}
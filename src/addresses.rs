@@ -1,6 +1,8 @@
 use anyhow::Result;
 use orchard::keys::Scope;
-use zcash_address::unified::{Encoding, Receiver, Ufvk};
+use serde::Serialize;
+use zcash_address::unified::{Container, Encoding, Fvk, Receiver, Ufvk};
+use zcash_protocol::consensus::NetworkType;
 
 pub struct DerivedAddress {
     pub ua_string: String,
@@ -18,22 +20,285 @@ pub fn derive_invoice_address(ufvk_str: &str, index: u32) -> Result<DerivedAddre
     let raw = addr.to_raw_address_bytes();
     let orchard_receiver_hex = hex::encode(raw);
 
+    let ua_string = orchard_address(raw, network)?;
+
+    Ok(DerivedAddress {
+        ua_string,
+        orchard_receiver_hex,
+    })
+}
+
+/// Name of the network a UFVK was encoded for: `mainnet`, `testnet` or `regtest`.
+pub fn ufvk_network(ufvk_str: &str) -> Result<&'static str> {
+    let (network, _) = Ufvk::decode(ufvk_str)
+        .map_err(|e| anyhow::anyhow!("UFVK decode failed: {:?}", e))?;
+    Ok(network_name(network))
+}
+
+fn network_name(network: NetworkType) -> &'static str {
+    match network {
+        NetworkType::Main => "mainnet",
+        NetworkType::Test => "testnet",
+        NetworkType::Regtest => "regtest",
+    }
+}
+
+/// Unified Address with `raw` as its only (Orchard) receiver.
+pub fn orchard_address(raw: [u8; 43], network: NetworkType) -> Result<String> {
     let ua = zcash_address::unified::Address::try_from_items(vec![
         Receiver::Orchard(raw),
     ])
     .map_err(|e| anyhow::anyhow!("UA construction failed: {:?}", e))?;
+    Ok(ua.encode(&network))
+}
 
-    let ua_string = ua.encode(&network);
+/// Raw Orchard receiver of a Unified Address, if it has one.
+pub fn orchard_receiver(addr: &str) -> Option<[u8; 43]> {
+    let (_, ua) = zcash_address::unified::Address::decode(addr).ok()?;
+    ua.items().into_iter().find_map(|r| match r {
+        Receiver::Orchard(raw) => Some(raw),
+        _ => None,
+    })
+}
 
-    Ok(DerivedAddress {
-        ua_string,
-        orchard_receiver_hex,
+pub struct DerivedTex {
+    pub tex_address: String,
+    pub transparent_receiver_hex: String,
+}
+
+/// Derive the TEX (ZIP-320) address at the given index from the UFVK's transparent
+/// component, or `None` when the UFVK has none. A TEX address is a transparent P2PKH
+/// receiver that tells ZIP-320 aware wallets to pay it from transparent funds only.
+pub fn derive_tex_address(ufvk_str: &str, index: u32) -> Result<Option<DerivedTex>> {
+    let (network, _) = Ufvk::decode(ufvk_str)
+        .map_err(|e| anyhow::anyhow!("UFVK decode failed: {:?}", e))?;
+    Ok(derive_transparent_receiver(ufvk_str, index)?.map(|receiver| DerivedTex {
+        tex_address: encode_tex(&receiver, network),
+        transparent_receiver_hex: hex::encode(receiver),
+    }))
+}
+
+/// Whether the UFVK has a transparent component to derive TEX addresses from.
+pub fn has_transparent(ufvk_str: &str) -> bool {
+    Ufvk::decode(ufvk_str).is_ok_and(|(_, ufvk)| {
+        ufvk.items().iter().any(|fvk| matches!(fvk, Fvk::P2pkh(_)))
     })
 }
 
+/// P2PKH key hash at the external child `index` of the UFVK's transparent account key.
+/// Non-hardened BIP32 derivation (CKDpub) along `account/0/index`, the path wallets use
+/// for their own receiving addresses.
+fn derive_transparent_receiver(ufvk_str: &str, index: u32) -> Result<Option<[u8; 20]>> {
+    let (_, ufvk) = Ufvk::decode(ufvk_str)
+        .map_err(|e| anyhow::anyhow!("UFVK decode failed: {:?}", e))?;
+    let Some(account) = ufvk.items().into_iter().find_map(|fvk| match fvk {
+        Fvk::P2pkh(data) => Some(data),
+        _ => None,
+    }) else {
+        return Ok(None);
+    };
+
+    let mut chain_code = [0u8; 32];
+    chain_code.copy_from_slice(&account[..32]);
+    let mut pubkey = [0u8; 33];
+    pubkey.copy_from_slice(&account[32..]);
+
+    let (chain_code, pubkey) = ckd_pub(&chain_code, &pubkey, 0)?;
+    let (_, pubkey) = ckd_pub(&chain_code, &pubkey, index)?;
+    Ok(Some(hash160(&pubkey)))
+}
+
+fn encode_tex(receiver: &[u8; 20], network: NetworkType) -> String {
+    use zcash_address::{ToAddress, ZcashAddress};
+    ZcashAddress::from_tex(network, *receiver).encode()
+}
+
+/// BIP32 public child key derivation for a non-hardened `index`.
+fn ckd_pub(chain_code: &[u8; 32], pubkey: &[u8; 33], index: u32) -> Result<([u8; 32], [u8; 33])> {
+    use hmac::{Hmac, Mac};
+    use k256::elliptic_curve::sec1::ToEncodedPoint;
+    use k256::elliptic_curve::PrimeField;
+
+    anyhow::ensure!(index < 0x8000_0000, "Hardened index {} needs a private key", index);
+
+    let mut mac = Hmac::<sha2::Sha512>::new_from_slice(chain_code)
+        .map_err(|e| anyhow::anyhow!("HMAC key: {}", e))?;
+    mac.update(pubkey);
+    mac.update(&index.to_be_bytes());
+    let i = mac.finalize().into_bytes();
+
+    let tweak: Option<k256::Scalar> =
+        k256::Scalar::from_repr(*k256::FieldBytes::from_slice(&i[..32])).into();
+    let tweak = tweak.ok_or_else(|| anyhow::anyhow!("Child {} is invalid", index))?;
+    let parent = k256::PublicKey::from_sec1_bytes(pubkey)
+        .map_err(|_| anyhow::anyhow!("Transparent account key is not a valid point"))?;
+    let child = k256::ProjectivePoint::GENERATOR * tweak + parent.to_projective();
+    let child = k256::PublicKey::from_affine(child.into())
+        .map_err(|_| anyhow::anyhow!("Child {} is invalid", index))?;
+
+    let mut child_chain_code = [0u8; 32];
+    child_chain_code.copy_from_slice(&i[32..]);
+    let mut child_pubkey = [0u8; 33];
+    child_pubkey.copy_from_slice(child.to_encoded_point(true).as_bytes());
+    Ok((child_chain_code, child_pubkey))
+}
+
+/// RIPEMD160(SHA256(pubkey)), the P2PKH key hash.
+fn hash160(pubkey: &[u8]) -> [u8; 20] {
+    use ripemd::Ripemd160;
+    use sha2::{Digest, Sha256};
+    Ripemd160::digest(Sha256::digest(pubkey)).into()
+}
+
+/// The key checks returned by `GET /api/merchants/me/ufvk/check`.
+#[derive(Debug, Serialize)]
+pub struct UfvkHealth {
+    pub healthy: bool,
+    /// Network the UFVK was encoded for, if it decodes at all.
+    pub network: Option<&'static str>,
+    pub network_matches: bool,
+    pub orchard_valid: bool,
+    /// Address at diversifier index 0, re-derived from the UFVK.
+    pub derived_address: Option<String>,
+    /// Whether the derived address is the merchant's stored payment address.
+    pub address_matches: bool,
+    /// Next diversifier index to be handed to an invoice.
+    pub diversifier_index: i64,
+    pub issues: Vec<String>,
+}
+
+/// Re-run the checks registration relies on against a stored UFVK, so a merchant whose
+/// payments are never detected can see which one fails.
+pub fn check_ufvk(ufvk_str: &str, payment_address: &str, diversifier_index: i64, is_testnet: bool) -> UfvkHealth {
+    let mut health = UfvkHealth {
+        healthy: false,
+        network: None,
+        network_matches: false,
+        orchard_valid: false,
+        derived_address: None,
+        address_matches: false,
+        diversifier_index,
+        issues: Vec::new(),
+    };
+
+    let network = match Ufvk::decode(ufvk_str) {
+        Ok((network, _)) => network,
+        Err(e) => {
+            health.issues.push(format!("UFVK does not decode: {:?}", e));
+            return health;
+        }
+    };
+    health.network = Some(network_name(network));
+    health.network_matches = match network {
+        NetworkType::Main => !is_testnet,
+        NetworkType::Test | NetworkType::Regtest => is_testnet,
+    };
+    if !health.network_matches {
+        health.issues.push(format!(
+            "UFVK is for {} but this server runs on {}",
+            health.network.unwrap_or_default(),
+            if is_testnet { "testnet" } else { "mainnet" },
+        ));
+    }
+
+    if let Err(e) = crate::scanner::decrypt::parse_orchard_fvk(ufvk_str) {
+        health.issues.push(format!("Orchard component is unusable: {}", e));
+        return health;
+    }
+    health.orchard_valid = true;
+
+    match derive_invoice_address(ufvk_str, 0) {
+        Ok(derived) => {
+            health.address_matches = derived.ua_string == payment_address;
+            if !health.address_matches {
+                health.issues.push("Stored payment address was not derived from this UFVK".into());
+            }
+            health.derived_address = Some(derived.ua_string);
+        }
+        Err(e) => health.issues.push(format!("Address derivation failed: {}", e)),
+    }
+
+    if !(1..=u32::MAX as i64).contains(&diversifier_index) {
+        health.issues.push(format!("Diversifier index {} is out of range", diversifier_index));
+    }
+
+    health.healthy = health.issues.is_empty();
+    health
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::scanner::fixtures;
+
+    #[test]
+    fn test_check_ufvk() {
+        let ufvk = fixtures::test_ufvk(7);
+        let address = derive_invoice_address(&ufvk, 0).unwrap().ua_string;
+
+        let health = check_ufvk(&ufvk, &address, 4, true);
+        assert!(health.healthy, "{:?}", health.issues);
+        assert_eq!(health.network, Some("testnet"));
+        assert_eq!(health.derived_address.as_deref(), Some(address.as_str()));
+        assert_eq!(health.diversifier_index, 4);
+
+        let mainnet = check_ufvk(&ufvk, &address, 4, false);
+        assert!(!mainnet.healthy);
+        assert!(!mainnet.network_matches);
+        assert!(mainnet.address_matches);
+
+        let other = derive_invoice_address(&fixtures::test_ufvk(8), 0).unwrap().ua_string;
+        let mismatched = check_ufvk(&ufvk, &other, 4, true);
+        assert!(!mismatched.healthy);
+        assert!(!mismatched.address_matches);
+
+        let garbage = check_ufvk("uviewtest1notakey", &address, 4, true);
+        assert!(!garbage.healthy);
+        assert_eq!(garbage.network, None);
+        assert!(!garbage.orchard_valid);
+    }
+
+    #[test]
+    fn test_ckd_pub_vector() {
+        // BIP32 test vector 1, m/0H -> m/0H/1.
+        let chain_code: [u8; 32] = hex::decode("47fdacbd0f1097043b78c63c20c34ef4ed9a111d980047ad16282c7ae6236141")
+            .unwrap().try_into().unwrap();
+        let pubkey: [u8; 33] = hex::decode("035a784662a4a20a65bf6aab9ae98a6c068a81c52e4b032c0fb5400c706cfccc56")
+            .unwrap().try_into().unwrap();
+        let (child_chain_code, child_pubkey) = ckd_pub(&chain_code, &pubkey, 1).unwrap();
+        assert_eq!(hex::encode(child_chain_code), "2a7857631386ba23dacac34180dd1983734e444fdbf774041578e9b6adb37c19");
+        assert_eq!(hex::encode(child_pubkey), "03501e454bf00751f24b1b489aa925215d66af2234e3891c3b21a52bedb3cd711c");
+        assert!(ckd_pub(&chain_code, &pubkey, 0x8000_0000).is_err());
+    }
+
+    #[test]
+    fn test_transparent_receiver_and_tex() {
+        let generator = hex::decode("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798").unwrap();
+        assert_eq!(hex::encode(hash160(&generator)), "751e76e8199196d454941c45d1b3a323f1433bd6");
+
+        // Orchard-only UFVK: no transparent receiver to offer.
+        assert_eq!(derive_transparent_receiver(&fixtures::test_ufvk(7), 3).unwrap(), None);
+
+        let mut account = [0u8; 65];
+        account[..32].copy_from_slice(&hex::decode("47fdacbd0f1097043b78c63c20c34ef4ed9a111d980047ad16282c7ae6236141").unwrap());
+        account[32..].copy_from_slice(&generator);
+        let ufvk = Ufvk::try_from_items(vec![
+            Fvk::P2pkh(account),
+            Fvk::Orchard(fixtures::test_fvk(7).to_bytes()),
+        ])
+        .unwrap()
+        .encode(&NetworkType::Test);
+        let first = derive_transparent_receiver(&ufvk, 1).unwrap().unwrap();
+        let second = derive_transparent_receiver(&ufvk, 2).unwrap().unwrap();
+        assert_ne!(first, second);
+
+        assert!(has_transparent(&ufvk));
+        assert!(!has_transparent(&fixtures::test_ufvk(7)));
+        let derived = derive_tex_address(&ufvk, 1).unwrap().unwrap();
+        assert_eq!(derived.transparent_receiver_hex, hex::encode(first));
+        assert!(derived.tex_address.starts_with("textest1"), "{}", derived.tex_address);
+        assert!(encode_tex(&first, NetworkType::Main).starts_with("tex1"));
+    }
 
     #[test]
     fn test_derive_different_indices_produce_different_addresses() {
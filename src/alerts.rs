@@ -0,0 +1,253 @@
+//! Operator alerts for system-level incidents: a stale price feed, a stalled scanner,
+//! CipherScan failing, webhook deliveries failing, or a burst of database errors. Sent to
+//! ALERT_EMAIL and/or ALERT_WEBHOOK_URL, once when an incident starts, again every
+//! cooldown while it lasts, and once more when it clears.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::config::Config;
+use crate::invoices::pricing::{PriceService, RateSource};
+use crate::scanner::cipherscan::CipherScans;
+
+/// Deliveries needed in the window before the failure rate is judged.
+const WEBHOOK_MIN_SAMPLE: i64 = 10;
+const WEBHOOK_WINDOW_MINUTES: i64 = 60;
+
+/// Database errors surfaced as 5xx responses since the last check.
+static DB_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+pub fn record_db_error() {
+    DB_ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    PriceFeedStale,
+    ScannerStalled,
+    CipherscanFailing,
+    WebhookFailures,
+    DatabaseErrors,
+}
+
+impl AlertKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::PriceFeedStale => "price_feed_stale",
+            Self::ScannerStalled => "scanner_stalled",
+            Self::CipherscanFailing => "cipherscan_failing",
+            Self::WebhookFailures => "webhook_failures",
+            Self::DatabaseErrors => "database_errors",
+        }
+    }
+
+    pub fn title(&self) -> &'static str {
+        match self {
+            Self::PriceFeedStale => "Price feed is stale",
+            Self::ScannerStalled => "Scanner has stalled",
+            Self::CipherscanFailing => "CipherScan is failing",
+            Self::WebhookFailures => "Webhook deliveries are failing",
+            Self::DatabaseErrors => "Database errors are elevated",
+        }
+    }
+}
+
+/// One notification: an incident starting or still going (`resolved` false), or clearing.
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    pub kind: AlertKind,
+    pub resolved: bool,
+    pub detail: String,
+    pub at: DateTime<Utc>,
+}
+
+/// Which incidents are open and when each was last notified.
+pub struct Tracker {
+    cooldown: Duration,
+    open: HashMap<AlertKind, DateTime<Utc>>,
+}
+
+impl Tracker {
+    pub fn new(cooldown: Duration) -> Self {
+        Self { cooldown, open: HashMap::new() }
+    }
+
+    /// Feed in one check result (`Some(detail)` while failing) and get back the alert to
+    /// send, if any.
+    pub fn observe(&mut self, kind: AlertKind, failing: Option<String>, now: DateTime<Utc>) -> Option<Alert> {
+        match failing {
+            Some(detail) => {
+                if self.open.get(&kind).is_some_and(|last| now - *last < self.cooldown) {
+                    return None;
+                }
+                self.open.insert(kind, now);
+                Some(Alert { kind, resolved: false, detail, at: now })
+            }
+            None => {
+                self.open.remove(&kind)?;
+                Some(Alert { kind, resolved: true, detail: format!("{} is back to normal.", kind.title()), at: now })
+            }
+        }
+    }
+}
+
+/// Check every condition once a minute, as the job `alerts`, and notify the operator.
+/// Does nothing unless ALERT_EMAIL or ALERT_WEBHOOK_URL is set.
+pub fn start(config: Config, pool: SqlitePool, http: reqwest::Client, cipherscans: CipherScans, prices: PriceService) {
+    if !config.alerts_configured() {
+        return;
+    }
+    tracing::info!(
+        email = config.alert_email.is_some(),
+        webhook = config.alert_webhook_url.is_some(),
+        "Operator alerts enabled"
+    );
+
+    let started_at = Utc::now();
+    let tracker = Arc::new(tokio::sync::Mutex::new(Tracker::new(Duration::minutes(config.alert_cooldown_minutes))));
+    crate::jobs::Job::new("alerts", std::time::Duration::from_secs(60)).delay_first().spawn(move || {
+        let (config, pool, http) = (config.clone(), pool.clone(), http.clone());
+        let (cipherscans, prices, tracker) = (cipherscans.clone(), prices.clone(), tracker.clone());
+        async move {
+            let checks = [
+                (AlertKind::PriceFeedStale, check_price_feed(&config, &prices).await),
+                (AlertKind::ScannerStalled, check_scanner(&config, started_at)),
+                (AlertKind::CipherscanFailing, check_cipherscan(&cipherscans)),
+                (AlertKind::WebhookFailures, check_webhooks(&config, &pool).await),
+                (AlertKind::DatabaseErrors, check_database(&config)),
+            ];
+            let mut tracker = tracker.lock().await;
+            for (kind, failing) in checks {
+                if let Some(alert) = tracker.observe(kind, failing, Utc::now()) {
+                    notify(&config, &pool, &http, &alert).await;
+                }
+            }
+            Ok(())
+        }
+    });
+}
+
+async fn check_price_feed(config: &Config, prices: &PriceService) -> Option<String> {
+    let limit = config.alert_price_stale_minutes;
+    match prices.get_rates().await {
+        Ok(rates) if rates.source == RateSource::Operator => None,
+        Ok(rates) => {
+            let age = (Utc::now() - rates.updated_at).num_minutes();
+            (age > limit).then(|| format!("The last ZEC rate is {} minutes old (limit {}). Invoices are being priced with it.", age, limit))
+        }
+        Err(e) => Some(format!("No ZEC rate is available: {}", e)),
+    }
+}
+
+fn check_scanner(config: &Config, started_at: DateTime<Utc>) -> Option<String> {
+    let limit = config.alert_scanner_stall_minutes;
+    let last = crate::scanner::last_pass();
+    let idle = (Utc::now() - last.unwrap_or(started_at)).num_minutes();
+    (idle > limit).then(|| match last {
+        Some(at) => format!("No scan has completed for {} minutes (last at {}). Payments are not being detected.", idle, at.format("%H:%M UTC")),
+        None => format!("No scan has completed in the {} minutes since startup. Payments are not being detected.", idle),
+    })
+}
+
+fn check_cipherscan(cipherscans: &CipherScans) -> Option<String> {
+    let failing: Vec<String> = cipherscans
+        .iter()
+        .map(|(network, client)| (network, client.status()))
+        .filter(|(_, status)| status.circuit != "closed")
+        .map(|(network, status)| format!(
+            "The {} circuit breaker is {} after {} consecutive failed calls.",
+            network, status.circuit, status.consecutive_failures,
+        ))
+        .collect();
+    (!failing.is_empty()).then(|| failing.join(" "))
+}
+
+async fn check_webhooks(config: &Config, pool: &SqlitePool) -> Option<String> {
+    let since = (Utc::now() - Duration::minutes(WEBHOOK_WINDOW_MINUTES))
+        .format("%Y-%m-%dT%H:%M:%SZ").to_string();
+    let (failing, total) = match crate::webhooks::recent_outcomes(pool, &since).await {
+        Ok(counts) => counts,
+        Err(e) => {
+            tracing::warn!(error = %e, "Alert check could not read webhook deliveries");
+            return None;
+        }
+    };
+    let percent = if total > 0 { failing * 100 / total } else { 0 };
+    (total >= WEBHOOK_MIN_SAMPLE && percent >= config.alert_webhook_failure_percent).then(|| format!(
+        "{} of {} webhook deliveries in the last hour failed ({}%).",
+        failing, total, percent,
+    ))
+}
+
+fn check_database(config: &Config) -> Option<String> {
+    let errors = DB_ERRORS.swap(0, Ordering::Relaxed);
+    (errors >= config.alert_db_errors).then(|| format!("{} requests failed with a database error in the last minute.", errors))
+}
+
+async fn notify(config: &Config, pool: &SqlitePool, http: &reqwest::Client, alert: &Alert) {
+    if alert.resolved {
+        tracing::info!(alert = alert.kind.as_str(), "Operator alert resolved");
+    } else {
+        tracing::warn!(alert = alert.kind.as_str(), detail = %alert.detail, "Operator alert");
+    }
+
+    if let Some(url) = &config.alert_webhook_url {
+        let text = format!("[{}] {}: {}", config.network, alert.kind.title(), alert.detail);
+        let body = serde_json::json!({
+            "text": if alert.resolved { format!("Resolved: {}", text) } else { text },
+            "alert": alert.kind,
+            "resolved": alert.resolved,
+            "detail": alert.detail,
+            "network": config.network,
+            "at": alert.at.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+        });
+        let result = http.post(url)
+            .json(&body)
+            .timeout(std::time::Duration::from_secs(10))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status());
+        if let Err(e) = result {
+            tracing::error!(error = %e, "Failed to send operator alert webhook");
+        }
+    }
+
+    // Queued like other email; if the database is what failed, the ops webhook still goes out.
+    if let Some(to) = &config.alert_email {
+        if let Err(e) = crate::email::send_operator_alert(pool, config, to, alert).await {
+            tracing::error!(error = %e, "Failed to send operator alert email");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracker_dedup_and_resolve() {
+        let mut tracker = Tracker::new(Duration::minutes(60));
+        let t0 = Utc::now();
+        let kind = AlertKind::ScannerStalled;
+
+        assert!(tracker.observe(kind, None, t0).is_none());
+        let first = tracker.observe(kind, Some("stalled".into()), t0).unwrap();
+        assert!(!first.resolved);
+        assert!(tracker.observe(kind, Some("stalled".into()), t0 + Duration::minutes(30)).is_none());
+        assert!(tracker.observe(AlertKind::DatabaseErrors, Some("errors".into()), t0).is_some());
+
+        let reminder = tracker.observe(kind, Some("still stalled".into()), t0 + Duration::minutes(60)).unwrap();
+        assert_eq!(reminder.detail, "still stalled");
+
+        let resolved = tracker.observe(kind, None, t0 + Duration::minutes(61)).unwrap();
+        assert!(resolved.resolved);
+        assert!(tracker.observe(kind, None, t0 + Duration::minutes(62)).is_none());
+        assert!(tracker.observe(kind, Some("again".into()), t0 + Duration::minutes(63)).is_some());
+    }
+}
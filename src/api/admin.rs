@@ -0,0 +1,421 @@
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+
+use crate::billing::report;
+use crate::config::Config;
+use crate::db::ReadPool;
+use crate::invoices::pricing::PriceService;
+
+/// Operator endpoints are enabled by setting ADMIN_TOKEN and called with
+/// `Authorization: Bearer <ADMIN_TOKEN>`. Without the variable they answer 404.
+pub fn authorize(req: &HttpRequest, config: &Config) -> Result<(), HttpResponse> {
+    let expected = match config.admin_token.as_deref() {
+        Some(t) => t,
+        None => return Err(HttpResponse::NotFound().finish()),
+    };
+
+    let provided = req.headers()
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+        .unwrap_or_default();
+
+    // Compare digests so the comparison time does not depend on the token prefix.
+    if Sha256::digest(provided.as_bytes()) != Sha256::digest(expected.as_bytes()) {
+        return Err(HttpResponse::Unauthorized().json(serde_json::json!({"error": "Invalid admin token"})));
+    }
+    Ok(())
+}
+
+/// Open a connection to the configured SMTP server and report whether the
+/// handshake (TLS and credentials included) succeeds.
+pub async fn smtp_check(
+    req: HttpRequest,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if let Err(resp) = authorize(&req, &config) {
+        return resp;
+    }
+
+    if !config.smtp_configured() {
+        return HttpResponse::Ok().json(serde_json::json!({
+            "configured": false,
+            "ok": false,
+            "error": "SMTP_HOST and SMTP_FROM must be set",
+        }));
+    }
+
+    let result = crate::email::check_connection(&config).await;
+    let body = serde_json::json!({
+        "configured": true,
+        "ok": result.is_ok(),
+        "host": config.smtp_host,
+        "port": config.smtp_port,
+        "tls": config.smtp_tls.as_str(),
+        "pool_size": config.smtp_pool_size,
+        "error": result.as_ref().err().map(|e| format!("{:#}", e)),
+    });
+
+    if result.is_ok() {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::BadGateway().json(body)
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct EmailQuery {
+    pub status: Option<String>,
+    pub limit: Option<i64>,
+}
+
+/// Outgoing email queue, newest first. Defaults to `status=failed`.
+pub async fn list_emails(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
+    query: web::Query<EmailQuery>,
+) -> HttpResponse {
+    if let Err(resp) = authorize(&req, &config) {
+        return resp;
+    }
+
+    let status = query.status.as_deref().unwrap_or("failed");
+    if !matches!(status, "pending" | "sent" | "failed") {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "status must be pending, sent or failed"
+        }));
+    }
+    let limit = query.limit.unwrap_or(50).clamp(1, 500);
+
+    let rows = sqlx::query_as::<_, (String, String, String, String, String, i64, Option<String>, Option<String>, Option<String>, String)>(
+        "SELECT id, kind, to_address, subject, status, attempts, last_error, next_attempt_at, sent_at, created_at
+         FROM emails WHERE status = ? ORDER BY created_at DESC LIMIT ?"
+    )
+    .bind(status)
+    .bind(limit)
+    .fetch_all(pool.get_ref())
+    .await;
+
+    match rows {
+        Ok(rows) => {
+            let emails: Vec<_> = rows.into_iter().map(|r| serde_json::json!({
+                "id": r.0,
+                "kind": r.1,
+                "to": r.2,
+                "subject": r.3,
+                "status": r.4,
+                "attempts": r.5,
+                "last_error": r.6,
+                "next_attempt_at": r.7,
+                "sent_at": r.8,
+                "created_at": r.9,
+            })).collect();
+            HttpResponse::Ok().json(serde_json::json!({ "emails": emails }))
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to list emails");
+            HttpResponse::InternalServerError().json(serde_json::json!({"error": "Internal error"}))
+        }
+    }
+}
+
+/// Put a failed email back in the queue for another round of attempts.
+pub async fn retry_email(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    if let Err(resp) = authorize(&req, &config) {
+        return resp;
+    }
+
+    let result = sqlx::query(
+        "UPDATE emails SET status = 'pending', attempts = 0, next_attempt_at = NULL
+         WHERE id = ? AND status = 'failed'"
+    )
+    .bind(path.into_inner())
+    .execute(pool.get_ref())
+    .await;
+
+    match result {
+        Ok(r) if r.rows_affected() > 0 => HttpResponse::Ok().json(serde_json::json!({"status": "pending"})),
+        Ok(_) => HttpResponse::NotFound().json(serde_json::json!({"error": "No failed email with that ID"})),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to requeue email");
+            HttpResponse::InternalServerError().json(serde_json::json!({"error": "Internal error"}))
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct SetRatesRequest {
+    pub zec_eur: f64,
+    pub zec_usd: f64,
+}
+
+/// Set or refresh the operator rate. It replaces the price feed until cleared.
+pub async fn set_rates(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    price_service: web::Data<PriceService>,
+    body: web::Json<SetRatesRequest>,
+) -> HttpResponse {
+    if let Err(resp) = authorize(&req, &config) {
+        return resp;
+    }
+
+    match price_service.set_manual(body.zec_eur, body.zec_usd).await {
+        Ok(rates) => HttpResponse::Ok().json(rates),
+        Err(e) => HttpResponse::BadRequest().json(serde_json::json!({"error": e.to_string()})),
+    }
+}
+
+/// Return to the price feed. Refused in static-rate mode, which has no feed to return to.
+pub async fn clear_rates(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    price_service: web::Data<PriceService>,
+) -> HttpResponse {
+    if let Err(resp) = authorize(&req, &config) {
+        return resp;
+    }
+
+    if config.fixed_rates().is_some() {
+        return HttpResponse::Conflict().json(serde_json::json!({
+            "error": "FIXED_ZEC_EUR/FIXED_ZEC_USD are set; POST a new rate instead"
+        }));
+    }
+    if price_service.clear_manual().await {
+        HttpResponse::Ok().json(serde_json::json!({"source": "feed"}))
+    } else {
+        HttpResponse::NotFound().json(serde_json::json!({"error": "No operator rate is set"}))
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct RevenueQuery {
+    pub months: Option<i64>,
+}
+
+/// Fees accrued, auto-collected and outstanding per month, unpaid settlement invoices by
+/// age, and merchants per billing status.
+pub async fn revenue(
+    req: HttpRequest,
+    pool: web::Data<ReadPool>,
+    config: web::Data<Config>,
+    query: web::Query<RevenueQuery>,
+) -> HttpResponse {
+    if let Err(resp) = authorize(&req, &config) {
+        return resp;
+    }
+
+    let months = query.months.unwrap_or(12).clamp(1, 120);
+    let result = async {
+        Ok::<_, crate::billing::BillingError>(serde_json::json!({
+            "fee_enabled": config.fee_enabled(),
+            "fee_rate": config.fee_rate,
+            "fee_currency": config.fee_currency.as_str(),
+            "periods": report::revenue_by_period(pool.get_ref(), months).await?,
+            "settlement_aging": report::settlement_aging(pool.get_ref()).await?,
+            "merchants_by_status": report::status_counts(pool.get_ref()).await?,
+        }))
+    }.await;
+
+    match result {
+        Ok(body) => HttpResponse::Ok().json(body),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct TopMerchantsQuery {
+    pub days: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+/// Merchants by confirmed volume over the last `days` (default 30).
+pub async fn top_merchants(
+    req: HttpRequest,
+    pool: web::Data<ReadPool>,
+    config: web::Data<Config>,
+    query: web::Query<TopMerchantsQuery>,
+) -> HttpResponse {
+    if let Err(resp) = authorize(&req, &config) {
+        return resp;
+    }
+
+    let days = query.days.unwrap_or(30).clamp(1, 365);
+    let limit = query.limit.unwrap_or(20).clamp(1, 100);
+    match report::top_merchants(pool.get_ref(), days, limit).await {
+        Ok(merchants) => HttpResponse::Ok().json(serde_json::json!({
+            "days": days,
+            "merchants": merchants,
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct DiversifierQuery {
+    pub limit: Option<i64>,
+}
+
+/// Diversifier index consumption per merchant, most consumed first.
+pub async fn diversifiers(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
+    query: web::Query<DiversifierQuery>,
+) -> HttpResponse {
+    if let Err(resp) = authorize(&req, &config) {
+        return resp;
+    }
+
+    let limit = query.limit.unwrap_or(20).clamp(1, 100);
+    match crate::merchants::diversifier_usage(pool.get_ref(), limit).await {
+        Ok(merchants) => HttpResponse::Ok().json(serde_json::json!({
+            "max_index": crate::merchants::MAX_DIVERSIFIER_INDEX,
+            "merchants": merchants,
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// Exempt a merchant from fees or grant fee-free days or volume.
+pub async fn update_fees(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
+    path: web::Path<String>,
+    body: web::Json<crate::billing::FeePromoUpdate>,
+) -> HttpResponse {
+    if let Err(resp) = authorize(&req, &config) {
+        return resp;
+    }
+
+    if body.fee_free_days.is_some_and(|d| !(0..=3650).contains(&d))
+        || body.fee_free_zec.is_some_and(|z| !z.is_finite() || z < 0.0)
+    {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "fee_free_days must be 0-3650 and fee_free_zec non-negative"
+        }));
+    }
+
+    match crate::billing::update_fee_promo(pool.get_ref(), &path.into_inner(), &body).await {
+        Ok(promo) => HttpResponse::Ok().json(promo),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct WalletQuery {
+    pub days: Option<i64>,
+}
+
+/// CipherScan circuit state and per-endpoint request counts and latency since startup, for
+/// the primary network and under `networks` for each one scanned.
+pub async fn cipherscan_status(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    cipherscans: web::Data<crate::scanner::cipherscan::CipherScans>,
+) -> HttpResponse {
+    if let Err(resp) = authorize(&req, &config) {
+        return resp;
+    }
+    HttpResponse::Ok().json(cipherscans.status())
+}
+
+/// Background jobs with their interval, run counts and how their last run went, and the
+/// names of those failing or stalled.
+pub async fn jobs(req: HttpRequest, config: web::Data<Config>) -> HttpResponse {
+    if let Err(resp) = authorize(&req, &config) {
+        return resp;
+    }
+    HttpResponse::Ok().json(serde_json::json!({
+        "jobs": crate::jobs::statuses(),
+        "unhealthy": crate::jobs::unhealthy(),
+    }))
+}
+
+/// Wallet compatibility matrix: per wallet and URI format, how often the fee output arrived.
+pub async fn wallet_compatibility(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
+    query: web::Query<WalletQuery>,
+) -> HttpResponse {
+    if let Err(resp) = authorize(&req, &config) {
+        return resp;
+    }
+
+    let days = query.days.unwrap_or(90).clamp(1, 365);
+    match report::wallet_compatibility(pool.get_ref(), days).await {
+        Ok(rows) => HttpResponse::Ok().json(serde_json::json!({
+            "days": days,
+            "wallets": rows,
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// Every merchant's UFVK and webhook secret, encrypted to `BACKUP_RECIPIENT` (an age file).
+/// Answers 409 when no recipient is configured.
+pub async fn export_backup(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
+) -> HttpResponse {
+    if let Err(resp) = authorize(&req, &config) {
+        return resp;
+    }
+    let Some(recipient) = config.backup_recipient.as_deref() else {
+        return HttpResponse::Conflict().json(serde_json::json!({
+            "error": "Set BACKUP_RECIPIENT to an age public key to export backups"
+        }));
+    };
+
+    match crate::backup::export(pool.get_ref(), &config.network, &config.encryption_key, recipient).await {
+        Ok(armored) => {
+            let filename = format!("cipherpay-secrets-{}.age", chrono::Utc::now().format("%Y%m%d-%H%M%S"));
+            tracing::warn!("Merchant secrets backup exported");
+            HttpResponse::Ok()
+                .content_type("application/octet-stream")
+                .insert_header(("Content-Disposition", format!("attachment; filename=\"{}\"", filename)))
+                .insert_header(("Cache-Control", "no-store"))
+                .body(armored)
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to export secrets backup");
+            HttpResponse::InternalServerError().json(serde_json::json!({"error": format!("{:#}", e)}))
+        }
+    }
+}
+
+/// Restore merchant secrets from a decrypted backup, re-encrypted under this instance's key.
+pub async fn restore_backup(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
+    body: web::Json<crate::backup::SecretsBackup>,
+) -> HttpResponse {
+    if let Err(resp) = authorize(&req, &config) {
+        return resp;
+    }
+    if body.network != config.network {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": format!("Backup is from {}, this instance runs {}", body.network, config.network)
+        }));
+    }
+
+    match crate::backup::restore(pool.get_ref(), &config.encryption_key, &body).await {
+        Ok(summary) => HttpResponse::Ok().json(summary),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to restore secrets backup");
+            HttpResponse::BadRequest().json(serde_json::json!({"error": format!("{:#}", e)}))
+        }
+    }
+}
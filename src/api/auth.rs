@@ -1,16 +1,17 @@
-use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
 use actix_web::cookie::{Cookie, SameSite};
 use chrono::{Duration, Utc};
 use serde::Deserialize;
 use sqlx::SqlitePool;
-use uuid::Uuid;
 
+use super::extract::SessionMerchant;
 use crate::config::Config;
+use crate::invoices::views::MerchantInvoice;
 use crate::merchants;
 use crate::validation;
 
 const SESSION_COOKIE: &str = "cpay_session";
-const SESSION_HOURS: i64 = 24;
+const ELEVATION_MINUTES: i64 = 5;
 
 #[derive(Debug, Deserialize)]
 pub struct CreateSessionRequest {
@@ -19,13 +20,17 @@ pub struct CreateSessionRequest {
 
 /// POST /api/auth/session -- exchange dashboard token for an HttpOnly session cookie
 pub async fn create_session(
+    req: HttpRequest,
     pool: web::Data<SqlitePool>,
     config: web::Data<Config>,
     body: web::Json<CreateSessionRequest>,
 ) -> HttpResponse {
+    let client_ip = crate::client_ip::from_request(&req).map(|ip| ip.to_string());
+
     let merchant = match merchants::authenticate_dashboard(pool.get_ref(), &body.token, &config.encryption_key).await {
         Ok(Some(m)) => m,
         Ok(None) => {
+            tracing::warn!(client_ip = ?client_ip, "Session rejected: invalid dashboard token");
             return HttpResponse::Unauthorized().json(serde_json::json!({
                 "error": "Invalid dashboard token"
             }));
@@ -38,25 +43,19 @@ pub async fn create_session(
         }
     };
 
-    let session_id = Uuid::new_v4().to_string();
-    let expires_at = (Utc::now() + Duration::hours(SESSION_HOURS))
-        .format("%Y-%m-%dT%H:%M:%SZ")
-        .to_string();
+    let session_id = match merchants::create_session(
+        pool.get_ref(), &merchant.id, client_ip.as_deref(), config.session_max_age_hours,
+    ).await {
+        Ok(id) => id,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to create session");
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Failed to create session"
+            }));
+        }
+    };
 
-    if let Err(e) = sqlx::query(
-        "INSERT INTO sessions (id, merchant_id, expires_at) VALUES (?, ?, ?)"
-    )
-    .bind(&session_id)
-    .bind(&merchant.id)
-    .bind(&expires_at)
-    .execute(pool.get_ref())
-    .await
-    {
-        tracing::error!(error = %e, "Failed to create session");
-        return HttpResponse::InternalServerError().json(serde_json::json!({
-            "error": "Failed to create session"
-        }));
-    }
+    tracing::info!(merchant_id = %merchant.id, client_ip = ?client_ip, "Dashboard session created");
 
     let cookie = build_session_cookie(&session_id, &config, false);
 
@@ -88,13 +87,22 @@ pub async fn logout(
         .json(serde_json::json!({ "status": "logged_out" }))
 }
 
-/// GET /api/merchants/me -- get current merchant info from session cookie
-pub async fn me(
+#[derive(Debug, Deserialize)]
+pub struct ElevateRequest {
+    pub token: String,
+}
+
+/// POST /api/auth/elevate -- re-enter the dashboard token to unlock destructive
+/// actions (key regeneration, webhook URL changes, account deletion) for a few minutes.
+pub async fn elevate(
     req: HttpRequest,
+    SessionMerchant(merchant): SessionMerchant,
     pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
+    body: web::Json<ElevateRequest>,
 ) -> HttpResponse {
-    let merchant = match resolve_session(&req, &pool).await {
-        Some(m) => m,
+    let session_id = match extract_session_id(&req) {
+        Some(id) => id,
         None => {
             return HttpResponse::Unauthorized().json(serde_json::json!({
                 "error": "Not authenticated"
@@ -102,7 +110,106 @@ pub async fn me(
         }
     };
 
-    let stats = get_merchant_stats(pool.get_ref(), &merchant.id).await;
+    let confirmed = match merchants::authenticate_dashboard(pool.get_ref(), &body.token, &config.encryption_key).await {
+        Ok(Some(m)) => m.id == merchant.id,
+        Ok(None) => false,
+        Err(e) => {
+            tracing::error!(error = %e, "Elevation auth error");
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            }));
+        }
+    };
+
+    let client_ip = crate::client_ip::from_request(&req);
+
+    if !confirmed {
+        tracing::warn!(merchant_id = %merchant.id, client_ip = ?client_ip, "Elevation rejected: wrong dashboard token");
+        return HttpResponse::Unauthorized().json(serde_json::json!({
+            "error": "Invalid dashboard token"
+        }));
+    }
+
+    let elevated_until = (Utc::now() + Duration::minutes(ELEVATION_MINUTES))
+        .format("%Y-%m-%dT%H:%M:%SZ")
+        .to_string();
+
+    // The elevated session gets a new ID, so one captured before the challenge cannot
+    // ride on it.
+    let rotated = match merchants::rotate_session(pool.get_ref(), &session_id, Some(&elevated_until)).await {
+        Ok(Some(id)) => id,
+        Ok(None) => {
+            return HttpResponse::Unauthorized().json(serde_json::json!({
+                "error": "Not authenticated"
+            }));
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to elevate session");
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            }));
+        }
+    };
+
+    tracing::info!(merchant_id = %merchant.id, client_ip = ?client_ip, "Session elevated and rotated");
+    HttpResponse::Ok().cookie(build_session_cookie(&rotated, &config, false)).json(serde_json::json!({
+        "status": "elevated",
+        "elevated_until": elevated_until,
+    }))
+}
+
+/// Returns true if the request's session was recently confirmed via /api/auth/elevate.
+pub async fn is_elevated(req: &HttpRequest, pool: &SqlitePool) -> bool {
+    let session_id = match extract_session_id(req) {
+        Some(id) => id,
+        None => return false,
+    };
+
+    sqlx::query_scalar::<_, i32>(
+        "SELECT COUNT(*) FROM sessions
+         WHERE id = ? AND elevated_until > strftime('%Y-%m-%dT%H:%M:%SZ', 'now')
+         AND expires_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now')"
+    )
+    .bind(&session_id)
+    .fetch_one(pool)
+    .await
+    .unwrap_or(0) > 0
+}
+
+/// 403 returned when a destructive action is attempted without a recent elevation.
+pub fn elevation_required() -> HttpResponse {
+    HttpResponse::Forbidden().json(serde_json::json!({
+        "error": "Please re-enter your dashboard token to confirm this action",
+        "code": "elevation_required",
+    }))
+}
+
+/// GET /api/merchants/me -- get current merchant info from session cookie
+pub async fn me(
+    SessionMerchant(merchant): SessionMerchant,
+    pool: web::Data<SqlitePool>,
+    merchants: web::Data<crate::services::MerchantService>,
+) -> HttpResponse {
+    let stats = merchants.stats(&merchant).await;
+    let tax = crate::invoices::tax::get_settings(pool.get_ref(), &merchant.id)
+        .await
+        .unwrap_or_default();
+    let (slug, store_about, tex_enabled, strict_address_mode, memo_lookup): (Option<String>, Option<String>, bool, bool, String) =
+        sqlx::query_as("SELECT slug, store_about, tex_enabled, strict_address_mode, memo_lookup FROM merchants WHERE id = ?")
+            .bind(&merchant.id)
+            .fetch_one(pool.get_ref())
+            .await
+            .unwrap_or_default();
+    let public_ref = slug.clone().unwrap_or_else(|| merchant.id.clone());
+    let nostr = crate::notifiers::nostr::get_profile(pool.get_ref(), &merchant.id)
+        .await
+        .unwrap_or_default();
+    let chat = crate::notifiers::chat::get_profile(pool.get_ref(), &merchant.id)
+        .await
+        .unwrap_or_default();
+    let webhook_events = crate::webhooks::get_events(pool.get_ref(), &merchant.id)
+        .await
+        .unwrap_or_default();
 
     let masked_secret = if merchant.webhook_secret.len() > 12 {
         format!("{}...", &merchant.webhook_secret[..12])
@@ -132,51 +239,37 @@ pub async fn me(
         "name": merchant.name,
         "payment_address": merchant.payment_address,
         "webhook_url": merchant.webhook_url,
+        "webhook_events": webhook_events,
         "webhook_secret_preview": masked_secret,
         "has_recovery_email": merchant.recovery_email.is_some(),
         "recovery_email_preview": masked_email,
         "created_at": merchant.created_at,
+        "tax": tax,
+        "slug": slug,
+        "store_url": format!("/store/{}", public_ref),
+        "catalog_url": format!("/api/v1/merchants/{}/catalog", public_ref),
+        "store_about": store_about,
+        "tex_enabled": tex_enabled,
+        "tex_available": crate::addresses::has_transparent(&merchant.ufvk),
+        "strict_address_mode": strict_address_mode,
+        "memo_lookup": crate::invoices::views::MemoLookup::parse(&memo_lookup),
+        "nostr": nostr,
+        "chat": chat,
         "stats": stats,
     }))
 }
 
 /// GET /api/merchants/me/invoices -- list invoices for the authenticated merchant
 pub async fn my_invoices(
-    req: HttpRequest,
+    SessionMerchant(merchant): SessionMerchant,
     pool: web::Data<SqlitePool>,
 ) -> HttpResponse {
-    let merchant = match resolve_session(&req, &pool).await {
-        Some(m) => m,
-        None => {
-            return HttpResponse::Unauthorized().json(serde_json::json!({
-                "error": "Not authenticated"
-            }));
-        }
-    };
-
-    let rows = sqlx::query_as::<_, crate::invoices::Invoice>(
-        "SELECT id, merchant_id, memo_code, product_name, size,
-         price_eur, price_usd, currency, price_zec, zec_rate_at_creation, payment_address, zcash_uri,
-         NULL AS merchant_name,
-         refund_address, status, detected_txid, detected_at,
-         confirmed_at, refunded_at, expires_at, purge_after, created_at,
-         orchard_receiver_hex, diversifier_index,
-         price_zatoshis, received_zatoshis
-         FROM invoices WHERE merchant_id = ?
-         ORDER BY created_at DESC LIMIT 100"
-    )
-    .bind(&merchant.id)
-    .fetch_all(pool.get_ref())
-    .await;
-
-    match rows {
-        Ok(invoices) => HttpResponse::Ok().json(invoices),
-        Err(e) => {
-            tracing::error!(error = %e, "Failed to list merchant invoices");
-            HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Internal error"
-            }))
+    match crate::invoices::list_for_merchant(pool.get_ref(), &merchant.id, &Default::default(), 100).await {
+        Ok(invoices) => {
+            let body: Vec<_> = invoices.iter().map(MerchantInvoice::new).collect();
+            HttpResponse::Ok().json(body)
         }
+        Err(e) => e.error_response(),
     }
 }
 
@@ -194,7 +287,9 @@ pub async fn resolve_session(
 ) -> Option<merchants::Merchant> {
     let session_id = extract_session_id(req)?;
     let config = req.app_data::<web::Data<crate::config::Config>>()?;
-    merchants::get_by_session(pool, &session_id, &config.encryption_key).await.ok()?
+    let merchant = merchants::get_by_session(pool, &session_id, config.session_max_age_hours, &config.encryption_key).await.ok()??;
+    crate::request_log::tag_merchant(req, &merchant.id);
+    Some(merchant)
 }
 
 fn build_session_cookie<'a>(value: &str, config: &Config, clear: bool) -> Cookie<'a> {
@@ -213,7 +308,7 @@ fn build_session_cookie<'a>(value: &str, config: &Config, clear: bool) -> Cookie
     if clear {
         builder = builder.max_age(actix_web::cookie::time::Duration::ZERO);
     } else {
-        builder = builder.max_age(actix_web::cookie::time::Duration::hours(SESSION_HOURS));
+        builder = builder.max_age(actix_web::cookie::time::Duration::hours(config.session_max_age_hours));
     }
 
     builder.finish()
@@ -224,34 +319,69 @@ pub struct UpdateMerchantRequest {
     pub name: Option<String>,
     pub webhook_url: Option<String>,
     pub recovery_email: Option<String>,
+    pub tax: Option<crate::invoices::tax::TaxSettings>,
+    /// About section shown on the hosted storefront; empty clears it.
+    pub store_about: Option<String>,
+    /// Vanity slug for public URLs. Can only be set once.
+    pub slug: Option<String>,
+    /// Nostr relays and tagged pubkey for confirmation notes; no relays disables them.
+    pub nostr: Option<crate::notifiers::nostr::NostrSettings>,
+    /// Slack/Discord webhook URLs and Matrix room for payment and billing messages.
+    pub chat: Option<crate::notifiers::chat::ChatSettings>,
+    /// Offer a TEX address on new invoices for buyers paying from exchanges. Needs a
+    /// UFVK with a transparent component.
+    pub tex_enabled: Option<bool>,
+    /// Webhook events to receive (see `webhooks::EVENTS`); empty turns them all off.
+    pub webhook_events: Option<Vec<String>>,
+    /// Match payments by invoice address only, never by memo, and report the rest.
+    pub strict_address_mode: Option<bool>,
+    /// What public memo code lookups show of the merchant's invoices.
+    pub memo_lookup: Option<crate::invoices::views::MemoLookup>,
 }
 
-/// PATCH /api/merchants/me -- update name, webhook URL, and/or recovery email.
+/// PATCH /api/merchants/me -- update name, slug (once), webhook URL and events, recovery email, tax settings, storefront text, Nostr notes, chat channels, TEX addresses, strict address mode, and/or memo lookup exposure.
+/// Changing the webhook URL or chat channels requires an elevated session.
 ///
 /// Payment address is intentionally NOT editable after registration.
 /// It is cryptographically tied to the UFVK used for trial decryption.
 /// Allowing changes would either:
 ///   - Break payment detection (new address from different wallet)
 ///   - Enable session-hijack fund diversion (attacker changes to their address)
+///
 /// Merchants who need a new address must re-register with a new UFVK.
 pub async fn update_me(
     req: HttpRequest,
+    SessionMerchant(merchant): SessionMerchant,
     pool: web::Data<SqlitePool>,
     config: web::Data<Config>,
     body: web::Json<UpdateMerchantRequest>,
 ) -> HttpResponse {
-    let merchant = match resolve_session(&req, &pool).await {
-        Some(m) => m,
-        None => {
-            return HttpResponse::Unauthorized().json(serde_json::json!({
-                "error": "Not authenticated"
-            }));
-        }
-    };
-
-    if let Err(e) = validate_update(&body, config.is_testnet()) {
+    if let Err(e) = validate_update(&body, merchant.network == "testnet") {
         return HttpResponse::BadRequest().json(e.to_json());
     }
+    if body.tex_enabled == Some(true) && !crate::addresses::has_transparent(&merchant.ufvk) {
+        return HttpResponse::BadRequest().json(validation::ValidationError::invalid(
+            "tex_enabled", "the UFVK has no transparent component to derive TEX addresses from",
+        ).to_json());
+    }
+
+    let webhook_changed = body.webhook_url.as_ref()
+        .is_some_and(|url| merchant.webhook_url.as_deref().unwrap_or("") != url.as_str());
+    if (webhook_changed || body.chat.is_some()) && !is_elevated(&req, &pool).await {
+        return elevation_required();
+    }
+
+    if let Some(ref slug) = body.slug {
+        match merchants::set_slug(pool.get_ref(), &merchant.id, slug).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return HttpResponse::Conflict().json(serde_json::json!({
+                    "error": "Slug is already set and cannot be changed"
+                }));
+            }
+            Err(e) => return e.error_response(),
+        }
+    }
 
     if let Some(ref name) = body.name {
         sqlx::query("UPDATE merchants SET name = ? WHERE id = ?")
@@ -273,6 +403,16 @@ pub async fn update_me(
         tracing::info!(merchant_id = %merchant.id, "Webhook URL updated");
     }
 
+    if let Some(ref events) = body.webhook_events {
+        if let Err(e) = crate::webhooks::set_events(pool.get_ref(), &merchant.id, events).await {
+            tracing::error!(error = %e, "Failed to update webhook events");
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            }));
+        }
+        tracing::info!(merchant_id = %merchant.id, ?events, "Webhook events updated");
+    }
+
     if let Some(ref email) = body.recovery_email {
         let val = if email.is_empty() { None } else { Some(email.as_str()) };
         sqlx::query("UPDATE merchants SET recovery_email = ? WHERE id = ?")
@@ -284,64 +424,114 @@ pub async fn update_me(
         tracing::info!(merchant_id = %merchant.id, "Recovery email updated");
     }
 
+    if let Some(ref about) = body.store_about {
+        sqlx::query("UPDATE merchants SET store_about = ? WHERE id = ?")
+            .bind(if about.is_empty() { None } else { Some(about.as_str()) })
+            .bind(&merchant.id)
+            .execute(pool.get_ref())
+            .await
+            .ok();
+        tracing::info!(merchant_id = %merchant.id, "Storefront about updated");
+    }
+
+    if let Some(tex_enabled) = body.tex_enabled {
+        sqlx::query("UPDATE merchants SET tex_enabled = ? WHERE id = ?")
+            .bind(tex_enabled)
+            .bind(&merchant.id)
+            .execute(pool.get_ref())
+            .await
+            .ok();
+        tracing::info!(merchant_id = %merchant.id, tex_enabled, "TEX addresses updated");
+    }
+
+    if let Some(strict) = body.strict_address_mode {
+        sqlx::query("UPDATE merchants SET strict_address_mode = ? WHERE id = ?")
+            .bind(strict)
+            .bind(&merchant.id)
+            .execute(pool.get_ref())
+            .await
+            .ok();
+        tracing::info!(merchant_id = %merchant.id, strict, "Strict address mode updated");
+    }
+
+    if let Some(memo_lookup) = body.memo_lookup {
+        sqlx::query("UPDATE merchants SET memo_lookup = ? WHERE id = ?")
+            .bind(memo_lookup.as_str())
+            .bind(&merchant.id)
+            .execute(pool.get_ref())
+            .await
+            .ok();
+        tracing::info!(merchant_id = %merchant.id, memo_lookup = memo_lookup.as_str(), "Memo lookup exposure updated");
+    }
+
+    if let Some(ref tax) = body.tax {
+        if let Err(e) = crate::invoices::tax::update_settings(pool.get_ref(), &merchant.id, tax).await {
+            return e.error_response();
+        }
+    }
+
+    if let Some(ref nostr) = body.nostr {
+        if let Err(e) = crate::notifiers::nostr::update_settings(pool.get_ref(), &merchant.id, nostr, &config.encryption_key).await {
+            tracing::error!(error = %e, "Failed to update Nostr settings");
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            }));
+        }
+    }
+
+    if let Some(ref chat) = body.chat {
+        if let Err(e) = crate::notifiers::chat::update_settings(pool.get_ref(), &merchant.id, chat, &config.encryption_key).await {
+            tracing::error!(error = %e, "Failed to update chat settings");
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            }));
+        }
+    }
+
     HttpResponse::Ok().json(serde_json::json!({ "status": "updated" }))
 }
 
-/// POST /api/merchants/me/regenerate-api-key
+/// POST /api/merchants/me/regenerate-api-key (requires elevated session)
 pub async fn regenerate_api_key(
     req: HttpRequest,
+    SessionMerchant(merchant): SessionMerchant,
     pool: web::Data<SqlitePool>,
 ) -> HttpResponse {
-    let merchant = match resolve_session(&req, &pool).await {
-        Some(m) => m,
-        None => return HttpResponse::Unauthorized().json(serde_json::json!({ "error": "Not authenticated" })),
-    };
+    if !is_elevated(&req, &pool).await {
+        return elevation_required();
+    }
 
     match merchants::regenerate_api_key(pool.get_ref(), &merchant.id).await {
         Ok(new_key) => HttpResponse::Ok().json(serde_json::json!({ "api_key": new_key })),
-        Err(e) => {
-            tracing::error!(error = %e, "Failed to regenerate API key");
-            HttpResponse::InternalServerError().json(serde_json::json!({ "error": "Failed to regenerate" }))
-        }
+        Err(e) => e.error_response(),
     }
 }
 
-/// POST /api/merchants/me/regenerate-dashboard-token
+/// POST /api/merchants/me/regenerate-dashboard-token (requires elevated session)
 pub async fn regenerate_dashboard_token(
     req: HttpRequest,
+    SessionMerchant(merchant): SessionMerchant,
     pool: web::Data<SqlitePool>,
 ) -> HttpResponse {
-    let merchant = match resolve_session(&req, &pool).await {
-        Some(m) => m,
-        None => return HttpResponse::Unauthorized().json(serde_json::json!({ "error": "Not authenticated" })),
-    };
+    if !is_elevated(&req, &pool).await {
+        return elevation_required();
+    }
 
     match merchants::regenerate_dashboard_token(pool.get_ref(), &merchant.id).await {
         Ok(new_token) => HttpResponse::Ok().json(serde_json::json!({ "dashboard_token": new_token })),
-        Err(e) => {
-            tracing::error!(error = %e, "Failed to regenerate dashboard token");
-            HttpResponse::InternalServerError().json(serde_json::json!({ "error": "Failed to regenerate" }))
-        }
+        Err(e) => e.error_response(),
     }
 }
 
 /// POST /api/merchants/me/regenerate-webhook-secret
 pub async fn regenerate_webhook_secret(
-    req: HttpRequest,
+    SessionMerchant(merchant): SessionMerchant,
     pool: web::Data<SqlitePool>,
     config: web::Data<Config>,
 ) -> HttpResponse {
-    let merchant = match resolve_session(&req, &pool).await {
-        Some(m) => m,
-        None => return HttpResponse::Unauthorized().json(serde_json::json!({ "error": "Not authenticated" })),
-    };
-
     match merchants::regenerate_webhook_secret(pool.get_ref(), &merchant.id, &config.encryption_key).await {
         Ok(new_secret) => HttpResponse::Ok().json(serde_json::json!({ "webhook_secret": new_secret })),
-        Err(e) => {
-            tracing::error!(error = %e, "Failed to regenerate webhook secret");
-            HttpResponse::InternalServerError().json(serde_json::json!({ "error": "Failed to regenerate" }))
-        }
+        Err(e) => e.error_response(),
     }
 }
 
@@ -379,7 +569,7 @@ pub async fn recover(
             .await
             .map_err(|e| tracing::error!(error = %e, "Failed to create recovery token"))?;
 
-        crate::email::send_recovery_email(&config, &body.email, &token)
+        crate::email::send_recovery_email(pool.get_ref(), &config, &body.email, &token)
             .await
             .map_err(|e| tracing::error!(error = %e, "Failed to send recovery email"))?;
 
@@ -407,14 +597,28 @@ pub struct RecoverConfirmRequest {
     pub token: String,
 }
 
-/// POST /api/auth/recover/confirm -- exchange recovery token for new dashboard token
+/// POST /api/auth/recover/confirm -- exchange recovery token for new dashboard token.
+/// Every existing session is ended and the caller gets a fresh one, never the ID its
+/// cookie carried before.
 pub async fn recover_confirm(
+    req: HttpRequest,
     pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
     body: web::Json<RecoverConfirmRequest>,
 ) -> HttpResponse {
     match merchants::confirm_recovery_token(pool.get_ref(), &body.token).await {
-        Ok(Some(new_dashboard_token)) => {
-            HttpResponse::Ok().json(serde_json::json!({
+        Ok(Some((merchant_id, new_dashboard_token))) => {
+            let client_ip = crate::client_ip::from_request(&req).map(|ip| ip.to_string());
+            let cookie = match merchants::create_session(
+                pool.get_ref(), &merchant_id, client_ip.as_deref(), config.session_max_age_hours,
+            ).await {
+                Ok(session_id) => build_session_cookie(&session_id, &config, false),
+                Err(e) => {
+                    tracing::error!(error = %e, "Failed to create session after recovery");
+                    build_session_cookie("", &config, true)
+                }
+            };
+            HttpResponse::Ok().cookie(cookie).json(serde_json::json!({
                 "dashboard_token": new_dashboard_token,
                 "message": "Account recovered. Save your new dashboard token."
             }))
@@ -424,37 +628,10 @@ pub async fn recover_confirm(
                 "error": "Invalid or expired recovery token"
             }))
         }
-        Err(e) => {
-            tracing::error!(error = %e, "Recovery confirmation failed");
-            HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Recovery failed"
-            }))
-        }
+        Err(e) => e.error_response(),
     }
 }
 
-async fn get_merchant_stats(pool: &SqlitePool, merchant_id: &str) -> serde_json::Value {
-    let row = sqlx::query_as::<_, (i64, i64, f64)>(
-        "SELECT
-            COUNT(*) as total,
-            COUNT(CASE WHEN status = 'confirmed' THEN 1 END) as confirmed,
-            COALESCE(SUM(CASE WHEN status = 'confirmed' THEN price_zec ELSE 0 END), 0.0) as total_zec
-         FROM invoices WHERE merchant_id = ?"
-    )
-    .bind(merchant_id)
-    .fetch_optional(pool)
-    .await
-    .ok()
-    .flatten()
-    .unwrap_or((0, 0, 0.0));
-
-    serde_json::json!({
-        "total_invoices": row.0,
-        "confirmed": row.1,
-        "total_zec": row.2,
-    })
-}
-
 fn validate_update(
     req: &UpdateMerchantRequest,
     is_testnet: bool,
@@ -467,10 +644,55 @@ fn validate_update(
             validation::validate_webhook_url("webhook_url", url, is_testnet)?;
         }
     }
+    if let Some(ref events) = req.webhook_events {
+        if let Some(unknown) = events.iter().find(|e| !crate::webhooks::EVENTS.contains(&e.as_str())) {
+            return Err(validation::ValidationError::invalid(
+                "webhook_events",
+                &format!("unknown event {:?}; expected one of {}", unknown, crate::webhooks::EVENTS.join(", ")),
+            ));
+        }
+    }
     if let Some(ref email) = req.recovery_email {
         if !email.is_empty() {
             validation::validate_email_format("recovery_email", email)?;
         }
     }
+    validation::validate_optional_length("store_about", &req.store_about, 2000)?;
+    if let Some(ref slug) = req.slug {
+        validation::validate_merchant_slug("slug", slug)?;
+    }
+    if let Some(ref nostr) = req.nostr {
+        if nostr.relays.len() > crate::notifiers::nostr::MAX_RELAYS {
+            return Err(validation::ValidationError::invalid("nostr.relays", "at most 10 relays"));
+        }
+        for relay in &nostr.relays {
+            validation::validate_relay_url("nostr.relays", relay, is_testnet)?;
+        }
+        if let Some(ref p) = nostr.notify_pubkey {
+            if !p.is_empty() && crate::notifiers::nostr::parse_pubkey(p).is_none() {
+                return Err(validation::ValidationError::invalid("nostr.notify_pubkey", "must be an npub or 64 hex characters"));
+            }
+        }
+    }
+    if let Some(ref chat) = req.chat {
+        crate::notifiers::chat::validate(chat, is_testnet)?;
+    }
+    if let Some(ref tax) = req.tax {
+        let valid_rate = |r: f64| (0.0..=100.0).contains(&r);
+        if !valid_rate(tax.rate) {
+            return Err(validation::ValidationError::invalid("tax.rate", "must be between 0 and 100"));
+        }
+        if tax.country_rates.len() > 250 {
+            return Err(validation::ValidationError::invalid("tax.country_rates", "too many countries"));
+        }
+        for (country, rate) in &tax.country_rates {
+            if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+                return Err(validation::ValidationError::invalid("tax.country_rates", "keys must be two-letter ISO country codes"));
+            }
+            if !valid_rate(*rate) {
+                return Err(validation::ValidationError::invalid("tax.country_rates", "rates must be between 0 and 100"));
+            }
+        }
+    }
     Ok(())
 }
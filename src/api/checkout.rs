@@ -0,0 +1,620 @@
+//! Buyer checkout without an API key: the one-step `POST /api/checkout`, and checkout
+//! sessions for widgets that let the buyer configure a cart before paying. Both end in
+//! the same `place_order`, which spends the checkout token and creates the invoice.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+
+use super::screen;
+use crate::abuse::AbuseGuard;
+use crate::config::Config;
+use crate::invoices::CreateInvoiceResponse;
+use crate::products::fields;
+use crate::products::sessions::{self, Cart, CheckoutSession};
+use crate::products::{self, Product};
+use crate::services::{BillingService, InvoiceService};
+use crate::validation::{self, ValidationError};
+
+/// The buyer's choices, as sent to checkout or to a session. On a session update, fields
+/// left out keep their value and an empty string clears one.
+#[derive(Debug, Default, Deserialize)]
+pub struct CartRequest {
+    variant: Option<String>,
+    quantity: Option<i64>,
+    /// Buyer's ISO 3166-1 alpha-2 country, used to pick the merchant's tax rate.
+    country: Option<String>,
+    refund_address: Option<String>,
+    /// Show the hosted page and receipt in this currency (EUR or USD); the product price is unchanged.
+    display_currency: Option<String>,
+    /// Language tag used to format amounts, e.g. `de-DE`.
+    locale: Option<String>,
+    /// Answers to the product's `checkout_fields`, by field name.
+    custom_fields: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CheckoutRequest {
+    product_id: String,
+    /// Token from `GET /api/products/{id}/public`.
+    checkout_token: Option<String>,
+    #[serde(flatten)]
+    cart: CartRequest,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateSessionRequest {
+    product_id: String,
+    #[serde(flatten)]
+    cart: CartRequest,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PaySessionRequest {
+    /// Token from `GET /api/products/{id}/public`.
+    checkout_token: Option<String>,
+}
+
+fn validate_cart(req: &CartRequest) -> Result<(), ValidationError> {
+    validation::validate_optional_length("variant", &req.variant, 100)?;
+    if req.quantity.is_some_and(|q| q < 1) {
+        return Err(ValidationError::invalid("quantity", "must be at least 1"));
+    }
+    if let Some(ref country) = req.country {
+        if !country.is_empty() && (country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic())) {
+            return Err(ValidationError::invalid("country", "must be a two-letter ISO country code"));
+        }
+    }
+    Ok(())
+}
+
+/// Check a refund address against the network the product's merchant is paid on, so a
+/// refund is never sent across networks.
+async fn validate_refund_address(pool: &SqlitePool, product: &Product, addr: &str) -> Result<(), HttpResponse> {
+    let network = match crate::merchants::get_network(pool, &product.merchant_id).await {
+        Ok(Some(network)) => network,
+        _ => {
+            return Err(HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Merchant not found"
+            })));
+        }
+    };
+    validation::validate_zcash_address_network("refund_address", addr, network == "testnet")
+        .map_err(|e| HttpResponse::BadRequest().json(e.to_json()))
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// The active product, or the response telling the buyer it is gone.
+async fn load_product(pool: &SqlitePool, product_id: &str) -> Result<Product, HttpResponse> {
+    match products::get_product(pool, product_id).await {
+        Ok(Some(p)) if p.active == 1 => Ok(p),
+        Ok(Some(_)) => Err(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Product is no longer available"
+        }))),
+        _ => Err(HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Product not found"
+        }))),
+    }
+}
+
+/// Check the variant and quantity against the product; it may have changed since the
+/// buyer picked them.
+fn check_options(product: &Product, cart: &Cart) -> Result<(), HttpResponse> {
+    if let Some(ref variant) = cart.variant {
+        let valid_variants = product.variants_list();
+        if !valid_variants.is_empty() && !valid_variants.contains(variant) {
+            return Err(HttpResponse::BadRequest().json(serde_json::json!({
+                "error": "Invalid variant",
+                "valid_variants": valid_variants,
+            })));
+        }
+    }
+    if cart.quantity > product.quantity_limit() {
+        return Err(HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Quantity exceeds the limit for this product",
+            "max_quantity": product.quantity_limit(),
+        })));
+    }
+    Ok(())
+}
+
+/// Validate answers to the product's fields and seal them for storage. A cart still being
+/// filled in may leave required fields blank; they are enforced when it is paid.
+fn seal_answers(
+    config: &Config,
+    product: &Product,
+    values: &HashMap<String, String>,
+    require_all: bool,
+) -> Result<Option<String>, HttpResponse> {
+    let mut product_fields = product.checkout_fields_list();
+    if !require_all {
+        product_fields.iter_mut().for_each(|f| f.required = false);
+    }
+    let answers = fields::validate_values(&product_fields, values)
+        .map_err(|e| HttpResponse::BadRequest().json(e.to_json()))?;
+    if answers.is_empty() {
+        return Ok(None);
+    }
+    fields::seal(&answers, &config.encryption_key).map(Some).map_err(|e| {
+        tracing::error!(error = %e, "Failed to encrypt checkout fields");
+        HttpResponse::InternalServerError().json(serde_json::json!({ "error": "Internal error" }))
+    })
+}
+
+/// Stored answers back as a map.
+fn open_answers(config: &Config, sealed: Option<&str>) -> HashMap<String, String> {
+    sealed
+        .and_then(|s| match fields::open(s, &config.encryption_key) {
+            Ok(v) => serde_json::from_value(v).ok(),
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to read checkout session fields");
+                None
+            }
+        })
+        .unwrap_or_default()
+}
+
+/// Apply a cart request on top of `cart` and check the result against the product.
+async fn merge_cart(
+    pool: &SqlitePool,
+    config: &Config,
+    product: &Product,
+    mut cart: Cart,
+    req: &CartRequest,
+    require_answers: bool,
+) -> Result<Cart, HttpResponse> {
+    validate_cart(req).map_err(|e| HttpResponse::BadRequest().json(e.to_json()))?;
+
+    if let Some(ref variant) = req.variant {
+        cart.variant = non_empty(variant);
+    }
+    if let Some(quantity) = req.quantity {
+        cart.quantity = quantity;
+    }
+    if let Some(ref country) = req.country {
+        cart.country = non_empty(&country.to_ascii_uppercase());
+    }
+    if let Some(ref addr) = req.refund_address {
+        if !addr.is_empty() {
+            validate_refund_address(pool, product, addr).await?;
+        }
+        cart.refund_address = non_empty(addr);
+    }
+    let display_currency = req.display_currency.as_deref().map_or(cart.display_currency.clone(), non_empty);
+    let locale = req.locale.as_deref().map_or(cart.locale.clone(), non_empty);
+    (cart.display_currency, cart.locale) =
+        crate::invoices::display::validate(display_currency.as_deref(), locale.as_deref())
+            .map_err(|e| HttpResponse::BadRequest().json(e.to_json()))?;
+    check_options(product, &cart)?;
+
+    if let Some(ref values) = req.custom_fields {
+        cart.custom_fields = seal_answers(config, product, values, require_answers)?;
+    } else if require_answers {
+        cart.custom_fields = seal_answers(config, product, &HashMap::new(), true)?;
+    }
+    Ok(cart)
+}
+
+/// Spend the buyer's checkout token and create the invoice for `cart`, priced now.
+#[allow(clippy::too_many_arguments)]
+async fn place_order(
+    pool: &SqlitePool,
+    config: &Config,
+    invoices: &InvoiceService,
+    billing: &BillingService,
+    guard: &AbuseGuard,
+    client_ip: Option<IpAddr>,
+    product: &Product,
+    cart: &Cart,
+    checkout_token: Option<&str>,
+) -> Result<CreateInvoiceResponse, HttpResponse> {
+    let strike = || {
+        if let Some(ip) = client_ip {
+            guard.strike(ip);
+        }
+    };
+
+    let merchant = match crate::merchants::get_all_merchants(pool, &config.encryption_key).await {
+        Ok(merchants) => match merchants.into_iter().find(|m| m.id == product.merchant_id) {
+            Some(m) => m,
+            None => {
+                return Err(HttpResponse::InternalServerError().json(serde_json::json!({
+                    "error": "Merchant not found"
+                })));
+            }
+        },
+        Err(_) => {
+            return Err(HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            })));
+        }
+    };
+
+    if let Some(status) = billing.blocking_status(&merchant.id).await {
+        return Err(crate::services::invoices::CreateInvoiceError::BillingPastDue(status).error_response());
+    }
+
+    let Some(token) = checkout_token else {
+        return Err(HttpResponse::Forbidden().json(serde_json::json!({
+            "error": "checkout_token is required; load the product first"
+        })));
+    };
+    let ip = client_ip.map(|ip| ip.to_string());
+    match products::tokens::redeem(pool, config, token, &product.id, ip.as_deref()).await {
+        Ok(products::tokens::Redemption::Accepted) => {}
+        Ok(products::tokens::Redemption::Invalid) => {
+            strike();
+            return Err(HttpResponse::Forbidden().json(serde_json::json!({
+                "error": "Invalid or expired checkout token; reload the product"
+            })));
+        }
+        Ok(products::tokens::Redemption::Exhausted) => {
+            return Err(HttpResponse::TooManyRequests().json(serde_json::json!({
+                "error": "Checkout token used up; reload the product"
+            })));
+        }
+        Ok(products::tokens::Redemption::IpLimited) => {
+            strike();
+            return Err(HttpResponse::TooManyRequests().json(serde_json::json!({
+                "error": "Too many checkouts, try again later"
+            })));
+        }
+        Ok(products::tokens::Redemption::ProductLimited) => {
+            tracing::warn!(product_id = %product.id, "Product checkout limit reached");
+            return Err(HttpResponse::TooManyRequests().json(serde_json::json!({
+                "error": "This product is getting too many checkouts, try again later"
+            })));
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to redeem checkout token");
+            return Err(HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            })));
+        }
+    }
+
+    let amount = product.price_eur * cart.quantity as f64;
+    let tax = match crate::invoices::tax::get_settings(pool, &merchant.id).await {
+        Ok(settings) => settings.apply(amount, cart.country.as_deref()),
+        Err(e) => return Err(e.error_response()),
+    };
+
+    let invoice_req = crate::invoices::CreateInvoiceRequest {
+        product_id: Some(product.id.clone()),
+        product_name: Some(product.name.clone()),
+        size: cart.variant.clone(),
+        quantity: Some(cart.quantity),
+        price_eur: tax.as_ref().map_or(amount, |t| t.total),
+        currency: Some(product.currency.clone()),
+        refund_address: cart.refund_address.clone(),
+        tax,
+        on_expiry: None,
+        display_currency: cart.display_currency.clone(),
+        locale: cart.locale.clone(),
+        custom_fields: cart.custom_fields.clone(),
+        memo_prefix: None,
+        expiry_minutes: None,
+        claim_code: false,
+    };
+
+    invoices.issue(&merchant, &invoice_req).await.map_err(|e| e.error_response())
+}
+
+/// Public checkout endpoint for buyer-driven invoice creation.
+/// Buyer selects a product, provides variant + shipping, invoice is created with server-side pricing.
+/// Requires the checkout token handed out by the product's public endpoint.
+#[allow(clippy::too_many_arguments)]
+pub async fn checkout(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
+    invoices: web::Data<InvoiceService>,
+    billing: web::Data<BillingService>,
+    guard: web::Data<AbuseGuard>,
+    body: web::Json<CheckoutRequest>,
+) -> HttpResponse {
+    let client_ip = match screen(&req, &guard) {
+        Ok(ip) => ip,
+        Err(resp) => return resp,
+    };
+    if let Err(e) = validation::validate_length("product_id", &body.product_id, 100)
+        .and_then(|_| validation::validate_optional_length("checkout_token", &body.checkout_token, 100))
+    {
+        return HttpResponse::BadRequest().json(e.to_json());
+    }
+
+    let product = match load_product(pool.get_ref(), &body.product_id).await {
+        Ok(p) => p,
+        Err(resp) => return resp,
+    };
+    let cart = Cart { quantity: 1, ..Cart::default() };
+    let cart = match merge_cart(pool.get_ref(), &config, &product, cart, &body.cart, true).await {
+        Ok(c) => c,
+        Err(resp) => return resp,
+    };
+
+    match place_order(
+        pool.get_ref(), &config, &invoices, &billing, &guard, client_ip,
+        &product, &cart, body.checkout_token.as_deref(),
+    ).await {
+        Ok(resp) => HttpResponse::Created().json(resp),
+        Err(resp) => resp,
+    }
+}
+
+/// A session as the buyer sees it: their choices, their answers, and what it would cost
+/// at the product's current price.
+async fn session_view(
+    pool: &SqlitePool,
+    config: &Config,
+    id: &str,
+    session: &CheckoutSession,
+    product: &Product,
+) -> Result<serde_json::Value, HttpResponse> {
+    let amount = product.price_eur * session.quantity as f64;
+    let tax = match crate::invoices::tax::get_settings(pool, &session.merchant_id).await {
+        Ok(settings) => settings.apply(amount, session.country.as_deref()),
+        Err(e) => return Err(e.error_response()),
+    };
+    let total = tax.as_ref().map_or(amount, |t| t.total);
+
+    Ok(serde_json::json!({
+        "id": id,
+        "status": session.status,
+        "product": {
+            "id": product.id,
+            "name": product.name,
+            "price_eur": product.price_eur,
+            "currency": product.currency,
+            "variants": product.variants_list(),
+            "max_quantity": product.quantity_limit(),
+            "checkout_fields": product.checkout_fields_list(),
+        },
+        "variant": session.variant,
+        "quantity": session.quantity,
+        "country": session.country,
+        "refund_address": session.refund_address,
+        "display_currency": session.display_currency,
+        "locale": session.locale,
+        "custom_fields": open_answers(config, session.custom_fields.as_deref()),
+        "currency": product.currency,
+        "subtotal": amount,
+        "tax": tax,
+        "total": total,
+        "invoice_id": session.invoice_id,
+        "expires_at": session.expires_at,
+        "created_at": session.created_at,
+        "updated_at": session.updated_at,
+    }))
+}
+
+fn session_not_found() -> HttpResponse {
+    HttpResponse::NotFound().json(serde_json::json!({
+        "error": "Checkout session not found or expired"
+    }))
+}
+
+fn session_closed(session: &CheckoutSession) -> HttpResponse {
+    HttpResponse::Conflict().json(serde_json::json!({
+        "error": if session.status == "converted" {
+            "Checkout session has already been paid"
+        } else {
+            "Checkout session is being paid"
+        },
+        "invoice_id": session.invoice_id,
+    }))
+}
+
+/// Public: open a checkout session for a product. No invoice is created yet.
+pub async fn create_session(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
+    body: web::Json<CreateSessionRequest>,
+) -> HttpResponse {
+    if let Err(e) = validation::validate_length("product_id", &body.product_id, 100) {
+        return HttpResponse::BadRequest().json(e.to_json());
+    }
+    let product = match load_product(pool.get_ref(), &body.product_id).await {
+        Ok(p) => p,
+        Err(resp) => return resp,
+    };
+    let cart = Cart { quantity: 1, ..Cart::default() };
+    let cart = match merge_cart(pool.get_ref(), &config, &product, cart, &body.cart, false).await {
+        Ok(c) => c,
+        Err(resp) => return resp,
+    };
+
+    let client_ip = crate::client_ip::from_request(&req).map(|ip| ip.to_string());
+    let id = match sessions::create(pool.get_ref(), &product.id, &product.merchant_id, client_ip.as_deref(), &cart).await {
+        Ok(Some(id)) => id,
+        Ok(None) => {
+            return HttpResponse::TooManyRequests().json(serde_json::json!({
+                "error": "Too many checkout sessions, try again later"
+            }));
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to create checkout session");
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            }));
+        }
+    };
+
+    match sessions::get(pool.get_ref(), &id).await {
+        Ok(Some(session)) => match session_view(pool.get_ref(), &config, &id, &session, &product).await {
+            Ok(view) => HttpResponse::Created().json(view),
+            Err(resp) => resp,
+        },
+        _ => HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": "Internal error"
+        })),
+    }
+}
+
+/// Public: the session's cart and current total. The session id is the credential.
+pub async fn get_session(
+    pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let id = path.into_inner();
+    let session = match sessions::get(pool.get_ref(), &id).await {
+        Ok(Some(s)) => s,
+        Ok(None) => return session_not_found(),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to get checkout session");
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            }));
+        }
+    };
+    match products::get_product(pool.get_ref(), &session.product_id).await {
+        Ok(Some(product)) => match session_view(pool.get_ref(), &config, &id, &session, &product).await {
+            Ok(view) => HttpResponse::Ok().json(view),
+            Err(resp) => resp,
+        },
+        _ => session_not_found(),
+    }
+}
+
+/// Public: change the cart of an open session.
+pub async fn update_session(
+    pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
+    path: web::Path<String>,
+    body: web::Json<CartRequest>,
+) -> HttpResponse {
+    let id = path.into_inner();
+    let session = match sessions::get(pool.get_ref(), &id).await {
+        Ok(Some(s)) => s,
+        Ok(None) => return session_not_found(),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to get checkout session");
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            }));
+        }
+    };
+    if session.status != "open" {
+        return session_closed(&session);
+    }
+    let product = match load_product(pool.get_ref(), &session.product_id).await {
+        Ok(p) => p,
+        Err(resp) => return resp,
+    };
+    let cart = match merge_cart(pool.get_ref(), &config, &product, session.cart(), &body, false).await {
+        Ok(c) => c,
+        Err(resp) => return resp,
+    };
+
+    match sessions::update(pool.get_ref(), &id, &cart).await {
+        Ok(true) => match sessions::get(pool.get_ref(), &id).await {
+            Ok(Some(updated)) => match session_view(pool.get_ref(), &config, &id, &updated, &product).await {
+                Ok(view) => HttpResponse::Ok().json(view),
+                Err(resp) => resp,
+            },
+            _ => session_not_found(),
+        },
+        Ok(false) => match sessions::get(pool.get_ref(), &id).await {
+            Ok(Some(current)) => session_closed(&current),
+            _ => session_not_found(),
+        },
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to update checkout session");
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            }))
+        }
+    }
+}
+
+/// Public: turn the session into an invoice, priced now. Same checks and checkout token
+/// as `POST /api/checkout`; a session is paid at most once.
+#[allow(clippy::too_many_arguments)]
+pub async fn pay_session(
+    req: HttpRequest,
+    pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
+    invoices: web::Data<InvoiceService>,
+    billing: web::Data<BillingService>,
+    guard: web::Data<AbuseGuard>,
+    path: web::Path<String>,
+    body: web::Json<PaySessionRequest>,
+) -> HttpResponse {
+    let client_ip = match screen(&req, &guard) {
+        Ok(ip) => ip,
+        Err(resp) => return resp,
+    };
+    if let Err(e) = validation::validate_optional_length("checkout_token", &body.checkout_token, 100) {
+        return HttpResponse::BadRequest().json(e.to_json());
+    }
+
+    let id = path.into_inner();
+    let session = match sessions::get(pool.get_ref(), &id).await {
+        Ok(Some(s)) => s,
+        Ok(None) => return session_not_found(),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to get checkout session");
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            }));
+        }
+    };
+    if session.status != "open" {
+        return session_closed(&session);
+    }
+    let product = match load_product(pool.get_ref(), &session.product_id).await {
+        Ok(p) => p,
+        Err(resp) => return resp,
+    };
+    let mut cart = session.cart();
+    if let Err(resp) = check_options(&product, &cart) {
+        return resp;
+    }
+    let answers = open_answers(&config, session.custom_fields.as_deref());
+    cart.custom_fields = match seal_answers(&config, &product, &answers, true) {
+        Ok(sealed) => sealed,
+        Err(resp) => return resp,
+    };
+
+    match sessions::begin_payment(pool.get_ref(), &id).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return match sessions::get(pool.get_ref(), &id).await {
+                Ok(Some(current)) => session_closed(&current),
+                _ => session_not_found(),
+            };
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to claim checkout session");
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            }));
+        }
+    }
+
+    match place_order(
+        pool.get_ref(), &config, &invoices, &billing, &guard, client_ip,
+        &product, &cart, body.checkout_token.as_deref(),
+    ).await {
+        Ok(resp) => {
+            if let Err(e) = sessions::finish_payment(pool.get_ref(), &id, &resp.invoice_id).await {
+                tracing::error!(invoice_id = %resp.invoice_id, error = %e, "Failed to mark checkout session paid");
+            }
+            HttpResponse::Created().json(resp)
+        }
+        Err(resp) => {
+            if let Err(e) = sessions::abandon_payment(pool.get_ref(), &id).await {
+                tracing::error!(error = %e, "Failed to reopen checkout session");
+            }
+            resp
+        }
+    }
+}
@@ -0,0 +1,401 @@
+//! HTTP mapping for the domain errors returned by `invoices`, `merchants` and `billing`,
+//! and for the operation errors of `services`. Handlers hand these back with
+//! `error_response()` (or `?` where the handler returns `Result`), so the same failure gets
+//! the same status and `code` on every route.
+
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+
+use crate::billing::BillingError;
+use crate::invoices::InvoiceError;
+use crate::merchants::MerchantError;
+use crate::services::billing::SettleError;
+use crate::services::invoices::{AttachPaymentError, CreateInvoiceError, PaymentRequestError, RefundUriError, TemplateError};
+use crate::services::merchants::{AddressBookError, DeleteAccountError, RegisterError, ResetSandboxError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ErrorKind {
+    NotFound,
+    Conflict,
+    Invalid,
+    QuotaExceeded,
+    Unavailable,
+    Internal,
+}
+
+impl ErrorKind {
+    fn status(self) -> StatusCode {
+        match self {
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::Conflict => StatusCode::CONFLICT,
+            ErrorKind::Invalid => StatusCode::BAD_REQUEST,
+            ErrorKind::QuotaExceeded => StatusCode::TOO_MANY_REQUESTS,
+            ErrorKind::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn code(self) -> &'static str {
+        match self {
+            ErrorKind::NotFound => "not_found",
+            ErrorKind::Conflict => "conflict",
+            ErrorKind::Invalid => "invalid_request",
+            ErrorKind::QuotaExceeded => "quota_exceeded",
+            ErrorKind::Unavailable => "unavailable",
+            ErrorKind::Internal => "internal",
+        }
+    }
+}
+
+/// SQLITE_BUSY and SQLITE_LOCKED, including their extended codes.
+fn is_busy(db: &dyn sqlx::error::DatabaseError) -> bool {
+    db.code()
+        .and_then(|c| c.parse::<i32>().ok())
+        .is_some_and(|c| matches!(c & 0xff, 5 | 6))
+}
+
+fn database_kind(e: &sqlx::Error) -> ErrorKind {
+    match e {
+        sqlx::Error::RowNotFound => ErrorKind::NotFound,
+        sqlx::Error::Database(db) if db.is_unique_violation() || db.is_foreign_key_violation() => {
+            ErrorKind::Conflict
+        }
+        sqlx::Error::Database(db) if is_busy(db.as_ref()) => ErrorKind::Unavailable,
+        sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed | sqlx::Error::Io(_) => ErrorKind::Unavailable,
+        _ => ErrorKind::Internal,
+    }
+}
+
+fn merchant_kind(e: &MerchantError) -> ErrorKind {
+    match e {
+        MerchantError::NotFound => ErrorKind::NotFound,
+        MerchantError::InvalidUfvk(_) => ErrorKind::Invalid,
+        MerchantError::SlugTaken | MerchantError::UfvkInUse | MerchantError::DiversifierExhausted => ErrorKind::Conflict,
+        MerchantError::Encryption(_) => ErrorKind::Internal,
+        MerchantError::Database(e) => database_kind(e),
+    }
+}
+
+fn invoice_kind(e: &InvoiceError) -> ErrorKind {
+    match e {
+        InvoiceError::NotFound => ErrorKind::NotFound,
+        InvoiceError::InvalidStatus => ErrorKind::Conflict,
+        InvoiceError::QuotaExceeded { .. } => ErrorKind::QuotaExceeded,
+        InvoiceError::BelowMinimum { .. } => ErrorKind::Invalid,
+        InvoiceError::Address(_) => ErrorKind::Internal,
+        InvoiceError::Merchant(e) => merchant_kind(e),
+        InvoiceError::Database(e) => database_kind(e),
+    }
+}
+
+fn billing_kind(e: &BillingError) -> ErrorKind {
+    match e {
+        BillingError::MerchantNotFound => ErrorKind::NotFound,
+        BillingError::Invoice(e) => invoice_kind(e),
+        BillingError::Database(e) => database_kind(e),
+    }
+}
+
+fn caused_by_database(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut current = Some(err);
+    while let Some(e) = current {
+        if e.is::<sqlx::Error>() {
+            return true;
+        }
+        current = e.source();
+    }
+    false
+}
+
+/// Client errors carry the domain message; server errors are logged and kept generic so
+/// no SQL or key material reaches the response.
+fn respond(kind: ErrorKind, err: &(dyn std::error::Error + 'static)) -> HttpResponse {
+    if matches!(kind, ErrorKind::Unavailable | ErrorKind::Internal) && caused_by_database(err) {
+        crate::alerts::record_db_error();
+    }
+    let message = match kind {
+        ErrorKind::Unavailable => {
+            tracing::warn!(error = %err, "Request failed, database unavailable");
+            "Service temporarily unavailable, try again".to_string()
+        }
+        ErrorKind::Internal => {
+            tracing::error!(error = %err, "Request failed");
+            "Internal error".to_string()
+        }
+        _ => err.to_string(),
+    };
+    HttpResponse::build(kind.status()).json(serde_json::json!({
+        "error": message,
+        "code": kind.code(),
+    }))
+}
+
+impl ResponseError for InvoiceError {
+    fn status_code(&self) -> StatusCode {
+        invoice_kind(self).status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        respond(invoice_kind(self), self)
+    }
+}
+
+impl ResponseError for MerchantError {
+    fn status_code(&self) -> StatusCode {
+        merchant_kind(self).status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        respond(merchant_kind(self), self)
+    }
+}
+
+impl ResponseError for BillingError {
+    fn status_code(&self) -> StatusCode {
+        billing_kind(self).status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        respond(billing_kind(self), self)
+    }
+}
+
+fn message(err: &dyn std::error::Error) -> serde_json::Value {
+    serde_json::json!({ "error": err.to_string() })
+}
+
+impl ResponseError for CreateInvoiceError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            CreateInvoiceError::Validation(_) => StatusCode::BAD_REQUEST,
+            CreateInvoiceError::BillingPastDue(_) => StatusCode::PAYMENT_REQUIRED,
+            CreateInvoiceError::PriceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            CreateInvoiceError::Invoice(e) => e.status_code(),
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            CreateInvoiceError::Validation(e) => HttpResponse::BadRequest().json(e.to_json()),
+            CreateInvoiceError::BillingPastDue(status) => HttpResponse::PaymentRequired().json(serde_json::json!({
+                "error": self.to_string(),
+                "billing_status": status,
+            })),
+            CreateInvoiceError::PriceUnavailable => HttpResponse::ServiceUnavailable().json(message(self)),
+            CreateInvoiceError::Invoice(e) => e.error_response(),
+        }
+    }
+}
+
+impl ResponseError for RefundUriError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            RefundUriError::Invoice(e) => e.status_code(),
+            RefundUriError::Merchant(e) => e.status_code(),
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            RefundUriError::InvalidAmount { received_zatoshis } => HttpResponse::BadRequest().json(serde_json::json!({
+                "error": self.to_string(),
+                "received_zec": crate::invoices::zatoshis_to_zec(*received_zatoshis),
+            })),
+            RefundUriError::Invoice(e) => e.error_response(),
+            RefundUriError::Merchant(e) => e.error_response(),
+            _ => HttpResponse::BadRequest().json(message(self)),
+        }
+    }
+}
+
+impl ResponseError for AttachPaymentError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AttachPaymentError::NotFound => StatusCode::NOT_FOUND,
+            AttachPaymentError::AlreadyAttached | AttachPaymentError::NotPayable => StatusCode::CONFLICT,
+            AttachPaymentError::Invoice(e) => e.status_code(),
+            AttachPaymentError::Apply(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            AttachPaymentError::Invoice(e) => e.error_response(),
+            AttachPaymentError::Apply(_) => respond(ErrorKind::Internal, self),
+            _ => HttpResponse::build(self.status_code()).json(message(self)),
+        }
+    }
+}
+
+impl ResponseError for RegisterError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            RegisterError::Validation(_) => StatusCode::BAD_REQUEST,
+            RegisterError::Merchant(e) => e.status_code(),
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            RegisterError::Validation(e) => HttpResponse::BadRequest().json(e.to_json()),
+            RegisterError::Merchant(e) => e.error_response(),
+        }
+    }
+}
+
+impl ResponseError for DeleteAccountError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            DeleteAccountError::OutstandingBalance => StatusCode::FORBIDDEN,
+            DeleteAccountError::Merchant(e) => e.status_code(),
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            DeleteAccountError::OutstandingBalance => HttpResponse::Forbidden().json(message(self)),
+            DeleteAccountError::Merchant(e) => e.error_response(),
+        }
+    }
+}
+
+impl ResponseError for ResetSandboxError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ResetSandboxError::NotTestnet => StatusCode::FORBIDDEN,
+            ResetSandboxError::Merchant(e) => e.status_code(),
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            ResetSandboxError::NotTestnet => HttpResponse::Forbidden().json(message(self)),
+            ResetSandboxError::Merchant(e) => e.error_response(),
+        }
+    }
+}
+
+impl ResponseError for AddressBookError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AddressBookError::Validation(_) => StatusCode::BAD_REQUEST,
+            AddressBookError::NotFound => StatusCode::NOT_FOUND,
+            AddressBookError::Full | AddressBookError::Duplicate => StatusCode::CONFLICT,
+            AddressBookError::Merchant(e) => e.status_code(),
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            AddressBookError::Validation(e) => HttpResponse::BadRequest().json(e.to_json()),
+            AddressBookError::Merchant(e) => e.error_response(),
+            _ => HttpResponse::build(self.status_code()).json(message(self)),
+        }
+    }
+}
+
+impl ResponseError for TemplateError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            TemplateError::Validation(_) => StatusCode::BAD_REQUEST,
+            TemplateError::NotFound => StatusCode::NOT_FOUND,
+            TemplateError::Full | TemplateError::Duplicate => StatusCode::CONFLICT,
+            TemplateError::Issue(e) => e.status_code(),
+            TemplateError::Invoice(e) => e.status_code(),
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            TemplateError::Validation(e) => HttpResponse::BadRequest().json(e.to_json()),
+            TemplateError::Issue(e) => e.error_response(),
+            TemplateError::Invoice(e) => e.error_response(),
+            _ => HttpResponse::build(self.status_code()).json(message(self)),
+        }
+    }
+}
+
+impl ResponseError for PaymentRequestError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PaymentRequestError::Validation(_) | PaymentRequestError::EmailUnavailable => StatusCode::BAD_REQUEST,
+            PaymentRequestError::TooMany => ErrorKind::QuotaExceeded.status(),
+            PaymentRequestError::NotFound => StatusCode::NOT_FOUND,
+            PaymentRequestError::Create(e) => e.status_code(),
+            PaymentRequestError::Invoice(e) => e.status_code(),
+            PaymentRequestError::Email(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            PaymentRequestError::Validation(e) => HttpResponse::BadRequest().json(e.to_json()),
+            PaymentRequestError::TooMany => respond(ErrorKind::QuotaExceeded, self),
+            PaymentRequestError::Create(e) => e.error_response(),
+            PaymentRequestError::Invoice(e) => e.error_response(),
+            PaymentRequestError::Email(_) => respond(ErrorKind::Internal, self),
+            _ => HttpResponse::build(self.status_code()).json(message(self)),
+        }
+    }
+}
+
+impl ResponseError for SettleError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SettleError::NotEnabled => StatusCode::BAD_REQUEST,
+            SettleError::RateUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            SettleError::Billing(e) => e.status_code(),
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            SettleError::Billing(e) => e.error_response(),
+            _ => HttpResponse::build(self.status_code()).json(message(self)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_domain_errors_map_to_status() {
+        assert_eq!(InvoiceError::NotFound.status_code(), StatusCode::NOT_FOUND);
+        assert_eq!(InvoiceError::InvalidStatus.status_code(), StatusCode::CONFLICT);
+        assert_eq!(
+            InvoiceError::QuotaExceeded { quota: "open invoices", limit: 10 }.status_code(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(MerchantError::SlugTaken.status_code(), StatusCode::CONFLICT);
+        assert_eq!(MerchantError::UfvkInUse.status_code(), StatusCode::CONFLICT);
+        assert_eq!(
+            InvoiceError::Merchant(MerchantError::NotFound).status_code(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            BillingError::from(sqlx::Error::PoolTimedOut).status_code(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            InvoiceError::from(sqlx::Error::RowNotFound).status_code(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            MerchantError::from(sqlx::Error::Protocol("bad".into())).status_code(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+        assert_eq!(
+            CreateInvoiceError::BillingPastDue("suspended".into()).status_code(),
+            StatusCode::PAYMENT_REQUIRED
+        );
+        assert_eq!(
+            RefundUriError::Invoice(InvoiceError::NotFound).status_code(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(DeleteAccountError::OutstandingBalance.status_code(), StatusCode::FORBIDDEN);
+        assert_eq!(ResetSandboxError::NotTestnet.status_code(), StatusCode::FORBIDDEN);
+    }
+}
@@ -0,0 +1,140 @@
+//! Chunked file exports. Rows are read from a sqlx fetch stream on a spawned task and
+//! handed to the response body through a small bounded channel: a slow client fills the
+//! channel and pauses the query, one that disconnects ends it, and at no point does the
+//! server hold more than a few rows of an export, however large.
+
+use actix_web::web::Bytes;
+use actix_web::HttpResponse;
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Encoded rows waiting for the client. Kept small so backpressure reaches the query fast.
+const BUFFERED_CHUNKS: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Ndjson,
+    Csv,
+}
+
+impl Format {
+    pub fn parse(value: Option<&str>) -> Option<Self> {
+        match value.unwrap_or("ndjson") {
+            "ndjson" | "jsonl" => Some(Self::Ndjson),
+            "csv" => Some(Self::Csv),
+            _ => None,
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Ndjson => "application/x-ndjson",
+            Self::Csv => "text/csv",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Ndjson => "ndjson",
+            Self::Csv => "csv",
+        }
+    }
+}
+
+type Chunk = Result<Bytes, std::io::Error>;
+
+/// The producing end of an export: encodes rows and waits for room in the channel.
+pub struct Sink {
+    tx: mpsc::Sender<Chunk>,
+    format: Format,
+    header_written: bool,
+}
+
+impl Sink {
+    /// Encode and queue one row. False once the client is gone, to stop reading rows.
+    pub async fn send<T: Serialize>(&mut self, row: &T) -> bool {
+        let encoded = match self.format {
+            Format::Ndjson => serde_json::to_vec(row)
+                .map(|mut line| {
+                    line.push(b'\n');
+                    line
+                })
+                .map_err(std::io::Error::other),
+            Format::Csv => csv_row(row, !self.header_written),
+        };
+        self.header_written = true;
+        match encoded {
+            Ok(bytes) => self.tx.send(Ok(Bytes::from(bytes))).await.is_ok(),
+            Err(e) => {
+                self.fail(e).await;
+                false
+            }
+        }
+    }
+
+    /// End the export with an error. The connection is dropped mid-body, so the client
+    /// sees a truncated transfer rather than a file that looks complete.
+    pub async fn fail(&self, error: impl std::fmt::Display) {
+        tracing::error!(error = %error, "Export failed");
+        let _ = self.tx.send(Err(std::io::Error::other("export failed"))).await;
+    }
+}
+
+/// One CSV record, with the header row in front of the first.
+fn csv_row<T: Serialize>(row: &T, with_header: bool) -> Result<Vec<u8>, std::io::Error> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(with_header)
+        .buffer_capacity(1024)
+        .from_writer(Vec::new());
+    writer.serialize(row).map_err(std::io::Error::other)?;
+    writer.into_inner().map_err(|e| std::io::Error::other(e.to_string()))
+}
+
+/// Start an export: `produce` runs on its own task, feeding rows to the sink, while the
+/// returned response streams them out as a file download named `{name}.{extension}`.
+pub fn respond<F, Fut>(format: Format, name: &str, produce: F) -> HttpResponse
+where
+    F: FnOnce(Sink) -> Fut,
+    Fut: std::future::Future<Output = ()> + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(BUFFERED_CHUNKS);
+    tokio::spawn(produce(Sink { tx, format, header_written: false }));
+
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"{}.{}\"", name, format.extension()),
+        ))
+        .streaming(ReceiverStream::new(rx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Row {
+        id: &'static str,
+        amount: Option<f64>,
+    }
+
+    #[tokio::test]
+    async fn test_rows_are_encoded_per_format() {
+        let (tx, mut rx) = mpsc::channel(4);
+        let mut sink = Sink { tx, format: Format::Csv, header_written: false };
+        assert!(sink.send(&Row { id: "a", amount: Some(1.5) }).await);
+        assert!(sink.send(&Row { id: "b", amount: None }).await);
+        assert_eq!(rx.recv().await.unwrap().unwrap(), Bytes::from("id,amount\na,1.5\n"));
+        assert_eq!(rx.recv().await.unwrap().unwrap(), Bytes::from("b,\n"));
+
+        let (tx, mut rx) = mpsc::channel(4);
+        let mut sink = Sink { tx, format: Format::Ndjson, header_written: false };
+        assert!(sink.send(&Row { id: "a", amount: None }).await);
+        assert_eq!(rx.recv().await.unwrap().unwrap(), Bytes::from("{\"id\":\"a\",\"amount\":null}\n"));
+
+        drop(rx);
+        assert!(!sink.send(&Row { id: "c", amount: None }).await);
+    }
+}
@@ -0,0 +1,172 @@
+//! Merchant authentication as actix extractors. Handlers take one of these as an argument
+//! instead of resolving credentials themselves, so every route authenticates the same way
+//! and rejects with the same 401 body.
+
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse, ResponseError};
+use futures::future::LocalBoxFuture;
+use sqlx::SqlitePool;
+
+use crate::config::Config;
+use crate::merchants::{self, Merchant};
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("Not authenticated")]
+    NotAuthenticated,
+    #[error("Missing or invalid Authorization header")]
+    MissingApiKey,
+    #[error("Invalid API key")]
+    InvalidApiKey,
+    #[error("Internal error")]
+    Internal,
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> actix_web::http::StatusCode {
+        match self {
+            AuthError::Internal => actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
+            _ => actix_web::http::StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(serde_json::json!({
+            "error": self.to_string()
+        }))
+    }
+}
+
+/// Merchant behind the dashboard session cookie.
+pub struct SessionMerchant(pub Merchant);
+
+/// Merchant behind `Authorization: Bearer <api_key>`.
+pub struct ApiKeyMerchant(pub Merchant);
+
+/// API key when an Authorization header is sent, the session cookie otherwise.
+pub struct AnyMerchant(pub Merchant);
+
+/// The API key from the Authorization header (`Bearer ` prefix optional).
+fn bearer_token(req: &HttpRequest) -> Option<String> {
+    let value = req.headers().get("Authorization")?.to_str().ok()?;
+    let key = value.strip_prefix("Bearer ").unwrap_or(value).trim();
+    if key.is_empty() { None } else { Some(key.to_string()) }
+}
+
+async fn from_session(req: &HttpRequest) -> Result<Merchant, AuthError> {
+    let pool = req.app_data::<web::Data<SqlitePool>>().ok_or(AuthError::Internal)?;
+    super::auth::resolve_session(req, pool).await.ok_or(AuthError::NotAuthenticated)
+}
+
+async fn from_api_key(req: &HttpRequest) -> Result<Merchant, AuthError> {
+    let key = bearer_token(req).ok_or(AuthError::MissingApiKey)?;
+    let pool = req.app_data::<web::Data<SqlitePool>>().ok_or(AuthError::Internal)?;
+    let config = req.app_data::<web::Data<Config>>().ok_or(AuthError::Internal)?;
+
+    match merchants::authenticate(pool, &key, &config.encryption_key).await {
+        Ok(Some(m)) => {
+            crate::request_log::tag_merchant(req, &m.id);
+            Ok(m)
+        }
+        Ok(None) => Err(AuthError::InvalidApiKey),
+        Err(e) => {
+            tracing::error!(error = %e, "API key auth error");
+            Err(AuthError::Internal)
+        }
+    }
+}
+
+impl FromRequest for SessionMerchant {
+    type Error = AuthError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move { from_session(&req).await.map(SessionMerchant) })
+    }
+}
+
+impl FromRequest for ApiKeyMerchant {
+    type Error = AuthError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move { from_api_key(&req).await.map(ApiKeyMerchant) })
+    }
+}
+
+impl FromRequest for AnyMerchant {
+    type Error = AuthError;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        Box::pin(async move {
+            let merchant = if req.headers().contains_key("Authorization") {
+                from_api_key(&req).await
+            } else {
+                from_session(&req).await
+            };
+            merchant.map(AnyMerchant)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::cookie::Cookie;
+    use actix_web::test::TestRequest;
+
+    fn request(pool: &SqlitePool) -> TestRequest {
+        TestRequest::default()
+            .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(Config::from_pairs(&[]).unwrap()))
+    }
+
+    #[actix_web::test]
+    async fn test_api_key_extractor_rejects_missing_and_bad_keys() {
+        let pool = crate::db::test_pool().await;
+        let merchant = crate::db::test_merchant(&pool, 1).await;
+
+        let req = request(&pool).to_http_request();
+        let err = ApiKeyMerchant::extract(&req).await.err().unwrap();
+        assert!(matches!(err, AuthError::MissingApiKey));
+        assert_eq!(err.status_code(), 401);
+
+        let req = request(&pool).insert_header(("Authorization", "Bearer cpay_sk_wrong")).to_http_request();
+        let err = ApiKeyMerchant::extract(&req).await.err().unwrap();
+        assert!(matches!(err, AuthError::InvalidApiKey));
+        assert_eq!(err.status_code(), 401);
+
+        let req = request(&pool)
+            .insert_header(("Authorization", format!("Bearer {}", merchant.api_key)))
+            .to_http_request();
+        assert_eq!(ApiKeyMerchant::extract(&req).await.unwrap().0.id, merchant.merchant_id);
+    }
+
+    #[actix_web::test]
+    async fn test_session_extractor_rejects_an_expired_session() {
+        let pool = crate::db::test_pool().await;
+        let merchant = crate::db::test_merchant(&pool, 1).await;
+        let session_id = merchants::create_session(&pool, &merchant.merchant_id, None, 24).await.unwrap();
+        let with_cookie = |req: TestRequest| req.cookie(Cookie::new("cpay_session", session_id.clone())).to_http_request();
+
+        let req = with_cookie(request(&pool));
+        assert_eq!(SessionMerchant::extract(&req).await.unwrap().0.id, merchant.merchant_id);
+
+        sqlx::query("UPDATE sessions SET expires_at = '2020-01-01T00:00:00Z' WHERE id = ?")
+            .bind(&session_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let req = with_cookie(request(&pool));
+        let err = SessionMerchant::extract(&req).await.err().unwrap();
+        assert!(matches!(err, AuthError::NotAuthenticated));
+        assert_eq!(err.status_code(), 401);
+
+        let req = request(&pool).to_http_request();
+        assert!(matches!(SessionMerchant::extract(&req).await.err().unwrap(), AuthError::NotAuthenticated));
+    }
+}
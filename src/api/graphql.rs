@@ -0,0 +1,318 @@
+//! GraphQL for the dashboard: `POST /api/v1/graphql` answers the profile, stats, billing,
+//! products and invoices in one round trip, returning only the fields asked for. Read-only
+//! and scoped to the merchant authenticated by `AnyMerchant` (API key or dashboard session);
+//! writes stay on the REST endpoints. Invoice lists are Relay connections (`first`/`after`).
+
+use actix_web::{web, HttpResponse};
+use async_graphql::connection::{self, Connection, Edge};
+use async_graphql::{Context, EmptyMutation, EmptySubscription, Json, Object, Schema, SimpleObject, ID};
+use sqlx::SqlitePool;
+
+use super::extract::AnyMerchant;
+use crate::invoices::state::InvoiceState;
+use crate::invoices::{self, Invoice, InvoiceFilter};
+use crate::merchants::Merchant;
+use crate::services::{BillingService, MerchantService};
+
+pub type DashboardSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+/// Largest `first` accepted on a connection.
+const MAX_PAGE: usize = 100;
+const DEFAULT_PAGE: usize = 20;
+
+pub fn schema(pool: SqlitePool, merchants: MerchantService, billing: BillingService) -> DashboardSchema {
+    Schema::build(Query, EmptyMutation, EmptySubscription)
+        .data(pool)
+        .data(merchants)
+        .data(billing)
+        .limit_depth(8)
+        .limit_complexity(500)
+        .finish()
+}
+
+pub async fn handler(
+    AnyMerchant(merchant): AnyMerchant,
+    schema: web::Data<DashboardSchema>,
+    body: web::Json<async_graphql::Request>,
+) -> HttpResponse {
+    HttpResponse::Ok().json(schema.execute(body.into_inner().data(merchant)).await)
+}
+
+/// Log the cause and hand the client a generic message, as the REST handlers do.
+fn internal(e: impl std::fmt::Display) -> async_graphql::Error {
+    tracing::error!(error = %e, "GraphQL resolver failed");
+    async_graphql::Error::new("Internal error")
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    async fn me(&self, ctx: &Context<'_>) -> Profile {
+        let merchant = ctx.data_unchecked::<Merchant>();
+        Profile {
+            id: ID(merchant.id.clone()),
+            name: merchant.name.clone(),
+            payment_address: merchant.payment_address.clone(),
+            webhook_url: merchant.webhook_url.clone(),
+            has_recovery_email: merchant.recovery_email.is_some(),
+            created_at: merchant.created_at.clone(),
+        }
+    }
+
+    async fn stats(&self, ctx: &Context<'_>) -> Stats {
+        let merchant = ctx.data_unchecked::<Merchant>();
+        let stats = ctx.data_unchecked::<MerchantService>().stats(merchant).await;
+        Stats {
+            total_invoices: stats.total_invoices,
+            confirmed: stats.confirmed,
+            total_zec: stats.total_zec,
+        }
+    }
+
+    async fn billing(&self, ctx: &Context<'_>) -> async_graphql::Result<Billing> {
+        let merchant = ctx.data_unchecked::<Merchant>();
+        let summary = ctx.data_unchecked::<BillingService>().summary(&merchant.id).await.map_err(internal)?;
+        let Some(summary) = summary else {
+            return Ok(Billing {
+                fee_enabled: false,
+                billing_status: "active".into(),
+                trust_tier: "standard".into(),
+                ..Default::default()
+            });
+        };
+        Ok(Billing {
+            fee_enabled: true,
+            fee_rate: summary.fee_rate,
+            trust_tier: summary.trust_tier,
+            billing_status: summary.billing_status,
+            total_fees_zec: summary.total_fees_zec,
+            auto_collected_zec: summary.auto_collected_zec,
+            outstanding_zec: summary.outstanding_zec,
+            fee_currency: summary.fee_currency,
+            outstanding_fiat: summary.outstanding_fiat,
+            current_cycle: summary.current_cycle.map(|c| Json(serde_json::json!(c))),
+        })
+    }
+
+    async fn products(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default = false)] include_archived: bool,
+    ) -> async_graphql::Result<Vec<Product>> {
+        let merchant = ctx.data_unchecked::<Merchant>();
+        let products = crate::products::list_products(ctx.data_unchecked::<SqlitePool>(), &merchant.id, include_archived)
+            .await
+            .map_err(internal)?;
+        Ok(products.into_iter().map(Product::from).collect())
+    }
+
+    /// One of the merchant's invoices; null if it doesn't exist or belongs to someone else.
+    async fn invoice(&self, ctx: &Context<'_>, id: ID) -> async_graphql::Result<Option<InvoiceNode>> {
+        let merchant = ctx.data_unchecked::<Merchant>();
+        let invoice = invoices::get_invoice(ctx.data_unchecked::<SqlitePool>(), &id)
+            .await
+            .map_err(internal)?;
+        Ok(invoice.filter(|i| i.merchant_id == merchant.id).map(InvoiceNode))
+    }
+
+    /// The merchant's invoices, newest first.
+    async fn invoices(
+        &self,
+        ctx: &Context<'_>,
+        after: Option<String>,
+        first: Option<i32>,
+        status: Option<String>,
+        received_gt: Option<i64>,
+    ) -> async_graphql::Result<Connection<usize, InvoiceNode>> {
+        let merchant = ctx.data_unchecked::<Merchant>();
+        let pool = ctx.data_unchecked::<SqlitePool>();
+        let status = match status.as_deref() {
+            Some(s) => Some(InvoiceState::parse(s).ok_or_else(|| async_graphql::Error::new(format!("Unknown status {:?}", s)))?),
+            None => None,
+        };
+        let filter = InvoiceFilter { status, received_gt };
+
+        connection::query(after, None, first, None, |after: Option<usize>, _, first, _| async move {
+            let offset = after.map(|a| a + 1).unwrap_or(0);
+            let limit = first.unwrap_or(DEFAULT_PAGE).min(MAX_PAGE);
+            // One extra row tells whether there is a next page.
+            let mut rows = invoices::page_for_merchant(pool, &merchant.id, &filter, limit as i64 + 1, offset as i64)
+                .await
+                .map_err(internal)?;
+            let has_next = rows.len() > limit;
+            rows.truncate(limit);
+
+            let mut page = Connection::new(offset > 0, has_next);
+            page.edges.extend(
+                rows.into_iter().enumerate().map(|(i, invoice)| Edge::new(offset + i, InvoiceNode(invoice))),
+            );
+            Ok::<_, async_graphql::Error>(page)
+        })
+        .await
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct Profile {
+    id: ID,
+    name: String,
+    payment_address: String,
+    webhook_url: Option<String>,
+    has_recovery_email: bool,
+    created_at: String,
+}
+
+#[derive(SimpleObject)]
+pub struct Stats {
+    total_invoices: i64,
+    confirmed: i64,
+    total_zec: f64,
+}
+
+#[derive(SimpleObject, Default)]
+pub struct Billing {
+    fee_enabled: bool,
+    fee_rate: f64,
+    trust_tier: String,
+    billing_status: String,
+    total_fees_zec: f64,
+    auto_collected_zec: f64,
+    outstanding_zec: f64,
+    fee_currency: String,
+    outstanding_fiat: f64,
+    current_cycle: Option<Json<serde_json::Value>>,
+}
+
+#[derive(SimpleObject)]
+pub struct Product {
+    id: ID,
+    slug: String,
+    name: String,
+    description: Option<String>,
+    price_eur: f64,
+    currency: String,
+    category: Option<String>,
+    active: bool,
+    archived_at: Option<String>,
+    created_at: String,
+}
+
+impl From<crate::products::Product> for Product {
+    fn from(p: crate::products::Product) -> Self {
+        Self {
+            id: ID(p.id),
+            slug: p.slug,
+            name: p.name,
+            description: p.description,
+            price_eur: p.price_eur,
+            currency: p.currency,
+            category: p.category,
+            active: p.active == 1,
+            archived_at: p.archived_at,
+            created_at: p.created_at,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct Payment {
+    txid: String,
+    amount_zatoshis: i64,
+    block_height: Option<i64>,
+    seen_at: String,
+    fee_zatoshis: Option<i64>,
+    tx_outputs: Option<i64>,
+}
+
+pub struct InvoiceNode(Invoice);
+
+#[Object(name = "Invoice")]
+impl InvoiceNode {
+    async fn id(&self) -> ID {
+        ID(self.0.id.clone())
+    }
+    async fn memo_code(&self) -> &str {
+        &self.0.memo_code
+    }
+    async fn status(&self) -> &str {
+        &self.0.status
+    }
+    async fn product_name(&self) -> Option<&str> {
+        self.0.product_name.as_deref()
+    }
+    async fn quantity(&self) -> i64 {
+        self.0.quantity
+    }
+    async fn price_eur(&self) -> f64 {
+        self.0.price_eur
+    }
+    async fn price_usd(&self) -> Option<f64> {
+        self.0.price_usd
+    }
+    async fn currency(&self) -> Option<&str> {
+        self.0.currency.as_deref()
+    }
+    async fn price_zec(&self) -> f64 {
+        self.0.price_zec
+    }
+    async fn price_zatoshis(&self) -> i64 {
+        self.0.price_zatoshis
+    }
+    async fn received_zatoshis(&self) -> i64 {
+        self.0.received_zatoshis
+    }
+    async fn payment_address(&self) -> &str {
+        &self.0.payment_address
+    }
+    async fn detected_txid(&self) -> Option<&str> {
+        self.0.detected_txid.as_deref()
+    }
+    async fn detected_at(&self) -> Option<&str> {
+        self.0.detected_at.as_deref()
+    }
+    async fn confirmed_at(&self) -> Option<&str> {
+        self.0.confirmed_at.as_deref()
+    }
+    async fn refunded_at(&self) -> Option<&str> {
+        self.0.refunded_at.as_deref()
+    }
+    async fn expires_at(&self) -> &str {
+        &self.0.expires_at
+    }
+    async fn created_at(&self) -> &str {
+        &self.0.created_at
+    }
+
+    /// Transactions paying this invoice; fetched only when selected.
+    async fn payments(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Payment>> {
+        let payments = invoices::get_payments(ctx.data_unchecked::<SqlitePool>(), &self.0.id)
+            .await
+            .map_err(internal)?;
+        Ok(payments
+            .into_iter()
+            .map(|p| Payment {
+                txid: p.txid,
+                amount_zatoshis: p.amount_zatoshis,
+                block_height: p.block_height,
+                seen_at: p.seen_at,
+                fee_zatoshis: p.fee_zatoshis,
+                tx_outputs: p.tx_outputs,
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema_contract() {
+        let sdl = Schema::build(Query, EmptyMutation, EmptySubscription).finish().sdl();
+        assert!(sdl.contains("invoices(after: String, first: Int, status: String, receivedGt: Int): InvoiceConnection!"));
+        assert!(sdl.contains("invoice(id: ID!): Invoice"));
+        assert!(sdl.contains("payments: [Payment!]!"));
+        assert!(!sdl.contains("type Mutation"));
+    }
+}
@@ -1,143 +1,461 @@
-use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use futures::StreamExt;
 use sqlx::SqlitePool;
 
+use super::extract::AnyMerchant;
+use crate::abuse::AbuseGuard;
 use crate::config::Config;
+use crate::db::ReadPool;
 use crate::invoices::{self, CreateInvoiceRequest};
-use crate::invoices::pricing::PriceService;
+use crate::invoices::requests::EmailInvoiceRequest;
+use crate::invoices::templates::{TemplateFields, UpdateTemplateRequest};
+use crate::invoices::views::{ExportedInvoice, MerchantInvoice, PublicInvoice};
+use crate::scanner::cipherscan::CipherScans;
+use crate::services::InvoiceService;
 use crate::validation;
 
 pub async fn create(
-    req: HttpRequest,
+    merchant: Option<AnyMerchant>,
     pool: web::Data<SqlitePool>,
     config: web::Data<Config>,
-    price_service: web::Data<PriceService>,
+    service: web::Data<InvoiceService>,
     body: web::Json<CreateInvoiceRequest>,
 ) -> HttpResponse {
-    if let Err(e) = validate_invoice_request(&body) {
-        return HttpResponse::BadRequest().json(e.to_json());
+    let merchant = match merchant {
+        Some(AnyMerchant(m)) => m,
+        None => match single_tenant_merchant(&pool, &config).await {
+            Some(m) => m,
+            None => {
+                return HttpResponse::Unauthorized().json(serde_json::json!({
+                    "error": "Invalid API key or no merchant configured. Register via POST /api/merchants first."
+                }));
+            }
+        },
+    };
+
+    match service.create(&merchant, body.into_inner()).await {
+        Ok(resp) => HttpResponse::Created().json(resp),
+        Err(e) => e.error_response(),
     }
+}
 
-    let merchant = match resolve_merchant(&req, &pool, &config).await {
-        Some(m) => m,
-        None => {
-            return HttpResponse::Unauthorized().json(serde_json::json!({
-                "error": "Invalid API key or no merchant configured. Register via POST /api/merchants first."
-            }));
-        }
+#[derive(Debug, serde::Deserialize)]
+pub struct ExportQuery {
+    /// `ndjson` (default) or `csv`.
+    pub format: Option<String>,
+    pub status: Option<String>,
+    pub received_gt: Option<i64>,
+}
+
+/// All of the merchant's invoices, oldest first, streamed as NDJSON or CSV. Takes the
+/// same filters as the invoice list but no limit.
+pub async fn export(
+    AnyMerchant(merchant): AnyMerchant,
+    pool: web::Data<ReadPool>,
+    query: web::Query<ExportQuery>,
+) -> HttpResponse {
+    let Some(format) = super::export::Format::parse(query.format.as_deref()) else {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "format must be ndjson or csv"
+        }));
+    };
+    let status = match query.status.as_deref() {
+        None => None,
+        Some(s) => match invoices::state::InvoiceState::parse(s) {
+            Some(state) => Some(state),
+            None => return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": format!("Unknown invoice status: {}", s)
+            })),
+        },
     };
+    let filter = invoices::InvoiceFilter { status, received_gt: query.received_gt };
+    let pool = pool.into_inner();
 
-    if config.fee_enabled() {
-        if let Ok(status) = crate::billing::get_merchant_billing_status(pool.get_ref(), &merchant.id).await {
-            if status == "past_due" || status == "suspended" {
-                return HttpResponse::PaymentRequired().json(serde_json::json!({
-                    "error": "Merchant account has outstanding fees",
-                    "billing_status": status,
-                }));
+    super::export::respond(format, "invoices", move |mut sink| async move {
+        let mut rows = invoices::stream_for_merchant(&pool, &merchant.id, &filter);
+        while let Some(row) = rows.next().await {
+            match row {
+                Ok(inv) => {
+                    if !sink.send(&ExportedInvoice::from(&inv)).await {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    sink.fail(e).await;
+                    break;
+                }
             }
         }
-    }
+    })
+}
 
-    let rates = match price_service.get_rates().await {
-        Ok(r) => r,
-        Err(e) => {
-            tracing::error!(error = %e, "Failed to fetch ZEC rate");
-            return HttpResponse::ServiceUnavailable().json(serde_json::json!({
-                "error": "Price feed unavailable"
-            }));
+/// Public invoice GET: returns only checkout-safe fields.
+/// Shipping info is NEVER exposed to unauthenticated callers.
+/// The owning merchant (API key or session) gets the merchant view with the per-transaction
+/// payment list and the buyer's checkout field answers.
+/// Anyone else asking by memo code goes through the public lookup and its limits.
+/// With `Accept: application/ld+json` the invoice is returned as schema.org JSON-LD.
+pub async fn get(
+    req: HttpRequest,
+    merchant: Option<AnyMerchant>,
+    pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
+    guard: web::Data<AbuseGuard>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let id_or_memo = path.into_inner();
+
+    let inv = match invoices::get_invoice(pool.get_ref(), &id_or_memo).await {
+        Ok(Some(inv)) => inv,
+        Ok(None) => {
+            let owned = match &merchant {
+                Some(AnyMerchant(m)) => invoices::get_invoice_by_memo(pool.get_ref(), &id_or_memo)
+                    .await
+                    .ok()
+                    .flatten()
+                    .filter(|inv| inv.merchant_id == m.id),
+                None => None,
+            };
+            match owned {
+                Some(inv) => inv,
+                None => return super::public_memo_lookup(&req, pool.get_ref(), &guard, &id_or_memo).await,
+            }
         }
+        Err(e) => return e.error_response(),
     };
 
-    let fee_config = if config.fee_enabled() {
-        config.fee_address.as_ref().map(|addr| invoices::FeeConfig {
-            fee_address: addr.clone(),
-            fee_rate: config.fee_rate,
-        })
-    } else {
-        None
+    let merchant_origin = get_merchant_webhook_origin(pool.get_ref(), &inv.merchant_id).await;
+    let product_image_url = crate::products::invoice_image_url(pool.get_ref(), &inv.id)
+        .await
+        .unwrap_or_default();
+    let confirmations = invoices::confirmations(pool.get_ref(), &inv.id).await.unwrap_or_default();
+
+    if wants_json_ld(&req) {
+        let api_url = crate::email::public_api_url(&config);
+        let public = PublicInvoice::new(&inv)
+            .with_product_image(product_image_url.map(|path| format!("{}{}", api_url, path)))
+            .with_merchant_origin(merchant_origin);
+        return HttpResponse::Ok()
+            .content_type("application/ld+json")
+            .insert_header(("Vary", "Accept"))
+            .body(public.json_ld(&crate::email::payment_page_link(&config, &inv.id)).to_string());
+    }
+
+    let is_owner = merchant.is_some_and(|AnyMerchant(m)| m.id == inv.merchant_id);
+    if !is_owner {
+        return HttpResponse::Ok().json(
+            PublicInvoice::new(&inv)
+                .with_product_image(product_image_url)
+                .with_merchant_origin(merchant_origin)
+                .with_confirmations(confirmations),
+        );
+    }
+
+    let mut body = MerchantInvoice::new(&inv);
+    body.public = body.public
+        .with_product_image(product_image_url)
+        .with_merchant_origin(merchant_origin)
+        .with_confirmations(confirmations);
+    match invoices::get_payments(pool.get_ref(), &inv.id).await {
+        Ok(payments) => body = body.with_payments(payments),
+        Err(e) => tracing::warn!(invoice_id = %inv.id, error = %e, "Failed to load invoice payments"),
+    }
+    match invoices::custom_fields(pool.get_ref(), &inv.id, &config.encryption_key).await {
+        Ok(fields) => body = body.with_custom_fields(fields),
+        Err(e) => tracing::warn!(invoice_id = %inv.id, error = %e, "Failed to read checkout fields"),
+    }
+
+    HttpResponse::Ok().json(body)
+}
+
+/// `Accept: application/ld+json` asks for the schema.org form of an invoice.
+fn wants_json_ld(req: &HttpRequest) -> bool {
+    req.headers()
+        .get(actix_web::http::header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/ld+json"))
+}
+
+/// Invoice lifecycle timeline (API key or dashboard session, owning merchant only).
+pub async fn events(
+    AnyMerchant(merchant): AnyMerchant,
+    service: web::Data<InvoiceService>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let invoice_id = path.into_inner();
+    match service.events(&merchant, &invoice_id).await {
+        Ok(events) => HttpResponse::Ok().json(serde_json::json!({
+            "invoice_id": invoice_id,
+            "events": events,
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// Viewing proof for an auditor holding the merchant's UFVK (API key or dashboard
+/// session, owning merchant only). See `scanner::proof`.
+pub async fn proof(
+    AnyMerchant(merchant): AnyMerchant,
+    pool: web::Data<SqlitePool>,
+    cipherscans: web::Data<CipherScans>,
+    service: web::Data<InvoiceService>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let inv = match service.get(&merchant, &path.into_inner()).await {
+        Ok(inv) => inv,
+        Err(e) => return e.error_response(),
     };
 
-    match invoices::create_invoice(
-        pool.get_ref(),
-        &merchant.id,
-        &merchant.ufvk,
-        &body,
-        rates.zec_eur,
-        rates.zec_usd,
-        config.invoice_expiry_minutes,
-        fee_config.as_ref(),
-    )
-    .await
-    {
-        Ok(resp) => HttpResponse::Created().json(resp),
+    match crate::scanner::proof::build(pool.get_ref(), cipherscans.for_network(&merchant.network), &inv, &merchant.ufvk).await {
+        Ok(proof) => HttpResponse::Ok().json(proof),
         Err(e) => {
-            tracing::error!(error = %e, "Failed to create invoice");
+            tracing::error!(invoice_id = %inv.id, error = %e, "Failed to build viewing proof");
             HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Failed to create invoice"
+                "error": "Internal error"
             }))
         }
     }
 }
 
-/// Public invoice GET: returns only checkout-safe fields.
-/// Shipping info is NEVER exposed to unauthenticated callers.
-pub async fn get(
+#[derive(Debug, serde::Deserialize)]
+pub struct RefundUriQuery {
+    /// Partial refund amount in ZEC; defaults to the full received amount.
+    pub amount: Option<f64>,
+    /// Address book entry (`refund_source`) to send from; defaults to the marked one.
+    pub source: Option<String>,
+}
+
+/// Generate a ZIP-321 refund URI for the buyer's refund address
+/// (API key or dashboard session, owning merchant only).
+pub async fn refund_uri(
+    AnyMerchant(merchant): AnyMerchant,
+    service: web::Data<InvoiceService>,
+    path: web::Path<String>,
+    query: web::Query<RefundUriQuery>,
+) -> HttpResponse {
+    match service.refund_uri(&merchant, &path.into_inner(), query.amount, query.source.as_deref()).await {
+        Ok(uri) => HttpResponse::Ok().json(uri),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// The merchant's invoice templates (API key or dashboard session).
+pub async fn list_templates(AnyMerchant(merchant): AnyMerchant, service: web::Data<InvoiceService>) -> HttpResponse {
+    match service.templates(&merchant).await {
+        Ok(templates) => HttpResponse::Ok().json(serde_json::json!({ "templates": templates })),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn add_template(
+    AnyMerchant(merchant): AnyMerchant,
+    service: web::Data<InvoiceService>,
+    body: web::Json<TemplateFields>,
+) -> HttpResponse {
+    match service.add_template(&merchant, body.into_inner()).await {
+        Ok(template) => HttpResponse::Created().json(template),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn update_template(
+    AnyMerchant(merchant): AnyMerchant,
+    service: web::Data<InvoiceService>,
+    path: web::Path<String>,
+    body: web::Json<UpdateTemplateRequest>,
+) -> HttpResponse {
+    match service.update_template(&merchant, &path, body.into_inner()).await {
+        Ok(template) => HttpResponse::Ok().json(template),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn remove_template(
+    AnyMerchant(merchant): AnyMerchant,
+    service: web::Data<InvoiceService>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    match service.remove_template(&merchant, &path).await {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({ "status": "deleted" })),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// Create an invoice from a template, priced at the current rate.
+pub async fn issue_template(
+    AnyMerchant(merchant): AnyMerchant,
+    service: web::Data<InvoiceService>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    match service.issue_template(&merchant, &path).await {
+        Ok(created) => HttpResponse::Created().json(created),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// Create an invoice and email the buyer a link to pay it.
+pub async fn email_invoice(
+    AnyMerchant(merchant): AnyMerchant,
+    service: web::Data<InvoiceService>,
+    body: web::Json<EmailInvoiceRequest>,
+) -> HttpResponse {
+    match service.email_invoice(&merchant, body.into_inner()).await {
+        Ok(request) => HttpResponse::Created().json(request),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct PageQuery {
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+/// The merchant's emailed payment requests, newest first, with whether each was opened and paid.
+pub async fn payment_requests(
+    AnyMerchant(merchant): AnyMerchant,
+    service: web::Data<InvoiceService>,
+    query: web::Query<PageQuery>,
+) -> HttpResponse {
+    let limit = query.limit.unwrap_or(50).clamp(1, 200);
+    let offset = query.offset.unwrap_or(0).max(0);
+    match service.payment_requests(&merchant, limit, offset).await {
+        Ok(requests) => HttpResponse::Ok().json(serde_json::json!({ "payment_requests": requests })),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// The payment link in a payment request email: count the opening and send the buyer on
+/// to the hosted payment page.
+pub async fn open_payment_request(
+    config: web::Data<Config>,
+    service: web::Data<InvoiceService>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    match service.open_payment_request(&path).await {
+        Ok(invoice) => HttpResponse::Found()
+            .insert_header(("Location", crate::email::payment_page_link(&config, &invoice.id)))
+            .finish(),
+        Err(e) => e.error_response(),
+    }
+}
+
+/// The QR code image in a payment request email. Loading it counts as an opening.
+pub async fn payment_request_qr(
+    service: web::Data<InvoiceService>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let invoice = match service.open_payment_request(&path).await {
+        Ok(invoice) => invoice,
+        Err(e) => return e.error_response(),
+    };
+    let Some(uri) = invoice.payment_uri(invoices::UriFormat::Multi) else {
+        return HttpResponse::NotFound().finish();
+    };
+    match super::generate_qr_png(&uri) {
+        Ok(png_bytes) => HttpResponse::Ok()
+            .content_type("image/png")
+            .insert_header(("Cache-Control", "no-store"))
+            .body(png_bytes),
+        Err(_) => HttpResponse::InternalServerError().finish(),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct UnmatchedQuery {
+    /// Also list payments already attached to an invoice.
+    #[serde(default)]
+    pub include_attached: bool,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+/// Payments the scanner decrypted for the merchant but matched to no open invoice
+/// (API key or dashboard session).
+pub async fn unmatched_payments(
+    AnyMerchant(merchant): AnyMerchant,
+    service: web::Data<InvoiceService>,
+    query: web::Query<UnmatchedQuery>,
+) -> HttpResponse {
+    let limit = query.limit.unwrap_or(50).clamp(1, 200);
+    let offset = query.offset.unwrap_or(0).max(0);
+    match service.unmatched_payments(&merchant, query.include_attached, limit, offset).await {
+        Ok(payments) => HttpResponse::Ok().json(serde_json::json!({ "unmatched_payments": payments })),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct AttachPaymentRequest {
+    pub invoice_id: String,
+}
+
+/// Apply an unmatched payment to one of the merchant's pending, underpaid or expired
+/// invoices, with the same transitions and webhooks as a scanner match.
+pub async fn attach_payment(
+    AnyMerchant(merchant): AnyMerchant,
+    service: web::Data<InvoiceService>,
+    path: web::Path<String>,
+    body: web::Json<AttachPaymentRequest>,
+) -> HttpResponse {
+    match service.attach_payment(&merchant, &path.into_inner(), &body.invoice_id).await {
+        Ok(invoice) => HttpResponse::Ok().json(MerchantInvoice::new(&invoice)),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct UriFormatRequest {
+    pub format: invoices::UriFormat,
+    /// Wallet the buyer picked, if the checkout asks (e.g. `zashi`, `ywallet`).
+    pub wallet: Option<String>,
+}
+
+/// Public: the checkout reports which payment URI it is showing, and gets it back.
+/// Recorded per invoice so the operator can see which wallets drop the fee output.
+pub async fn set_uri_format(
     pool: web::Data<SqlitePool>,
     path: web::Path<String>,
+    body: web::Json<UriFormatRequest>,
 ) -> HttpResponse {
-    let id_or_memo = path.into_inner();
+    let wallet = body.wallet.as_deref().map(|w| w.trim().to_ascii_lowercase()).filter(|w| !w.is_empty());
+    if let Some(w) = &wallet {
+        if w.len() > 32 || !w.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return HttpResponse::BadRequest().json(validation::ValidationError::invalid(
+                "wallet", "must be up to 32 letters, digits, hyphens or underscores",
+            ).to_json());
+        }
+    }
 
-    let invoice = match invoices::get_invoice(pool.get_ref(), &id_or_memo).await {
-        Ok(Some(inv)) => Some(inv),
-        Ok(None) => invoices::get_invoice_by_memo(pool.get_ref(), &id_or_memo)
-            .await
-            .ok()
-            .flatten(),
-        Err(e) => {
-            tracing::error!(error = %e, "Failed to get invoice");
-            return HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Internal error"
+    let inv = match invoices::get_invoice(pool.get_ref(), &path.into_inner()).await {
+        Ok(Some(inv)) => inv,
+        Ok(None) => {
+            return HttpResponse::NotFound().json(serde_json::json!({
+                "error": "Invoice not found"
             }));
         }
+        Err(e) => return e.error_response(),
+    };
+    let Some(zcash_uri) = inv.payment_uri(body.format) else {
+        return HttpResponse::BadRequest().json(validation::ValidationError::invalid(
+            "format", "this invoice has no TEX address",
+        ).to_json());
     };
 
-    match invoice {
-        Some(inv) => {
-            let received_zec = invoices::zatoshis_to_zec(inv.received_zatoshis);
-            let overpaid = inv.received_zatoshis > inv.price_zatoshis + 1000 && inv.price_zatoshis > 0;
-
-            let merchant_origin = get_merchant_webhook_origin(pool.get_ref(), &inv.merchant_id).await;
-
-            HttpResponse::Ok().json(serde_json::json!({
-                "id": inv.id,
-                "memo_code": inv.memo_code,
-                "product_name": inv.product_name,
-                "size": inv.size,
-                "price_eur": inv.price_eur,
-                "price_usd": inv.price_usd,
-                "currency": inv.currency,
-                "price_zec": inv.price_zec,
-                "zec_rate_at_creation": inv.zec_rate_at_creation,
-                "payment_address": inv.payment_address,
-                "zcash_uri": inv.zcash_uri,
-                "merchant_name": inv.merchant_name,
-                "merchant_origin": merchant_origin,
-                "status": inv.status,
-                "detected_txid": inv.detected_txid,
-                "detected_at": inv.detected_at,
-                "confirmed_at": inv.confirmed_at,
-                "refunded_at": inv.refunded_at,
-                "expires_at": inv.expires_at,
-                "created_at": inv.created_at,
-                "received_zec": received_zec,
-                "price_zatoshis": inv.price_zatoshis,
-                "received_zatoshis": inv.received_zatoshis,
-                "overpaid": overpaid,
-            }))
+    match invoices::set_uri_format(pool.get_ref(), &inv.id, body.format, wallet.as_deref()).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return HttpResponse::Conflict().json(serde_json::json!({
+                "error": "Invoice is no longer awaiting payment"
+            }));
         }
-        None => HttpResponse::NotFound().json(serde_json::json!({
-            "error": "Invoice not found"
-        })),
+        Err(e) => return e.error_response(),
     }
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "format": body.format,
+        "zcash_uri": zcash_uri,
+    }))
 }
 
 /// Extract the origin (scheme+host+port) from a merchant's webhook URL.
@@ -154,68 +472,28 @@ async fn get_merchant_webhook_origin(pool: &SqlitePool, merchant_id: &str) -> Op
     url::Url::parse(&webhook_url).ok().map(|u| u.origin().ascii_serialization())
 }
 
-/// Resolve the merchant from the request:
-/// 1. If Authorization header has "Bearer cpay_...", authenticate by API key
-/// 2. Try session cookie (dashboard)
-/// 3. In testnet, fall back to sole merchant (single-tenant test mode)
-async fn resolve_merchant(
-    req: &HttpRequest,
+/// Single-tenant test mode: with no credentials, invoices go to the sole merchant.
+/// Testnet only.
+async fn single_tenant_merchant(
     pool: &SqlitePool,
     config: &Config,
 ) -> Option<crate::merchants::Merchant> {
-    if let Some(auth) = req.headers().get("Authorization") {
-        if let Ok(auth_str) = auth.to_str() {
-            let key = auth_str
-                .strip_prefix("Bearer ")
-                .unwrap_or(auth_str)
-                .trim();
-
-            if key.starts_with("cpay_sk_") || key.starts_with("cpay_") {
-                return crate::merchants::authenticate(pool, key, &config.encryption_key)
-                    .await
-                    .ok()
-                    .flatten();
-            }
-        }
+    if !config.is_testnet() {
+        return None;
     }
 
-    if let Some(merchant) = crate::api::auth::resolve_session(req, pool).await {
-        return Some(merchant);
-    }
-
-    // Single-tenant fallback: ONLY in testnet mode
-    if config.is_testnet() {
-        return crate::merchants::get_all_merchants(pool, &config.encryption_key)
-            .await
-            .ok()
-            .and_then(|m| {
-                if m.len() == 1 {
-                    m.into_iter().next()
-                } else {
-                    tracing::warn!(
-                        count = m.len(),
-                        "Multiple merchants but no API key provided"
-                    );
-                    None
-                }
-            });
-    }
-
-    None
-}
-
-fn validate_invoice_request(req: &CreateInvoiceRequest) -> Result<(), validation::ValidationError> {
-    validation::validate_optional_length("product_id", &req.product_id, 100)?;
-    validation::validate_optional_length("product_name", &req.product_name, 200)?;
-    validation::validate_optional_length("size", &req.size, 100)?;
-    validation::validate_optional_length("currency", &req.currency, 10)?;
-    if let Some(ref addr) = req.refund_address {
-        if !addr.is_empty() {
-            validation::validate_zcash_address("refund_address", addr)?;
-        }
-    }
-    if req.price_eur < 0.0 {
-        return Err(validation::ValidationError::invalid("price_eur", "must be non-negative"));
-    }
-    Ok(())
+    crate::merchants::get_all_merchants(pool, &config.encryption_key)
+        .await
+        .ok()
+        .and_then(|m| {
+            if m.len() == 1 {
+                m.into_iter().next()
+            } else {
+                tracing::warn!(
+                    count = m.len(),
+                    "Multiple merchants but no API key provided"
+                );
+                None
+            }
+        })
 }
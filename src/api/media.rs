@@ -0,0 +1,35 @@
+use actix_web::{web, HttpResponse};
+
+use crate::config::Config;
+
+/// Public media route for product images. Keys are random and never reused,
+/// so responses can be cached indefinitely.
+pub async fn get(
+    config: web::Data<Config>,
+    http: web::Data<reqwest::Client>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let key = path.into_inner();
+    if !crate::media::is_valid_key(&key) {
+        return HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Not found"
+        }));
+    }
+
+    match crate::media::load(&config, &http, &key).await {
+        Ok(Some(bytes)) => HttpResponse::Ok()
+            .content_type(crate::media::content_type_for(&key))
+            .insert_header(("Cache-Control", "public, max-age=31536000, immutable"))
+            .insert_header(("X-Content-Type-Options", "nosniff"))
+            .body(bytes),
+        Ok(None) => HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Not found"
+        })),
+        Err(e) => {
+            tracing::error!(key = %key, error = %e, "Failed to load media");
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            }))
+        }
+    }
+}
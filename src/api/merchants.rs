@@ -1,48 +1,151 @@
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpResponse, ResponseError};
+use serde::Deserialize;
 use sqlx::SqlitePool;
 
-use crate::config::Config;
-use crate::merchants::{CreateMerchantRequest, create_merchant};
-use crate::validation;
+use super::extract::SessionMerchant;
+use crate::merchants::CreateMerchantRequest;
+use crate::scanner::cipherscan::CipherScans;
+use crate::scanner::dry_run;
+use crate::services::MerchantService;
 
 pub async fn create(
-    pool: web::Data<SqlitePool>,
-    config: web::Data<Config>,
+    service: web::Data<MerchantService>,
     body: web::Json<CreateMerchantRequest>,
 ) -> HttpResponse {
-    if let Err(e) = validate_registration(&body, config.is_testnet()) {
-        return HttpResponse::BadRequest().json(e.to_json());
+    match service.register(&body).await {
+        Ok(resp) => HttpResponse::Created().json(resp),
+        Err(e) => e.error_response(),
     }
+}
 
-    match create_merchant(pool.get_ref(), &body, &config.encryption_key).await {
-        Ok(resp) => HttpResponse::Created().json(resp),
+#[derive(Debug, Deserialize)]
+pub struct UfvkCheckRequest {
+    pub blocks: Option<u64>,
+}
+
+/// Start a shadow scan of recent blocks with the merchant's UFVK (see `scanner::dry_run`).
+pub async fn start_ufvk_check(
+    SessionMerchant(merchant): SessionMerchant,
+    pool: web::Data<SqlitePool>,
+    cipherscans: web::Data<CipherScans>,
+    body: Option<web::Json<UfvkCheckRequest>>,
+) -> HttpResponse {
+    let blocks = body.and_then(|b| b.blocks).unwrap_or(dry_run::DEFAULT_BLOCKS);
+    if blocks == 0 || blocks > dry_run::MAX_BLOCKS {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": format!("blocks must be between 1 and {}", dry_run::MAX_BLOCKS)
+        }));
+    }
+
+    match dry_run::start(pool.get_ref(), cipherscans.for_network(&merchant.network), &merchant.id, &merchant.ufvk, blocks).await {
+        Ok(Some(id)) => HttpResponse::Accepted().json(serde_json::json!({
+            "id": id,
+            "status": "running",
+            "blocks": blocks,
+        })),
+        Ok(None) => HttpResponse::Conflict().json(serde_json::json!({
+            "error": "A check is already running"
+        })),
         Err(e) => {
-            tracing::error!(error = %e, "Failed to create merchant");
+            tracing::error!(error = %e, "Failed to start UFVK check");
             HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Failed to create merchant"
+                "error": "Internal error"
             }))
         }
     }
 }
 
-fn validate_registration(
-    req: &CreateMerchantRequest,
-    is_testnet: bool,
-) -> Result<(), validation::ValidationError> {
-    if let Some(ref name) = req.name {
-        validation::validate_length("name", name, 100)?;
-    }
-    validation::validate_length("ufvk", &req.ufvk, 2000)?;
-    validation::validate_ufvk_network("ufvk", &req.ufvk, is_testnet)?;
-    if let Some(ref url) = req.webhook_url {
-        if !url.is_empty() {
-            validation::validate_webhook_url("webhook_url", url, is_testnet)?;
+/// The merchant's UFVK: the stored key re-checked (network, Orchard component, and that
+/// index 0 still derives the payment address on file), with the latest shadow scan of
+/// recent blocks under `scan`, null until one has been run.
+pub async fn ufvk_check(
+    SessionMerchant(merchant): SessionMerchant,
+    pool: web::Data<SqlitePool>,
+) -> HttpResponse {
+    let scan = match dry_run::latest(pool.get_ref(), &merchant.id).await {
+        Ok(scan) => scan,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to load UFVK check");
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            }));
         }
+    };
+    let health = crate::addresses::check_ufvk(
+        &merchant.ufvk,
+        &merchant.payment_address,
+        merchant.diversifier_index,
+        merchant.network == "testnet",
+    );
+    let mut body = serde_json::json!(health);
+    body["scan"] = scan.into();
+    HttpResponse::Ok().json(body)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddressListQuery {
+    pub purpose: Option<String>,
+}
+
+/// The merchant's address book, optionally for one purpose.
+pub async fn list_addresses(
+    SessionMerchant(merchant): SessionMerchant,
+    service: web::Data<MerchantService>,
+    query: web::Query<AddressListQuery>,
+) -> HttpResponse {
+    match service.addresses(&merchant, query.purpose.as_deref()).await {
+        Ok(addresses) => HttpResponse::Ok().json(serde_json::json!({ "addresses": addresses })),
+        Err(e) => e.error_response(),
     }
-    if let Some(ref email) = req.email {
-        if !email.is_empty() {
-            validation::validate_email_format("email", email)?;
-        }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddAddressRequest {
+    pub label: String,
+    pub address: String,
+    pub purpose: String,
+    #[serde(default)]
+    pub is_default: bool,
+}
+
+pub async fn add_address(
+    SessionMerchant(merchant): SessionMerchant,
+    service: web::Data<MerchantService>,
+    body: web::Json<AddAddressRequest>,
+) -> HttpResponse {
+    match service.add_address(&merchant, &body.label, body.address.trim(), &body.purpose, body.is_default).await {
+        Ok(entry) => HttpResponse::Created().json(entry),
+        Err(e) => e.error_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateAddressRequest {
+    pub label: Option<String>,
+    /// Make this the default for its purpose. There is always at most one.
+    #[serde(default)]
+    pub is_default: bool,
+}
+
+pub async fn update_address(
+    SessionMerchant(merchant): SessionMerchant,
+    service: web::Data<MerchantService>,
+    path: web::Path<String>,
+    body: web::Json<UpdateAddressRequest>,
+) -> HttpResponse {
+    match service.update_address(&merchant, &path, body.label.as_deref(), body.is_default).await {
+        Ok(entry) => HttpResponse::Ok().json(entry),
+        Err(e) => e.error_response(),
+    }
+}
+
+pub async fn remove_address(
+    SessionMerchant(merchant): SessionMerchant,
+    service: web::Data<MerchantService>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    match service.remove_address(&merchant, &path).await {
+        Ok(()) => HttpResponse::Ok().json(serde_json::json!({ "status": "deleted" })),
+        Err(e) => e.error_response(),
     }
-    Ok(())
 }
@@ -1,405 +1,501 @@
+pub mod admin;
 pub mod auth;
+pub mod checkout;
+pub mod error;
+pub mod export;
+pub mod extract;
+pub mod graphql;
 pub mod invoices;
+pub mod media;
 pub mod merchants;
 pub mod products;
 pub mod rates;
+pub mod simulate;
 pub mod status;
+pub mod system;
+pub mod version;
+pub mod webhooks;
 pub mod x402;
 
-use actix_governor::{Governor, GovernorConfigBuilder};
-use actix_web::web;
+use actix_governor::Governor;
+use actix_web::{middleware, web, ResponseError};
 use actix_web_lab::sse;
-use base64::Engine;
 use sqlx::SqlitePool;
+
+use self::extract::{AnyMerchant, SessionMerchant};
+use crate::client_ip::{self, RateLimiter};
+use crate::db::ReadPool;
+use crate::invoices::views::{InvoiceAmounts, MemoLookup, MerchantInvoice, PublicInvoice, RequoteEvent, StatusEvent};
 use std::time::Duration;
 use tokio::time::interval;
 
-pub fn configure(cfg: &mut web::ServiceConfig) {
-    let auth_rate_limit = GovernorConfigBuilder::default()
-        .seconds_per_request(10)
-        .burst_size(5)
-        .finish()
-        .expect("Failed to build auth rate limiter");
+pub fn configure(cfg: &mut web::ServiceConfig, config: &crate::config::Config) {
+    let auth_rate_limit = client_ip::rate_limiter(config.auth_rate_limit, &config.trusted_proxies);
 
+    // The versioned scope goes first: `/api` would otherwise claim `/api/v1/...` paths.
+    cfg.service(
+        web::scope("/api/v1")
+            .wrap(middleware::from_fn(version::middleware))
+            .configure(|cfg| routes(cfg, &auth_rate_limit)),
+    );
     cfg.service(
         web::scope("/api")
-            .route("/health", web::get().to(health))
-            .service(
-                web::scope("/merchants")
-                    .wrap(Governor::new(&auth_rate_limit))
-                    .route("", web::post().to(merchants::create))
-                    .route("/me", web::get().to(auth::me))
-                    .route("/me", web::patch().to(auth::update_me))
-                    .route("/me/invoices", web::get().to(auth::my_invoices))
-                    .route("/me/regenerate-api-key", web::post().to(auth::regenerate_api_key))
-                    .route("/me/regenerate-dashboard-token", web::post().to(auth::regenerate_dashboard_token))
-                    .route("/me/regenerate-webhook-secret", web::post().to(auth::regenerate_webhook_secret))
-                    .route("/me/billing", web::get().to(billing_summary))
-                    .route("/me/billing/history", web::get().to(billing_history))
-                    .route("/me/billing/settle", web::post().to(billing_settle))
-                    .route("/me/delete", web::post().to(delete_account))
-                    .route("/me/x402/history", web::get().to(x402::history))
-            )
-            .service(
-                web::scope("/auth")
-                    .wrap(Governor::new(&auth_rate_limit))
-                    .route("/session", web::post().to(auth::create_session))
-                    .route("/logout", web::post().to(auth::logout))
-                    .route("/recover", web::post().to(auth::recover))
-                    .route("/recover/confirm", web::post().to(auth::recover_confirm))
-            )
-            // Product endpoints (dashboard auth)
-            .route("/products", web::post().to(products::create))
-            .route("/products", web::get().to(products::list))
-            .route("/products/{id}", web::patch().to(products::update))
-            .route("/products/{id}", web::delete().to(products::deactivate))
-            .route("/products/{id}/public", web::get().to(products::get_public))
-            // Buyer checkout (public)
-            .route("/checkout", web::post().to(checkout))
-            // Invoice endpoints (API key auth)
-            .route("/invoices", web::post().to(invoices::create))
-            .route("/invoices", web::get().to(list_invoices))
-            .route("/invoices/lookup/{memo_code}", web::get().to(lookup_by_memo))
-            .route("/invoices/{id}", web::get().to(invoices::get))
-            .route("/invoices/{id}/status", web::get().to(status::get))
-            .route("/invoices/{id}/stream", web::get().to(invoice_stream))
-            .route("/invoices/{id}/cancel", web::post().to(cancel_invoice))
-            .route("/invoices/{id}/refund", web::post().to(refund_invoice))
-            .route("/invoices/{id}/refund-address", web::patch().to(update_refund_address))
-            .route("/invoices/{id}/qr", web::get().to(qr_code))
-            .route("/rates", web::get().to(rates::get))
-            // x402 facilitator
-            .route("/x402/verify", web::post().to(x402::verify)),
+            .wrap(middleware::from_fn(version::middleware))
+            .configure(|cfg| routes(cfg, &auth_rate_limit)),
     );
 }
 
-/// Public checkout endpoint for buyer-driven invoice creation.
-/// Buyer selects a product, provides variant + shipping, invoice is created with server-side pricing.
-async fn checkout(
-    pool: web::Data<SqlitePool>,
-    config: web::Data<crate::config::Config>,
-    price_service: web::Data<crate::invoices::pricing::PriceService>,
-    body: web::Json<CheckoutRequest>,
-) -> actix_web::HttpResponse {
-    if let Err(e) = validate_checkout(&body) {
-        return actix_web::HttpResponse::BadRequest().json(e.to_json());
-    }
-
-    let product = match crate::products::get_product(pool.get_ref(), &body.product_id).await {
-        Ok(Some(p)) if p.active == 1 => p,
-        Ok(Some(_)) => {
-            return actix_web::HttpResponse::BadRequest().json(serde_json::json!({
-                "error": "Product is no longer available"
-            }));
-        }
-        _ => {
-            return actix_web::HttpResponse::NotFound().json(serde_json::json!({
-                "error": "Product not found"
-            }));
-        }
-    };
+/// Every API route, mounted under `/api/v1` and under `/api` as an unversioned alias.
+fn routes(cfg: &mut web::ServiceConfig, auth_rate_limit: &RateLimiter) {
+    cfg
+        .route("/health", web::get().to(health))
+        .route("/system/status", web::get().to(system::status))
+        .route("/admin/smtp-check", web::get().to(admin::smtp_check))
+        .route("/admin/emails", web::get().to(admin::list_emails))
+        .route("/admin/emails/{id}/retry", web::post().to(admin::retry_email))
+        .route("/admin/rates", web::post().to(admin::set_rates))
+        .route("/admin/rates", web::delete().to(admin::clear_rates))
+        .route("/admin/revenue", web::get().to(admin::revenue))
+        .route("/admin/revenue/merchants", web::get().to(admin::top_merchants))
+        .route("/admin/merchants/{id}/fees", web::patch().to(admin::update_fees))
+        .route("/admin/diversifiers", web::get().to(admin::diversifiers))
+        .route("/admin/wallets", web::get().to(admin::wallet_compatibility))
+        .route("/admin/cipherscan", web::get().to(admin::cipherscan_status))
+        .route("/admin/jobs", web::get().to(admin::jobs))
+        .route("/admin/backup", web::post().to(admin::export_backup))
+        .route("/admin/backup/restore", web::post().to(admin::restore_backup))
+        // Public storefront catalog (outside the rate-limited /merchants scope)
+        .route("/merchants/{id}/catalog", web::get().to(products::catalog))
+        .route("/merchants/me/ufvk/check", web::get().to(merchants::ufvk_check))
+        .route("/merchants/me/events/stream", web::get().to(merchant_stream))
+        .service(
+            web::scope("/merchants")
+                .wrap(Governor::new(auth_rate_limit))
+                .route("", web::post().to(merchants::create))
+                .route("/me", web::get().to(auth::me))
+                .route("/me", web::patch().to(auth::update_me))
+                .route("/me/invoices", web::get().to(auth::my_invoices))
+                .route("/me/unmatched-payments", web::get().to(invoices::unmatched_payments))
+                .route("/me/unmatched-payments/{id}/attach", web::post().to(invoices::attach_payment))
+                .route("/me/regenerate-api-key", web::post().to(auth::regenerate_api_key))
+                .route("/me/regenerate-dashboard-token", web::post().to(auth::regenerate_dashboard_token))
+                .route("/me/regenerate-webhook-secret", web::post().to(auth::regenerate_webhook_secret))
+                .route("/me/billing", web::get().to(billing_summary))
+                .route("/me/billing/history", web::get().to(billing_history))
+                .route("/me/billing/settle", web::post().to(billing_settle))
+                .route("/me/delete", web::post().to(delete_account))
+                .route("/me/reset", web::post().to(reset_sandbox))
+                .route("/me/ufvk/check", web::post().to(merchants::start_ufvk_check))
+                .route("/me/addresses", web::get().to(merchants::list_addresses))
+                .route("/me/addresses", web::post().to(merchants::add_address))
+                .route("/me/addresses/{id}", web::patch().to(merchants::update_address))
+                .route("/me/addresses/{id}", web::delete().to(merchants::remove_address))
+                .route("/me/x402/history", web::get().to(x402::history))
+        )
+        .service(
+            web::scope("/auth")
+                .wrap(Governor::new(auth_rate_limit))
+                .route("/session", web::post().to(auth::create_session))
+                .route("/logout", web::post().to(auth::logout))
+                .route("/elevate", web::post().to(auth::elevate))
+                .route("/recover", web::post().to(auth::recover))
+                .route("/recover/confirm", web::post().to(auth::recover_confirm))
+        )
+        // Product endpoints (dashboard auth)
+        .route("/products", web::post().to(products::create))
+        .route("/products", web::get().to(products::list))
+        .service(
+            web::resource("/products/import")
+                .app_data(web::PayloadConfig::new(crate::products::import::MAX_IMPORT_BYTES))
+                .route(web::post().to(products::import)),
+        )
+        .route("/products/export", web::get().to(products::export))
+        .route("/products/archive", web::post().to(products::archive_bulk))
+        .route("/products/cleanup", web::post().to(products::cleanup))
+        .route("/products/{id}", web::patch().to(products::update))
+        .route("/products/{id}", web::delete().to(products::deactivate))
+        .route("/products/{id}/archive", web::post().to(products::archive))
+        .route("/products/{id}/public", web::get().to(products::get_public))
+        .route("/products/{id}/images", web::post().to(products::upload_image))
+        .route("/products/{id}/images/{image_id}", web::delete().to(products::delete_image))
+        .route("/media/{key}", web::get().to(media::get))
+        // Buyer checkout (public)
+        .route("/checkout", web::post().to(checkout::checkout))
+        .route("/checkout/sessions", web::post().to(checkout::create_session))
+        .route("/checkout/sessions/{id}", web::get().to(checkout::get_session))
+        .route("/checkout/sessions/{id}", web::patch().to(checkout::update_session))
+        .route("/checkout/sessions/{id}/pay", web::post().to(checkout::pay_session))
+        .route("/pow/challenge", web::get().to(pow_challenge))
+        // Invoice endpoints (API key auth)
+        .route("/invoices", web::post().to(invoices::create))
+        .route("/invoices", web::get().to(list_invoices))
+        .route("/invoices/export", web::get().to(invoices::export))
+        .route("/graphql", web::post().to(graphql::handler))
+        .route("/invoices/lookup/{memo_code}", web::get().to(lookup_by_memo))
+        .route("/invoices/claim", web::post().to(claim_invoice))
+        .route("/invoices/{id}", web::get().to(invoices::get))
+        .route("/invoices/{id}/status", web::get().to(status::get))
+        .route("/invoices/{id}/events", web::get().to(invoices::events))
+        .route("/invoices/{id}/proof", web::get().to(invoices::proof))
+        .route("/invoices/{id}/diagnose", web::get().to(diagnose_invoice))
+        .route("/invoices/{id}/stream", web::get().to(invoice_stream))
+        .route("/invoices/{id}/cancel", web::post().to(cancel_invoice))
+        .route("/invoices/{id}/refund", web::post().to(refund_invoice))
+        .route("/invoices/{id}/refund-uri", web::get().to(invoices::refund_uri))
+        .route("/invoices/{id}/refund-txid", web::post().to(register_refund_txid))
+        .route("/invoices/{id}/refund-address", web::patch().to(update_refund_address))
+        // Testnet only
+        .route("/invoices/{id}/simulate-detect", web::post().to(simulate::detect))
+        .route("/invoices/{id}/simulate-confirm", web::post().to(simulate::confirm))
+        .route("/invoices/{id}/simulate-expire", web::post().to(simulate::expire))
+        .route("/invoices/{id}/qr", web::get().to(qr_code))
+        .route("/invoices/{id}/uri-format", web::post().to(invoices::set_uri_format))
+        .route("/invoice-templates", web::get().to(invoices::list_templates))
+        .route("/invoice-templates", web::post().to(invoices::add_template))
+        .route("/invoice-templates/{id}", web::patch().to(invoices::update_template))
+        .route("/invoice-templates/{id}", web::delete().to(invoices::remove_template))
+        .route("/invoice-templates/{id}/issue", web::post().to(invoices::issue_template))
+        .route("/payment-requests", web::post().to(invoices::email_invoice))
+        .route("/payment-requests", web::get().to(invoices::payment_requests))
+        .route("/payment-requests/{id}/open", web::get().to(invoices::open_payment_request))
+        .route("/payment-requests/{id}/qr", web::get().to(invoices::payment_request_qr))
+        .route("/rates", web::get().to(rates::get))
+        .route("/webhooks/signing-info", web::get().to(webhooks::signing_info))
+        .route("/webhooks/verify", web::post().to(webhooks::verify))
+        // x402 facilitator
+        .route("/x402/verify", web::post().to(x402::verify));
+}
 
-    if let Some(ref variant) = body.variant {
-        let valid_variants = product.variants_list();
-        if !valid_variants.is_empty() && !valid_variants.contains(variant) {
-            return actix_web::HttpResponse::BadRequest().json(serde_json::json!({
-                "error": "Invalid variant",
-                "valid_variants": valid_variants,
-            }));
-        }
+/// Ban and proof-of-work checks for the public endpoints that create or read invoices.
+/// Returns the client IP to strike later misbehaviour against.
+fn screen(
+    req: &actix_web::HttpRequest,
+    guard: &crate::abuse::AbuseGuard,
+) -> Result<Option<std::net::IpAddr>, actix_web::HttpResponse> {
+    let ip = crate::client_ip::from_request(req);
+    if let Some(remaining) = ip.and_then(|ip| guard.banned(ip)) {
+        return Err(actix_web::HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", remaining.as_secs().max(1).to_string()))
+            .json(serde_json::json!({ "error": "Too many requests, try again later" })));
     }
-
-    let merchant = match crate::merchants::get_all_merchants(pool.get_ref(), &config.encryption_key).await {
-        Ok(merchants) => match merchants.into_iter().find(|m| m.id == product.merchant_id) {
-            Some(m) => m,
-            None => {
-                return actix_web::HttpResponse::InternalServerError().json(serde_json::json!({
-                    "error": "Merchant not found"
-                }));
+    if guard.pow_required() {
+        let solution = req.headers().get(crate::abuse::POW_HEADER).and_then(|v| v.to_str().ok());
+        if let Err(e) = guard.verify_pow(solution) {
+            if e != crate::abuse::PowError::Missing {
+                if let Some(ip) = ip {
+                    guard.strike(ip);
+                }
             }
-        },
-        Err(_) => {
-            return actix_web::HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Internal error"
-            }));
+            return Err(actix_web::HttpResponse::Forbidden().json(serde_json::json!({
+                "error": "Proof of work required",
+                "reason": format!("{:?}", e).to_lowercase(),
+                "challenge_url": "/api/v1/pow/challenge",
+            })));
         }
-    };
+    }
+    Ok(ip)
+}
 
-    if config.fee_enabled() {
-        if let Ok(status) = crate::billing::get_merchant_billing_status(pool.get_ref(), &merchant.id).await {
-            if status == "past_due" || status == "suspended" {
-                return actix_web::HttpResponse::PaymentRequired().json(serde_json::json!({
-                    "error": "Merchant account has outstanding fees",
-                    "billing_status": status,
-                }));
-            }
-        }
+/// Proof-of-work challenge for checkout and lookup, when the operator requires one.
+async fn pow_challenge(guard: web::Data<crate::abuse::AbuseGuard>) -> actix_web::HttpResponse {
+    if !guard.pow_required() {
+        return actix_web::HttpResponse::Ok().json(serde_json::json!({ "required": false }));
     }
+    let challenge = guard.challenge();
+    actix_web::HttpResponse::Ok().json(serde_json::json!({
+        "required": true,
+        "challenge": challenge.challenge,
+        "difficulty": challenge.difficulty,
+        "expires_at": challenge.expires_at,
+    }))
+}
 
-    let rates = match price_service.get_rates().await {
-        Ok(r) => r,
-        Err(e) => {
-            tracing::error!(error = %e, "Failed to fetch ZEC rate for checkout");
-            return actix_web::HttpResponse::ServiceUnavailable().json(serde_json::json!({
-                "error": "Price feed unavailable"
-            }));
-        }
-    };
+/// Public liveness check. Which jobs are failing is for operators, on `GET /api/admin/jobs`.
+async fn health() -> actix_web::HttpResponse {
+    actix_web::HttpResponse::Ok().json(serde_json::json!({
+        "status": "ok",
+        "service": "cipherpay",
+        "jobs": {
+            "total": crate::jobs::statuses().len(),
+            "healthy": crate::jobs::unhealthy().is_empty(),
+        },
+    }))
+}
 
-    let invoice_req = crate::invoices::CreateInvoiceRequest {
-        product_id: Some(product.id.clone()),
-        product_name: Some(product.name.clone()),
-        size: body.variant.clone(),
-        price_eur: product.price_eur,
-        currency: Some(product.currency.clone()),
-        refund_address: body.refund_address.clone(),
-    };
+#[derive(Debug, serde::Deserialize)]
+struct ListInvoicesQuery {
+    status: Option<String>,
+    received_gt: Option<i64>,
+}
 
-    let fee_config = if config.fee_enabled() {
-        config.fee_address.as_ref().map(|addr| crate::invoices::FeeConfig {
-            fee_address: addr.clone(),
-            fee_rate: config.fee_rate,
-        })
-    } else {
-        None
+/// List invoices: requires API key or session auth. Scoped to the authenticated merchant.
+/// `?status=expired&received_gt=0` lists expired invoices holding partial payments to refund.
+async fn list_invoices(
+    AnyMerchant(merchant): AnyMerchant,
+    pool: web::Data<ReadPool>,
+    query: web::Query<ListInvoicesQuery>,
+) -> actix_web::HttpResponse {
+    let status = match query.status.as_deref() {
+        None => None,
+        Some(s) => match crate::invoices::state::InvoiceState::parse(s) {
+            Some(state) => Some(state),
+            None => return actix_web::HttpResponse::BadRequest().json(serde_json::json!({
+                "error": format!("Unknown invoice status: {}", s)
+            })),
+        },
     };
+    let filter = crate::invoices::InvoiceFilter { status, received_gt: query.received_gt };
 
-    match crate::invoices::create_invoice(
-        pool.get_ref(),
-        &merchant.id,
-        &merchant.ufvk,
-        &invoice_req,
-        rates.zec_eur,
-        rates.zec_usd,
-        config.invoice_expiry_minutes,
-        fee_config.as_ref(),
-    )
-    .await
-    {
-        Ok(resp) => actix_web::HttpResponse::Created().json(resp),
-        Err(e) => {
-            tracing::error!(error = %e, "Checkout invoice creation failed");
-            actix_web::HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Failed to create invoice"
-            }))
+    match crate::invoices::list_for_merchant(pool.get_ref(), &merchant.id, &filter, 50).await {
+        Ok(invoices) => {
+            let body: Vec<_> = invoices.iter().map(MerchantInvoice::new).collect();
+            actix_web::HttpResponse::Ok().json(body)
         }
+        Err(e) => e.error_response(),
     }
 }
 
-#[derive(Debug, serde::Deserialize)]
-struct CheckoutRequest {
-    product_id: String,
-    variant: Option<String>,
-    refund_address: Option<String>,
+async fn lookup_by_memo(
+    req: actix_web::HttpRequest,
+    pool: web::Data<SqlitePool>,
+    guard: web::Data<crate::abuse::AbuseGuard>,
+    path: web::Path<String>,
+) -> actix_web::HttpResponse {
+    public_memo_lookup(&req, pool.get_ref(), &guard, &path).await
 }
 
-fn validate_checkout(req: &CheckoutRequest) -> Result<(), crate::validation::ValidationError> {
-    crate::validation::validate_length("product_id", &req.product_id, 100)?;
-    crate::validation::validate_optional_length("variant", &req.variant, 100)?;
-    if let Some(ref addr) = req.refund_address {
-        if !addr.is_empty() {
-            crate::validation::validate_zcash_address("refund_address", addr)?;
+/// `screen`, then the per-IP lookup limit, for the public endpoints that read an invoice.
+fn screen_lookup(
+    req: &actix_web::HttpRequest,
+    guard: &crate::abuse::AbuseGuard,
+) -> Result<Option<std::net::IpAddr>, actix_web::HttpResponse> {
+    let client_ip = screen(req, guard)?;
+    if let Some(ip) = client_ip {
+        if !guard.allow_lookup(ip) {
+            guard.strike(ip);
+            return Err(actix_web::HttpResponse::TooManyRequests().json(serde_json::json!({
+                "error": "Too many lookups, try again later"
+            })));
         }
     }
-    Ok(())
+    Ok(client_ip)
 }
 
-async fn health() -> actix_web::HttpResponse {
-    actix_web::HttpResponse::Ok().json(serde_json::json!({
-        "status": "ok",
-        "service": "cipherpay",
-    }))
+/// Answer a memo code lookup by someone other than the invoice's merchant with as much as
+/// the merchant lets buyers see. A merchant who disabled lookups gets the same 404 as an
+/// unknown code, so the two cannot be told apart.
+async fn public_memo_lookup(
+    req: &actix_web::HttpRequest,
+    pool: &SqlitePool,
+    guard: &crate::abuse::AbuseGuard,
+    memo_code: &str,
+) -> actix_web::HttpResponse {
+    let client_ip = match screen_lookup(req, guard) {
+        Ok(ip) => ip,
+        Err(resp) => return resp,
+    };
+
+    let found = match crate::invoices::get_invoice_by_memo(pool, memo_code).await {
+        Ok(Some(inv)) => match crate::invoices::memo_lookup(pool, &inv.merchant_id).await {
+            Ok(MemoLookup::Disabled) => None,
+            Ok(exposure) => Some((inv, exposure)),
+            Err(e) => return e.error_response(),
+        },
+        Ok(None) => None,
+        Err(e) => return e.error_response(),
+    };
+    let Some((inv, exposure)) = found else {
+        // Misses are how memo codes get guessed.
+        if let Some(ip) = client_ip {
+            guard.strike(ip);
+        }
+        return actix_web::HttpResponse::NotFound().json(serde_json::json!({
+            "error": "No invoice found for this memo code"
+        }));
+    };
+
+    let confirmations = crate::invoices::confirmations(pool, &inv.id).await.unwrap_or_default();
+    match exposure {
+        MemoLookup::AmountsOnly => actix_web::HttpResponse::Ok().json(InvoiceAmounts::new(&inv, confirmations)),
+        _ => actix_web::HttpResponse::Ok().json(PublicInvoice::new(&inv).with_confirmations(confirmations)),
+    }
 }
 
-/// List invoices: requires API key or session auth. Scoped to the authenticated merchant.
-async fn list_invoices(
+#[derive(Debug, serde::Deserialize)]
+struct ClaimRequest {
+    code: String,
+}
+
+/// Redeem a claim code for the invoice it was issued with, so a buyer who only kept the
+/// code can get back to their receipt. Public, with the lookup limits; the code travels in
+/// the body so it stays out of access logs. Every miss is a strike, as for memo codes.
+async fn claim_invoice(
     req: actix_web::HttpRequest,
     pool: web::Data<SqlitePool>,
+    guard: web::Data<crate::abuse::AbuseGuard>,
+    body: web::Json<ClaimRequest>,
 ) -> actix_web::HttpResponse {
-    let merchant = match auth::resolve_session(&req, &pool).await {
-        Some(m) => m,
-        None => {
-            if let Some(auth_header) = req.headers().get("Authorization") {
-                if let Ok(auth_str) = auth_header.to_str() {
-                    let key = auth_str.strip_prefix("Bearer ").unwrap_or(auth_str).trim();
-                    let enc_key = req.app_data::<web::Data<crate::config::Config>>()
-                        .map(|c| c.encryption_key.clone()).unwrap_or_default();
-                    match crate::merchants::authenticate(&pool, key, &enc_key).await {
-                        Ok(Some(m)) => m,
-                        _ => return actix_web::HttpResponse::Unauthorized().json(serde_json::json!({"error": "Invalid API key"})),
-                    }
-                } else {
-                    return actix_web::HttpResponse::Unauthorized().json(serde_json::json!({"error": "Not authenticated"}));
-                }
-            } else {
-                return actix_web::HttpResponse::Unauthorized().json(serde_json::json!({"error": "Not authenticated"}));
-            }
-        }
+    let client_ip = match screen_lookup(&req, &guard) {
+        Ok(ip) => ip,
+        Err(resp) => return resp,
     };
 
-    let rows = sqlx::query(
-        "SELECT id, merchant_id, memo_code, product_name, size,
-         price_eur, price_usd, currency, price_zec, zec_rate_at_creation, payment_address, zcash_uri,
-         status, detected_txid,
-         detected_at, expires_at, confirmed_at, refunded_at,
-         refund_address, created_at, price_zatoshis, received_zatoshis
-         FROM invoices WHERE merchant_id = ? ORDER BY created_at DESC LIMIT 50",
-    )
-    .bind(&merchant.id)
-    .fetch_all(pool.get_ref())
-    .await;
-
-    match rows {
-        Ok(rows) => {
-            use sqlx::Row;
-            let invoices: Vec<_> = rows
-                .into_iter()
-                .map(|r| {
-                    let pz = r.get::<i64, _>("price_zatoshis");
-                    let rz = r.get::<i64, _>("received_zatoshis");
-                    serde_json::json!({
-                        "id": r.get::<String, _>("id"),
-                        "merchant_id": r.get::<String, _>("merchant_id"),
-                        "memo_code": r.get::<String, _>("memo_code"),
-                        "product_name": r.get::<Option<String>, _>("product_name"),
-                        "size": r.get::<Option<String>, _>("size"),
-                        "price_eur": r.get::<f64, _>("price_eur"),
-                        "price_usd": r.get::<Option<f64>, _>("price_usd"),
-                        "currency": r.get::<Option<String>, _>("currency"),
-                        "price_zec": r.get::<f64, _>("price_zec"),
-                        "zec_rate": r.get::<f64, _>("zec_rate_at_creation"),
-                        "payment_address": r.get::<String, _>("payment_address"),
-                        "zcash_uri": r.get::<String, _>("zcash_uri"),
-                        "status": r.get::<String, _>("status"),
-                        "detected_txid": r.get::<Option<String>, _>("detected_txid"),
-                        "detected_at": r.get::<Option<String>, _>("detected_at"),
-                        "expires_at": r.get::<String, _>("expires_at"),
-                        "confirmed_at": r.get::<Option<String>, _>("confirmed_at"),
-                        "refunded_at": r.get::<Option<String>, _>("refunded_at"),
-                        "refund_address": r.get::<Option<String>, _>("refund_address"),
-                        "created_at": r.get::<String, _>("created_at"),
-                        "received_zec": crate::invoices::zatoshis_to_zec(rz),
-                        "price_zatoshis": pz,
-                        "received_zatoshis": rz,
-                        "overpaid": rz > pz + 1000 && pz > 0,
-                    })
-                })
-                .collect();
-            actix_web::HttpResponse::Ok().json(invoices)
-        }
-        Err(e) => {
-            tracing::error!(error = %e, "Failed to list invoices");
-            actix_web::HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Internal error"
-            }))
+    let found = match crate::invoices::normalize_claim_code(&body.code) {
+        Some(code) => match crate::invoices::get_invoice_by_claim_code(pool.get_ref(), &code).await {
+            Ok(found) => found,
+            Err(e) => return e.error_response(),
+        },
+        None => None,
+    };
+    let Some(inv) = found else {
+        if let Some(ip) = client_ip {
+            guard.strike(ip);
         }
-    }
+        return actix_web::HttpResponse::NotFound().json(serde_json::json!({
+            "error": "No invoice found for this claim code"
+        }));
+    };
+
+    let confirmations = crate::invoices::confirmations(pool.get_ref(), &inv.id).await.unwrap_or_default();
+    actix_web::HttpResponse::Ok().json(PublicInvoice::new(&inv).with_confirmations(confirmations))
 }
 
-async fn lookup_by_memo(
+/// Buyer-facing troubleshooting for an invoice: whether a payment was seen, underpaid or
+/// late, and where detection stands against the chain. Public, with the lookup limits.
+async fn diagnose_invoice(
+    req: actix_web::HttpRequest,
     pool: web::Data<SqlitePool>,
+    guard: web::Data<crate::abuse::AbuseGuard>,
     path: web::Path<String>,
 ) -> actix_web::HttpResponse {
-    let memo_code = path.into_inner();
+    let client_ip = match screen_lookup(&req, &guard) {
+        Ok(ip) => ip,
+        Err(resp) => return resp,
+    };
+    let invoice_id = path.into_inner();
 
-    match crate::invoices::get_invoice_by_memo(pool.get_ref(), &memo_code).await {
-        Ok(Some(inv)) => {
-            let received_zec = crate::invoices::zatoshis_to_zec(inv.received_zatoshis);
-            let overpaid = inv.received_zatoshis > inv.price_zatoshis + 1000 && inv.price_zatoshis > 0;
-            actix_web::HttpResponse::Ok().json(serde_json::json!({
-                "id": inv.id,
-                "memo_code": inv.memo_code,
-                "product_name": inv.product_name,
-                "size": inv.size,
-                "price_eur": inv.price_eur,
-                "price_usd": inv.price_usd,
-                "currency": inv.currency,
-                "price_zec": inv.price_zec,
-                "zec_rate_at_creation": inv.zec_rate_at_creation,
-                "payment_address": inv.payment_address,
-                "zcash_uri": inv.zcash_uri,
-                "merchant_name": inv.merchant_name,
-                "status": inv.status,
-                "detected_txid": inv.detected_txid,
-                "detected_at": inv.detected_at,
-                "confirmed_at": inv.confirmed_at,
-                "refunded_at": inv.refunded_at,
-                "expires_at": inv.expires_at,
-                "created_at": inv.created_at,
-                "received_zec": received_zec,
-                "price_zatoshis": inv.price_zatoshis,
-                "received_zatoshis": inv.received_zatoshis,
-                "overpaid": overpaid,
-            }))
-        },
-        Ok(None) => actix_web::HttpResponse::NotFound().json(serde_json::json!({
-            "error": "No invoice found for this memo code"
-        })),
-        Err(e) => {
-            tracing::error!(error = %e, "Failed to lookup invoice by memo");
-            actix_web::HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Internal error"
-            }))
+    let invoice = match crate::invoices::get_invoice(pool.get_ref(), &invoice_id).await {
+        Ok(Some(inv)) => inv,
+        Ok(None) => {
+            if let Some(ip) = client_ip {
+                guard.strike(ip);
+            }
+            return actix_web::HttpResponse::NotFound().json(serde_json::json!({
+                "error": "Invoice not found"
+            }));
         }
-    }
+        Err(e) => return e.error_response(),
+    };
+    let payments = match crate::invoices::get_payments(pool.get_ref(), &invoice.id).await {
+        Ok(p) => p,
+        Err(e) => return e.error_response(),
+    };
+    let chain_height = crate::db::get_scanner_state(pool.get_ref(), &crate::db::height_key(&invoice.network))
+        .await
+        .and_then(|h| h.parse().ok());
+    let now = chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+
+    actix_web::HttpResponse::Ok().json(crate::invoices::diagnose::diagnose(&invoice, &payments, chain_height, &now))
 }
 
 /// SSE stream for invoice status updates -- replaces client-side polling.
-/// The server polls the DB internally and pushes only when state changes.
+/// Events come from the invoice timeline and carry its row ID as the SSE event ID,
+/// so a client reconnecting with `Last-Event-ID` gets every transition it missed.
 async fn invoice_stream(
+    req: actix_web::HttpRequest,
     pool: web::Data<SqlitePool>,
     path: web::Path<String>,
 ) -> impl actix_web::Responder {
     let invoice_id = path.into_inner();
+    let resume_from = req.headers()
+        .get("Last-Event-ID")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<i64>().ok());
     let (tx, rx) = tokio::sync::mpsc::channel::<sse::Event>(10);
 
     tokio::spawn(async move {
-        let mut tick = interval(Duration::from_secs(2));
-        let mut last_status = String::new();
-
-        // Send initial state immediately
-        if let Ok(Some(status)) = crate::invoices::get_invoice_status(&pool, &invoice_id).await {
-            last_status.clone_from(&status.status);
-            let data = serde_json::json!({
-                "status": status.status,
-                "txid": status.detected_txid,
-                "received_zatoshis": status.received_zatoshis,
-                "price_zatoshis": status.price_zatoshis,
-            });
-            let _ = tx
-                .send(sse::Data::new(data.to_string()).event("status").into())
-                .await;
-        }
+        let is_final = |s: &str| matches!(s, "confirmed" | "expired" | "paid_late" | "refunded");
+
+        let status = match crate::invoices::get_invoice_status(&pool, &invoice_id).await {
+            Ok(Some(s)) => s,
+            _ => return,
+        };
 
-        let mut last_received: i64 = 0;
+        let mut cursor = match resume_from {
+            Some(id) => id,
+            None => {
+                // Fresh connection: current state first, then follow new events.
+                let latest = crate::invoices::events::latest_id(&pool, &invoice_id).await.unwrap_or(0);
+                let data = serde_json::to_string(&StatusEvent::from(&status)).unwrap_or_default();
+                let _ = tx
+                    .send(sse::Data::new(data).event("status").id(latest.to_string()).into())
+                    .await;
+                if is_final(&status.status) {
+                    return;
+                }
+                latest
+            }
+        };
+        let mut last_received = if resume_from.is_some() { -1 } else { status.received_zatoshis };
+
+        let mut tick = interval(Duration::from_secs(2));
         loop {
             tick.tick().await;
 
-            match crate::invoices::get_invoice_status(&pool, &invoice_id).await {
-                Ok(Some(status)) => {
-                    let amounts_changed = status.received_zatoshis != last_received;
-                    if status.status != last_status || amounts_changed {
-                        last_status.clone_from(&status.status);
-                        last_received = status.received_zatoshis;
-                        let data = serde_json::json!({
-                            "status": status.status,
-                            "txid": status.detected_txid,
-                            "received_zatoshis": status.received_zatoshis,
-                            "price_zatoshis": status.price_zatoshis,
-                        });
-                        if tx
-                            .send(sse::Data::new(data.to_string()).event("status").into())
-                            .await
-                            .is_err()
-                        {
-                            break;
-                        }
-                        if status.status == "confirmed" || status.status == "expired" {
-                            break;
-                        }
+            let new_events = match crate::invoices::events::since(&pool, &invoice_id, cursor).await {
+                Ok(e) => e,
+                Err(_) => break,
+            };
+            let status = match crate::invoices::get_invoice_status(&pool, &invoice_id).await {
+                Ok(Some(s)) => s,
+                _ => break,
+            };
+
+            let mut sent_status = false;
+            let mut sent_final = false;
+            for event in &new_events {
+                cursor = event.id;
+                let (name, data) = if event.event_type == "requoted" {
+                    match crate::invoices::get_invoice(&pool, &invoice_id).await {
+                        Ok(Some(inv)) => ("requoted", serde_json::to_string(&RequoteEvent::from(&inv))),
+                        _ => continue,
                     }
+                } else if let Some(state) = crate::invoices::events::status_for(&event.event_type) {
+                    sent_status = true;
+                    sent_final = is_final(state);
+                    ("status", serde_json::to_string(&StatusEvent {
+                        status: state.to_string(),
+                        txid: event.txid.clone().or_else(|| status.detected_txid.clone()),
+                        ..StatusEvent::from(&status)
+                    }))
+                } else {
+                    continue;
+                };
+                let sse_event = sse::Data::new(data.unwrap_or_default()).event(name).id(event.id.to_string());
+                if tx.send(sse_event.into()).await.is_err() {
+                    return;
                 }
-                _ => break,
+            }
+
+            if sent_final {
+                break;
+            }
+
+            // Additional partial payments change the received amount without a new status,
+            // and invoices from before the timeline existed have no events at all.
+            let settled_without_event = is_final(&status.status) && new_events.is_empty();
+            if !sent_status
+                && (settled_without_event || (last_received >= 0 && status.received_zatoshis != last_received))
+            {
+                let data = serde_json::to_string(&StatusEvent::from(&status)).unwrap_or_default();
+                if tx.send(sse::Data::new(data).event("status").into()).await.is_err() {
+                    return;
+                }
+            }
+            last_received = status.received_zatoshis;
+
+            if settled_without_event {
+                break;
             }
         }
     });
@@ -407,10 +503,90 @@ async fn invoice_stream(
     sse::Sse::from_infallible_receiver(rx).with_retry_duration(Duration::from_secs(5))
 }
 
-/// Generate a QR code PNG for a zcash: payment URI (ZIP-321 compliant)
+/// Most invoice events sent per poll; a backlog after a reconnect drains over a few ticks.
+const MERCHANT_STREAM_BATCH: i64 = 100;
+
+/// Everything happening on the merchant's account for a dashboard: an `invoice` event for
+/// each timeline event on any of their invoices (resumable with `Last-Event-ID`), and a
+/// `billing` event with the fee summary when it changes. Ends when the session does.
+async fn merchant_stream(
+    req: actix_web::HttpRequest,
+    SessionMerchant(merchant): SessionMerchant,
+    pool: web::Data<SqlitePool>,
+    config: web::Data<crate::config::Config>,
+    billing: web::Data<crate::services::BillingService>,
+) -> impl actix_web::Responder {
+    let resume_from = req.headers()
+        .get("Last-Event-ID")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<i64>().ok());
+    let session_id = auth::extract_session_id(&req).unwrap_or_default();
+    let (tx, rx) = tokio::sync::mpsc::channel::<sse::Event>(10);
+
+    tokio::spawn(async move {
+        let mut cursor = match resume_from {
+            Some(id) => id,
+            None => crate::invoices::events::latest_id_overall(&pool).await.unwrap_or(0),
+        };
+        let mut last_billing: Option<serde_json::Value> = None;
+
+        let mut tick = interval(Duration::from_secs(2));
+        for round in 0u64.. {
+            tick.tick().await;
+            if tx.is_closed() {
+                return;
+            }
+            if round % 30 == 29 {
+                match crate::merchants::get_by_session(&pool, &session_id, config.session_max_age_hours, &config.encryption_key).await {
+                    Ok(Some(m)) if m.id == merchant.id => {}
+                    _ => return,
+                }
+            }
+
+            let events = match crate::invoices::events::since_for_merchant(
+                &pool, &merchant.id, cursor, MERCHANT_STREAM_BATCH,
+            ).await {
+                Ok(e) => e,
+                Err(_) => return,
+            };
+            for event in &events {
+                cursor = event.id;
+                let data = serde_json::to_string(event).unwrap_or_default();
+                if tx.send(sse::Data::new(data).event("invoice").id(event.id.to_string()).into()).await.is_err() {
+                    return;
+                }
+            }
+
+            let summary = match billing.summary(&merchant.id).await {
+                Ok(Some(summary)) => serde_json::to_value(&summary).ok(),
+                _ => None,
+            };
+            if summary.is_some() && summary != last_billing {
+                let data = summary.as_ref().map(|s| s.to_string()).unwrap_or_default();
+                if tx.send(sse::Data::new(data).event("billing").into()).await.is_err() {
+                    return;
+                }
+                last_billing = summary;
+            }
+        }
+    });
+
+    sse::Sse::from_infallible_receiver(rx)
+        .with_retry_duration(Duration::from_secs(5))
+        .with_keep_alive(Duration::from_secs(15))
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct QrQuery {
+    format: Option<crate::invoices::UriFormat>,
+}
+
+/// Generate a QR code PNG for a zcash: payment URI (ZIP-321 compliant).
+/// `?format=simple` encodes the single-output URI, `?format=tex` the TEX address.
 async fn qr_code(
     pool: web::Data<SqlitePool>,
     path: web::Path<String>,
+    query: web::Query<QrQuery>,
 ) -> actix_web::HttpResponse {
     let invoice_id = path.into_inner();
 
@@ -419,12 +595,9 @@ async fn qr_code(
         _ => return actix_web::HttpResponse::NotFound().finish(),
     };
 
-    let uri = if invoice.zcash_uri.is_empty() {
-        let memo_b64 = base64::engine::general_purpose::URL_SAFE_NO_PAD
-            .encode(invoice.memo_code.as_bytes());
-        format!("zcash:{}?amount={:.8}&memo={}", invoice.payment_address, invoice.price_zec, memo_b64)
-    } else {
-        invoice.zcash_uri.clone()
+    let format = query.format.unwrap_or(crate::invoices::UriFormat::Multi);
+    let Some(uri) = invoice.payment_uri(format) else {
+        return actix_web::HttpResponse::NotFound().finish();
     };
 
     match generate_qr_png(&uri) {
@@ -451,33 +624,28 @@ fn generate_qr_png(data: &str) -> anyhow::Result<Vec<u8>> {
     Ok(buf.into_inner())
 }
 
-/// Cancel a pending invoice (only pending invoices can be cancelled)
+/// Cancel a pending invoice (API key or dashboard session; only pending invoices can be cancelled)
 async fn cancel_invoice(
-    req: actix_web::HttpRequest,
+    AnyMerchant(merchant): AnyMerchant,
     pool: web::Data<SqlitePool>,
     path: web::Path<String>,
 ) -> actix_web::HttpResponse {
-    let merchant = match auth::resolve_session(&req, &pool).await {
-        Some(m) => m,
-        None => {
-            return actix_web::HttpResponse::Unauthorized().json(serde_json::json!({
-                "error": "Not authenticated"
-            }));
-        }
-    };
-
     let invoice_id = path.into_inner();
 
     match crate::invoices::get_invoice(pool.get_ref(), &invoice_id).await {
         Ok(Some(inv)) if inv.merchant_id == merchant.id && inv.status == "pending" => {
-            if let Err(e) = crate::invoices::mark_expired(pool.get_ref(), &invoice_id).await {
-                return actix_web::HttpResponse::InternalServerError().json(serde_json::json!({
-                    "error": format!("{}", e)
-                }));
+            match crate::invoices::mark_expired(pool.get_ref(), &invoice_id).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    return actix_web::HttpResponse::Conflict().json(serde_json::json!({
+                        "error": "Invoice status changed, refresh and try again"
+                    }));
+                }
+                Err(e) => return e.error_response(),
             }
             actix_web::HttpResponse::Ok().json(serde_json::json!({ "status": "cancelled" }))
         }
-        Ok(Some(_)) => {
+        Ok(Some(inv)) if inv.merchant_id == merchant.id => {
             actix_web::HttpResponse::BadRequest().json(serde_json::json!({
                 "error": "Only pending invoices can be cancelled"
             }))
@@ -490,29 +658,24 @@ async fn cancel_invoice(
     }
 }
 
-/// Mark an invoice as refunded (dashboard auth)
+/// Mark an invoice as refunded (API key or dashboard session)
 async fn refund_invoice(
-    req: actix_web::HttpRequest,
+    AnyMerchant(merchant): AnyMerchant,
     pool: web::Data<SqlitePool>,
     path: web::Path<String>,
 ) -> actix_web::HttpResponse {
-    let merchant = match auth::resolve_session(&req, &pool).await {
-        Some(m) => m,
-        None => {
-            return actix_web::HttpResponse::Unauthorized().json(serde_json::json!({
-                "error": "Not authenticated"
-            }));
-        }
-    };
-
     let invoice_id = path.into_inner();
 
     match crate::invoices::get_invoice(pool.get_ref(), &invoice_id).await {
-        Ok(Some(inv)) if inv.merchant_id == merchant.id && inv.status == "confirmed" => {
-            if let Err(e) = crate::invoices::mark_refunded(pool.get_ref(), &invoice_id).await {
-                return actix_web::HttpResponse::InternalServerError().json(serde_json::json!({
-                    "error": format!("{}", e)
-                }));
+        Ok(Some(inv)) if inv.merchant_id == merchant.id && matches!(inv.status.as_str(), "confirmed" | "paid_late") => {
+            match crate::invoices::mark_refunded(pool.get_ref(), &invoice_id).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    return actix_web::HttpResponse::Conflict().json(serde_json::json!({
+                        "error": "Invoice status changed, refresh and try again"
+                    }));
+                }
+                Err(e) => return e.error_response(),
             }
             let response = serde_json::json!({
                 "status": "refunded",
@@ -520,9 +683,9 @@ async fn refund_invoice(
             });
             actix_web::HttpResponse::Ok().json(response)
         }
-        Ok(Some(_)) => {
+        Ok(Some(inv)) if inv.merchant_id == merchant.id => {
             actix_web::HttpResponse::BadRequest().json(serde_json::json!({
-                "error": "Only confirmed invoices can be refunded"
+                "error": "Only confirmed or paid_late invoices can be refunded"
             }))
         }
         _ => {
@@ -533,6 +696,81 @@ async fn refund_invoice(
     }
 }
 
+#[derive(serde::Deserialize)]
+struct RefundTxidRequest {
+    txid: String,
+    /// Refunded amount in ZEC; defaults to the full received amount.
+    amount: Option<f64>,
+}
+
+/// Register the txid of a refund the merchant sent (API key or dashboard session).
+/// The invoice moves to refunded once the scanner has verified the transaction.
+async fn register_refund_txid(
+    AnyMerchant(merchant): AnyMerchant,
+    pool: web::Data<SqlitePool>,
+    path: web::Path<String>,
+    body: web::Json<RefundTxidRequest>,
+) -> actix_web::HttpResponse {
+    let txid = body.txid.trim().to_lowercase();
+    if txid.len() != 64 || !txid.chars().all(|c| c.is_ascii_hexdigit()) {
+        return actix_web::HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "txid must be a 64-character hex transaction id"
+        }));
+    }
+
+    let invoice_id = path.into_inner();
+
+    let inv = match crate::invoices::get_invoice(pool.get_ref(), &invoice_id).await {
+        Ok(Some(inv)) if inv.merchant_id == merchant.id => inv,
+        _ => {
+            return actix_web::HttpResponse::NotFound().json(serde_json::json!({
+                "error": "Invoice not found"
+            }));
+        }
+    };
+
+    if !matches!(inv.status.as_str(), "confirmed" | "expired" | "paid_late") || inv.received_zatoshis <= 0 {
+        return actix_web::HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "Only confirmed, expired or paid_late invoices with received funds can be refunded"
+        }));
+    }
+
+    let amount_zatoshis = match body.amount {
+        None => inv.received_zatoshis,
+        Some(zec) => {
+            let z = crate::invoices::amounts::zec_to_zatoshis(zec);
+            if z <= 0 || z > inv.received_zatoshis {
+                return actix_web::HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": "amount must be positive and no more than the received amount"
+                }));
+            }
+            z
+        }
+    };
+
+    match crate::invoices::is_payment_txid(pool.get_ref(), &invoice_id, &txid).await {
+        Ok(false) => {}
+        Ok(true) => {
+            return actix_web::HttpResponse::BadRequest().json(serde_json::json!({
+                "error": "txid is the buyer's payment to this invoice, not a refund"
+            }));
+        }
+        Err(e) => return e.error_response(),
+    }
+
+    match crate::invoices::register_refund(pool.get_ref(), &invoice_id, &txid, amount_zatoshis).await {
+        Ok(true) => actix_web::HttpResponse::Ok().json(serde_json::json!({
+            "status": "refund_pending",
+            "refund_txid": txid,
+            "refund_zec": crate::invoices::zatoshis_to_zec(amount_zatoshis),
+        })),
+        Ok(false) => actix_web::HttpResponse::Conflict().json(serde_json::json!({
+            "error": "Invoice status changed, refresh and try again"
+        })),
+        Err(e) => e.error_response(),
+    }
+}
+
 /// Buyer can save a refund address on their invoice (write-once).
 async fn update_refund_address(
     pool: web::Data<SqlitePool>,
@@ -550,7 +788,16 @@ async fn update_refund_address(
         }
     };
 
-    if let Err(e) = crate::validation::validate_zcash_address("refund_address", address) {
+    let network = match crate::invoices::get_invoice(pool.get_ref(), &invoice_id).await {
+        Ok(Some(invoice)) => invoice.network,
+        Ok(None) => {
+            return actix_web::HttpResponse::NotFound().json(serde_json::json!({
+                "error": "Invoice not found"
+            }));
+        }
+        Err(e) => return e.error_response(),
+    };
+    if let Err(e) = crate::validation::validate_zcash_address_network("refund_address", address, network == "testnet") {
         return actix_web::HttpResponse::BadRequest().json(e.to_json());
     }
 
@@ -562,40 +809,16 @@ async fn update_refund_address(
         Ok(false) => actix_web::HttpResponse::Conflict().json(serde_json::json!({
             "error": "Refund address is already set or invoice status does not allow changes"
         })),
-        Err(e) => {
-            tracing::error!(error = %e, "Failed to update refund address");
-            actix_web::HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Internal error"
-            }))
-        }
+        Err(e) => e.error_response(),
     }
 }
 
 async fn billing_summary(
-    req: actix_web::HttpRequest,
-    pool: web::Data<SqlitePool>,
-    config: web::Data<crate::config::Config>,
+    SessionMerchant(merchant): SessionMerchant,
+    billing: web::Data<crate::services::BillingService>,
 ) -> actix_web::HttpResponse {
-    let merchant = match auth::resolve_session(&req, &pool).await {
-        Some(m) => m,
-        None => {
-            return actix_web::HttpResponse::Unauthorized().json(serde_json::json!({
-                "error": "Not authenticated"
-            }));
-        }
-    };
-
-    if !config.fee_enabled() {
-        return actix_web::HttpResponse::Ok().json(serde_json::json!({
-            "fee_enabled": false,
-            "fee_rate": 0.0,
-            "billing_status": "active",
-            "trust_tier": "standard",
-        }));
-    }
-
-    match crate::billing::get_billing_summary(pool.get_ref(), &merchant.id, &config).await {
-        Ok(summary) => actix_web::HttpResponse::Ok().json(serde_json::json!({
+    match billing.summary(&merchant.id).await {
+        Ok(Some(summary)) => actix_web::HttpResponse::Ok().json(serde_json::json!({
             "fee_enabled": true,
             "fee_rate": summary.fee_rate,
             "trust_tier": summary.trust_tier,
@@ -604,158 +827,100 @@ async fn billing_summary(
             "total_fees_zec": summary.total_fees_zec,
             "auto_collected_zec": summary.auto_collected_zec,
             "outstanding_zec": summary.outstanding_zec,
+            "fee_currency": summary.fee_currency,
+            "outstanding_fiat": summary.outstanding_fiat,
+            "promo": summary.promo,
         })),
-        Err(e) => {
-            tracing::error!(error = %e, "Failed to get billing summary");
-            actix_web::HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Internal error"
-            }))
-        }
+        Ok(None) => actix_web::HttpResponse::Ok().json(serde_json::json!({
+            "fee_enabled": false,
+            "fee_rate": 0.0,
+            "billing_status": "active",
+            "trust_tier": "standard",
+        })),
+        Err(e) => e.error_response(),
     }
 }
 
 async fn billing_history(
-    req: actix_web::HttpRequest,
-    pool: web::Data<SqlitePool>,
+    SessionMerchant(merchant): SessionMerchant,
+    billing: web::Data<crate::services::BillingService>,
 ) -> actix_web::HttpResponse {
-    let merchant = match auth::resolve_session(&req, &pool).await {
-        Some(m) => m,
-        None => {
-            return actix_web::HttpResponse::Unauthorized().json(serde_json::json!({
-                "error": "Not authenticated"
-            }));
-        }
-    };
-
-    match crate::billing::get_billing_history(pool.get_ref(), &merchant.id).await {
+    match billing.history(&merchant.id).await {
         Ok(cycles) => actix_web::HttpResponse::Ok().json(cycles),
-        Err(e) => {
-            tracing::error!(error = %e, "Failed to get billing history");
-            actix_web::HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Internal error"
-            }))
-        }
+        Err(e) => e.error_response(),
     }
 }
 
 async fn billing_settle(
-    req: actix_web::HttpRequest,
-    pool: web::Data<SqlitePool>,
-    config: web::Data<crate::config::Config>,
-    price_service: web::Data<crate::invoices::pricing::PriceService>,
+    SessionMerchant(merchant): SessionMerchant,
+    billing: web::Data<crate::services::BillingService>,
 ) -> actix_web::HttpResponse {
-    let merchant = match auth::resolve_session(&req, &pool).await {
-        Some(m) => m,
-        None => {
-            return actix_web::HttpResponse::Unauthorized().json(serde_json::json!({
-                "error": "Not authenticated"
-            }));
-        }
-    };
-
-    let fee_address = match &config.fee_address {
-        Some(addr) => addr.clone(),
-        None => {
-            return actix_web::HttpResponse::BadRequest().json(serde_json::json!({
-                "error": "Billing not enabled"
-            }));
-        }
-    };
-
-    let summary = match crate::billing::get_billing_summary(pool.get_ref(), &merchant.id, &config).await {
-        Ok(s) => s,
-        Err(e) => {
-            tracing::error!(error = %e, "Failed to get billing for settle");
-            return actix_web::HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Internal error"
-            }));
-        }
-    };
-
-    if summary.outstanding_zec < 0.00001 {
-        return actix_web::HttpResponse::Ok().json(serde_json::json!({
+    match billing.settle(&merchant.id).await {
+        Ok(crate::services::billing::Settlement::NothingOwed) => actix_web::HttpResponse::Ok().json(serde_json::json!({
             "message": "No outstanding balance",
             "outstanding_zec": 0.0,
-        }));
-    }
-
-    let (zec_eur, zec_usd) = match price_service.get_rates().await {
-        Ok(rates) => (rates.zec_eur, rates.zec_usd),
-        Err(_) => (0.0, 0.0),
-    };
-
-    match crate::billing::create_settlement_invoice(
-        pool.get_ref(), &merchant.id, summary.outstanding_zec, &fee_address, zec_eur, zec_usd,
-    ).await {
-        Ok(invoice_id) => {
-            if let Some(cycle) = &summary.current_cycle {
-                let _ = sqlx::query(
-                    "UPDATE billing_cycles SET settlement_invoice_id = ?, status = 'invoiced',
-                     grace_until = strftime('%Y-%m-%dT%H:%M:%SZ', 'now', '+7 days')
-                     WHERE id = ? AND status = 'open'"
-                )
-                .bind(&invoice_id)
-                .bind(&cycle.id)
-                .execute(pool.get_ref())
-                .await;
-            }
-
+        })),
+        Ok(crate::services::billing::Settlement::Invoiced { invoice_id, outstanding_zec, pay_from }) => {
             actix_web::HttpResponse::Created().json(serde_json::json!({
                 "invoice_id": invoice_id,
-                "outstanding_zec": summary.outstanding_zec,
+                "outstanding_zec": outstanding_zec,
+                "pay_from": pay_from,
                 "message": "Settlement invoice created. Pay to restore full access.",
             }))
         }
-        Err(e) => {
-            tracing::error!(error = %e, "Failed to create settlement invoice");
-            actix_web::HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Failed to create settlement invoice"
-            }))
-        }
+        Err(e) => e.error_response(),
     }
 }
 
+/// Delete the merchant's account. Keys, secrets, contact details and settings are erased,
+/// but a tombstone row keeps the payment address and diversifier index, and invoices stay
+/// as payment records: registering the same UFVK again is recognised by its payment
+/// address and carries on from the next index, so old invoice addresses are never reused.
 async fn delete_account(
     req: actix_web::HttpRequest,
+    SessionMerchant(merchant): SessionMerchant,
     pool: web::Data<SqlitePool>,
-    config: web::Data<crate::config::Config>,
+    merchants: web::Data<crate::services::MerchantService>,
 ) -> actix_web::HttpResponse {
-    let merchant = match auth::resolve_session(&req, &pool).await {
-        Some(m) => m,
-        None => {
-            return actix_web::HttpResponse::Unauthorized().json(serde_json::json!({
-                "error": "Not authenticated"
-            }));
-        }
-    };
-
-    if config.fee_enabled() {
-        match crate::merchants::has_outstanding_balance(pool.get_ref(), &merchant.id).await {
-            Ok(true) => {
-                return actix_web::HttpResponse::Forbidden().json(serde_json::json!({
-                    "error": "Cannot delete account with outstanding billing balance. Please settle your fees first."
-                }));
-            }
-            Err(e) => {
-                tracing::error!(error = %e, "Failed to check billing balance");
-                return actix_web::HttpResponse::InternalServerError().json(serde_json::json!({
-                    "error": "Internal error"
-                }));
-            }
-            _ => {}
-        }
+    if !auth::is_elevated(&req, &pool).await {
+        return auth::elevation_required();
     }
 
-    match crate::merchants::delete_merchant(pool.get_ref(), &merchant.id).await {
+    match merchants.delete(&merchant).await {
         Ok(()) => actix_web::HttpResponse::Ok().json(serde_json::json!({
             "status": "deleted",
-            "message": "Your account and all associated data have been permanently deleted."
+            "message": "Your account has been deleted. Its keys, secrets and contact details are erased; \
+                its payment address and invoices are kept as payment records."
         })),
-        Err(e) => {
-            tracing::error!(error = %e, "Failed to delete merchant account");
-            actix_web::HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Failed to delete account"
+        Err(e) => e.error_response(),
+    }
+}
+
+/// Delete a testnet merchant's invoices and products, keeping the account and its keys.
+async fn reset_sandbox(
+    req: actix_web::HttpRequest,
+    SessionMerchant(merchant): SessionMerchant,
+    pool: web::Data<SqlitePool>,
+    config: web::Data<crate::config::Config>,
+    http: web::Data<reqwest::Client>,
+    merchants: web::Data<crate::services::MerchantService>,
+) -> actix_web::HttpResponse {
+    if !auth::is_elevated(&req, &pool).await {
+        return auth::elevation_required();
+    }
+
+    match merchants.reset_sandbox(&merchant).await {
+        Ok(reset) => {
+            for key in &reset.image_keys {
+                if let Err(e) = crate::media::delete(&config, &http, key).await {
+                    tracing::warn!(key = %key, error = %e, "Failed to delete product image");
+                }
+            }
+            actix_web::HttpResponse::Ok().json(serde_json::json!({
+                "status": "reset",
+                "deleted": reset,
             }))
         }
+        Err(e) => e.error_response(),
     }
 }
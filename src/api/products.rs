@@ -1,29 +1,30 @@
-use actix_web::{web, HttpRequest, HttpResponse};
+use actix_multipart::Multipart;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use futures::StreamExt;
 use sqlx::SqlitePool;
 
-use crate::products::{self, CreateProductRequest, UpdateProductRequest};
+use super::extract::SessionMerchant;
+use crate::config::Config;
+use crate::db::ReadPool;
+use crate::products::{self, import, CreateProductRequest, Product, UpdateProductRequest};
 use crate::validation;
 
 pub async fn create(
-    req: HttpRequest,
+    SessionMerchant(merchant): SessionMerchant,
     pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
+    http: web::Data<reqwest::Client>,
     body: web::Json<CreateProductRequest>,
 ) -> HttpResponse {
-    let merchant = match super::auth::resolve_session(&req, &pool).await {
-        Some(m) => m,
-        None => {
-            return HttpResponse::Unauthorized().json(serde_json::json!({
-                "error": "Not authenticated"
-            }));
-        }
-    };
-
     if let Err(e) = validate_product_create(&body) {
         return HttpResponse::BadRequest().json(e.to_json());
     }
 
     match products::create_product(pool.get_ref(), &merchant.id, &body).await {
-        Ok(product) => HttpResponse::Created().json(product),
+        Ok(product) => {
+            notify(&pool, &config, &http, "product.created", std::slice::from_ref(&product)).await;
+            HttpResponse::Created().json(product)
+        }
         Err(e) => {
             let msg = e.to_string();
             if msg.contains("UNIQUE constraint") {
@@ -40,20 +41,21 @@ pub async fn create(
     }
 }
 
+#[derive(Debug, serde::Deserialize)]
+pub struct ListQuery {
+    /// `archived` to include archived products.
+    pub include: Option<String>,
+}
+
 pub async fn list(
-    req: HttpRequest,
+    SessionMerchant(merchant): SessionMerchant,
     pool: web::Data<SqlitePool>,
+    query: web::Query<ListQuery>,
 ) -> HttpResponse {
-    let merchant = match super::auth::resolve_session(&req, &pool).await {
-        Some(m) => m,
-        None => {
-            return HttpResponse::Unauthorized().json(serde_json::json!({
-                "error": "Not authenticated"
-            }));
-        }
-    };
+    let include_archived = query.include.as_deref()
+        .is_some_and(|v| v.split(',').any(|i| i.trim() == "archived"));
 
-    match products::list_products(pool.get_ref(), &merchant.id).await {
+    match products::list_products(pool.get_ref(), &merchant.id, include_archived).await {
         Ok(products) => HttpResponse::Ok().json(products),
         Err(e) => {
             tracing::error!(error = %e, "Failed to list products");
@@ -65,20 +67,13 @@ pub async fn list(
 }
 
 pub async fn update(
-    req: HttpRequest,
+    SessionMerchant(merchant): SessionMerchant,
     pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
+    http: web::Data<reqwest::Client>,
     path: web::Path<String>,
     body: web::Json<UpdateProductRequest>,
 ) -> HttpResponse {
-    let merchant = match super::auth::resolve_session(&req, &pool).await {
-        Some(m) => m,
-        None => {
-            return HttpResponse::Unauthorized().json(serde_json::json!({
-                "error": "Not authenticated"
-            }));
-        }
-    };
-
     let product_id = path.into_inner();
 
     if let Err(e) = validate_product_update(&body) {
@@ -86,7 +81,11 @@ pub async fn update(
     }
 
     match products::update_product(pool.get_ref(), &product_id, &merchant.id, &body).await {
-        Ok(Some(product)) => HttpResponse::Ok().json(product),
+        Ok(Some(product)) => {
+            let event = if body.active == Some(false) { "product.deactivated" } else { "product.updated" };
+            notify(&pool, &config, &http, event, std::slice::from_ref(&product)).await;
+            HttpResponse::Ok().json(product)
+        }
         Ok(None) => HttpResponse::NotFound().json(serde_json::json!({
             "error": "Product not found"
         })),
@@ -100,28 +99,369 @@ pub async fn update(
 }
 
 pub async fn deactivate(
+    SessionMerchant(merchant): SessionMerchant,
+    pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
+    http: web::Data<reqwest::Client>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let product_id = path.into_inner();
+
+    match products::deactivate_product(pool.get_ref(), &product_id, &merchant.id).await {
+        Ok(true) => {
+            if let Ok(Some(product)) = products::get_product(pool.get_ref(), &product_id).await {
+                notify(&pool, &config, &http, "product.deactivated", &[product]).await;
+            }
+            HttpResponse::Ok().json(serde_json::json!({ "status": "deactivated" }))
+        }
+        Ok(false) => HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Product not found"
+        })),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to deactivate product");
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            }))
+        }
+    }
+}
+
+pub async fn archive(
+    SessionMerchant(merchant): SessionMerchant,
+    pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
+    http: web::Data<reqwest::Client>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    match products::archive_products(pool.get_ref(), &[path.into_inner()], &merchant.id).await {
+        Ok(archived) if !archived.is_empty() => {
+            notify(&pool, &config, &http, "product.deactivated", &archived).await;
+            HttpResponse::Ok().json(serde_json::json!({ "status": "archived" }))
+        }
+        Ok(_) => HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Product not found or already archived"
+        })),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to archive product");
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            }))
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct BulkArchiveRequest {
+    pub ids: Vec<String>,
+}
+
+pub async fn archive_bulk(
+    SessionMerchant(merchant): SessionMerchant,
+    pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
+    http: web::Data<reqwest::Client>,
+    body: web::Json<BulkArchiveRequest>,
+) -> HttpResponse {
+    if body.ids.is_empty() || body.ids.len() > 100 {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "ids must contain between 1 and 100 product IDs"
+        }));
+    }
+
+    match products::archive_products(pool.get_ref(), &body.ids, &merchant.id).await {
+        Ok(archived) => {
+            notify(&pool, &config, &http, "product.deactivated", &archived).await;
+            HttpResponse::Ok().json(serde_json::json!({ "archived": archived.len() }))
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to archive products");
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            }))
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ImportQuery {
+    /// Check every row and report what would be imported, without creating anything.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Create products in bulk from a CSV (`Content-Type: text/csv`) or JSON array body.
+/// Every row is checked first; if any fails, none are created and each failure is reported.
+pub async fn import(
     req: HttpRequest,
+    SessionMerchant(merchant): SessionMerchant,
+    pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
+    http: web::Data<reqwest::Client>,
+    query: web::Query<ImportQuery>,
+    body: web::Bytes,
+) -> HttpResponse {
+    let is_csv = req.content_type().eq_ignore_ascii_case("text/csv");
+    let (rows, errors) = if is_csv {
+        import::parse_csv(&body)
+    } else {
+        match import::parse_json(&body) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                return HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": format!("Expected a JSON array of products or a text/csv body: {}", e)
+                }));
+            }
+        }
+    };
+    if rows.len() + errors.len() > import::MAX_IMPORT_ROWS {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": format!("At most {} products can be imported at once", import::MAX_IMPORT_ROWS)
+        }));
+    }
+
+    match import::import_products(pool.get_ref(), &merchant.id, &rows, errors, query.dry_run, validate_product_create).await {
+        Ok(report) if report.errors.is_empty() => {
+            let mut created = Vec::with_capacity(report.created.len());
+            for id in &report.created {
+                if let Ok(Some(product)) = products::get_product(pool.get_ref(), id).await {
+                    created.push(product);
+                }
+            }
+            notify(&pool, &config, &http, "product.created", &created).await;
+            HttpResponse::Ok().json(report)
+        }
+        Ok(report) => HttpResponse::UnprocessableEntity().json(report),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to import products");
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            }))
+        }
+    }
+}
+
+/// Queue a catalog webhook for each product, in order, and send them in the background.
+async fn notify(pool: &SqlitePool, config: &Config, http: &reqwest::Client, event: &str, products: &[Product]) {
+    let mut queued = None;
+    for product in products {
+        match crate::webhooks::enqueue_product(pool, event, product).await {
+            Ok(Some(merchant_id)) => queued = Some(merchant_id),
+            Ok(None) => {}
+            Err(e) => tracing::error!(product_id = %product.id, event, error = %e, "Failed to queue product webhook"),
+        }
+    }
+    if let Some(merchant_id) = queued {
+        crate::webhooks::spawn_delivery(pool, http, merchant_id, &config.encryption_key);
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct ExportQuery {
+    /// `csv` (default) or `json`.
+    pub format: Option<String>,
+    /// `archived` to include archived products.
+    pub include: Option<String>,
+}
+
+/// The merchant's products in the import format, as a file download.
+pub async fn export(
+    SessionMerchant(merchant): SessionMerchant,
     pool: web::Data<SqlitePool>,
+    query: web::Query<ExportQuery>,
+) -> HttpResponse {
+    let include_archived = query.include.as_deref()
+        .is_some_and(|v| v.split(',').any(|i| i.trim() == "archived"));
+    let products = match products::list_products(pool.get_ref(), &merchant.id, include_archived).await {
+        Ok(products) => products,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to list products for export");
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            }));
+        }
+    };
+
+    let (body, content_type, extension) = match query.format.as_deref().unwrap_or("csv") {
+        "csv" => match import::to_csv(&products) {
+            Ok(csv) => (csv, "text/csv", "csv"),
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to write product CSV");
+                return HttpResponse::InternalServerError().json(serde_json::json!({
+                    "error": "Internal error"
+                }));
+            }
+        },
+        "json" => {
+            let rows: Vec<CreateProductRequest> = products.iter().map(CreateProductRequest::from).collect();
+            (serde_json::to_string_pretty(&rows).unwrap_or_default(), "application/json", "json")
+        }
+        _ => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": "format must be csv or json"
+            }));
+        }
+    };
+
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"products.{}\"", extension),
+        ))
+        .body(body)
+}
+
+/// Permanently delete archived products that no invoice references.
+pub async fn cleanup(
+    SessionMerchant(merchant): SessionMerchant,
+    pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
+    http: web::Data<reqwest::Client>,
+) -> HttpResponse {
+    match products::purge_archived_products(pool.get_ref(), &merchant.id).await {
+        Ok((count, image_keys)) => {
+            for key in &image_keys {
+                if let Err(e) = crate::media::delete(&config, &http, key).await {
+                    tracing::warn!(key = %key, error = %e, "Failed to delete product image");
+                }
+            }
+            HttpResponse::Ok().json(serde_json::json!({ "deleted": count }))
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to clean up archived products");
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            }))
+        }
+    }
+}
+
+/// Upload an image for a product (multipart/form-data, field `file`).
+/// The type is detected from the file contents; only PNG, JPEG, GIF and WebP are accepted.
+pub async fn upload_image(
+    SessionMerchant(merchant): SessionMerchant,
+    pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
+    http: web::Data<reqwest::Client>,
     path: web::Path<String>,
+    mut payload: Multipart,
 ) -> HttpResponse {
-    let merchant = match super::auth::resolve_session(&req, &pool).await {
-        Some(m) => m,
+    let product_id = path.into_inner();
+    match products::get_product(pool.get_ref(), &product_id).await {
+        Ok(Some(p)) if p.merchant_id == merchant.id => {}
+        Ok(_) => {
+            return HttpResponse::NotFound().json(serde_json::json!({
+                "error": "Product not found"
+            }));
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to look up product");
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            }));
+        }
+    }
+
+    let mut bytes: Option<Vec<u8>> = None;
+    while let Some(field) = payload.next().await {
+        let mut field = match field {
+            Ok(f) => f,
+            Err(e) => {
+                return HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": format!("Invalid multipart body: {}", e)
+                }));
+            }
+        };
+        if field.name() != Some("file") {
+            continue;
+        }
+
+        let mut buf = Vec::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = match chunk {
+                Ok(c) => c,
+                Err(e) => {
+                    return HttpResponse::BadRequest().json(serde_json::json!({
+                        "error": format!("Invalid multipart body: {}", e)
+                    }));
+                }
+            };
+            if buf.len() + chunk.len() > config.media_max_bytes {
+                return HttpResponse::PayloadTooLarge().json(serde_json::json!({
+                    "error": format!("Image exceeds the {} byte limit", config.media_max_bytes)
+                }));
+            }
+            buf.extend_from_slice(&chunk);
+        }
+        bytes = Some(buf);
+        break;
+    }
+
+    let bytes = match bytes {
+        Some(b) if !b.is_empty() => b,
+        _ => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": "Missing file field"
+            }));
+        }
+    };
+
+    let (content_type, ext) = match crate::media::sniff_image(&bytes) {
+        Some(t) => t,
         None => {
-            return HttpResponse::Unauthorized().json(serde_json::json!({
-                "error": "Not authenticated"
+            return HttpResponse::UnsupportedMediaType().json(serde_json::json!({
+                "error": "Unsupported image type (PNG, JPEG, GIF or WebP)"
             }));
         }
     };
 
-    let product_id = path.into_inner();
+    let key = format!("{}.{}", uuid::Uuid::new_v4(), ext);
+    let size = bytes.len();
+    if let Err(e) = crate::media::store(&config, &http, &key, bytes, content_type).await {
+        tracing::error!(error = %e, "Failed to store product image");
+        return HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": "Failed to store image"
+        }));
+    }
 
-    match products::deactivate_product(pool.get_ref(), &product_id, &merchant.id).await {
-        Ok(true) => HttpResponse::Ok().json(serde_json::json!({ "status": "deactivated" })),
-        Ok(false) => HttpResponse::NotFound().json(serde_json::json!({
-            "error": "Product not found"
+    match products::add_image(pool.get_ref(), &product_id, &merchant.id, &key, content_type, size).await {
+        Ok(image) => HttpResponse::Created().json(serde_json::json!({
+            "id": image.id,
+            "url": image.url(),
+            "content_type": image.content_type,
+            "size_bytes": image.size_bytes,
+            "position": image.position,
         })),
         Err(e) => {
-            tracing::error!(error = %e, "Failed to deactivate product");
+            let _ = crate::media::delete(&config, &http, &key).await;
+            HttpResponse::BadRequest().json(serde_json::json!({
+                "error": e.to_string()
+            }))
+        }
+    }
+}
+
+pub async fn delete_image(
+    SessionMerchant(merchant): SessionMerchant,
+    pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
+    http: web::Data<reqwest::Client>,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    let (product_id, image_id) = path.into_inner();
+
+    match products::delete_image(pool.get_ref(), &image_id, &product_id, &merchant.id).await {
+        Ok(Some(key)) => {
+            if let Err(e) = crate::media::delete(&config, &http, &key).await {
+                tracing::warn!(key = %key, error = %e, "Failed to delete product image");
+            }
+            HttpResponse::Ok().json(serde_json::json!({ "status": "deleted" }))
+        }
+        Ok(None) => HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Image not found"
+        })),
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to delete product image");
             HttpResponse::InternalServerError().json(serde_json::json!({
                 "error": "Internal error"
             }))
@@ -129,8 +469,19 @@ pub async fn deactivate(
     }
 }
 
+/// Image URLs for a product, in display order.
+async fn image_urls(pool: &SqlitePool, product_id: &str) -> Vec<String> {
+    products::list_images(pool, product_id)
+        .await
+        .unwrap_or_default()
+        .iter()
+        .map(|i| i.url())
+        .collect()
+}
+
 /// Public endpoint: get product details for buyers (only active products)
 pub async fn get_public(
+    req: HttpRequest,
     pool: web::Data<SqlitePool>,
     path: web::Path<String>,
 ) -> HttpResponse {
@@ -138,6 +489,15 @@ pub async fn get_public(
 
     match products::get_product(pool.get_ref(), &product_id).await {
         Ok(Some(product)) if product.active == 1 => {
+            let images = image_urls(pool.get_ref(), &product.id).await;
+            let client_ip = crate::client_ip::from_request(&req).map(|ip| ip.to_string());
+            let token = match products::tokens::issue(pool.get_ref(), &product.id, client_ip.as_deref()).await {
+                Ok(token) => token,
+                Err(e) => {
+                    tracing::error!(error = %e, "Failed to issue checkout token");
+                    None
+                }
+            };
             HttpResponse::Ok().json(serde_json::json!({
                 "id": product.id,
                 "name": product.name,
@@ -146,6 +506,13 @@ pub async fn get_public(
                 "currency": product.currency,
                 "variants": product.variants_list(),
                 "slug": product.slug,
+                "category": product.category,
+                "tags": product.tags_list(),
+                "max_quantity": product.quantity_limit(),
+                "checkout_fields": product.checkout_fields_list(),
+                "images": images,
+                "checkout_token": token.as_ref().map(|t| &t.token),
+                "checkout_token_expires_at": token.as_ref().map(|t| &t.expires_at),
             }))
         }
         _ => HttpResponse::NotFound().json(serde_json::json!({
@@ -154,6 +521,130 @@ pub async fn get_public(
     }
 }
 
+#[derive(Debug, serde::Deserialize)]
+pub struct CatalogQuery {
+    pub category: Option<String>,
+    pub tag: Option<String>,
+    pub page: Option<i64>,
+    pub per_page: Option<i64>,
+}
+
+/// Public storefront catalog: a merchant's active products grouped by category, paginated.
+/// The merchant can be addressed by ID or vanity slug.
+pub async fn catalog(
+    pool: web::Data<ReadPool>,
+    path: web::Path<String>,
+    query: web::Query<CatalogQuery>,
+) -> HttpResponse {
+    let merchant_ref = path.into_inner();
+
+    let merchant: Option<(String, String, Option<String>)> = match sqlx::query_as(
+        "SELECT id, name, slug FROM merchants WHERE id = ? OR slug = ? COLLATE NOCASE"
+    )
+    .bind(&merchant_ref)
+    .bind(&merchant_ref)
+    .fetch_optional(&**pool.get_ref())
+    .await
+    {
+        Ok(row) => row,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to look up merchant");
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            }));
+        }
+    };
+    let (merchant_id, merchant_name, merchant_slug) = match merchant {
+        Some(m) => m,
+        None => {
+            return HttpResponse::NotFound().json(serde_json::json!({
+                "error": "Merchant not found"
+            }));
+        }
+    };
+
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(50).clamp(1, 100);
+
+    let (items, total) = match products::list_catalog(
+        pool.get_ref(),
+        &merchant_id,
+        query.category.as_deref(),
+        query.tag.as_deref(),
+        per_page,
+        (page - 1) * per_page,
+    ).await {
+        Ok(r) => r,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to list catalog");
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            }));
+        }
+    };
+
+    let ids: Vec<String> = items.iter().map(|p| p.id.clone()).collect();
+    let mut images = match products::list_images_for(pool.get_ref(), &ids).await {
+        Ok(images) => images,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to list catalog images");
+            Default::default()
+        }
+    };
+
+    // Rows arrive ordered by category, so grouping only needs to watch for changes.
+    let mut categories: Vec<serde_json::Value> = Vec::new();
+    let mut current: Option<Option<String>> = None;
+    for product in items {
+        if current.as_ref() != Some(&product.category) {
+            categories.push(serde_json::json!({
+                "category": product.category,
+                "products": [],
+            }));
+            current = Some(product.category.clone());
+        }
+        let images: Vec<String> = images.remove(&product.id).unwrap_or_default().iter().map(|i| i.url()).collect();
+        if let Some(group) = categories.last_mut() {
+            if let Some(list) = group["products"].as_array_mut() {
+                list.push(serde_json::json!({
+                    "id": product.id,
+                    "slug": product.slug,
+                    "name": product.name,
+                    "description": product.description,
+                    "price_eur": product.price_eur,
+                    "currency": product.currency,
+                    "variants": product.variants_list(),
+                    "tags": product.tags_list(),
+                    "max_quantity": product.quantity_limit(),
+                    "images": images,
+                }));
+            }
+        }
+    }
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "merchant_id": merchant_id,
+        "merchant_name": merchant_name,
+        "merchant_slug": merchant_slug,
+        "page": page,
+        "per_page": per_page,
+        "total": total,
+        "categories": categories,
+    }))
+}
+
+fn validate_tags(tags: &Option<Vec<String>>) -> Result<(), validation::ValidationError> {
+    if let Some(ref tags) = tags {
+        if tags.len() > 20 {
+            return Err(validation::ValidationError::invalid("tags", "too many tags (max 20)"));
+        }
+        for t in tags {
+            validation::validate_length("tag", t, 50)?;
+        }
+    }
+    Ok(())
+}
+
 fn validate_product_create(req: &CreateProductRequest) -> Result<(), validation::ValidationError> {
     validation::validate_length("slug", &req.slug, 100)?;
     validation::validate_length("name", &req.name, 200)?;
@@ -171,6 +662,16 @@ fn validate_product_create(req: &CreateProductRequest) -> Result<(), validation:
             validation::validate_length("variant", v, 100)?;
         }
     }
+    validation::validate_optional_length("category", &req.category, 100)?;
+    validate_tags(&req.tags)?;
+    if let Some(q) = req.max_quantity {
+        if !(1..=10_000).contains(&q) {
+            return Err(validation::ValidationError::invalid("max_quantity", "must be between 1 and 10000"));
+        }
+    }
+    if let Some(ref fields) = req.checkout_fields {
+        products::fields::validate_definitions(fields)?;
+    }
     Ok(())
 }
 
@@ -194,5 +695,101 @@ fn validate_product_update(req: &UpdateProductRequest) -> Result<(), validation:
             validation::validate_length("variant", v, 100)?;
         }
     }
+    validation::validate_optional_length("category", &req.category, 100)?;
+    validate_tags(&req.tags)?;
+    if let Some(q) = req.max_quantity {
+        if !(0..=10_000).contains(&q) {
+            return Err(validation::ValidationError::invalid("max_quantity", "must be between 0 (default) and 10000"));
+        }
+    }
+    if let Some(ref fields) = req.checkout_fields {
+        products::fields::validate_definitions(fields)?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+
+    async fn product(pool: &SqlitePool, merchant_id: &str, slug: &str, category: Option<&str>, tags: &[&str]) -> Product {
+        products::create_product(pool, merchant_id, &CreateProductRequest {
+            slug: slug.into(),
+            name: slug.to_uppercase(),
+            description: None,
+            price_eur: 10.0,
+            currency: None,
+            variants: None,
+            category: category.map(String::from),
+            tags: Some(tags.iter().map(|t| t.to_string()).collect()),
+            max_quantity: None,
+            checkout_fields: None,
+        }).await.unwrap()
+    }
+
+    /// `(category, [slugs])` per group of a catalog response.
+    fn groups(body: &serde_json::Value) -> Vec<(Option<String>, Vec<String>)> {
+        body["categories"].as_array().unwrap().iter()
+            .map(|g| (
+                g["category"].as_str().map(String::from),
+                g["products"].as_array().unwrap().iter().map(|p| p["slug"].as_str().unwrap().to_string()).collect(),
+            ))
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn test_catalog_groups_active_products_by_category() {
+        let pool = crate::db::test_pool().await;
+        let merchant_id = crate::db::test_merchant(&pool, 1).await.merchant_id;
+        let tee = product(&pool, &merchant_id, "tee", Some("Apparel"), &["cotton"]).await;
+        product(&pool, &merchant_id, "cap", Some("Apparel"), &[]).await;
+        let mug = product(&pool, &merchant_id, "mug", Some("Kitchen"), &["Cotton"]).await;
+        product(&pool, &merchant_id, "sticker", None, &[]).await;
+        let hidden = product(&pool, &merchant_id, "old-mug", Some("Kitchen"), &[]).await;
+        products::deactivate_product(&pool, &hidden.id, &merchant_id).await.unwrap();
+        let archived = product(&pool, &merchant_id, "old-tee", Some("Apparel"), &[]).await;
+        products::archive_products(&pool, &[archived.id], &merchant_id).await.unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(ReadPool::primary(&pool)))
+                .route("/merchants/{id}/catalog", web::get().to(catalog)),
+        )
+        .await;
+        let get = |query: String| test::TestRequest::get().uri(&format!("/merchants/{}/catalog{}", merchant_id, query)).to_request();
+
+        products::add_image(&pool, &tee.id, &merchant_id, "tee-front.png", "image/png", 10).await.unwrap();
+        products::add_image(&pool, &tee.id, &merchant_id, "tee-back.png", "image/png", 10).await.unwrap();
+        products::add_image(&pool, &mug.id, &merchant_id, "mug.png", "image/png", 10).await.unwrap();
+
+        let body: serde_json::Value = test::call_and_read_body_json(&app, get(String::new())).await;
+        assert_eq!(body["total"], 4);
+        let images = |group: usize, product: usize| body["categories"][group]["products"][product]["images"].clone();
+        assert_eq!(images(0, 0), serde_json::json!([]));
+        assert_eq!(images(0, 1), serde_json::json!(["/api/v1/media/tee-front.png", "/api/v1/media/tee-back.png"]));
+        assert_eq!(images(1, 0), serde_json::json!(["/api/v1/media/mug.png"]));
+        let apparel = (Some("Apparel".to_string()), vec!["cap".to_string(), "tee".to_string()]);
+        assert_eq!(groups(&body), [
+            apparel.clone(),
+            (Some("Kitchen".into()), vec!["mug".into()]),
+            (None, vec!["sticker".into()]),
+        ]);
+
+        // A page can end mid-category; the next one carries on with it.
+        let body: serde_json::Value = test::call_and_read_body_json(&app, get("?per_page=1&page=2".into())).await;
+        assert_eq!((body["total"].as_i64(), body["page"].as_i64()), (Some(4), Some(2)));
+        assert_eq!(groups(&body), [(Some("Apparel".into()), vec!["tee".into()])]);
+
+        let body: serde_json::Value = test::call_and_read_body_json(&app, get("?tag=COTTON".into())).await;
+        assert_eq!(groups(&body), [
+            (Some("Apparel".into()), vec!["tee".into()]),
+            (Some("Kitchen".into()), vec!["mug".into()]),
+        ]);
+        let body: serde_json::Value = test::call_and_read_body_json(&app, get("?category=Apparel".into())).await;
+        assert_eq!(groups(&body), [apparel]);
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/merchants/nobody/catalog").to_request()).await;
+        assert_eq!(res.status(), 404);
+    }
+}
@@ -1,8 +1,75 @@
 use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use sqlx::SqlitePool;
 
-use crate::invoices::pricing::PriceService;
+use crate::invoices::pricing::{self, HistoryRange, PriceService};
+
+#[derive(Debug, Deserialize)]
+pub struct RatesQuery {
+    /// RFC 3339 timestamp: return the rate in effect at that time.
+    pub at: Option<String>,
+    /// `24h` or `7d`: return the recorded rates over that window.
+    pub range: Option<String>,
+}
+
+pub async fn get(
+    pool: web::Data<SqlitePool>,
+    price_service: web::Data<PriceService>,
+    query: web::Query<RatesQuery>,
+) -> HttpResponse {
+    if query.at.is_some() && query.range.is_some() {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "at and range cannot be combined"
+        }));
+    }
+
+    if let Some(at) = &query.at {
+        let at = match DateTime::parse_from_rfc3339(at) {
+            Ok(t) => t.with_timezone(&Utc),
+            Err(_) => {
+                return HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": "at must be an RFC 3339 timestamp"
+                }));
+            }
+        };
+        return match pricing::rate_at(pool.get_ref(), at).await {
+            Ok(Some(rates)) => HttpResponse::Ok().json(rates),
+            Ok(None) => HttpResponse::NotFound().json(serde_json::json!({
+                "error": "No rate recorded before that time"
+            })),
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to look up historical rate");
+                HttpResponse::InternalServerError().json(serde_json::json!({
+                    "error": "Internal error"
+                }))
+            }
+        };
+    }
+
+    if let Some(range) = &query.range {
+        let range = match HistoryRange::parse(range) {
+            Some(r) => r,
+            None => {
+                return HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": "range must be 24h or 7d"
+                }));
+            }
+        };
+        return match pricing::history(pool.get_ref(), range).await {
+            Ok(points) => HttpResponse::Ok().json(serde_json::json!({
+                "range": range.as_str(),
+                "points": points,
+            })),
+            Err(e) => {
+                tracing::error!(error = %e, "Failed to load rate history");
+                HttpResponse::InternalServerError().json(serde_json::json!({
+                    "error": "Internal error"
+                }))
+            }
+        };
+    }
 
-pub async fn get(price_service: web::Data<PriceService>) -> HttpResponse {
     match price_service.get_rates().await {
         Ok(rates) => HttpResponse::Ok().json(rates),
         Err(e) => {
@@ -0,0 +1,196 @@
+use actix_web::{web, HttpResponse, ResponseError};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+
+use super::extract::AnyMerchant;
+use crate::config::Config;
+use crate::invoices::{self, pricing::PriceService, state::InvoiceState, Invoice};
+use crate::scanner::{decrypt, simulate};
+
+const MAX_DELAY_SECS: u64 = 300;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SimulateRequest {
+    /// ZEC paid (simulate-detect only). Defaults to what is still owed; less makes the
+    /// invoice underpaid, more overpaid.
+    pub amount_zec: Option<f64>,
+    /// Apply the change after this many seconds instead of right away.
+    pub delay_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Action {
+    Detect,
+    Confirm,
+    Expire,
+}
+
+/// POST /api/invoices/{id}/simulate-detect -- a payment reaches the mempool.
+pub async fn detect(
+    merchant: AnyMerchant,
+    state: SimulationState,
+    path: web::Path<String>,
+    body: Option<web::Json<SimulateRequest>>,
+) -> HttpResponse {
+    simulate_action(Action::Detect, merchant, state, path, body).await
+}
+
+/// POST /api/invoices/{id}/simulate-confirm -- the detected payment is mined.
+pub async fn confirm(
+    merchant: AnyMerchant,
+    state: SimulationState,
+    path: web::Path<String>,
+    body: Option<web::Json<SimulateRequest>>,
+) -> HttpResponse {
+    simulate_action(Action::Confirm, merchant, state, path, body).await
+}
+
+/// POST /api/invoices/{id}/simulate-expire -- the invoice runs out of time.
+pub async fn expire(
+    merchant: AnyMerchant,
+    state: SimulationState,
+    path: web::Path<String>,
+    body: Option<web::Json<SimulateRequest>>,
+) -> HttpResponse {
+    simulate_action(Action::Expire, merchant, state, path, body).await
+}
+
+type SimulationState = (
+    web::Data<SqlitePool>,
+    web::Data<Config>,
+    web::Data<reqwest::Client>,
+    web::Data<PriceService>,
+);
+
+async fn simulate_action(
+    action: Action,
+    AnyMerchant(merchant): AnyMerchant,
+    (pool, config, http, prices): SimulationState,
+    path: web::Path<String>,
+    body: Option<web::Json<SimulateRequest>>,
+) -> HttpResponse {
+    if merchant.network != "testnet" {
+        return HttpResponse::NotFound().json(serde_json::json!({
+            "error": "Simulation is only available on testnet"
+        }));
+    }
+    let body = body.map(|b| b.into_inner()).unwrap_or_default();
+
+    let invoice = match invoices::get_invoice(pool.get_ref(), &path.into_inner()).await {
+        Ok(Some(inv)) if inv.merchant_id == merchant.id => inv,
+        Ok(_) => {
+            return HttpResponse::NotFound().json(serde_json::json!({
+                "error": "Invoice not found"
+            }));
+        }
+        Err(e) => return e.error_response(),
+    };
+
+    let allowed = match (action, invoice.state()) {
+        (_, None) => false,
+        (Action::Detect, Some(InvoiceState::Expired)) => {
+            in_grace_window(pool.get_ref(), &config, &invoice.id).await
+        }
+        (Action::Detect, Some(state)) => state.can_become(InvoiceState::Detected),
+        (Action::Confirm, Some(state)) => state.can_become(InvoiceState::Confirmed),
+        (Action::Expire, Some(state)) => state.can_become(InvoiceState::Expired),
+    };
+    if !allowed {
+        return HttpResponse::Conflict().json(serde_json::json!({
+            "error": format!("Cannot simulate this on a {} invoice", invoice.status)
+        }));
+    }
+
+    let owed = (invoice.price_zatoshis - invoice.received_zatoshis).max(1);
+    let amount_zatoshis = match body.amount_zec {
+        None => owed,
+        Some(zec) if zec.is_finite() && zec > 0.0 => crate::invoices::amounts::zec_to_zatoshis(zec),
+        Some(_) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "error": "amount_zec must be positive"
+            }));
+        }
+    };
+    if matches!(action, Action::Detect) && decrypt::is_dust(amount_zatoshis, invoice.price_zatoshis) {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "amount_zec is below the dust threshold; the scanner would ignore it"
+        }));
+    }
+
+    let delay_secs = body.delay_secs.unwrap_or(0);
+    if delay_secs > MAX_DELAY_SECS {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": format!("delay_secs must be at most {}", MAX_DELAY_SECS)
+        }));
+    }
+
+    let txid = match action {
+        Action::Detect => Some(simulate::fake_txid()),
+        Action::Confirm => invoice.detected_txid.clone(),
+        Action::Expire => None,
+    };
+
+    if delay_secs > 0 {
+        let (invoice_id, task_txid) = (invoice.id.clone(), txid.clone());
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_secs(delay_secs)).await;
+            if let Err(e) = apply(action, &config, &pool, &http, &prices, &invoice_id, task_txid.as_deref(), amount_zatoshis).await {
+                tracing::warn!(invoice_id, error = %e, "Delayed simulation failed");
+            }
+        });
+        return HttpResponse::Accepted().json(serde_json::json!({
+            "status": "scheduled",
+            "txid": txid,
+            "delay_secs": delay_secs,
+        }));
+    }
+
+    match apply(action, &config, &pool, &http, &prices, &invoice.id, txid.as_deref(), amount_zatoshis).await {
+        Ok(inv) => HttpResponse::Ok().json(serde_json::json!({
+            "status": inv.status,
+            "txid": txid,
+            "received_zatoshis": inv.received_zatoshis,
+            "price_zatoshis": inv.price_zatoshis,
+        })),
+        Err(e) => {
+            tracing::error!(invoice_id = %invoice.id, error = %e, "Simulation failed");
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": "Internal error"
+            }))
+        }
+    }
+}
+
+async fn in_grace_window(pool: &SqlitePool, config: &Config, invoice_id: &str) -> bool {
+    invoices::get_recently_expired(pool, "testnet", config.late_payment_grace_minutes)
+        .await
+        .is_ok_and(|recent| recent.iter().any(|i| i.id == invoice_id))
+}
+
+/// Run the action against the invoice as it is now (it may have moved on during a
+/// delay) and return it afterwards.
+#[allow(clippy::too_many_arguments)]
+async fn apply(
+    action: Action,
+    config: &Config,
+    pool: &SqlitePool,
+    http: &reqwest::Client,
+    prices: &PriceService,
+    invoice_id: &str,
+    txid: Option<&str>,
+    amount_zatoshis: i64,
+) -> anyhow::Result<Invoice> {
+    let invoice = invoices::get_invoice(pool, invoice_id).await?
+        .ok_or_else(|| anyhow::anyhow!("invoice not found"))?;
+
+    match action {
+        Action::Detect => {
+            simulate::payment(config, pool, http, &invoice, txid.unwrap_or_default(), amount_zatoshis).await?;
+        }
+        Action::Confirm => simulate::confirmation(config, pool, http, &invoice).await?,
+        Action::Expire => simulate::expiry(config, pool, http, prices, &invoice).await?,
+    }
+
+    invoices::get_invoice(pool, invoice_id).await?
+        .ok_or_else(|| anyhow::anyhow!("invoice not found"))
+}
@@ -0,0 +1,119 @@
+//! Public operational status: whether the scanner and the price feed are keeping up, so a
+//! merchant waiting on a detection can tell whether the delay is on CipherPay's side.
+//! Coarse on purpose; it reveals nothing about merchants or invoices.
+
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use super::version::ApiVersion;
+use crate::config::Config;
+use crate::invoices::pricing::{PriceService, RateSource};
+
+/// How long a computed status is served before it is recomputed.
+const CACHE_SECS: u64 = 15;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SystemStatus {
+    /// `operational`, or `degraded` when a scanner or the price feed is behind.
+    pub status: &'static str,
+    pub api_version: &'static str,
+    pub server_version: &'static str,
+    /// The primary network; `networks` has one entry per network served.
+    pub network: String,
+    pub networks: Vec<NetworkStatus>,
+    pub price_feed: PriceFeedStatus,
+    pub generated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NetworkStatus {
+    pub network: String,
+    /// Highest block the scanner has processed.
+    pub chain_height: Option<u64>,
+    pub mempool_scanned_at: Option<DateTime<Utc>>,
+    pub mempool_scan_age_secs: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PriceFeedStatus {
+    pub source: Option<RateSource>,
+    pub updated_at: Option<DateTime<Utc>>,
+    pub age_secs: Option<i64>,
+}
+
+static CACHE: LazyLock<Mutex<Option<(Instant, SystemStatus)>>> = LazyLock::new(Default::default);
+
+pub async fn status(
+    pool: web::Data<SqlitePool>,
+    config: web::Data<Config>,
+    prices: web::Data<PriceService>,
+) -> HttpResponse {
+    let cached = CACHE.lock().ok().and_then(|cache| {
+        cache.as_ref()
+            .filter(|(at, _)| at.elapsed() < Duration::from_secs(CACHE_SECS))
+            .map(|(_, status)| status.clone())
+    });
+    let status = match cached {
+        Some(status) => status,
+        None => {
+            let status = compute(pool.get_ref(), &config, &prices).await;
+            if let Ok(mut cache) = CACHE.lock() {
+                *cache = Some((Instant::now(), status.clone()));
+            }
+            status
+        }
+    };
+
+    HttpResponse::Ok()
+        .insert_header(("Cache-Control", format!("public, max-age={}", CACHE_SECS)))
+        .json(status)
+}
+
+async fn compute(pool: &SqlitePool, config: &Config, prices: &PriceService) -> SystemStatus {
+    let now = Utc::now();
+    let scanner_limit = config.alert_scanner_stall_minutes * 60;
+    let mut degraded = false;
+
+    let mut networks = Vec::new();
+    for endpoint in &config.networks {
+        let chain_height = crate::db::get_scanner_state(pool, &crate::db::height_key(&endpoint.network))
+            .await
+            .and_then(|h| h.parse().ok());
+        let scanned_at = crate::scanner::last_mempool_pass(&endpoint.network);
+        let age = scanned_at.map(|at| (now - at).num_seconds());
+        degraded |= age.is_none_or(|age| age > scanner_limit);
+        networks.push(NetworkStatus {
+            network: endpoint.network.clone(),
+            chain_height,
+            mempool_scanned_at: scanned_at,
+            mempool_scan_age_secs: age,
+        });
+    }
+
+    let price_feed = match prices.get_rates().await {
+        Ok(rates) => {
+            let age = (now - rates.updated_at).num_seconds();
+            degraded |= rates.source == RateSource::Feed && age > config.alert_price_stale_minutes * 60;
+            PriceFeedStatus { source: Some(rates.source), updated_at: Some(rates.updated_at), age_secs: Some(age) }
+        }
+        Err(_) => {
+            degraded = true;
+            PriceFeedStatus { source: None, updated_at: None, age_secs: None }
+        }
+    };
+
+    SystemStatus {
+        status: if degraded { "degraded" } else { "operational" },
+        api_version: ApiVersion::DEFAULT.as_str(),
+        server_version: env!("CARGO_PKG_VERSION"),
+        network: config.network.clone(),
+        networks,
+        price_feed,
+        generated_at: now,
+    }
+}
@@ -0,0 +1,103 @@
+//! API versioning. Routes are served under `/api/v1`; the unversioned `/api` paths are
+//! aliases kept for existing integrations and will be removed in a later release. On an
+//! unversioned path a client can ask for a version with the `X-CipherPay-Version` request
+//! header. Every response carries the version that served it in the same header.
+//!
+//! A breaking response change ships as a new `ApiVersion` variant and `/api/v2` mount;
+//! handlers that differ take `ApiVersion` as an extractor and shape their response by it.
+
+use std::future::{ready, Ready};
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{FromRequest, HttpMessage, HttpRequest, HttpResponse};
+
+pub const VERSION_HEADER: &str = "x-cipherpay-version";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ApiVersion {
+    V1,
+}
+
+impl ApiVersion {
+    /// Served on unversioned paths when the client doesn't ask for a version.
+    pub const DEFAULT: ApiVersion = ApiVersion::V1;
+    pub const SUPPORTED: &'static [ApiVersion] = &[ApiVersion::V1];
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().trim_start_matches(['v', 'V']) {
+            "1" => Some(ApiVersion::V1),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ApiVersion::V1 => "1",
+        }
+    }
+
+    /// The version named by a `/api/v{N}/...` path, if it has one.
+    fn from_path(path: &str) -> Option<Self> {
+        let segment = path.strip_prefix("/api/")?.split('/').next()?;
+        segment.starts_with('v').then(|| Self::parse(segment)).flatten()
+    }
+}
+
+/// Resolve the request's version (path first, then header), make it available to
+/// handlers and echo it on the response. An unsupported version in the header is a 400.
+pub async fn middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let requested = req.headers().get(VERSION_HEADER).map(|v| v.to_str().unwrap_or_default().to_string());
+    let version = match (ApiVersion::from_path(req.path()), requested) {
+        (Some(version), _) => version,
+        (None, None) => ApiVersion::DEFAULT,
+        (None, Some(requested)) => match ApiVersion::parse(&requested) {
+            Some(version) => version,
+            None => {
+                let supported: Vec<&str> = ApiVersion::SUPPORTED.iter().map(|v| v.as_str()).collect();
+                let resp = HttpResponse::BadRequest().json(serde_json::json!({
+                    "error": format!("Unsupported API version {:?}", requested),
+                    "supported_versions": supported,
+                }));
+                return Ok(req.into_response(resp));
+            }
+        },
+    };
+
+    req.extensions_mut().insert(version);
+    let mut res = next.call(req).await?.map_into_boxed_body();
+    res.headers_mut().insert(
+        HeaderName::from_static(VERSION_HEADER),
+        HeaderValue::from_static(version.as_str()),
+    );
+    Ok(res)
+}
+
+impl FromRequest for ApiVersion {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(Ok(req.extensions().get::<ApiVersion>().copied().unwrap_or(ApiVersion::DEFAULT)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_from_path_and_header() {
+        assert_eq!(ApiVersion::from_path("/api/v1/invoices"), Some(ApiVersion::V1));
+        assert_eq!(ApiVersion::from_path("/api/invoices/v1"), None);
+        assert_eq!(ApiVersion::from_path("/api/health"), None);
+        assert_eq!(ApiVersion::parse("1"), Some(ApiVersion::V1));
+        assert_eq!(ApiVersion::parse("v1"), Some(ApiVersion::V1));
+        assert_eq!(ApiVersion::parse("2"), None);
+    }
+}
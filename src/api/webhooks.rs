@@ -0,0 +1,50 @@
+use actix_web::{web, HttpResponse};
+use cipherpay_client::webhook::{self, WebhookHeaders};
+use serde::Deserialize;
+
+use super::extract::SessionMerchant;
+
+/// Signature schemes, replay tolerance and a verification example for webhook receivers.
+pub async fn signing_info() -> HttpResponse {
+    HttpResponse::Ok().json(crate::webhooks::signing_info())
+}
+
+/// A delivery as the merchant's receiver saw it: header values and the raw body.
+#[derive(Debug, Deserialize)]
+pub struct VerifyRequest {
+    pub event_id: String,
+    pub timestamp: String,
+    pub signatures: Option<String>,
+    pub signature: Option<String>,
+    pub body: String,
+}
+
+/// POST /api/webhooks/verify -- check a received delivery against the merchant's current
+/// webhook secret (dashboard session), to debug a receiver that rejects our signatures.
+pub async fn verify(
+    SessionMerchant(merchant): SessionMerchant,
+    body: web::Json<VerifyRequest>,
+) -> HttpResponse {
+    if body.body.len() > 64 * 1024 {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "body too large"
+        }));
+    }
+
+    let headers = WebhookHeaders {
+        event_id: &body.event_id,
+        timestamp: &body.timestamp,
+        signatures: body.signatures.as_deref(),
+        signature: body.signature.as_deref(),
+    };
+    match webhook::verify_signature(&merchant.webhook_secret, &headers, body.body.as_bytes(), chrono::Utc::now()) {
+        Ok(scheme) => HttpResponse::Ok().json(serde_json::json!({
+            "valid": true,
+            "scheme": scheme.as_str(),
+        })),
+        Err(e) => HttpResponse::Ok().json(serde_json::json!({
+            "valid": false,
+            "error": e.to_string(),
+        })),
+    }
+}
@@ -1,10 +1,10 @@
-use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web::{web, HttpResponse};
 use serde::{Deserialize, Serialize};
 use sqlx::SqlitePool;
 use uuid::Uuid;
 
-use crate::config::Config;
-use crate::merchants;
+use super::extract::{AnyMerchant, ApiKeyMerchant};
+use crate::scanner::cipherscan::CipherScans;
 use crate::scanner::{decrypt, mempool};
 
 const SLIPPAGE_TOLERANCE: f64 = 0.995;
@@ -26,36 +26,11 @@ struct VerifyResponse {
 }
 
 pub async fn verify(
-    req: HttpRequest,
+    ApiKeyMerchant(merchant): ApiKeyMerchant,
     pool: web::Data<SqlitePool>,
-    config: web::Data<Config>,
-    http_client: web::Data<reqwest::Client>,
+    cipherscans: web::Data<CipherScans>,
     body: web::Json<VerifyRequest>,
 ) -> HttpResponse {
-    let api_key = match extract_api_key(&req) {
-        Some(k) => k,
-        None => {
-            return HttpResponse::Unauthorized().json(serde_json::json!({
-                "error": "Missing or invalid Authorization header"
-            }));
-        }
-    };
-
-    let merchant = match merchants::authenticate(&pool, &api_key, &config.encryption_key).await {
-        Ok(Some(m)) => m,
-        Ok(None) => {
-            return HttpResponse::Unauthorized().json(serde_json::json!({
-                "error": "Invalid API key"
-            }));
-        }
-        Err(e) => {
-            tracing::error!(error = %e, "x402 auth error");
-            return HttpResponse::InternalServerError().json(serde_json::json!({
-                "error": "Internal error"
-            }));
-        }
-    };
-
     if body.txid.len() != 64 || !body.txid.chars().all(|c| c.is_ascii_hexdigit()) {
         return HttpResponse::BadRequest().json(serde_json::json!({
             "error": "Invalid txid format — expected 64 hex characters"
@@ -70,7 +45,7 @@ pub async fn verify(
 
     let previously_verified = was_previously_verified(&pool, &merchant.id, &body.txid).await;
 
-    let raw_hex = match mempool::fetch_raw_tx(&http_client, &config.cipherscan_api_url, &body.txid).await {
+    let raw_hex = match mempool::fetch_raw_tx(cipherscans.for_network(&merchant.network), &body.txid).await {
         Ok(hex) => hex,
         Err(e) => {
             tracing::warn!(txid = %body.txid, error = %e, "x402: failed to fetch raw tx");
@@ -95,7 +70,7 @@ pub async fn verify(
 
     let total_zatoshis: u64 = outputs.iter().map(|o| o.amount_zatoshis).sum();
     let total_zec = total_zatoshis as f64 / 100_000_000.0;
-    let expected_zatoshis = (body.expected_amount_zec * 100_000_000.0) as u64;
+    let expected_zatoshis = crate::invoices::amounts::zec_to_zatoshis(body.expected_amount_zec).max(0) as u64;
     let min_acceptable = (expected_zatoshis as f64 * SLIPPAGE_TOLERANCE) as u64;
 
     if total_zatoshis >= min_acceptable {
@@ -132,20 +107,10 @@ pub struct HistoryQuery {
 }
 
 pub async fn history(
-    req: HttpRequest,
+    AnyMerchant(merchant): AnyMerchant,
     pool: web::Data<SqlitePool>,
-    config: web::Data<Config>,
     query: web::Query<HistoryQuery>,
 ) -> HttpResponse {
-    let merchant = match resolve_merchant(&req, &pool, &config).await {
-        Some(m) => m,
-        None => {
-            return HttpResponse::Unauthorized().json(serde_json::json!({
-                "error": "Not authenticated"
-            }));
-        }
-    };
-
     let limit = query.limit.unwrap_or(50).min(200);
     let offset = query.offset.unwrap_or(0).max(0);
 
@@ -186,30 +151,6 @@ pub async fn history(
     }
 }
 
-/// Try session cookie first, then fall back to API key auth.
-async fn resolve_merchant(
-    req: &HttpRequest,
-    pool: &SqlitePool,
-    config: &Config,
-) -> Option<merchants::Merchant> {
-    if let Some(m) = super::auth::resolve_session(req, pool).await {
-        return Some(m);
-    }
-    if let Some(key) = extract_api_key(req) {
-        if let Ok(Some(m)) = merchants::authenticate(pool, &key, &config.encryption_key).await {
-            return Some(m);
-        }
-    }
-    None
-}
-
-fn extract_api_key(req: &HttpRequest) -> Option<String> {
-    let header = req.headers().get("Authorization")?;
-    let value = header.to_str().ok()?;
-    let key = value.strip_prefix("Bearer ").unwrap_or(value).trim();
-    if key.is_empty() { None } else { Some(key.to_string()) }
-}
-
 async fn build_rejected(
     pool: &SqlitePool,
     merchant_id: &str,
@@ -0,0 +1,147 @@
+//! Secrets backup for disaster recovery. Every merchant's UFVK and webhook secret are
+//! exported as JSON encrypted to the operator's age X25519 key (`BACKUP_RECIPIENT`), so the
+//! server never writes them out in plaintext and cannot read a backup back. To recover onto
+//! a new instance (or after losing `ENCRYPTION_KEY`), restore the database, decrypt the
+//! backup offline with `age -d -i <identity>` and post the JSON to the restore endpoint,
+//! which re-encrypts each secret under the instance's current key.
+
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+/// Bumped if the document layout changes.
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SecretsBackup {
+    pub version: u32,
+    pub network: String,
+    pub created_at: String,
+    pub merchants: Vec<MerchantSecrets>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MerchantSecrets {
+    pub id: String,
+    pub name: String,
+    pub ufvk: String,
+    pub webhook_secret: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RestoreSummary {
+    pub restored: usize,
+    /// Merchants in the backup that this database does not have.
+    pub unknown: Vec<String>,
+    /// Merchants whose backed-up UFVK does not derive their payment address; left unchanged.
+    pub mismatched: Vec<String>,
+}
+
+/// The ASCII-armored age file holding every active merchant's secrets.
+pub async fn export(pool: &SqlitePool, network: &str, encryption_key: &str, recipient: &str) -> anyhow::Result<String> {
+    let rows: Vec<(String, String, String, String)> = sqlx::query_as(
+        "SELECT id, name, ufvk, webhook_secret FROM merchants WHERE deleted_at IS NULL ORDER BY created_at"
+    )
+    .fetch_all(pool)
+    .await?;
+
+    // Unlike `merchants::row_to_merchant`, a value that fails to decrypt is an error here:
+    // a backup silently holding ciphertext would be useless when it is needed.
+    let mut merchants = Vec::with_capacity(rows.len());
+    for (id, name, ufvk, webhook_secret) in rows {
+        merchants.push(MerchantSecrets {
+            ufvk: crate::crypto::decrypt_or_plaintext(&ufvk, encryption_key)
+                .map_err(|e| anyhow::anyhow!("merchant {}: UFVK: {}", id, e))?,
+            webhook_secret: crate::crypto::decrypt_webhook_secret(&webhook_secret, encryption_key)
+                .map_err(|e| anyhow::anyhow!("merchant {}: webhook secret: {}", id, e))?,
+            id,
+            name,
+        });
+    }
+
+    let backup = SecretsBackup {
+        version: FORMAT_VERSION,
+        network: network.to_string(),
+        created_at: chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+        merchants,
+    };
+    encrypt_to(recipient, serde_json::to_string(&backup)?.as_bytes())
+}
+
+fn encrypt_to(recipient: &str, plaintext: &[u8]) -> anyhow::Result<String> {
+    let recipient: age::x25519::Recipient = recipient
+        .parse()
+        .map_err(|e| anyhow::anyhow!("invalid backup recipient: {}", e))?;
+    Ok(age::encrypt_and_armor(&recipient, plaintext)?)
+}
+
+/// Write the backed-up secrets of merchants this database has, encrypted under
+/// `encryption_key`. A UFVK is only restored if it derives the merchant's payment address.
+pub async fn restore(pool: &SqlitePool, encryption_key: &str, backup: &SecretsBackup) -> anyhow::Result<RestoreSummary> {
+    if backup.version != FORMAT_VERSION {
+        anyhow::bail!("unsupported backup version {}", backup.version);
+    }
+    let seal = |value: &str| -> anyhow::Result<String> {
+        if encryption_key.is_empty() {
+            Ok(value.to_string())
+        } else {
+            crate::crypto::encrypt(value, encryption_key)
+        }
+    };
+
+    let mut summary = RestoreSummary { restored: 0, unknown: Vec::new(), mismatched: Vec::new() };
+    let mut tx = crate::db::begin_write(pool).await?;
+    for merchant in &backup.merchants {
+        let payment_address: Option<String> = sqlx::query_scalar("SELECT payment_address FROM merchants WHERE id = ?")
+            .bind(&merchant.id)
+            .fetch_optional(&mut *tx)
+            .await?;
+        let Some(payment_address) = payment_address else {
+            summary.unknown.push(merchant.id.clone());
+            continue;
+        };
+        let derives = crate::addresses::derive_invoice_address(&merchant.ufvk, 0)
+            .is_ok_and(|derived| derived.ua_string == payment_address);
+        if !derives {
+            summary.mismatched.push(merchant.id.clone());
+            continue;
+        }
+
+        sqlx::query("UPDATE merchants SET ufvk = ?, webhook_secret = ? WHERE id = ?")
+            .bind(seal(&merchant.ufvk)?)
+            .bind(seal(&merchant.webhook_secret)?)
+            .bind(&merchant.id)
+            .execute(&mut *tx)
+            .await?;
+        summary.restored += 1;
+    }
+    tx.commit().await?;
+
+    tracing::info!(
+        restored = summary.restored,
+        unknown = summary.unknown.len(),
+        mismatched = summary.mismatched.len(),
+        "Merchant secrets restored from backup"
+    );
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backup_decrypts_only_with_identity() {
+        let identity = age::x25519::Identity::generate();
+        let recipient = identity.to_public().to_string();
+        let armored = encrypt_to(&recipient, br#"{"ufvk":"uviewtest1"}"#).unwrap();
+        assert!(armored.starts_with("-----BEGIN AGE ENCRYPTED FILE-----"));
+        assert!(!armored.contains("uviewtest1"));
+
+        let plaintext = age::decrypt(&identity, armored.as_bytes()).unwrap();
+        assert_eq!(plaintext, br#"{"ufvk":"uviewtest1"}"#);
+
+        let other = age::x25519::Identity::generate();
+        assert!(age::decrypt(&other, armored.as_bytes()).is_err());
+        assert!(encrypt_to("not-a-key", b"{}").is_err());
+    }
+}
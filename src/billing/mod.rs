@@ -1,16 +1,43 @@
+pub mod report;
+
 use chrono::{Duration, Utc};
 use serde::Serialize;
 use sqlx::SqlitePool;
 use uuid::Uuid;
 
-use crate::config::Config;
+use crate::config::{Config, FeeCurrency};
+use crate::invoices::amounts::zec_to_zatoshis;
+use crate::invoices::state::{InvoiceState, Transition};
+
+#[derive(Debug, thiserror::Error)]
+pub enum BillingError {
+    #[error("Merchant not found")]
+    MerchantNotFound,
+    #[error(transparent)]
+    Invoice(#[from] crate::invoices::InvoiceError),
+    #[error("Database error: {0}")]
+    Database(#[source] sqlx::Error),
+}
+
+impl From<sqlx::Error> for BillingError {
+    fn from(e: sqlx::Error) -> Self {
+        match e {
+            sqlx::Error::RowNotFound => BillingError::MerchantNotFound,
+            e => BillingError::Database(e),
+        }
+    }
+}
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Serialize, sqlx::FromRow)]
 pub struct FeeEntry {
     pub id: String,
     pub invoice_id: String,
     pub merchant_id: String,
     pub fee_amount_zec: f64,
+    pub fee_currency: String,
+    /// Fee in `fee_currency` at the invoice's rate; None for ZEC-denominated fees.
+    pub fee_amount_fiat: Option<f64>,
     pub auto_collected: i32,
     pub collected_at: Option<String>,
     pub billing_cycle_id: Option<String>,
@@ -30,6 +57,162 @@ pub struct BillingCycle {
     pub status: String,
     pub grace_until: Option<String>,
     pub created_at: String,
+    /// Denomination the cycle accrues in. For EUR or USD, the `_fiat` totals are
+    /// authoritative and converted to ZEC when the cycle is invoiced.
+    pub fee_currency: String,
+    pub total_fees_fiat: f64,
+    pub auto_collected_fiat: f64,
+    pub outstanding_fiat: f64,
+    /// Set when the period ends: the rates used and the cycle's fees valued at them.
+    pub closed_at: Option<String>,
+    pub close_zec_eur: Option<f64>,
+    pub close_zec_usd: Option<f64>,
+    pub total_fees_eur: Option<f64>,
+    pub total_fees_usd: Option<f64>,
+}
+
+impl BillingCycle {
+    /// The cycle's fees in EUR and USD. Fiat cycles keep their own currency's total as
+    /// accrued and convert through ZEC for the other; ZEC cycles convert at the given rates.
+    /// A missing (zero) rate leaves that side None.
+    pub fn fiat_totals(&self, zec_eur: f64, zec_usd: f64) -> (Option<f64>, Option<f64>) {
+        let rate = |r: f64| (r > 0.0).then_some(r);
+        let zec = match self.fee_currency.as_str() {
+            "EUR" => rate(zec_eur).map(|r| self.total_fees_fiat / r),
+            "USD" => rate(zec_usd).map(|r| self.total_fees_fiat / r),
+            _ => Some(self.total_fees_zec),
+        };
+        let eur = match self.fee_currency.as_str() {
+            "EUR" => Some(self.total_fees_fiat),
+            _ => zec.zip(rate(zec_eur)).map(|(z, r)| z * r),
+        };
+        let usd = match self.fee_currency.as_str() {
+            "USD" => Some(self.total_fees_fiat),
+            _ => zec.zip(rate(zec_usd)).map(|(z, r)| z * r),
+        };
+        (eur, usd)
+    }
+
+    /// ZEC to settle the cycle at today's rates: the accrued `outstanding_zec` for ZEC
+    /// cycles, the fiat balance converted for EUR/USD ones. None if that rate is unknown.
+    /// Once invoiced, the cycle owes the amount already converted.
+    pub fn settlement_zec(&self, zec_eur: f64, zec_usd: f64) -> Option<f64> {
+        if self.status != "open" {
+            return Some(self.outstanding_zec);
+        }
+        let rate = match self.fee_currency.as_str() {
+            "EUR" => zec_eur,
+            "USD" => zec_usd,
+            _ => return Some(self.outstanding_zec),
+        };
+        (rate > 0.0).then(|| self.outstanding_fiat / rate)
+    }
+}
+
+/// Fee waivers the operator granted a merchant.
+#[derive(Debug, Clone, Default, Serialize, sqlx::FromRow)]
+pub struct FeePromo {
+    /// No fees at all, until lifted.
+    pub fee_exempt: bool,
+    /// No fees on invoices confirmed before this time.
+    pub fee_free_until: Option<String>,
+    /// Confirmed volume still to be charged no fee, in zatoshis.
+    pub fee_free_zatoshis: i64,
+}
+
+impl FeePromo {
+    /// True while every fee is waived: exempt, or inside a fee-free period.
+    pub fn waives_all(&self) -> bool {
+        let now = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+        self.fee_exempt || self.fee_free_until.as_deref().is_some_and(|until| until > now.as_str())
+    }
+}
+
+/// SQL condition, over merchants aliased `m`, matching `FeePromo::waives_all`.
+pub const FEES_WAIVED_SQL: &str =
+    "(m.fee_exempt = 1 OR COALESCE(m.fee_free_until, '') > strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))";
+
+pub async fn get_fee_promo(pool: &SqlitePool, merchant_id: &str) -> Result<FeePromo, BillingError> {
+    let promo = sqlx::query_as(
+        "SELECT fee_exempt, fee_free_until, fee_free_zatoshis FROM merchants WHERE id = ?"
+    )
+    .bind(merchant_id)
+    .fetch_one(pool)
+    .await?;
+    Ok(promo)
+}
+
+/// Operator changes to a merchant's waivers; None leaves a field as it is.
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct FeePromoUpdate {
+    pub fee_exempt: Option<bool>,
+    /// Fee-free days from now; 0 ends a running period.
+    pub fee_free_days: Option<i64>,
+    /// Fee-free volume in ZEC, replacing what is left; 0 clears it.
+    pub fee_free_zec: Option<f64>,
+}
+
+pub async fn update_fee_promo(
+    pool: &SqlitePool,
+    merchant_id: &str,
+    update: &FeePromoUpdate,
+) -> Result<FeePromo, BillingError> {
+    let fee_free_until = update.fee_free_days.map(|days| {
+        (days > 0).then(|| (Utc::now() + Duration::days(days)).format("%Y-%m-%dT%H:%M:%SZ").to_string())
+    });
+    let fee_free_zatoshis = update.fee_free_zec.map(|zec| zec_to_zatoshis(zec.max(0.0)));
+
+    let promo = sqlx::query_as(
+        "UPDATE merchants SET
+            fee_exempt = COALESCE(?, fee_exempt),
+            fee_free_until = CASE WHEN ? THEN ? ELSE fee_free_until END,
+            fee_free_zatoshis = COALESCE(?, fee_free_zatoshis)
+         WHERE id = ?
+         RETURNING fee_exempt, fee_free_until, fee_free_zatoshis"
+    )
+    .bind(update.fee_exempt)
+    .bind(fee_free_until.is_some())
+    .bind(fee_free_until.flatten())
+    .bind(fee_free_zatoshis)
+    .bind(merchant_id)
+    .fetch_one(pool)
+    .await?;
+
+    tracing::info!(merchant_id, ?update, "Fee waivers updated");
+    Ok(promo)
+}
+
+/// The share of an invoice's fee the merchant pays, after waivers: 0.0 when exempt or in a
+/// fee-free period, otherwise what is left once the invoice has drawn down any fee-free
+/// volume. Call once per confirmed invoice; the drawdown is permanent.
+pub async fn billable_share(pool: &SqlitePool, merchant_id: &str, price_zatoshis: i64) -> Result<f64, BillingError> {
+    if price_zatoshis <= 0 {
+        return Ok(1.0);
+    }
+    let mut tx = crate::db::begin_write(pool).await?;
+    let promo: FeePromo = sqlx::query_as(
+        "SELECT fee_exempt, fee_free_until, fee_free_zatoshis FROM merchants WHERE id = ?"
+    )
+    .bind(merchant_id)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if promo.waives_all() {
+        return Ok(0.0);
+    }
+    if promo.fee_free_zatoshis <= 0 {
+        return Ok(1.0);
+    }
+
+    let waived = promo.fee_free_zatoshis.min(price_zatoshis);
+    sqlx::query("UPDATE merchants SET fee_free_zatoshis = fee_free_zatoshis - ? WHERE id = ?")
+        .bind(waived)
+        .bind(merchant_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok((price_zatoshis - waived) as f64 / price_zatoshis as f64)
 }
 
 #[derive(Debug, Serialize)]
@@ -41,92 +224,117 @@ pub struct BillingSummary {
     pub total_fees_zec: f64,
     pub auto_collected_zec: f64,
     pub outstanding_zec: f64,
+    pub fee_currency: String,
+    pub outstanding_fiat: f64,
+    pub promo: FeePromo,
 }
 
+/// Record the fee owed on a confirmed invoice and add it to the merchant's open cycle.
+/// `fee_amount_fiat` is the same fee in `fee_currency`, at the invoice's rate.
+/// Idempotent per invoice: a second call leaves the ledger and the cycle untouched.
 pub async fn create_fee_entry(
     pool: &SqlitePool,
     invoice_id: &str,
     merchant_id: &str,
     fee_amount_zec: f64,
-) -> anyhow::Result<()> {
+    fee_currency: FeeCurrency,
+    fee_amount_fiat: Option<f64>,
+) -> Result<(), BillingError> {
     let id = Uuid::new_v4().to_string();
     let now = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
 
+    let mut tx = crate::db::begin_write(pool).await?;
     let cycle_id: Option<String> = sqlx::query_scalar(
         "SELECT id FROM billing_cycles WHERE merchant_id = ? AND status = 'open' LIMIT 1"
     )
     .bind(merchant_id)
-    .fetch_optional(pool)
+    .fetch_optional(&mut *tx)
     .await?;
 
-    sqlx::query(
-        "INSERT OR IGNORE INTO fee_ledger (id, invoice_id, merchant_id, fee_amount_zec, billing_cycle_id, created_at)
-         VALUES (?, ?, ?, ?, ?, ?)"
+    let inserted = sqlx::query(
+        "INSERT OR IGNORE INTO fee_ledger (id, invoice_id, merchant_id, fee_amount_zec, fee_currency, fee_amount_fiat,
+         billing_cycle_id, created_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
     )
     .bind(&id)
     .bind(invoice_id)
     .bind(merchant_id)
     .bind(fee_amount_zec)
+    .bind(fee_currency.as_str())
+    .bind(fee_amount_fiat)
     .bind(&cycle_id)
     .bind(&now)
-    .execute(pool)
-    .await?;
+    .execute(&mut *tx)
+    .await?
+    .rows_affected() > 0;
 
-    if let Some(cid) = &cycle_id {
+    if let (true, Some(cid)) = (inserted, &cycle_id) {
+        let fiat = fee_amount_fiat.unwrap_or(0.0);
         sqlx::query(
             "UPDATE billing_cycles SET
                 total_fees_zec = total_fees_zec + ?,
-                outstanding_zec = outstanding_zec + ?
+                outstanding_zec = outstanding_zec + ?,
+                total_fees_fiat = total_fees_fiat + CASE WHEN fee_currency = ? THEN ? ELSE 0 END,
+                outstanding_fiat = outstanding_fiat + CASE WHEN fee_currency = ? THEN ? ELSE 0 END
              WHERE id = ?"
         )
         .bind(fee_amount_zec)
         .bind(fee_amount_zec)
+        .bind(fee_currency.as_str())
+        .bind(fiat)
+        .bind(fee_currency.as_str())
+        .bind(fiat)
         .bind(cid)
-        .execute(pool)
+        .execute(&mut *tx)
         .await?;
     }
+    tx.commit().await?;
 
-    tracing::debug!(invoice_id, fee_amount_zec, "Fee entry created");
+    if inserted {
+        tracing::debug!(invoice_id, fee_amount_zec, "Fee entry created");
+    }
     Ok(())
 }
 
-pub async fn mark_fee_collected(pool: &SqlitePool, invoice_id: &str) -> anyhow::Result<()> {
+pub async fn mark_fee_collected(pool: &SqlitePool, invoice_id: &str) -> Result<(), BillingError> {
     let now = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
 
-    let result = sqlx::query(
+    let mut tx = crate::db::begin_write(pool).await?;
+    let entry: Option<(f64, String, Option<f64>, Option<String>)> = sqlx::query_as(
         "UPDATE fee_ledger SET auto_collected = 1, collected_at = ?
-         WHERE invoice_id = ? AND auto_collected = 0"
+         WHERE invoice_id = ? AND auto_collected = 0
+         RETURNING fee_amount_zec, fee_currency, fee_amount_fiat, billing_cycle_id"
     )
     .bind(&now)
     .bind(invoice_id)
-    .execute(pool)
+    .fetch_optional(&mut *tx)
     .await?;
 
-    if result.rows_affected() > 0 {
-        let entry: Option<(f64, Option<String>)> = sqlx::query_as(
-            "SELECT fee_amount_zec, billing_cycle_id FROM fee_ledger WHERE invoice_id = ?"
+    if let Some((amount, currency, fiat, Some(cycle_id))) = &entry {
+        let fiat = fiat.unwrap_or(0.0);
+        sqlx::query(
+            "UPDATE billing_cycles SET
+                auto_collected_zec = auto_collected_zec + ?,
+                outstanding_zec = MAX(0, outstanding_zec - ?),
+                auto_collected_fiat = auto_collected_fiat + CASE WHEN fee_currency = ? THEN ? ELSE 0 END,
+                outstanding_fiat = MAX(0, outstanding_fiat - CASE WHEN fee_currency = ? THEN ? ELSE 0 END)
+             WHERE id = ?"
         )
-        .bind(invoice_id)
-        .fetch_optional(pool)
+        .bind(amount)
+        .bind(amount)
+        .bind(currency)
+        .bind(fiat)
+        .bind(currency)
+        .bind(fiat)
+        .bind(cycle_id)
+        .execute(&mut *tx)
         .await?;
+    }
+    tx.commit().await?;
 
-        if let Some((amount, Some(cycle_id))) = entry {
-            sqlx::query(
-                "UPDATE billing_cycles SET
-                    auto_collected_zec = auto_collected_zec + ?,
-                    outstanding_zec = MAX(0, outstanding_zec - ?)
-                 WHERE id = ?"
-            )
-            .bind(amount)
-            .bind(amount)
-            .bind(&cycle_id)
-            .execute(pool)
-            .await?;
-        }
-
+    if entry.is_some() {
         tracing::info!(invoice_id, "Fee auto-collected");
     }
-
     Ok(())
 }
 
@@ -134,7 +342,7 @@ pub async fn get_billing_summary(
     pool: &SqlitePool,
     merchant_id: &str,
     config: &Config,
-) -> anyhow::Result<BillingSummary> {
+) -> Result<BillingSummary, BillingError> {
     let (trust_tier, billing_status): (String, String) = sqlx::query_as(
         "SELECT COALESCE(trust_tier, 'new'), COALESCE(billing_status, 'active')
          FROM merchants WHERE id = ?"
@@ -151,10 +359,13 @@ pub async fn get_billing_summary(
     .fetch_optional(pool)
     .await?;
 
-    let (total_fees, auto_collected, outstanding) = match &current_cycle {
-        Some(c) => (c.total_fees_zec, c.auto_collected_zec, c.outstanding_zec),
-        None => (0.0, 0.0, 0.0),
+    let (total_fees, auto_collected, outstanding, outstanding_fiat) = match &current_cycle {
+        Some(c) => (c.total_fees_zec, c.auto_collected_zec, c.outstanding_zec, c.outstanding_fiat),
+        None => (0.0, 0.0, 0.0, 0.0),
     };
+    let fee_currency = current_cycle.as_ref()
+        .map(|c| c.fee_currency.clone())
+        .unwrap_or_else(|| config.fee_currency.as_str().to_string());
 
     Ok(BillingSummary {
         fee_rate: config.fee_rate,
@@ -164,13 +375,16 @@ pub async fn get_billing_summary(
         total_fees_zec: total_fees,
         auto_collected_zec: auto_collected,
         outstanding_zec: outstanding,
+        fee_currency,
+        outstanding_fiat,
+        promo: get_fee_promo(pool, merchant_id).await?,
     })
 }
 
 pub async fn get_billing_history(
     pool: &SqlitePool,
     merchant_id: &str,
-) -> anyhow::Result<Vec<BillingCycle>> {
+) -> Result<Vec<BillingCycle>, BillingError> {
     let cycles = sqlx::query_as::<_, BillingCycle>(
         "SELECT * FROM billing_cycles WHERE merchant_id = ?
          ORDER BY period_start DESC LIMIT 24"
@@ -182,12 +396,13 @@ pub async fn get_billing_history(
     Ok(cycles)
 }
 
-pub async fn ensure_billing_cycle(pool: &SqlitePool, merchant_id: &str, config: &Config) -> anyhow::Result<()> {
+pub async fn ensure_billing_cycle(pool: &SqlitePool, merchant_id: &str, config: &Config) -> Result<(), BillingError> {
+    let mut tx = crate::db::begin_write(pool).await?;
     let existing: Option<String> = sqlx::query_scalar(
         "SELECT id FROM billing_cycles WHERE merchant_id = ? AND status = 'open' LIMIT 1"
     )
     .bind(merchant_id)
-    .fetch_optional(pool)
+    .fetch_optional(&mut *tx)
     .await?;
 
     if existing.is_some() {
@@ -198,7 +413,7 @@ pub async fn ensure_billing_cycle(pool: &SqlitePool, merchant_id: &str, config:
         "SELECT COALESCE(trust_tier, 'new') FROM merchants WHERE id = ?"
     )
     .bind(merchant_id)
-    .fetch_one(pool)
+    .fetch_one(&mut *tx)
     .await?;
 
     let cycle_days = match trust_tier.as_str() {
@@ -212,14 +427,15 @@ pub async fn ensure_billing_cycle(pool: &SqlitePool, merchant_id: &str, config:
     let period_end = (now + Duration::days(cycle_days)).format("%Y-%m-%dT%H:%M:%SZ").to_string();
 
     sqlx::query(
-        "INSERT INTO billing_cycles (id, merchant_id, period_start, period_end, status)
-         VALUES (?, ?, ?, ?, 'open')"
+        "INSERT INTO billing_cycles (id, merchant_id, period_start, period_end, status, fee_currency)
+         VALUES (?, ?, ?, ?, 'open', ?)"
     )
     .bind(&id)
     .bind(merchant_id)
     .bind(&period_start)
     .bind(&period_end)
-    .execute(pool)
+    .bind(config.fee_currency.as_str())
+    .execute(&mut *tx)
     .await?;
 
     sqlx::query(
@@ -227,71 +443,125 @@ pub async fn ensure_billing_cycle(pool: &SqlitePool, merchant_id: &str, config:
     )
     .bind(&period_start)
     .bind(merchant_id)
-    .execute(pool)
+    .execute(&mut *tx)
     .await?;
+    tx.commit().await?;
 
     tracing::info!(merchant_id, cycle_days, "Billing cycle created");
     Ok(())
 }
 
+/// Create a settlement invoice for `outstanding_zec`, paid to a diversified address of the
+/// operator's fee wallet (`fee_ufvk`). If `cycle_id` names an open or invoiced cycle, it
+/// moves to invoiced, owing `outstanding_zec` by `grace_until` (kept if already set), in the
+/// same transaction.
+///
+/// Settling a cycle is idempotent: while its settlement invoice is unpaid for the same
+/// amount, that invoice is returned. When the amount changed, an invoice nothing was paid
+/// to yet is expired as superseded and a new one created; one already paid into is kept.
+#[allow(clippy::too_many_arguments)]
 pub async fn create_settlement_invoice(
     pool: &SqlitePool,
     merchant_id: &str,
+    cycle_id: Option<&str>,
     outstanding_zec: f64,
-    fee_address: &str,
+    fee_ufvk: &str,
     zec_eur_rate: f64,
     zec_usd_rate: f64,
-) -> anyhow::Result<String> {
-    let id = Uuid::new_v4().to_string();
-    let memo_code = format!("SETTLE-{}", &Uuid::new_v4().to_string()[..8].to_uppercase());
-    let now = Utc::now();
-    let expires_at = (now + Duration::days(7)).format("%Y-%m-%dT%H:%M:%SZ").to_string();
-    let created_at = now.format("%Y-%m-%dT%H:%M:%SZ").to_string();
-
-    let price_eur = outstanding_zec * zec_eur_rate;
-    let price_usd = outstanding_zec * zec_usd_rate;
-    let price_zatoshis = (outstanding_zec * 100_000_000.0) as i64;
-
-    let memo_b64 = base64::Engine::encode(
-        &base64::engine::general_purpose::URL_SAFE_NO_PAD,
-        memo_code.as_bytes(),
-    );
-    let zcash_uri = format!(
-        "zcash:{}?amount={:.8}&memo={}",
-        fee_address, outstanding_zec, memo_b64
-    );
+    grace_until: &str,
+) -> Result<String, BillingError> {
+    let expires_at = (Utc::now() + Duration::days(7)).format("%Y-%m-%dT%H:%M:%SZ").to_string();
 
-    sqlx::query(
-        "INSERT INTO invoices (id, merchant_id, memo_code, product_name, price_eur, price_usd, currency, price_zec,
-         zec_rate_at_creation, payment_address, zcash_uri, status, expires_at, created_at, price_zatoshis)
-         VALUES (?, ?, ?, 'Fee Settlement', ?, ?, 'EUR', ?, ?, ?, ?, 'pending', ?, ?, ?)"
+    let mut tx = crate::db::begin_write(pool).await?;
+    if let Some(cycle_id) = cycle_id {
+        let open: Option<(String, String, i64, i64)> = sqlx::query_as(
+            "SELECT id, status, price_zatoshis, received_zatoshis FROM invoices
+             WHERE billing_cycle_id = ? AND status IN ('pending', 'underpaid', 'detected')"
+        )
+        .bind(cycle_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some((existing_id, status, price_zatoshis, received_zatoshis)) = open {
+            let outstanding_zatoshis = zec_to_zatoshis(outstanding_zec);
+            if price_zatoshis == outstanding_zatoshis || status != "pending" || received_zatoshis > 0 {
+                tracing::info!(merchant_id, cycle_id, invoice_id = %existing_id, "Settlement invoice already open");
+                return Ok(existing_id);
+            }
+            Transition::new(InvoiceState::Expired, "superseded")
+                .only_from(&[InvoiceState::Pending])
+                .detail(serde_json::json!({ "outstanding_zatoshis": outstanding_zatoshis }))
+                .apply_in(&mut tx, &existing_id)
+                .await?;
+            tracing::info!(merchant_id, cycle_id, invoice_id = %existing_id, "Superseded settlement invoice expired");
+        }
+    }
+
+    let invoice = crate::invoices::create_settlement_invoice(
+        &mut tx, merchant_id, cycle_id, fee_ufvk, outstanding_zec, zec_eur_rate, zec_usd_rate, &expires_at,
     )
-    .bind(&id)
-    .bind(merchant_id)
-    .bind(&memo_code)
-    .bind(price_eur)
-    .bind(price_usd)
-    .bind(outstanding_zec)
-    .bind(zec_eur_rate)
-    .bind(fee_address)
-    .bind(&zcash_uri)
-    .bind(&expires_at)
-    .bind(&created_at)
-    .bind(price_zatoshis)
-    .execute(pool)
     .await?;
+    let id = invoice.invoice_id;
 
-    tracing::info!(merchant_id, outstanding_zec, price_eur, invoice_id = %id, "Settlement invoice created");
+    if let Some(cycle_id) = cycle_id {
+        sqlx::query(
+            "UPDATE billing_cycles SET status = 'invoiced', settlement_invoice_id = ?,
+             grace_until = COALESCE(grace_until, ?), outstanding_zec = ?
+             WHERE id = ? AND status IN ('open', 'invoiced')"
+        )
+        .bind(&id)
+        .bind(grace_until)
+        .bind(outstanding_zec)
+        .bind(cycle_id)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+
+    tracing::info!(merchant_id, outstanding_zec, price_eur = invoice.price_eur, invoice_id = %id, "Settlement invoice created");
     Ok(id)
 }
 
+fn suspend_days_for(trust_tier: &str) -> i64 {
+    match trust_tier {
+        "new" => 7,
+        "trusted" => 30,
+        _ => 14,
+    }
+}
+
+/// Recovery email of the merchant, when SMTP is set up to reach it.
+async fn billing_email(pool: &SqlitePool, config: &Config, merchant_id: &str) -> Option<String> {
+    if !config.smtp_configured() {
+        return None;
+    }
+    sqlx::query_scalar::<_, Option<String>>("SELECT recovery_email FROM merchants WHERE id = ?")
+        .bind(merchant_id)
+        .fetch_optional(pool)
+        .await
+        .ok()
+        .flatten()
+        .flatten()
+}
+
+fn spawn_dunning_email(pool: &SqlitePool, config: &Config, to: String, stage: &'static str, outstanding_zec: f64, suspend_date: Option<String>) {
+    let pool = pool.clone();
+    let config = config.clone();
+    tokio::spawn(async move {
+        if let Err(e) = crate::email::send_dunning_email(
+            &pool, &config, &to, stage, outstanding_zec, suspend_date.as_deref(),
+        ).await {
+            tracing::error!(error = %e, stage, "Failed to send dunning email");
+        }
+    });
+}
+
 /// Runs billing cycle processing: close expired cycles, enforce, upgrade tiers.
 pub async fn process_billing_cycles(
     pool: &SqlitePool,
     config: &Config,
     zec_eur: f64,
     zec_usd: f64,
-) -> anyhow::Result<()> {
+) -> Result<(), BillingError> {
     if !config.fee_enabled() {
         return Ok(());
     }
@@ -307,13 +577,17 @@ pub async fn process_billing_cycles(
     .await?;
 
     for cycle in &expired_cycles {
-        if cycle.outstanding_zec <= 0.0001 {
+        let Some(outstanding_zec) = cycle.settlement_zec(zec_eur, zec_usd) else {
+            tracing::warn!(cycle_id = %cycle.id, currency = %cycle.fee_currency, "No rate to convert fees, cycle stays open");
+            continue;
+        };
+        if outstanding_zec <= 0.0001 {
             sqlx::query("UPDATE billing_cycles SET status = 'paid' WHERE id = ?")
                 .bind(&cycle.id)
                 .execute(pool)
                 .await?;
             tracing::info!(merchant_id = %cycle.merchant_id, "Billing cycle closed (fully collected)");
-        } else if let Some(fee_addr) = &config.fee_address {
+        } else if let Some(fee_ufvk) = &config.fee_ufvk {
             let grace_days: i64 = match get_trust_tier(pool, &cycle.merchant_id).await?.as_str() {
                 "new" => 3,
                 "trusted" => 14,
@@ -323,27 +597,38 @@ pub async fn process_billing_cycles(
                 .format("%Y-%m-%dT%H:%M:%SZ").to_string();
 
             let settlement_id = create_settlement_invoice(
-                pool, &cycle.merchant_id, cycle.outstanding_zec, fee_addr, zec_eur, zec_usd,
+                pool, &cycle.merchant_id, Some(&cycle.id), outstanding_zec, fee_ufvk,
+                zec_eur, zec_usd, &grace_until,
             ).await?;
 
-            sqlx::query(
-                "UPDATE billing_cycles SET status = 'invoiced', settlement_invoice_id = ?, grace_until = ?
-                 WHERE id = ?"
-            )
-            .bind(&settlement_id)
-            .bind(&grace_until)
-            .bind(&cycle.id)
-            .execute(pool)
-            .await?;
-
             tracing::info!(
                 merchant_id = %cycle.merchant_id,
-                outstanding = cycle.outstanding_zec,
+                outstanding = outstanding_zec,
                 grace_until = %grace_until,
                 "Settlement invoice generated"
             );
+
+            crate::notifiers::billing_event(pool, &settlement_id, &format!(
+                "CipherPay fees for this period: {:.8} ZEC. Pay the settlement invoice by {} to avoid interruption.",
+                outstanding_zec, &grace_until[..10],
+            )).await;
+
+            if let Some(to) = billing_email(pool, config, &cycle.merchant_id).await {
+                let pool = pool.clone();
+                let config = config.clone();
+                let outstanding = outstanding_zec;
+                let due_date = grace_until[..10].to_string();
+                tokio::spawn(async move {
+                    if let Err(e) = crate::email::send_billing_notice(
+                        &pool, &config, &to, outstanding, outstanding * zec_eur, &due_date, &settlement_id,
+                    ).await {
+                        tracing::error!(error = %e, "Failed to send billing notice");
+                    }
+                });
+            }
         }
 
+        record_close(pool, cycle, zec_eur, zec_usd).await?;
         ensure_billing_cycle(pool, &cycle.merchant_id, config).await?;
     }
 
@@ -356,15 +641,23 @@ pub async fn process_billing_cycles(
     .await?;
 
     for cycle in &overdue_cycles {
-        sqlx::query("UPDATE billing_cycles SET status = 'past_due' WHERE id = ?")
-            .bind(&cycle.id)
-            .execute(pool)
-            .await?;
-        sqlx::query("UPDATE merchants SET billing_status = 'past_due' WHERE id = ?")
-            .bind(&cycle.merchant_id)
-            .execute(pool)
-            .await?;
+        set_cycle_status(pool, cycle, "past_due").await?;
         tracing::warn!(merchant_id = %cycle.merchant_id, "Merchant billing past due");
+
+        if let Some(ref settlement_id) = cycle.settlement_invoice_id {
+            crate::notifiers::billing_event(pool, settlement_id, &format!(
+                "CipherPay fees of {:.8} ZEC are past due. Pay the settlement invoice to keep accepting payments.",
+                cycle.outstanding_zec,
+            )).await;
+        }
+
+        if let Some(to) = billing_email(pool, config, &cycle.merchant_id).await {
+            let suspend_days = suspend_days_for(&get_trust_tier(pool, &cycle.merchant_id).await?);
+            let suspend_date = cycle.grace_until.as_deref()
+                .and_then(|g| chrono::NaiveDateTime::parse_from_str(g, "%Y-%m-%dT%H:%M:%SZ").ok())
+                .map(|g| (g + Duration::days(suspend_days)).format("%Y-%m-%d").to_string());
+            spawn_dunning_email(pool, config, to, "past_due", cycle.outstanding_zec, suspend_date);
+        }
     }
 
     // 3. Enforce suspension (7 days after past_due for new, 14 for standard/trusted)
@@ -375,25 +668,25 @@ pub async fn process_billing_cycles(
     .await?;
 
     for cycle in &past_due_cycles {
-        let suspend_days: i64 = match get_trust_tier(pool, &cycle.merchant_id).await?.as_str() {
-            "new" => 7,
-            "trusted" => 30,
-            _ => 14,
-        };
+        let suspend_days = suspend_days_for(&get_trust_tier(pool, &cycle.merchant_id).await?);
 
         if let Some(grace_until) = &cycle.grace_until {
             if let Ok(grace_dt) = chrono::NaiveDateTime::parse_from_str(grace_until, "%Y-%m-%dT%H:%M:%SZ") {
                 let suspend_at = grace_dt + Duration::days(suspend_days);
                 if Utc::now().naive_utc() > suspend_at {
-                    sqlx::query("UPDATE billing_cycles SET status = 'suspended' WHERE id = ?")
-                        .bind(&cycle.id)
-                        .execute(pool)
-                        .await?;
-                    sqlx::query("UPDATE merchants SET billing_status = 'suspended' WHERE id = ?")
-                        .bind(&cycle.merchant_id)
-                        .execute(pool)
-                        .await?;
+                    set_cycle_status(pool, cycle, "suspended").await?;
                     tracing::warn!(merchant_id = %cycle.merchant_id, "Merchant suspended for non-payment");
+
+                    if let Some(ref settlement_id) = cycle.settlement_invoice_id {
+                        crate::notifiers::billing_event(pool, settlement_id, &format!(
+                            "Your CipherPay account is suspended for {:.8} ZEC in unpaid fees. Pay the settlement invoice to restore it.",
+                            cycle.outstanding_zec,
+                        )).await;
+                    }
+
+                    if let Some(to) = billing_email(pool, config, &cycle.merchant_id).await {
+                        spawn_dunning_email(pool, config, to, "suspended", cycle.outstanding_zec, None);
+                    }
                 }
             }
         }
@@ -401,7 +694,8 @@ pub async fn process_billing_cycles(
 
     // 4. Upgrade trust tiers: 3+ consecutive paid on time
     let merchants_for_upgrade: Vec<(String, String)> = sqlx::query_as(
-        "SELECT id, COALESCE(trust_tier, 'new') FROM merchants WHERE trust_tier != 'trusted'"
+        "SELECT id, COALESCE(trust_tier, 'new') FROM merchants
+         WHERE trust_tier != 'trusted' AND deleted_at IS NULL"
     )
     .fetch_all(pool)
     .await?;
@@ -445,8 +739,123 @@ pub async fn process_billing_cycles(
     Ok(())
 }
 
+/// Move a cycle and its merchant's billing status to `status` together.
+async fn set_cycle_status(pool: &SqlitePool, cycle: &BillingCycle, status: &str) -> Result<(), BillingError> {
+    let mut tx = crate::db::begin_write(pool).await?;
+    sqlx::query("UPDATE billing_cycles SET status = ? WHERE id = ?")
+        .bind(status)
+        .bind(&cycle.id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("UPDATE merchants SET billing_status = ? WHERE id = ?")
+        .bind(status)
+        .bind(&cycle.merchant_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Stamp a closed cycle with the rates it closed at and its fees valued in fiat.
+async fn record_close(pool: &SqlitePool, cycle: &BillingCycle, zec_eur: f64, zec_usd: f64) -> Result<(), BillingError> {
+    let (total_eur, total_usd) = cycle.fiat_totals(zec_eur, zec_usd);
+    sqlx::query(
+        "UPDATE billing_cycles SET closed_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now'),
+         close_zec_eur = ?, close_zec_usd = ?, total_fees_eur = ?, total_fees_usd = ?
+         WHERE id = ?"
+    )
+    .bind((zec_eur > 0.0).then_some(zec_eur))
+    .bind((zec_usd > 0.0).then_some(zec_usd))
+    .bind(total_eur)
+    .bind(total_usd)
+    .bind(&cycle.id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// A cycle's recorded totals next to the ones its fee ledger entries add up to.
+#[derive(sqlx::FromRow)]
+struct CycleTotals {
+    id: String,
+    merchant_id: String,
+    total_fees_zec: f64,
+    auto_collected_zec: f64,
+    total_fees_fiat: f64,
+    auto_collected_fiat: f64,
+    ledger_total_zec: f64,
+    ledger_collected_zec: f64,
+    ledger_total_fiat: f64,
+    ledger_collected_fiat: f64,
+}
+
+impl CycleTotals {
+    fn drifted(&self) -> bool {
+        [
+            (self.total_fees_zec, self.ledger_total_zec),
+            (self.auto_collected_zec, self.ledger_collected_zec),
+            (self.total_fees_fiat, self.ledger_total_fiat),
+            (self.auto_collected_fiat, self.ledger_collected_fiat),
+        ]
+        .iter()
+        .any(|(recorded, ledger)| (recorded - ledger).abs() > 0.00000001)
+    }
+}
+
+/// Recompute unpaid cycles' totals from the fee ledger, repairing drift left by a crash or
+/// an update applied outside a transaction. Outstanding balances are only recomputed for
+/// open cycles; an invoiced cycle owes what its settlement invoice asks for.
+/// Returns the number of cycles corrected.
+pub async fn reconcile_cycles(pool: &SqlitePool) -> Result<u64, BillingError> {
+    let mut tx = crate::db::begin_write(pool).await?;
+    let cycles: Vec<CycleTotals> = sqlx::query_as(
+        "SELECT bc.id, bc.merchant_id, bc.total_fees_zec, bc.auto_collected_zec,
+                bc.total_fees_fiat, bc.auto_collected_fiat,
+                COALESCE(SUM(fl.fee_amount_zec), 0.0) AS ledger_total_zec,
+                COALESCE(SUM(CASE WHEN fl.auto_collected = 1 THEN fl.fee_amount_zec END), 0.0) AS ledger_collected_zec,
+                COALESCE(SUM(CASE WHEN fl.fee_currency = bc.fee_currency THEN fl.fee_amount_fiat END), 0.0) AS ledger_total_fiat,
+                COALESCE(SUM(CASE WHEN fl.fee_currency = bc.fee_currency AND fl.auto_collected = 1
+                                  THEN fl.fee_amount_fiat END), 0.0) AS ledger_collected_fiat
+         FROM billing_cycles bc
+         LEFT JOIN fee_ledger fl ON fl.billing_cycle_id = bc.id
+         WHERE bc.status IN ('open', 'invoiced', 'past_due', 'suspended')
+         GROUP BY bc.id"
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let mut corrected = 0;
+    for c in cycles.iter().filter(|c| c.drifted()) {
+        tracing::warn!(
+            cycle_id = %c.id, merchant_id = %c.merchant_id,
+            total_zec = c.total_fees_zec, ledger_total_zec = c.ledger_total_zec,
+            total_fiat = c.total_fees_fiat, ledger_total_fiat = c.ledger_total_fiat,
+            "Billing cycle out of sync with fee ledger, correcting"
+        );
+        sqlx::query(
+            "UPDATE billing_cycles SET
+                total_fees_zec = ?1, auto_collected_zec = ?2,
+                total_fees_fiat = ?3, auto_collected_fiat = ?4,
+                outstanding_zec = CASE WHEN status = 'open' THEN MAX(0, ?1 - ?2) ELSE outstanding_zec END,
+                outstanding_fiat = CASE WHEN status = 'open' THEN MAX(0, ?3 - ?4) ELSE outstanding_fiat END
+             WHERE id = ?5"
+        )
+        .bind(c.ledger_total_zec)
+        .bind(c.ledger_collected_zec)
+        .bind(c.ledger_total_fiat)
+        .bind(c.ledger_collected_fiat)
+        .bind(&c.id)
+        .execute(&mut *tx)
+        .await?;
+        corrected += 1;
+    }
+    tx.commit().await?;
+
+    Ok(corrected)
+}
+
 /// Check if a settlement invoice was paid and restore merchant access.
-pub async fn check_settlement_payments(pool: &SqlitePool) -> anyhow::Result<()> {
+pub async fn check_settlement_payments(pool: &SqlitePool) -> Result<(), BillingError> {
     let settled = sqlx::query_as::<_, BillingCycle>(
         "SELECT bc.* FROM billing_cycles bc
          JOIN invoices i ON i.id = bc.settlement_invoice_id
@@ -457,21 +866,23 @@ pub async fn check_settlement_payments(pool: &SqlitePool) -> anyhow::Result<()>
     .await?;
 
     for cycle in &settled {
-        sqlx::query("UPDATE billing_cycles SET status = 'paid', outstanding_zec = 0.0 WHERE id = ?")
+        let mut tx = crate::db::begin_write(pool).await?;
+        sqlx::query("UPDATE billing_cycles SET status = 'paid', outstanding_zec = 0.0, outstanding_fiat = 0.0 WHERE id = ?")
             .bind(&cycle.id)
-            .execute(pool)
+            .execute(&mut *tx)
             .await?;
         sqlx::query("UPDATE merchants SET billing_status = 'active' WHERE id = ?")
             .bind(&cycle.merchant_id)
-            .execute(pool)
+            .execute(&mut *tx)
             .await?;
+        tx.commit().await?;
         tracing::info!(merchant_id = %cycle.merchant_id, "Settlement paid, merchant restored");
     }
 
     Ok(())
 }
 
-async fn get_trust_tier(pool: &SqlitePool, merchant_id: &str) -> anyhow::Result<String> {
+async fn get_trust_tier(pool: &SqlitePool, merchant_id: &str) -> Result<String, BillingError> {
     let tier: String = sqlx::query_scalar(
         "SELECT COALESCE(trust_tier, 'new') FROM merchants WHERE id = ?"
     )
@@ -481,7 +892,7 @@ async fn get_trust_tier(pool: &SqlitePool, merchant_id: &str) -> anyhow::Result<
     Ok(tier)
 }
 
-pub async fn get_merchant_billing_status(pool: &SqlitePool, merchant_id: &str) -> anyhow::Result<String> {
+pub async fn get_merchant_billing_status(pool: &SqlitePool, merchant_id: &str) -> Result<String, BillingError> {
     let status: String = sqlx::query_scalar(
         "SELECT COALESCE(billing_status, 'active') FROM merchants WHERE id = ?"
     )
@@ -490,3 +901,302 @@ pub async fn get_merchant_billing_status(pool: &SqlitePool, merchant_id: &str) -
     .await?;
     Ok(status)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::BillingCycle;
+
+    fn cycle(fee_currency: &str, total_fees_zec: f64, total_fees_fiat: f64) -> BillingCycle {
+        BillingCycle {
+            id: "c".into(),
+            merchant_id: "m".into(),
+            period_start: String::new(),
+            period_end: String::new(),
+            total_fees_zec,
+            auto_collected_zec: 0.0,
+            outstanding_zec: total_fees_zec,
+            settlement_invoice_id: None,
+            status: "open".into(),
+            grace_until: None,
+            created_at: String::new(),
+            fee_currency: fee_currency.into(),
+            total_fees_fiat,
+            auto_collected_fiat: 0.0,
+            outstanding_fiat: total_fees_fiat,
+            closed_at: None,
+            close_zec_eur: None,
+            close_zec_usd: None,
+            total_fees_eur: None,
+            total_fees_usd: None,
+        }
+    }
+
+    #[test]
+    fn test_fiat_totals() {
+        assert_eq!(cycle("ZEC", 0.5, 0.0).fiat_totals(40.0, 44.0), (Some(20.0), Some(22.0)));
+        assert_eq!(cycle("EUR", 0.4, 20.0).fiat_totals(40.0, 44.0), (Some(20.0), Some(22.0)));
+        assert_eq!(cycle("EUR", 0.4, 20.0).fiat_totals(0.0, 44.0), (Some(20.0), None));
+        assert_eq!(cycle("ZEC", 0.5, 0.0).fiat_totals(0.0, 0.0), (None, None));
+        assert_eq!(cycle("EUR", 0.5, 20.0).settlement_zec(40.0, 44.0), Some(0.5));
+        assert_eq!(cycle("USD", 0.5, 22.0).settlement_zec(40.0, 0.0), None);
+    }
+
+    #[tokio::test]
+    async fn test_settlement_invoice_uses_fee_wallet_addresses() {
+        use crate::merchants::{create_merchant, CreateMerchantRequest};
+
+        let pool = crate::db::test_pool().await;
+        let merchant = create_merchant(&pool, &CreateMerchantRequest {
+            name: None,
+            ufvk: crate::scanner::fixtures::test_ufvk(1),
+            webhook_url: None,
+            email: None,
+            verify_blocks: None,
+        }, "").await.unwrap();
+        let fee_ufvk = crate::scanner::fixtures::test_ufvk(9);
+
+        let first = super::create_settlement_invoice(
+            &pool, &merchant.merchant_id, None, 0.5, &fee_ufvk, 40.0, 44.0, "2030-01-01T00:00:00Z",
+        ).await.unwrap();
+        let second = super::create_settlement_invoice(
+            &pool, &merchant.merchant_id, None, 0.25, &fee_ufvk, 0.0, 0.0, "2030-01-01T00:00:00Z",
+        ).await.unwrap();
+
+        let first = crate::invoices::get_invoice(&pool, &first).await.unwrap().unwrap();
+        let second = crate::invoices::get_invoice(&pool, &second).await.unwrap().unwrap();
+        assert!(first.is_settlement() && second.is_settlement());
+        assert_eq!(first.merchant_id, merchant.merchant_id);
+        assert_eq!(first.price_zatoshis, 50_000_000);
+        assert_eq!(first.diversifier_index, Some(1));
+        assert_eq!(second.diversifier_index, Some(2));
+        let derived = crate::addresses::derive_invoice_address(&fee_ufvk, 1).unwrap();
+        assert_eq!(first.payment_address, derived.ua_string);
+        assert_eq!(first.orchard_receiver_hex.as_deref(), Some(derived.orchard_receiver_hex.as_str()));
+        assert!(!first.zcash_uri.contains("address.1"), "settlements carry no fee output");
+        assert_eq!(crate::invoices::events::list(&pool, &first.id).await.unwrap()[0].event_type, "created");
+    }
+
+    #[tokio::test]
+    async fn test_settling_a_cycle_is_idempotent() {
+        use crate::merchants::{create_merchant, CreateMerchantRequest};
+
+        let pool = crate::db::test_pool().await;
+        let merchant = create_merchant(&pool, &CreateMerchantRequest {
+            name: None,
+            ufvk: crate::scanner::fixtures::test_ufvk(1),
+            webhook_url: None,
+            email: None,
+            verify_blocks: None,
+        }, "").await.unwrap();
+        sqlx::query("INSERT INTO billing_cycles (id, merchant_id, period_start, period_end) VALUES ('c-1', ?, '2030-01-01', '2030-02-01')")
+            .bind(&merchant.merchant_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let fee_ufvk = crate::scanner::fixtures::test_ufvk(9);
+        let settle = |zec: f64, grace: &'static str| {
+            let (pool, merchant_id, fee_ufvk) = (pool.clone(), merchant.merchant_id.clone(), fee_ufvk.clone());
+            async move {
+                super::create_settlement_invoice(&pool, &merchant_id, Some("c-1"), zec, &fee_ufvk, 40.0, 44.0, grace)
+                    .await
+                    .unwrap()
+            }
+        };
+        let cycle = |pool: sqlx::SqlitePool| async move {
+            sqlx::query_as::<_, (String, Option<String>, Option<String>)>(
+                "SELECT status, settlement_invoice_id, grace_until FROM billing_cycles WHERE id = 'c-1'"
+            )
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+        };
+
+        let first = settle(0.5, "2030-03-01T00:00:00Z").await;
+        assert_eq!(settle(0.5, "2030-03-09T00:00:00Z").await, first);
+        assert_eq!(cycle(pool.clone()).await, ("invoiced".into(), Some(first.clone()), Some("2030-03-01T00:00:00Z".into())));
+
+        // The amount changed: the unpaid invoice is superseded, the grace period is not reset.
+        let second = settle(0.6, "2030-03-09T00:00:00Z").await;
+        assert_ne!(second, first);
+        let first_invoice = crate::invoices::get_invoice(&pool, &first).await.unwrap().unwrap();
+        assert_eq!(first_invoice.status, "expired");
+        assert_eq!(crate::invoices::events::list(&pool, &first).await.unwrap().last().unwrap().event_type, "superseded");
+        assert_eq!(cycle(pool.clone()).await, ("invoiced".into(), Some(second.clone()), Some("2030-03-01T00:00:00Z".into())));
+
+        // Once something was paid to it, the open invoice stands.
+        sqlx::query("UPDATE invoices SET status = 'underpaid', received_zatoshis = 1000 WHERE id = ?")
+            .bind(&second)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(settle(0.7, "2030-03-09T00:00:00Z").await, second);
+
+        let duplicate = sqlx::query("UPDATE invoices SET status = 'pending' WHERE id = ?")
+            .bind(&first)
+            .execute(&pool)
+            .await;
+        assert!(duplicate.is_err(), "a cycle has one open settlement invoice");
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_follows_the_cycle_through_overdue_and_paid() {
+        use crate::config::FeeCurrency;
+
+        let pool = crate::db::test_pool().await;
+        let merchant_id = crate::db::test_merchant(&pool, 1).await.merchant_id;
+        sqlx::query("INSERT INTO billing_cycles (id, merchant_id, period_start, period_end, fee_currency) VALUES ('c-1', ?, '2030-01-01', '2030-02-01', 'EUR')")
+            .bind(&merchant_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        for n in 1..=2 {
+            let invoice_id = crate::db::test_invoice(&pool, &merchant_id, n).await.invoice_id;
+            super::create_fee_entry(&pool, &invoice_id, &merchant_id, 0.01, FeeCurrency::Eur, Some(0.4)).await.unwrap();
+            if n == 1 {
+                super::mark_fee_collected(&pool, &invoice_id).await.unwrap();
+            }
+        }
+        let totals = |pool: sqlx::SqlitePool| async move {
+            sqlx::query_as::<_, (String, f64, f64, f64, f64)>(
+                "SELECT status, total_fees_zec, auto_collected_zec, outstanding_zec, total_fees_fiat FROM billing_cycles WHERE id = 'c-1'"
+            )
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+        };
+        let drift = |pool: sqlx::SqlitePool| async move {
+            sqlx::query("UPDATE billing_cycles SET total_fees_zec = 0.5, auto_collected_zec = 0, outstanding_zec = 0.25, total_fees_fiat = 0 WHERE id = 'c-1'")
+                .execute(&pool)
+                .await
+                .unwrap();
+        };
+
+        // In sync: nothing to correct.
+        assert_eq!(super::reconcile_cycles(&pool).await.unwrap(), 0);
+        assert_eq!(totals(pool.clone()).await, ("open".into(), 0.02, 0.01, 0.01, 0.8));
+
+        // Open: totals and the outstanding balance come back from the ledger.
+        drift(pool.clone()).await;
+        assert_eq!(super::reconcile_cycles(&pool).await.unwrap(), 1);
+        assert_eq!(totals(pool.clone()).await, ("open".into(), 0.02, 0.01, 0.01, 0.8));
+        assert_eq!(super::reconcile_cycles(&pool).await.unwrap(), 0);
+
+        // Invoiced then overdue: totals are repaired, but the cycle still owes what its
+        // settlement invoice asks for.
+        let fee_ufvk = crate::scanner::fixtures::test_ufvk(9);
+        let settlement_id = super::create_settlement_invoice(
+            &pool, &merchant_id, Some("c-1"), 0.01, &fee_ufvk, 40.0, 44.0, "2000-01-01T00:00:00Z",
+        ).await.unwrap();
+        let cycle = sqlx::query_as::<_, BillingCycle>("SELECT * FROM billing_cycles WHERE id = 'c-1'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        super::set_cycle_status(&pool, &cycle, "past_due").await.unwrap();
+        assert_eq!(super::get_merchant_billing_status(&pool, &merchant_id).await.unwrap(), "past_due");
+        drift(pool.clone()).await;
+        assert_eq!(super::reconcile_cycles(&pool).await.unwrap(), 1);
+        assert_eq!(totals(pool.clone()).await, ("past_due".into(), 0.02, 0.01, 0.25, 0.8));
+
+        // Paid: the merchant is restored and the settled cycle is left alone.
+        sqlx::query("UPDATE invoices SET status = 'confirmed' WHERE id = ?")
+            .bind(&settlement_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        super::check_settlement_payments(&pool).await.unwrap();
+        assert_eq!(super::get_merchant_billing_status(&pool, &merchant_id).await.unwrap(), "active");
+        drift(pool.clone()).await;
+        assert_eq!(super::reconcile_cycles(&pool).await.unwrap(), 0);
+        assert_eq!(totals(pool.clone()).await.0, "paid");
+    }
+
+    #[tokio::test]
+    async fn test_fee_waivers_run_out() {
+        use super::{billable_share, update_fee_promo, FeePromoUpdate, FEES_WAIVED_SQL};
+
+        let pool = crate::db::test_pool().await;
+        let merchant_id = crate::db::test_merchant(&pool, 1).await.merchant_id;
+        let waived_in_sql = |pool: sqlx::SqlitePool, merchant_id: String| async move {
+            sqlx::query_scalar::<_, bool>(&format!("SELECT {} FROM merchants m WHERE m.id = ?", FEES_WAIVED_SQL))
+                .bind(merchant_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+        };
+        assert_eq!(billable_share(&pool, &merchant_id, 25_000_000).await.unwrap(), 1.0);
+
+        // Fee-free volume is drawn down invoice by invoice until it is gone.
+        let promo = update_fee_promo(&pool, &merchant_id, &FeePromoUpdate { fee_free_zec: Some(0.3), ..Default::default() }).await.unwrap();
+        assert_eq!(promo.fee_free_zatoshis, 30_000_000);
+        assert!(!waived_in_sql(pool.clone(), merchant_id.clone()).await);
+        assert_eq!(billable_share(&pool, &merchant_id, 25_000_000).await.unwrap(), 0.0);
+        assert_eq!(billable_share(&pool, &merchant_id, 25_000_000).await.unwrap(), 0.8);
+        assert_eq!(billable_share(&pool, &merchant_id, 25_000_000).await.unwrap(), 1.0);
+        assert_eq!(super::get_fee_promo(&pool, &merchant_id).await.unwrap().fee_free_zatoshis, 0);
+
+        // A fee-free period waives everything until it ends; 0 days ends it now.
+        update_fee_promo(&pool, &merchant_id, &FeePromoUpdate { fee_free_days: Some(2), ..Default::default() }).await.unwrap();
+        assert!(waived_in_sql(pool.clone(), merchant_id.clone()).await);
+        assert_eq!(billable_share(&pool, &merchant_id, 25_000_000).await.unwrap(), 0.0);
+        update_fee_promo(&pool, &merchant_id, &FeePromoUpdate { fee_free_days: Some(0), ..Default::default() }).await.unwrap();
+        assert!(!waived_in_sql(pool.clone(), merchant_id.clone()).await);
+        assert_eq!(billable_share(&pool, &merchant_id, 25_000_000).await.unwrap(), 1.0);
+
+        // Exemption waives everything and leaves remaining fee-free volume untouched.
+        update_fee_promo(&pool, &merchant_id, &FeePromoUpdate { fee_exempt: Some(true), fee_free_zec: Some(0.1), ..Default::default() }).await.unwrap();
+        assert!(waived_in_sql(pool.clone(), merchant_id.clone()).await);
+        assert_eq!(billable_share(&pool, &merchant_id, 25_000_000).await.unwrap(), 0.0);
+        let promo = update_fee_promo(&pool, &merchant_id, &FeePromoUpdate { fee_exempt: Some(false), ..Default::default() }).await.unwrap();
+        assert_eq!((promo.fee_exempt, promo.fee_free_zatoshis), (false, 10_000_000));
+        assert_eq!(billable_share(&pool, &merchant_id, 25_000_000).await.unwrap(), 0.6);
+    }
+
+    #[tokio::test]
+    async fn test_fiat_cycle_accrues_in_its_currency_and_converts_at_settlement() {
+        use crate::config::FeeCurrency;
+
+        let pool = crate::db::test_pool().await;
+        let merchant_id = crate::db::test_merchant(&pool, 1).await.merchant_id;
+        sqlx::query("INSERT INTO billing_cycles (id, merchant_id, period_start, period_end, fee_currency) VALUES ('c-1', ?, '2030-01-01', '2030-02-01', 'EUR')")
+            .bind(&merchant_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let load = |pool: sqlx::SqlitePool| async move {
+            sqlx::query_as::<_, BillingCycle>("SELECT * FROM billing_cycles WHERE id = 'c-1'")
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+        };
+
+        // 1% of two 10 EUR invoices at 40 EUR/ZEC, one auto-collected through its fee output.
+        // An entry accrued in another currency adds to the ZEC totals only.
+        let mut invoices = Vec::new();
+        for n in 1..=3 {
+            invoices.push(crate::db::test_invoice(&pool, &merchant_id, n).await.invoice_id);
+        }
+        super::create_fee_entry(&pool, &invoices[0], &merchant_id, 0.0025, FeeCurrency::Eur, Some(0.1)).await.unwrap();
+        super::create_fee_entry(&pool, &invoices[1], &merchant_id, 0.0025, FeeCurrency::Eur, Some(0.1)).await.unwrap();
+        super::create_fee_entry(&pool, &invoices[2], &merchant_id, 0.0025, FeeCurrency::Usd, Some(0.11)).await.unwrap();
+        super::mark_fee_collected(&pool, &invoices[0]).await.unwrap();
+
+        let cycle = load(pool.clone()).await;
+        assert_eq!((cycle.total_fees_fiat, cycle.auto_collected_fiat, cycle.outstanding_fiat), (0.2, 0.1, 0.1));
+        assert!((cycle.outstanding_zec - 0.005).abs() < 1e-12);
+
+        // The ZEC price halved since: the EUR balance now takes twice the ZEC to settle,
+        // and no USD rate is needed for an EUR cycle.
+        assert_eq!(cycle.settlement_zec(20.0, 0.0), Some(0.005));
+        assert_eq!(cycle.settlement_zec(0.0, 22.0), None);
+
+        // Invoicing fixes the amount at that rate.
+        let fee_ufvk = crate::scanner::fixtures::test_ufvk(9);
+        let settlement = super::create_settlement_invoice(
+            &pool, &merchant_id, Some("c-1"), 0.005, &fee_ufvk, 20.0, 22.0, "2030-03-01T00:00:00Z",
+        ).await.unwrap();
+        assert_eq!(crate::invoices::get_invoice(&pool, &settlement).await.unwrap().unwrap().price_zatoshis, 500_000);
+        let cycle = load(pool.clone()).await;
+        assert_eq!(cycle.status, "invoiced");
+        assert_eq!(cycle.settlement_zec(80.0, 88.0), Some(0.005));
+    }
+}
@@ -0,0 +1,263 @@
+//! Operator economics: what the fee system accrued, collected and is still owed, read from
+//! billing cycles, the fee ledger and settlement invoices. Everything here is read-only.
+
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use super::BillingError;
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct PeriodRevenue {
+    /// `YYYY-MM` of the cycles' start.
+    pub period: String,
+    pub cycles: i64,
+    pub cycles_paid: i64,
+    pub total_fees_zec: f64,
+    pub auto_collected_zec: f64,
+    pub outstanding_zec: f64,
+    /// Sum over closed cycles, valued at the rates each closed at.
+    pub total_fees_eur: f64,
+    pub total_fees_usd: f64,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct AgingBucket {
+    pub bucket: String,
+    pub invoices: i64,
+    pub outstanding_zec: f64,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct StatusCount {
+    pub billing_status: String,
+    pub merchants: i64,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct WalletFormat {
+    pub wallet: String,
+    pub uri_format: String,
+    pub invoices: i64,
+    /// Of those, paid with the fee output included.
+    pub fee_outputs: i64,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct MerchantVolume {
+    pub merchant_id: String,
+    pub name: String,
+    pub billing_status: String,
+    pub invoices: i64,
+    pub volume_zatoshis: i64,
+    pub fees_zec: f64,
+}
+
+/// Fees per calendar month of cycle start, newest first.
+pub async fn revenue_by_period(pool: &SqlitePool, months: i64) -> Result<Vec<PeriodRevenue>, BillingError> {
+    let rows = sqlx::query_as(
+        "SELECT strftime('%Y-%m', period_start) AS period,
+                COUNT(*) AS cycles,
+                COUNT(CASE WHEN status = 'paid' THEN 1 END) AS cycles_paid,
+                COALESCE(SUM(total_fees_zec), 0.0) AS total_fees_zec,
+                COALESCE(SUM(auto_collected_zec), 0.0) AS auto_collected_zec,
+                COALESCE(SUM(CASE WHEN status != 'paid' THEN outstanding_zec END), 0.0) AS outstanding_zec,
+                COALESCE(SUM(total_fees_eur), 0.0) AS total_fees_eur,
+                COALESCE(SUM(total_fees_usd), 0.0) AS total_fees_usd
+         FROM billing_cycles
+         GROUP BY period
+         ORDER BY period DESC
+         LIMIT ?"
+    )
+    .bind(months)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+/// Unpaid settlement invoices by days since they were issued.
+pub async fn settlement_aging(pool: &SqlitePool) -> Result<Vec<AgingBucket>, BillingError> {
+    let rows = sqlx::query_as(
+        "SELECT CASE
+                    WHEN age < 7 THEN '0-7d'
+                    WHEN age < 30 THEN '7-30d'
+                    WHEN age < 60 THEN '30-60d'
+                    ELSE '60d+'
+                END AS bucket,
+                COUNT(*) AS invoices,
+                COALESCE(SUM(outstanding_zec), 0.0) AS outstanding_zec
+         FROM (
+            SELECT julianday('now') - julianday(i.created_at) AS age, bc.outstanding_zec
+            FROM billing_cycles bc
+            JOIN invoices i ON i.id = bc.settlement_invoice_id
+            WHERE bc.status IN ('invoiced', 'past_due', 'suspended')
+         )
+         GROUP BY bucket
+         ORDER BY MIN(age)"
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+/// Merchants per billing status (active, past_due, suspended).
+pub async fn status_counts(pool: &SqlitePool) -> Result<Vec<StatusCount>, BillingError> {
+    let rows = sqlx::query_as(
+        "SELECT billing_status, COUNT(*) AS merchants FROM merchants
+         WHERE deleted_at IS NULL
+         GROUP BY billing_status ORDER BY billing_status"
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+/// Merchants with the most confirmed volume over the last `days`, settlement invoices excluded.
+pub async fn top_merchants(pool: &SqlitePool, days: i64, limit: i64) -> Result<Vec<MerchantVolume>, BillingError> {
+    let since = format!("-{} days", days);
+    let rows = sqlx::query_as(
+        "SELECT m.id AS merchant_id, m.name, m.billing_status,
+                COUNT(i.id) AS invoices,
+                COALESCE(SUM(i.received_zatoshis), 0) AS volume_zatoshis,
+                COALESCE((SELECT SUM(fl.fee_amount_zec) FROM fee_ledger fl
+                          WHERE fl.merchant_id = m.id
+                          AND fl.created_at >= strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?1)), 0.0) AS fees_zec
+         FROM merchants m
+         JOIN invoices i ON i.merchant_id = m.id
+         WHERE i.status IN ('confirmed', 'paid_late')
+           AND i.memo_code NOT LIKE 'SETTLE-%'
+           AND COALESCE(i.confirmed_at, i.detected_at) >= strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?1)
+         GROUP BY m.id
+         ORDER BY volume_zatoshis DESC
+         LIMIT ?2"
+    )
+    .bind(since)
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+/// Paid invoices that owed a fee, by the wallet and URI format the checkout reported, with
+/// how many carried the fee output. A multi-output row well short of its invoice count
+/// points at a wallet that drops the second output.
+pub async fn wallet_compatibility(pool: &SqlitePool, days: i64) -> Result<Vec<WalletFormat>, BillingError> {
+    let rows = sqlx::query_as(
+        "SELECT COALESCE(i.wallet, 'unknown') AS wallet,
+                COALESCE(i.uri_format, 'multi') AS uri_format,
+                COUNT(*) AS invoices,
+                COUNT(CASE WHEN i.fee_output_seen = 1 THEN 1 END) AS fee_outputs
+         FROM invoices i
+         JOIN fee_ledger fl ON fl.invoice_id = i.id
+         WHERE i.created_at >= strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?)
+         GROUP BY wallet, uri_format
+         ORDER BY invoices DESC"
+    )
+    .bind(format!("-{} days", days))
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Mark an invoice paid in full `days_ago`.
+    async fn confirm(pool: &SqlitePool, invoice_id: &str, days_ago: i64) {
+        sqlx::query(
+            "UPDATE invoices SET status = 'confirmed', received_zatoshis = price_zatoshis,
+             confirmed_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?) WHERE id = ?"
+        )
+        .bind(format!("-{} days", days_ago))
+        .bind(invoice_id)
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    async fn cycle(pool: &SqlitePool, id: &str, merchant_id: &str, period_start: &str, status: &str, outstanding_zec: f64) {
+        sqlx::query(
+            "INSERT INTO billing_cycles (id, merchant_id, period_start, period_end, status, total_fees_zec, outstanding_zec)
+             VALUES (?, ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(id)
+        .bind(merchant_id)
+        .bind(period_start)
+        .bind(period_start)
+        .bind(status)
+        .bind(outstanding_zec)
+        .bind(outstanding_zec)
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_top_merchants_window_and_limit() {
+        let pool = crate::db::test_pool().await;
+        let mut merchants = Vec::new();
+        for n in 1..=3 {
+            merchants.push(crate::db::test_merchant(&pool, n).await.merchant_id);
+        }
+        let (big, small, old) = (&merchants[0], &merchants[1], &merchants[2]);
+        for _ in 0..2 {
+            let id = crate::db::test_invoice(&pool, big, 1).await.invoice_id;
+            confirm(&pool, &id, 1).await;
+            crate::billing::create_fee_entry(&pool, &id, big, 0.01, crate::config::FeeCurrency::Zec, None).await.unwrap();
+        }
+        confirm(&pool, &crate::db::test_invoice(&pool, small, 2).await.invoice_id, 1).await;
+        crate::db::test_invoice(&pool, small, 2).await;
+        confirm(&pool, &crate::db::test_invoice(&pool, old, 3).await.invoice_id, 40).await;
+
+        // Paying fees is not volume.
+        let fee_ufvk = crate::scanner::fixtures::test_ufvk(9);
+        let settlement = crate::billing::create_settlement_invoice(
+            &pool, small, None, 5.0, &fee_ufvk, 40.0, 44.0, "2030-01-01T00:00:00Z",
+        ).await.unwrap();
+        confirm(&pool, &settlement, 1).await;
+
+        let top = top_merchants(&pool, 30, 10).await.unwrap();
+        let ranked: Vec<_> = top.iter().map(|m| (m.merchant_id.as_str(), m.invoices, m.volume_zatoshis)).collect();
+        assert_eq!(ranked, [(big.as_str(), 2, 50_000_000), (small.as_str(), 1, 25_000_000)]);
+        assert_eq!((top[0].fees_zec, top[1].fees_zec), (0.02, 0.0));
+        assert_eq!(top_merchants(&pool, 30, 1).await.unwrap().len(), 1);
+        assert_eq!(top_merchants(&pool, 60, 10).await.unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_revenue_periods_and_settlement_aging() {
+        let pool = crate::db::test_pool().await;
+        let merchant_id = crate::db::test_merchant(&pool, 1).await.merchant_id;
+        cycle(&pool, "c-1", &merchant_id, "2030-02-01T00:00:00Z", "paid", 0.5).await;
+        cycle(&pool, "c-2", &merchant_id, "2030-02-15T00:00:00Z", "open", 0.2).await;
+        cycle(&pool, "c-3", &merchant_id, "2030-01-01T00:00:00Z", "open", 0.1).await;
+
+        let periods = revenue_by_period(&pool, 12).await.unwrap();
+        assert_eq!(periods.iter().map(|p| p.period.as_str()).collect::<Vec<_>>(), ["2030-02", "2030-01"]);
+        assert_eq!((periods[0].cycles, periods[0].cycles_paid), (2, 1));
+        assert_eq!((periods[0].total_fees_zec, periods[0].outstanding_zec), (0.7, 0.2));
+        assert_eq!(revenue_by_period(&pool, 1).await.unwrap().len(), 1);
+
+        // Unpaid settlement invoices, bucketed by how long they have been waiting.
+        let fee_ufvk = crate::scanner::fixtures::test_ufvk(9);
+        for (cycle_id, zec) in [("c-2", 0.2), ("c-3", 0.1)] {
+            crate::billing::create_settlement_invoice(
+                &pool, &merchant_id, Some(cycle_id), zec, &fee_ufvk, 40.0, 44.0, "2030-03-01T00:00:00Z",
+            ).await.unwrap();
+        }
+        sqlx::query(
+            "UPDATE invoices SET created_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now', '-40 days')
+             WHERE id = (SELECT settlement_invoice_id FROM billing_cycles WHERE id = 'c-3')"
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let aging: Vec<_> = settlement_aging(&pool).await.unwrap().into_iter()
+            .map(|b| (b.bucket, b.invoices, b.outstanding_zec))
+            .collect();
+        assert_eq!(aging, [("0-7d".to_string(), 1, 0.2), ("30-60d".to_string(), 1, 0.1)]);
+
+        let statuses = status_counts(&pool).await.unwrap();
+        assert_eq!(statuses.iter().map(|s| (s.billing_status.as_str(), s.merchants)).collect::<Vec<_>>(), [("active", 1)]);
+    }
+}
@@ -0,0 +1,271 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use actix_governor::governor::middleware::NoOpMiddleware;
+use actix_governor::{GovernorConfig, GovernorConfigBuilder, KeyExtractor, SimpleKeyExtractionError};
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::HeaderMap;
+use actix_web::{web, HttpRequest};
+
+use crate::config::RateLimit;
+
+/// A trusted proxy: a single address (`10.0.0.1`) or a CIDR range (`10.0.0.0/8`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProxyRange {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl ProxyRange {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr.parse::<IpAddr>()?, Some(len.parse::<u8>()?)),
+            None => (s.parse::<IpAddr>()?, None),
+        };
+        let width = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = prefix_len.unwrap_or(width);
+        if prefix_len > width {
+            anyhow::bail!("Invalid proxy range '{}': prefix longer than {} bits", s, width);
+        }
+        Ok(Self { addr, prefix_len })
+    }
+
+    /// IPv4-mapped IPv6 addresses, as a dual-stack listener reports IPv4 peers, match
+    /// IPv4 ranges.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let same_prefix = |a: u128, b: u128, width: u8| {
+            self.prefix_len == 0 || a >> (width - self.prefix_len) == b >> (width - self.prefix_len)
+        };
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => same_prefix(u32::from(net).into(), u32::from(ip).into(), 32),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => same_prefix(net.into(), ip.into(), 128),
+            _ => false,
+        }
+    }
+}
+
+fn is_trusted(trusted: &[ProxyRange], ip: &IpAddr) -> bool {
+    trusted.iter().any(|range| range.contains(*ip))
+}
+
+/// Resolve the real client IP for a request.
+///
+/// Forwarding headers are only honored when the direct peer is a trusted proxy;
+/// otherwise anyone could spoof their address by sending X-Forwarded-For.
+/// The chain is walked right-to-left and the first hop that is not itself a
+/// trusted proxy is taken as the client. If every hop is trusted, the request came
+/// from inside the proxy network and the peer is used.
+pub fn resolve(peer: Option<IpAddr>, headers: &HeaderMap, trusted: &[ProxyRange]) -> Option<IpAddr> {
+    let peer = peer?;
+    if !is_trusted(trusted, &peer) {
+        return Some(peer);
+    }
+
+    let chain = forwarded_chain(headers);
+    let client = chain.iter().rev().find(|ip| !is_trusted(trusted, ip));
+    Some(client.copied().unwrap_or(peer))
+}
+
+/// Client IP for a handler, using TRUSTED_PROXIES from the app config.
+pub fn from_request(req: &HttpRequest) -> Option<IpAddr> {
+    let trusted = req
+        .app_data::<web::Data<crate::config::Config>>()
+        .map(|c| c.trusted_proxies.clone())
+        .unwrap_or_default();
+    resolve(req.peer_addr().map(|a| a.ip()), req.headers(), &trusted)
+}
+
+/// Hops from the standard `Forwarded` header, falling back to `X-Forwarded-For`.
+fn forwarded_chain(headers: &HeaderMap) -> Vec<IpAddr> {
+    let forwarded: Vec<IpAddr> = headers
+        .get_all("Forwarded")
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                if key.eq_ignore_ascii_case("for") {
+                    parse_node(value)
+                } else {
+                    None
+                }
+            })
+        })
+        .collect();
+
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+
+    headers
+        .get_all("X-Forwarded-For")
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(parse_node)
+        .collect()
+}
+
+/// Parse a single forwarding hop: `1.2.3.4`, `1.2.3.4:5678`, `"[2001:db8::1]:443"`, `2001:db8::1`.
+fn parse_node(raw: &str) -> Option<IpAddr> {
+    let value = raw.trim().trim_matches('"');
+    if let Ok(ip) = value.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(sock) = value.parse::<SocketAddr>() {
+        return Some(sock.ip());
+    }
+    value
+        .strip_prefix('[')
+        .and_then(|v| v.split(']').next())
+        .and_then(|v| v.parse().ok())
+}
+
+/// Governor key extractor that rate-limits on the resolved client IP rather than
+/// the proxy's address. IPv6 clients are grouped by /56 prefix like the default extractor.
+#[derive(Clone)]
+pub struct ClientIpKeyExtractor {
+    trusted: Arc<Vec<ProxyRange>>,
+}
+
+impl ClientIpKeyExtractor {
+    pub fn new(trusted: Vec<ProxyRange>) -> Self {
+        Self { trusted: Arc::new(trusted) }
+    }
+}
+
+impl KeyExtractor for ClientIpKeyExtractor {
+    type Key = IpAddr;
+    type KeyExtractionError = SimpleKeyExtractionError<&'static str>;
+
+    fn extract(&self, req: &ServiceRequest) -> Result<Self::Key, Self::KeyExtractionError> {
+        let ip = resolve(req.peer_addr().map(|a| a.ip()), req.headers(), &self.trusted)
+            .ok_or_else(|| SimpleKeyExtractionError::new("Could not determine client IP address"))?;
+
+        Ok(match ip {
+            IpAddr::V6(v6) => {
+                let mut octets = v6.octets();
+                octets[7..16].fill(0);
+                IpAddr::V6(octets.into())
+            }
+            v4 => v4,
+        })
+    }
+}
+
+pub type RateLimiter = GovernorConfig<ClientIpKeyExtractor, NoOpMiddleware>;
+
+/// Governor config enforcing `limit` per resolved client IP.
+pub fn rate_limiter(limit: RateLimit, trusted: &[ProxyRange]) -> RateLimiter {
+    GovernorConfigBuilder::default()
+        .key_extractor(ClientIpKeyExtractor::new(trusted.to_vec()))
+        .milliseconds_per_request(limit.period_ms)
+        .burst_size(limit.burst)
+        .finish()
+        .expect("RateLimit is validated as non-zero")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    fn headers(pairs: &[(&str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (k, v) in pairs {
+            map.append(
+                HeaderName::from_bytes(k.as_bytes()).unwrap(),
+                HeaderValue::from_str(v).unwrap(),
+            );
+        }
+        map
+    }
+
+    fn ranges(list: &[&str]) -> Vec<ProxyRange> {
+        list.iter().map(|s| ProxyRange::parse(s).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_untrusted_peer_ignores_headers() {
+        let h = headers(&[("X-Forwarded-For", "1.1.1.1")]);
+        let peer = "8.8.8.8".parse().ok();
+        assert_eq!(resolve(peer, &h, &[]), peer);
+    }
+
+    #[test]
+    fn test_trusted_peer_uses_rightmost_untrusted_hop() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let h = headers(&[("X-Forwarded-For", "6.6.6.6, 1.2.3.4, 10.0.0.1")]);
+        assert_eq!(resolve(Some(proxy), &h, &ranges(&["10.0.0.1"])), "1.2.3.4".parse().ok());
+    }
+
+    #[test]
+    fn test_forwarded_header_preferred() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let h = headers(&[
+            ("Forwarded", "for=\"[2001:db8::1]:443\";proto=https"),
+            ("X-Forwarded-For", "9.9.9.9"),
+        ]);
+        assert_eq!(resolve(Some(proxy), &h, &ranges(&["10.0.0.1"])), "2001:db8::1".parse().ok());
+    }
+
+    #[test]
+    fn test_trusted_peer_without_headers_falls_back_to_peer() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(resolve(Some(proxy), &HeaderMap::new(), &ranges(&["10.0.0.1"])), Some(proxy));
+    }
+
+    #[test]
+    fn test_trusted_ranges_are_skipped_in_the_chain() {
+        let trusted = ranges(&["10.0.0.0/8", "2001:db8::/32"]);
+        let proxy: IpAddr = "10.1.2.3".parse().unwrap();
+        let h = headers(&[("X-Forwarded-For", "1.2.3.4, 2001:db8::7, 10.200.0.1")]);
+        assert_eq!(resolve(Some(proxy), &h, &trusted), "1.2.3.4".parse().ok());
+
+        // A peer outside every range is the client, whatever it forwards.
+        let outsider: IpAddr = "11.0.0.1".parse().unwrap();
+        assert_eq!(resolve(Some(outsider), &h, &trusted), Some(outsider));
+        let mapped: IpAddr = "::ffff:10.0.0.9".parse().unwrap();
+        assert_eq!(resolve(Some(mapped), &h, &trusted), "1.2.3.4".parse().ok());
+    }
+
+    #[test]
+    fn test_all_trusted_hops_fall_back_to_peer() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let h = headers(&[("X-Forwarded-For", "10.0.0.7, 10.0.0.8")]);
+        assert_eq!(resolve(Some(proxy), &h, &ranges(&["10.0.0.0/24"])), Some(proxy));
+    }
+
+    #[test]
+    fn test_proxy_range_parsing() {
+        let range = ProxyRange::parse("192.168.0.0/16").unwrap();
+        assert!(range.contains("192.168.255.1".parse().unwrap()));
+        assert!(!range.contains("192.169.0.1".parse().unwrap()));
+        assert!(!range.contains("::1".parse().unwrap()));
+        let single = ProxyRange::parse("::1").unwrap();
+        assert!(single.contains("::1".parse().unwrap()));
+        assert!(!single.contains("::2".parse().unwrap()));
+        assert!(ProxyRange::parse("0.0.0.0/0").unwrap().contains("8.8.8.8".parse().unwrap()));
+        assert!(ProxyRange::parse("10.0.0.0/33").is_err());
+        assert!(ProxyRange::parse("10.0.0.0/x").is_err());
+        assert!(ProxyRange::parse("proxy.local").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_rate_limiter_enforces_the_configured_burst() {
+        use actix_web::{test, App, HttpResponse};
+
+        let limiter = rate_limiter(RateLimit { period_ms: 60_000, burst: 2 }, &[]);
+        let app = test::init_service(
+            App::new()
+                .wrap(actix_governor::Governor::new(&limiter))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        ).await;
+        let from = |ip: &str| test::TestRequest::get().uri("/").peer_addr(format!("{}:4000", ip).parse().unwrap()).to_request();
+
+        for _ in 0..2 {
+            assert_eq!(test::call_service(&app, from("8.8.8.8")).await.status(), 200);
+        }
+        assert_eq!(test::call_service(&app, from("8.8.8.8")).await.status(), 429);
+        assert_eq!(test::call_service(&app, from("1.1.1.1")).await.status(), 200);
+    }
+}
@@ -3,98 +3,568 @@ use std::env;
 #[derive(Clone, Debug)]
 pub struct Config {
     pub database_url: String,
+    /// Database connections for HTTP and gRPC requests.
+    pub db_api_pool_size: u32,
+    /// Database connections for the scanner, deliveries, billing and purges, kept apart
+    /// so a long rescan cannot hold the ones requests need.
+    pub db_worker_pool_size: u32,
+    /// A read-only copy of the database (e.g. a LiteFS or Litestream replica) for
+    /// read-heavy handlers that can serve slightly stale data. Unset: they use the API pool.
+    pub database_read_url: Option<String>,
+    pub db_read_pool_size: u32,
     pub cipherscan_api_url: String,
+    /// Per-request timeout for CipherScan calls.
+    pub cipherscan_timeout_secs: u64,
+    /// Retries after a failed CipherScan call (timeouts, connection errors, 5xx, 429).
+    pub cipherscan_retries: u32,
+    /// The primary network. Platform fees (`FEE_UFVK`, `FEE_ADDRESS`) are charged on it only.
     pub network: String,
+    /// Every network this instance serves, `network` first, each with its CipherScan API.
+    pub networks: Vec<NetworkEndpoint>,
     pub api_host: String,
     pub api_port: u16,
+    /// Port for the gRPC API on `api_host`; unset leaves it off.
+    pub grpc_port: Option<u16>,
     pub mempool_poll_interval_secs: u64,
     pub block_poll_interval_secs: u64,
+    /// The data key as 64 hex chars. Taken from `ENCRYPTION_KEY` here and replaced at
+    /// startup with whatever `key_provider` unwraps.
     #[allow(dead_code)]
     pub encryption_key: String,
+    pub key_provider: crate::keys::KeyProvider,
     pub invoice_expiry_minutes: i64,
+    /// Minutes after expiry during which payments are still matched (as `paid_late`).
+    pub late_payment_grace_minutes: i64,
     #[allow(dead_code)]
     pub data_purge_days: i64,
+    /// Days after an invoice settles before its buyer data is removed, unless
+    /// `pii_purge_fields` gives a field its own.
+    pub pii_purge_days: i64,
+    /// Buyer-data fields to purge, as `field` or `field:days`, comma-separated.
+    pub pii_purge_fields: String,
+    /// Days settled invoices and their payments are kept; 0 keeps them forever.
+    pub invoice_retention_days: i64,
     pub coingecko_api_url: String,
+    /// CoinGecko-compatible rate sources used instead of `coingecko_api_url`, such as
+    /// self-hosted oracles; the rate used is the median of all that answer.
+    pub price_feed_urls: Vec<String>,
+    /// HTTP or HTTPS proxy every price feed request goes through.
+    pub price_proxy_url: Option<String>,
+    /// Fetch rates only in the background at random times, through the proxy, without
+    /// identifying the software, so the feed cannot tie requests to this instance's traffic.
+    pub price_privacy_mode: bool,
     pub price_cache_secs: u64,
+    /// Static-rate mode: when both are set the price feed is never called.
+    pub fixed_zec_eur: Option<f64>,
+    pub fixed_zec_usd: Option<f64>,
+    /// Dashboard origins besides FRONTEND_URL, allowed credentialed requests.
     pub allowed_origins: Vec<String>,
+    /// Origins allowed on public checkout endpoints, without credentials; `*` for any.
+    pub cors_public_origins: Vec<String>,
+    /// Proxies whose forwarding headers are trusted, as addresses or CIDR ranges.
+    pub trusted_proxies: Vec<crate::client_ip::ProxyRange>,
+    /// Let webhook and relay URLs point at localhost or private networks. Testnet only,
+    /// for local receivers and end-to-end tests.
+    pub allow_private_webhooks: bool,
     pub cookie_domain: Option<String>,
+    /// Hours a dashboard session lasts from login, however active; rotation on elevation
+    /// or recovery does not extend it.
+    pub session_max_age_hours: i64,
     pub frontend_url: Option<String>,
+    /// Where this API is reachable from the internet, for links and images in emails.
+    pub public_api_url: Option<String>,
     pub smtp_host: Option<String>,
+    pub smtp_port: Option<u16>,
+    pub smtp_tls: SmtpTls,
+    pub smtp_pool_size: u32,
     pub smtp_user: Option<String>,
     pub smtp_pass: Option<String>,
     pub smtp_from: Option<String>,
+    pub email_templates_dir: Option<String>,
+    pub admin_token: Option<String>,
+    /// age X25519 recipient (`age1...`) that secrets backups are encrypted to.
+    pub backup_recipient: Option<String>,
     pub fee_ufvk: Option<String>,
     pub fee_address: Option<String>,
     pub fee_rate: f64,
+    /// Currency fees accrue in. Fiat fees are converted to ZEC when the settlement invoice
+    /// is generated, so a price drop mid-cycle does not shrink them.
+    pub fee_currency: FeeCurrency,
     pub billing_cycle_days_new: i64,
     pub billing_cycle_days_standard: i64,
+    pub media_dir: String,
+    pub media_max_bytes: usize,
+    pub s3_endpoint: Option<String>,
+    pub s3_bucket: Option<String>,
+    pub s3_region: String,
+    pub s3_access_key_id: Option<String>,
+    pub s3_secret_access_key: Option<String>,
+    /// Checkout invoices one IP can create per hour.
+    pub checkout_ip_limit_per_hour: i64,
+    /// Checkout invoices one product can get per hour, from all buyers; 0 for no limit.
+    pub checkout_product_limit_per_hour: i64,
+    /// Memo lookups one IP can make per minute; 0 for no limit.
+    pub lookup_ip_limit_per_minute: u32,
+    /// Proof-of-work difficulty in leading zero bits for checkout and lookup; 0 turns it off.
+    pub pow_difficulty: u32,
+    /// Strikes within an hour that get an IP banned from checkout and lookup; 0 never bans.
+    pub abuse_ban_strikes: u32,
+    pub abuse_ban_minutes: u64,
+    /// Unpaid (pending or underpaid) invoices a merchant may have open; 0 for no limit.
+    pub max_open_invoices_per_merchant: i64,
+    /// Invoices a merchant may create per hour; 0 for no limit.
+    pub max_invoices_per_merchant_per_hour: i64,
+    /// Smallest invoice price, in the invoice's own fiat currency.
+    pub min_invoice_fiat: f64,
+    /// Smallest invoice price in zatoshis; never below the scanner's dust floor.
+    pub min_invoice_zatoshis: i64,
+    /// Largest JSON request body accepted, in bytes.
+    pub json_limit_bytes: usize,
+    /// Per-IP limit on every route.
+    pub rate_limit: RateLimit,
+    /// Stricter per-IP limit on the `/merchants` and `/auth` scopes.
+    pub auth_rate_limit: RateLimit,
+    /// Per-peer and per-API-key limit on gRPC calls.
+    pub grpc_rate_limit: RateLimit,
+    /// Where operator alerts go; alerts are off unless one of these is set.
+    pub alert_email: Option<String>,
+    pub alert_webhook_url: Option<String>,
+    /// Minutes before an ongoing incident is notified again.
+    pub alert_cooldown_minutes: i64,
+    /// Age of the last feed rate that counts as stale.
+    pub alert_price_stale_minutes: i64,
+    /// Minutes without a completed scan that count as a stall.
+    pub alert_scanner_stall_minutes: i64,
+    /// Share of webhook deliveries failing over the last hour that raises an alert.
+    pub alert_webhook_failure_percent: i64,
+    /// Database errors within one minute that raise an alert.
+    pub alert_db_errors: u64,
+}
+
+/// A network served and the CipherScan API that indexes it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NetworkEndpoint {
+    pub network: String,
+    pub cipherscan_api_url: String,
+    /// A second, independently run CipherScan-compatible API that must agree on the block a
+    /// transaction was mined in before an invoice or refund is confirmed.
+    pub confirmation_verify_url: Option<String>,
+}
+
+/// Looks up a setting by name the way `env::var` does.
+type VarLookup<'a> = &'a dyn Fn(&str) -> Result<String, env::VarError>;
+
+/// `network` and `cipherscan_api_url` first, then each of `extra` (comma-separated) with its
+/// `CIPHERSCAN_API_URL_<NETWORK>`. Verification sources come from `CONFIRMATION_VERIFY_URL`
+/// and `CONFIRMATION_VERIFY_URL_<NETWORK>` the same way.
+fn parse_networks(var: VarLookup, network: &str, cipherscan_api_url: &str, extra: &str) -> anyhow::Result<Vec<NetworkEndpoint>> {
+    let mut networks = vec![NetworkEndpoint {
+        network: network.to_string(),
+        cipherscan_api_url: cipherscan_api_url.to_string(),
+        confirmation_verify_url: var("CONFIRMATION_VERIFY_URL").ok().filter(|s| !s.is_empty()),
+    }];
+    for name in extra.split(',').map(|s| s.trim().to_ascii_lowercase()).filter(|s| !s.is_empty()) {
+        if name != "mainnet" && name != "testnet" {
+            anyhow::bail!("Invalid network '{}' in EXTRA_NETWORKS: expected mainnet or testnet", name);
+        }
+        if networks.iter().any(|n| n.network == name) {
+            anyhow::bail!("Network '{}' is listed twice (NETWORK and EXTRA_NETWORKS)", name);
+        }
+        let url_var = format!("CIPHERSCAN_API_URL_{}", name.to_ascii_uppercase());
+        let url = var(&url_var).ok().filter(|s| !s.is_empty())
+            .ok_or_else(|| anyhow::anyhow!("{} must be set to serve {}", url_var, name))?;
+        let confirmation_verify_url = var(&format!("CONFIRMATION_VERIFY_URL_{}", name.to_ascii_uppercase()))
+            .ok()
+            .filter(|s| !s.is_empty());
+        networks.push(NetworkEndpoint { network: name, cipherscan_api_url: url, confirmation_verify_url });
+    }
+    for n in &networks {
+        if n.confirmation_verify_url.as_deref().map(|u| u.trim_end_matches('/')) == Some(n.cipherscan_api_url.trim_end_matches('/')) {
+            anyhow::bail!("The {} confirmation verification source must differ from its CipherScan API", n.network);
+        }
+    }
+    Ok(networks)
+}
+
+/// Token bucket per client IP (per API key for gRPC): `burst` requests at once, then one
+/// more every `period_ms`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub period_ms: u64,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// Read `{prefix}_PERIOD_MS` and `{prefix}_BURST`.
+    fn from_env(var: VarLookup, prefix: &str, period_ms: u64, burst: u32) -> anyhow::Result<Self> {
+        let limit = Self {
+            period_ms: var(&format!("{}_PERIOD_MS", prefix)).map_or(Ok(period_ms), |v| v.parse())?,
+            burst: var(&format!("{}_BURST", prefix)).map_or(Ok(burst), |v| v.parse())?,
+        };
+        if limit.period_ms == 0 || limit.burst == 0 {
+            anyhow::bail!("{}_PERIOD_MS and {}_BURST must be greater than 0", prefix, prefix);
+        }
+        Ok(limit)
+    }
+}
+
+/// How the SMTP connection is secured.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SmtpTls {
+    /// Plain connection upgraded with STARTTLS (usually port 587).
+    StartTls,
+    /// TLS from the first byte (usually port 465). The default.
+    Implicit,
+    /// No encryption, for a relay on localhost or a private network.
+    None,
+}
+
+impl SmtpTls {
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "starttls" => Ok(Self::StartTls),
+            "implicit" | "tls" => Ok(Self::Implicit),
+            "none" => Ok(Self::None),
+            other => anyhow::bail!("Invalid SMTP_TLS '{}': expected starttls, implicit or none", other),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::StartTls => "starttls",
+            Self::Implicit => "implicit",
+            Self::None => "none",
+        }
+    }
+}
+
+/// Denomination of platform fees.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FeeCurrency {
+    Zec,
+    Eur,
+    Usd,
+}
+
+impl FeeCurrency {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        match s.trim().to_ascii_uppercase().as_str() {
+            "ZEC" => Ok(Self::Zec),
+            "EUR" => Ok(Self::Eur),
+            "USD" => Ok(Self::Usd),
+            other => anyhow::bail!("Invalid FEE_CURRENCY '{}': expected ZEC, EUR or USD", other),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Zec => "ZEC",
+            Self::Eur => "EUR",
+            Self::Usd => "USD",
+        }
+    }
 }
 
 impl Config {
     pub fn from_env() -> anyhow::Result<Self> {
+        Self::from_vars(&|name| env::var(name))
+    }
+
+    /// The config an environment setting only `vars` would give, for unit tests.
+    #[cfg(test)]
+    pub(crate) fn from_pairs(vars: &[(&str, &str)]) -> anyhow::Result<Self> {
+        Self::from_vars(&|name| {
+            vars.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.to_string())
+                .ok_or(env::VarError::NotPresent)
+        })
+    }
+
+    fn from_vars(var: VarLookup) -> anyhow::Result<Self> {
+        let fixed_zec_eur: Option<f64> = var("FIXED_ZEC_EUR").ok().filter(|s| !s.is_empty()).map(|s| s.parse()).transpose()?;
+        let fixed_zec_usd: Option<f64> = var("FIXED_ZEC_USD").ok().filter(|s| !s.is_empty()).map(|s| s.parse()).transpose()?;
+        if fixed_zec_eur.is_some() != fixed_zec_usd.is_some() {
+            anyhow::bail!("FIXED_ZEC_EUR and FIXED_ZEC_USD must be set together");
+        }
+        let price_proxy_url = var("PRICE_PROXY_URL").ok().filter(|s| !s.is_empty());
+        let price_privacy_mode = var("PRICE_PRIVACY_MODE").is_ok_and(|v| v == "true");
+        if price_privacy_mode && price_proxy_url.is_none() {
+            anyhow::bail!("PRICE_PRIVACY_MODE needs PRICE_PROXY_URL");
+        }
+        let pow_difficulty: u32 = var("POW_DIFFICULTY").unwrap_or_else(|_| "0".into()).parse()?;
+        if pow_difficulty > 32 {
+            anyhow::bail!("POW_DIFFICULTY must be at most 32 bits");
+        }
+        let dust_floor = crate::scanner::decrypt::DUST_THRESHOLD_MIN_ZATOSHIS;
+        let min_invoice_zatoshis: i64 = var("MIN_INVOICE_ZATOSHIS")
+            .unwrap_or_else(|_| dust_floor.to_string())
+            .parse()?;
+        if min_invoice_zatoshis < dust_floor {
+            anyhow::bail!("MIN_INVOICE_ZATOSHIS must be at least the dust floor of {} zatoshis", dust_floor);
+        }
+        let session_max_age_hours: i64 = var("SESSION_MAX_AGE_HOURS").unwrap_or_else(|_| "24".into()).parse()?;
+        if session_max_age_hours <= 0 {
+            anyhow::bail!("SESSION_MAX_AGE_HOURS must be greater than 0");
+        }
+        let db_api_pool_size: u32 = var("DB_API_POOL_SIZE").unwrap_or_else(|_| "5".into()).parse()?;
+        let db_worker_pool_size: u32 = var("DB_WORKER_POOL_SIZE").unwrap_or_else(|_| "3".into()).parse()?;
+        let db_read_pool_size: u32 = var("DB_READ_POOL_SIZE").unwrap_or_else(|_| "5".into()).parse()?;
+        if db_api_pool_size == 0 || db_worker_pool_size == 0 || db_read_pool_size == 0 {
+            anyhow::bail!("DB_API_POOL_SIZE, DB_WORKER_POOL_SIZE and DB_READ_POOL_SIZE must be greater than 0");
+        }
+        let backup_recipient = var("BACKUP_RECIPIENT").ok().filter(|s| !s.is_empty());
+        if let Some(ref recipient) = backup_recipient {
+            if recipient.parse::<age::x25519::Recipient>().is_err() {
+                anyhow::bail!("BACKUP_RECIPIENT must be an age X25519 public key (age1...)");
+            }
+        }
+
+        let network = var("NETWORK").unwrap_or_else(|_| "testnet".into());
+        let cipherscan_api_url = var("CIPHERSCAN_API_URL")
+            .unwrap_or_else(|_| "https://api.testnet.cipherscan.app".into());
+        let networks = parse_networks(var, &network, &cipherscan_api_url, &var("EXTRA_NETWORKS").unwrap_or_default())?;
+
         Ok(Self {
-            database_url: env::var("DATABASE_URL")
+            database_url: var("DATABASE_URL")
                 .unwrap_or_else(|_| "sqlite:cipherpay.db".into()),
-            cipherscan_api_url: env::var("CIPHERSCAN_API_URL")
-                .unwrap_or_else(|_| "https://api.testnet.cipherscan.app".into()),
-            network: env::var("NETWORK").unwrap_or_else(|_| "testnet".into()),
-            api_host: env::var("API_HOST").unwrap_or_else(|_| "127.0.0.1".into()),
-            api_port: env::var("API_PORT")
+            db_api_pool_size,
+            db_worker_pool_size,
+            database_read_url: var("DATABASE_READ_URL").ok().filter(|s| !s.is_empty()),
+            db_read_pool_size,
+            cipherscan_api_url,
+            cipherscan_timeout_secs: var("CIPHERSCAN_TIMEOUT_SECS")
+                .unwrap_or_else(|_| "10".into())
+                .parse()?,
+            cipherscan_retries: var("CIPHERSCAN_RETRIES")
+                .unwrap_or_else(|_| "2".into())
+                .parse()?,
+            network,
+            networks,
+            api_host: var("API_HOST").unwrap_or_else(|_| "127.0.0.1".into()),
+            api_port: var("API_PORT")
                 .unwrap_or_else(|_| "3080".into())
                 .parse()?,
-            mempool_poll_interval_secs: env::var("MEMPOOL_POLL_INTERVAL_SECS")
+            grpc_port: var("GRPC_PORT").ok().filter(|s| !s.is_empty()).map(|s| s.parse()).transpose()?,
+            mempool_poll_interval_secs: var("MEMPOOL_POLL_INTERVAL_SECS")
                 .unwrap_or_else(|_| "5".into())
                 .parse()?,
-            block_poll_interval_secs: env::var("BLOCK_POLL_INTERVAL_SECS")
+            block_poll_interval_secs: var("BLOCK_POLL_INTERVAL_SECS")
                 .unwrap_or_else(|_| "15".into())
                 .parse()?,
-            encryption_key: env::var("ENCRYPTION_KEY").unwrap_or_default(),
-            invoice_expiry_minutes: env::var("INVOICE_EXPIRY_MINUTES")
+            encryption_key: var("ENCRYPTION_KEY").unwrap_or_default(),
+            key_provider: crate::keys::KeyProvider::from_env()?,
+            invoice_expiry_minutes: var("INVOICE_EXPIRY_MINUTES")
                 .unwrap_or_else(|_| "30".into())
                 .parse()?,
-            data_purge_days: env::var("DATA_PURGE_DAYS")
+            late_payment_grace_minutes: var("LATE_PAYMENT_GRACE_MINUTES")
+                .unwrap_or_else(|_| "10".into())
+                .parse()?,
+            data_purge_days: var("DATA_PURGE_DAYS")
                 .unwrap_or_else(|_| "30".into())
                 .parse()?,
-            coingecko_api_url: env::var("COINGECKO_API_URL")
+            pii_purge_days: var("PII_PURGE_DAYS")
+                .unwrap_or_else(|_| "7".into())
+                .parse()?,
+            pii_purge_fields: var("PII_PURGE_FIELDS")
+                .unwrap_or_else(|_| "refund_address:30,custom_fields".into()),
+            invoice_retention_days: var("INVOICE_RETENTION_DAYS")
+                .unwrap_or_else(|_| "0".into())
+                .parse()?,
+            coingecko_api_url: var("COINGECKO_API_URL")
                 .unwrap_or_else(|_| "https://api.coingecko.com/api/v3".into()),
-            price_cache_secs: env::var("PRICE_CACHE_SECS")
+            price_feed_urls: var("PRICE_FEED_URLS")
+                .unwrap_or_default()
+                .split(',')
+                .map(|s| s.trim().trim_end_matches('/').to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            price_proxy_url,
+            price_privacy_mode,
+            price_cache_secs: var("PRICE_CACHE_SECS")
                 .unwrap_or_else(|_| "300".into())
                 .parse()?,
-            allowed_origins: env::var("ALLOWED_ORIGINS")
+            fixed_zec_eur,
+            fixed_zec_usd,
+            allowed_origins: var("ALLOWED_ORIGINS")
                 .unwrap_or_default()
                 .split(',')
                 .map(|s| s.trim().to_string())
                 .filter(|s| !s.is_empty())
                 .collect(),
-            cookie_domain: env::var("COOKIE_DOMAIN").ok().filter(|s| !s.is_empty()),
-            frontend_url: env::var("FRONTEND_URL").ok().filter(|s| !s.is_empty()),
-            smtp_host: env::var("SMTP_HOST").ok().filter(|s| !s.is_empty()),
-            smtp_user: env::var("SMTP_USER").ok().filter(|s| !s.is_empty()),
-            smtp_pass: env::var("SMTP_PASS").ok().filter(|s| !s.is_empty()),
-            smtp_from: env::var("SMTP_FROM").ok().filter(|s| !s.is_empty()),
-            fee_ufvk: env::var("FEE_UFVK").ok().filter(|s| !s.is_empty()),
-            fee_address: env::var("FEE_ADDRESS").ok().filter(|s| !s.is_empty()),
-            fee_rate: env::var("FEE_RATE")
+            cors_public_origins: var("CORS_PUBLIC_ORIGINS")
+                .unwrap_or_else(|_| "*".into())
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            trusted_proxies: var("TRUSTED_PROXIES")
+                .unwrap_or_default()
+                .split(',')
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+                .map(crate::client_ip::ProxyRange::parse)
+                .collect::<anyhow::Result<_>>()?,
+            allow_private_webhooks: var("ALLOW_PRIVATE_WEBHOOKS").is_ok_and(|v| v == "true"),
+            cookie_domain: var("COOKIE_DOMAIN").ok().filter(|s| !s.is_empty()),
+            session_max_age_hours,
+            frontend_url: var("FRONTEND_URL").ok().filter(|s| !s.is_empty()),
+            public_api_url: var("PUBLIC_API_URL").ok()
+                .map(|s| s.trim_end_matches('/').to_string())
+                .filter(|s| !s.is_empty()),
+            smtp_host: var("SMTP_HOST").ok().filter(|s| !s.is_empty()),
+            smtp_port: var("SMTP_PORT").ok().filter(|s| !s.is_empty()).map(|s| s.parse()).transpose()?,
+            smtp_tls: SmtpTls::parse(&var("SMTP_TLS").unwrap_or_else(|_| "implicit".into()))?,
+            smtp_pool_size: var("SMTP_POOL_SIZE")
+                .unwrap_or_else(|_| "4".into())
+                .parse()?,
+            smtp_user: var("SMTP_USER").ok().filter(|s| !s.is_empty()),
+            smtp_pass: var("SMTP_PASS").ok().filter(|s| !s.is_empty()),
+            smtp_from: var("SMTP_FROM").ok().filter(|s| !s.is_empty()),
+            email_templates_dir: var("EMAIL_TEMPLATES_DIR").ok().filter(|s| !s.is_empty()),
+            admin_token: var("ADMIN_TOKEN").ok().filter(|s| !s.is_empty()),
+            backup_recipient,
+            fee_ufvk: var("FEE_UFVK").ok().filter(|s| !s.is_empty()),
+            fee_address: var("FEE_ADDRESS").ok().filter(|s| !s.is_empty()),
+            fee_rate: var("FEE_RATE")
                 .unwrap_or_else(|_| "0.01".into())
                 .parse()?,
-            billing_cycle_days_new: env::var("BILLING_CYCLE_DAYS_NEW")
+            fee_currency: FeeCurrency::parse(&var("FEE_CURRENCY").unwrap_or_else(|_| "ZEC".into()))?,
+            billing_cycle_days_new: var("BILLING_CYCLE_DAYS_NEW")
                 .unwrap_or_else(|_| "7".into())
                 .parse()?,
-            billing_cycle_days_standard: env::var("BILLING_CYCLE_DAYS_STANDARD")
+            billing_cycle_days_standard: var("BILLING_CYCLE_DAYS_STANDARD")
+                .unwrap_or_else(|_| "30".into())
+                .parse()?,
+            media_dir: var("MEDIA_DIR").unwrap_or_else(|_| "media".into()),
+            media_max_bytes: var("MEDIA_MAX_BYTES")
+                .unwrap_or_else(|_| "2097152".into())
+                .parse()?,
+            s3_endpoint: var("S3_ENDPOINT").ok().filter(|s| !s.is_empty()),
+            s3_bucket: var("S3_BUCKET").ok().filter(|s| !s.is_empty()),
+            s3_region: var("S3_REGION").unwrap_or_else(|_| "us-east-1".into()),
+            s3_access_key_id: var("S3_ACCESS_KEY_ID").ok().filter(|s| !s.is_empty()),
+            s3_secret_access_key: var("S3_SECRET_ACCESS_KEY").ok().filter(|s| !s.is_empty()),
+            checkout_ip_limit_per_hour: var("CHECKOUT_IP_LIMIT_PER_HOUR")
+                .unwrap_or_else(|_| "20".into())
+                .parse()?,
+            checkout_product_limit_per_hour: var("CHECKOUT_PRODUCT_LIMIT_PER_HOUR")
+                .unwrap_or_else(|_| "200".into())
+                .parse()?,
+            lookup_ip_limit_per_minute: var("LOOKUP_IP_LIMIT_PER_MINUTE")
                 .unwrap_or_else(|_| "30".into())
                 .parse()?,
+            pow_difficulty,
+            abuse_ban_strikes: var("ABUSE_BAN_STRIKES")
+                .unwrap_or_else(|_| "10".into())
+                .parse()?,
+            abuse_ban_minutes: var("ABUSE_BAN_MINUTES")
+                .unwrap_or_else(|_| "60".into())
+                .parse()?,
+            max_open_invoices_per_merchant: var("MAX_OPEN_INVOICES_PER_MERCHANT")
+                .unwrap_or_else(|_| "10000".into())
+                .parse()?,
+            max_invoices_per_merchant_per_hour: var("MAX_INVOICES_PER_MERCHANT_PER_HOUR")
+                .unwrap_or_else(|_| "1000".into())
+                .parse()?,
+            min_invoice_fiat: var("MIN_INVOICE_FIAT")
+                .unwrap_or_else(|_| "0.01".into())
+                .parse()?,
+            min_invoice_zatoshis,
+            json_limit_bytes: var("JSON_LIMIT_BYTES")
+                .unwrap_or_else(|_| "65536".into())
+                .parse()?,
+            rate_limit: RateLimit::from_env(var, "RATE_LIMIT", 1_000, 60)?,
+            auth_rate_limit: RateLimit::from_env(var, "AUTH_RATE_LIMIT", 10_000, 5)?,
+            grpc_rate_limit: RateLimit::from_env(var, "GRPC_RATE_LIMIT", 100, 100)?,
+            alert_email: var("ALERT_EMAIL").ok().filter(|s| !s.is_empty()),
+            alert_webhook_url: var("ALERT_WEBHOOK_URL").ok().filter(|s| !s.is_empty()),
+            alert_cooldown_minutes: var("ALERT_COOLDOWN_MINUTES")
+                .unwrap_or_else(|_| "60".into())
+                .parse()?,
+            alert_price_stale_minutes: var("ALERT_PRICE_STALE_MINUTES")
+                .unwrap_or_else(|_| "30".into())
+                .parse()?,
+            alert_scanner_stall_minutes: var("ALERT_SCANNER_STALL_MINUTES")
+                .unwrap_or_else(|_| "10".into())
+                .parse()?,
+            alert_webhook_failure_percent: var("ALERT_WEBHOOK_FAILURE_PERCENT")
+                .unwrap_or_else(|_| "50".into())
+                .parse()?,
+            alert_db_errors: var("ALERT_DB_ERRORS")
+                .unwrap_or_else(|_| "10".into())
+                .parse()?,
         })
     }
 
+    /// Every network served is testnet, so mainnet safeguards (secure cookies, public
+    /// webhook hosts, locked-down CORS) can be relaxed.
     pub fn is_testnet(&self) -> bool {
-        self.network == "testnet"
+        self.networks.iter().all(|n| n.network == "testnet")
+    }
+
+    pub fn serves(&self, network: &str) -> bool {
+        self.networks.iter().any(|n| n.network == network)
+    }
+
+    pub fn alerts_configured(&self) -> bool {
+        self.alert_email.is_some() || self.alert_webhook_url.is_some()
     }
 
     pub fn smtp_configured(&self) -> bool {
         self.smtp_host.is_some() && self.smtp_from.is_some()
     }
 
+    /// Product images go to S3-compatible storage when fully configured, otherwise to MEDIA_DIR.
+    pub fn media_s3_configured(&self) -> bool {
+        self.s3_endpoint.is_some()
+            && self.s3_bucket.is_some()
+            && self.s3_access_key_id.is_some()
+            && self.s3_secret_access_key.is_some()
+    }
+
+    pub fn fixed_rates(&self) -> Option<(f64, f64)> {
+        self.fixed_zec_eur.zip(self.fixed_zec_usd)
+    }
+
+    /// Every price feed queried: `PRICE_FEED_URLS`, or `COINGECKO_API_URL` when unset.
+    pub fn price_sources(&self) -> Vec<String> {
+        if self.price_feed_urls.is_empty() {
+            vec![self.coingecko_api_url.clone()]
+        } else {
+            self.price_feed_urls.clone()
+        }
+    }
+
     pub fn fee_enabled(&self) -> bool {
         self.fee_address.is_some() && self.fee_ufvk.is_some() && self.fee_rate > 0.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_limits_default_and_parse() {
+        let config = Config::from_pairs(&[]).unwrap();
+        assert_eq!(config.json_limit_bytes, 65_536);
+        assert_eq!(config.rate_limit, RateLimit { period_ms: 1_000, burst: 60 });
+        assert_eq!(config.auth_rate_limit, RateLimit { period_ms: 10_000, burst: 5 });
+        assert_eq!(config.grpc_rate_limit, RateLimit { period_ms: 100, burst: 100 });
+
+        let config = Config::from_pairs(&[
+            ("JSON_LIMIT_BYTES", "1048576"),
+            ("RATE_LIMIT_PERIOD_MS", "250"),
+            ("RATE_LIMIT_BURST", "200"),
+            ("AUTH_RATE_LIMIT_BURST", "20"),
+        ]).unwrap();
+        assert_eq!(config.json_limit_bytes, 1_048_576);
+        assert_eq!(config.rate_limit, RateLimit { period_ms: 250, burst: 200 });
+        assert_eq!(config.auth_rate_limit, RateLimit { period_ms: 10_000, burst: 20 });
+
+        assert!(Config::from_pairs(&[("RATE_LIMIT_BURST", "0")]).is_err());
+        assert!(Config::from_pairs(&[("AUTH_RATE_LIMIT_PERIOD_MS", "soon")]).is_err());
+        assert!(Config::from_pairs(&[("JSON_LIMIT_BYTES", "-1")]).is_err());
+    }
+}
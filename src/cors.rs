@@ -0,0 +1,190 @@
+//! CORS by route group. Public checkout endpoints (buyer invoice reads, checkout, rates,
+//! catalogs, media) answer any origin in CORS_PUBLIC_ORIGINS, without credentials. Every
+//! other endpoint belongs to the dashboard and answers only FRONTEND_URL and
+//! ALLOWED_ORIGINS, with credentials. Dashboard origins get credentials on public
+//! endpoints too, where the owning merchant reads the full invoice with its session.
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse};
+
+use crate::config::Config;
+
+const ALLOWED_METHODS: &str = "GET, POST, PATCH, DELETE, OPTIONS";
+const MAX_AGE_SECS: &str = "3600";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Group {
+    Public,
+    Dashboard,
+}
+
+/// The group an API path belongs to. Anything outside `/api` (storefront, widget assets)
+/// is public.
+pub fn group(path: &str) -> Group {
+    let Some(rest) = path.strip_prefix("/api/v1").or_else(|| path.strip_prefix("/api")) else {
+        return Group::Public;
+    };
+    let segments: Vec<&str> = rest.trim_matches('/').split('/').collect();
+    let public = match segments.as_slice() {
+        ["health"] | ["system", "status"] | ["rates"] | ["pow", "challenge"] => true,
+        ["checkout", ..] => true,
+        ["invoices", "export"] => false,
+        ["invoices", "lookup", _] | ["invoices", _] => true,
+        ["invoices", _, "status" | "stream" | "qr" | "diagnose" | "refund-address"] => true,
+        ["merchants", id, "catalog"] => *id != "me",
+        ["products", _, "public"] | ["media", _] => true,
+        ["payment-requests", _, "open" | "qr"] => true,
+        ["webhooks", "signing-info"] | ["x402", "verify"] => true,
+        _ => false,
+    };
+    if public { Group::Public } else { Group::Dashboard }
+}
+
+/// How a request from an allowed origin is answered.
+#[derive(Debug, PartialEq)]
+struct Allow {
+    /// `*` or the request's origin.
+    origin: String,
+    credentials: bool,
+}
+
+pub struct CorsPolicy {
+    /// Origins allowed on public endpoints; empty allows any.
+    public: Vec<String>,
+    /// Origins allowed everywhere, with credentials.
+    dashboard: Vec<String>,
+}
+
+impl CorsPolicy {
+    /// Fails when a credentialed origin is a wildcard: browsers would send the merchant's
+    /// session to any site that asked.
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        if config.allowed_origins.iter().any(|o| o.contains('*')) {
+            anyhow::bail!("ALLOWED_ORIGINS cannot contain '*': dashboard requests carry credentials, list each origin");
+        }
+        let frontend = config.frontend_url.as_deref().unwrap_or("http://localhost:3000");
+        let frontend = url::Url::parse(frontend)
+            .map_err(|e| anyhow::anyhow!("Invalid FRONTEND_URL: {}", e))?
+            .origin()
+            .ascii_serialization();
+        let mut dashboard = vec![frontend];
+        dashboard.extend(config.allowed_origins.iter().map(|o| o.trim_end_matches('/').to_string()));
+
+        let public = if config.cors_public_origins.iter().any(|o| o == "*") {
+            Vec::new()
+        } else {
+            config.cors_public_origins.iter().map(|o| o.trim_end_matches('/').to_string()).collect()
+        };
+        Ok(Self { public, dashboard })
+    }
+
+    fn allow(&self, group: Group, origin: &str) -> Option<Allow> {
+        if self.dashboard.iter().any(|o| o == origin) {
+            return Some(Allow { origin: origin.to_string(), credentials: true });
+        }
+        match group {
+            Group::Public if self.public.is_empty() => Some(Allow { origin: "*".into(), credentials: false }),
+            Group::Public if self.public.iter().any(|o| o == origin) => {
+                Some(Allow { origin: origin.to_string(), credentials: false })
+            }
+            _ => None,
+        }
+    }
+}
+
+fn insert(headers: &mut header::HeaderMap, name: header::HeaderName, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(name, value);
+    }
+}
+
+fn apply(headers: &mut header::HeaderMap, allow: &Allow) {
+    insert(headers, header::ACCESS_CONTROL_ALLOW_ORIGIN, &allow.origin);
+    if allow.credentials {
+        insert(headers, header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true");
+    }
+    insert(headers, header::ACCESS_CONTROL_EXPOSE_HEADERS, crate::api::version::VERSION_HEADER);
+}
+
+/// Answer preflights and add CORS headers to responses for allowed origins. Requests from
+/// other origins still run; the browser just does not let the page read the response.
+pub async fn middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let Some(origin) = req.headers().get(header::ORIGIN).and_then(|v| v.to_str().ok()).map(String::from) else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+    let allow = req
+        .app_data::<web::Data<CorsPolicy>>()
+        .and_then(|policy| policy.allow(group(req.path()), &origin));
+
+    let preflight = req.method() == Method::OPTIONS
+        && req.headers().contains_key(header::ACCESS_CONTROL_REQUEST_METHOD);
+    if preflight {
+        let Some(allow) = allow else {
+            return Ok(req.into_response(HttpResponse::Forbidden().finish()));
+        };
+        let mut resp = HttpResponse::NoContent().finish();
+        let requested_headers = req.headers().get(header::ACCESS_CONTROL_REQUEST_HEADERS).cloned();
+        let headers = resp.headers_mut();
+        apply(headers, &allow);
+        insert(headers, header::ACCESS_CONTROL_ALLOW_METHODS, ALLOWED_METHODS);
+        if let Some(requested) = requested_headers {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, requested);
+        }
+        insert(headers, header::ACCESS_CONTROL_MAX_AGE, MAX_AGE_SECS);
+        insert(headers, header::VARY, "Origin");
+        return Ok(req.into_response(resp));
+    }
+
+    let mut res = next.call(req).await?.map_into_boxed_body();
+    let headers = res.headers_mut();
+    if let Some(allow) = allow {
+        apply(headers, &allow);
+    }
+    headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(public: &[&str]) -> CorsPolicy {
+        CorsPolicy {
+            public: public.iter().map(|s| s.to_string()).collect(),
+            dashboard: vec!["https://cipherpay.app".into()],
+        }
+    }
+
+    #[test]
+    fn test_route_groups() {
+        for path in ["/api/v1/invoices/abc", "/api/invoices/abc/status", "/api/v1/checkout/sessions/1/pay",
+            "/api/v1/merchants/m1/catalog", "/api/v1/rates", "/store/shop", "/widget/cipherpay.js"] {
+            assert_eq!(group(path), Group::Public, "{}", path);
+        }
+        for path in ["/api/v1/invoices", "/api/invoices/export", "/api/v1/invoices/abc/refund", "/api/v1/merchants/me",
+            "/api/v1/merchants/me/catalog", "/api/auth/session", "/api/v1/products", "/api/v1/admin/jobs"] {
+            assert_eq!(group(path), Group::Dashboard, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_credentials_only_for_dashboard_origins() {
+        let open = policy(&[]);
+        let dashboard = Some(Allow { origin: "https://cipherpay.app".into(), credentials: true });
+        assert_eq!(open.allow(Group::Dashboard, "https://cipherpay.app"), dashboard);
+        assert_eq!(open.allow(Group::Public, "https://cipherpay.app"), dashboard);
+        assert_eq!(open.allow(Group::Public, "https://shop.example"), Some(Allow { origin: "*".into(), credentials: false }));
+        assert_eq!(open.allow(Group::Dashboard, "https://shop.example"), None);
+
+        let listed = policy(&["https://shop.example"]);
+        assert_eq!(listed.allow(Group::Public, "https://shop.example").map(|a| a.credentials), Some(false));
+        assert_eq!(listed.allow(Group::Public, "https://evil.example"), None);
+    }
+}
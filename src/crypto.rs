@@ -6,6 +6,33 @@ use anyhow::{anyhow, Result};
 
 const NONCE_LEN: usize = 12;
 
+/// Unwrap the data key through the configured provider and return it as the 64 hex
+/// characters `encrypt` and `decrypt` take. KMS plaintexts may be the raw 32 bytes or
+/// the hex text; an empty key leaves encryption off, as an unset `ENCRYPTION_KEY` does.
+/// The provider's credentials are dropped once it has been asked, whatever the outcome.
+pub async fn load_data_key(provider: &mut crate::keys::KeyProvider, http: &reqwest::Client) -> Result<String> {
+    let material = provider.unwrap_data_key(http).await;
+    provider.drop_credentials();
+    let material = material?;
+    let key_hex = match material.len() {
+        32 => hex::encode(&material),
+        _ => String::from_utf8(material)
+            .map_err(|_| anyhow!("Data key from the {} provider is neither 32 bytes nor hex", provider.as_str()))?
+            .trim()
+            .to_string(),
+    };
+    if key_hex.is_empty() {
+        if !matches!(provider, crate::keys::KeyProvider::Env) {
+            return Err(anyhow!("The {} key provider returned an empty key", provider.as_str()));
+        }
+        return Ok(key_hex);
+    }
+    if hex::decode(&key_hex).map(|b| b.len()) != Ok(32) {
+        return Err(anyhow!("Data key from the {} provider must be 32 bytes (64 hex chars)", provider.as_str()));
+    }
+    Ok(key_hex)
+}
+
 pub fn encrypt(plaintext: &str, key_hex: &str) -> Result<String> {
     let key_bytes = hex::decode(key_hex)
         .map_err(|_| anyhow!("ENCRYPTION_KEY must be 64 hex characters (32 bytes)"))?;
@@ -107,4 +134,22 @@ mod tests {
         let result = decrypt_or_plaintext("uviewtest1abc", &key).unwrap();
         assert_eq!(result, "uviewtest1abc");
     }
+
+    #[tokio::test]
+    async fn test_load_data_key_accepts_raw_bytes_and_hex() {
+        let dir = std::env::temp_dir().join(format!("cipherpay-crypto-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let http = reqwest::Client::new();
+        let file = |name: &str| crate::keys::KeyProvider::File { path: dir.join(name).to_string_lossy().into_owned() };
+
+        std::fs::write(dir.join("raw"), [0xabu8; 32]).unwrap();
+        std::fs::write(dir.join("hex"), format!("{}\n", "ab".repeat(32))).unwrap();
+        std::fs::write(dir.join("short"), "abcd").unwrap();
+        std::fs::write(dir.join("empty"), "").unwrap();
+        assert_eq!(load_data_key(&mut file("raw"), &http).await.unwrap(), "ab".repeat(32));
+        assert_eq!(load_data_key(&mut file("hex"), &http).await.unwrap(), "ab".repeat(32));
+        assert!(load_data_key(&mut file("short"), &http).await.is_err());
+        assert!(load_data_key(&mut file("empty"), &http).await.is_err());
+        std::fs::remove_dir_all(dir).ok();
+    }
 }
@@ -1,16 +1,136 @@
-use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
-use sqlx::SqlitePool;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection, SqlitePoolOptions, SqliteSynchronous};
+use sqlx::{Connection, Sqlite, SqlitePool, Transaction};
+use std::ops::{Deref, DerefMut};
 use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::{Mutex, MutexGuard};
 
-pub async fn create_pool(database_url: &str) -> anyhow::Result<SqlitePool> {
+/// How long a statement waits for another connection's write lock before SQLITE_BUSY.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Multi-statement writes take their turn here, one at a time, so they queue in-process
+/// instead of racing each other for SQLite's single write lock. The queue is not
+/// re-entrant: a task asking for a second turn while it holds one waits on itself forever.
+static WRITE_QUEUE: Mutex<()> = Mutex::const_new(());
+
+/// The task holding the write queue turn, so a nested `begin_write` fails loudly in debug
+/// builds instead of deadlocking.
+static WRITE_HOLDER: std::sync::Mutex<Option<tokio::task::Id>> = std::sync::Mutex::new(None);
+
+/// A write queue turn; gives it up, and forgets its holder, when dropped.
+struct Turn {
+    _guard: MutexGuard<'static, ()>,
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        if let Ok(mut holder) = WRITE_HOLDER.lock() {
+            *holder = None;
+        }
+    }
+}
+
+/// An immediate (write-locked from the start) transaction, holding the write queue turn
+/// until it is committed or dropped. Use it like a `sqlx::Transaction`: `&mut *tx`.
+pub struct WriteTx {
+    tx: Transaction<'static, Sqlite>,
+    _turn: Turn,
+}
+
+impl WriteTx {
+    pub async fn commit(self) -> sqlx::Result<()> {
+        self.tx.commit().await
+    }
+}
+
+impl Deref for WriteTx {
+    type Target = SqliteConnection;
+
+    fn deref(&self) -> &SqliteConnection {
+        &self.tx
+    }
+}
+
+impl DerefMut for WriteTx {
+    fn deref_mut(&mut self) -> &mut SqliteConnection {
+        &mut self.tx
+    }
+}
+
+/// Start a write transaction with `BEGIN IMMEDIATE`. A deferred transaction that reads
+/// first and writes later can fail with SQLITE_BUSY on the lock upgrade no matter the
+/// busy timeout; taking the lock up front makes it wait instead.
+///
+/// Must not be nested: a task holding a `WriteTx` that calls this again (directly or
+/// through a helper that writes) deadlocks. Do the inner work on `&mut *tx` instead.
+pub async fn begin_write(pool: &SqlitePool) -> sqlx::Result<WriteTx> {
+    let task = tokio::task::try_id();
+    debug_assert!(
+        task.is_none() || *WRITE_HOLDER.lock().unwrap() != task,
+        "begin_write nested inside a write transaction of the same task"
+    );
+    let turn = Turn { _guard: WRITE_QUEUE.lock().await };
+    if let Ok(mut holder) = WRITE_HOLDER.lock() {
+        *holder = task;
+    }
+    let tx = pool.begin_with("BEGIN IMMEDIATE").await?;
+    Ok(WriteTx { tx, _turn: turn })
+}
+
+/// Connect without touching the schema; for pools opened after `create_pool` has migrated
+/// the database.
+pub async fn open_pool(database_url: &str, max_connections: u32) -> anyhow::Result<SqlitePool> {
     let options = SqliteConnectOptions::from_str(database_url)?
         .create_if_missing(true)
-        .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal);
+        .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal)
+        .synchronous(SqliteSynchronous::Normal)
+        .busy_timeout(BUSY_TIMEOUT);
 
     let pool = SqlitePoolOptions::new()
-        .max_connections(5)
+        .max_connections(max_connections)
         .connect_with(options)
         .await?;
+    Ok(pool)
+}
+
+/// Connections for read-heavy handlers that can serve slightly stale data: a read-only
+/// replica when DATABASE_READ_URL is set, the API pool otherwise. Anything that writes,
+/// or must see its own writes, takes the API pool instead.
+#[derive(Clone)]
+pub struct ReadPool(SqlitePool);
+
+impl ReadPool {
+    /// Reads go to the primary database through `pool`.
+    pub fn primary(pool: &SqlitePool) -> Self {
+        Self(pool.clone())
+    }
+
+    /// Open `replica_url` read-only. The replica is kept up to date from outside, so
+    /// the schema is not touched and the file must already exist.
+    pub async fn replica(replica_url: &str, max_connections: u32) -> anyhow::Result<Self> {
+        let options = SqliteConnectOptions::from_str(replica_url)?
+            .read_only(true)
+            .busy_timeout(BUSY_TIMEOUT);
+
+        let pool = SqlitePoolOptions::new()
+            .max_connections(max_connections)
+            .connect_with(options)
+            .await?;
+        Ok(Self(pool))
+    }
+}
+
+impl Deref for ReadPool {
+    type Target = SqlitePool;
+
+    fn deref(&self) -> &SqlitePool {
+        &self.0
+    }
+}
+
+/// Connect and bring the schema up to date.
+pub async fn create_pool(database_url: &str, max_connections: u32) -> anyhow::Result<SqlitePool> {
+    let pool = open_pool(database_url, max_connections).await?;
 
     // Run migrations inline
     sqlx::query(include_str!("../migrations/001_init.sql"))
@@ -41,6 +161,16 @@ pub async fn create_pool(database_url: &str) -> anyhow::Result<SqlitePool> {
     .await
     .ok();
 
+    // Short-lived elevation marker for destructive dashboard actions
+    sqlx::query("ALTER TABLE sessions ADD COLUMN elevated_until TEXT")
+        .execute(&pool)
+        .await
+        .ok();
+    sqlx::query("ALTER TABLE sessions ADD COLUMN ip_address TEXT")
+        .execute(&pool)
+        .await
+        .ok();
+
     // Add payment_address + zcash_uri to invoices for checkout display
     let invoice_upgrades = [
         "ALTER TABLE invoices ADD COLUMN payment_address TEXT NOT NULL DEFAULT ''",
@@ -100,6 +230,20 @@ pub async fn create_pool(database_url: &str) -> anyhow::Result<SqlitePool> {
         .await
         .ok();
 
+    sqlx::query("ALTER TABLE products ADD COLUMN archived_at TEXT")
+        .execute(&pool)
+        .await
+        .ok();
+
+    // Storefront catalog grouping
+    let product_catalog_upgrades = [
+        "ALTER TABLE products ADD COLUMN category TEXT",
+        "ALTER TABLE products ADD COLUMN tags TEXT",
+    ];
+    for sql in &product_catalog_upgrades {
+        sqlx::query(sql).execute(&pool).await.ok();
+    }
+
     sqlx::query("ALTER TABLE invoices ADD COLUMN currency TEXT")
         .execute(&pool)
         .await
@@ -166,11 +310,6 @@ pub async fn create_pool(database_url: &str) -> anyhow::Result<SqlitePool> {
         tracing::info!("Invoices table migration complete");
     }
 
-    sqlx::query("CREATE UNIQUE INDEX IF NOT EXISTS idx_merchants_ufvk ON merchants(ufvk)")
-        .execute(&pool)
-        .await
-        .ok();
-
     // Diversified addresses: per-invoice unique address derivation
     sqlx::query("ALTER TABLE merchants ADD COLUMN diversifier_index INTEGER NOT NULL DEFAULT 0")
         .execute(&pool).await.ok();
@@ -256,6 +395,147 @@ pub async fn create_pool(database_url: &str) -> anyhow::Result<SqlitePool> {
     sqlx::query("DROP TABLE IF EXISTS invoices_old").execute(&pool).await.ok();
     sqlx::query("DROP TABLE IF EXISTS invoices_old2").execute(&pool).await.ok();
 
+    // Merchant-submitted refund transaction, verified by the scanner before marking refunded
+    let refund_upgrades = [
+        "ALTER TABLE invoices ADD COLUMN refund_txid TEXT",
+        "ALTER TABLE invoices ADD COLUMN refund_zatoshis INTEGER",
+        // Why the scanner could not verify the refund; the merchant resolves it by hand
+        "ALTER TABLE invoices ADD COLUMN refund_review TEXT",
+    ];
+    for sql in &refund_upgrades {
+        sqlx::query(sql).execute(&pool).await.ok();
+    }
+
+    // Checkout quantity (units on the invoice, optional per-product cap)
+    sqlx::query("ALTER TABLE invoices ADD COLUMN quantity INTEGER NOT NULL DEFAULT 1")
+        .execute(&pool).await.ok();
+    sqlx::query("ALTER TABLE products ADD COLUMN max_quantity INTEGER")
+        .execute(&pool).await.ok();
+
+    // Sales tax / VAT: merchant settings and the itemized amount on each invoice
+    let tax_upgrades = [
+        "ALTER TABLE merchants ADD COLUMN tax_rate REAL NOT NULL DEFAULT 0",
+        "ALTER TABLE merchants ADD COLUMN tax_inclusive INTEGER NOT NULL DEFAULT 0",
+        "ALTER TABLE merchants ADD COLUMN tax_country_rates TEXT",
+        "ALTER TABLE invoices ADD COLUMN tax_rate REAL",
+        "ALTER TABLE invoices ADD COLUMN tax_amount REAL",
+        "ALTER TABLE invoices ADD COLUMN tax_inclusive INTEGER",
+        "ALTER TABLE invoices ADD COLUMN tax_country TEXT",
+    ];
+    for sql in &tax_upgrades {
+        sqlx::query(sql).execute(&pool).await.ok();
+    }
+
+    // Hosted storefront "about" text
+    sqlx::query("ALTER TABLE merchants ADD COLUMN store_about TEXT")
+        .execute(&pool).await.ok();
+
+    // Vanity slug for public URLs, unique regardless of case
+    sqlx::query("ALTER TABLE merchants ADD COLUMN slug TEXT")
+        .execute(&pool).await.ok();
+    sqlx::query("CREATE UNIQUE INDEX IF NOT EXISTS idx_merchants_slug ON merchants(slug COLLATE NOCASE)")
+        .execute(&pool).await.ok();
+
+    // What happens when an unpaid invoice runs out of time: 'expire' or 'requote'
+    let expiry_upgrades = [
+        "ALTER TABLE invoices ADD COLUMN on_expiry TEXT NOT NULL DEFAULT 'expire'",
+        "ALTER TABLE invoices ADD COLUMN requote_count INTEGER NOT NULL DEFAULT 0",
+    ];
+    for sql in &expiry_upgrades {
+        sqlx::query(sql).execute(&pool).await.ok();
+    }
+
+    // Add 'paid_late' to status CHECK. The table is rebuilt from its current
+    // definition so columns added above carry over. The copy is renamed into
+    // place (rather than renaming the original away) so the REFERENCES clauses
+    // of invoice_events/invoice_payments keep pointing at `invoices`.
+    let invoices_schema: Option<String> = sqlx::query_scalar(
+        "SELECT sql FROM sqlite_master WHERE type='table' AND name='invoices'"
+    ).fetch_optional(&pool).await.ok().flatten();
+    if let Some(schema) = invoices_schema.filter(|s| !s.contains("paid_late")) {
+        match (schema.find('('), schema.contains("'expired', 'refunded'")) {
+            (Some(body), true) => {
+                tracing::info!("Migrating invoices table (adding paid_late status)...");
+                let create = format!("CREATE TABLE invoices_new {}", &schema[body..])
+                    .replace("'expired', 'refunded'", "'expired', 'paid_late', 'refunded'");
+                let mut conn = pool.acquire().await?;
+                sqlx::query("PRAGMA foreign_keys = OFF").execute(&mut *conn).await.ok();
+                let migrated = async {
+                    let mut tx = conn.begin().await?;
+                    sqlx::query(&create).execute(&mut *tx).await?;
+                    sqlx::query("INSERT INTO invoices_new SELECT * FROM invoices").execute(&mut *tx).await?;
+                    sqlx::query("DROP TABLE invoices").execute(&mut *tx).await?;
+                    sqlx::query("ALTER TABLE invoices_new RENAME TO invoices").execute(&mut *tx).await?;
+                    tx.commit().await
+                }.await;
+                for sql in [
+                    "CREATE INDEX IF NOT EXISTS idx_invoices_status ON invoices(status)",
+                    "CREATE INDEX IF NOT EXISTS idx_invoices_memo ON invoices(memo_code)",
+                    "CREATE INDEX IF NOT EXISTS idx_invoices_orchard_receiver ON invoices(orchard_receiver_hex)",
+                ] {
+                    sqlx::query(sql).execute(&mut *conn).await.ok();
+                }
+                sqlx::query("PRAGMA foreign_keys = ON").execute(&mut *conn).await.ok();
+                match migrated {
+                    Ok(()) => tracing::info!("Invoices table migration (paid_late) complete"),
+                    Err(e) => tracing::error!(error = %e, "Invoices table migration (paid_late) failed"),
+                }
+            }
+            _ => tracing::warn!("Unrecognized invoices schema, not adding paid_late status"),
+        }
+    }
+
+    // Buyer-facing display options chosen at checkout (settlement is unaffected)
+    let display_upgrades = [
+        "ALTER TABLE invoices ADD COLUMN display_currency TEXT",
+        "ALTER TABLE invoices ADD COLUMN locale TEXT",
+    ];
+    for sql in &display_upgrades {
+        sqlx::query(sql).execute(&pool).await.ok();
+    }
+
+    // Payment URI the checkout showed (multi or simple), the wallet it named, and whether
+    // the paying transaction carried the fee output
+    let uri_format_upgrades = [
+        "ALTER TABLE invoices ADD COLUMN uri_format TEXT",
+        "ALTER TABLE invoices ADD COLUMN wallet TEXT",
+        "ALTER TABLE invoices ADD COLUMN fee_output_seen INTEGER NOT NULL DEFAULT 0",
+    ];
+    for sql in &uri_format_upgrades {
+        sqlx::query(sql).execute(&pool).await.ok();
+    }
+
+    // TEX addresses: per-merchant opt-in, and the transparent receiver each invoice offered
+    let tex_upgrades = [
+        "ALTER TABLE merchants ADD COLUMN tex_enabled INTEGER NOT NULL DEFAULT 0",
+        "ALTER TABLE invoices ADD COLUMN transparent_receiver_hex TEXT",
+        "ALTER TABLE invoices ADD COLUMN tex_address TEXT",
+    ];
+    for sql in &tex_upgrades {
+        sqlx::query(sql).execute(&pool).await.ok();
+    }
+
+    // Soft deletion: deleted merchants stay as tombstones so a re-registered UFVK carries
+    // on from their diversifier index. Duplicates are found by derived address; a unique
+    // index on the encrypted ufvk never fires, as each ciphertext has its own nonce.
+    sqlx::query("ALTER TABLE merchants ADD COLUMN deleted_at TEXT")
+        .execute(&pool).await.ok();
+    sqlx::query("DROP INDEX IF EXISTS idx_merchants_ufvk")
+        .execute(&pool).await.ok();
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_merchants_payment_address ON merchants(payment_address)")
+        .execute(&pool).await.ok();
+
+    // Nostr notifier: relays, the generated signing key, and the pubkey tagged in notes
+    let nostr_upgrades = [
+        "ALTER TABLE merchants ADD COLUMN nostr_relays TEXT",
+        "ALTER TABLE merchants ADD COLUMN nostr_secret_key TEXT",
+        "ALTER TABLE merchants ADD COLUMN nostr_pubkey TEXT",
+        "ALTER TABLE merchants ADD COLUMN nostr_notify_pubkey TEXT",
+    ];
+    for sql in &nostr_upgrades {
+        sqlx::query(sql).execute(&pool).await.ok();
+    }
+
     // Repair FK references in webhook_deliveries/fee_ledger that may have been
     // auto-rewritten by SQLite during RENAME TABLE (pointing to invoices_old).
     let wd_schema: Option<String> = sqlx::query_scalar(
@@ -317,6 +597,28 @@ pub async fn create_pool(database_url: &str) -> anyhow::Result<SqlitePool> {
     // Re-enable FK enforcement after all migrations
     sqlx::query("PRAGMA foreign_keys = ON").execute(&pool).await.ok();
 
+    // Per-merchant webhook sequence numbers (ordering guarantee for receivers)
+    let webhook_seq_upgrades = [
+        "ALTER TABLE merchants ADD COLUMN webhook_seq INTEGER NOT NULL DEFAULT 0",
+        "ALTER TABLE webhook_deliveries ADD COLUMN sequence INTEGER",
+    ];
+    for sql in &webhook_seq_upgrades {
+        sqlx::query(sql).execute(&pool).await.ok();
+    }
+
+    // Chat notification channels, delivered through the webhook outbox
+    let chat_upgrades = [
+        "ALTER TABLE merchants ADD COLUMN slack_webhook_url TEXT",
+        "ALTER TABLE merchants ADD COLUMN discord_webhook_url TEXT",
+        "ALTER TABLE merchants ADD COLUMN matrix_homeserver TEXT",
+        "ALTER TABLE merchants ADD COLUMN matrix_room_id TEXT",
+        "ALTER TABLE merchants ADD COLUMN matrix_access_token TEXT",
+        "ALTER TABLE webhook_deliveries ADD COLUMN channel TEXT NOT NULL DEFAULT 'webhook'",
+    ];
+    for sql in &chat_upgrades {
+        sqlx::query(sql).execute(&pool).await.ok();
+    }
+
     sqlx::query(
         "CREATE TABLE IF NOT EXISTS recovery_tokens (
             id TEXT PRIMARY KEY,
@@ -335,6 +637,9 @@ pub async fn create_pool(database_url: &str) -> anyhow::Result<SqlitePool> {
         "ALTER TABLE merchants ADD COLUMN trust_tier TEXT NOT NULL DEFAULT 'new'",
         "ALTER TABLE merchants ADD COLUMN billing_status TEXT NOT NULL DEFAULT 'active'",
         "ALTER TABLE merchants ADD COLUMN billing_started_at TEXT",
+        "ALTER TABLE merchants ADD COLUMN fee_exempt INTEGER NOT NULL DEFAULT 0",
+        "ALTER TABLE merchants ADD COLUMN fee_free_until TEXT",
+        "ALTER TABLE merchants ADD COLUMN fee_free_zatoshis INTEGER NOT NULL DEFAULT 0",
     ];
     for sql in &billing_upgrades {
         sqlx::query(sql).execute(&pool).await.ok();
@@ -388,6 +693,31 @@ pub async fn create_pool(database_url: &str) -> anyhow::Result<SqlitePool> {
     sqlx::query("CREATE INDEX IF NOT EXISTS idx_billing_cycles_merchant ON billing_cycles(merchant_id)")
         .execute(&pool).await.ok();
 
+    // Fiat-denominated fees (FEE_CURRENCY): amounts in the cycle's currency alongside ZEC
+    let fiat_fee_upgrades = [
+        "ALTER TABLE fee_ledger ADD COLUMN fee_currency TEXT NOT NULL DEFAULT 'ZEC'",
+        "ALTER TABLE fee_ledger ADD COLUMN fee_amount_fiat REAL",
+        "ALTER TABLE billing_cycles ADD COLUMN fee_currency TEXT NOT NULL DEFAULT 'ZEC'",
+        "ALTER TABLE billing_cycles ADD COLUMN total_fees_fiat REAL NOT NULL DEFAULT 0.0",
+        "ALTER TABLE billing_cycles ADD COLUMN auto_collected_fiat REAL NOT NULL DEFAULT 0.0",
+        "ALTER TABLE billing_cycles ADD COLUMN outstanding_fiat REAL NOT NULL DEFAULT 0.0",
+    ];
+    for sql in &fiat_fee_upgrades {
+        sqlx::query(sql).execute(&pool).await.ok();
+    }
+
+    // Rates a cycle closed at and its fees valued at them, for operator reporting
+    let cycle_close_upgrades = [
+        "ALTER TABLE billing_cycles ADD COLUMN closed_at TEXT",
+        "ALTER TABLE billing_cycles ADD COLUMN close_zec_eur REAL",
+        "ALTER TABLE billing_cycles ADD COLUMN close_zec_usd REAL",
+        "ALTER TABLE billing_cycles ADD COLUMN total_fees_eur REAL",
+        "ALTER TABLE billing_cycles ADD COLUMN total_fees_usd REAL",
+    ];
+    for sql in &cycle_close_upgrades {
+        sqlx::query(sql).execute(&pool).await.ok();
+    }
+
     // Scanner state persistence (crash-safe block height tracking)
     sqlx::query(
         "CREATE TABLE IF NOT EXISTS scanner_state (
@@ -419,6 +749,362 @@ pub async fn create_pool(database_url: &str) -> anyhow::Result<SqlitePool> {
     sqlx::query("CREATE INDEX IF NOT EXISTS idx_x402_merchant ON x402_verifications(merchant_id, created_at)")
         .execute(&pool).await.ok();
 
+    // Invoice lifecycle timeline
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS invoice_events (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            invoice_id TEXT NOT NULL REFERENCES invoices(id),
+            event_type TEXT NOT NULL,
+            txid TEXT,
+            block_height INTEGER,
+            detail TEXT,
+            created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))
+        )"
+    )
+    .execute(&pool)
+    .await
+    .ok();
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_invoice_events_invoice ON invoice_events(invoice_id, id)")
+        .execute(&pool).await.ok();
+
+    // Every transaction that contributed to an invoice (payments can span several txs)
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS invoice_payments (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            invoice_id TEXT NOT NULL REFERENCES invoices(id),
+            txid TEXT NOT NULL,
+            amount_zatoshis INTEGER NOT NULL,
+            block_height INTEGER,
+            seen_at TEXT NOT NULL,
+            UNIQUE(invoice_id, txid)
+        )"
+    )
+    .execute(&pool)
+    .await
+    .ok();
+
+    // Backfill from the single detected_txid kept before this table existed
+    sqlx::query(
+        "INSERT OR IGNORE INTO invoice_payments (invoice_id, txid, amount_zatoshis, seen_at)
+         SELECT id, detected_txid, received_zatoshis, COALESCE(detected_at, created_at)
+         FROM invoices WHERE detected_txid IS NOT NULL AND detected_txid != ''"
+    )
+    .execute(&pool)
+    .await
+    .ok();
+
+    // Product images (files live in MEDIA_DIR or S3; rows hold the storage key)
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS product_images (
+            id TEXT PRIMARY KEY,
+            product_id TEXT NOT NULL REFERENCES products(id),
+            merchant_id TEXT NOT NULL REFERENCES merchants(id),
+            storage_key TEXT NOT NULL UNIQUE,
+            content_type TEXT NOT NULL,
+            size_bytes INTEGER NOT NULL,
+            position INTEGER NOT NULL DEFAULT 0,
+            created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))
+        )"
+    )
+    .execute(&pool)
+    .await
+    .ok();
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_product_images_product ON product_images(product_id, position)")
+        .execute(&pool).await.ok();
+
+    // Outgoing email queue. Bodies are rendered at enqueue time and cleared once sent,
+    // since recovery emails carry a login link.
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS emails (
+            id TEXT PRIMARY KEY,
+            kind TEXT NOT NULL,
+            to_address TEXT NOT NULL,
+            subject TEXT NOT NULL,
+            text_body TEXT,
+            html_body TEXT,
+            status TEXT NOT NULL DEFAULT 'pending'
+                CHECK (status IN ('pending', 'sent', 'failed')),
+            attempts INTEGER NOT NULL DEFAULT 0,
+            last_error TEXT,
+            next_attempt_at TEXT,
+            sent_at TEXT,
+            created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))
+        )"
+    )
+    .execute(&pool)
+    .await
+    .ok();
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_emails_status ON emails(status, next_attempt_at)")
+        .execute(&pool).await.ok();
+
+    // Every price fetched from the feed, for charts and historical lookups
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS rates_history (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            zec_eur REAL NOT NULL,
+            zec_usd REAL NOT NULL,
+            fetched_at TEXT NOT NULL
+        )"
+    )
+    .execute(&pool)
+    .await
+    .ok();
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_rates_history_fetched ON rates_history(fetched_at)")
+        .execute(&pool).await.ok();
+    sqlx::query("ALTER TABLE rates_history ADD COLUMN source TEXT NOT NULL DEFAULT 'feed'")
+        .execute(&pool).await.ok();
+
+    // Shadow scans of recent blocks with a merchant's UFVK (latest per merchant)
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS ufvk_checks (
+            id TEXT PRIMARY KEY,
+            merchant_id TEXT NOT NULL REFERENCES merchants(id),
+            blocks INTEGER NOT NULL,
+            status TEXT NOT NULL CHECK (status IN ('running', 'done', 'failed')),
+            from_height INTEGER,
+            to_height INTEGER,
+            txs_scanned INTEGER NOT NULL DEFAULT 0,
+            outputs_found INTEGER NOT NULL DEFAULT 0,
+            received_zatoshis INTEGER NOT NULL DEFAULT 0,
+            samples TEXT,
+            error TEXT,
+            created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+            finished_at TEXT
+        )"
+    )
+    .execute(&pool)
+    .await
+    .ok();
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_ufvk_checks_merchant ON ufvk_checks(merchant_id)")
+        .execute(&pool).await.ok();
+
+    // Checkout tokens handed out by the public product endpoint, required by /api/checkout
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS checkout_tokens (
+            token_hash TEXT PRIMARY KEY,
+            product_id TEXT NOT NULL,
+            client_ip TEXT,
+            invoices_created INTEGER NOT NULL DEFAULT 0,
+            expires_at TEXT NOT NULL,
+            created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))
+        )"
+    )
+    .execute(&pool)
+    .await
+    .ok();
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_checkout_tokens_ip ON checkout_tokens(client_ip, created_at)")
+        .execute(&pool).await.ok();
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_checkout_tokens_product ON checkout_tokens(product_id, created_at)")
+        .execute(&pool).await.ok();
+
+    // Per-merchant invoice quotas count recent creations
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_invoices_merchant_created ON invoices(merchant_id, created_at)")
+        .execute(&pool).await.ok();
+
+    // Merchant-defined checkout fields: definitions per product, encrypted answers per invoice
+    sqlx::query("ALTER TABLE products ADD COLUMN checkout_fields TEXT")
+        .execute(&pool).await.ok();
+    sqlx::query("ALTER TABLE invoices ADD COLUMN custom_fields TEXT")
+        .execute(&pool).await.ok();
+
+    // Webhook events a merchant subscribes to, comma-separated; NULL is the default set
+    sqlx::query("ALTER TABLE merchants ADD COLUMN webhook_events TEXT")
+        .execute(&pool).await.ok();
+
+    // Strict address mode: payments only match invoices by address, never by memo
+    sqlx::query("ALTER TABLE merchants ADD COLUMN strict_address_mode INTEGER NOT NULL DEFAULT 0")
+        .execute(&pool).await.ok();
+
+    // Network the merchant's UFVK is on; '' until `migrate_networks` fills in older rows
+    sqlx::query("ALTER TABLE merchants ADD COLUMN network TEXT NOT NULL DEFAULT ''")
+        .execute(&pool).await.ok();
+
+    // Payments decrypted for a merchant that matched no open invoice, until attached by hand
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS unmatched_payments (
+            id TEXT PRIMARY KEY,
+            merchant_id TEXT NOT NULL REFERENCES merchants(id),
+            txid TEXT NOT NULL,
+            action_index INTEGER NOT NULL,
+            amount_zatoshis INTEGER NOT NULL,
+            memo TEXT NOT NULL DEFAULT '',
+            diversifier_index INTEGER NOT NULL,
+            address_invoice_id TEXT,
+            block_height INTEGER,
+            seen_at TEXT NOT NULL,
+            attached_invoice_id TEXT,
+            attached_at TEXT,
+            UNIQUE(merchant_id, txid, action_index)
+        )"
+    )
+    .execute(&pool)
+    .await
+    .ok();
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_unmatched_payments_merchant ON unmatched_payments(merchant_id, seen_at)")
+        .execute(&pool).await.ok();
+
+    // Checkout sessions: a buyer's cart, turned into an invoice only when they pay
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS checkout_sessions (
+            id_hash TEXT PRIMARY KEY,
+            product_id TEXT NOT NULL,
+            merchant_id TEXT NOT NULL,
+            client_ip TEXT,
+            variant TEXT,
+            quantity INTEGER NOT NULL DEFAULT 1,
+            country TEXT,
+            refund_address TEXT,
+            display_currency TEXT,
+            locale TEXT,
+            custom_fields TEXT,
+            status TEXT NOT NULL DEFAULT 'open' CHECK (status IN ('open', 'paying', 'converted')),
+            invoice_id TEXT,
+            expires_at TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            updated_at TEXT NOT NULL
+        )"
+    )
+    .execute(&pool)
+    .await
+    .ok();
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_checkout_sessions_ip ON checkout_sessions(client_ip, created_at)")
+        .execute(&pool).await.ok();
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_checkout_sessions_merchant ON checkout_sessions(merchant_id)")
+        .execute(&pool).await.ok();
+
+    // Address book: the merchant's own named wallet addresses (payout, refund source,
+    // settlement source). The address is encrypted; its hash keeps entries unique.
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS merchant_addresses (
+            id TEXT PRIMARY KEY,
+            merchant_id TEXT NOT NULL REFERENCES merchants(id),
+            label TEXT NOT NULL,
+            address TEXT NOT NULL,
+            address_hash TEXT NOT NULL,
+            purpose TEXT NOT NULL CHECK (purpose IN ('payout', 'refund_source', 'settlement_source')),
+            is_default INTEGER NOT NULL DEFAULT 0,
+            created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+            UNIQUE(merchant_id, purpose, address_hash)
+        )"
+    )
+    .execute(&pool)
+    .await
+    .ok();
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_merchant_addresses_merchant ON merchant_addresses(merchant_id, purpose)")
+        .execute(&pool).await.ok();
+
+    // Invoice templates: presets a merchant issues repeat invoices from
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS invoice_templates (
+            id TEXT PRIMARY KEY,
+            merchant_id TEXT NOT NULL REFERENCES merchants(id),
+            name TEXT NOT NULL,
+            price_eur REAL NOT NULL,
+            currency TEXT NOT NULL DEFAULT 'EUR',
+            product_name TEXT,
+            memo_prefix TEXT,
+            expiry_minutes INTEGER,
+            on_expiry TEXT NOT NULL DEFAULT 'expire' CHECK (on_expiry IN ('expire', 'requote')),
+            created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+            updated_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+            UNIQUE(merchant_id, name)
+        )"
+    )
+    .execute(&pool)
+    .await
+    .ok();
+
+    // Settlement invoices name the billing cycle they settle; a cycle has at most one unpaid
+    sqlx::query("ALTER TABLE invoices ADD COLUMN billing_cycle_id TEXT")
+        .execute(&pool).await.ok();
+    sqlx::query(
+        "CREATE UNIQUE INDEX IF NOT EXISTS idx_invoices_open_settlement ON invoices(billing_cycle_id)
+         WHERE billing_cycle_id IS NOT NULL AND status IN ('pending', 'underpaid', 'detected')"
+    )
+    .execute(&pool).await.ok();
+
+    // Short codes a buyer can redeem on the hosted site instead of the invoice URL
+    sqlx::query("ALTER TABLE invoices ADD COLUMN claim_code TEXT")
+        .execute(&pool).await.ok();
+    sqlx::query(
+        "CREATE UNIQUE INDEX IF NOT EXISTS idx_invoices_claim_code ON invoices(claim_code)
+         WHERE claim_code IS NOT NULL"
+    )
+    .execute(&pool).await.ok();
+
+    // Payment requests: invoices emailed to a buyer, with whether the email was opened
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS payment_requests (
+            id TEXT PRIMARY KEY,
+            merchant_id TEXT NOT NULL REFERENCES merchants(id),
+            invoice_id TEXT NOT NULL REFERENCES invoices(id),
+            email TEXT NOT NULL,
+            message TEXT,
+            opened_at TEXT,
+            open_count INTEGER NOT NULL DEFAULT 0,
+            created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))
+        )"
+    )
+    .execute(&pool)
+    .await
+    .ok();
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_payment_requests_merchant ON payment_requests(merchant_id, created_at)")
+        .execute(&pool).await.ok();
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_payment_requests_invoice ON payment_requests(invoice_id)")
+        .execute(&pool).await.ok();
+
+    // What public memo code lookups show of a merchant's invoices (`MemoLookup`)
+    sqlx::query("ALTER TABLE merchants ADD COLUMN memo_lookup TEXT NOT NULL DEFAULT 'full'")
+        .execute(&pool).await.ok();
+
+    // Miner fee and output count of each payment's transaction, for explaining short payments
+    for col in ["fee_zatoshis INTEGER", "tx_outputs INTEGER"] {
+        sqlx::query(&format!("ALTER TABLE invoice_payments ADD COLUMN {}", col))
+            .execute(&pool).await.ok();
+    }
+
+    // Webhook deliveries not about an invoice (catalog events): `invoice_id` becomes optional
+    // and every delivery names its merchant. Rebuilt once, filling in `merchant_id` from
+    // each delivery's invoice.
+    let wd_schema: Option<String> = sqlx::query_scalar(
+        "SELECT sql FROM sqlite_master WHERE type='table' AND name='webhook_deliveries'"
+    ).fetch_optional(&pool).await.ok().flatten();
+    if wd_schema.is_some_and(|schema| !schema.contains("merchant_id")) {
+        tracing::info!("Adding merchant_id to webhook_deliveries...");
+        let mut tx = pool.begin().await?;
+        sqlx::query("ALTER TABLE webhook_deliveries RENAME TO _wd_rebuild")
+            .execute(&mut *tx).await?;
+        sqlx::query(
+            "CREATE TABLE webhook_deliveries (
+                id TEXT PRIMARY KEY,
+                invoice_id TEXT REFERENCES invoices(id),
+                merchant_id TEXT REFERENCES merchants(id),
+                url TEXT NOT NULL,
+                payload TEXT NOT NULL,
+                status TEXT NOT NULL DEFAULT 'pending'
+                    CHECK (status IN ('pending', 'delivered', 'failed')),
+                attempts INTEGER NOT NULL DEFAULT 0,
+                last_attempt_at TEXT,
+                next_retry_at TEXT,
+                created_at TEXT NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%SZ', 'now')),
+                sequence INTEGER,
+                channel TEXT NOT NULL DEFAULT 'webhook'
+            )"
+        ).execute(&mut *tx).await?;
+        sqlx::query(
+            "INSERT INTO webhook_deliveries (id, invoice_id, merchant_id, url, payload, status, attempts,
+                last_attempt_at, next_retry_at, created_at, sequence, channel)
+             SELECT wd.id, wd.invoice_id, i.merchant_id, wd.url, wd.payload, wd.status, wd.attempts,
+                wd.last_attempt_at, wd.next_retry_at, wd.created_at, wd.sequence, wd.channel
+             FROM _wd_rebuild wd LEFT JOIN invoices i ON i.id = wd.invoice_id"
+        ).execute(&mut *tx).await?;
+        sqlx::query("DROP TABLE _wd_rebuild").execute(&mut *tx).await?;
+        tx.commit().await?;
+    }
+    sqlx::query("CREATE INDEX IF NOT EXISTS idx_webhook_deliveries_pending ON webhook_deliveries(merchant_id, status)")
+        .execute(&pool).await.ok();
+
     tracing::info!("Database ready (SQLite)");
     Ok(pool)
 }
@@ -446,7 +1132,7 @@ pub async fn set_scanner_state(pool: &SqlitePool, key: &str, value: &str) -> any
     Ok(())
 }
 
-/// Periodic data purge: cleans up expired sessions, old webhook deliveries,
+/// Periodic data purge: cleans up expired sessions, checkout tokens and checkout sessions, old webhook deliveries,
 /// expired recovery tokens, and optionally old expired/refunded invoices.
 pub async fn run_data_purge(pool: &SqlitePool, purge_days: i64) -> anyhow::Result<()> {
     let cutoff = format!("-{} days", purge_days);
@@ -461,18 +1147,38 @@ pub async fn run_data_purge(pool: &SqlitePool, purge_days: i64) -> anyhow::Resul
         "DELETE FROM recovery_tokens WHERE expires_at < strftime('%Y-%m-%dT%H:%M:%SZ', 'now')"
     ).execute(pool).await?;
 
+    // Checkout tokens, once out of the per-IP counting window
+    let checkout_tokens = sqlx::query(
+        "DELETE FROM checkout_tokens WHERE expires_at < strftime('%Y-%m-%dT%H:%M:%SZ', 'now', '-1 day')"
+    ).execute(pool).await?;
+
+    // Expired checkout sessions, paid or not (a paid one's cart lives on in its invoice)
+    let checkout_sessions = sqlx::query(
+        "DELETE FROM checkout_sessions WHERE expires_at < strftime('%Y-%m-%dT%H:%M:%SZ', 'now')"
+    ).execute(pool).await?;
+
     // Old delivered/failed webhook deliveries
     let webhooks = sqlx::query(
         "DELETE FROM webhook_deliveries WHERE status IN ('delivered', 'failed')
          AND created_at < strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?)"
     ).bind(&cutoff).execute(pool).await?;
 
-    let total = sessions.rows_affected() + tokens.rows_affected() + webhooks.rows_affected();
+    // Old sent/failed emails
+    let emails = sqlx::query(
+        "DELETE FROM emails WHERE status IN ('sent', 'failed')
+         AND created_at < strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?)"
+    ).bind(&cutoff).execute(pool).await?;
+
+    let total = sessions.rows_affected() + tokens.rows_affected() + checkout_tokens.rows_affected()
+        + checkout_sessions.rows_affected() + webhooks.rows_affected() + emails.rows_affected();
     if total > 0 {
         tracing::info!(
             sessions = sessions.rows_affected(),
             tokens = tokens.rows_affected(),
+            checkout_tokens = checkout_tokens.rows_affected(),
+            checkout_sessions = checkout_sessions.rows_affected(),
             webhooks = webhooks.rows_affected(),
+            emails = emails.rows_affected(),
             "Data purge completed"
         );
     }
@@ -538,3 +1244,334 @@ pub async fn migrate_encrypt_ufvks(pool: &SqlitePool, encryption_key: &str) -> a
     tracing::info!("UFVK encryption migration complete");
     Ok(())
 }
+
+/// Put merchants and the scanner's height from before multi-network support on `network`,
+/// the only one the instance served then.
+pub async fn migrate_networks(pool: &SqlitePool, network: &str) -> anyhow::Result<()> {
+    let merchants = sqlx::query("UPDATE merchants SET network = ? WHERE network = ''")
+        .bind(network)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    if merchants > 0 {
+        tracing::info!(count = merchants, network, "Merchants assigned to the primary network");
+    }
+    sqlx::query(
+        "INSERT OR IGNORE INTO scanner_state (key, value)
+         SELECT ?, value FROM scanner_state WHERE key = 'last_height'"
+    )
+    .bind(height_key(network))
+    .execute(pool)
+    .await?;
+    sqlx::query("DELETE FROM scanner_state WHERE key = 'last_height'")
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// `scanner_state` key of the last block height scanned on `network`.
+pub fn height_key(network: &str) -> String {
+    format!("last_height:{}", network)
+}
+
+/// A fresh database with the full schema, in a temporary file (an in-memory database
+/// would be a different one on each pooled connection).
+#[cfg(test)]
+pub(crate) async fn test_pool() -> SqlitePool {
+    let path = std::env::temp_dir().join(format!("cipherpay-unit-{}.db", uuid::Uuid::new_v4()));
+    create_pool(&format!("sqlite:{}?mode=rwc", path.display()), 5).await.unwrap()
+}
+
+/// A merchant registered with the `n`th fixture UFVK.
+#[cfg(test)]
+pub(crate) async fn test_merchant(pool: &SqlitePool, n: u8) -> crate::merchants::CreateMerchantResponse {
+    crate::merchants::create_merchant(pool, &crate::merchants::CreateMerchantRequest {
+        name: Some("Shop".into()),
+        ufvk: crate::scanner::fixtures::test_ufvk(n),
+        webhook_url: None,
+        email: None,
+        verify_blocks: None,
+    }, "").await.unwrap()
+}
+
+/// A 10 EUR invoice (0.25 ZEC at 40 EUR) for a merchant from `test_merchant(pool, n)`.
+#[cfg(test)]
+pub(crate) async fn test_invoice(pool: &SqlitePool, merchant_id: &str, n: u8) -> crate::invoices::CreateInvoiceResponse {
+    let req = crate::invoices::CreateInvoiceRequest {
+        product_id: None,
+        product_name: Some("Shirt".into()),
+        size: None,
+        quantity: None,
+        price_eur: 10.0,
+        currency: None,
+        refund_address: None,
+        tax: None,
+        on_expiry: None,
+        display_currency: None,
+        locale: None,
+        custom_fields: None,
+        memo_prefix: None,
+        expiry_minutes: None,
+        claim_code: true,
+    };
+    let quotas = crate::invoices::InvoiceQuotas { max_open: 100, max_per_hour: 100, min_fiat: 0.0, min_zatoshis: 0 };
+    let ufvk = crate::scanner::fixtures::test_ufvk(n);
+    crate::invoices::create_invoice(pool, merchant_id, &ufvk, &req, 40.0, 44.0, 30, None, &quotas)
+        .await
+        .unwrap()
+}
+
+/// Run the hand-written queries that map rows to structs against the migrated schema, so a
+/// column missing from a SELECT fails here rather than at runtime.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::invoices::views::MemoLookup;
+    use crate::invoices::{self, InvoiceFilter};
+    use crate::merchants;
+    use crate::products::{self, sessions, CreateProductRequest};
+
+    async fn merchant_with_invoice(pool: &SqlitePool) -> (merchants::CreateMerchantResponse, invoices::CreateInvoiceResponse) {
+        let merchant = test_merchant(pool, 1).await;
+        let invoice = test_invoice(pool, &merchant.merchant_id, 1).await;
+        (merchant, invoice)
+    }
+
+    #[tokio::test]
+    async fn test_invoice_queries_map_rows() {
+        let pool = test_pool().await;
+        let (merchant, created) = merchant_with_invoice(&pool).await;
+        let id = created.invoice_id.as_str();
+
+        let invoice = invoices::get_invoice(&pool, id).await.unwrap().unwrap();
+        assert_eq!(invoice.merchant_name.as_deref(), Some("Shop"));
+        assert_eq!(invoice.price_zatoshis, 25_000_000);
+        assert_eq!(invoices::get_invoice_by_memo(&pool, &created.memo_code).await.unwrap().unwrap().id, id);
+        assert_eq!(created.memo_code.len(), "CP-".len() + 16);
+        let claim_code = created.claim_code.as_deref().unwrap();
+        assert_eq!(invoices::get_invoice_by_claim_code(&pool, claim_code).await.unwrap().unwrap().id, id);
+        assert_eq!(invoices::memo_lookup(&pool, &merchant.merchant_id).await.unwrap(), MemoLookup::Full);
+        let listed = invoices::list_for_merchant(&pool, &merchant.merchant_id, &InvoiceFilter::default(), 10).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        let filter = InvoiceFilter::default();
+        let exported: Vec<_> = futures::StreamExt::collect(invoices::stream_for_merchant(&pool, &merchant.merchant_id, &filter)).await;
+        assert_eq!(exported.into_iter().map(|r| r.unwrap().id).collect::<Vec<_>>(), [id]);
+        assert_eq!(invoices::get_pending_invoices(&pool, "testnet").await.unwrap().len(), 1);
+        let receiver = invoice.orchard_receiver_hex.as_deref().unwrap();
+        assert_eq!(invoices::find_by_orchard_receiver(&pool, receiver).await.unwrap().unwrap().id, id);
+        assert_eq!(invoices::get_invoice_status(&pool, id).await.unwrap().unwrap().status, "pending");
+        assert_eq!(invoices::confirmations(&pool, id).await.unwrap(), None);
+        assert!(invoices::get_payments(&pool, id).await.unwrap().is_empty());
+        assert!(invoices::get_pending_refunds(&pool, "testnet").await.unwrap().is_empty());
+        assert_eq!(invoices::events::list(&pool, id).await.unwrap()[0].event_type, "created");
+        let events = invoices::events::since_for_merchant(&pool, &merchant.merchant_id, 0, 10).await.unwrap();
+        assert_eq!(events[0].invoice_status, "pending");
+
+        sqlx::query("UPDATE invoices SET status = 'expired' WHERE id = ?").bind(id).execute(&pool).await.unwrap();
+        assert_eq!(invoices::get_recently_expired(&pool, "testnet", 60).await.unwrap()[0].id, id);
+    }
+
+    #[tokio::test]
+    async fn test_merchant_and_product_queries_map_rows() {
+        let pool = test_pool().await;
+        let (merchant, _) = merchant_with_invoice(&pool).await;
+        let merchant_id = merchant.merchant_id.as_str();
+
+        assert_eq!(merchants::get_all_merchants(&pool, "").await.unwrap().len(), 1);
+        assert!(merchants::authenticate(&pool, &merchant.api_key, "").await.unwrap().is_some());
+        assert!(merchants::authenticate_dashboard(&pool, &merchant.dashboard_token, "").await.unwrap().is_some());
+        sqlx::query(
+            "INSERT INTO billing_cycles (id, merchant_id, period_start, period_end) VALUES ('c-1', ?, '2030-01-01', '2030-02-01')"
+        ).bind(merchant_id).execute(&pool).await.unwrap();
+        assert_eq!(crate::billing::get_billing_history(&pool, merchant_id).await.unwrap().len(), 1);
+
+        let product = products::create_product(&pool, merchant_id, &CreateProductRequest {
+            slug: "shirt".into(),
+            name: "Shirt".into(),
+            description: None,
+            price_eur: 10.0,
+            currency: None,
+            variants: None,
+            category: None,
+            tags: None,
+            max_quantity: None,
+            checkout_fields: None,
+        }).await.unwrap();
+        assert!(products::get_product(&pool, &product.id).await.unwrap().is_some());
+        assert!(products::get_product_by_slug(&pool, merchant_id, "shirt").await.unwrap().is_some());
+        assert_eq!(products::list_products(&pool, merchant_id, false).await.unwrap().len(), 1);
+        assert_eq!(products::list_catalog(&pool, merchant_id, None, None, 10, 0).await.unwrap().1, 1);
+        assert!(products::list_images(&pool, &product.id).await.unwrap().is_empty());
+
+        let cart = sessions::Cart { quantity: 2, ..Default::default() };
+        let session_id = sessions::create(&pool, &product.id, merchant_id, None, &cart).await.unwrap().unwrap();
+        assert_eq!(sessions::get(&pool, &session_id).await.unwrap().unwrap().quantity, 2);
+        assert!(crate::scanner::unmatched::list(&pool, merchant_id, true, 10, 0).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_original_schema_is_brought_up_to_date() {
+        // A deployment from before the inline upgrades has only the initial migration.
+        let path = std::env::temp_dir().join(format!("cipherpay-unit-{}.db", uuid::Uuid::new_v4()));
+        let url = format!("sqlite:{}?mode=rwc", path.display());
+        let old = open_pool(&url, 1).await.unwrap();
+        sqlx::raw_sql(include_str!("../migrations/001_init.sql")).execute(&old).await.unwrap();
+        old.close().await;
+
+        let pool = create_pool(&url, 5).await.unwrap();
+        for table in ["invoice_events", "invoice_payments", "product_images", "emails"] {
+            let found: Option<String> = sqlx::query_scalar("SELECT name FROM sqlite_master WHERE type = 'table' AND name = ?")
+                .bind(table).fetch_optional(&pool).await.unwrap();
+            assert!(found.is_some(), "missing table {}", table);
+        }
+        let session_cols: Vec<String> = sqlx::query_scalar("SELECT name FROM pragma_table_info('sessions')")
+            .fetch_all(&pool).await.unwrap();
+        assert!(session_cols.iter().any(|c| c == "elevated_until") && session_cols.iter().any(|c| c == "ip_address"));
+
+        let (_, created) = merchant_with_invoice(&pool).await;
+        sqlx::query("UPDATE invoices SET status = 'paid_late' WHERE id = ?")
+            .bind(&created.invoice_id).execute(&pool).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_session_rotation_and_absolute_age() {
+        let pool = test_pool().await;
+        let (merchant, _) = merchant_with_invoice(&pool).await;
+        let merchant_id = merchant.merchant_id.as_str();
+
+        let old = merchants::create_session(&pool, merchant_id, Some("127.0.0.1"), 24).await.unwrap();
+        let new = merchants::rotate_session(&pool, &old, Some("2099-01-01T00:00:00Z")).await.unwrap().unwrap();
+        assert_ne!(old, new);
+        assert!(merchants::get_by_session(&pool, &old, 24, "").await.unwrap().is_none());
+        assert!(merchants::get_by_session(&pool, &new, 24, "").await.unwrap().is_some());
+        assert!(merchants::rotate_session(&pool, &old, None).await.unwrap().is_none());
+
+        // Rotation keeps the original login time, so it cannot outlive the age limit.
+        sqlx::query("UPDATE sessions SET created_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now', '-3 hours') WHERE id = ?")
+            .bind(&new).execute(&pool).await.unwrap();
+        let rotated = merchants::rotate_session(&pool, &new, None).await.unwrap().unwrap();
+        assert!(merchants::get_by_session(&pool, &rotated, 24, "").await.unwrap().is_some());
+        assert!(merchants::get_by_session(&pool, &rotated, 2, "").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_sandbox_reset_keeps_the_account() {
+        let pool = test_pool().await;
+        let (merchant, created) = merchant_with_invoice(&pool).await;
+        let merchant_id = merchant.merchant_id.as_str();
+        invoices::record_payment(&pool, &created.invoice_id, "tx", 1, None).await.unwrap();
+        products::create_product(&pool, merchant_id, &CreateProductRequest {
+            slug: "shirt".into(),
+            name: "Shirt".into(),
+            description: None,
+            price_eur: 10.0,
+            currency: None,
+            variants: None,
+            category: None,
+            tags: None,
+            max_quantity: None,
+            checkout_fields: None,
+        }).await.unwrap();
+
+        let reset = merchants::reset_sandbox(&pool, merchant_id).await.unwrap();
+        assert_eq!((reset.invoices, reset.products), (1, 1));
+        assert!(invoices::get_invoice(&pool, &created.invoice_id).await.unwrap().is_none());
+        assert!(products::list_products(&pool, merchant_id, true).await.unwrap().is_empty());
+        assert_eq!(merchants::stats(&pool, merchant_id).await.total_invoices, 0);
+        assert!(merchants::authenticate(&pool, &merchant.api_key, "").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_migrate_networks_keeps_merchants_and_height() {
+        let pool = test_pool().await;
+        let (merchant, created) = merchant_with_invoice(&pool).await;
+        sqlx::query("UPDATE merchants SET network = ''").execute(&pool).await.unwrap();
+        set_scanner_state(&pool, "last_height", "1000").await.unwrap();
+
+        migrate_networks(&pool, "testnet").await.unwrap();
+        let merchant = merchants::authenticate(&pool, &merchant.api_key, "").await.unwrap().unwrap();
+        assert_eq!(merchant.network, "testnet");
+        assert_eq!(get_scanner_state(&pool, &height_key("testnet")).await.as_deref(), Some("1000"));
+        assert_eq!(get_scanner_state(&pool, "last_height").await, None);
+        assert!(invoices::get_pending_invoices(&pool, "mainnet").await.unwrap().is_empty());
+
+        invoices::record_payment(&pool, &created.invoice_id, "tx", 1, Some(991)).await.unwrap();
+        assert_eq!(invoices::confirmations(&pool, &created.invoice_id).await.unwrap(), Some(10));
+    }
+
+    #[tokio::test]
+    async fn test_diversifier_index_jumps_ahead_and_stops_at_the_end() {
+        let pool = test_pool().await;
+        let (merchant, created) = merchant_with_invoice(&pool).await;
+        let merchant_id = merchant.merchant_id.as_str();
+        let invoice = invoices::get_invoice(&pool, &created.invoice_id).await.unwrap().unwrap();
+        assert_eq!(invoice.diversifier_index, Some(1));
+
+        // A counter set back behind the invoice's index must not hand it out again.
+        sqlx::query("UPDATE merchants SET diversifier_index = 1 WHERE id = ?").bind(merchant_id).execute(&pool).await.unwrap();
+        assert_eq!(merchants::next_diversifier_index(&pool, merchant_id).await.unwrap(), 2);
+        assert_eq!(merchants::next_diversifier_index(&pool, merchant_id).await.unwrap(), 3);
+
+        let usage = merchants::diversifier_usage(&pool, 10).await.unwrap();
+        assert_eq!((usage[0].next_index, usage[0].invoices, usage[0].skipped), (4, 1, 2));
+
+        sqlx::query("UPDATE merchants SET diversifier_index = ? WHERE id = ?")
+            .bind(merchants::MAX_DIVERSIFIER_INDEX).bind(merchant_id).execute(&pool).await.unwrap();
+        assert_eq!(merchants::next_diversifier_index(&pool, merchant_id).await.unwrap(), u32::MAX);
+        assert!(matches!(
+            merchants::next_diversifier_index(&pool, merchant_id).await,
+            Err(merchants::MerchantError::DiversifierExhausted)
+        ));
+        assert!(matches!(
+            merchants::next_diversifier_index(&pool, "missing").await,
+            Err(merchants::MerchantError::NotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_worker_pool_reads_while_api_connections_are_busy() {
+        let path = std::env::temp_dir().join(format!("cipherpay-unit-{}.db", uuid::Uuid::new_v4()));
+        let url = format!("sqlite:{}?mode=rwc", path.display());
+        let api = create_pool(&url, 2).await.unwrap();
+        let worker = open_pool(&url, 1).await.unwrap();
+        let (_, created) = merchant_with_invoice(&api).await;
+
+        // Every request connection held, as by slow handlers: the scanner still reads.
+        let _held = (api.acquire().await.unwrap(), api.acquire().await.unwrap());
+        let pending = tokio::time::timeout(Duration::from_secs(2), invoices::get_pending_invoices(&worker, "testnet"))
+            .await
+            .expect("worker pool waited on the API pool")
+            .unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, created.invoice_id);
+    }
+
+    #[tokio::test]
+    async fn test_read_pool_serves_a_replica_read_only() {
+        let path = std::env::temp_dir().join(format!("cipherpay-unit-{}.db", uuid::Uuid::new_v4()));
+        let primary = create_pool(&format!("sqlite:{}?mode=rwc", path.display()), 1).await.unwrap();
+        let (merchant, _) = merchant_with_invoice(&primary).await;
+
+        let replica = ReadPool::replica(&format!("sqlite:{}", path.display()), 1).await.unwrap();
+        let listed = invoices::list_for_merchant(&replica, &merchant.merchant_id, &InvoiceFilter::default(), 10).await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert!(sqlx::query("DELETE FROM invoices").execute(&*replica).await.is_err());
+
+        let fallback = ReadPool::primary(&primary);
+        assert_eq!(invoices::list_for_merchant(&fallback, &merchant.merchant_id, &InvoiceFilter::default(), 10).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "begin_write nested")]
+    async fn test_nested_write_transaction_panics_instead_of_deadlocking() {
+        let pool = test_pool().await;
+        // Spawned, as handlers and jobs are: the check goes by task ID.
+        let nested = tokio::spawn(async move {
+            let _outer = begin_write(&pool).await.unwrap();
+            let _inner = begin_write(&pool).await;
+        });
+        if let Err(e) = nested.await {
+            std::panic::resume_unwind(e.into_panic());
+        }
+    }
+}
@@ -1,49 +1,647 @@
-use crate::config::Config;
-use lettre::message::header::ContentType;
+use std::sync::OnceLock;
+
+use crate::config::{Config, SmtpTls};
+use lettre::message::MultiPart;
 use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::PoolConfig;
 use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use chrono::Utc;
+use sqlx::SqlitePool;
+use tera::{Context, Tera};
+use uuid::Uuid;
+
+/// Built-in templates. Each email has an `.html` and a `.txt` variant; HTML ones
+/// extend `base.html`. Operators override any of them by dropping a file with the
+/// same name into EMAIL_TEMPLATES_DIR.
+const DEFAULT_TEMPLATES: &[(&str, &str)] = &[
+    ("base.html", include_str!("../templates/email/base.html")),
+    ("recovery.html", include_str!("../templates/email/recovery.html")),
+    ("recovery.txt", include_str!("../templates/email/recovery.txt")),
+    ("receipt.html", include_str!("../templates/email/receipt.html")),
+    ("receipt.txt", include_str!("../templates/email/receipt.txt")),
+    ("billing_notice.html", include_str!("../templates/email/billing_notice.html")),
+    ("billing_notice.txt", include_str!("../templates/email/billing_notice.txt")),
+    ("dunning.html", include_str!("../templates/email/dunning.html")),
+    ("dunning.txt", include_str!("../templates/email/dunning.txt")),
+    ("late_payment.html", include_str!("../templates/email/late_payment.html")),
+    ("late_payment.txt", include_str!("../templates/email/late_payment.txt")),
+    ("unmatched_payment.html", include_str!("../templates/email/unmatched_payment.html")),
+    ("unmatched_payment.txt", include_str!("../templates/email/unmatched_payment.txt")),
+    ("alert.html", include_str!("../templates/email/alert.html")),
+    ("alert.txt", include_str!("../templates/email/alert.txt")),
+    ("payment_request.html", include_str!("../templates/email/payment_request.html")),
+    ("payment_request.txt", include_str!("../templates/email/payment_request.txt")),
+];
+
+static TEMPLATES: OnceLock<Tera> = OnceLock::new();
+
+fn build_templates(overrides_dir: Option<&str>) -> anyhow::Result<Tera> {
+    let mut sources: Vec<(&str, String)> = Vec::with_capacity(DEFAULT_TEMPLATES.len());
+    for (name, default) in DEFAULT_TEMPLATES {
+        let custom = overrides_dir.and_then(|dir| {
+            std::fs::read_to_string(std::path::Path::new(dir).join(name)).ok()
+        });
+        if custom.is_some() {
+            tracing::info!(template = name, "Using email template override");
+        }
+        sources.push((name, custom.unwrap_or_else(|| default.to_string())));
+    }
+
+    let mut tera = Tera::default();
+    tera.add_raw_templates(sources)?;
+    Ok(tera)
+}
+
+/// Compiled templates, loaded once. A broken override set falls back to the
+/// built-in templates so a bad edit never stops recovery emails going out.
+fn templates(config: &Config) -> &'static Tera {
+    TEMPLATES.get_or_init(|| {
+        match build_templates(config.email_templates_dir.as_deref()) {
+            Ok(t) => t,
+            Err(e) => {
+                tracing::error!(error = %e, "Invalid email template overrides, using defaults");
+                build_templates(None).expect("built-in email templates compile")
+            }
+        }
+    })
+}
+
+fn render(config: &Config, name: &str, ctx: &Context) -> anyhow::Result<(String, String)> {
+    let tera = templates(config);
+    let html = tera.render(&format!("{}.html", name), ctx)?;
+    let text = tera.render(&format!("{}.txt", name), ctx)?;
+    Ok((text, html))
+}
+
+static TRANSPORT: OnceLock<AsyncSmtpTransport<Tokio1Executor>> = OnceLock::new();
 
-pub async fn send_recovery_email(config: &Config, to: &str, token: &str) -> anyhow::Result<()> {
+fn build_transport(config: &Config) -> anyhow::Result<AsyncSmtpTransport<Tokio1Executor>> {
     let smtp_host = config.smtp_host.as_deref()
         .ok_or_else(|| anyhow::anyhow!("SMTP not configured"))?;
+
+    let mut builder = match config.smtp_tls {
+        SmtpTls::Implicit => AsyncSmtpTransport::<Tokio1Executor>::relay(smtp_host)?,
+        SmtpTls::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(smtp_host)?,
+        SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(smtp_host),
+    };
+
+    if let Some(port) = config.smtp_port {
+        builder = builder.port(port);
+    }
+    if let (Some(user), Some(pass)) = (&config.smtp_user, &config.smtp_pass) {
+        builder = builder.credentials(Credentials::new(user.clone(), pass.clone()));
+    }
+
+    Ok(builder
+        .pool_config(PoolConfig::new().max_size(config.smtp_pool_size.max(1)))
+        .build())
+}
+
+/// Shared pooled transport, so connections are reused across emails.
+fn transport(config: &Config) -> anyhow::Result<&'static AsyncSmtpTransport<Tokio1Executor>> {
+    if let Some(t) = TRANSPORT.get() {
+        return Ok(t);
+    }
+    let t = build_transport(config)?;
+    Ok(TRANSPORT.get_or_init(|| t))
+}
+
+/// Connect to the SMTP server and run the handshake (TLS and AUTH included)
+/// without sending anything.
+pub async fn check_connection(config: &Config) -> anyhow::Result<()> {
+    if !transport(config)?.test_connection().await? {
+        anyhow::bail!("SMTP server did not accept the connection");
+    }
+    Ok(())
+}
+
+/// Give up on an email after this many failed attempts.
+const MAX_ATTEMPTS: i64 = 6;
+
+fn retry_delay_secs(attempt: i64) -> i64 {
+    match attempt {
+        1 => 30,
+        2 => 120,
+        3 => 600,
+        4 => 1800,
+        _ => 7200,
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct QueuedEmail {
+    id: String,
+    to_address: String,
+    subject: String,
+    text_body: Option<String>,
+    html_body: Option<String>,
+    attempts: i64,
+}
+
+/// Render an email into the queue and try to deliver it straight away. A failed
+/// attempt stays queued and is retried by `drain_queue`, so callers only see
+/// errors from rendering or enqueueing.
+async fn send(
+    pool: &SqlitePool,
+    config: &Config,
+    to: &str,
+    subject: &str,
+    template: &str,
+    ctx: &Context,
+) -> anyhow::Result<()> {
+    let (text, html) = render(config, template, ctx)?;
+    let id = Uuid::new_v4().to_string();
+
+    sqlx::query(
+        "INSERT INTO emails (id, kind, to_address, subject, text_body, html_body) VALUES (?, ?, ?, ?, ?, ?)"
+    )
+    .bind(&id)
+    .bind(template)
+    .bind(to)
+    .bind(subject)
+    .bind(&text)
+    .bind(&html)
+    .execute(pool)
+    .await?;
+
+    let email = QueuedEmail {
+        id,
+        to_address: to.to_string(),
+        subject: subject.to_string(),
+        text_body: Some(text),
+        html_body: Some(html),
+        attempts: 0,
+    };
+    attempt_delivery(pool, config, &email).await?;
+    Ok(())
+}
+
+/// The message for a queued email: text and HTML alternatives from SMTP_FROM.
+fn build_message(config: &Config, email: &QueuedEmail) -> anyhow::Result<Message> {
     let from = config.smtp_from.as_deref()
         .ok_or_else(|| anyhow::anyhow!("SMTP_FROM not configured"))?;
 
-    let frontend_url = config.frontend_url.as_deref().unwrap_or("http://localhost:3000");
-    let recovery_link = format!("{}/dashboard/recover/confirm?token={}", frontend_url, token);
-
-    let body = format!(
-        "CipherPay Account Recovery\n\
-         \n\
-         Someone requested a recovery link for the merchant account associated with this email.\n\
-         \n\
-         Click the link below to get a new dashboard token:\n\
-         {}\n\
-         \n\
-         This link expires in 1 hour.\n\
-         \n\
-         If you did not request this, you can safely ignore this email.\n\
-         \n\
-         — CipherPay",
-        recovery_link
-    );
-
-    let email = Message::builder()
+    Ok(Message::builder()
         .from(from.parse()?)
-        .to(to.parse()?)
-        .subject("CipherPay: Account Recovery")
-        .header(ContentType::TEXT_PLAIN)
-        .body(body)?;
+        .to(email.to_address.parse()?)
+        .subject(&email.subject)
+        .multipart(MultiPart::alternative_plain_html(
+            email.text_body.clone().unwrap_or_default(),
+            email.html_body.clone().unwrap_or_default(),
+        ))?)
+}
+
+async fn deliver(config: &Config, email: &QueuedEmail) -> anyhow::Result<()> {
+    let message = build_message(config, email)?;
+    transport(config)?.send(message).await?;
+    Ok(())
+}
 
-    let mut transport_builder = AsyncSmtpTransport::<Tokio1Executor>::relay(smtp_host)?;
+/// Claim one attempt on a queued email and send it. The claim is a conditional
+/// update on the attempt counter, so the worker and an inline send never both
+/// deliver the same row.
+async fn attempt_delivery(pool: &SqlitePool, config: &Config, email: &QueuedEmail) -> anyhow::Result<bool> {
+    let attempt = email.attempts + 1;
+    let next_attempt = (Utc::now() + chrono::Duration::seconds(retry_delay_secs(attempt)))
+        .format("%Y-%m-%dT%H:%M:%SZ").to_string();
 
-    if let (Some(user), Some(pass)) = (&config.smtp_user, &config.smtp_pass) {
-        transport_builder = transport_builder.credentials(Credentials::new(user.clone(), pass.clone()));
+    let claimed = sqlx::query(
+        "UPDATE emails SET attempts = ?, next_attempt_at = ?
+         WHERE id = ? AND status = 'pending' AND attempts = ?"
+    )
+    .bind(attempt)
+    .bind(&next_attempt)
+    .bind(&email.id)
+    .bind(email.attempts)
+    .execute(pool)
+    .await?;
+    if claimed.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    match deliver(config, email).await {
+        Ok(()) => {
+            let now = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+            sqlx::query(
+                "UPDATE emails SET status = 'sent', sent_at = ?, last_error = NULL,
+                 text_body = NULL, html_body = NULL WHERE id = ?"
+            )
+            .bind(&now)
+            .bind(&email.id)
+            .execute(pool)
+            .await?;
+            Ok(true)
+        }
+        Err(e) => {
+            let status = if attempt >= MAX_ATTEMPTS { "failed" } else { "pending" };
+            sqlx::query("UPDATE emails SET status = ?, last_error = ? WHERE id = ?")
+                .bind(status)
+                .bind(format!("{:#}", e))
+                .bind(&email.id)
+                .execute(pool)
+                .await?;
+            tracing::warn!(email_id = %email.id, attempt, status, error = %e, "Email delivery failed");
+            Ok(false)
+        }
+    }
+}
+
+/// Retry queued emails whose backoff has elapsed. Run periodically.
+pub async fn drain_queue(pool: &SqlitePool, config: &Config) -> anyhow::Result<()> {
+    if !config.smtp_configured() {
+        return Ok(());
+    }
+    let now = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+
+    let rows = sqlx::query_as::<_, QueuedEmail>(
+        "SELECT id, to_address, subject, text_body, html_body, attempts FROM emails
+         WHERE status = 'pending' AND (next_attempt_at IS NULL OR next_attempt_at <= ?)
+         ORDER BY created_at ASC LIMIT 50"
+    )
+    .bind(&now)
+    .fetch_all(pool)
+    .await?;
+
+    for email in rows {
+        if attempt_delivery(pool, config, &email).await? {
+            tracing::info!(email_id = %email.id, attempt = email.attempts + 1, "Queued email delivered");
+        }
+    }
+    Ok(())
+}
+
+fn frontend_url(config: &Config) -> &str {
+    config.frontend_url.as_deref().unwrap_or("http://localhost:3000")
+}
+
+/// Where this API is reachable from the internet.
+pub fn public_api_url(config: &Config) -> String {
+    config.public_api_url.clone().unwrap_or_else(|| format!("http://localhost:{}", config.api_port))
+}
+
+/// The hosted payment page of an invoice.
+pub fn payment_page_link(config: &Config, invoice_id: &str) -> String {
+    format!("{}/pay/{}", frontend_url(config), invoice_id)
+}
+
+pub async fn send_recovery_email(pool: &SqlitePool, config: &Config, to: &str, token: &str) -> anyhow::Result<()> {
+    let mut ctx = Context::new();
+    ctx.insert("recovery_link", &format!("{}/dashboard/recover/confirm?token={}", frontend_url(config), token));
+    ctx.insert("expires_in", "1 hour");
+
+    send(pool, config, to, "CipherPay: Account Recovery", "recovery", &ctx).await?;
+
+    tracing::info!(to, "Recovery email queued");
+    Ok(())
+}
+
+/// Buyer-facing payment receipt. Amounts are in `currency` and formatted for `locale`.
+#[allow(dead_code)]
+pub struct Receipt<'a> {
+    pub merchant_name: &'a str,
+    pub memo_code: &'a str,
+    pub product_name: Option<&'a str>,
+    pub quantity: i64,
+    pub price_fiat: f64,
+    pub currency: &'a str,
+    pub locale: Option<&'a str>,
+    pub tax_rate: Option<f64>,
+    pub tax_amount: Option<f64>,
+    pub received_zec: f64,
+    pub txid: Option<&'a str>,
+}
+
+#[allow(dead_code)]
+impl<'a> Receipt<'a> {
+    /// Receipt for an invoice, shown in the display currency and locale picked at checkout.
+    pub fn for_invoice(inv: &'a crate::invoices::Invoice) -> Self {
+        let display = crate::invoices::display::DisplayPrice::of(inv);
+        Self {
+            merchant_name: inv.merchant_name.as_deref().unwrap_or(""),
+            memo_code: &inv.memo_code,
+            product_name: inv.product_name.as_deref(),
+            quantity: inv.quantity,
+            price_fiat: display.amount,
+            currency: inv.display_currency.as_deref().or(inv.currency.as_deref()).unwrap_or("EUR"),
+            locale: inv.locale.as_deref(),
+            tax_rate: inv.tax_rate,
+            tax_amount: display.tax_amount,
+            received_zec: crate::invoices::zatoshis_to_zec(inv.received_zatoshis),
+            txid: inv.detected_txid.as_deref(),
+        }
     }
+}
+
+#[allow(dead_code)]
+pub async fn send_receipt_email(pool: &SqlitePool, config: &Config, to: &str, receipt: &Receipt<'_>) -> anyhow::Result<()> {
+    let format = |amount: f64| crate::invoices::display::format_amount(amount, receipt.currency, receipt.locale);
+    let mut ctx = Context::new();
+    ctx.insert("merchant_name", if receipt.merchant_name.is_empty() { "the merchant" } else { receipt.merchant_name });
+    ctx.insert("memo_code", receipt.memo_code);
+    ctx.insert("product_name", &receipt.product_name);
+    ctx.insert("quantity", &receipt.quantity);
+    ctx.insert("price_fiat", &format(receipt.price_fiat));
+    ctx.insert("tax_rate", &receipt.tax_rate);
+    ctx.insert("tax_amount", &receipt.tax_amount.map(format));
+    ctx.insert("received_zec", &format!("{:.8}", receipt.received_zec));
+    ctx.insert("txid", &receipt.txid);
+
+    send(pool, config, to, "Your payment receipt", "receipt", &ctx).await?;
+
+    tracing::info!(memo = receipt.memo_code, "Receipt email queued");
+    Ok(())
+}
+
+/// An invoice emailed to a buyer. Its links go through the API so opening the email is
+/// recorded against the payment request.
+pub struct PaymentRequestEmail<'a> {
+    pub request_id: &'a str,
+    pub merchant_name: &'a str,
+    pub message: Option<&'a str>,
+    pub memo_code: &'a str,
+    pub product_name: Option<&'a str>,
+    pub price_fiat: f64,
+    pub currency: &'a str,
+    pub locale: Option<&'a str>,
+    pub price_zec: f64,
+    pub expires_at: &'a str,
+}
+
+pub async fn send_payment_request(pool: &SqlitePool, config: &Config, to: &str, request: &PaymentRequestEmail<'_>) -> anyhow::Result<()> {
+    let merchant_name = if request.merchant_name.is_empty() { "A merchant" } else { request.merchant_name };
+    let links = format!("{}/api/v1/payment-requests/{}", public_api_url(config), request.request_id);
+    let mut ctx = Context::new();
+    ctx.insert("merchant_name", merchant_name);
+    ctx.insert("message", &request.message);
+    ctx.insert("memo_code", request.memo_code);
+    ctx.insert("product_name", &request.product_name);
+    ctx.insert("price_fiat", &crate::invoices::display::format_amount(request.price_fiat, request.currency, request.locale));
+    ctx.insert("price_zec", &format!("{:.8}", request.price_zec));
+    ctx.insert("expires_at", &request.expires_at.replace('T', " ").replace('Z', " UTC"));
+    ctx.insert("pay_link", &format!("{}/open", links));
+    ctx.insert("qr_url", &format!("{}/qr", links));
+
+    let subject = format!("Payment request from {}", merchant_name);
+    send(pool, config, to, &subject, "payment_request", &ctx).await?;
+
+    tracing::info!(request_id = request.request_id, memo = request.memo_code, "Payment request email queued");
+    Ok(())
+}
+
+/// Fee statement sent when a billing cycle closes with an outstanding balance.
+pub async fn send_billing_notice(
+    pool: &SqlitePool,
+    config: &Config,
+    to: &str,
+    outstanding_zec: f64,
+    outstanding_eur: f64,
+    due_date: &str,
+    settlement_invoice_id: &str,
+) -> anyhow::Result<()> {
+    let mut ctx = Context::new();
+    ctx.insert("outstanding_zec", &format!("{:.8}", outstanding_zec));
+    ctx.insert("outstanding_eur", &format!("{:.2}", outstanding_eur));
+    ctx.insert("due_date", due_date);
+    ctx.insert("settlement_invoice_id", settlement_invoice_id);
+    ctx.insert("billing_link", &format!("{}/dashboard/billing", frontend_url(config)));
+
+    send(pool, config, to, "CipherPay: Fee statement", "billing_notice", &ctx).await?;
+
+    tracing::info!(settlement_invoice_id, "Billing notice queued");
+    Ok(())
+}
+
+/// Overdue reminder. `stage` is the billing status just entered:
+/// `past_due` (with the date suspension kicks in) or `suspended`.
+pub async fn send_dunning_email(
+    pool: &SqlitePool,
+    config: &Config,
+    to: &str,
+    stage: &str,
+    outstanding_zec: f64,
+    suspend_date: Option<&str>,
+) -> anyhow::Result<()> {
+    let mut ctx = Context::new();
+    ctx.insert("stage", stage);
+    ctx.insert("outstanding_zec", &format!("{:.8}", outstanding_zec));
+    ctx.insert("suspend_date", &suspend_date);
+    ctx.insert("billing_link", &format!("{}/dashboard/billing", frontend_url(config)));
 
-    let mailer = transport_builder.build();
-    mailer.send(email).await?;
+    let subject = if stage == "suspended" {
+        "CipherPay: Account suspended for unpaid fees"
+    } else {
+        "CipherPay: Fee payment overdue"
+    };
+    send(pool, config, to, subject, "dunning", &ctx).await?;
 
-    tracing::info!(to, "Recovery email sent");
+    tracing::info!(stage, "Dunning email queued");
     Ok(())
 }
+
+/// Tells the merchant an invoice was paid after it expired and needs a decision.
+pub async fn send_late_payment_notice(
+    pool: &SqlitePool,
+    config: &Config,
+    to: &str,
+    invoice: &crate::invoices::Invoice,
+    received_zatoshis: i64,
+    txid: &str,
+) -> anyhow::Result<()> {
+    let mut ctx = Context::new();
+    ctx.insert("memo_code", &invoice.memo_code);
+    ctx.insert("expired_at", &invoice.expires_at);
+    ctx.insert("price_zec", &format!("{:.8}", crate::invoices::zatoshis_to_zec(invoice.price_zatoshis)));
+    ctx.insert("received_zec", &format!("{:.8}", crate::invoices::zatoshis_to_zec(received_zatoshis)));
+    ctx.insert("txid", txid);
+    ctx.insert("dashboard_link", &format!("{}/dashboard", frontend_url(config)));
+
+    let subject = format!("CipherPay: Late payment on {}", invoice.memo_code);
+    send(pool, config, to, &subject, "late_payment", &ctx).await?;
+
+    tracing::info!(memo = %invoice.memo_code, "Late payment notice queued");
+    Ok(())
+}
+
+pub async fn send_unmatched_payment_notice(
+    pool: &SqlitePool,
+    config: &Config,
+    to: &str,
+    payment: &crate::scanner::unmatched::UnmatchedPayment,
+) -> anyhow::Result<()> {
+    let mut ctx = Context::new();
+    ctx.insert("amount_zec", &format!("{:.8}", crate::invoices::zatoshis_to_zec(payment.amount_zatoshis)));
+    ctx.insert("memo", &payment.memo);
+    ctx.insert("base_address", &(payment.diversifier_index == 0));
+    ctx.insert("diversifier_index", &payment.diversifier_index);
+    ctx.insert("txid", &payment.txid);
+    ctx.insert("dashboard_link", &format!("{}/dashboard", frontend_url(config)));
+
+    send(pool, config, to, "CipherPay: Payment without a matching invoice", "unmatched_payment", &ctx).await?;
+
+    tracing::info!(txid = %payment.txid, "Unmatched payment notice queued");
+    Ok(())
+}
+
+pub async fn send_operator_alert(
+    pool: &SqlitePool,
+    config: &Config,
+    to: &str,
+    alert: &crate::alerts::Alert,
+) -> anyhow::Result<()> {
+    let mut ctx = Context::new();
+    ctx.insert("alert", alert.kind.as_str());
+    ctx.insert("title", alert.kind.title());
+    ctx.insert("detail", &alert.detail);
+    ctx.insert("resolved", &alert.resolved);
+    ctx.insert("network", &config.network);
+    ctx.insert("at", &alert.at.format("%Y-%m-%d %H:%M UTC").to_string());
+
+    let subject = format!("CipherPay {}: {}", if alert.resolved { "resolved" } else { "alert" }, alert.kind.title());
+    send(pool, config, to, &subject, "alert", &ctx).await?;
+
+    tracing::info!(alert = alert.kind.as_str(), resolved = alert.resolved, "Operator alert email queued");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_templates_render() {
+        let tera = build_templates(None).unwrap();
+        let mut ctx = Context::new();
+        ctx.insert("recovery_link", "https://example.com/r?token=<x>");
+        ctx.insert("expires_in", "1 hour");
+
+        let html = tera.render("recovery.html", &ctx).unwrap();
+        assert!(html.contains("token=&lt;x&gt;"));
+        let text = tera.render("recovery.txt", &ctx).unwrap();
+        assert!(text.contains("token=<x>"));
+
+        ctx.insert("merchant_name", "Studio");
+        ctx.insert("message", "<b>Thanks!</b>");
+        ctx.insert("memo_code", "CP-1A2B3C4D");
+        ctx.insert("product_name", &None::<String>);
+        ctx.insert("price_fiat", "€300.00");
+        ctx.insert("price_zec", "7.50000000");
+        ctx.insert("expires_at", "2026-01-02 10:00:00 UTC");
+        ctx.insert("pay_link", "https://api.example.com/api/v1/payment-requests/r1/open");
+        ctx.insert("qr_url", "https://api.example.com/api/v1/payment-requests/r1/qr");
+        let html = tera.render("payment_request.html", &ctx).unwrap();
+        assert!(html.contains("&lt;b&gt;Thanks!"));
+        assert!(!html.contains(">For<"));
+        let text = tera.render("payment_request.txt", &ctx).unwrap();
+        assert!(text.contains("7.50000000 ZEC"));
+    }
+
+    fn queued(to: &str) -> QueuedEmail {
+        QueuedEmail {
+            id: "e-1".into(),
+            to_address: to.into(),
+            subject: "CipherPay: Account Recovery".into(),
+            text_body: Some("Recover at https://example.com/r".into()),
+            html_body: Some("<p>Recover</p>".into()),
+            attempts: 0,
+        }
+    }
+
+    #[test]
+    fn test_message_has_text_and_html_alternatives() {
+        let config = Config::from_pairs(&[
+            ("SMTP_HOST", "smtp.example.com"),
+            ("SMTP_FROM", "CipherPay <noreply@example.com>"),
+        ]).unwrap();
+        let message = build_message(&config, &queued("buyer@example.com")).unwrap();
+        let raw = String::from_utf8(message.formatted()).unwrap();
+
+        assert!(raw.contains("From: CipherPay <noreply@example.com>"));
+        assert!(raw.contains("To: buyer@example.com"));
+        assert!(raw.contains("Subject: CipherPay: Account Recovery"));
+        assert!(raw.contains("multipart/alternative"));
+        assert!(raw.contains("Content-Type: text/plain"));
+        assert!(raw.contains("Recover at https://example.com/r"));
+        assert!(raw.contains("Content-Type: text/html"));
+        assert!(raw.contains("<p>Recover</p>"));
+
+        assert!(build_message(&config, &queued("not an address")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_missing_smtp_settings_disable_sending() {
+        let config = Config::from_pairs(&[]).unwrap();
+        assert!(!config.smtp_configured());
+        assert!(!Config::from_pairs(&[("SMTP_HOST", "smtp.example.com")]).unwrap().smtp_configured());
+        assert!(!Config::from_pairs(&[("SMTP_FROM", "noreply@example.com")]).unwrap().smtp_configured());
+        assert!(build_message(&config, &queued("buyer@example.com")).unwrap_err().to_string().contains("SMTP_FROM"));
+        assert!(build_transport(&config).unwrap_err().to_string().contains("SMTP not configured"));
+
+        // The queue is left alone rather than burning attempts on a mailer that isn't there.
+        let pool = crate::db::test_pool().await;
+        sqlx::query("INSERT INTO emails (id, kind, to_address, subject, text_body, html_body) VALUES ('e-1', 'recovery', 'buyer@example.com', 'Hi', 'text', 'html')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        drain_queue(&pool, &config).await.unwrap();
+        let (status, attempts): (String, i64) = sqlx::query_as("SELECT status, attempts FROM emails WHERE id = 'e-1'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!((status.as_str(), attempts), ("pending", 0));
+    }
+
+    async fn queue_row(pool: &SqlitePool) -> (String, i64, Option<String>, Option<String>) {
+        sqlx::query_as("SELECT status, attempts, next_attempt_at, last_error FROM emails")
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    async fn make_due(pool: &SqlitePool) {
+        sqlx::query("UPDATE emails SET next_attempt_at = '2020-01-01T00:00:00Z'").execute(pool).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_queued_email_backs_off_then_gives_up() {
+        // Nothing listens on the port, so every attempt fails.
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port().to_string();
+        let config = Config::from_pairs(&[
+            ("SMTP_HOST", "127.0.0.1"),
+            ("SMTP_PORT", &port),
+            ("SMTP_TLS", "none"),
+            ("SMTP_FROM", "noreply@example.com"),
+        ]).unwrap();
+        let pool = crate::db::test_pool().await;
+        let next_in = |at: &Option<String>| {
+            let at = chrono::NaiveDateTime::parse_from_str(at.as_deref().unwrap(), "%Y-%m-%dT%H:%M:%SZ").unwrap();
+            (at.and_utc() - Utc::now()).num_seconds()
+        };
+
+        // Queued with its first attempt made inline.
+        send_recovery_email(&pool, &config, "buyer@example.com", "token").await.unwrap();
+        let (status, attempts, next, error) = queue_row(&pool).await;
+        assert_eq!((status.as_str(), attempts), ("pending", 1));
+        assert!((25..=30).contains(&next_in(&next)));
+        assert!(error.is_some());
+
+        // Not retried before the backoff elapses, then retried with a longer one.
+        drain_queue(&pool, &config).await.unwrap();
+        assert_eq!(queue_row(&pool).await.1, 1);
+        make_due(&pool).await;
+        drain_queue(&pool, &config).await.unwrap();
+        let (status, attempts, next, _) = queue_row(&pool).await;
+        assert_eq!((status.as_str(), attempts), ("pending", 2));
+        assert!((115..=120).contains(&next_in(&next)));
+
+        // The last attempt failing marks the email failed, and it is not tried again.
+        sqlx::query("UPDATE emails SET attempts = ?").bind(MAX_ATTEMPTS - 1).execute(&pool).await.unwrap();
+        make_due(&pool).await;
+        drain_queue(&pool, &config).await.unwrap();
+        let (status, attempts, _, error) = queue_row(&pool).await;
+        assert_eq!((status.as_str(), attempts), ("failed", MAX_ATTEMPTS));
+        assert!(error.is_some());
+        make_due(&pool).await;
+        drain_queue(&pool, &config).await.unwrap();
+        assert_eq!(queue_row(&pool).await.1, MAX_ATTEMPTS);
+    }
+}
@@ -0,0 +1,372 @@
+//! gRPC API (`proto/cipherpay.proto`) for merchants integrating from backend systems that
+//! want protobuf contracts and a status stream instead of polling or SSE. Runs on
+//! `GRPC_PORT` next to the REST server and goes through the same `InvoiceService`, so the
+//! two transports cannot drift on validation, pricing or billing checks. Calls carry the
+//! merchant API key as `authorization: Bearer <key>` metadata.
+
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroU32;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_governor::governor::{DefaultKeyedRateLimiter, Quota, RateLimiter};
+use sqlx::SqlitePool;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+
+use crate::config::{Config, RateLimit};
+use crate::invoices::{self, InvoiceError};
+use crate::merchants::Merchant;
+use crate::services::invoices::CreateInvoiceError;
+use crate::services::InvoiceService;
+
+pub mod pb {
+    tonic::include_proto!("cipherpay.v1");
+}
+
+use pb::invoices_server::{Invoices, InvoicesServer};
+
+/// How often `WatchStatus` re-reads an invoice, as the SSE stream does.
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+fn is_final(status: &str) -> bool {
+    matches!(status, "confirmed" | "expired" | "paid_late" | "refunded")
+}
+
+/// Calls allowed per peer address and per API key, counted before authentication. The
+/// peer bucket comes first, so a client cannot get around the limit by sending a fresh
+/// made-up key with every call. Applies to every method, `WatchStatus` included.
+#[derive(Clone)]
+struct CallRateLimit {
+    by_peer: Arc<DefaultKeyedRateLimiter<IpAddr>>,
+    by_key: Arc<DefaultKeyedRateLimiter<String>>,
+}
+
+impl CallRateLimit {
+    fn new(limit: RateLimit) -> Self {
+        let quota = Quota::with_period(Duration::from_millis(limit.period_ms))
+            .expect("RATE_LIMIT_PERIOD_MS is validated to be non-zero")
+            .allow_burst(NonZeroU32::new(limit.burst).expect("RATE_LIMIT_BURST is validated to be non-zero"));
+        Self {
+            by_peer: Arc::new(RateLimiter::keyed(quota)),
+            by_key: Arc::new(RateLimiter::keyed(quota)),
+        }
+    }
+
+    /// Interceptor: calls without a key go on to be rejected by `authenticate`. The
+    /// signature is tonic's.
+    #[allow(clippy::result_large_err)]
+    fn check(&self, req: Request<()>) -> Result<Request<()>, Status> {
+        let exhausted = || Status::resource_exhausted("Rate limit exceeded");
+        if let Some(peer) = req.remote_addr() {
+            self.by_peer.check_key(&peer.ip()).map_err(|_| exhausted())?;
+        }
+        if let Some(key) = api_key(&req) {
+            self.by_key.check_key(&key.to_string()).map_err(|_| exhausted())?;
+        }
+        Ok(req)
+    }
+}
+
+pub struct GrpcService {
+    pool: SqlitePool,
+    config: Config,
+    invoices: InvoiceService,
+}
+
+/// Serve the gRPC API until the process exits.
+#[allow(clippy::result_large_err)]
+pub async fn serve(addr: SocketAddr, pool: SqlitePool, config: Config, invoices: InvoiceService) -> anyhow::Result<()> {
+    tracing::info!(%addr, "gRPC API listening");
+    let limit = CallRateLimit::new(config.grpc_rate_limit);
+
+    // Peers and keys whose bucket has refilled need no state; drop them so those seen once
+    // do not pile up.
+    let evict = limit.clone();
+    crate::jobs::Job::new("grpc_rate_limit.evict", Duration::from_secs(60)).delay_first().spawn(move || {
+        let limit = evict.clone();
+        async move {
+            limit.by_peer.retain_recent();
+            limit.by_key.retain_recent();
+            Ok(())
+        }
+    });
+
+    let service = GrpcService { pool, config, invoices };
+    tonic::transport::Server::builder()
+        .add_service(InvoicesServer::with_interceptor(service, move |req| limit.check(req)))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
+
+/// The API key from `authorization: Bearer <key>` metadata.
+fn api_key<T>(req: &Request<T>) -> Option<&str> {
+    req.metadata()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.strip_prefix("Bearer ").unwrap_or(v).trim())
+        .filter(|k| !k.is_empty())
+}
+
+impl GrpcService {
+    async fn authenticate<T>(&self, req: &Request<T>) -> Result<Merchant, Status> {
+        let key = api_key(req).ok_or_else(|| Status::unauthenticated("Missing or invalid authorization metadata"))?;
+
+        match crate::merchants::authenticate(&self.pool, key, &self.config.encryption_key).await {
+            Ok(Some(m)) => Ok(m),
+            Ok(None) => Err(Status::unauthenticated("Invalid API key")),
+            Err(e) => {
+                tracing::error!(error = %e, "gRPC API key auth error");
+                Err(Status::internal("Internal error"))
+            }
+        }
+    }
+}
+
+fn invoice_status(e: InvoiceError) -> Status {
+    match e {
+        InvoiceError::NotFound => Status::not_found(e.to_string()),
+        InvoiceError::InvalidStatus => Status::failed_precondition(e.to_string()),
+        InvoiceError::QuotaExceeded { .. } => Status::resource_exhausted(e.to_string()),
+        InvoiceError::BelowMinimum { .. } => Status::invalid_argument(e.to_string()),
+        InvoiceError::Address(_) | InvoiceError::Merchant(_) | InvoiceError::Database(_) => {
+            tracing::error!(error = %e, "gRPC request failed");
+            Status::internal("Internal error")
+        }
+    }
+}
+
+fn create_status(e: CreateInvoiceError) -> Status {
+    match e {
+        CreateInvoiceError::Validation(e) => Status::invalid_argument(e.message),
+        CreateInvoiceError::BillingPastDue(status) => {
+            Status::failed_precondition(format!("Merchant account has outstanding fees (billing status {})", status))
+        }
+        CreateInvoiceError::PriceUnavailable => Status::unavailable(e.to_string()),
+        CreateInvoiceError::Invoice(e) => invoice_status(e),
+    }
+}
+
+#[tonic::async_trait]
+impl Invoices for GrpcService {
+    async fn create_invoice(
+        &self,
+        req: Request<pb::CreateInvoiceRequest>,
+    ) -> Result<Response<pb::CreateInvoiceResponse>, Status> {
+        let merchant = self.authenticate(&req).await?;
+        let r = req.into_inner();
+        let body = invoices::CreateInvoiceRequest {
+            product_id: r.product_id,
+            product_name: r.product_name,
+            size: r.size,
+            quantity: r.quantity,
+            price_eur: r.price_eur,
+            currency: r.currency,
+            refund_address: r.refund_address,
+            tax: None,
+            on_expiry: r.on_expiry,
+            display_currency: r.display_currency,
+            locale: r.locale,
+            custom_fields: None,
+            memo_prefix: None,
+            expiry_minutes: None,
+            claim_code: r.claim_code.unwrap_or(false),
+        };
+        let created = self.invoices.create(&merchant, body).await.map_err(create_status)?;
+        Ok(Response::new(pb::CreateInvoiceResponse {
+            invoice_id: created.invoice_id,
+            memo_code: created.memo_code,
+            price_eur: created.price_eur,
+            price_usd: created.price_usd,
+            price_zec: created.price_zec,
+            zec_rate: created.zec_rate,
+            payment_address: created.payment_address,
+            zcash_uri: created.zcash_uri,
+            tex_address: created.tex_address,
+            claim_code: created.claim_code,
+            expires_at: created.expires_at,
+        }))
+    }
+
+    async fn get_invoice(&self, req: Request<pb::GetInvoiceRequest>) -> Result<Response<pb::Invoice>, Status> {
+        let merchant = self.authenticate(&req).await?;
+        let inv = self
+            .invoices
+            .get(&merchant, &req.into_inner().invoice_id)
+            .await
+            .map_err(invoice_status)?;
+        Ok(Response::new(pb::Invoice {
+            invoice_id: inv.id,
+            memo_code: inv.memo_code,
+            status: inv.status,
+            product_name: inv.product_name,
+            quantity: inv.quantity,
+            price_eur: inv.price_eur,
+            price_usd: inv.price_usd,
+            currency: inv.currency,
+            price_zec: inv.price_zec,
+            price_zatoshis: inv.price_zatoshis,
+            received_zatoshis: inv.received_zatoshis,
+            payment_address: inv.payment_address,
+            zcash_uri: inv.zcash_uri,
+            detected_txid: inv.detected_txid,
+            detected_at: inv.detected_at,
+            confirmed_at: inv.confirmed_at,
+            refunded_at: inv.refunded_at,
+            expires_at: inv.expires_at,
+            created_at: inv.created_at,
+        }))
+    }
+
+    async fn get_status(&self, req: Request<pb::GetInvoiceRequest>) -> Result<Response<pb::InvoiceStatus>, Status> {
+        let merchant = self.authenticate(&req).await?;
+        let status = self
+            .invoices
+            .status(&merchant, &req.into_inner().invoice_id)
+            .await
+            .map_err(invoice_status)?;
+        Ok(Response::new(status.into()))
+    }
+
+    type WatchStatusStream = Pin<Box<dyn tokio_stream::Stream<Item = Result<pb::InvoiceStatus, Status>> + Send>>;
+
+    async fn watch_status(
+        &self,
+        req: Request<pb::GetInvoiceRequest>,
+    ) -> Result<Response<Self::WatchStatusStream>, Status> {
+        let merchant = self.authenticate(&req).await?;
+        let invoice_id = req.into_inner().invoice_id;
+        // Ownership is checked once, up front; the loop only re-reads the status.
+        let first: pb::InvoiceStatus = self
+            .invoices
+            .status(&merchant, &invoice_id)
+            .await
+            .map_err(invoice_status)?
+            .into();
+
+        let (tx, rx) = tokio::sync::mpsc::channel(8);
+        let pool = self.pool.clone();
+        tokio::spawn(async move {
+            let mut last = first;
+            if tx.send(Ok(last.clone())).await.is_err() || is_final(&last.status) {
+                return;
+            }
+            let mut tick = tokio::time::interval(WATCH_INTERVAL);
+            loop {
+                tick.tick().await;
+                let current: pb::InvoiceStatus = match invoices::get_invoice_status(&pool, &invoice_id).await {
+                    Ok(Some(s)) => s.into(),
+                    Ok(None) => {
+                        let _ = tx.send(Err(Status::not_found("Invoice not found"))).await;
+                        return;
+                    }
+                    Err(e) => {
+                        let _ = tx.send(Err(invoice_status(e))).await;
+                        return;
+                    }
+                };
+                if current == last {
+                    // Nothing new; stop polling once the client has gone away.
+                    if tx.is_closed() {
+                        return;
+                    }
+                    continue;
+                }
+                let done = is_final(&current.status);
+                if tx.send(Ok(current.clone())).await.is_err() || done {
+                    return;
+                }
+                last = current;
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+}
+
+impl From<invoices::InvoiceStatus> for pb::InvoiceStatus {
+    fn from(s: invoices::InvoiceStatus) -> Self {
+        Self {
+            invoice_id: s.invoice_id,
+            status: s.status,
+            detected_txid: s.detected_txid,
+            received_zatoshis: s.received_zatoshis,
+            price_zatoshis: s.price_zatoshis,
+            remaining_zatoshis: s.remaining_zatoshis,
+            confirmations: s.confirmations,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Code;
+
+    fn call(key: Option<&str>) -> Request<()> {
+        let mut req = Request::new(());
+        if let Some(key) = key {
+            req.metadata_mut().insert("authorization", format!("Bearer {}", key).parse().unwrap());
+        }
+        req
+    }
+
+    fn call_from(peer: &str, key: &str) -> Request<()> {
+        let mut req = call(Some(key));
+        req.extensions_mut().insert(tonic::transport::server::TcpConnectInfo {
+            local_addr: None,
+            remote_addr: Some(peer.parse().unwrap()),
+        });
+        req
+    }
+
+    #[test]
+    fn test_calls_are_limited_per_api_key() {
+        let limit = CallRateLimit::new(RateLimit { period_ms: 60_000, burst: 2 });
+        assert!(limit.check(call(Some("cpay_sk_a"))).is_ok());
+        assert!(limit.check(call(Some("cpay_sk_a"))).is_ok());
+        let refused = limit.check(call(Some("cpay_sk_a"))).unwrap_err();
+        assert_eq!(refused.code(), Code::ResourceExhausted);
+
+        // Another key has its own budget; a call without one is left to `authenticate`.
+        assert!(limit.check(call(Some("cpay_sk_b"))).is_ok());
+        assert!(limit.check(call(None)).is_ok());
+    }
+
+    #[test]
+    fn test_fresh_keys_do_not_reset_the_peer_budget() {
+        let limit = CallRateLimit::new(RateLimit { period_ms: 60_000, burst: 2 });
+        assert!(limit.check(call_from("192.0.2.1:5000", "cpay_sk_1")).is_ok());
+        assert!(limit.check(call_from("192.0.2.1:5001", "cpay_sk_2")).is_ok());
+        let refused = limit.check(call_from("192.0.2.1:5002", "cpay_sk_3")).unwrap_err();
+        assert_eq!(refused.code(), Code::ResourceExhausted);
+
+        assert!(limit.check(call_from("192.0.2.2:5000", "cpay_sk_4")).is_ok());
+    }
+
+    #[test]
+    fn test_errors_map_to_grpc_codes() {
+        assert_eq!(invoice_status(InvoiceError::NotFound).code(), Code::NotFound);
+        assert_eq!(invoice_status(InvoiceError::InvalidStatus).code(), Code::FailedPrecondition);
+        assert_eq!(
+            invoice_status(InvoiceError::QuotaExceeded { quota: "open invoices", limit: 10 }).code(),
+            Code::ResourceExhausted,
+        );
+        let internal = invoice_status(InvoiceError::Database(sqlx::Error::PoolClosed));
+        assert_eq!(internal.code(), Code::Internal);
+        assert_eq!(internal.message(), "Internal error");
+
+        let invalid = create_status(CreateInvoiceError::Validation(
+            crate::validation::ValidationError::invalid("quantity", "must be at least 1"),
+        ));
+        assert_eq!(invalid.code(), Code::InvalidArgument);
+        assert_eq!(invalid.message(), "quantity: must be at least 1");
+        assert_eq!(create_status(CreateInvoiceError::PriceUnavailable).code(), Code::Unavailable);
+        assert_eq!(
+            create_status(CreateInvoiceError::BillingPastDue("suspended".into())).code(),
+            Code::FailedPrecondition,
+        );
+    }
+}
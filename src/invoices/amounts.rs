@@ -0,0 +1,40 @@
+//! Rounding rules for invoice amounts. Fiat amounts are kept to cents and ZEC amounts to
+//! zatoshis, rounding half away from zero, so the stored price, the payment URI and the
+//! amounts shown to the buyer always agree.
+
+pub const ZATOSHIS_PER_ZEC: f64 = 100_000_000.0;
+
+/// A fiat amount to 2 decimals.
+pub fn round_fiat(v: f64) -> f64 {
+    (v * 100.0).round() / 100.0
+}
+
+/// A ZEC amount to 8 decimals, the smallest unit a wallet can send.
+pub fn round_zec(v: f64) -> f64 {
+    zatoshis_to_zec(zec_to_zatoshis(v))
+}
+
+/// Whole zatoshis in `zec`, rounded rather than truncated: `0.29 * 1e8` is
+/// `28999999.999999996` in floating point.
+pub fn zec_to_zatoshis(zec: f64) -> i64 {
+    (zec * ZATOSHIS_PER_ZEC).round() as i64
+}
+
+pub fn zatoshis_to_zec(z: i64) -> f64 {
+    format!("{:.8}", z as f64 / ZATOSHIS_PER_ZEC).parse::<f64>().unwrap_or(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rounding() {
+        assert_eq!(round_fiat(19.999), 20.0);
+        assert_eq!(round_fiat(0.004), 0.0);
+        assert_eq!(zec_to_zatoshis(0.29), 29_000_000);
+        assert_eq!(zec_to_zatoshis(0.123456789), 12_345_679);
+        assert_eq!(round_zec(1.0 / 3.0), 0.33333333);
+        assert_eq!(zatoshis_to_zec(zec_to_zatoshis(round_zec(0.1 + 0.2))), 0.3);
+    }
+}
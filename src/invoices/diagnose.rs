@@ -0,0 +1,161 @@
+//! "I paid but nothing happened": what the scanner has seen for an invoice, in terms a
+//! buyer can act on. Public, so it reports amounts, timing and heights but never txids,
+//! addresses or anything about the merchant.
+
+use serde::Serialize;
+
+use super::{zatoshis_to_zec, Invoice, InvoicePayment};
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Diagnosis {
+    pub invoice_id: String,
+    pub status: String,
+    pub payment_seen: bool,
+    pub payments_seen: usize,
+    pub received_zec: f64,
+    /// Still owed; only set while a payment falls short of the price.
+    pub underpaid_by_zec: Option<f64>,
+    pub underpaid_by_zatoshis: Option<i64>,
+    pub expired: bool,
+    /// A payment arrived after `expires_at`.
+    pub paid_after_expiry: bool,
+    /// Last block the scanner processed.
+    pub chain_height: Option<i64>,
+    /// Block the first payment was mined in.
+    pub detection_height: Option<i64>,
+    pub confirmations: Option<i64>,
+    /// Machine-readable summary: `no_payment_seen`, `awaiting_confirmation`, `underpaid`,
+    /// `expired_unpaid`, `expired_underpaid`, `paid_late`, `confirmed` or `refunded`.
+    pub hint: &'static str,
+    pub message: String,
+}
+
+/// `now` is an RFC 3339 UTC timestamp in the same format as the invoice's.
+pub fn diagnose(invoice: &Invoice, payments: &[InvoicePayment], chain_height: Option<i64>, now: &str) -> Diagnosis {
+    let expired = invoice.expires_at.as_str() <= now;
+    let paid_after_expiry = invoice.status == "paid_late"
+        || payments.iter().any(|p| p.seen_at > invoice.expires_at);
+    let detection_height = payments.iter().filter_map(|p| p.block_height).min();
+    let confirmations = chain_height
+        .zip(payments.iter().filter_map(|p| p.block_height).max())
+        .map(|(tip, mined)| (tip - mined + 1).max(1));
+    let short = invoice.price_zatoshis - invoice.received_zatoshis;
+    let underpaid = invoice.received_zatoshis > 0 && short > 0;
+
+    let (hint, message) = match invoice.status.as_str() {
+        "confirmed" => ("confirmed", "Payment received and confirmed. Nothing else to do.".to_string()),
+        "refunded" => ("refunded", "The merchant has refunded this invoice.".to_string()),
+        "paid_late" => (
+            "paid_late",
+            "Your payment arrived after the invoice expired. The merchant has been notified and \
+             will either honor the order or refund you."
+                .to_string(),
+        ),
+        "detected" => (
+            "awaiting_confirmation",
+            "Payment seen and waiting to be mined, which usually takes a few minutes.".to_string(),
+        ),
+        _ if expired && underpaid => (
+            "expired_underpaid",
+            format!(
+                "The invoice expired {} ZEC short of the price. Do not send more to it; contact the \
+                 merchant about a refund of the {} ZEC received.",
+                zatoshis_to_zec(short),
+                zatoshis_to_zec(invoice.received_zatoshis),
+            ),
+        ),
+        _ if expired => (
+            "expired_unpaid",
+            "The invoice expired before any payment was seen. Do not send funds to it; ask the \
+             merchant for a new invoice. If you already sent a payment, it may still be in transit; \
+             check again in a few minutes."
+                .to_string(),
+        ),
+        _ if underpaid => (
+            "underpaid",
+            format!(
+                "Payment received but {} ZEC short. Send the rest to the same address before the \
+                 invoice expires.",
+                zatoshis_to_zec(short),
+            ),
+        ),
+        _ => (
+            "no_payment_seen",
+            "No payment has been seen yet. Payments usually show up within a minute of being sent; \
+             make sure your wallet sent the exact amount to the address shown, and that the \
+             transaction was broadcast."
+                .to_string(),
+        ),
+    };
+
+    Diagnosis {
+        invoice_id: invoice.id.clone(),
+        status: invoice.status.clone(),
+        payment_seen: !payments.is_empty() || invoice.received_zatoshis > 0,
+        payments_seen: payments.len(),
+        received_zec: zatoshis_to_zec(invoice.received_zatoshis),
+        underpaid_by_zec: underpaid.then(|| zatoshis_to_zec(short)),
+        underpaid_by_zatoshis: underpaid.then_some(short),
+        expired,
+        paid_after_expiry,
+        chain_height,
+        detection_height,
+        confirmations,
+        hint,
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::invoices::test_invoice;
+
+    const BEFORE_EXPIRY: &str = "2029-12-31T23:00:00Z";
+    const AFTER_EXPIRY: &str = "2030-01-01T00:10:00Z";
+
+    fn payment(amount_zatoshis: i64, block_height: Option<i64>, seen_at: &str) -> InvoicePayment {
+        InvoicePayment {
+            txid: "ab".repeat(32),
+            amount_zatoshis,
+            block_height,
+            seen_at: seen_at.into(),
+            fee_zatoshis: None,
+            tx_outputs: None,
+        }
+    }
+
+    #[test]
+    fn test_diagnose_nothing_seen() {
+        let d = diagnose(&test_invoice(), &[], Some(100), BEFORE_EXPIRY);
+        assert_eq!(d.hint, "no_payment_seen");
+        assert!(!d.payment_seen && !d.expired);
+
+        let d = diagnose(&test_invoice(), &[], Some(100), AFTER_EXPIRY);
+        assert_eq!(d.hint, "expired_unpaid");
+    }
+
+    #[test]
+    fn test_diagnose_underpaid() {
+        let invoice = Invoice { status: "underpaid".into(), received_zatoshis: 20_000_000, ..test_invoice() };
+        let payments = [payment(20_000_000, None, BEFORE_EXPIRY)];
+        let d = diagnose(&invoice, &payments, Some(100), BEFORE_EXPIRY);
+        assert_eq!(d.hint, "underpaid");
+        assert_eq!(d.underpaid_by_zatoshis, Some(5_000_000));
+        assert_eq!(d.underpaid_by_zec, Some(0.05));
+
+        let expired = Invoice { status: "expired".into(), ..invoice };
+        assert_eq!(diagnose(&expired, &payments, Some(100), AFTER_EXPIRY).hint, "expired_underpaid");
+    }
+
+    #[test]
+    fn test_diagnose_heights_and_late_payment() {
+        let invoice = Invoice { status: "paid_late".into(), received_zatoshis: 25_000_000, ..test_invoice() };
+        let payments = [payment(25_000_000, Some(98), AFTER_EXPIRY)];
+        let d = diagnose(&invoice, &payments, Some(100), AFTER_EXPIRY);
+        assert_eq!(d.hint, "paid_late");
+        assert!(d.paid_after_expiry);
+        assert_eq!(d.underpaid_by_zatoshis, None);
+        assert_eq!((d.detection_height, d.confirmations), (Some(98), Some(3)));
+    }
+}
@@ -0,0 +1,163 @@
+use serde::Serialize;
+
+use super::amounts::round_fiat;
+use super::Invoice;
+use crate::validation::ValidationError;
+
+/// Fiat currencies an invoice can be shown in. Limited to the ones the price feed quotes,
+/// since every invoice stores its price in both.
+pub const DISPLAY_CURRENCIES: &[&str] = &["EUR", "USD"];
+
+/// Languages that write amounts as `1.234,56 €`.
+const COMMA_DECIMAL: &[&str] = &[
+    "bg", "cs", "da", "de", "el", "es", "fi", "fr", "hr", "hu", "id", "it", "nb", "nl",
+    "no", "pl", "pt", "ro", "ru", "sk", "sl", "sv", "tr", "uk", "vi",
+];
+
+/// Of those, the ones that group thousands with a space instead of a dot.
+const SPACE_GROUPING: &[&str] = &[
+    "bg", "cs", "fi", "fr", "hu", "nb", "no", "pl", "ru", "sk", "sv", "uk",
+];
+
+/// How the buyer sees an invoice: currency and formatting only. Settlement (ZEC amount,
+/// the merchant's currency and tax) is unaffected.
+#[derive(Debug, Serialize)]
+pub struct DisplayPrice {
+    pub currency: String,
+    pub locale: Option<String>,
+    pub amount: f64,
+    pub formatted: String,
+    pub tax_amount: Option<f64>,
+    pub tax_formatted: Option<String>,
+}
+
+impl DisplayPrice {
+    /// None when the invoice was created without display options.
+    pub fn for_invoice(inv: &Invoice) -> Option<Self> {
+        if inv.display_currency.is_none() && inv.locale.is_none() {
+            return None;
+        }
+        Some(Self::of(inv))
+    }
+
+    /// The invoice total in its display currency (falling back to its own currency).
+    pub fn of(inv: &Invoice) -> Self {
+        let currency = inv.display_currency.clone()
+            .or_else(|| inv.currency.clone())
+            .unwrap_or_else(|| "EUR".into());
+        let amount = match currency.as_str() {
+            "USD" => inv.price_usd.unwrap_or(inv.price_eur),
+            _ => inv.price_eur,
+        };
+        let amount = round_fiat(amount);
+
+        // Tax is stored in the invoice currency; convert it at the same rate as the total.
+        let own_total = inv.total_in_currency();
+        let tax_amount = inv.tax_amount
+            .filter(|_| own_total > 0.0)
+            .map(|tax| round_fiat(tax * amount / own_total));
+
+        Self {
+            formatted: format_amount(amount, &currency, inv.locale.as_deref()),
+            tax_formatted: tax_amount.map(|t| format_amount(t, &currency, inv.locale.as_deref())),
+            currency,
+            locale: inv.locale.clone(),
+            amount,
+            tax_amount,
+        }
+    }
+}
+
+/// Validate and normalize checkout display options: an uppercase currency from
+/// `DISPLAY_CURRENCIES` and a BCP 47 style tag such as `de` or `en-US`.
+pub fn validate(
+    display_currency: Option<&str>,
+    locale: Option<&str>,
+) -> Result<(Option<String>, Option<String>), ValidationError> {
+    let currency = match display_currency {
+        Some(c) => {
+            let c = c.trim().to_ascii_uppercase();
+            if !DISPLAY_CURRENCIES.contains(&c.as_str()) {
+                return Err(ValidationError::invalid("display_currency", "must be EUR or USD"));
+            }
+            Some(c)
+        }
+        None => None,
+    };
+
+    let locale = match locale {
+        Some(l) => {
+            let l = l.trim();
+            let mut parts = l.split('-');
+            let lang_ok = parts.next()
+                .is_some_and(|p| (2..=3).contains(&p.len()) && p.chars().all(|c| c.is_ascii_alphabetic()));
+            let rest_ok = parts.all(|p| (2..=8).contains(&p.len()) && p.chars().all(|c| c.is_ascii_alphanumeric()));
+            if l.len() > 35 || !lang_ok || !rest_ok {
+                return Err(ValidationError::invalid("locale", "must be a language tag such as en or de-DE"));
+            }
+            Some(l.to_string())
+        }
+        None => None,
+    };
+
+    Ok((currency, locale))
+}
+
+/// Format a fiat amount for a locale, e.g. `€1,234.50` (en) or `1.234,50 €` (de).
+/// Unknown or missing locales use the English style.
+pub fn format_amount(amount: f64, currency: &str, locale: Option<&str>) -> String {
+    let lang = locale
+        .and_then(|l| l.split('-').next())
+        .map(|l| l.to_ascii_lowercase())
+        .unwrap_or_default();
+    let symbol = match currency {
+        "EUR" => "€",
+        "USD" => "$",
+        other => other,
+    };
+
+    let cents = (amount.abs() * 100.0).round() as u64;
+    let digits = (cents / 100).to_string();
+    let comma_decimal = COMMA_DECIMAL.contains(&lang.as_str());
+    let (group, decimal) = match (comma_decimal, SPACE_GROUPING.contains(&lang.as_str())) {
+        (true, true) => ('\u{a0}', ','),
+        (true, false) => ('.', ','),
+        _ => (',', '.'),
+    };
+
+    let mut whole = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            whole.push(group);
+        }
+        whole.push(c);
+    }
+    let sign = if amount < 0.0 && cents > 0 { "-" } else { "" };
+    let number = format!("{}{}{}{:02}", sign, whole, decimal, cents % 100);
+
+    if comma_decimal {
+        format!("{}\u{a0}{}", number, symbol)
+    } else {
+        format!("{}{}", symbol, number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_amount() {
+        assert_eq!(format_amount(1234.5, "USD", Some("en-US")), "$1,234.50");
+        assert_eq!(format_amount(1234.5, "EUR", Some("de-DE")), "1.234,50\u{a0}€");
+        assert_eq!(format_amount(1234567.0, "EUR", Some("fr")), "1\u{a0}234\u{a0}567,00\u{a0}€");
+        assert_eq!(format_amount(0.5, "USD", None), "$0.50");
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(validate(Some("usd"), Some("de-DE")).ok(), Some((Some("USD".into()), Some("de-DE".into()))));
+        assert!(validate(Some("GBP"), None).is_err());
+        assert!(validate(None, Some("<script>")).is_err());
+    }
+}
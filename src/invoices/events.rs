@@ -0,0 +1,200 @@
+use serde::Serialize;
+use sqlx::{FromRow, SqlitePool};
+
+use super::InvoiceError;
+
+/// One entry in an invoice's lifecycle timeline.
+#[derive(Debug, Serialize)]
+pub struct InvoiceEvent {
+    /// Monotonic across all invoices; the SSE stream uses it as the event ID.
+    pub id: i64,
+    pub event_type: String,
+    pub txid: Option<String>,
+    pub block_height: Option<i64>,
+    pub detail: Option<serde_json::Value>,
+    pub created_at: String,
+}
+
+#[derive(FromRow)]
+struct EventRow {
+    id: i64,
+    event_type: String,
+    txid: Option<String>,
+    block_height: Option<i64>,
+    detail: Option<String>,
+    created_at: String,
+}
+
+/// Append an event to the invoice timeline.
+/// The timeline is informational, so failures are logged rather than propagated
+/// and never abort the state transition that triggered them.
+pub async fn record(
+    pool: &SqlitePool,
+    invoice_id: &str,
+    event_type: &str,
+    txid: Option<&str>,
+    block_height: Option<u64>,
+    detail: Option<serde_json::Value>,
+) {
+    if let Err(e) = insert(pool, invoice_id, event_type, txid, block_height, detail).await {
+        tracing::warn!(invoice_id, event_type, error = %e, "Failed to record invoice event");
+    }
+}
+
+/// Append an event on any executor, so a status change and its event can share a
+/// transaction (see `state::Transition`).
+pub(crate) async fn insert<'e, E: sqlx::SqliteExecutor<'e>>(
+    executor: E,
+    invoice_id: &str,
+    event_type: &str,
+    txid: Option<&str>,
+    block_height: Option<u64>,
+    detail: Option<serde_json::Value>,
+) -> sqlx::Result<()> {
+    sqlx::query(
+        "INSERT INTO invoice_events (invoice_id, event_type, txid, block_height, detail)
+         VALUES (?, ?, ?, ?, ?)"
+    )
+    .bind(invoice_id)
+    .bind(event_type)
+    .bind(txid)
+    .bind(block_height.map(|h| h as i64))
+    .bind(detail.map(|d| d.to_string()))
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Full timeline for an invoice, oldest first.
+pub async fn list(pool: &SqlitePool, invoice_id: &str) -> Result<Vec<InvoiceEvent>, InvoiceError> {
+    since(pool, invoice_id, 0).await
+}
+
+/// Events recorded after `after_id`, oldest first.
+pub async fn since(pool: &SqlitePool, invoice_id: &str, after_id: i64) -> Result<Vec<InvoiceEvent>, InvoiceError> {
+    let rows = sqlx::query_as::<_, EventRow>(
+        "SELECT id, event_type, txid, block_height, detail, created_at
+         FROM invoice_events WHERE invoice_id = ? AND id > ?
+         ORDER BY id ASC"
+    )
+    .bind(invoice_id)
+    .bind(after_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|r| InvoiceEvent {
+            id: r.id,
+            event_type: r.event_type,
+            txid: r.txid,
+            block_height: r.block_height,
+            detail: r.detail.and_then(|d| serde_json::from_str(&d).ok()),
+            created_at: r.created_at,
+        })
+        .collect())
+}
+
+/// ID of the invoice's most recent event, or 0 if it has none.
+pub async fn latest_id(pool: &SqlitePool, invoice_id: &str) -> Result<i64, InvoiceError> {
+    let id: Option<i64> = sqlx::query_scalar("SELECT MAX(id) FROM invoice_events WHERE invoice_id = ?")
+        .bind(invoice_id)
+        .fetch_one(pool)
+        .await?;
+    Ok(id.unwrap_or(0))
+}
+
+/// A timeline event on one of a merchant's invoices, with where that invoice stands now.
+#[derive(Debug, Serialize, FromRow)]
+pub struct MerchantEvent {
+    pub id: i64,
+    pub invoice_id: String,
+    pub memo_code: String,
+    pub event_type: String,
+    pub txid: Option<String>,
+    pub block_height: Option<i64>,
+    pub created_at: String,
+    pub invoice_status: String,
+    pub received_zatoshis: i64,
+}
+
+/// Events on any of the merchant's invoices recorded after `after_id`, oldest first.
+pub async fn since_for_merchant(
+    pool: &SqlitePool,
+    merchant_id: &str,
+    after_id: i64,
+    limit: i64,
+) -> Result<Vec<MerchantEvent>, InvoiceError> {
+    let rows = sqlx::query_as::<_, MerchantEvent>(
+        "SELECT e.id, e.invoice_id, i.memo_code, e.event_type, e.txid, e.block_height, e.created_at,
+                i.status AS invoice_status, i.received_zatoshis
+         FROM invoice_events e JOIN invoices i ON i.id = e.invoice_id
+         WHERE i.merchant_id = ? AND e.id > ?
+         ORDER BY e.id ASC LIMIT ?"
+    )
+    .bind(merchant_id)
+    .bind(after_id)
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+/// ID of the most recent event on any invoice, or 0 if there are none.
+pub async fn latest_id_overall(pool: &SqlitePool) -> Result<i64, InvoiceError> {
+    let id: Option<i64> = sqlx::query_scalar("SELECT MAX(id) FROM invoice_events")
+        .fetch_one(pool)
+        .await?;
+    Ok(id.unwrap_or(0))
+}
+
+/// Invoice status a timeline event moves the invoice to, for events the public
+/// stream forwards. Scanner sightings and refund bookkeeping return None.
+pub fn status_for(event_type: &str) -> Option<&'static str> {
+    match event_type {
+        "created" => Some("pending"),
+        "detected" => Some("detected"),
+        "underpaid" => Some("underpaid"),
+        "confirmed" => Some("confirmed"),
+        "expired" | "cancelled" => Some("expired"),
+        "paid_late" => Some("paid_late"),
+        "refund_marked" | "refund_confirmed" => Some("refunded"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{test_invoice, test_merchant, test_pool};
+    use crate::invoices;
+
+    #[tokio::test]
+    async fn test_transitions_build_the_timeline() {
+        let pool = test_pool().await;
+        let merchant = test_merchant(&pool, 1).await;
+        let id = test_invoice(&pool, &merchant.merchant_id, 1).await.invoice_id;
+
+        invoices::mark_underpaid(&pool, &id, 10_000_000, "tx-1").await.unwrap();
+        let (total, detected) = invoices::accumulate_payment(&pool, &id, "tx-2", 15_000_000, 25_000_000).await.unwrap();
+        assert_eq!((total, detected), (25_000_000, true));
+        assert!(invoices::mark_confirmed(&pool, &id, "tx-2", Some(120)).await.unwrap());
+        // A transition that does not apply records nothing.
+        assert!(!invoices::mark_confirmed(&pool, &id, "tx-2", Some(121)).await.unwrap());
+
+        let timeline = list(&pool, &id).await.unwrap();
+        let types: Vec<_> = timeline.iter().map(|e| e.event_type.as_str()).collect();
+        assert_eq!(types, ["created", "underpaid", "detected", "confirmed"]);
+        assert_eq!(timeline[1].txid.as_deref(), Some("tx-1"));
+        assert_eq!(timeline[2].detail.as_ref().unwrap()["received_zatoshis"], 25_000_000);
+        assert_eq!(timeline[3].block_height, Some(120));
+        assert!(timeline.windows(2).all(|w| w[0].id < w[1].id));
+
+        let after_created = since(&pool, &id, timeline[0].id).await.unwrap();
+        assert_eq!(after_created.len(), 3);
+        assert_eq!(latest_id(&pool, &id).await.unwrap(), timeline[3].id);
+        assert_eq!(latest_id(&pool, "missing").await.unwrap(), 0);
+        assert_eq!(status_for("detected"), Some("detected"));
+        assert_eq!(status_for("mempool_seen"), None);
+    }
+}
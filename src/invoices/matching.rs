@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use super::Invoice;
 
 /// Primary matching: find an invoice by its Orchard receiver address.
@@ -11,6 +13,17 @@ pub fn find_by_address<'a>(
     })
 }
 
+/// Transparent matching: find an invoice by the P2PKH receiver behind its TEX address.
+/// Transparent outputs carry no memo, so this is the only way to match them.
+pub fn find_by_transparent<'a>(
+    invoices: &'a [Invoice],
+    receiver_hex: &str,
+) -> Option<&'a Invoice> {
+    invoices.iter().find(|i| {
+        i.transparent_receiver_hex.as_deref() == Some(receiver_hex)
+    })
+}
+
 /// Fallback matching: find a pending invoice whose memo_code matches the decrypted memo text.
 /// Only used for old invoices created before diversified addresses were enabled.
 pub fn find_by_memo<'a>(
@@ -43,3 +56,67 @@ pub fn find_matching_invoice<'a>(
 
     find_by_memo(invoices, memo_text)
 }
+
+/// The scan cycle's pending invoices keyed by receiver and memo code, so matching an
+/// output is a hash lookup rather than a pass over every pending invoice. Rebuilt each
+/// cycle with the pending set; same precedence as `find_matching_invoice`.
+pub struct PendingIndex<'a> {
+    by_receiver: HashMap<&'a str, &'a Invoice>,
+    by_transparent: HashMap<&'a str, &'a Invoice>,
+    by_memo: HashMap<&'a str, &'a Invoice>,
+    has_settlement: bool,
+}
+
+impl<'a> PendingIndex<'a> {
+    pub fn new(invoices: &'a [Invoice]) -> Self {
+        let mut index = Self {
+            by_receiver: HashMap::with_capacity(invoices.len()),
+            by_transparent: HashMap::new(),
+            by_memo: HashMap::with_capacity(invoices.len()),
+            has_settlement: invoices.iter().any(Invoice::is_settlement),
+        };
+        // First wins, like the linear search.
+        for invoice in invoices {
+            if let Some(receiver) = invoice.orchard_receiver_hex.as_deref() {
+                index.by_receiver.entry(receiver).or_insert(invoice);
+            }
+            if let Some(receiver) = invoice.transparent_receiver_hex.as_deref() {
+                index.by_transparent.entry(receiver).or_insert(invoice);
+            }
+            index.by_memo.entry(invoice.memo_code.as_str()).or_insert(invoice);
+        }
+        index
+    }
+
+    pub fn by_address(&self, recipient_hex: &str) -> Option<&'a Invoice> {
+        self.by_receiver.get(recipient_hex).copied()
+    }
+
+    pub fn by_transparent(&self, receiver_hex: &str) -> Option<&'a Invoice> {
+        self.by_transparent.get(receiver_hex).copied()
+    }
+
+    pub fn has_transparent(&self) -> bool {
+        !self.by_transparent.is_empty()
+    }
+
+    /// Whether a settlement invoice is pending, so the fee wallet's key is worth trying.
+    pub fn has_settlement(&self) -> bool {
+        self.has_settlement
+    }
+
+    /// The whole trimmed memo, else any word of it (memo codes are letters, digits and
+    /// hyphens), so "Order CP-1A2B3C4D, thanks" still matches.
+    pub fn by_memo(&self, memo_text: &str) -> Option<&'a Invoice> {
+        let memo_trimmed = memo_text.trim();
+        if memo_trimmed.is_empty() {
+            return None;
+        }
+        if let Some(invoice) = self.by_memo.get(memo_trimmed) {
+            return Some(invoice);
+        }
+        memo_trimmed
+            .split(|c: char| !(c.is_ascii_alphanumeric() || c == '-'))
+            .find_map(|word| self.by_memo.get(word).copied())
+    }
+}
@@ -0,0 +1,74 @@
+//! ZIP-321 `memo` parameters. Wallets reject a whole payment URI over a memo they cannot
+//! take, so every memo goes through `param`: at most 512 bytes once decoded, no control
+//! characters, base64url without padding.
+
+use base64::Engine;
+
+/// Size of a Zcash memo field.
+pub const MAX_BYTES: usize = 512;
+
+/// The memo text as it will be sent: control characters removed (a NUL ends the memo when
+/// decrypted, so nothing after it would be matched), surrounding whitespace trimmed and
+/// cut to `MAX_BYTES` on a character boundary.
+pub fn sanitize(text: &str) -> String {
+    let mut memo: String = text.chars().filter(|c| !c.is_control()).collect();
+    let trimmed = memo.trim();
+    if trimmed.len() != memo.len() {
+        memo = trimmed.to_string();
+    }
+    if memo.len() > MAX_BYTES {
+        let mut end = MAX_BYTES;
+        while !memo.is_char_boundary(end) {
+            end -= 1;
+        }
+        memo.truncate(end);
+        memo.truncate(memo.trim_end().len());
+    }
+    memo
+}
+
+/// The `memo` parameter value for `text`.
+pub fn param(text: &str) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(sanitize(text).as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(param: &str) -> String {
+        let bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(param).unwrap();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn test_param_alphabet() {
+        // Zashi rejects padded memos and some wallets form-decode the query ('+' becomes
+        // a space), so the value must stay within [A-Za-z0-9_-] at every padding length.
+        for text in ["C", "CP", "CP-", "CP-A", "CP-AB", "FEE-x?>", "\u{fb}\u{ff}\u{fe}"] {
+            let p = param(text);
+            assert!(p.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_'), "{}", p);
+            assert_eq!(decode(&p), text);
+        }
+    }
+
+    #[test]
+    fn test_param_survives_uri_parsing() {
+        // YWallet and Zashi both read `memo` as a query value; it must come back unchanged.
+        let p = param("CP-1A2B3C4D ünïcode");
+        let uri = format!("zcash:utest1abc?amount=1.00000000&memo={}", p);
+        let parsed = url::Url::parse(&uri).unwrap();
+        let memo = parsed.query_pairs().find(|(k, _)| k == "memo").unwrap().1;
+        assert_eq!(decode(&memo), "CP-1A2B3C4D ünïcode");
+    }
+
+    #[test]
+    fn test_sanitize_strips_and_truncates() {
+        assert_eq!(sanitize("  CP-AB\0CD\r\n"), "CP-ABCD");
+        assert_eq!(sanitize(&"a".repeat(600)).len(), MAX_BYTES);
+        // 511 ASCII bytes plus a 2-byte character: cut before it rather than split it.
+        let text = format!("{}é", "a".repeat(511));
+        assert_eq!(sanitize(&text), "a".repeat(511));
+        assert_eq!(decode(&param(&text)).len(), 511);
+    }
+}
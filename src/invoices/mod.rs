@@ -1,19 +1,61 @@
+pub mod amounts;
+pub mod diagnose;
+pub mod display;
+pub mod events;
 pub mod matching;
+pub mod memo;
 pub mod pricing;
+pub mod purge;
+pub mod requests;
+pub mod state;
+pub mod tax;
+pub mod templates;
+pub mod views;
 
-use base64::Engine;
 use chrono::{Duration, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::{FromRow, SqlitePool};
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub use amounts::zatoshis_to_zec;
+use state::{InvoiceState, Transition};
+
+#[derive(Debug, thiserror::Error)]
+pub enum InvoiceError {
+    #[error("Invoice not found")]
+    NotFound,
+    #[error("Invoice status does not allow this")]
+    InvalidStatus,
+    #[error("Invoice quota exceeded: at most {limit} {quota}")]
+    QuotaExceeded { quota: &'static str, limit: i64 },
+    #[error("Invoice amount is below the minimum of {minimum}")]
+    BelowMinimum { minimum: String },
+    #[error("Could not derive a payment address: {0}")]
+    Address(#[source] anyhow::Error),
+    #[error(transparent)]
+    Merchant(#[from] crate::merchants::MerchantError),
+    #[error("Database error: {0}")]
+    Database(#[source] sqlx::Error),
+}
+
+impl From<sqlx::Error> for InvoiceError {
+    fn from(e: sqlx::Error) -> Self {
+        match e {
+            sqlx::Error::RowNotFound => InvoiceError::NotFound,
+            e => InvoiceError::Database(e),
+        }
+    }
+}
+
+/// Database row. Not serializable on purpose: responses go through `views`.
+#[derive(Debug, Clone, FromRow)]
 pub struct Invoice {
     pub id: String,
     pub merchant_id: String,
     pub memo_code: String,
     pub product_name: Option<String>,
     pub size: Option<String>,
+    pub quantity: i64,
     pub price_eur: f64,
     pub price_usd: Option<f64>,
     pub currency: Option<String>,
@@ -22,6 +64,8 @@ pub struct Invoice {
     pub payment_address: String,
     pub zcash_uri: String,
     pub merchant_name: Option<String>,
+    /// The merchant's network, which the invoice is paid on.
+    pub network: String,
     pub refund_address: Option<String>,
     pub status: String,
     pub detected_txid: Option<String>,
@@ -31,13 +75,112 @@ pub struct Invoice {
     pub expires_at: String,
     pub purge_after: Option<String>,
     pub created_at: String,
-    #[serde(skip_serializing)]
     pub orchard_receiver_hex: Option<String>,
-    #[serde(skip_serializing)]
     #[allow(dead_code)]
     pub diversifier_index: Option<i64>,
+    /// P2PKH key hash behind `tex_address`, for merchants that opted in to TEX payments.
+    pub transparent_receiver_hex: Option<String>,
+    pub tex_address: Option<String>,
     pub price_zatoshis: i64,
     pub received_zatoshis: i64,
+    pub tax_rate: Option<f64>,
+    pub tax_amount: Option<f64>,
+    pub tax_inclusive: Option<bool>,
+    pub tax_country: Option<String>,
+    pub on_expiry: String,
+    pub requote_count: i64,
+    pub display_currency: Option<String>,
+    pub locale: Option<String>,
+}
+
+/// A pending invoice with every field filled in, for unit tests.
+#[cfg(test)]
+pub(crate) fn test_invoice() -> Invoice {
+    Invoice {
+        id: "inv-1".into(),
+        merchant_id: "m-1".into(),
+        memo_code: "CP-00000001".into(),
+        product_name: Some("Shirt".into()),
+        size: None,
+        quantity: 1,
+        price_eur: 10.0,
+        price_usd: Some(11.0),
+        currency: Some("EUR".into()),
+        price_zec: 0.25,
+        zec_rate_at_creation: 40.0,
+        payment_address: "utest1".into(),
+        zcash_uri: "zcash:utest1?amount=0.25".into(),
+        merchant_name: None,
+        network: "testnet".into(),
+        refund_address: Some("u1refund".into()),
+        status: "pending".into(),
+        detected_txid: None,
+        detected_at: None,
+        confirmed_at: None,
+        refunded_at: None,
+        expires_at: "2030-01-01T00:00:00Z".into(),
+        purge_after: None,
+        created_at: "2030-01-01T00:00:00Z".into(),
+        orchard_receiver_hex: Some("abcd".into()),
+        diversifier_index: Some(7),
+        transparent_receiver_hex: None,
+        tex_address: None,
+        price_zatoshis: 25_000_000,
+        received_zatoshis: 0,
+        tax_rate: None,
+        tax_amount: None,
+        tax_inclusive: None,
+        tax_country: None,
+        on_expiry: "expire".into(),
+        requote_count: 0,
+        display_currency: None,
+        locale: None,
+    }
+}
+
+impl Invoice {
+    /// Status as an `InvoiceState`; `None` for a status this build doesn't know.
+    pub fn state(&self) -> Option<InvoiceState> {
+        InvoiceState::parse(&self.status)
+    }
+
+    /// A fee settlement invoice, paid to the operator's fee wallet rather than the merchant's.
+    pub fn is_settlement(&self) -> bool {
+        self.memo_code.starts_with(SETTLEMENT_MEMO_PREFIX)
+    }
+
+    /// Itemized tax for display (hosted page, receipts), or null when none was charged.
+    /// The payment URI for `format`; `None` for TEX when the invoice has no TEX address.
+    pub fn payment_uri(&self, format: UriFormat) -> Option<String> {
+        let simple = || simple_zcash_uri(&self.payment_address, self.price_zec, &self.memo_code);
+        match format {
+            UriFormat::Multi if self.zcash_uri.is_empty() => Some(simple()),
+            UriFormat::Multi => Some(self.zcash_uri.clone()),
+            UriFormat::Simple => Some(simple()),
+            UriFormat::Tex => self.tex_address.as_deref().map(|tex| tex_zcash_uri(tex, self.price_zec)),
+        }
+    }
+
+    pub fn tax_json(&self) -> serde_json::Value {
+        match self.tax_amount {
+            Some(amount) => serde_json::json!({
+                "rate": self.tax_rate,
+                "inclusive": self.tax_inclusive.unwrap_or(false),
+                "country": self.tax_country,
+                "amount": amount,
+                "subtotal": amounts::round_fiat(self.total_in_currency() - amount),
+            }),
+            None => serde_json::Value::Null,
+        }
+    }
+
+    /// Invoice total in its own currency (tax amounts are stored in that currency).
+    fn total_in_currency(&self) -> f64 {
+        match (self.currency.as_deref(), self.price_usd) {
+            (Some("USD"), Some(usd)) => usd,
+            _ => self.price_eur,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, FromRow)]
@@ -48,6 +191,41 @@ pub struct InvoiceStatus {
     pub detected_txid: Option<String>,
     pub received_zatoshis: i64,
     pub price_zatoshis: i64,
+    pub remaining_zatoshis: i64,
+    pub confirmations: Option<i64>,
+}
+
+/// Confirmations of invoice `i`'s most recently mined payment, counted to the scanner's
+/// last scanned height on its merchant's network. NULL until a payment is mined.
+const CONFIRMATIONS_SQL: &str =
+    "(SELECT MAX(CAST(s.value AS INTEGER) - MAX(p.block_height) + 1, 1)
+      FROM invoice_payments p
+      JOIN scanner_state s ON s.key = 'last_height:' || (SELECT network FROM merchants WHERE id = i.merchant_id)
+      WHERE p.invoice_id = i.id AND p.block_height IS NOT NULL)";
+
+/// A single transaction contributing to an invoice's received amount.
+#[derive(Debug, Serialize, FromRow)]
+pub struct InvoicePayment {
+    pub txid: String,
+    pub amount_zatoshis: i64,
+    pub block_height: Option<i64>,
+    pub seen_at: String,
+    /// Miner fee the buyer's transaction paid; null if it spent transparent funds or
+    /// was recorded before fees were read.
+    pub fee_zatoshis: Option<i64>,
+    /// Outputs of the buyer's transaction (Orchard actions, padded to at least two,
+    /// plus Sapling and transparent outputs).
+    pub tx_outputs: Option<i64>,
+}
+
+/// A refund the merchant has submitted a txid for, awaiting scanner verification.
+#[derive(Debug, FromRow)]
+pub struct PendingRefund {
+    pub id: String,
+    pub merchant_id: String,
+    pub refund_address: Option<String>,
+    pub refund_txid: String,
+    pub refund_zatoshis: i64,
 }
 
 #[derive(Debug, Deserialize)]
@@ -55,9 +233,34 @@ pub struct CreateInvoiceRequest {
     pub product_id: Option<String>,
     pub product_name: Option<String>,
     pub size: Option<String>,
+    /// Number of units; informational here, `price_eur` is always the invoice total.
+    pub quantity: Option<i64>,
     pub price_eur: f64,
     pub currency: Option<String>,
     pub refund_address: Option<String>,
+    /// Set by checkout from the merchant's tax settings; `price_eur` is then the tax-inclusive total.
+    #[serde(skip)]
+    pub tax: Option<tax::TaxBreakdown>,
+    /// `expire` (default) or `requote`: reprice at the current rate instead of expiring.
+    pub on_expiry: Option<String>,
+    /// Fiat currency the buyer sees on the hosted page and receipt (EUR or USD).
+    pub display_currency: Option<String>,
+    /// Language tag for formatting amounts, e.g. `de-DE`.
+    pub locale: Option<String>,
+    /// Set by checkout: the buyer's answers to the product's checkout fields, sealed
+    /// with `products::fields::seal`.
+    #[serde(skip)]
+    pub custom_fields: Option<String>,
+    /// Set by invoice templates: replaces `CP` in the memo code.
+    #[serde(skip)]
+    pub memo_prefix: Option<String>,
+    /// Set by invoice templates: minutes until expiry instead of the server default.
+    #[serde(skip)]
+    pub expiry_minutes: Option<i64>,
+    /// Issue a claim code the buyer can enter on the hosted site to get back to the
+    /// invoice without its URL, e.g. after paying at a market stall.
+    #[serde(default)]
+    pub claim_code: bool,
 }
 
 #[derive(Debug, Serialize)]
@@ -70,19 +273,201 @@ pub struct CreateInvoiceResponse {
     pub zec_rate: f64,
     pub payment_address: String,
     pub zcash_uri: String,
+    /// Present when the merchant opted in to TEX payments.
+    pub tex_address: Option<String>,
+    /// Present when the request asked for one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub claim_code: Option<String>,
     pub expires_at: String,
 }
 
-fn generate_memo_code() -> String {
-    let bytes: [u8; 4] = rand::random();
-    format!("CP-{}", hex::encode(bytes).to_uppercase())
+/// 64 random bits, so memo codes cannot be guessed to look up other buyers' invoices.
+fn generate_memo_code(prefix: Option<&str>) -> String {
+    let bytes: [u8; 8] = rand::random();
+    format!("{}-{}", prefix.unwrap_or("CP"), hex::encode(bytes).to_uppercase())
+}
+
+/// Letters and digits that cannot be misread for one another (no 0/O, 1/I/L).
+const CLAIM_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789";
+const CLAIM_CODE_LEN: usize = 8;
+
+/// Eight characters read out as `XXXX-XXXX`: about 2^39 codes, short enough to copy off a
+/// receipt by hand. Guessing is held off by the lookup limits and bans, not the length.
+fn generate_claim_code() -> String {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    let code: String = (0..CLAIM_CODE_LEN)
+        .map(|_| CLAIM_CODE_ALPHABET[rng.gen_range(0..CLAIM_CODE_ALPHABET.len())] as char)
+        .collect();
+    format!("{}-{}", &code[..4], &code[4..])
+}
+
+/// A claim code as typed by a buyer, in its stored form: case, spaces and dashes are
+/// ignored. `None` if it cannot be a claim code, so it is refused without a query.
+pub fn normalize_claim_code(input: &str) -> Option<String> {
+    let code: String = input
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    if code.len() != CLAIM_CODE_LEN || !code.bytes().all(|b| CLAIM_CODE_ALPHABET.contains(&b)) {
+        return None;
+    }
+    Some(format!("{}-{}", &code[..4], &code[4..]))
 }
 
+/// Most times an invoice with `on_expiry = requote` is repriced before it expires for good.
+pub const MAX_REQUOTES: i64 = 5;
+
 pub struct FeeConfig {
     pub fee_address: String,
     pub fee_rate: f64,
+    /// The primary network; only its invoices carry a fee output.
+    pub network: String,
+}
+
+impl FeeConfig {
+    pub fn from_config(config: &crate::config::Config) -> Option<Self> {
+        if !config.fee_enabled() {
+            return None;
+        }
+        config.fee_address.as_ref().map(|addr| FeeConfig {
+            fee_address: addr.clone(),
+            fee_rate: config.fee_rate,
+            network: config.network.clone(),
+        })
+    }
+
+    /// `from_config`, or None while the merchant's fees are waived or it is on another
+    /// network than the fee address, so customers are not asked to pay a fee output.
+    pub async fn for_merchant(pool: &SqlitePool, config: &crate::config::Config, merchant: &crate::merchants::Merchant) -> Option<Self> {
+        let fee_config = Self::from_config(config).filter(|f| f.network == merchant.network)?;
+        match crate::billing::get_fee_promo(pool, &merchant.id).await {
+            Ok(promo) if promo.waives_all() => None,
+            _ => Some(fee_config),
+        }
+    }
+}
+
+/// Per-merchant creation limits, so a runaway integration cannot flood the scanner's
+/// pending set, and the smallest amount an invoice may ask for. 0 turns a limit off.
+pub struct InvoiceQuotas {
+    /// Invoices still awaiting payment (pending or underpaid).
+    pub max_open: i64,
+    pub max_per_hour: i64,
+    /// In the invoice's own currency.
+    pub min_fiat: f64,
+    pub min_zatoshis: i64,
 }
 
+impl InvoiceQuotas {
+    pub fn from_config(config: &crate::config::Config) -> Self {
+        Self {
+            max_open: config.max_open_invoices_per_merchant,
+            max_per_hour: config.max_invoices_per_merchant_per_hour,
+            min_fiat: config.min_invoice_fiat,
+            min_zatoshis: config.min_invoice_zatoshis,
+        }
+    }
+
+    /// Reject an amount too small to be paid: below the dust floor, partial payments
+    /// are dropped by the scanner and the invoice can never fill up.
+    fn check_amount(&self, fiat: f64, currency: &str, price_zatoshis: i64) -> Result<(), InvoiceError> {
+        if fiat < self.min_fiat {
+            return Err(InvoiceError::BelowMinimum { minimum: format!("{:.2} {}", self.min_fiat, currency) });
+        }
+        if price_zatoshis < self.min_zatoshis {
+            return Err(InvoiceError::BelowMinimum {
+                minimum: format!("{:.8} ZEC", zatoshis_to_zec(self.min_zatoshis)),
+            });
+        }
+        Ok(())
+    }
+
+    async fn check(&self, pool: &SqlitePool, merchant_id: &str) -> Result<(), InvoiceError> {
+        if self.max_open <= 0 && self.max_per_hour <= 0 {
+            return Ok(());
+        }
+        let (open, last_hour): (i64, i64) = sqlx::query_as(
+            "SELECT COUNT(CASE WHEN status IN ('pending', 'underpaid') THEN 1 END),
+                    COUNT(CASE WHEN created_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now', '-1 hour') THEN 1 END)
+             FROM invoices WHERE merchant_id = ?
+             AND (status IN ('pending', 'underpaid')
+                  OR created_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now', '-1 hour'))"
+        )
+        .bind(merchant_id)
+        .fetch_one(pool)
+        .await?;
+
+        if self.max_open > 0 && open >= self.max_open {
+            return Err(InvoiceError::QuotaExceeded { quota: "open invoices", limit: self.max_open });
+        }
+        if self.max_per_hour > 0 && last_hour >= self.max_per_hour {
+            return Err(InvoiceError::QuotaExceeded { quota: "invoices per hour", limit: self.max_per_hour });
+        }
+        Ok(())
+    }
+}
+
+/// Which payment URI the checkout showed the buyer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UriFormat {
+    /// `zcash_uri`: the merchant output plus the fee output when fees are enabled.
+    Multi,
+    /// `zcash_uri_simple`: the merchant output only, for wallets that cannot pay (or
+    /// silently drop) the second output of a ZIP-321 multi-payment request.
+    Simple,
+    /// `tex.zcash_uri`: the invoice's TEX address, for buyers paying from an exchange.
+    Tex,
+}
+
+impl UriFormat {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            UriFormat::Multi => "multi",
+            UriFormat::Simple => "simple",
+            UriFormat::Tex => "tex",
+        }
+    }
+}
+
+/// Single-output ZIP-321 URI paying the merchant.
+pub fn simple_zcash_uri(payment_address: &str, price_zec: f64, memo_code: &str) -> String {
+    format!("zcash:{}?amount={:.8}&memo={}", payment_address, price_zec, memo::param(memo_code))
+}
+
+/// ZIP-321 URI paying a TEX address. Transparent outputs cannot carry a memo.
+pub fn tex_zcash_uri(tex_address: &str, price_zec: f64) -> String {
+    format!("zcash:{}?amount={:.8}", tex_address, price_zec)
+}
+
+/// ZIP-321 payment URI for an invoice, with a second output for the platform fee when enabled.
+fn build_zcash_uri(
+    payment_address: &str,
+    price_zec: f64,
+    memo_code: &str,
+    invoice_id: &str,
+    fee_config: Option<&FeeConfig>,
+) -> String {
+    let memo_b64 = memo::param(memo_code);
+
+    if let Some(fc) = fee_config {
+        let fee_amount = price_zec * fc.fee_rate;
+        if fee_amount >= 0.00000001 {
+            let fee_memo = format!("FEE-{}", invoice_id);
+            let fee_memo_b64 = memo::param(&fee_memo);
+            return format!(
+                "zcash:?address={}&amount={:.8}&memo={}&address.1={}&amount.1={:.8}&memo.1={}",
+                payment_address, price_zec, memo_b64,
+                fc.fee_address, fee_amount, fee_memo_b64
+            );
+        }
+    }
+    simple_zcash_uri(payment_address, price_zec, memo_code)
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn create_invoice(
     pool: &SqlitePool,
     merchant_id: &str,
@@ -92,58 +477,59 @@ pub async fn create_invoice(
     zec_usd: f64,
     expiry_minutes: i64,
     fee_config: Option<&FeeConfig>,
-) -> anyhow::Result<CreateInvoiceResponse> {
-    let id = Uuid::new_v4().to_string();
-    let memo_code = generate_memo_code();
+    quotas: &InvoiceQuotas,
+) -> Result<CreateInvoiceResponse, InvoiceError> {
     let currency = req.currency.as_deref().unwrap_or("EUR");
     let (price_eur, price_usd, price_zec) = if currency == "USD" {
-        let usd = req.price_eur;
-        let zec = usd / zec_usd;
-        let eur = zec * zec_eur;
+        let usd = amounts::round_fiat(req.price_eur);
+        let zec = amounts::round_zec(usd / zec_usd);
+        let eur = amounts::round_fiat(zec * zec_eur);
         (eur, usd, zec)
     } else {
-        let zec = req.price_eur / zec_eur;
-        let usd = zec * zec_usd;
-        (req.price_eur, usd, zec)
+        let eur = amounts::round_fiat(req.price_eur);
+        let zec = amounts::round_zec(eur / zec_eur);
+        let usd = amounts::round_fiat(zec * zec_usd);
+        (eur, usd, zec)
     };
+    let price_zatoshis = amounts::zec_to_zatoshis(price_zec);
+    quotas.check_amount(if currency == "USD" { price_usd } else { price_eur }, currency, price_zatoshis)?;
+    quotas.check(pool, merchant_id).await?;
+
+    let id = Uuid::new_v4().to_string();
+    let memo_code = generate_memo_code(req.memo_prefix.as_deref());
+    let claim_code = req.claim_code.then(generate_claim_code);
     let expires_at = (Utc::now() + Duration::minutes(expiry_minutes))
         .format("%Y-%m-%dT%H:%M:%SZ")
         .to_string();
     let created_at = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
 
     let div_index = crate::merchants::next_diversifier_index(pool, merchant_id).await?;
-    let derived = crate::addresses::derive_invoice_address(merchant_ufvk, div_index)?;
+    let derived = crate::addresses::derive_invoice_address(merchant_ufvk, div_index)
+        .map_err(InvoiceError::Address)?;
     let payment_address = &derived.ua_string;
 
-    let memo_b64 = base64::engine::general_purpose::URL_SAFE_NO_PAD
-        .encode(memo_code.as_bytes());
-
-    let zcash_uri = if let Some(fc) = fee_config {
-        let fee_amount = price_zec * fc.fee_rate;
-        if fee_amount >= 0.00000001 {
-            let fee_memo = format!("FEE-{}", id);
-            let fee_memo_b64 = base64::engine::general_purpose::URL_SAFE_NO_PAD
-                .encode(fee_memo.as_bytes());
-            format!(
-                "zcash:?address={}&amount={:.8}&memo={}&address.1={}&amount.1={:.8}&memo.1={}",
-                payment_address, price_zec, memo_b64,
-                fc.fee_address, fee_amount, fee_memo_b64
-            )
-        } else {
-            format!("zcash:{}?amount={:.8}&memo={}", payment_address, price_zec, memo_b64)
-        }
+    let tex_enabled: bool = sqlx::query_scalar("SELECT tex_enabled FROM merchants WHERE id = ?")
+        .bind(merchant_id)
+        .fetch_optional(pool)
+        .await?
+        .unwrap_or(false);
+    let tex = if tex_enabled {
+        crate::addresses::derive_tex_address(merchant_ufvk, div_index)
+            .map_err(InvoiceError::Address)?
     } else {
-        format!("zcash:{}?amount={:.8}&memo={}", payment_address, price_zec, memo_b64)
+        None
     };
 
-    let price_zatoshis = (price_zec * 100_000_000.0) as i64;
+    let zcash_uri = build_zcash_uri(payment_address, price_zec, &memo_code, &id, fee_config);
 
     sqlx::query(
-        "INSERT INTO invoices (id, merchant_id, memo_code, product_id, product_name, size,
+        "INSERT INTO invoices (id, merchant_id, memo_code, product_id, product_name, size, quantity,
          price_eur, price_usd, currency, price_zec, zec_rate_at_creation, payment_address, zcash_uri,
          refund_address, status, expires_at, created_at,
-         diversifier_index, orchard_receiver_hex, price_zatoshis)
-         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, 'pending', ?, ?, ?, ?, ?)"
+         diversifier_index, orchard_receiver_hex, transparent_receiver_hex, tex_address, price_zatoshis,
+         tax_rate, tax_amount, tax_inclusive, tax_country, on_expiry, display_currency, locale, custom_fields,
+         claim_code)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, 'pending', ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
     )
     .bind(&id)
     .bind(merchant_id)
@@ -151,6 +537,7 @@ pub async fn create_invoice(
     .bind(&req.product_id)
     .bind(&req.product_name)
     .bind(&req.size)
+    .bind(req.quantity.unwrap_or(1))
     .bind(price_eur)
     .bind(price_usd)
     .bind(currency)
@@ -163,7 +550,18 @@ pub async fn create_invoice(
     .bind(&created_at)
     .bind(div_index as i64)
     .bind(&derived.orchard_receiver_hex)
+    .bind(tex.as_ref().map(|t| t.transparent_receiver_hex.as_str()))
+    .bind(tex.as_ref().map(|t| t.tex_address.as_str()))
     .bind(price_zatoshis)
+    .bind(req.tax.as_ref().map(|t| t.rate))
+    .bind(req.tax.as_ref().map(|t| t.tax_amount))
+    .bind(req.tax.as_ref().map(|t| t.inclusive))
+    .bind(req.tax.as_ref().and_then(|t| t.country.clone()))
+    .bind(req.on_expiry.as_deref().unwrap_or("expire"))
+    .bind(&req.display_currency)
+    .bind(&req.locale)
+    .bind(&req.custom_fields)
+    .bind(&claim_code)
     .execute(pool)
     .await?;
 
@@ -173,6 +571,10 @@ pub async fn create_invoice(
         diversifier_index = div_index,
         "Invoice created with unique address"
     );
+    events::record(pool, &id, "created", None, None, Some(serde_json::json!({
+        "price_zatoshis": price_zatoshis,
+        "expires_at": &expires_at,
+    }))).await;
 
     Ok(CreateInvoiceResponse {
         invoice_id: id,
@@ -183,25 +585,127 @@ pub async fn create_invoice(
         zec_rate: zec_eur,
         payment_address: payment_address.to_string(),
         zcash_uri,
+        tex_address: tex.map(|t| t.tex_address),
+        claim_code,
         expires_at,
     })
 }
 
-pub async fn get_invoice(pool: &SqlitePool, id: &str) -> anyhow::Result<Option<Invoice>> {
-    let row = sqlx::query_as::<_, Invoice>(
-        "SELECT i.id, i.merchant_id, i.memo_code, i.product_name, i.size,
-         i.price_eur, i.price_usd, i.currency, i.price_zec, i.zec_rate_at_creation,
-         COALESCE(NULLIF(i.payment_address, ''), m.payment_address) AS payment_address,
-         i.zcash_uri,
-         NULLIF(m.name, '') AS merchant_name,
-         i.refund_address, i.status, i.detected_txid, i.detected_at,
-         i.confirmed_at, i.refunded_at, i.expires_at, i.purge_after, i.created_at,
-         i.orchard_receiver_hex, i.diversifier_index,
-         i.price_zatoshis, i.received_zatoshis
-         FROM invoices i
-         LEFT JOIN merchants m ON m.id = i.merchant_id
-         WHERE i.id = ?"
+/// Memo codes of fee settlement invoices start with this.
+pub const SETTLEMENT_MEMO_PREFIX: &str = "SETTLE-";
+
+/// Next diversifier index of the operator's fee wallet for a settlement invoice. Starts
+/// at 1: the fee address that ordinary invoices' fee outputs pay is left to index 0.
+async fn next_fee_diversifier_index(conn: &mut sqlx::SqliteConnection) -> Result<u32, InvoiceError> {
+    let index: i64 = sqlx::query_scalar(
+        "INSERT INTO scanner_state (key, value) VALUES ('fee_diversifier_index', '2')
+         ON CONFLICT(key) DO UPDATE SET value = CAST(value AS INTEGER) + 1
+         RETURNING CAST(value AS INTEGER) - 1"
     )
+    .fetch_one(conn)
+    .await?;
+    u32::try_from(index).map_err(|_| InvoiceError::Merchant(crate::merchants::MerchantError::DiversifierExhausted))
+}
+
+/// Create a fee settlement invoice for `price_zec`, billed to `merchant_id` and paid to a
+/// fresh diversified address of the operator's fee wallet (`fee_ufvk`), on `conn` so the
+/// caller can tie its billing cycle to it in one transaction. It is matched by address
+/// like any other invoice, carries no fee output or TEX address, and is not counted
+/// against the merchant's quotas. A cycle can have one unpaid settlement invoice at a time.
+#[allow(clippy::too_many_arguments)]
+pub async fn create_settlement_invoice(
+    conn: &mut sqlx::SqliteConnection,
+    merchant_id: &str,
+    billing_cycle_id: Option<&str>,
+    fee_ufvk: &str,
+    price_zec: f64,
+    zec_eur: f64,
+    zec_usd: f64,
+    expires_at: &str,
+) -> Result<CreateInvoiceResponse, InvoiceError> {
+    let id = Uuid::new_v4().to_string();
+    let memo_code = format!("{SETTLEMENT_MEMO_PREFIX}{}", &Uuid::new_v4().to_string()[..8].to_uppercase());
+    let created_at = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+    let price_zec = amounts::round_zec(price_zec);
+    let price_eur = amounts::round_fiat(price_zec * zec_eur);
+    let price_usd = amounts::round_fiat(price_zec * zec_usd);
+    let price_zatoshis = amounts::zec_to_zatoshis(price_zec);
+
+    let div_index = next_fee_diversifier_index(&mut *conn).await?;
+    let derived = crate::addresses::derive_invoice_address(fee_ufvk, div_index)
+        .map_err(InvoiceError::Address)?;
+    let zcash_uri = build_zcash_uri(&derived.ua_string, price_zec, &memo_code, &id, None);
+
+    sqlx::query(
+        "INSERT INTO invoices (id, merchant_id, memo_code, product_name, price_eur, price_usd, currency,
+         price_zec, zec_rate_at_creation, payment_address, zcash_uri, status, expires_at, created_at,
+         diversifier_index, orchard_receiver_hex, price_zatoshis, billing_cycle_id)
+         VALUES (?, ?, ?, 'Fee Settlement', ?, ?, 'EUR', ?, ?, ?, ?, 'pending', ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(&id)
+    .bind(merchant_id)
+    .bind(&memo_code)
+    .bind(price_eur)
+    .bind(price_usd)
+    .bind(price_zec)
+    .bind(zec_eur)
+    .bind(&derived.ua_string)
+    .bind(&zcash_uri)
+    .bind(expires_at)
+    .bind(&created_at)
+    .bind(div_index as i64)
+    .bind(&derived.orchard_receiver_hex)
+    .bind(price_zatoshis)
+    .bind(billing_cycle_id)
+    .execute(&mut *conn)
+    .await?;
+    events::insert(&mut *conn, &id, "created", None, None, Some(serde_json::json!({
+        "price_zatoshis": price_zatoshis,
+        "expires_at": expires_at,
+    }))).await?;
+
+    tracing::info!(invoice_id = %id, memo = %memo_code, diversifier_index = div_index, "Settlement invoice created with unique address");
+
+    Ok(CreateInvoiceResponse {
+        invoice_id: id,
+        memo_code,
+        price_eur,
+        price_usd,
+        price_zec,
+        zec_rate: zec_eur,
+        payment_address: derived.ua_string,
+        zcash_uri,
+        tex_address: None,
+        claim_code: None,
+        expires_at: expires_at.to_string(),
+    })
+}
+
+/// Every column of `Invoice`, read from `invoices i` joined to its merchant. All queries
+/// that map rows to `Invoice` go through `select_invoices`, so the list cannot drift from
+/// the struct.
+const INVOICE_COLUMNS: &str = "i.id, i.merchant_id, i.memo_code, i.product_name, i.size, i.quantity,
+     i.price_eur, i.price_usd, i.currency, i.price_zec, i.zec_rate_at_creation,
+     COALESCE(NULLIF(i.payment_address, ''), m.payment_address) AS payment_address,
+     i.zcash_uri,
+     NULLIF(m.name, '') AS merchant_name, COALESCE(m.network, '') AS network,
+     i.refund_address, i.status, i.detected_txid, i.detected_at,
+     i.confirmed_at, i.refunded_at, i.expires_at, i.purge_after, i.created_at,
+     i.orchard_receiver_hex, i.diversifier_index, i.transparent_receiver_hex, i.tex_address,
+     i.price_zatoshis, i.received_zatoshis,
+     i.tax_rate, i.tax_amount, i.tax_inclusive, i.tax_country,
+     i.on_expiry, i.requote_count, i.display_currency, i.locale";
+
+/// `SELECT` of `INVOICE_COLUMNS` followed by `conditions` (a `WHERE` clause, ordering,
+/// limits), with `i` the invoice and `m` its merchant.
+fn select_invoices(conditions: &str) -> String {
+    format!(
+        "SELECT {INVOICE_COLUMNS} FROM invoices i LEFT JOIN merchants m ON m.id = i.merchant_id {conditions}"
+    )
+}
+
+pub async fn get_invoice(pool: &SqlitePool, id: &str) -> Result<Option<Invoice>, InvoiceError> {
+    let row = sqlx::query_as::<_, Invoice>(&select_invoices("WHERE i.id = ?"))
     .bind(id)
     .fetch_optional(pool)
     .await?;
@@ -209,22 +713,9 @@ pub async fn get_invoice(pool: &SqlitePool, id: &str) -> anyhow::Result<Option<I
     Ok(row)
 }
 
-/// Look up an invoice by its memo code (e.g. CP-C6CDB775)
-pub async fn get_invoice_by_memo(pool: &SqlitePool, memo_code: &str) -> anyhow::Result<Option<Invoice>> {
-    let row = sqlx::query_as::<_, Invoice>(
-        "SELECT i.id, i.merchant_id, i.memo_code, i.product_name, i.size,
-         i.price_eur, i.price_usd, i.currency, i.price_zec, i.zec_rate_at_creation,
-         COALESCE(NULLIF(i.payment_address, ''), m.payment_address) AS payment_address,
-         i.zcash_uri,
-         NULLIF(m.name, '') AS merchant_name,
-         i.refund_address, i.status, i.detected_txid, i.detected_at,
-         i.confirmed_at, i.refunded_at, i.expires_at, i.purge_after, i.created_at,
-         i.orchard_receiver_hex, i.diversifier_index,
-         i.price_zatoshis, i.received_zatoshis
-         FROM invoices i
-         LEFT JOIN merchants m ON m.id = i.merchant_id
-         WHERE i.memo_code = ?"
-    )
+/// Look up an invoice by its memo code (e.g. CP-C6CDB7751A2B3C4D)
+pub async fn get_invoice_by_memo(pool: &SqlitePool, memo_code: &str) -> Result<Option<Invoice>, InvoiceError> {
+    let row = sqlx::query_as::<_, Invoice>(&select_invoices("WHERE i.memo_code = ?"))
     .bind(memo_code)
     .fetch_optional(pool)
     .await?;
@@ -232,10 +723,109 @@ pub async fn get_invoice_by_memo(pool: &SqlitePool, memo_code: &str) -> anyhow::
     Ok(row)
 }
 
-pub async fn get_invoice_status(pool: &SqlitePool, id: &str) -> anyhow::Result<Option<InvoiceStatus>> {
-    let row = sqlx::query_as::<_, InvoiceStatus>(
-        "SELECT id, status, detected_txid, received_zatoshis, price_zatoshis FROM invoices WHERE id = ?"
-    )
+/// Look up an invoice by a claim code already passed through `normalize_claim_code`.
+pub async fn get_invoice_by_claim_code(pool: &SqlitePool, claim_code: &str) -> Result<Option<Invoice>, InvoiceError> {
+    let row = sqlx::query_as::<_, Invoice>(&select_invoices("WHERE i.claim_code = ?"))
+    .bind(claim_code)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row)
+}
+
+/// What public memo code lookups may show of `merchant_id`'s invoices.
+pub async fn memo_lookup(pool: &SqlitePool, merchant_id: &str) -> Result<views::MemoLookup, InvoiceError> {
+    let value: Option<String> = sqlx::query_scalar("SELECT memo_lookup FROM merchants WHERE id = ?")
+        .bind(merchant_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(value.map(|v| views::MemoLookup::parse(&v)).unwrap_or_default())
+}
+
+/// Narrows `list_for_merchant`; unset fields match everything.
+#[derive(Debug, Default)]
+pub struct InvoiceFilter {
+    pub status: Option<InvoiceState>,
+    /// Only invoices that received more than this, e.g. `0` for expired invoices with
+    /// partial payments to refund.
+    pub received_gt: Option<i64>,
+}
+
+/// The buyer's answers to the product's checkout fields, if the invoice has any.
+pub async fn custom_fields(pool: &SqlitePool, invoice_id: &str, encryption_key: &str) -> anyhow::Result<Option<serde_json::Value>> {
+    let stored: Option<String> = sqlx::query_scalar("SELECT custom_fields FROM invoices WHERE id = ?")
+        .bind(invoice_id)
+        .fetch_optional(pool)
+        .await?
+        .flatten();
+    stored.map(|s| crate::products::fields::open(&s, encryption_key)).transpose()
+}
+
+/// A merchant's most recent invoices matching `filter`, newest first.
+pub async fn list_for_merchant(
+    pool: &SqlitePool,
+    merchant_id: &str,
+    filter: &InvoiceFilter,
+    limit: i64,
+) -> Result<Vec<Invoice>, InvoiceError> {
+    page_for_merchant(pool, merchant_id, filter, limit, 0).await
+}
+
+/// `list_for_merchant`, skipping the first `offset` matches.
+pub async fn page_for_merchant(
+    pool: &SqlitePool,
+    merchant_id: &str,
+    filter: &InvoiceFilter,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<Invoice>, InvoiceError> {
+    let rows = sqlx::query_as::<_, Invoice>(&select_invoices(
+        "WHERE i.merchant_id = ?1
+         AND (?2 IS NULL OR i.status = ?2)
+         AND (?3 IS NULL OR i.received_zatoshis > ?3)
+         ORDER BY i.created_at DESC, i.id LIMIT ?4 OFFSET ?5"
+    ))
+    .bind(merchant_id)
+    .bind(filter.status.map(InvoiceState::as_str))
+    .bind(filter.received_gt)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows)
+}
+
+static EXPORT_SQL: std::sync::LazyLock<String> = std::sync::LazyLock::new(|| select_invoices(
+    "WHERE i.merchant_id = ?1
+     AND (?2 IS NULL OR i.status = ?2)
+     AND (?3 IS NULL OR i.received_zatoshis > ?3)
+     ORDER BY i.created_at, i.id"
+));
+
+/// Every invoice of `merchant_id` matching `filter`, oldest first, as a fetch stream:
+/// rows are decoded as they are read and the query waits while the consumer does, so an
+/// export never holds the whole result. The stream keeps one connection until it ends.
+pub fn stream_for_merchant<'a>(
+    pool: &'a SqlitePool,
+    merchant_id: &'a str,
+    filter: &'a InvoiceFilter,
+) -> futures::stream::BoxStream<'a, Result<Invoice, sqlx::Error>> {
+    sqlx::query_as::<_, Invoice>(EXPORT_SQL.as_str())
+        .bind(merchant_id)
+        .bind(filter.status.map(InvoiceState::as_str))
+        .bind(filter.received_gt)
+        .fetch(pool)
+}
+
+pub async fn get_invoice_status(pool: &SqlitePool, id: &str) -> Result<Option<InvoiceStatus>, InvoiceError> {
+    let row = sqlx::query_as::<_, InvoiceStatus>(&format!(
+        "SELECT i.id, i.status, i.detected_txid, i.received_zatoshis, i.price_zatoshis,
+         MAX(i.price_zatoshis - i.received_zatoshis, 0) AS remaining_zatoshis,
+         {} AS confirmations
+         FROM invoices i WHERE i.id = ?",
+        CONFIRMATIONS_SQL
+    ))
     .bind(id)
     .fetch_optional(pool)
     .await?;
@@ -243,18 +833,43 @@ pub async fn get_invoice_status(pool: &SqlitePool, id: &str) -> anyhow::Result<O
     Ok(row)
 }
 
-pub async fn get_pending_invoices(pool: &SqlitePool) -> anyhow::Result<Vec<Invoice>> {
-    let rows = sqlx::query_as::<_, Invoice>(
-        "SELECT id, merchant_id, memo_code, product_name, size,
-         price_eur, price_usd, currency, price_zec, zec_rate_at_creation, payment_address, zcash_uri,
-         NULL AS merchant_name,
-         refund_address, status, detected_txid, detected_at,
-         confirmed_at, NULL AS refunded_at, expires_at, purge_after, created_at,
-         orchard_receiver_hex, diversifier_index,
-         price_zatoshis, received_zatoshis
-         FROM invoices WHERE status IN ('pending', 'underpaid', 'detected')
-         AND expires_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now')"
-    )
+/// See `CONFIRMATIONS_SQL`.
+pub async fn confirmations(pool: &SqlitePool, invoice_id: &str) -> Result<Option<i64>, InvoiceError> {
+    let confirmations = sqlx::query_scalar(&format!("SELECT {} FROM invoices i WHERE i.id = ?", CONFIRMATIONS_SQL))
+        .bind(invoice_id)
+        .fetch_optional(pool)
+        .await?
+        .flatten();
+    Ok(confirmations)
+}
+
+pub async fn get_pending_invoices(pool: &SqlitePool, network: &str) -> Result<Vec<Invoice>, InvoiceError> {
+    let rows = sqlx::query_as::<_, Invoice>(&select_invoices(
+        "WHERE i.status IN ('pending', 'underpaid', 'detected')
+         AND i.expires_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now')
+         AND m.network = ?"
+    ))
+    .bind(network)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows)
+}
+
+/// Invoices that expired less than `grace_minutes` ago and have no refund underway.
+/// The scanner keeps matching them so a payment broadcast just before expiry is not lost.
+pub async fn get_recently_expired(pool: &SqlitePool, network: &str, grace_minutes: i64) -> Result<Vec<Invoice>, InvoiceError> {
+    if grace_minutes <= 0 {
+        return Ok(Vec::new());
+    }
+    let cutoff = (Utc::now() - Duration::minutes(grace_minutes))
+        .format("%Y-%m-%dT%H:%M:%SZ")
+        .to_string();
+    let rows = sqlx::query_as::<_, Invoice>(&select_invoices(
+        "WHERE i.status = 'expired' AND i.expires_at > ? AND i.refund_txid IS NULL AND m.network = ?"
+    ))
+    .bind(&cutoff)
+    .bind(network)
     .fetch_all(pool)
     .await?;
 
@@ -262,18 +877,11 @@ pub async fn get_pending_invoices(pool: &SqlitePool) -> anyhow::Result<Vec<Invoi
 }
 
 /// Find a pending invoice by its Orchard receiver hex (O(1) indexed lookup).
-pub async fn find_by_orchard_receiver(pool: &SqlitePool, receiver_hex: &str) -> anyhow::Result<Option<Invoice>> {
-    let row = sqlx::query_as::<_, Invoice>(
-        "SELECT id, merchant_id, memo_code, product_name, size,
-         price_eur, price_usd, currency, price_zec, zec_rate_at_creation, payment_address, zcash_uri,
-         NULL AS merchant_name,
-         refund_address, status, detected_txid, detected_at,
-         confirmed_at, NULL AS refunded_at, expires_at, purge_after, created_at,
-         orchard_receiver_hex, diversifier_index,
-         price_zatoshis, received_zatoshis
-         FROM invoices WHERE orchard_receiver_hex = ? AND status IN ('pending', 'underpaid', 'detected')
-         AND expires_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now')"
-    )
+pub async fn find_by_orchard_receiver(pool: &SqlitePool, receiver_hex: &str) -> Result<Option<Invoice>, InvoiceError> {
+    let row = sqlx::query_as::<_, Invoice>(&select_invoices(
+        "WHERE i.orchard_receiver_hex = ? AND i.status IN ('pending', 'underpaid', 'detected')
+         AND i.expires_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now')"
+    ))
     .bind(receiver_hex)
     .fetch_optional(pool)
     .await?;
@@ -281,144 +889,484 @@ pub async fn find_by_orchard_receiver(pool: &SqlitePool, receiver_hex: &str) ->
     Ok(row)
 }
 
+fn now_string() -> String {
+    Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+fn detected(txid: &str, received_zatoshis: i64) -> Transition<'_> {
+    Transition::new(InvoiceState::Detected, "detected")
+        .set("detected_txid", txid)
+        .set("detected_at", now_string())
+        .set("received_zatoshis", received_zatoshis)
+        .txid(txid)
+        .detail(serde_json::json!({ "received_zatoshis": received_zatoshis }))
+}
+
 /// Returns true if the status actually changed (used to gate webhook dispatch).
-pub async fn mark_detected(pool: &SqlitePool, invoice_id: &str, txid: &str, received_zatoshis: i64) -> anyhow::Result<bool> {
+pub async fn mark_detected(pool: &SqlitePool, invoice_id: &str, txid: &str, received_zatoshis: i64) -> Result<bool, InvoiceError> {
+    let changed = detected(txid, received_zatoshis).apply(pool, invoice_id).await?;
+
+    if changed {
+        tracing::info!(invoice_id, txid, received_zatoshis, "Payment detected");
+    }
+    Ok(changed)
+}
+
+/// Returns true if the status actually changed (used to gate webhook dispatch).
+pub async fn mark_confirmed(pool: &SqlitePool, invoice_id: &str, txid: &str, block_height: Option<u64>) -> Result<bool, InvoiceError> {
+    let changed = Transition::new(InvoiceState::Confirmed, "confirmed")
+        .set("confirmed_at", now_string())
+        .txid(txid)
+        .block_height(block_height)
+        .apply(pool, invoice_id)
+        .await?;
+
+    if changed {
+        tracing::info!(invoice_id, block_height, "Payment confirmed");
+        if let Some(height) = block_height {
+            sqlx::query(
+                "UPDATE invoice_payments SET block_height = ?
+                 WHERE invoice_id = ? AND txid = ? AND block_height IS NULL"
+            )
+            .bind(height as i64)
+            .bind(invoice_id)
+            .bind(txid)
+            .execute(pool)
+            .await?;
+        }
+    }
+    Ok(changed)
+}
+
+/// Mark a paid invoice refunded without a verified refund transaction.
+/// Returns true if the status actually changed.
+pub async fn mark_refunded(pool: &SqlitePool, invoice_id: &str) -> Result<bool, InvoiceError> {
+    let changed = Transition::new(InvoiceState::Refunded, "refund_marked")
+        .only_from(&[InvoiceState::Confirmed, InvoiceState::PaidLate])
+        .set("refunded_at", now_string())
+        .apply(pool, invoice_id)
+        .await?;
+
+    if changed {
+        tracing::info!(invoice_id, "Invoice marked as refunded");
+    }
+    Ok(changed)
+}
+
+/// Cancel a pending invoice. Returns true if the status actually changed.
+pub async fn mark_expired(pool: &SqlitePool, invoice_id: &str) -> Result<bool, InvoiceError> {
+    let changed = Transition::new(InvoiceState::Expired, "cancelled")
+        .only_from(&[InvoiceState::Pending])
+        .apply(pool, invoice_id)
+        .await?;
+
+    if changed {
+        tracing::info!(invoice_id, "Invoice cancelled/expired");
+    }
+    Ok(changed)
+}
+
+/// An invoice that was repriced instead of expiring.
+#[derive(Debug)]
+pub struct Requote {
+    pub invoice_id: String,
+    pub price_zatoshis: i64,
+    pub expires_at: String,
+}
+
+#[derive(FromRow)]
+struct RequoteCandidate {
+    id: String,
+    memo_code: String,
+    payment_address: String,
+    currency: Option<String>,
+    price_eur: f64,
+    price_usd: Option<f64>,
+    price_zatoshis: i64,
+    expires_at: String,
+    fees_waived: bool,
+    network: String,
+}
+
+/// Reprice unpaid `on_expiry = requote` invoices that have run out of time at the current
+/// rate, keeping their memo code and address. Must run before `expire_old_invoices`.
+pub async fn requote_expired(
+    pool: &SqlitePool,
+    zec_eur: f64,
+    zec_usd: f64,
+    expiry_minutes: i64,
+    fee_config: Option<&FeeConfig>,
+) -> Result<Vec<Requote>, InvoiceError> {
+    if zec_eur <= 0.0 || zec_usd <= 0.0 {
+        return Ok(vec![]);
+    }
+
     let now = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+    let due = sqlx::query_as::<_, RequoteCandidate>(&format!(
+        "SELECT i.id, i.memo_code, i.payment_address, i.currency, i.price_eur, i.price_usd,
+                i.price_zatoshis, i.expires_at, {} AS fees_waived, m.network
+         FROM invoices i JOIN merchants m ON m.id = i.merchant_id
+         WHERE i.status = 'pending' AND i.on_expiry = 'requote' AND i.received_zatoshis = 0
+         AND i.requote_count < ? AND i.expires_at < ?",
+        crate::billing::FEES_WAIVED_SQL,
+    ))
+    .bind(MAX_REQUOTES)
+    .bind(&now)
+    .fetch_all(pool)
+    .await?;
+
+    let mut requoted = Vec::with_capacity(due.len());
+    for inv in due {
+        // Keep the amount fixed in the invoice's own currency.
+        let (price_eur, price_usd, price_zec) = match (inv.currency.as_deref(), inv.price_usd) {
+            (Some("USD"), Some(usd)) => {
+                let zec = amounts::round_zec(usd / zec_usd);
+                (amounts::round_fiat(zec * zec_eur), usd, zec)
+            }
+            _ => {
+                let zec = amounts::round_zec(inv.price_eur / zec_eur);
+                (inv.price_eur, amounts::round_fiat(zec * zec_usd), zec)
+            }
+        };
+        let price_zatoshis = amounts::zec_to_zatoshis(price_zec);
+        let zcash_uri = build_zcash_uri(
+            &inv.payment_address, price_zec, &inv.memo_code, &inv.id,
+            fee_config.filter(|f| !inv.fees_waived && f.network == inv.network),
+        );
+        let expires_at = (Utc::now() + Duration::minutes(expiry_minutes))
+            .format("%Y-%m-%dT%H:%M:%SZ")
+            .to_string();
+
+        let result = sqlx::query(
+            "UPDATE invoices SET price_eur = ?, price_usd = ?, price_zec = ?, price_zatoshis = ?,
+             zec_rate_at_creation = ?, zcash_uri = ?, expires_at = ?, requote_count = requote_count + 1
+             WHERE id = ? AND status = 'pending' AND received_zatoshis = 0 AND expires_at = ?"
+        )
+        .bind(price_eur)
+        .bind(price_usd)
+        .bind(price_zec)
+        .bind(price_zatoshis)
+        .bind(zec_eur)
+        .bind(&zcash_uri)
+        .bind(&expires_at)
+        .bind(&inv.id)
+        .bind(&inv.expires_at)
+        .execute(pool)
+        .await?;
+        if result.rows_affected() == 0 {
+            continue;
+        }
+
+        tracing::info!(invoice_id = %inv.id, old_zatoshis = inv.price_zatoshis, price_zatoshis, "Invoice requoted");
+        events::record(pool, &inv.id, "requoted", None, None, Some(serde_json::json!({
+            "previous_price_zatoshis": inv.price_zatoshis,
+            "price_zatoshis": price_zatoshis,
+            "expires_at": &expires_at,
+        }))).await;
+
+        requoted.push(Requote { invoice_id: inv.id, price_zatoshis, expires_at });
+    }
+    Ok(requoted)
+}
+
+/// Move an unpaid invoice's deadline into the past, so the next expiry pass treats it
+/// like any invoice that ran out of time (testnet simulation).
+pub async fn backdate_expiry(pool: &SqlitePool, invoice_id: &str) -> Result<bool, InvoiceError> {
     let result = sqlx::query(
-        "UPDATE invoices SET status = 'detected', detected_txid = ?, detected_at = ?, received_zatoshis = ?
+        "UPDATE invoices SET expires_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now', '-1 second')
          WHERE id = ? AND status IN ('pending', 'underpaid')"
     )
-    .bind(txid)
-    .bind(&now)
-    .bind(received_zatoshis)
     .bind(invoice_id)
     .execute(pool)
     .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+pub struct ExpiredInvoice {
+    pub invoice_id: String,
+    pub price_zatoshis: i64,
+    /// Partial payments the buyer sent before the invoice ran out; they need refunding.
+    pub received_zatoshis: i64,
+    pub has_refund_address: bool,
+}
+
+/// Expire pending and underpaid invoices past their `expires_at`, returning those expired.
+pub async fn expire_old_invoices(pool: &SqlitePool) -> Result<Vec<ExpiredInvoice>, InvoiceError> {
+    let due: Vec<(String, i64, bool)> = sqlx::query_as(
+        "SELECT id, price_zatoshis, refund_address IS NOT NULL AND refund_address != '' FROM invoices
+         WHERE status IN ('pending', 'underpaid') AND expires_at < strftime('%Y-%m-%dT%H:%M:%SZ', 'now')"
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut expired = Vec::new();
+    for (invoice_id, price_zatoshis, has_refund_address) in due {
+        let total = Transition::new(InvoiceState::Expired, "expired")
+            .require("expires_at < strftime('%Y-%m-%dT%H:%M:%SZ', 'now')")
+            .detail_with_total()
+            .apply_returning_total(pool, &invoice_id)
+            .await?;
+        if let Some(received_zatoshis) = total {
+            expired.push(ExpiredInvoice { invoice_id, price_zatoshis, received_zatoshis, has_refund_address });
+        }
+    }
+
+    if !expired.is_empty() {
+        tracing::info!(count = expired.len(), "Expired old invoices");
+    }
+    Ok(expired)
+}
+
+/// Returns true if the status actually changed (used to gate webhook dispatch).
+pub async fn mark_underpaid(pool: &SqlitePool, invoice_id: &str, received_zatoshis: i64, txid: &str) -> Result<bool, InvoiceError> {
+    let new_expires = (Utc::now() + Duration::minutes(10))
+        .format("%Y-%m-%dT%H:%M:%SZ")
+        .to_string();
+    let changed = Transition::new(InvoiceState::Underpaid, "underpaid")
+        .set("received_zatoshis", received_zatoshis)
+        .set("detected_txid", txid)
+        .set("detected_at", now_string())
+        .set("expires_at", new_expires)
+        .txid(txid)
+        .detail(serde_json::json!({ "received_zatoshis": received_zatoshis }))
+        .apply(pool, invoice_id)
+        .await?;
 
-    let changed = result.rows_affected() > 0;
     if changed {
-        tracing::info!(invoice_id, txid, received_zatoshis, "Payment detected");
+        tracing::info!(invoice_id, received_zatoshis, "Invoice marked as underpaid");
     }
     Ok(changed)
 }
 
-/// Returns true if the status actually changed (used to gate webhook dispatch).
-pub async fn mark_confirmed(pool: &SqlitePool, invoice_id: &str) -> anyhow::Result<bool> {
-    let now = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+/// A payment for an invoice that had already expired (within the grace window).
+/// The amount is added to anything received before expiry; the merchant decides
+/// whether to fulfil or refund. Returns the new total, or None if the invoice was
+/// no longer expired.
+pub async fn mark_paid_late(pool: &SqlitePool, invoice_id: &str, txid: &str, amount_zatoshis: i64) -> Result<Option<i64>, InvoiceError> {
+    let total = Transition::new(InvoiceState::PaidLate, "paid_late")
+        .set("received_zatoshis", state::Value::Add(amount_zatoshis))
+        .set("detected_txid", txid)
+        .set("detected_at", now_string())
+        .txid(txid)
+        .detail(serde_json::json!({ "amount_zatoshis": amount_zatoshis }))
+        .detail_with_total()
+        .apply_returning_total(pool, invoice_id)
+        .await?;
+
+    if let Some(total) = total {
+        tracing::warn!(invoice_id, txid, total, "Payment received after expiry");
+    }
+    Ok(total)
+}
+
+/// Add a further payment to an underpaid invoice and extend its expiry. If the total now
+/// reaches `detect_at`, the invoice is marked detected in the same transaction.
+/// Returns the new received_zatoshis and whether the invoice was detected.
+/// Only operates on invoices in 'underpaid' status to prevent race conditions.
+pub async fn accumulate_payment(
+    pool: &SqlitePool,
+    invoice_id: &str,
+    txid: &str,
+    additional_zatoshis: i64,
+    detect_at: i64,
+) -> Result<(i64, bool), InvoiceError> {
+    let new_expires = (Utc::now() + Duration::minutes(10))
+        .format("%Y-%m-%dT%H:%M:%SZ")
+        .to_string();
+
+    let mut tx = crate::db::begin_write(pool).await?;
+    let total: Option<i64> = sqlx::query_scalar(
+        "UPDATE invoices SET received_zatoshis = received_zatoshis + ?, expires_at = ?
+         WHERE id = ? AND status = 'underpaid' RETURNING received_zatoshis"
+    )
+    .bind(additional_zatoshis)
+    .bind(&new_expires)
+    .bind(invoice_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let Some(total) = total else {
+        tracing::warn!(invoice_id, "accumulate_payment: invoice not in underpaid status, skipping");
+        return Err(InvoiceError::InvalidStatus);
+    };
+    let was_detected = total >= detect_at
+        && detected(txid, total).apply_in(&mut tx, invoice_id).await?.is_some();
+    tx.commit().await?;
+
+    tracing::info!(invoice_id, additional_zatoshis, total, "Payment accumulated");
+    if was_detected {
+        tracing::info!(invoice_id, txid, received_zatoshis = total, "Payment detected");
+    }
+    Ok((total, was_detected))
+}
+
+/// Register the txid of a refund the merchant sent. The invoice stays in its current
+/// status until the scanner verifies the transaction (see `confirm_refund`).
+pub async fn register_refund(pool: &SqlitePool, invoice_id: &str, txid: &str, amount_zatoshis: i64) -> Result<bool, InvoiceError> {
     let result = sqlx::query(
-        "UPDATE invoices SET status = 'confirmed', confirmed_at = ?
-         WHERE id = ? AND status = 'detected'"
+        "UPDATE invoices SET refund_txid = ?, refund_zatoshis = ?, refund_review = NULL
+         WHERE id = ? AND status IN ('confirmed', 'expired', 'paid_late')"
     )
-    .bind(&now)
+    .bind(txid)
+    .bind(amount_zatoshis)
     .bind(invoice_id)
     .execute(pool)
     .await?;
 
     let changed = result.rows_affected() > 0;
     if changed {
-        tracing::info!(invoice_id, "Payment confirmed");
+        tracing::info!(invoice_id, txid, amount_zatoshis, "Refund txid registered");
+        events::record(pool, invoice_id, "refund_submitted", Some(txid), None, Some(serde_json::json!({
+            "amount_zatoshis": amount_zatoshis,
+        }))).await;
     }
     Ok(changed)
 }
 
-pub async fn mark_refunded(pool: &SqlitePool, invoice_id: &str) -> anyhow::Result<()> {
-    let now = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
-    sqlx::query(
-        "UPDATE invoices SET status = 'refunded', refunded_at = ?
-         WHERE id = ? AND status = 'confirmed'"
+pub async fn get_pending_refunds(pool: &SqlitePool, network: &str) -> Result<Vec<PendingRefund>, InvoiceError> {
+    let rows = sqlx::query_as::<_, PendingRefund>(
+        "SELECT i.id, i.merchant_id, i.refund_address, i.refund_txid, i.refund_zatoshis
+         FROM invoices i JOIN merchants m ON m.id = i.merchant_id
+         WHERE i.refund_txid IS NOT NULL AND i.refund_zatoshis IS NOT NULL AND i.refund_review IS NULL
+         AND i.status IN ('confirmed', 'expired', 'paid_late')
+         AND m.network = ?"
     )
-    .bind(&now)
-    .bind(invoice_id)
-    .execute(pool)
+    .bind(network)
+    .fetch_all(pool)
     .await?;
+    Ok(rows)
+}
 
-    tracing::info!(invoice_id, "Invoice marked as refunded");
-    Ok(())
+/// Returns true if the status actually changed (used to gate webhook dispatch).
+pub async fn confirm_refund(pool: &SqlitePool, invoice_id: &str, txid: &str, block_height: Option<u64>) -> Result<bool, InvoiceError> {
+    let changed = Transition::new(InvoiceState::Refunded, "refund_confirmed")
+        .set("refunded_at", now_string())
+        .require_eq("refund_txid", txid)
+        .txid(txid)
+        .block_height(block_height)
+        .apply(pool, invoice_id)
+        .await?;
+
+    if changed {
+        tracing::info!(invoice_id, txid, "Refund confirmed on-chain");
+    }
+    Ok(changed)
 }
 
-pub async fn mark_expired(pool: &SqlitePool, invoice_id: &str) -> anyhow::Result<()> {
+/// Clear a refund txid that failed verification so the merchant can submit another.
+pub async fn reject_refund(pool: &SqlitePool, invoice_id: &str, txid: &str, reason: &str) -> Result<(), InvoiceError> {
     sqlx::query(
-        "UPDATE invoices SET status = 'expired'
-         WHERE id = ? AND status = 'pending'"
+        "UPDATE invoices SET refund_txid = NULL, refund_zatoshis = NULL
+         WHERE id = ? AND refund_txid = ?"
     )
     .bind(invoice_id)
+    .bind(txid)
     .execute(pool)
     .await?;
 
-    tracing::info!(invoice_id, "Invoice cancelled/expired");
+    tracing::warn!(invoice_id, txid, reason, "Refund verification failed");
+    events::record(pool, invoice_id, "refund_rejected", Some(txid), None, Some(serde_json::json!({
+        "reason": reason,
+    }))).await;
     Ok(())
 }
 
-pub async fn expire_old_invoices(pool: &SqlitePool) -> anyhow::Result<u64> {
+/// Stop checking a mined refund the scanner cannot verify, and tell the merchant why on the
+/// timeline. The invoice keeps its status: the merchant marks it refunded once satisfied,
+/// or registers another txid, which clears the flag.
+pub async fn flag_refund_for_review(pool: &SqlitePool, invoice_id: &str, txid: &str, reason: &str) -> Result<(), InvoiceError> {
     let result = sqlx::query(
-        "UPDATE invoices SET status = 'expired'
-         WHERE status IN ('pending', 'underpaid') AND expires_at < strftime('%Y-%m-%dT%H:%M:%SZ', 'now')"
+        "UPDATE invoices SET refund_review = ?
+         WHERE id = ? AND refund_txid = ? AND refund_review IS NULL"
     )
+    .bind(reason)
+    .bind(invoice_id)
+    .bind(txid)
     .execute(pool)
     .await?;
 
-    let count = result.rows_affected();
-    if count > 0 {
-        tracing::info!(count, "Expired old invoices");
+    if result.rows_affected() > 0 {
+        tracing::warn!(invoice_id, txid, reason, "Refund could not be verified, left for review");
+        events::record(pool, invoice_id, "refund_unverified", Some(txid), None, Some(serde_json::json!({
+            "reason": reason,
+        }))).await;
     }
-    Ok(count)
+    Ok(())
 }
 
-pub async fn mark_underpaid(pool: &SqlitePool, invoice_id: &str, received_zatoshis: i64, txid: &str) -> anyhow::Result<()> {
+/// True if `txid` paid into the invoice, so cannot be a refund out of it.
+pub async fn is_payment_txid(pool: &SqlitePool, invoice_id: &str, txid: &str) -> Result<bool, InvoiceError> {
+    let count: i64 = sqlx::query_scalar(
+        "SELECT (SELECT COUNT(*) FROM invoice_payments WHERE invoice_id = ?1 AND txid = ?2)
+              + (SELECT COUNT(*) FROM invoices WHERE id = ?1 AND detected_txid = ?2)"
+    )
+    .bind(invoice_id)
+    .bind(txid)
+    .fetch_one(pool)
+    .await?;
+    Ok(count > 0)
+}
+
+/// Record a transaction's contribution to an invoice. Idempotent per (invoice, txid):
+/// seeing the same tx again (e.g. mempool then block) only fills in the block height.
+pub async fn record_payment(
+    pool: &SqlitePool,
+    invoice_id: &str,
+    txid: &str,
+    amount_zatoshis: i64,
+    block_height: Option<u64>,
+) -> Result<(), InvoiceError> {
     let now = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
-    let new_expires = (Utc::now() + Duration::minutes(10))
-        .format("%Y-%m-%dT%H:%M:%SZ")
-        .to_string();
     sqlx::query(
-        "UPDATE invoices SET status = 'underpaid', received_zatoshis = ?, detected_txid = ?,
-         detected_at = ?, expires_at = ?
-         WHERE id = ? AND status = 'pending'"
+        "INSERT INTO invoice_payments (invoice_id, txid, amount_zatoshis, block_height, seen_at)
+         VALUES (?, ?, ?, ?, ?)
+         ON CONFLICT(invoice_id, txid) DO UPDATE SET
+         block_height = COALESCE(invoice_payments.block_height, excluded.block_height)"
     )
-    .bind(received_zatoshis)
+    .bind(invoice_id)
     .bind(txid)
+    .bind(amount_zatoshis)
+    .bind(block_height.map(|h| h as i64))
     .bind(&now)
-    .bind(&new_expires)
-    .bind(invoice_id)
     .execute(pool)
     .await?;
+    Ok(())
+}
 
-    tracing::info!(invoice_id, received_zatoshis, "Invoice marked as underpaid");
+/// Fill in the fee and output count of a transaction on every payment it made.
+pub async fn record_payment_shape(
+    pool: &SqlitePool,
+    txid: &str,
+    fee_zatoshis: Option<i64>,
+    outputs: i64,
+) -> Result<(), InvoiceError> {
+    sqlx::query(
+        "UPDATE invoice_payments SET fee_zatoshis = ?, tx_outputs = ?
+         WHERE txid = ? AND tx_outputs IS NULL"
+    )
+    .bind(fee_zatoshis)
+    .bind(outputs)
+    .bind(txid)
+    .execute(pool)
+    .await?;
     Ok(())
 }
 
-/// Add additional zatoshis to an underpaid invoice and extend its expiry.
-/// Returns the new total received_zatoshis.
-/// Only operates on invoices in 'underpaid' status to prevent race conditions.
-pub async fn accumulate_payment(pool: &SqlitePool, invoice_id: &str, additional_zatoshis: i64) -> anyhow::Result<i64> {
-    let new_expires = (Utc::now() + Duration::minutes(10))
-        .format("%Y-%m-%dT%H:%M:%SZ")
-        .to_string();
-    let row: Option<(i64,)> = sqlx::query_as(
-        "UPDATE invoices SET received_zatoshis = received_zatoshis + ?, expires_at = ?
-         WHERE id = ? AND status = 'underpaid' RETURNING received_zatoshis"
+pub async fn get_payments(pool: &SqlitePool, invoice_id: &str) -> Result<Vec<InvoicePayment>, InvoiceError> {
+    let rows = sqlx::query_as::<_, InvoicePayment>(
+        "SELECT txid, amount_zatoshis, block_height, seen_at, fee_zatoshis, tx_outputs
+         FROM invoice_payments WHERE invoice_id = ? ORDER BY id ASC"
     )
-    .bind(additional_zatoshis)
-    .bind(&new_expires)
     .bind(invoice_id)
-    .fetch_optional(pool)
+    .fetch_all(pool)
     .await?;
-
-    match row {
-        Some((total,)) => {
-            tracing::info!(invoice_id, additional_zatoshis, total, "Payment accumulated");
-            Ok(total)
-        }
-        None => {
-            tracing::warn!(invoice_id, "accumulate_payment: invoice not in underpaid status, skipping");
-            anyhow::bail!("invoice not in underpaid status")
-        }
-    }
+    Ok(rows)
 }
 
-pub async fn update_refund_address(pool: &SqlitePool, invoice_id: &str, address: &str) -> anyhow::Result<bool> {
+pub async fn update_refund_address(pool: &SqlitePool, invoice_id: &str, address: &str) -> Result<bool, InvoiceError> {
     let result = sqlx::query(
         "UPDATE invoices SET refund_address = ?
-         WHERE id = ? AND status IN ('pending', 'underpaid', 'expired')
+         WHERE id = ? AND status IN ('pending', 'underpaid', 'expired', 'paid_late')
          AND (refund_address IS NULL OR refund_address = '')"
     )
     .bind(address)
@@ -429,7 +1377,227 @@ pub async fn update_refund_address(pool: &SqlitePool, invoice_id: &str, address:
     Ok(result.rows_affected() > 0)
 }
 
-pub fn zatoshis_to_zec(z: i64) -> f64 {
-    format!("{:.8}", z as f64 / 100_000_000.0).parse::<f64>().unwrap_or(0.0)
+/// Record the URI format (and wallet, if the checkout knows it) shown for an unpaid
+/// invoice. Returns false once the invoice is paid or closed.
+pub async fn set_uri_format(
+    pool: &SqlitePool,
+    invoice_id: &str,
+    format: UriFormat,
+    wallet: Option<&str>,
+) -> Result<bool, InvoiceError> {
+    let result = sqlx::query(
+        "UPDATE invoices SET uri_format = ?, wallet = COALESCE(?, wallet)
+         WHERE id = ? AND status IN ('pending', 'underpaid')"
+    )
+    .bind(format.as_str())
+    .bind(wallet)
+    .bind(invoice_id)
+    .execute(pool)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Note that a transaction paying the invoice also paid the platform fee output.
+pub async fn mark_fee_output_seen(pool: &SqlitePool, invoice_id: &str) -> Result<(), InvoiceError> {
+    sqlx::query("UPDATE invoices SET fee_output_seen = 1 WHERE id = ?")
+        .bind(invoice_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn fee_output_seen(pool: &SqlitePool, invoice_id: &str) -> Result<bool, InvoiceError> {
+    let seen: Option<bool> = sqlx::query_scalar("SELECT fee_output_seen FROM invoices WHERE id = ?")
+        .bind(invoice_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(seen.unwrap_or(false))
 }
 
+/// ZIP-321 payment URI a merchant can scan to send a refund back to the buyer.
+/// The `REFUND-{memo_code}` memo is omitted for addresses that cannot carry one (transparent).
+pub fn build_refund_uri(refund_address: &str, amount_zatoshis: i64, memo_code: &str) -> String {
+    let amount = zatoshis_to_zec(amount_zatoshis);
+    let can_memo = zcash_address::ZcashAddress::try_from_encoded(refund_address)
+        .map(|a| a.can_receive_memo())
+        .unwrap_or(false);
+
+    if can_memo {
+        let memo_b64 = memo::param(&format!("REFUND-{}", memo_code));
+        format!("zcash:{}?amount={:.8}&memo={}", refund_address, amount, memo_b64)
+    } else {
+        format!("zcash:{}?amount={:.8}", refund_address, amount)
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_minimum_amounts() {
+        let quotas = InvoiceQuotas { max_open: 0, max_per_hour: 0, min_fiat: 0.5, min_zatoshis: 10_000 };
+        assert!(quotas.check_amount(0.5, "EUR", 1_250_000).is_ok());
+        let err = quotas.check_amount(0.49, "USD", 1_250_000).unwrap_err();
+        assert_eq!(err.to_string(), "Invoice amount is below the minimum of 0.50 USD");
+        let err = quotas.check_amount(1.0, "EUR", 9_999).unwrap_err();
+        assert_eq!(err.to_string(), "Invoice amount is below the minimum of 0.00010000 ZEC");
+    }
+
+    #[tokio::test]
+    async fn test_quotas_count_open_and_recent_invoices() {
+        let pool = crate::db::test_pool().await;
+        let merchant = crate::db::test_merchant(&pool, 1).await.merchant_id;
+        let other = crate::db::test_merchant(&pool, 2).await.merchant_id;
+        let open = InvoiceQuotas { max_open: 2, max_per_hour: 0, min_fiat: 0.0, min_zatoshis: 0 };
+        let hourly = InvoiceQuotas { max_open: 0, max_per_hour: 3, ..open };
+        let exceeded = |r: Result<(), InvoiceError>| match r {
+            Err(InvoiceError::QuotaExceeded { quota, limit }) => Some((quota, limit)),
+            Ok(()) => None,
+            Err(e) => panic!("unexpected error: {}", e),
+        };
+
+        let first = crate::db::test_invoice(&pool, &merchant, 1).await.invoice_id;
+        assert_eq!(exceeded(open.check(&pool, &merchant).await), None);
+        let second = crate::db::test_invoice(&pool, &merchant, 1).await.invoice_id;
+        assert_eq!(exceeded(open.check(&pool, &merchant).await), Some(("open invoices", 2)));
+        assert_eq!(exceeded(open.check(&pool, &other).await), None);
+
+        // Underpaid still waits on the buyer; an expired invoice no longer does.
+        mark_underpaid(&pool, &first, 1_000, "tx-1").await.unwrap();
+        assert!(exceeded(open.check(&pool, &merchant).await).is_some());
+        sqlx::query("UPDATE invoices SET status = 'expired' WHERE id = ?").bind(&second).execute(&pool).await.unwrap();
+        assert_eq!(exceeded(open.check(&pool, &merchant).await), None);
+
+        // Closed or not, everything created in the last hour counts towards the hourly limit.
+        assert_eq!(exceeded(hourly.check(&pool, &merchant).await), None);
+        crate::db::test_invoice(&pool, &merchant, 1).await;
+        assert_eq!(exceeded(hourly.check(&pool, &merchant).await), Some(("invoices per hour", 3)));
+        sqlx::query("UPDATE invoices SET created_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now', '-2 hours') WHERE id = ?")
+            .bind(&second)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(exceeded(hourly.check(&pool, &merchant).await), None);
+
+        let unlimited = InvoiceQuotas { max_open: 0, max_per_hour: 0, ..open };
+        assert_eq!(exceeded(unlimited.check(&pool, &merchant).await), None);
+    }
+
+    #[tokio::test]
+    async fn test_payments_are_recorded_once_per_transaction() {
+        let pool = crate::db::test_pool().await;
+        let merchant = crate::db::test_merchant(&pool, 1).await;
+        let id = crate::db::test_invoice(&pool, &merchant.merchant_id, 1).await.invoice_id;
+
+        // Seen in the mempool, then again in a block: one row, which gains the height.
+        record_payment(&pool, &id, "tx-1", 10_000_000, None).await.unwrap();
+        record_payment(&pool, &id, "tx-1", 10_000_000, Some(100)).await.unwrap();
+        record_payment(&pool, &id, "tx-1", 10_000_000, Some(101)).await.unwrap();
+        record_payment(&pool, &id, "tx-2", 15_000_000, None).await.unwrap();
+        record_payment_shape(&pool, "tx-2", Some(10_000), 2).await.unwrap();
+
+        let payments = get_payments(&pool, &id).await.unwrap();
+        assert_eq!(payments.len(), 2);
+        assert_eq!((payments[0].txid.as_str(), payments[0].block_height), ("tx-1", Some(100)));
+        assert_eq!((payments[1].amount_zatoshis, payments[1].block_height), (15_000_000, None));
+        assert_eq!((payments[1].fee_zatoshis, payments[1].tx_outputs), (Some(10_000), Some(2)));
+
+        // Confirmation fills in the height of the transaction that confirmed it.
+        mark_detected(&pool, &id, "tx-2", 25_000_000).await.unwrap();
+        mark_confirmed(&pool, &id, "tx-2", Some(102)).await.unwrap();
+        assert_eq!(get_payments(&pool, &id).await.unwrap()[1].block_height, Some(102));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_payments_all_accumulate() {
+        let pool = crate::db::test_pool().await;
+        let merchant = crate::db::test_merchant(&pool, 1).await;
+        let id = crate::db::test_invoice(&pool, &merchant.merchant_id, 1).await.invoice_id;
+        assert!(mark_underpaid(&pool, &id, 23_000_000, "tx-0").await.unwrap());
+
+        // Twenty payments racing in from separate tasks and connections: every one is
+        // counted, and exactly one of them completes the invoice.
+        let tasks: Vec<_> = (1..=20)
+            .map(|n| {
+                let (pool, id) = (pool.clone(), id.clone());
+                tokio::spawn(async move { accumulate_payment(&pool, &id, &format!("tx-{}", n), 100_000, 25_000_000).await })
+            })
+            .collect();
+        let mut totals = Vec::new();
+        let mut detections = 0;
+        for task in tasks {
+            let (total, detected) = task.await.unwrap().unwrap();
+            totals.push(total);
+            detections += detected as usize;
+        }
+        totals.sort();
+        assert_eq!(totals, (1..=20).map(|n| 23_000_000 + n * 100_000).collect::<Vec<_>>());
+        assert_eq!(detections, 1);
+
+        let invoice = get_invoice(&pool, &id).await.unwrap().unwrap();
+        assert_eq!((invoice.status.as_str(), invoice.received_zatoshis), ("detected", 25_000_000));
+    }
+
+    #[tokio::test]
+    async fn test_refunds_flagged_for_review_leave_the_queue() {
+        let pool = crate::db::test_pool().await;
+        let merchant = crate::db::test_merchant(&pool, 1).await;
+        let id = crate::db::test_invoice(&pool, &merchant.merchant_id, 1).await.invoice_id;
+
+        // Only settled invoices can be refunded.
+        assert!(!register_refund(&pool, &id, "refund-1", 25_000_000).await.unwrap());
+        record_payment(&pool, &id, "pay-1", 25_000_000, None).await.unwrap();
+        mark_detected(&pool, &id, "pay-1", 25_000_000).await.unwrap();
+        mark_confirmed(&pool, &id, "pay-1", Some(100)).await.unwrap();
+        assert!(is_payment_txid(&pool, &id, "pay-1").await.unwrap());
+        assert!(!is_payment_txid(&pool, &id, "refund-1").await.unwrap());
+
+        assert!(register_refund(&pool, &id, "refund-1", 25_000_000).await.unwrap());
+        let pending = get_pending_refunds(&pool, "testnet").await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!((pending[0].refund_txid.as_str(), pending[0].refund_zatoshis), ("refund-1", 25_000_000));
+
+        // A stale txid cannot flag the current one.
+        flag_refund_for_review(&pool, &id, "refund-0", "merchant has no viewing key").await.unwrap();
+        assert_eq!(get_pending_refunds(&pool, "testnet").await.unwrap().len(), 1);
+
+        flag_refund_for_review(&pool, &id, "refund-1", "merchant has no viewing key").await.unwrap();
+        assert!(get_pending_refunds(&pool, "testnet").await.unwrap().is_empty());
+        let invoice = get_invoice(&pool, &id).await.unwrap().unwrap();
+        assert_eq!(invoice.status, "confirmed");
+        let events = events::list(&pool, &id).await.unwrap();
+        assert_eq!(events.last().unwrap().event_type, "refund_unverified");
+
+        // Registering another txid puts the refund back in the queue.
+        assert!(register_refund(&pool, &id, "refund-2", 25_000_000).await.unwrap());
+        assert_eq!(get_pending_refunds(&pool, "testnet").await.unwrap()[0].refund_txid, "refund-2");
+        assert!(confirm_refund(&pool, &id, "refund-2", Some(110)).await.unwrap());
+        assert_eq!(get_invoice(&pool, &id).await.unwrap().unwrap().status, "refunded");
+        assert!(get_pending_refunds(&pool, "testnet").await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_refund_uri_memo_only_where_it_can_be_carried() {
+        let shielded = crate::scanner::fixtures::test_address(2, 0);
+        let uri = build_refund_uri(&shielded, 12_345_678, "CP-1");
+        assert_eq!(uri, format!("zcash:{}?amount=0.12345678&memo=UkVGVU5ELUNQLTE", shielded));
+
+        use zcash_address::ToAddress;
+        let transparent = zcash_address::ZcashAddress::from_transparent_p2pkh(zcash_protocol::consensus::NetworkType::Test, [7; 20]).encode();
+        assert_eq!(build_refund_uri(&transparent, 100_000_000, "CP-1"), format!("zcash:{}?amount=1.00000000", transparent));
+    }
+
+    #[test]
+    fn test_claim_codes_normalize_as_typed() {
+        let code = generate_claim_code();
+        assert_eq!(code.len(), 9);
+        assert_eq!(normalize_claim_code(&code).as_deref(), Some(code.as_str()));
+        assert_eq!(normalize_claim_code(&code.to_lowercase().replace('-', " ")).as_deref(), Some(code.as_str()));
+        assert_eq!(normalize_claim_code("abcd efgh").as_deref(), Some("ABCD-EFGH"));
+        assert!(normalize_claim_code("ABCD-EFG0").is_none());
+        assert!(normalize_claim_code("ABCD-EFG").is_none());
+    }
+}
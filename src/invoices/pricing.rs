@@ -1,83 +1,207 @@
 use std::sync::Arc;
+use anyhow::Context;
 use tokio::sync::RwLock;
 use chrono::{DateTime, Utc};
 use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::config::Config;
 
 #[derive(Debug, Clone, Serialize)]
 pub struct ZecRates {
     pub zec_eur: f64,
     pub zec_usd: f64,
     pub updated_at: DateTime<Utc>,
+    pub source: RateSource,
+}
+
+/// Where a rate came from. Operator-set rates are shown as such to buyers and merchants.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateSource {
+    Feed,
+    Operator,
 }
 
+impl RateSource {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Feed => "feed",
+            Self::Operator => "operator",
+        }
+    }
+
+    fn parse(s: &str) -> Self {
+        if s == "operator" { Self::Operator } else { Self::Feed }
+    }
+}
+
+/// How much the privacy-mode refresh interval varies either way, so fetches do not fall
+/// on a schedule that fingerprints the instance.
+const PRIVATE_JITTER: f64 = 0.5;
+
 #[derive(Clone)]
 pub struct PriceService {
-    api_url: String,
+    /// CoinGecko-compatible APIs; the rate is the median of those that answer.
+    sources: Vec<String>,
     cache_secs: u64,
+    /// Privacy mode: rates are fetched only by the `price_feed` job, never because a
+    /// request needed one, so the feed sees no pattern of this instance's traffic.
+    private: bool,
     cached: Arc<RwLock<Option<ZecRates>>>,
+    /// Operator-set rate (FIXED_ZEC_EUR/FIXED_ZEC_USD or the admin endpoint). While set,
+    /// it is authoritative and the feed is not called.
+    manual: Arc<RwLock<Option<ZecRates>>>,
     http: reqwest::Client,
+    pool: SqlitePool,
 }
 
 impl PriceService {
-    pub fn new(api_url: &str, cache_secs: u64) -> Self {
-        let http = reqwest::Client::builder()
-            .user_agent("CipherPay/1.0")
-            .build()
-            .expect("Failed to build HTTP client");
-        Self {
-            api_url: api_url.to_string(),
-            cache_secs,
+    /// Every live fetch is also appended to `rates_history`. Fails on an invalid
+    /// PRICE_PROXY_URL.
+    pub fn new(config: &Config, pool: SqlitePool) -> anyhow::Result<Self> {
+        let mut http = reqwest::Client::builder().timeout(std::time::Duration::from_secs(10));
+        if let Some(proxy) = &config.price_proxy_url {
+            http = http.proxy(reqwest::Proxy::all(proxy).context("Invalid PRICE_PROXY_URL")?);
+        }
+        // No User-Agent at all in privacy mode: the requests look like any other client's.
+        if !config.price_privacy_mode {
+            http = http.user_agent("CipherPay/1.0");
+        }
+        Ok(Self {
+            sources: config.price_sources(),
+            cache_secs: config.price_cache_secs,
+            private: config.price_privacy_mode,
             cached: Arc::new(RwLock::new(None)),
-            http,
+            manual: Arc::new(RwLock::new(None)),
+            http: http.build()?,
+            pool,
+        })
+    }
+
+    /// In privacy mode, refresh the rate in the background every PRICE_CACHE_SECS give or
+    /// take half of it, as the `price_feed` job. Otherwise rates are fetched when needed.
+    pub fn start(&self) {
+        if !self.private {
+            return;
+        }
+        tracing::info!(sources = self.sources.len(), "Price feed privacy mode enabled");
+        let service = self.clone();
+        let every = std::time::Duration::from_secs(self.cache_secs.max(1));
+        crate::jobs::Job::new("price_feed", every).jitter(PRIVATE_JITTER).spawn(move || {
+            let service = service.clone();
+            async move {
+                if service.manual.read().await.is_none() {
+                    service.refresh().await?;
+                }
+                Ok(())
+            }
+        });
+    }
+
+    /// Set or refresh the operator rate. Recorded in the history like fetched rates.
+    pub async fn set_manual(&self, zec_eur: f64, zec_usd: f64) -> anyhow::Result<ZecRates> {
+        if !(zec_eur.is_finite() && zec_eur > 0.0 && zec_usd.is_finite() && zec_usd > 0.0) {
+            anyhow::bail!("Rates must be positive numbers");
+        }
+        let rates = ZecRates { zec_eur, zec_usd, updated_at: Utc::now(), source: RateSource::Operator };
+        *self.manual.write().await = Some(rates.clone());
+        tracing::info!(zec_eur, zec_usd, "Operator rate set");
+        if let Err(e) = record_history(&self.pool, &rates).await {
+            tracing::warn!(error = %e, "Failed to record rate history");
         }
+        Ok(rates)
+    }
+
+    /// Drop the operator rate and go back to the feed. Returns whether one was set.
+    pub async fn clear_manual(&self) -> bool {
+        let cleared = self.manual.write().await.take().is_some();
+        if cleared {
+            tracing::info!("Operator rate cleared, using the price feed");
+        }
+        cleared
     }
 
     pub async fn get_rates(&self) -> anyhow::Result<ZecRates> {
+        if let Some(rates) = &*self.manual.read().await {
+            return Ok(rates.clone());
+        }
+
         {
             let cache = self.cached.read().await;
             if let Some(rates) = &*cache {
                 let age = (Utc::now() - rates.updated_at).num_seconds() as u64;
-                if age < self.cache_secs {
+                if age < self.cache_secs || self.private {
                     return Ok(rates.clone());
                 }
             }
         }
 
-        match self.fetch_live_rates().await {
-            Ok(rates) => {
-                let mut cache = self.cached.write().await;
-                *cache = Some(rates.clone());
-                tracing::info!(zec_eur = rates.zec_eur, zec_usd = rates.zec_usd, "Price feed updated");
-                Ok(rates)
-            }
+        match self.refresh().await {
+            Ok(rates) => Ok(rates),
             Err(e) => {
                 let cache = self.cached.read().await;
                 if let Some(stale) = &*cache {
-                    tracing::warn!(error = %e, age_secs = (Utc::now() - stale.updated_at).num_seconds(), "CoinGecko unavailable, using last known rate");
+                    tracing::warn!(error = %e, age_secs = (Utc::now() - stale.updated_at).num_seconds(), "Price feed unavailable, using last known rate");
                     return Ok(stale.clone());
                 }
-                tracing::error!(error = %e, "CoinGecko unavailable and no cached rate — prices will be inaccurate");
+                tracing::error!(error = %e, "Price feed unavailable and no cached rate — prices will be inaccurate");
                 anyhow::bail!("No price data available: {}", e)
             }
         }
     }
 
+    /// Fetch a live rate and cache and record it.
+    async fn refresh(&self) -> anyhow::Result<ZecRates> {
+        let rates = self.fetch_live_rates().await?;
+        *self.cached.write().await = Some(rates.clone());
+        tracing::info!(zec_eur = rates.zec_eur, zec_usd = rates.zec_usd, "Price feed updated");
+        if let Err(e) = record_history(&self.pool, &rates).await {
+            tracing::warn!(error = %e, "Failed to record rate history");
+        }
+        Ok(rates)
+    }
+
+    /// The median of every source's rate. Sources that fail are skipped; all failing is
+    /// an error.
     async fn fetch_live_rates(&self) -> anyhow::Result<ZecRates> {
-        let url = format!(
-            "{}/simple/price?ids=zcash&vs_currencies=eur,usd",
-            self.api_url
-        );
+        let results = futures::future::join_all(self.sources.iter().map(|s| self.fetch_source(s))).await;
+        let mut quotes = Vec::new();
+        let mut last_error = None;
+        for (source, result) in self.sources.iter().zip(results) {
+            match result {
+                Ok(quote) => quotes.push(quote),
+                Err(e) => {
+                    if self.sources.len() > 1 {
+                        tracing::warn!(source = %source, error = %e, "Price source failed");
+                    }
+                    last_error = Some(e);
+                }
+            }
+        }
+        let (zec_eur, zec_usd) = match aggregate(&quotes) {
+            Some(rates) => rates,
+            None => return Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No price sources configured"))),
+        };
+
+        Ok(ZecRates {
+            zec_eur,
+            zec_usd,
+            updated_at: Utc::now(),
+            source: RateSource::Feed,
+        })
+    }
+
+    /// ZEC/EUR and ZEC/USD from one source's `/simple/price`.
+    async fn fetch_source(&self, api_url: &str) -> anyhow::Result<(f64, f64)> {
+        let url = format!("{}/simple/price?ids=zcash&vs_currencies=eur,usd", api_url);
 
-        let response = self.http
-            .get(&url)
-            .timeout(std::time::Duration::from_secs(10))
-            .send()
-            .await?;
+        let response = self.http.get(&url).send().await?;
 
         let status = response.status();
         if !status.is_success() {
             let body = response.text().await.unwrap_or_default();
-            anyhow::bail!("CoinGecko returned HTTP {}: {}", status, &body[..body.len().min(200)]);
+            anyhow::bail!("Price feed returned HTTP {}: {}", status, &body[..body.len().min(200)]);
         }
 
         let resp: serde_json::Value = response.json().await?;
@@ -88,11 +212,179 @@ impl PriceService {
         let zec_usd = resp["zcash"]["usd"]
             .as_f64()
             .ok_or_else(|| anyhow::anyhow!("Missing ZEC/USD rate in response: {}", resp))?;
+        Ok((zec_eur, zec_usd))
+    }
+}
 
-        Ok(ZecRates {
-            zec_eur,
-            zec_usd,
-            updated_at: Utc::now(),
-        })
+/// The median ZEC/EUR and ZEC/USD of the quotes, so one wrong source cannot move the rate
+/// when there are three or more.
+fn aggregate(quotes: &[(f64, f64)]) -> Option<(f64, f64)> {
+    fn median(mut values: Vec<f64>) -> Option<f64> {
+        values.retain(|v| v.is_finite() && *v > 0.0);
+        values.sort_by(f64::total_cmp);
+        let mid = values.len() / 2;
+        match values.len() {
+            0 => None,
+            n if n % 2 == 0 => Some((values[mid - 1] + values[mid]) / 2.0),
+            _ => Some(values[mid]),
+        }
+    }
+    Some((
+        median(quotes.iter().map(|q| q.0).collect())?,
+        median(quotes.iter().map(|q| q.1).collect())?,
+    ))
+}
+
+/// Time window for `GET /api/rates?range=`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HistoryRange {
+    /// Every recorded fetch of the last 24 hours.
+    Day,
+    /// Hourly averages over the last 7 days.
+    Week,
+}
+
+impl HistoryRange {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "24h" => Some(Self::Day),
+            "7d" => Some(Self::Week),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Day => "24h",
+            Self::Week => "7d",
+        }
+    }
+}
+
+async fn record_history(pool: &SqlitePool, rates: &ZecRates) -> anyhow::Result<()> {
+    sqlx::query("INSERT INTO rates_history (zec_eur, zec_usd, fetched_at, source) VALUES (?, ?, ?, ?)")
+        .bind(rates.zec_eur)
+        .bind(rates.zec_usd)
+        .bind(rates.updated_at.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+        .bind(rates.source.as_str())
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+fn history_row((zec_eur, zec_usd, fetched_at, source): (f64, f64, String, String)) -> Option<ZecRates> {
+    let updated_at = DateTime::parse_from_rfc3339(&fetched_at).ok()?.with_timezone(&Utc);
+    Some(ZecRates { zec_eur, zec_usd, updated_at, source: RateSource::parse(&source) })
+}
+
+/// The rate in effect at `at`: the last one fetched at or before it.
+pub async fn rate_at(pool: &SqlitePool, at: DateTime<Utc>) -> anyhow::Result<Option<ZecRates>> {
+    let row: Option<(f64, f64, String, String)> = sqlx::query_as(
+        "SELECT zec_eur, zec_usd, fetched_at, source FROM rates_history
+         WHERE fetched_at <= ? ORDER BY fetched_at DESC, id DESC LIMIT 1"
+    )
+    .bind(at.format("%Y-%m-%dT%H:%M:%SZ").to_string())
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.and_then(history_row))
+}
+
+/// Recorded rates over `range`, oldest first.
+pub async fn history(pool: &SqlitePool, range: HistoryRange) -> anyhow::Result<Vec<ZecRates>> {
+    let rows: Vec<(f64, f64, String, String)> = match range {
+        HistoryRange::Day => sqlx::query_as(
+            "SELECT zec_eur, zec_usd, fetched_at, source FROM rates_history
+             WHERE fetched_at >= strftime('%Y-%m-%dT%H:%M:%SZ', 'now', '-1 day')
+             ORDER BY fetched_at, id"
+        )
+        .fetch_all(pool)
+        .await?,
+        HistoryRange::Week => sqlx::query_as(
+            "SELECT AVG(zec_eur), AVG(zec_usd), MAX(fetched_at), MAX(source) FROM rates_history
+             WHERE fetched_at >= strftime('%Y-%m-%dT%H:%M:%SZ', 'now', '-7 days')
+             GROUP BY substr(fetched_at, 1, 13)
+             ORDER BY 3"
+        )
+        .fetch_all(pool)
+        .await?,
+    };
+    Ok(rows.into_iter().filter_map(history_row).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DurationRound;
+
+    #[test]
+    fn test_aggregate_takes_the_median() {
+        assert_eq!(aggregate(&[]), None);
+        assert_eq!(aggregate(&[(40.0, 44.0)]), Some((40.0, 44.0)));
+        assert_eq!(aggregate(&[(40.0, 44.0), (42.0, 46.0)]), Some((41.0, 45.0)));
+        assert_eq!(aggregate(&[(40.0, 44.0), (400.0, 0.0), (41.0, 45.0)]), Some((41.0, 44.5)));
+    }
+
+    #[tokio::test]
+    async fn test_operator_rate_wins_over_the_feed_until_cleared() {
+        use wiremock::{matchers::path, Mock, MockServer, ResponseTemplate};
+
+        let feed = MockServer::start().await;
+        Mock::given(path("/simple/price"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "zcash": { "eur": 40.0, "usd": 44.0 } })))
+            .mount(&feed)
+            .await;
+        let prices = PriceService {
+            sources: vec![feed.uri()],
+            cache_secs: 0,
+            private: false,
+            cached: Arc::default(),
+            manual: Arc::default(),
+            http: reqwest::Client::new(),
+            pool: crate::db::test_pool().await,
+        };
+
+        assert!(prices.set_manual(0.0, 55.0).await.is_err());
+        prices.set_manual(50.0, 55.0).await.unwrap();
+        let rates = prices.get_rates().await.unwrap();
+        assert_eq!((rates.zec_eur, rates.source), (50.0, RateSource::Operator));
+        assert!(feed.received_requests().await.unwrap().is_empty());
+
+        // Cleared, the feed is back; there is nothing left to clear.
+        assert!(prices.clear_manual().await);
+        let rates = prices.get_rates().await.unwrap();
+        assert_eq!((rates.zec_eur, rates.source), (40.0, RateSource::Feed));
+        assert!(!prices.clear_manual().await);
+    }
+
+    async fn record_at(pool: &SqlitePool, zec_eur: f64, updated_at: DateTime<Utc>) {
+        let rates = ZecRates { zec_eur, zec_usd: zec_eur * 1.1, updated_at, source: RateSource::Feed };
+        record_history(pool, &rates).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_recorded_rates_are_read_back_by_time_and_range() {
+        let pool = crate::db::test_pool().await;
+        let now = Utc::now();
+        let hour = now.duration_trunc(chrono::Duration::hours(1)).unwrap() - chrono::Duration::days(3);
+        record_at(&pool, 30.0, now - chrono::Duration::days(8)).await;
+        record_at(&pool, 40.0, hour + chrono::Duration::minutes(10)).await;
+        record_at(&pool, 44.0, hour + chrono::Duration::minutes(20)).await;
+        record_at(&pool, 50.0, now - chrono::Duration::hours(25)).await;
+        record_at(&pool, 60.0, now - chrono::Duration::hours(2)).await;
+        record_at(&pool, 61.0, now - chrono::Duration::hours(1)).await;
+
+        // The rate in effect is the last one fetched at or before the time asked about.
+        assert!(rate_at(&pool, now - chrono::Duration::days(9)).await.unwrap().is_none());
+        let at = hour + chrono::Duration::minutes(20);
+        assert_eq!(rate_at(&pool, at).await.unwrap().unwrap().zec_eur, 44.0);
+        assert_eq!(rate_at(&pool, at - chrono::Duration::seconds(1)).await.unwrap().unwrap().zec_eur, 40.0);
+        assert_eq!(rate_at(&pool, now).await.unwrap().unwrap().zec_eur, 61.0);
+
+        // 24h stops short of the rate fetched 25 hours ago; 7d leaves out the 8 day old one
+        // and averages each hour.
+        let day: Vec<f64> = history(&pool, HistoryRange::Day).await.unwrap().iter().map(|r| r.zec_eur).collect();
+        assert_eq!(day, [60.0, 61.0]);
+        let week: Vec<f64> = history(&pool, HistoryRange::Week).await.unwrap().iter().map(|r| r.zec_eur).collect();
+        assert_eq!(week, [42.0, 50.0, 60.0, 61.0]);
     }
 }
@@ -0,0 +1,183 @@
+//! Two-phase invoice purging. Buyer-identifying data (the refund address, checkout field
+//! answers) is removed shortly after an invoice is settled, field by field as configured in
+//! `PII_PURGE_FIELDS`. The financial record (amounts, payments and their txids, the fee
+//! ledger entry) stays for `INVOICE_RETENTION_DAYS`, after which the invoice is deleted.
+//!
+//! An invoice counts as settled once confirmed or refunded, or expired with nothing
+//! received. Expired and late-paid invoices still holding buyer funds keep their refund
+//! address until they are refunded.
+
+use sqlx::SqlitePool;
+
+use super::InvoiceError;
+use crate::config::Config;
+
+const SETTLED: &str = "(status IN ('confirmed', 'refunded') OR (status = 'expired' AND received_zatoshis = 0))";
+const SETTLED_AT: &str = "COALESCE(refunded_at, confirmed_at, expires_at)";
+
+/// Invoices deleted per transaction, so a first run over a large table doesn't hold the
+/// write lock for long.
+const DELETE_BATCH: i64 = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PiiField {
+    RefundAddress,
+    CustomFields,
+}
+
+impl PiiField {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "refund_address" => Some(PiiField::RefundAddress),
+            "custom_fields" => Some(PiiField::CustomFields),
+            _ => None,
+        }
+    }
+
+    fn column(self) -> &'static str {
+        match self {
+            PiiField::RefundAddress => "refund_address",
+            PiiField::CustomFields => "custom_fields",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PurgePolicy {
+    /// Each field purged, with the days after settlement it is kept.
+    pub fields: Vec<(PiiField, i64)>,
+    /// 0 keeps settled invoices forever.
+    pub retention_days: i64,
+}
+
+impl PurgePolicy {
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        Ok(Self {
+            fields: parse_fields(&config.pii_purge_fields, config.pii_purge_days)?,
+            retention_days: config.invoice_retention_days,
+        })
+    }
+}
+
+/// `refund_address,custom_fields:2`: comma-separated fields, each kept `default_days`
+/// unless it names its own.
+fn parse_fields(spec: &str, default_days: i64) -> anyhow::Result<Vec<(PiiField, i64)>> {
+    let mut fields = Vec::new();
+    for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (name, days) = match entry.split_once(':') {
+            Some((name, days)) => (name.trim(), days.trim().parse()?),
+            None => (entry, default_days),
+        };
+        let field = PiiField::parse(name)
+            .ok_or_else(|| anyhow::anyhow!("PII_PURGE_FIELDS: unknown field {:?}", name))?;
+        if days < 0 {
+            anyhow::bail!("PII_PURGE_FIELDS: days for {} must not be negative", name);
+        }
+        fields.push((field, days));
+    }
+    Ok(fields)
+}
+
+/// Phase one: clear buyer data from invoices settled long enough ago. Returns the number of
+/// fields cleared.
+pub async fn purge_buyer_data(pool: &SqlitePool, policy: &PurgePolicy) -> Result<u64, InvoiceError> {
+    let mut cleared = 0;
+    for (field, days) in &policy.fields {
+        let column = field.column();
+        let result = sqlx::query(&format!(
+            "UPDATE invoices SET {column} = NULL
+             WHERE {column} IS NOT NULL AND {SETTLED}
+             AND {SETTLED_AT} < strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?)"
+        ))
+        .bind(format!("-{} days", days))
+        .execute(pool)
+        .await?;
+        cleared += result.rows_affected();
+    }
+    Ok(cleared)
+}
+
+/// Phase two: delete settled invoices past the retention period with their payments
+/// (attached unmatched ones included), timeline, webhook deliveries, fee ledger entry and
+/// emailed payment request. Settlement invoices and invoices whose fee is still owed are
+/// kept. Returns the number of invoices deleted.
+pub async fn purge_records(pool: &SqlitePool, policy: &PurgePolicy) -> Result<u64, InvoiceError> {
+    if policy.retention_days <= 0 {
+        return Ok(0);
+    }
+    let cutoff = format!("-{} days", policy.retention_days);
+    let mut deleted = 0;
+    loop {
+        let mut tx = crate::db::begin_write(pool).await?;
+        let ids: Vec<String> = sqlx::query_scalar(&format!(
+            "SELECT id FROM invoices
+             WHERE {SETTLED} AND {SETTLED_AT} < strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?)
+             AND id NOT IN (SELECT settlement_invoice_id FROM billing_cycles WHERE settlement_invoice_id IS NOT NULL)
+             AND NOT EXISTS (
+                SELECT 1 FROM fee_ledger fl LEFT JOIN billing_cycles bc ON bc.id = fl.billing_cycle_id
+                WHERE fl.invoice_id = invoices.id
+                AND fl.auto_collected = 0 AND fl.collected_at IS NULL AND COALESCE(bc.status, 'open') != 'paid'
+             )
+             LIMIT ?"
+        ))
+        .bind(&cutoff)
+        .bind(DELETE_BATCH)
+        .fetch_all(&mut *tx)
+        .await?;
+        if ids.is_empty() {
+            break;
+        }
+
+        for (table, column) in [
+            ("webhook_deliveries", "invoice_id"),
+            ("invoice_events", "invoice_id"),
+            ("invoice_payments", "invoice_id"),
+            ("unmatched_payments", "attached_invoice_id"),
+            ("fee_ledger", "invoice_id"),
+            ("payment_requests", "invoice_id"),
+            ("invoices", "id"),
+        ] {
+            let mut query = sqlx::QueryBuilder::new(format!("DELETE FROM {} WHERE {} IN (", table, column));
+            let mut list = query.separated(", ");
+            for id in &ids {
+                list.push_bind(id);
+            }
+            query.push(")");
+            query.build().execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+
+        deleted += ids.len() as u64;
+        if (ids.len() as i64) < DELETE_BATCH {
+            break;
+        }
+    }
+    Ok(deleted)
+}
+
+/// Run both phases.
+pub async fn run(pool: &SqlitePool, policy: &PurgePolicy) -> Result<(), InvoiceError> {
+    let cleared = purge_buyer_data(pool, policy).await?;
+    let deleted = purge_records(pool, policy).await?;
+    if cleared > 0 || deleted > 0 {
+        tracing::info!(buyer_fields_cleared = cleared, invoices_deleted = deleted, "Invoice purge completed");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_fields() {
+        assert_eq!(
+            parse_fields("refund_address, custom_fields:2", 7).unwrap(),
+            vec![(PiiField::RefundAddress, 7), (PiiField::CustomFields, 2)],
+        );
+        assert!(parse_fields("", 7).unwrap().is_empty());
+        assert!(parse_fields("shoe_size", 7).is_err());
+        assert!(parse_fields("refund_address:-1", 7).is_err());
+        assert!(parse_fields("refund_address:soon", 7).is_err());
+    }
+}
@@ -0,0 +1,288 @@
+//! Payment requests: an invoice emailed to a buyer, for merchants who bill clients rather
+//! than sell through a checkout. The email links through the API, so the request records
+//! when it was first opened; whether it was paid is read from the invoice.
+
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqlitePool};
+use uuid::Uuid;
+
+use super::{CreateInvoiceRequest, InvoiceError};
+use crate::merchants::MerchantError;
+
+/// Payment requests one merchant can email per hour.
+pub const MAX_PER_HOUR: i64 = 50;
+/// How long an emailed invoice stays payable unless the request says otherwise: a day.
+pub const DEFAULT_EXPIRY_MINUTES: i64 = 24 * 60;
+/// Longest note to the buyer.
+pub const MAX_MESSAGE_LEN: usize = 2000;
+
+#[derive(Debug, Deserialize)]
+pub struct EmailInvoiceRequest {
+    /// The buyer's address.
+    pub email: String,
+    pub price_eur: f64,
+    pub currency: Option<String>,
+    /// What the payment is for, shown in the email and on the invoice.
+    pub product_name: Option<String>,
+    /// A note to the buyer, shown above the amount.
+    pub message: Option<String>,
+    /// Minutes the invoice stays payable; `DEFAULT_EXPIRY_MINUTES` when unset.
+    pub expiry_minutes: Option<i64>,
+    pub on_expiry: Option<String>,
+    /// Language tag for formatting the amount in the email and on the payment page.
+    pub locale: Option<String>,
+}
+
+impl EmailInvoiceRequest {
+    /// The invoice this request emails.
+    pub fn to_invoice_request(&self) -> CreateInvoiceRequest {
+        CreateInvoiceRequest {
+            product_id: None,
+            product_name: self.product_name.clone(),
+            size: None,
+            quantity: None,
+            price_eur: self.price_eur,
+            currency: self.currency.clone(),
+            refund_address: None,
+            tax: None,
+            on_expiry: self.on_expiry.clone(),
+            display_currency: None,
+            locale: self.locale.clone(),
+            custom_fields: None,
+            memo_prefix: None,
+            expiry_minutes: Some(self.expiry_minutes.unwrap_or(DEFAULT_EXPIRY_MINUTES)),
+            claim_code: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PaymentRequest {
+    pub id: String,
+    pub invoice_id: String,
+    pub email: String,
+    pub message: Option<String>,
+    pub memo_code: String,
+    pub price_eur: f64,
+    pub currency: Option<String>,
+    pub price_zec: f64,
+    /// `sent`, `opened`, `underpaid`, `detected`, `paid` or `expired`.
+    pub status: &'static str,
+    pub invoice_status: String,
+    pub opened_at: Option<String>,
+    pub open_count: i64,
+    pub created_at: String,
+}
+
+#[derive(FromRow)]
+struct RequestRow {
+    id: String,
+    invoice_id: String,
+    email: String,
+    message: Option<String>,
+    memo_code: String,
+    price_eur: f64,
+    currency: Option<String>,
+    price_zec: f64,
+    invoice_status: String,
+    opened_at: Option<String>,
+    open_count: i64,
+    created_at: String,
+}
+
+const COLUMNS: &str = "r.id, r.invoice_id, r.email, r.message, i.memo_code, i.price_eur, i.currency,
+     i.price_zec, i.status AS invoice_status, r.opened_at, r.open_count, r.created_at";
+
+/// Where the buyer is, from the invoice's status and whether the email was opened.
+fn status(invoice_status: &str, opened: bool) -> &'static str {
+    match invoice_status {
+        "pending" if opened => "opened",
+        "pending" => "sent",
+        "underpaid" => "underpaid",
+        "detected" => "detected",
+        "expired" => "expired",
+        _ => "paid",
+    }
+}
+
+fn seal(email: &str, encryption_key: &str) -> Result<String, InvoiceError> {
+    if encryption_key.is_empty() {
+        return Ok(email.to_string());
+    }
+    crate::crypto::encrypt(email, encryption_key).map_err(|e| MerchantError::Encryption(e).into())
+}
+
+/// Email addresses are never valid hex, so anything that is was sealed.
+fn open(row: RequestRow, encryption_key: &str) -> Result<PaymentRequest, InvoiceError> {
+    let email = if encryption_key.is_empty() || hex::decode(&row.email).is_err() {
+        row.email
+    } else {
+        crate::crypto::decrypt(&row.email, encryption_key).map_err(MerchantError::Encryption)?
+    };
+    Ok(PaymentRequest {
+        id: row.id,
+        invoice_id: row.invoice_id,
+        email,
+        message: row.message,
+        memo_code: row.memo_code,
+        price_eur: row.price_eur,
+        currency: row.currency,
+        price_zec: row.price_zec,
+        status: status(&row.invoice_status, row.opened_at.is_some()),
+        invoice_status: row.invoice_status,
+        opened_at: row.opened_at,
+        open_count: row.open_count,
+        created_at: row.created_at,
+    })
+}
+
+/// Record that `invoice_id` was emailed to `email`. Returns the request's id.
+pub async fn create(
+    pool: &SqlitePool,
+    merchant_id: &str,
+    invoice_id: &str,
+    email: &str,
+    message: Option<&str>,
+    encryption_key: &str,
+) -> Result<String, InvoiceError> {
+    let id = Uuid::new_v4().to_string();
+    sqlx::query(
+        "INSERT INTO payment_requests (id, merchant_id, invoice_id, email, message) VALUES (?, ?, ?, ?, ?)"
+    )
+    .bind(&id)
+    .bind(merchant_id)
+    .bind(invoice_id)
+    .bind(seal(email, encryption_key)?)
+    .bind(message)
+    .execute(pool)
+    .await?;
+    Ok(id)
+}
+
+/// Requests the merchant emailed in the last hour, for `MAX_PER_HOUR`.
+pub async fn sent_last_hour(pool: &SqlitePool, merchant_id: &str) -> Result<i64, InvoiceError> {
+    let count: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM payment_requests
+         WHERE merchant_id = ? AND created_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now', '-1 hour')"
+    )
+    .bind(merchant_id)
+    .fetch_one(pool)
+    .await?;
+    Ok(count)
+}
+
+pub async fn get(
+    pool: &SqlitePool,
+    merchant_id: &str,
+    id: &str,
+    encryption_key: &str,
+) -> Result<Option<PaymentRequest>, InvoiceError> {
+    let row = sqlx::query_as::<_, RequestRow>(&format!(
+        "SELECT {COLUMNS} FROM payment_requests r JOIN invoices i ON i.id = r.invoice_id
+         WHERE r.id = ? AND r.merchant_id = ?"
+    ))
+    .bind(id)
+    .bind(merchant_id)
+    .fetch_optional(pool)
+    .await?;
+    row.map(|r| open(r, encryption_key)).transpose()
+}
+
+/// The merchant's payment requests, newest first.
+pub async fn list(
+    pool: &SqlitePool,
+    merchant_id: &str,
+    limit: i64,
+    offset: i64,
+    encryption_key: &str,
+) -> Result<Vec<PaymentRequest>, InvoiceError> {
+    let rows = sqlx::query_as::<_, RequestRow>(&format!(
+        "SELECT {COLUMNS} FROM payment_requests r JOIN invoices i ON i.id = r.invoice_id
+         WHERE r.merchant_id = ?
+         ORDER BY r.created_at DESC, r.rowid DESC LIMIT ? OFFSET ?"
+    ))
+    .bind(merchant_id)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await?;
+    rows.into_iter().map(|r| open(r, encryption_key)).collect()
+}
+
+/// Count an opening of the email (its link followed or its QR code loaded). Returns the
+/// invoice it is for, or `None` for an unknown request.
+pub async fn record_open(pool: &SqlitePool, id: &str) -> Result<Option<String>, InvoiceError> {
+    let invoice_id: Option<String> = sqlx::query_scalar(
+        "UPDATE payment_requests SET open_count = open_count + 1,
+         opened_at = COALESCE(opened_at, strftime('%Y-%m-%dT%H:%M:%SZ', 'now'))
+         WHERE id = ? RETURNING invoice_id"
+    )
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(invoice_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+
+    #[tokio::test]
+    async fn test_payment_request_tracks_opens_and_payment() {
+        let pool = test_pool().await;
+        let key = "11".repeat(32);
+        let ufvk = crate::scanner::fixtures::test_ufvk(1);
+        let merchant = crate::merchants::create_merchant(&pool, &crate::merchants::CreateMerchantRequest {
+            name: Some("Studio".into()),
+            ufvk: ufvk.clone(),
+            webhook_url: None,
+            email: None,
+            verify_blocks: None,
+        }, "").await.unwrap().merchant_id;
+        let req = EmailInvoiceRequest {
+            email: "client@example.com".into(),
+            price_eur: 300.0,
+            currency: None,
+            product_name: Some("Logo design".into()),
+            message: None,
+            expiry_minutes: None,
+            on_expiry: None,
+            locale: None,
+        };
+        let invoice_req = req.to_invoice_request();
+        assert_eq!(invoice_req.expiry_minutes, Some(DEFAULT_EXPIRY_MINUTES));
+        let quotas = crate::invoices::InvoiceQuotas { max_open: 100, max_per_hour: 100, min_fiat: 0.0, min_zatoshis: 0 };
+        let invoice = crate::invoices::create_invoice(&pool, &merchant, &ufvk, &invoice_req, 40.0, 44.0, 30, None, &quotas)
+            .await
+            .unwrap();
+
+        let id = create(&pool, &merchant, &invoice.invoice_id, &req.email, Some("Thanks!"), &key).await.unwrap();
+        let sealed: String = sqlx::query_scalar("SELECT email FROM payment_requests WHERE id = ?")
+            .bind(&id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert!(!sealed.contains('@'));
+        assert_eq!(sent_last_hour(&pool, &merchant).await.unwrap(), 1);
+
+        let sent = get(&pool, &merchant, &id, &key).await.unwrap().unwrap();
+        assert_eq!((sent.status, sent.email.as_str()), ("sent", "client@example.com"));
+
+        assert_eq!(record_open(&pool, &id).await.unwrap(), Some(invoice.invoice_id.clone()));
+        record_open(&pool, &id).await.unwrap();
+        assert_eq!(record_open(&pool, "unknown").await.unwrap(), None);
+        let opened = get(&pool, &merchant, &id, &key).await.unwrap().unwrap();
+        assert_eq!((opened.status, opened.open_count), ("opened", 2));
+        assert!(opened.opened_at.is_some());
+
+        sqlx::query("UPDATE invoices SET status = 'confirmed' WHERE id = ?")
+            .bind(&invoice.invoice_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let listed = list(&pool, &merchant, 50, 0, &key).await.unwrap();
+        assert_eq!(listed[0].status, "paid");
+        assert!(get(&pool, "someone-else", &id, &key).await.unwrap().is_none());
+    }
+}
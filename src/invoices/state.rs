@@ -0,0 +1,256 @@
+//! Invoice status as a state machine. Every status change goes through `Transition`,
+//! which only updates invoices currently in a state allowed to move to the target, and
+//! records the timeline event in the same database transaction.
+
+use sqlx::{QueryBuilder, Sqlite, SqliteConnection, SqlitePool};
+
+use super::events;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvoiceState {
+    Pending,
+    Underpaid,
+    Detected,
+    Confirmed,
+    Expired,
+    PaidLate,
+    Refunded,
+}
+
+impl InvoiceState {
+    pub const ALL: [InvoiceState; 7] = [
+        InvoiceState::Pending,
+        InvoiceState::Underpaid,
+        InvoiceState::Detected,
+        InvoiceState::Confirmed,
+        InvoiceState::Expired,
+        InvoiceState::PaidLate,
+        InvoiceState::Refunded,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            InvoiceState::Pending => "pending",
+            InvoiceState::Underpaid => "underpaid",
+            InvoiceState::Detected => "detected",
+            InvoiceState::Confirmed => "confirmed",
+            InvoiceState::Expired => "expired",
+            InvoiceState::PaidLate => "paid_late",
+            InvoiceState::Refunded => "refunded",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|state| state.as_str() == s)
+    }
+
+    /// The allowed transitions.
+    pub fn can_become(self, to: InvoiceState) -> bool {
+        use InvoiceState::*;
+        matches!(
+            (self, to),
+            (Pending, Underpaid | Detected | Expired)
+                | (Underpaid, Detected | Expired)
+                | (Detected, Confirmed)
+                | (Expired, PaidLate | Refunded)
+                | (Confirmed | PaidLate, Refunded)
+        )
+    }
+
+    /// States that may move to `to`.
+    pub fn sources(to: InvoiceState) -> Vec<InvoiceState> {
+        Self::ALL.into_iter().filter(|from| from.can_become(to)).collect()
+    }
+}
+
+/// A value written alongside the status.
+pub enum Value {
+    Text(String),
+    Int(i64),
+    /// `column = column + n`
+    Add(i64),
+}
+
+impl From<&str> for Value {
+    fn from(v: &str) -> Self {
+        Value::Text(v.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(v: String) -> Self {
+        Value::Text(v)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(v: i64) -> Self {
+        Value::Int(v)
+    }
+}
+
+/// A status change, applied with `apply`.
+pub struct Transition<'a> {
+    to: InvoiceState,
+    from: Vec<InvoiceState>,
+    set: Vec<(&'static str, Value)>,
+    conditions: Vec<(&'static str, Option<Value>)>,
+    event: &'a str,
+    txid: Option<&'a str>,
+    block_height: Option<u64>,
+    detail: Option<serde_json::Value>,
+    detail_with_total: bool,
+}
+
+impl<'a> Transition<'a> {
+    /// Move to `to` from any state allowed to, recording `event` on the timeline.
+    pub fn new(to: InvoiceState, event: &'a str) -> Self {
+        Self {
+            to,
+            from: InvoiceState::sources(to),
+            set: Vec::new(),
+            conditions: Vec::new(),
+            event,
+            txid: None,
+            block_height: None,
+            detail: None,
+            detail_with_total: false,
+        }
+    }
+
+    /// Narrow the allowed source states. Each must be allowed by the graph.
+    pub fn only_from(mut self, from: &[InvoiceState]) -> Self {
+        debug_assert!(from.iter().all(|s| s.can_become(self.to)), "{:?} -> {:?}", from, self.to);
+        self.from = from.iter().copied().filter(|s| s.can_become(self.to)).collect();
+        self
+    }
+
+    pub fn set(mut self, column: &'static str, value: impl Into<Value>) -> Self {
+        self.set.push((column, value.into()));
+        self
+    }
+
+    /// Extra `column = value` the row must match.
+    pub fn require_eq(mut self, column: &'static str, value: impl Into<Value>) -> Self {
+        self.conditions.push((column, Some(value.into())));
+        self
+    }
+
+    /// Extra SQL condition the row must match.
+    pub fn require(mut self, condition: &'static str) -> Self {
+        self.conditions.push((condition, None));
+        self
+    }
+
+    pub fn txid(mut self, txid: &'a str) -> Self {
+        self.txid = Some(txid);
+        self
+    }
+
+    pub fn block_height(mut self, height: Option<u64>) -> Self {
+        self.block_height = height;
+        self
+    }
+
+    pub fn detail(mut self, detail: serde_json::Value) -> Self {
+        self.detail = Some(detail);
+        self
+    }
+
+    /// Add the invoice's `received_zatoshis` after the change to the event detail.
+    pub fn detail_with_total(mut self) -> Self {
+        self.detail_with_total = true;
+        self
+    }
+
+    /// Returns true if the invoice changed state.
+    pub async fn apply(self, pool: &SqlitePool, invoice_id: &str) -> sqlx::Result<bool> {
+        Ok(self.apply_returning_total(pool, invoice_id).await?.is_some())
+    }
+
+    /// Like `apply`, returning the invoice's `received_zatoshis` after the change.
+    pub async fn apply_returning_total(self, pool: &SqlitePool, invoice_id: &str) -> sqlx::Result<Option<i64>> {
+        if self.from.is_empty() {
+            return Ok(None);
+        }
+        let mut tx = crate::db::begin_write(pool).await?;
+        let total = self.apply_in(&mut tx, invoice_id).await?;
+        if total.is_some() {
+            tx.commit().await?;
+        }
+        Ok(total)
+    }
+
+    /// Apply inside a transaction the caller commits, after its own writes.
+    pub async fn apply_in(self, conn: &mut SqliteConnection, invoice_id: &str) -> sqlx::Result<Option<i64>> {
+        if self.from.is_empty() {
+            return Ok(None);
+        }
+
+        let mut query: QueryBuilder<Sqlite> = QueryBuilder::new("UPDATE invoices SET status = ");
+        query.push_bind(self.to.as_str());
+        for (column, value) in self.set {
+            query.push(format!(", {} = ", column));
+            match value {
+                Value::Text(v) => { query.push_bind(v); }
+                Value::Int(v) => { query.push_bind(v); }
+                Value::Add(v) => { query.push(format!("{} + ", column)).push_bind(v); }
+            }
+        }
+        query.push(" WHERE id = ").push_bind(invoice_id.to_string());
+        query.push(" AND status IN (");
+        let mut states = query.separated(", ");
+        for state in &self.from {
+            states.push_bind(state.as_str());
+        }
+        query.push(")");
+        for (condition, value) in self.conditions {
+            match value {
+                None => { query.push(format!(" AND {}", condition)); }
+                Some(Value::Text(v)) => { query.push(format!(" AND {} = ", condition)).push_bind(v); }
+                Some(Value::Int(v) | Value::Add(v)) => { query.push(format!(" AND {} = ", condition)).push_bind(v); }
+            }
+        }
+        query.push(" RETURNING received_zatoshis");
+
+        let total: Option<i64> = query.build_query_scalar().fetch_optional(&mut *conn).await?;
+        let Some(total) = total else {
+            return Ok(None);
+        };
+
+        let mut detail = self.detail;
+        if self.detail_with_total {
+            let detail = detail.get_or_insert_with(|| serde_json::json!({}));
+            detail["received_zatoshis"] = total.into();
+        }
+        events::insert(&mut *conn, invoice_id, self.event, self.txid, self.block_height, detail).await?;
+        Ok(Some(total))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InvoiceState::{self, *};
+
+    #[test]
+    fn test_transition_graph() {
+        assert!(Pending.can_become(Detected));
+        assert!(Underpaid.can_become(Detected));
+        assert!(Detected.can_become(Confirmed));
+        assert!(Expired.can_become(PaidLate));
+        assert!(!Confirmed.can_become(Detected));
+        assert!(!Detected.can_become(Expired));
+        assert!(!Refunded.can_become(Pending));
+        assert!(!Pending.can_become(Refunded));
+        assert_eq!(InvoiceState::sources(Refunded), vec![Confirmed, Expired, PaidLate]);
+        assert!(InvoiceState::sources(Pending).is_empty());
+    }
+
+    #[test]
+    fn test_state_names_round_trip() {
+        for state in InvoiceState::ALL {
+            assert_eq!(InvoiceState::parse(state.as_str()), Some(state));
+        }
+        assert_eq!(InvoiceState::parse("shipped"), None);
+    }
+}
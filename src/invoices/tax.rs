@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use super::amounts::round_fiat;
+use super::InvoiceError;
+
+/// Merchant tax configuration applied at checkout.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TaxSettings {
+    /// Default rate in percent (20.0 = 20%). 0 disables tax.
+    pub rate: f64,
+    /// Product prices already include tax (usual for EU consumer sales).
+    pub inclusive: bool,
+    /// Per-country rates in percent, keyed by ISO 3166-1 alpha-2 code.
+    #[serde(default)]
+    pub country_rates: BTreeMap<String, f64>,
+}
+
+/// Tax itemization stored on an invoice. Amounts are in the invoice currency.
+#[derive(Debug, Clone, Serialize)]
+pub struct TaxBreakdown {
+    pub rate: f64,
+    pub inclusive: bool,
+    pub country: Option<String>,
+    pub subtotal: f64,
+    pub tax_amount: f64,
+    pub total: f64,
+}
+
+impl TaxSettings {
+    pub fn rate_for(&self, country: Option<&str>) -> f64 {
+        country
+            .and_then(|c| self.country_rates.get(&c.to_ascii_uppercase()))
+            .copied()
+            .unwrap_or(self.rate)
+    }
+
+    /// Apply tax to a price. Returns None when no tax applies.
+    pub fn apply(&self, amount: f64, country: Option<&str>) -> Option<TaxBreakdown> {
+        let rate = self.rate_for(country);
+        if rate <= 0.0 {
+            return None;
+        }
+
+        let (subtotal, tax_amount, total) = if self.inclusive {
+            let tax = round_fiat(amount - amount / (1.0 + rate / 100.0));
+            (round_fiat(amount - tax), tax, amount)
+        } else {
+            let tax = round_fiat(amount * rate / 100.0);
+            (amount, tax, round_fiat(amount + tax))
+        };
+
+        Some(TaxBreakdown {
+            rate,
+            inclusive: self.inclusive,
+            country: country.map(|c| c.to_ascii_uppercase()),
+            subtotal,
+            tax_amount,
+            total,
+        })
+    }
+}
+
+pub async fn get_settings(pool: &SqlitePool, merchant_id: &str) -> Result<TaxSettings, InvoiceError> {
+    let row: Option<(f64, bool, Option<String>)> = sqlx::query_as(
+        "SELECT tax_rate, tax_inclusive, tax_country_rates FROM merchants WHERE id = ?"
+    )
+    .bind(merchant_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(match row {
+        Some((rate, inclusive, country_rates)) => TaxSettings {
+            rate,
+            inclusive,
+            country_rates: country_rates
+                .and_then(|c| serde_json::from_str(&c).ok())
+                .unwrap_or_default(),
+        },
+        None => TaxSettings::default(),
+    })
+}
+
+pub async fn update_settings(pool: &SqlitePool, merchant_id: &str, settings: &TaxSettings) -> Result<(), InvoiceError> {
+    let country_rates: BTreeMap<String, f64> = settings
+        .country_rates
+        .iter()
+        .map(|(c, r)| (c.to_ascii_uppercase(), *r))
+        .collect();
+
+    sqlx::query(
+        "UPDATE merchants SET tax_rate = ?, tax_inclusive = ?, tax_country_rates = ? WHERE id = ?"
+    )
+    .bind(settings.rate)
+    .bind(settings.inclusive)
+    .bind(if country_rates.is_empty() { None } else { Some(serde_json::json!(country_rates).to_string()) })
+    .bind(merchant_id)
+    .execute(pool)
+    .await?;
+
+    tracing::info!(merchant_id, rate = settings.rate, inclusive = settings.inclusive, "Tax settings updated");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(inclusive: bool) -> TaxSettings {
+        TaxSettings {
+            rate: 20.0,
+            inclusive,
+            country_rates: BTreeMap::from([("DE".to_string(), 19.0)]),
+        }
+    }
+
+    #[test]
+    fn test_exclusive_tax_is_added() {
+        let t = settings(false).apply(100.0, None).unwrap();
+        assert_eq!((t.subtotal, t.tax_amount, t.total), (100.0, 20.0, 120.0));
+    }
+
+    #[test]
+    fn test_inclusive_tax_is_extracted() {
+        let t = settings(true).apply(119.0, Some("de")).unwrap();
+        assert_eq!((t.subtotal, t.tax_amount, t.total), (100.0, 19.0, 119.0));
+        assert_eq!(t.country.as_deref(), Some("DE"));
+    }
+
+    #[test]
+    fn test_zero_rate_means_no_tax() {
+        assert!(TaxSettings::default().apply(50.0, Some("FR")).is_none());
+    }
+}
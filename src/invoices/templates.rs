@@ -0,0 +1,273 @@
+//! Invoice templates: named presets of price, currency, product, memo prefix and expiry
+//! for a service the merchant bills again and again. `POST /api/invoice-templates/{id}/issue`
+//! creates an invoice from one, priced at the current rate like any other.
+
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, SqlitePool};
+use uuid::Uuid;
+
+use super::{CreateInvoiceRequest, InvoiceError};
+
+/// Templates one merchant can keep.
+pub const MAX_TEMPLATES: i64 = 100;
+/// Longest expiry a template can set: 30 days.
+pub const MAX_EXPIRY_MINUTES: i64 = 30 * 24 * 60;
+/// Memo prefixes the platform uses for its own payments.
+pub const RESERVED_MEMO_PREFIXES: [&str; 2] = ["FEE", "SETTLE"];
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct InvoiceTemplate {
+    pub id: String,
+    pub name: String,
+    /// Invoice total in `currency`, as in `CreateInvoiceRequest`.
+    pub price_eur: f64,
+    pub currency: String,
+    pub product_name: Option<String>,
+    /// Replaces `CP` in the memo codes of issued invoices, e.g. `RENT-1A2B3C4D`.
+    pub memo_prefix: Option<String>,
+    /// Minutes issued invoices stay payable; the server default when unset.
+    pub expiry_minutes: Option<i64>,
+    pub on_expiry: String,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+/// A template's settings, as given when it is created.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TemplateFields {
+    pub name: String,
+    pub price_eur: f64,
+    pub currency: Option<String>,
+    pub product_name: Option<String>,
+    pub memo_prefix: Option<String>,
+    pub expiry_minutes: Option<i64>,
+    pub on_expiry: Option<String>,
+}
+
+/// Changes to a template; absent fields are kept.
+#[derive(Debug, Default, Deserialize)]
+pub struct UpdateTemplateRequest {
+    pub name: Option<String>,
+    pub price_eur: Option<f64>,
+    pub currency: Option<String>,
+    /// Empty string clears the product name.
+    pub product_name: Option<String>,
+    /// Empty string goes back to `CP`.
+    pub memo_prefix: Option<String>,
+    /// 0 goes back to the server default.
+    pub expiry_minutes: Option<i64>,
+    pub on_expiry: Option<String>,
+}
+
+impl UpdateTemplateRequest {
+    /// `existing` with these changes applied.
+    pub fn apply(self, existing: &InvoiceTemplate) -> TemplateFields {
+        let clearable = |new: Option<String>, old: &Option<String>| match new {
+            Some(v) if v.is_empty() => None,
+            Some(v) => Some(v),
+            None => old.clone(),
+        };
+        TemplateFields {
+            name: self.name.unwrap_or_else(|| existing.name.clone()),
+            price_eur: self.price_eur.unwrap_or(existing.price_eur),
+            currency: self.currency.or_else(|| Some(existing.currency.clone())),
+            product_name: clearable(self.product_name, &existing.product_name),
+            memo_prefix: clearable(self.memo_prefix, &existing.memo_prefix),
+            expiry_minutes: match self.expiry_minutes {
+                Some(0) => None,
+                Some(m) => Some(m),
+                None => existing.expiry_minutes,
+            },
+            on_expiry: self.on_expiry.or_else(|| Some(existing.on_expiry.clone())),
+        }
+    }
+}
+
+impl InvoiceTemplate {
+    /// The invoice request this template issues.
+    pub fn to_request(&self) -> CreateInvoiceRequest {
+        CreateInvoiceRequest {
+            product_id: None,
+            product_name: self.product_name.clone(),
+            size: None,
+            quantity: None,
+            price_eur: self.price_eur,
+            currency: Some(self.currency.clone()),
+            refund_address: None,
+            tax: None,
+            on_expiry: Some(self.on_expiry.clone()),
+            display_currency: None,
+            locale: None,
+            custom_fields: None,
+            memo_prefix: self.memo_prefix.clone(),
+            expiry_minutes: self.expiry_minutes,
+            claim_code: false,
+        }
+    }
+}
+
+const COLUMNS: &str =
+    "id, name, price_eur, currency, product_name, memo_prefix, expiry_minutes, on_expiry, created_at, updated_at";
+
+/// The merchant's templates, by name.
+pub async fn list(pool: &SqlitePool, merchant_id: &str) -> Result<Vec<InvoiceTemplate>, InvoiceError> {
+    let rows = sqlx::query_as::<_, InvoiceTemplate>(&format!(
+        "SELECT {COLUMNS} FROM invoice_templates WHERE merchant_id = ? ORDER BY name"
+    ))
+    .bind(merchant_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+pub async fn get(pool: &SqlitePool, merchant_id: &str, id: &str) -> Result<Option<InvoiceTemplate>, InvoiceError> {
+    let row = sqlx::query_as::<_, InvoiceTemplate>(&format!(
+        "SELECT {COLUMNS} FROM invoice_templates WHERE id = ? AND merchant_id = ?"
+    ))
+    .bind(id)
+    .bind(merchant_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row)
+}
+
+/// Store a template, already validated and normalized. Returns `None` once the merchant
+/// has `MAX_TEMPLATES`.
+pub async fn create(
+    pool: &SqlitePool,
+    merchant_id: &str,
+    fields: &TemplateFields,
+) -> Result<Option<InvoiceTemplate>, InvoiceError> {
+    let mut tx = crate::db::begin_write(pool).await?;
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM invoice_templates WHERE merchant_id = ?")
+        .bind(merchant_id)
+        .fetch_one(&mut *tx)
+        .await?;
+    if count >= MAX_TEMPLATES {
+        return Ok(None);
+    }
+
+    let id = Uuid::new_v4().to_string();
+    sqlx::query(
+        "INSERT INTO invoice_templates
+         (id, merchant_id, name, price_eur, currency, product_name, memo_prefix, expiry_minutes, on_expiry)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(&id)
+    .bind(merchant_id)
+    .bind(&fields.name)
+    .bind(fields.price_eur)
+    .bind(fields.currency.as_deref().unwrap_or("EUR"))
+    .bind(&fields.product_name)
+    .bind(&fields.memo_prefix)
+    .bind(fields.expiry_minutes)
+    .bind(fields.on_expiry.as_deref().unwrap_or("expire"))
+    .execute(&mut *tx)
+    .await?;
+    let created = sqlx::query_as::<_, InvoiceTemplate>(&format!("SELECT {COLUMNS} FROM invoice_templates WHERE id = ?"))
+        .bind(&id)
+        .fetch_one(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(Some(created))
+}
+
+/// Replace a template's settings. `None` if the merchant has no such template.
+pub async fn update(
+    pool: &SqlitePool,
+    merchant_id: &str,
+    id: &str,
+    fields: &TemplateFields,
+) -> Result<Option<InvoiceTemplate>, InvoiceError> {
+    let updated = sqlx::query(
+        "UPDATE invoice_templates SET name = ?, price_eur = ?, currency = ?, product_name = ?,
+         memo_prefix = ?, expiry_minutes = ?, on_expiry = ?,
+         updated_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now')
+         WHERE id = ? AND merchant_id = ?"
+    )
+    .bind(&fields.name)
+    .bind(fields.price_eur)
+    .bind(fields.currency.as_deref().unwrap_or("EUR"))
+    .bind(&fields.product_name)
+    .bind(&fields.memo_prefix)
+    .bind(fields.expiry_minutes)
+    .bind(fields.on_expiry.as_deref().unwrap_or("expire"))
+    .bind(id)
+    .bind(merchant_id)
+    .execute(pool)
+    .await?
+    .rows_affected();
+    if updated == 0 {
+        return Ok(None);
+    }
+    get(pool, merchant_id, id).await
+}
+
+pub async fn remove(pool: &SqlitePool, merchant_id: &str, id: &str) -> Result<bool, InvoiceError> {
+    let removed = sqlx::query("DELETE FROM invoice_templates WHERE id = ? AND merchant_id = ?")
+        .bind(id)
+        .bind(merchant_id)
+        .execute(pool)
+        .await?
+        .rows_affected();
+    Ok(removed > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+
+    fn fields(name: &str) -> TemplateFields {
+        TemplateFields {
+            name: name.into(),
+            price_eur: 49.0,
+            currency: Some("USD".into()),
+            product_name: Some("Monthly support".into()),
+            memo_prefix: Some("SUP".into()),
+            expiry_minutes: Some(1440),
+            on_expiry: None,
+        }
+    }
+
+    async fn merchant(pool: &SqlitePool) -> String {
+        let req = crate::merchants::CreateMerchantRequest {
+            name: Some("Shop".into()),
+            ufvk: crate::scanner::fixtures::test_ufvk(1),
+            webhook_url: None,
+            email: None,
+            verify_blocks: None,
+        };
+        crate::merchants::create_merchant(pool, &req, "").await.unwrap().merchant_id
+    }
+
+    #[tokio::test]
+    async fn test_template_lifecycle() {
+        let pool = test_pool().await;
+        let merchant_id = merchant(&pool).await;
+
+        let created = create(&pool, &merchant_id, &fields("Support")).await.unwrap().unwrap();
+        assert_eq!(created.on_expiry, "expire");
+        let req = created.to_request();
+        assert_eq!(req.memo_prefix.as_deref(), Some("SUP"));
+        assert_eq!(req.expiry_minutes, Some(1440));
+        assert_eq!(req.currency.as_deref(), Some("USD"));
+
+        let changes = UpdateTemplateRequest {
+            price_eur: Some(59.0),
+            memo_prefix: Some(String::new()),
+            expiry_minutes: Some(0),
+            ..Default::default()
+        };
+        let updated = update(&pool, &merchant_id, &created.id, &changes.apply(&created)).await.unwrap().unwrap();
+        assert_eq!(updated.price_eur, 59.0);
+        assert_eq!(updated.memo_prefix, None);
+        assert_eq!(updated.expiry_minutes, None);
+        assert_eq!(updated.product_name.as_deref(), Some("Monthly support"));
+
+        assert!(get(&pool, "someone-else", &created.id).await.unwrap().is_none());
+        assert_eq!(list(&pool, &merchant_id).await.unwrap().len(), 1);
+        assert!(remove(&pool, &merchant_id, &created.id).await.unwrap());
+        assert!(list(&pool, &merchant_id).await.unwrap().is_empty());
+    }
+}
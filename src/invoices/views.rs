@@ -0,0 +1,501 @@
+//! Response shapes for invoices. Every endpoint serializes invoices through these
+//! structs, so a new column only reaches API consumers when it is added here.
+
+use serde::{Deserialize, Serialize};
+
+use super::display::DisplayPrice;
+use super::{zatoshis_to_zec, Invoice, InvoicePayment, InvoiceStatus};
+
+/// Received amount counted as an overpayment above the price (0.00001 ZEC).
+const OVERPAID_TOLERANCE_ZATOSHIS: i64 = 1000;
+
+/// Shown with every TEX address; the checkout should display it before the address.
+pub const TEX_WARNING: &str = "This is a transparent address: the amount, this address and \
+the sending address are public on the blockchain. Only use it if your exchange cannot \
+send to a shielded address.";
+
+/// TEX alternative offered when the merchant opted in, for buyers paying from an
+/// exchange that only sends to transparent addresses.
+#[derive(Debug, Serialize)]
+pub struct TexPayment {
+    pub address: String,
+    pub zcash_uri: String,
+    pub warning: &'static str,
+}
+
+/// What anyone holding an invoice ID or memo code may see (hosted checkout, widget).
+#[derive(Debug, Serialize)]
+pub struct PublicInvoice {
+    pub id: String,
+    pub memo_code: String,
+    pub product_name: Option<String>,
+    pub product_image_url: Option<String>,
+    pub size: Option<String>,
+    pub quantity: i64,
+    pub tax: serde_json::Value,
+    pub price_eur: f64,
+    pub price_usd: Option<f64>,
+    pub currency: Option<String>,
+    /// Buyer-facing price when checkout asked for another currency or locale.
+    pub display: Option<DisplayPrice>,
+    pub price_zec: f64,
+    pub zec_rate_at_creation: f64,
+    pub payment_address: String,
+    pub zcash_uri: String,
+    /// Merchant output only; equals `zcash_uri` when no fee output is requested.
+    pub zcash_uri_simple: String,
+    pub tex: Option<TexPayment>,
+    pub merchant_name: Option<String>,
+    pub merchant_origin: Option<String>,
+    pub status: String,
+    pub detected_txid: Option<String>,
+    pub detected_at: Option<String>,
+    pub confirmed_at: Option<String>,
+    pub refunded_at: Option<String>,
+    pub expires_at: String,
+    pub on_expiry: String,
+    pub created_at: String,
+    pub received_zec: f64,
+    pub price_zatoshis: i64,
+    pub received_zatoshis: i64,
+    /// Still to pay; 0 once the price is covered.
+    pub remaining_zatoshis: i64,
+    /// Of the latest mined payment; null until one is mined.
+    pub confirmations: Option<i64>,
+    pub overpaid: bool,
+}
+
+impl PublicInvoice {
+    pub fn new(inv: &Invoice) -> Self {
+        Self {
+            id: inv.id.clone(),
+            memo_code: inv.memo_code.clone(),
+            product_name: inv.product_name.clone(),
+            product_image_url: None,
+            size: inv.size.clone(),
+            quantity: inv.quantity,
+            tax: inv.tax_json(),
+            price_eur: inv.price_eur,
+            price_usd: inv.price_usd,
+            currency: inv.currency.clone(),
+            display: DisplayPrice::for_invoice(inv),
+            price_zec: inv.price_zec,
+            zec_rate_at_creation: inv.zec_rate_at_creation,
+            payment_address: inv.payment_address.clone(),
+            zcash_uri: inv.zcash_uri.clone(),
+            zcash_uri_simple: super::simple_zcash_uri(&inv.payment_address, inv.price_zec, &inv.memo_code),
+            tex: inv.tex_address.as_ref().map(|address| TexPayment {
+                address: address.clone(),
+                zcash_uri: super::tex_zcash_uri(address, inv.price_zec),
+                warning: TEX_WARNING,
+            }),
+            merchant_name: inv.merchant_name.clone(),
+            merchant_origin: None,
+            status: inv.status.clone(),
+            detected_txid: inv.detected_txid.clone(),
+            detected_at: inv.detected_at.clone(),
+            confirmed_at: inv.confirmed_at.clone(),
+            refunded_at: inv.refunded_at.clone(),
+            expires_at: inv.expires_at.clone(),
+            on_expiry: inv.on_expiry.clone(),
+            created_at: inv.created_at.clone(),
+            received_zec: zatoshis_to_zec(inv.received_zatoshis),
+            price_zatoshis: inv.price_zatoshis,
+            received_zatoshis: inv.received_zatoshis,
+            remaining_zatoshis: (inv.price_zatoshis - inv.received_zatoshis).max(0),
+            confirmations: None,
+            overpaid: inv.received_zatoshis > inv.price_zatoshis + OVERPAID_TOLERANCE_ZATOSHIS
+                && inv.price_zatoshis > 0,
+        }
+    }
+
+    pub fn with_product_image(mut self, url: Option<String>) -> Self {
+        self.product_image_url = url;
+        self
+    }
+
+    pub fn with_confirmations(mut self, confirmations: Option<i64>) -> Self {
+        self.confirmations = confirmations;
+        self
+    }
+
+    /// Origin of the merchant's webhook URL, which the hosted page posts payment messages to.
+    pub fn with_merchant_origin(mut self, origin: Option<String>) -> Self {
+        self.merchant_origin = origin;
+        self
+    }
+
+    /// The invoice as a schema.org `Invoice` for JSON-LD, so wallets and crawlers reading
+    /// the hosted checkout or receipt find the payment request. `page_url` is the hosted
+    /// payment page. The order it pays is under `referencesOrder` and the `zcash:` URI to
+    /// pay is the target of a `PayAction`.
+    pub fn json_ld(&self, page_url: &str) -> serde_json::Value {
+        let (payment_status, order_status) = match self.status.as_str() {
+            "pending" | "underpaid" => ("PaymentDue", "OrderPaymentDue"),
+            "detected" => ("PaymentAutomaticallyApplied", "OrderProcessing"),
+            "expired" => ("PaymentPastDue", "OrderCancelled"),
+            "refunded" => ("PaymentDeclined", "OrderReturned"),
+            _ => ("PaymentComplete", "OrderProcessing"),
+        };
+        let provider = serde_json::json!({
+            "@type": "Organization",
+            "name": self.merchant_name,
+            "url": self.merchant_origin,
+        });
+        let zec = |value: f64| serde_json::json!({
+            "@type": "MonetaryAmount",
+            "value": format!("{:.8}", value),
+            "currency": "ZEC",
+        });
+        serde_json::json!({
+            "@context": "https://schema.org",
+            "@type": "Invoice",
+            "identifier": self.id,
+            "confirmationNumber": self.memo_code,
+            "url": page_url,
+            "provider": provider,
+            "paymentStatus": format!("https://schema.org/{}", payment_status),
+            "paymentDueDate": self.expires_at,
+            "paymentMethod": "Zcash",
+            "totalPaymentDue": zec(self.price_zec),
+            "minimumPaymentDue": zec(zatoshis_to_zec(self.remaining_zatoshis)),
+            "referencesOrder": {
+                "@type": "Order",
+                "orderNumber": self.memo_code,
+                "orderDate": self.created_at,
+                "orderStatus": format!("https://schema.org/{}", order_status),
+                "seller": provider,
+                "acceptedOffer": {
+                    "@type": "Offer",
+                    "itemOffered": {
+                        "@type": "Product",
+                        "name": self.product_name,
+                        "image": self.product_image_url,
+                    },
+                    "price": self.price_eur,
+                    "priceCurrency": self.currency.as_deref().unwrap_or("EUR"),
+                    "eligibleQuantity": { "@type": "QuantitativeValue", "value": self.quantity },
+                },
+            },
+            "potentialAction": {
+                "@type": "PayAction",
+                "target": self.zcash_uri,
+                "recipient": provider,
+            },
+        })
+    }
+}
+
+/// How much of an invoice a public memo code lookup shows, set per merchant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MemoLookup {
+    /// `PublicInvoice`, as the hosted checkout shows it.
+    #[default]
+    Full,
+    /// `InvoiceAmounts`: what is owed and paid, nothing about the order or the merchant.
+    AmountsOnly,
+    /// Lookups answer as if the code did not exist.
+    Disabled,
+}
+
+impl MemoLookup {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MemoLookup::Full => "full",
+            MemoLookup::AmountsOnly => "amounts_only",
+            MemoLookup::Disabled => "disabled",
+        }
+    }
+
+    /// The stored value; anything unknown is `Full`, the column's default.
+    pub fn parse(s: &str) -> Self {
+        match s {
+            "amounts_only" => MemoLookup::AmountsOnly,
+            "disabled" => MemoLookup::Disabled,
+            _ => MemoLookup::Full,
+        }
+    }
+}
+
+/// A memo code lookup for a merchant with `amounts_only` exposure. No invoice ID either:
+/// it would open the full public view.
+#[derive(Debug, Serialize)]
+pub struct InvoiceAmounts {
+    pub memo_code: String,
+    pub status: String,
+    pub price_zec: f64,
+    pub price_zatoshis: i64,
+    pub received_zatoshis: i64,
+    pub remaining_zatoshis: i64,
+    pub expires_at: String,
+    pub confirmations: Option<i64>,
+}
+
+impl InvoiceAmounts {
+    pub fn new(inv: &Invoice, confirmations: Option<i64>) -> Self {
+        Self {
+            memo_code: inv.memo_code.clone(),
+            status: inv.status.clone(),
+            price_zec: inv.price_zec,
+            price_zatoshis: inv.price_zatoshis,
+            received_zatoshis: inv.received_zatoshis,
+            remaining_zatoshis: (inv.price_zatoshis - inv.received_zatoshis).max(0),
+            expires_at: inv.expires_at.clone(),
+            confirmations,
+        }
+    }
+}
+
+/// The owning merchant's view (API key or dashboard session): the public fields plus
+/// bookkeeping columns. Keys, receivers and diversifier indexes are never included.
+#[derive(Debug, Serialize)]
+pub struct MerchantInvoice {
+    #[serde(flatten)]
+    pub public: PublicInvoice,
+    pub merchant_id: String,
+    pub refund_address: Option<String>,
+    pub purge_after: Option<String>,
+    /// Same as `zec_rate_at_creation`; kept for existing list consumers.
+    pub zec_rate: f64,
+    pub tax_rate: Option<f64>,
+    pub tax_amount: Option<f64>,
+    pub tax_inclusive: Option<bool>,
+    pub tax_country: Option<String>,
+    pub requote_count: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payments: Option<Vec<InvoicePayment>>,
+    /// Whether what is still owed equals the miner fee of the buyer's payments, as when a
+    /// wallet takes its fee out of the amount sent. Null when nothing is owed or no fee is
+    /// known; set with `payments`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shortfall_is_fee: Option<bool>,
+    /// The buyer's answers to the product's checkout fields, decrypted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub custom_fields: Option<serde_json::Value>,
+}
+
+impl MerchantInvoice {
+    pub fn new(inv: &Invoice) -> Self {
+        Self {
+            public: PublicInvoice::new(inv),
+            merchant_id: inv.merchant_id.clone(),
+            refund_address: inv.refund_address.clone(),
+            purge_after: inv.purge_after.clone(),
+            zec_rate: inv.zec_rate_at_creation,
+            tax_rate: inv.tax_rate,
+            tax_amount: inv.tax_amount,
+            tax_inclusive: inv.tax_inclusive,
+            tax_country: inv.tax_country.clone(),
+            requote_count: inv.requote_count,
+            payments: None,
+            shortfall_is_fee: None,
+            custom_fields: None,
+        }
+    }
+
+    pub fn with_payments(mut self, payments: Vec<InvoicePayment>) -> Self {
+        let short = self.public.remaining_zatoshis;
+        let fees: Vec<i64> = payments.iter().filter_map(|p| p.fee_zatoshis).collect();
+        self.shortfall_is_fee = (short > 0 && !fees.is_empty())
+            .then(|| fees.contains(&short) || fees.iter().sum::<i64>() == short);
+        self.payments = Some(payments);
+        self
+    }
+
+    pub fn with_custom_fields(mut self, custom_fields: Option<serde_json::Value>) -> Self {
+        self.custom_fields = custom_fields;
+        self
+    }
+}
+
+/// `status` event on the public SSE stream.
+#[derive(Debug, Serialize)]
+pub struct StatusEvent {
+    pub status: String,
+    pub txid: Option<String>,
+    pub received_zatoshis: i64,
+    pub price_zatoshis: i64,
+    pub remaining_zatoshis: i64,
+    pub confirmations: Option<i64>,
+}
+
+impl From<&InvoiceStatus> for StatusEvent {
+    fn from(s: &InvoiceStatus) -> Self {
+        Self {
+            status: s.status.clone(),
+            txid: s.detected_txid.clone(),
+            received_zatoshis: s.received_zatoshis,
+            price_zatoshis: s.price_zatoshis,
+            remaining_zatoshis: s.remaining_zatoshis,
+            confirmations: s.confirmations,
+        }
+    }
+}
+
+/// `requoted` event on the public SSE stream.
+#[derive(Debug, Serialize)]
+pub struct RequoteEvent {
+    pub price_zec: f64,
+    pub price_zatoshis: i64,
+    pub zcash_uri: String,
+    pub expires_at: String,
+}
+
+impl From<&Invoice> for RequoteEvent {
+    fn from(inv: &Invoice) -> Self {
+        Self {
+            price_zec: inv.price_zec,
+            price_zatoshis: inv.price_zatoshis,
+            zcash_uri: inv.zcash_uri.clone(),
+            expires_at: inv.expires_at.clone(),
+        }
+    }
+}
+
+/// One row of an invoice export, flat so CSV and NDJSON exports share their columns.
+#[derive(Debug, Serialize)]
+pub struct ExportedInvoice<'a> {
+    pub id: &'a str,
+    pub memo_code: &'a str,
+    pub status: &'a str,
+    pub network: &'a str,
+    pub product_name: Option<&'a str>,
+    pub size: Option<&'a str>,
+    pub quantity: i64,
+    pub currency: Option<&'a str>,
+    pub price_eur: f64,
+    pub price_usd: Option<f64>,
+    pub price_zec: f64,
+    pub zec_rate: f64,
+    pub price_zatoshis: i64,
+    pub received_zatoshis: i64,
+    pub tax_rate: Option<f64>,
+    pub tax_amount: Option<f64>,
+    pub tax_country: Option<&'a str>,
+    pub refund_address: Option<&'a str>,
+    pub detected_txid: Option<&'a str>,
+    pub created_at: &'a str,
+    pub detected_at: Option<&'a str>,
+    pub confirmed_at: Option<&'a str>,
+    pub refunded_at: Option<&'a str>,
+    pub expires_at: &'a str,
+}
+
+impl<'a> From<&'a Invoice> for ExportedInvoice<'a> {
+    fn from(inv: &'a Invoice) -> Self {
+        Self {
+            id: &inv.id,
+            memo_code: &inv.memo_code,
+            status: &inv.status,
+            network: &inv.network,
+            product_name: inv.product_name.as_deref(),
+            size: inv.size.as_deref(),
+            quantity: inv.quantity,
+            currency: inv.currency.as_deref(),
+            price_eur: inv.price_eur,
+            price_usd: inv.price_usd,
+            price_zec: inv.price_zec,
+            zec_rate: inv.zec_rate_at_creation,
+            price_zatoshis: inv.price_zatoshis,
+            received_zatoshis: inv.received_zatoshis,
+            tax_rate: inv.tax_rate,
+            tax_amount: inv.tax_amount,
+            tax_country: inv.tax_country.as_deref(),
+            refund_address: inv.refund_address.as_deref(),
+            detected_txid: inv.detected_txid.as_deref(),
+            created_at: &inv.created_at,
+            detected_at: inv.detected_at.as_deref(),
+            confirmed_at: inv.confirmed_at.as_deref(),
+            refunded_at: inv.refunded_at.as_deref(),
+            expires_at: &inv.expires_at,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::invoices::test_invoice;
+
+    #[test]
+    fn test_public_view_hides_merchant_fields() {
+        let json = serde_json::to_value(PublicInvoice::new(&test_invoice())).unwrap();
+        for key in ["merchant_id", "refund_address", "orchard_receiver_hex", "diversifier_index", "payments", "custom_fields"] {
+            assert!(json.get(key).is_none(), "{} leaked", key);
+        }
+
+        let json = serde_json::to_value(MerchantInvoice::new(&test_invoice())).unwrap();
+        assert_eq!(json["refund_address"], "u1refund");
+        assert_eq!(json["memo_code"], "CP-00000001");
+        assert!(json.get("orchard_receiver_hex").is_none());
+        assert!(json["display"].is_null());
+    }
+
+    #[test]
+    fn test_display_currency_keeps_settlement() {
+        let mut inv = test_invoice();
+        inv.display_currency = Some("USD".into());
+        inv.locale = Some("de-DE".into());
+        let json = serde_json::to_value(PublicInvoice::new(&inv)).unwrap();
+        assert_eq!(json["display"]["formatted"], "11,00\u{a0}$");
+        assert_eq!(json["currency"], "EUR");
+        assert_eq!(json["price_zatoshis"], 25_000_000);
+    }
+
+    #[test]
+    fn test_remaining_amount() {
+        let mut inv = test_invoice();
+        inv.received_zatoshis = 10_000_000;
+        let json = serde_json::to_value(PublicInvoice::new(&inv)).unwrap();
+        assert_eq!(json["remaining_zatoshis"], 15_000_000);
+        assert!(json["confirmations"].is_null());
+
+        inv.received_zatoshis = 30_000_000;
+        assert_eq!(PublicInvoice::new(&inv).remaining_zatoshis, 0);
+    }
+
+    #[test]
+    fn test_amounts_only_view_hides_the_order() {
+        let json = serde_json::to_value(InvoiceAmounts::new(&test_invoice(), Some(2))).unwrap();
+        let keys: Vec<_> = json.as_object().unwrap().keys().map(String::as_str).collect();
+        assert_eq!(keys.len(), 8, "{:?}", keys);
+        for key in ["id", "product_name", "merchant_name", "payment_address", "zcash_uri", "detected_txid", "price_eur"] {
+            assert!(json.get(key).is_none(), "{} leaked", key);
+        }
+        assert_eq!(json["remaining_zatoshis"], 25_000_000);
+
+        assert_eq!(MemoLookup::parse(MemoLookup::AmountsOnly.as_str()), MemoLookup::AmountsOnly);
+        assert_eq!(MemoLookup::parse("unknown"), MemoLookup::Full);
+    }
+
+    #[test]
+    fn test_shortfall_matched_to_wallet_fee() {
+        let mut inv = test_invoice();
+        inv.received_zatoshis = inv.price_zatoshis - 15_000;
+        let payment = |fee_zatoshis| InvoicePayment {
+            txid: "ab".repeat(32),
+            amount_zatoshis: inv.received_zatoshis,
+            block_height: None,
+            seen_at: "2025-01-01T00:00:00Z".into(),
+            fee_zatoshis,
+            tx_outputs: Some(2),
+        };
+        let view = |fee| MerchantInvoice::new(&inv).with_payments(vec![payment(fee)]).shortfall_is_fee;
+        assert_eq!(view(Some(15_000)), Some(true));
+        assert_eq!(view(Some(10_000)), Some(false));
+        assert_eq!(view(None), None);
+    }
+
+    #[test]
+    fn test_json_ld_describes_the_payment_request() {
+        let inv = test_invoice();
+        let ld = PublicInvoice::new(&inv).json_ld("https://pay.example.com/pay/inv-1");
+        assert_eq!((ld["@context"].as_str(), ld["@type"].as_str()), (Some("https://schema.org"), Some("Invoice")));
+        assert_eq!(ld["paymentStatus"], "https://schema.org/PaymentDue");
+        assert_eq!(ld["confirmationNumber"], "CP-00000001");
+        assert_eq!(ld["totalPaymentDue"]["value"], "0.25000000");
+        assert_eq!(ld["potentialAction"]["target"], inv.zcash_uri.as_str());
+        assert_eq!(ld["referencesOrder"]["acceptedOffer"]["priceCurrency"], "EUR");
+    }
+}
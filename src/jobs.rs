@@ -0,0 +1,230 @@
+//! Background jobs: named tasks run on an interval with jitter. Each run is its own task,
+//! so a panic is caught and recorded and the job simply runs again on its next turn. The
+//! last run of every job is kept for `GET /api/admin/jobs` and the health check.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// Intervals are stretched or shortened at random by up to this share, so jobs started
+/// together do not keep hitting the database and CipherScan at the same moment.
+const DEFAULT_JITTER: f64 = 0.1;
+
+/// A job counts as stale once it has gone this many intervals without finishing a run.
+const STALE_AFTER_INTERVALS: i64 = 3;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub name: String,
+    pub interval_secs: u64,
+    pub running: bool,
+    pub runs: u64,
+    pub failures: u64,
+    pub panics: u64,
+    pub last_started_at: Option<DateTime<Utc>>,
+    pub last_finished_at: Option<DateTime<Utc>>,
+    pub last_duration_ms: Option<u64>,
+    /// Why the last run failed; None once a run succeeds.
+    pub last_error: Option<String>,
+}
+
+impl JobStatus {
+    fn new(name: &str, every: Duration) -> Self {
+        Self {
+            name: name.to_string(),
+            interval_secs: every.as_secs(),
+            running: false,
+            runs: 0,
+            failures: 0,
+            panics: 0,
+            last_started_at: None,
+            last_finished_at: None,
+            last_duration_ms: None,
+            last_error: None,
+        }
+    }
+
+    /// Whether the job's last run failed, or it has not finished one in a while.
+    pub fn unhealthy(&self, now: DateTime<Utc>) -> bool {
+        let limit = (self.interval_secs as i64).max(1) * STALE_AFTER_INTERVALS;
+        let since = self.last_finished_at.or(self.last_started_at);
+        self.last_error.is_some() || since.is_some_and(|at| (now - at).num_seconds() > limit)
+    }
+}
+
+static REGISTRY: LazyLock<Mutex<BTreeMap<String, JobStatus>>> = LazyLock::new(Default::default);
+
+fn update(name: &str, f: impl FnOnce(&mut JobStatus)) {
+    if let Ok(mut jobs) = REGISTRY.lock() {
+        if let Some(status) = jobs.get_mut(name) {
+            f(status);
+        }
+    }
+}
+
+/// Every registered job, by name.
+pub fn statuses() -> Vec<JobStatus> {
+    REGISTRY.lock().map(|jobs| jobs.values().cloned().collect()).unwrap_or_default()
+}
+
+/// Names of the jobs whose last run failed or that have stopped finishing runs.
+pub fn unhealthy() -> Vec<String> {
+    let now = Utc::now();
+    statuses().into_iter().filter(|s| s.unhealthy(now)).map(|s| s.name).collect()
+}
+
+/// `every`, moved by a random amount of up to `jitter` of it either way.
+fn jittered(every: Duration, jitter: f64) -> Duration {
+    let factor = 1.0 + jitter * (rand::random::<f64>() * 2.0 - 1.0);
+    every.mul_f64(factor)
+}
+
+pub struct Job {
+    name: String,
+    every: Duration,
+    jitter: f64,
+    delay_first: bool,
+    log_errors: bool,
+}
+
+impl Job {
+    pub fn new(name: impl Into<String>, every: Duration) -> Self {
+        Self { name: name.into(), every, jitter: DEFAULT_JITTER, delay_first: false, log_errors: true }
+    }
+
+    /// Vary the interval by up to this share of it either way instead of 10%.
+    pub fn jitter(mut self, share: f64) -> Self {
+        self.jitter = share.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Wait one interval before the first run instead of running at startup.
+    pub fn delay_first(mut self) -> Self {
+        self.delay_first = true;
+        self
+    }
+
+    /// The job logs its own errors (at the level they deserve); record failed runs
+    /// without logging them a second time.
+    pub fn logs_own_errors(mut self) -> Self {
+        self.log_errors = false;
+        self
+    }
+
+    /// Register the job and run `run` on its interval, one run at a time, for as long as the
+    /// process lives. The interval is counted from the end of each run. Errors are logged
+    /// (unless `logs_own_errors`) and recorded.
+    pub fn spawn<F, Fut>(self, mut run: F)
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let Job { name, every, jitter, delay_first, log_errors } = self;
+        if let Ok(mut jobs) = REGISTRY.lock() {
+            jobs.insert(name.clone(), JobStatus::new(&name, every));
+        }
+
+        tokio::spawn(async move {
+            if delay_first {
+                tokio::time::sleep(jittered(every, jitter)).await;
+            }
+            loop {
+                let started = Instant::now();
+                update(&name, |s| {
+                    s.running = true;
+                    s.last_started_at = Some(Utc::now());
+                });
+
+                let outcome = tokio::spawn(run()).await;
+                let panicked = outcome.as_ref().is_err_and(|e| e.is_panic());
+                let error = match outcome {
+                    Ok(Ok(())) => None,
+                    Ok(Err(e)) => {
+                        if log_errors {
+                            tracing::error!(job = %name, error = %format!("{:#}", e), "Job failed");
+                        }
+                        Some(format!("{:#}", e))
+                    }
+                    Err(e) => {
+                        tracing::error!(job = %name, error = %e, "Job panicked, running it again next interval");
+                        Some(if e.is_panic() { "panicked".to_string() } else { e.to_string() })
+                    }
+                };
+
+                update(&name, |s| {
+                    s.running = false;
+                    s.runs += 1;
+                    s.failures += error.is_some() as u64;
+                    s.panics += panicked as u64;
+                    s.last_finished_at = Some(Utc::now());
+                    s.last_duration_ms = Some(started.elapsed().as_millis() as u64);
+                    s.last_error = error;
+                });
+
+                tokio::time::sleep(jittered(every, jitter)).await;
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jitter_stays_within_bounds() {
+        let every = Duration::from_secs(100);
+        for _ in 0..100 {
+            let d = jittered(every, DEFAULT_JITTER);
+            assert!(d >= Duration::from_secs(90) && d <= Duration::from_secs(110), "{:?}", d);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_job_survives_panics() {
+        let calls = std::sync::Arc::new(std::sync::atomic::AtomicU32::new(0));
+        let counter = calls.clone();
+        Job::new("test.panics", Duration::from_millis(10)).spawn(move || {
+            let n = counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async move {
+                if n == 0 {
+                    panic!("first run");
+                }
+                anyhow::ensure!(n != 1, "second run");
+                Ok(())
+            }
+        });
+
+        let status = loop {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let status = statuses().into_iter().find(|s| s.name == "test.panics").unwrap();
+            if status.runs >= 3 && !status.running {
+                break status;
+            }
+        };
+        assert_eq!((status.panics, status.failures), (1, 2));
+        assert_eq!(status.last_error, None);
+        assert!(!status.unhealthy(Utc::now()));
+    }
+
+    #[tokio::test]
+    async fn test_failures_logged_by_the_job_still_count() {
+        Job::new("test.logs_own_errors", Duration::from_secs(60)).logs_own_errors().spawn(|| async {
+            anyhow::bail!("CipherScan unavailable")
+        });
+
+        let status = loop {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            let status = statuses().into_iter().find(|s| s.name == "test.logs_own_errors").unwrap();
+            if status.runs >= 1 {
+                break status;
+            }
+        };
+        assert_eq!(status.last_error.as_deref(), Some("CipherScan unavailable"));
+        assert!(unhealthy().contains(&"test.logs_own_errors".to_string()));
+    }
+}
@@ -0,0 +1,303 @@
+//! Where the data key comes from. Secrets at rest (UFVKs, webhook secrets, buyer data) are
+//! encrypted with one AES-256 key, `ENCRYPTION_KEY`. Keeping that key in the environment
+//! is fine for development; serious deployments keep it wrapped and only unwrap it at
+//! startup: from a mounted file, from an age-encrypted keyfile, or through Vault's transit
+//! engine or AWS KMS, so the plaintext key never sits in the environment or on disk.
+
+use std::env;
+use std::fmt;
+
+use base64::Engine;
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+
+/// `Debug` leaves out the Vault token and the AWS secrets, since `Config` is logged.
+#[derive(Clone)]
+pub enum KeyProvider {
+    /// `ENCRYPTION_KEY` itself.
+    Env,
+    /// A file holding the hex key, such as a mounted secret.
+    File { path: String },
+    /// An age file holding the hex key, decrypted with an X25519 identity file.
+    Age { path: String, identity_path: String },
+    /// A key wrapped by Vault's transit engine (`vault:v1:...`).
+    Vault { addr: String, token: String, mount: String, key_name: String, wrapped: String },
+    /// A key wrapped by AWS KMS (base64 `CiphertextBlob`).
+    AwsKms { endpoint: String, region: String, access_key_id: String, secret_access_key: String, session_token: Option<String>, wrapped: String },
+}
+
+impl fmt::Debug for KeyProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        const REDACTED: &str = "[redacted]";
+        match self {
+            Self::Env => f.write_str("Env"),
+            Self::File { path } => f.debug_struct("File").field("path", path).finish(),
+            Self::Age { path, identity_path } => {
+                f.debug_struct("Age").field("path", path).field("identity_path", identity_path).finish()
+            }
+            Self::Vault { addr, mount, key_name, wrapped, .. } => f
+                .debug_struct("Vault")
+                .field("addr", addr)
+                .field("token", &REDACTED)
+                .field("mount", mount)
+                .field("key_name", key_name)
+                .field("wrapped", wrapped)
+                .finish(),
+            Self::AwsKms { endpoint, region, access_key_id, session_token, wrapped, .. } => f
+                .debug_struct("AwsKms")
+                .field("endpoint", endpoint)
+                .field("region", region)
+                .field("access_key_id", access_key_id)
+                .field("secret_access_key", &REDACTED)
+                .field("session_token", &session_token.as_ref().map(|_| REDACTED))
+                .field("wrapped", wrapped)
+                .finish(),
+        }
+    }
+}
+
+fn required(name: &str, provider: &str) -> anyhow::Result<String> {
+    env::var(name)
+        .ok()
+        .filter(|s| !s.is_empty())
+        .ok_or_else(|| anyhow::anyhow!("ENCRYPTION_KEY_PROVIDER={} needs {}", provider, name))
+}
+
+impl KeyProvider {
+    pub fn from_env() -> anyhow::Result<Self> {
+        let provider = env::var("ENCRYPTION_KEY_PROVIDER").unwrap_or_else(|_| "env".into());
+        Ok(match provider.as_str() {
+            "env" | "" => Self::Env,
+            "file" => Self::File { path: required("ENCRYPTION_KEY_FILE", &provider)? },
+            "age" => Self::Age {
+                path: required("ENCRYPTION_KEY_FILE", &provider)?,
+                identity_path: required("ENCRYPTION_KEY_AGE_IDENTITY", &provider)?,
+            },
+            "vault" => Self::Vault {
+                addr: required("VAULT_ADDR", &provider)?.trim_end_matches('/').to_string(),
+                token: required("VAULT_TOKEN", &provider)?,
+                mount: env::var("VAULT_TRANSIT_MOUNT").unwrap_or_else(|_| "transit".into()),
+                key_name: required("VAULT_TRANSIT_KEY", &provider)?,
+                wrapped: required("ENCRYPTION_KEY_WRAPPED", &provider)?,
+            },
+            "aws-kms" => {
+                let region = required("AWS_REGION", &provider)?;
+                Self::AwsKms {
+                    endpoint: env::var("AWS_KMS_ENDPOINT")
+                        .ok()
+                        .filter(|s| !s.is_empty())
+                        .unwrap_or_else(|| format!("https://kms.{}.amazonaws.com", region))
+                        .trim_end_matches('/')
+                        .to_string(),
+                    region,
+                    access_key_id: required("AWS_ACCESS_KEY_ID", &provider)?,
+                    secret_access_key: required("AWS_SECRET_ACCESS_KEY", &provider)?,
+                    session_token: env::var("AWS_SESSION_TOKEN").ok().filter(|s| !s.is_empty()),
+                    wrapped: required("ENCRYPTION_KEY_WRAPPED", &provider)?,
+                }
+            }
+            other => anyhow::bail!(
+                "Invalid ENCRYPTION_KEY_PROVIDER '{}': expected env, file, age, vault or aws-kms",
+                other
+            ),
+        })
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Env => "env",
+            Self::File { .. } => "file",
+            Self::Age { .. } => "age",
+            Self::Vault { .. } => "vault",
+            Self::AwsKms { .. } => "aws-kms",
+        }
+    }
+
+    /// The unwrapped key material: hex text for the file-based providers, whatever the
+    /// KMS was asked to encrypt for the others. `crypto::load_data_key` normalizes it.
+    pub async fn unwrap_data_key(&self, http: &reqwest::Client) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Self::Env => Ok(env::var("ENCRYPTION_KEY").unwrap_or_default().into_bytes()),
+            Self::File { path } => Ok(tokio::fs::read(path).await?),
+            Self::Age { path, identity_path } => {
+                let identity = read_age_identity(&tokio::fs::read_to_string(identity_path).await?)?;
+                let ciphertext = tokio::fs::read(path).await?;
+                age::decrypt(&identity, &ciphertext)
+                    .map_err(|e| anyhow::anyhow!("Failed to decrypt {}: {}", path, e))
+            }
+            Self::Vault { addr, token, mount, key_name, wrapped } => {
+                let resp = http
+                    .post(format!("{}/v1/{}/decrypt/{}", addr, mount, key_name))
+                    .header("X-Vault-Token", token)
+                    .json(&serde_json::json!({ "ciphertext": wrapped }))
+                    .send()
+                    .await?;
+                if !resp.status().is_success() {
+                    anyhow::bail!("Vault transit decrypt failed: HTTP {}", resp.status());
+                }
+                let body: serde_json::Value = resp.json().await?;
+                decode_base64(body["data"]["plaintext"].as_str(), "Vault")
+            }
+            Self::AwsKms { .. } => {
+                let body: serde_json::Value = self.kms_decrypt(http).await?.json().await?;
+                decode_base64(body["Plaintext"].as_str(), "AWS KMS")
+            }
+        }
+    }
+
+    /// Forget the Vault token and AWS secrets once the data key is unwrapped; nothing
+    /// needs them again for the life of the process.
+    pub fn drop_credentials(&mut self) {
+        match self {
+            Self::Vault { token, .. } => *token = String::new(),
+            Self::AwsKms { secret_access_key, session_token, .. } => {
+                *secret_access_key = String::new();
+                *session_token = None;
+            }
+            Self::Env | Self::File { .. } | Self::Age { .. } => {}
+        }
+    }
+
+    /// `TrentService.Decrypt`, signed with AWS Signature V4.
+    async fn kms_decrypt(&self, http: &reqwest::Client) -> anyhow::Result<reqwest::Response> {
+        let Self::AwsKms { endpoint, region, access_key_id, secret_access_key, session_token, wrapped } = self else {
+            unreachable!("kms_decrypt on a non-KMS provider");
+        };
+
+        let url = url::Url::parse(endpoint)?;
+        let host = match (url.host_str(), url.port()) {
+            (Some(h), Some(p)) => format!("{}:{}", h, p),
+            (Some(h), None) => h.to_string(),
+            _ => anyhow::bail!("AWS_KMS_ENDPOINT has no host"),
+        };
+        let body = serde_json::json!({ "CiphertextBlob": wrapped }).to_string();
+
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex::encode(Sha256::digest(body.as_bytes()));
+
+        let mut headers = vec![
+            ("content-type", "application/x-amz-json-1.1".to_string()),
+            ("host", host),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        headers.push(("x-amz-target", "TrentService.Decrypt".to_string()));
+        let canonical_headers: String = headers.iter().map(|(k, v)| format!("{}:{}\n", k, v)).collect();
+        let signed_headers = headers.iter().map(|(k, _)| *k).collect::<Vec<_>>().join(";");
+
+        let canonical_request = format!("POST\n/\n\n{}\n{}\n{}", canonical_headers, signed_headers, payload_hash);
+        let scope = format!("{}/{}/kms/aws4_request", date, region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date, scope, hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let mut signing_key = crate::media::hmac(format!("AWS4{}", secret_access_key).as_bytes(), date.as_bytes());
+        for part in [region.as_str(), "kms", "aws4_request"] {
+            signing_key = crate::media::hmac(&signing_key, part.as_bytes());
+        }
+        let signature = hex::encode(crate::media::hmac(&signing_key, string_to_sign.as_bytes()));
+
+        let mut req = http.post(url).header(
+            "Authorization",
+            format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                access_key_id, scope, signed_headers, signature
+            ),
+        );
+        for (name, value) in headers.iter().filter(|(k, _)| *k != "host") {
+            req = req.header(*name, value);
+        }
+        let resp = req.body(body).send().await?;
+        if !resp.status().is_success() {
+            anyhow::bail!("AWS KMS decrypt failed: HTTP {}", resp.status());
+        }
+        Ok(resp)
+    }
+}
+
+/// The first X25519 secret key in an `age-keygen` identity file.
+fn read_age_identity(contents: &str) -> anyhow::Result<age::x25519::Identity> {
+    contents
+        .lines()
+        .map(str::trim)
+        .find(|line| line.starts_with("AGE-SECRET-KEY-"))
+        .ok_or_else(|| anyhow::anyhow!("ENCRYPTION_KEY_AGE_IDENTITY holds no AGE-SECRET-KEY line"))?
+        .parse()
+        .map_err(|e| anyhow::anyhow!("Invalid age identity: {}", e))
+}
+
+fn decode_base64(value: Option<&str>, source: &str) -> anyhow::Result<Vec<u8>> {
+    let value = value.ok_or_else(|| anyhow::anyhow!("{} returned no plaintext", source))?;
+    base64::engine::general_purpose::STANDARD
+        .decode(value)
+        .map_err(|_| anyhow::anyhow!("{} returned invalid base64 plaintext", source))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age::secrecy::ExposeSecret;
+
+    #[tokio::test]
+    async fn test_age_keyfile_unwraps() {
+        let dir = std::env::temp_dir().join(format!("cipherpay-keys-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let identity = age::x25519::Identity::generate();
+        let key = "c".repeat(64);
+        let wrapped = age::encrypt_and_armor(&identity.to_public(), key.as_bytes()).unwrap();
+        std::fs::write(dir.join("key.age"), wrapped).unwrap();
+        std::fs::write(
+            dir.join("identity.txt"),
+            format!("# created: now\n# public key: {}\n{}\n", identity.to_public(), identity.to_string().expose_secret()),
+        )
+        .unwrap();
+
+        let provider = KeyProvider::Age {
+            path: dir.join("key.age").to_string_lossy().into_owned(),
+            identity_path: dir.join("identity.txt").to_string_lossy().into_owned(),
+        };
+        let unwrapped = provider.unwrap_data_key(&reqwest::Client::new()).await.unwrap();
+        assert_eq!(unwrapped, key.as_bytes());
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_debug_and_dropped_credentials_leave_out_secrets() {
+        let mut provider = KeyProvider::AwsKms {
+            endpoint: "https://kms.eu-west-1.amazonaws.com".into(),
+            region: "eu-west-1".into(),
+            access_key_id: "AKIDEXAMPLE".into(),
+            secret_access_key: "kms-secret".into(),
+            session_token: Some("kms-session".into()),
+            wrapped: "d3JhcHBlZA==".into(),
+        };
+        let debug = format!("{:?}", provider);
+        assert!(debug.contains("AKIDEXAMPLE"), "{}", debug);
+        assert!(!debug.contains("kms-secret") && !debug.contains("kms-session"), "{}", debug);
+
+        provider.drop_credentials();
+        let KeyProvider::AwsKms { secret_access_key, session_token, .. } = &provider else { unreachable!() };
+        assert!(secret_access_key.is_empty() && session_token.is_none());
+
+        let mut vault = KeyProvider::Vault {
+            addr: "https://vault:8200".into(),
+            token: "hvs.vault-token".into(),
+            mount: "transit".into(),
+            key_name: "cipherpay".into(),
+            wrapped: "vault:v1:abc".into(),
+        };
+        assert!(!format!("{:?}", vault).contains("hvs.vault-token"));
+        vault.drop_credentials();
+        assert!(matches!(&vault, KeyProvider::Vault { token, .. } if token.is_empty()));
+    }
+
+    #[test]
+    fn test_read_age_identity_needs_a_secret_key() {
+        assert!(read_age_identity("# public key: age1abc\n").is_err());
+    }
+}
@@ -1,20 +1,35 @@
+mod abuse;
 mod addresses;
+mod alerts;
 mod api;
+mod backup;
 mod billing;
+mod client_ip;
 mod config;
+mod cors;
 mod crypto;
 mod db;
 mod email;
+mod grpc;
 mod invoices;
+mod jobs;
+mod keys;
+mod media;
 mod merchants;
+mod notifiers;
 mod products;
+mod request_log;
 mod scanner;
+mod services;
+mod storefront;
 mod validation;
 mod webhooks;
 
-use actix_cors::Cors;
-use actix_governor::{Governor, GovernorConfigBuilder};
+use std::time::Duration;
+
+use actix_governor::Governor;
 use actix_web::{web, App, HttpServer, middleware};
+use anyhow::Context;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -27,110 +42,166 @@ async fn main() -> anyhow::Result<()> {
         )
         .init();
 
-    let config = config::Config::from_env()?;
-    let pool = db::create_pool(&config.database_url).await?;
+    let mut config = config::Config::from_env()?;
+    config.encryption_key = crypto::load_data_key(&mut config.key_provider, &reqwest::Client::new())
+        .await
+        .context("Failed to load the data key")?;
+    tracing::info!(provider = config.key_provider.as_str(), "Data key loaded");
+    if config.allow_private_webhooks {
+        if config.is_testnet() {
+            tracing::warn!("ALLOW_PRIVATE_WEBHOOKS is set: webhooks may target private addresses");
+            validation::allow_private_hosts();
+        } else {
+            tracing::warn!("ALLOW_PRIVATE_WEBHOOKS is ignored on mainnet");
+        }
+    }
+    let pool = db::create_pool(&config.database_url, config.db_api_pool_size).await?;
+    // Background tasks get their own connections, so a long rescan cannot starve requests.
+    let worker_pool = db::open_pool(&config.database_url, config.db_worker_pool_size).await?;
+    let read_pool = match config.database_read_url.as_deref() {
+        Some(url) => {
+            tracing::info!("Serving read-heavy endpoints from the read replica");
+            db::ReadPool::replica(url, config.db_read_pool_size).await?
+        }
+        None => db::ReadPool::primary(&pool),
+    };
     db::migrate_encrypt_ufvks(&pool, &config.encryption_key).await?;
     db::migrate_encrypt_webhook_secrets(&pool, &config.encryption_key).await?;
+    db::migrate_networks(&pool, &config.network).await?;
     let http_client = reqwest::Client::builder()
         .timeout(std::time::Duration::from_secs(30))
         .build()?;
 
-    let price_service = invoices::pricing::PriceService::new(
-        &config.coingecko_api_url,
-        config.price_cache_secs,
-    );
+    let cipherscans = scanner::cipherscan::CipherScans::new(&config)?;
+
+    let price_service = invoices::pricing::PriceService::new(&config, pool.clone())?;
+    if let Some((zec_eur, zec_usd)) = config.fixed_rates() {
+        price_service.set_manual(zec_eur, zec_usd).await?;
+    }
+    price_service.start();
 
     tracing::info!(
         network = %config.network,
+        extra_networks = config.networks.len() - 1,
         api = %format!("{}:{}", config.api_host, config.api_port),
         cipherscan = %config.cipherscan_api_url,
         "CipherPay starting"
     );
 
-    let scanner_config = config.clone();
-    let scanner_pool = pool.clone();
-    let scanner_http = http_client.clone();
-    tokio::spawn(async move {
-        scanner::run(scanner_config, scanner_pool, scanner_http).await;
-    });
+    if config.smtp_configured() {
+        let smtp_config = config.clone();
+        tokio::spawn(async move {
+            match email::check_connection(&smtp_config).await {
+                Ok(()) => tracing::info!(
+                    host = ?smtp_config.smtp_host,
+                    tls = smtp_config.smtp_tls.as_str(),
+                    "SMTP connection OK"
+                ),
+                Err(e) => tracing::warn!(error = %format!("{:#}", e), "SMTP connection check failed"),
+            }
+        });
+    }
+
+    for (network, cipherscan) in cipherscans.iter() {
+        scanner::start(
+            config.clone(),
+            network.to_string(),
+            worker_pool.clone(),
+            http_client.clone(),
+            cipherscan.clone(),
+            price_service.clone(),
+        ).await;
+    }
 
-    let retry_pool = pool.clone();
+    let retry_pool = worker_pool.clone();
     let retry_http = http_client.clone();
     let retry_enc_key = config.encryption_key.clone();
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
-        loop {
-            interval.tick().await;
-            let _ = webhooks::retry_failed(&retry_pool, &retry_http, &retry_enc_key).await;
-        }
+    jobs::Job::new("webhook_retries", Duration::from_secs(60)).spawn(move || {
+        let (pool, http, enc_key) = (retry_pool.clone(), retry_http.clone(), retry_enc_key.clone());
+        async move { webhooks::retry_failed(&pool, &http, &enc_key).await }
     });
 
-    let purge_pool = pool.clone();
+    alerts::start(config.clone(), worker_pool.clone(), http_client.clone(), cipherscans.clone(), price_service.clone());
+
+    let email_pool = worker_pool.clone();
+    let email_config = config.clone();
+    jobs::Job::new("email_queue", Duration::from_secs(30)).spawn(move || {
+        let (pool, config) = (email_pool.clone(), email_config.clone());
+        async move { email::drain_queue(&pool, &config).await }
+    });
+
+    let purge_pool = worker_pool.clone();
     let purge_days = config.data_purge_days;
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(std::time::Duration::from_secs(3600));
-        loop {
-            interval.tick().await;
-            if let Err(e) = db::run_data_purge(&purge_pool, purge_days).await {
-                tracing::error!(error = %e, "Data purge error");
-            }
+    let purge_policy = std::sync::Arc::new(invoices::purge::PurgePolicy::from_config(&config)?);
+    jobs::Job::new("data_purge", Duration::from_secs(3600)).spawn(move || {
+        let (pool, policy) = (purge_pool.clone(), purge_policy.clone());
+        async move {
+            let data = db::run_data_purge(&pool, purge_days).await.context("data purge");
+            let invoices = invoices::purge::run(&pool, &policy).await.context("invoice purge");
+            data.and(invoices)
         }
     });
 
     if config.fee_enabled() {
-        let billing_pool = pool.clone();
+        tracing::info!(
+            fee_rate = config.fee_rate,
+            fee_address = ?config.fee_address,
+            "Billing system enabled"
+        );
+        let billing_pool = worker_pool.clone();
         let billing_config = config.clone();
         let billing_prices = price_service.clone();
-        tokio::spawn(async move {
-            let mut interval = tokio::time::interval(std::time::Duration::from_secs(3600));
-            tracing::info!(
-                fee_rate = billing_config.fee_rate,
-                fee_address = ?billing_config.fee_address,
-                "Billing system enabled"
-            );
-            loop {
-                interval.tick().await;
-                let (zec_eur, zec_usd) = match billing_prices.get_rates().await {
+        jobs::Job::new("billing", Duration::from_secs(3600)).spawn(move || {
+            let (pool, config, prices) = (billing_pool.clone(), billing_config.clone(), billing_prices.clone());
+            async move {
+                let (zec_eur, zec_usd) = match prices.get_rates().await {
                     Ok(r) => (r.zec_eur, r.zec_usd),
                     Err(_) => (0.0, 0.0),
                 };
-                if let Err(e) = billing::process_billing_cycles(&billing_pool, &billing_config, zec_eur, zec_usd).await {
-                    tracing::error!(error = %e, "Billing cycle processing error");
-                }
+                let reconciled = billing::reconcile_cycles(&pool).await.context("billing reconciliation");
+                let processed = billing::process_billing_cycles(&pool, &config, zec_eur, zec_usd)
+                    .await
+                    .context("billing cycle processing");
+                reconciled.and(processed)
             }
         });
     }
 
     let bind_addr = format!("{}:{}", config.api_host, config.api_port);
 
-    let rate_limit = GovernorConfigBuilder::default()
-        .seconds_per_request(1)
-        .burst_size(60)
-        .finish()
-        .expect("Failed to build rate limiter");
+    if !config.trusted_proxies.is_empty() {
+        tracing::info!(proxies = ?config.trusted_proxies, "Honoring forwarding headers from trusted proxies");
+    }
 
-    HttpServer::new(move || {
-        let cors = if config.is_testnet() || config.allowed_origins.is_empty() {
-            Cors::default()
-                .allowed_origin_fn(|_origin, _req_head| true)
-                .allow_any_method()
-                .allow_any_header()
-                .supports_credentials()
-                .max_age(3600)
-        } else {
-            let mut cors = Cors::default()
-                .allow_any_method()
-                .allow_any_header()
-                .supports_credentials()
-                .max_age(3600);
-            for origin in &config.allowed_origins {
-                cors = cors.allowed_origin(origin);
+    let abuse_guard = web::Data::new(abuse::AbuseGuard::new(&config));
+    if abuse_guard.pow_required() {
+        tracing::info!(difficulty = config.pow_difficulty, "Proof of work required for checkout and lookup");
+    }
+
+    let rate_limit = client_ip::rate_limiter(config.rate_limit, &config.trusted_proxies);
+
+    let invoice_service = services::InvoiceService::new(pool.clone(), config.clone(), price_service.clone(), http_client.clone());
+    let merchant_service = services::MerchantService::new(pool.clone(), config.clone(), cipherscans.clone());
+    let billing_service = services::BillingService::new(pool.clone(), config.clone(), price_service.clone());
+    let graphql_schema = api::graphql::schema(pool.clone(), merchant_service.clone(), billing_service.clone());
+
+    if let Some(grpc_port) = config.grpc_port {
+        let grpc_addr = format!("{}:{}", config.api_host, grpc_port).parse()?;
+        let grpc_pool = pool.clone();
+        let grpc_config = config.clone();
+        let grpc_invoices = invoice_service.clone();
+        tokio::spawn(async move {
+            if let Err(e) = grpc::serve(grpc_addr, grpc_pool, grpc_config, grpc_invoices).await {
+                tracing::error!(error = %e, "gRPC server stopped");
             }
-            cors
-        };
+        });
+    }
 
+    let cors_policy = web::Data::new(cors::CorsPolicy::from_config(&config)?);
+
+    HttpServer::new(move || {
         App::new()
-            .wrap(cors)
+            .wrap(middleware::from_fn(cors::middleware))
             .wrap(Governor::new(&rate_limit))
             .wrap(middleware::DefaultHeaders::new()
                 .add(("X-Content-Type-Options", "nosniff"))
@@ -139,13 +210,23 @@ async fn main() -> anyhow::Result<()> {
                 .add(("Strict-Transport-Security", "max-age=63072000; includeSubDomains; preload"))
                 .add(("Permissions-Policy", "camera=(), microphone=(), geolocation=()"))
             )
-            .app_data(web::JsonConfig::default().limit(65_536))
+            .wrap(middleware::from_fn(request_log::middleware))
+            .app_data(web::JsonConfig::default().limit(config.json_limit_bytes))
             .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(read_pool.clone()))
             .app_data(web::Data::new(config.clone()))
             .app_data(web::Data::new(price_service.clone()))
             .app_data(web::Data::new(http_client.clone()))
-            .configure(api::configure)
+            .app_data(web::Data::new(cipherscans.clone()))
+            .app_data(web::Data::new(graphql_schema.clone()))
+            .app_data(web::Data::new(invoice_service.clone()))
+            .app_data(web::Data::new(merchant_service.clone()))
+            .app_data(web::Data::new(billing_service.clone()))
+            .app_data(abuse_guard.clone())
+            .app_data(cors_policy.clone())
+            .configure(|cfg| api::configure(cfg, &config))
             .route("/", web::get().to(serve_ui))
+            .route("/store/{merchant}", web::get().to(storefront::page))
             .service(web::resource("/widget/{filename}")
                 .route(web::get().to(serve_widget)))
     })
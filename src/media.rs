@@ -0,0 +1,177 @@
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Detect the image type from its magic bytes. The client-supplied content type is never trusted.
+/// Returns (content type, file extension).
+pub fn sniff_image(bytes: &[u8]) -> Option<(&'static str, &'static str)> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some(("image/png", "png"))
+    } else if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some(("image/jpeg", "jpg"))
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some(("image/gif", "gif"))
+    } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some(("image/webp", "webp"))
+    } else {
+        None
+    }
+}
+
+/// Content type for a stored key, derived from the extension we assigned at upload.
+pub fn content_type_for(key: &str) -> &'static str {
+    match key.rsplit('.').next() {
+        Some("png") => "image/png",
+        Some("jpg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Keys are generated server-side as `{uuid}.{ext}`; reject anything else so a
+/// request path can never escape the media directory or bucket prefix.
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= 64
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+        && !key.starts_with('.')
+        && !key.contains("..")
+}
+
+pub async fn store(config: &Config, http: &reqwest::Client, key: &str, bytes: Vec<u8>, content_type: &str) -> anyhow::Result<()> {
+    if config.media_s3_configured() {
+        let resp = s3_request(config, http, reqwest::Method::PUT, key, bytes, Some(content_type)).await?;
+        if !resp.status().is_success() {
+            anyhow::bail!("S3 upload failed: HTTP {}", resp.status());
+        }
+        return Ok(());
+    }
+
+    tokio::fs::create_dir_all(&config.media_dir).await?;
+    tokio::fs::write(std::path::Path::new(&config.media_dir).join(key), bytes).await?;
+    Ok(())
+}
+
+pub async fn load(config: &Config, http: &reqwest::Client, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+    if config.media_s3_configured() {
+        let resp = s3_request(config, http, reqwest::Method::GET, key, Vec::new(), None).await?;
+        if resp.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !resp.status().is_success() {
+            anyhow::bail!("S3 download failed: HTTP {}", resp.status());
+        }
+        return Ok(Some(resp.bytes().await?.to_vec()));
+    }
+
+    match tokio::fs::read(std::path::Path::new(&config.media_dir).join(key)).await {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub async fn delete(config: &Config, http: &reqwest::Client, key: &str) -> anyhow::Result<()> {
+    if config.media_s3_configured() {
+        let resp = s3_request(config, http, reqwest::Method::DELETE, key, Vec::new(), None).await?;
+        if !resp.status().is_success() && resp.status() != reqwest::StatusCode::NOT_FOUND {
+            anyhow::bail!("S3 delete failed: HTTP {}", resp.status());
+        }
+        return Ok(());
+    }
+
+    match tokio::fs::remove_file(std::path::Path::new(&config.media_dir).join(key)).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Path-style request to an S3-compatible endpoint, signed with AWS Signature V4.
+async fn s3_request(
+    config: &Config,
+    http: &reqwest::Client,
+    method: reqwest::Method,
+    key: &str,
+    body: Vec<u8>,
+    content_type: Option<&str>,
+) -> anyhow::Result<reqwest::Response> {
+    let endpoint = config.s3_endpoint.as_deref().unwrap_or_default().trim_end_matches('/');
+    let bucket = config.s3_bucket.as_deref().unwrap_or_default();
+    let access_key = config.s3_access_key_id.as_deref().unwrap_or_default();
+    let secret_key = config.s3_secret_access_key.as_deref().unwrap_or_default();
+    let region = &config.s3_region;
+
+    let url = url::Url::parse(&format!("{}/{}/{}", endpoint, bucket, key))?;
+    let host = match (url.host_str(), url.port()) {
+        (Some(h), Some(p)) => format!("{}:{}", h, p),
+        (Some(h), None) => h.to_string(),
+        _ => anyhow::bail!("S3_ENDPOINT has no host"),
+    };
+
+    let now = Utc::now();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    let payload_hash = hex::encode(Sha256::digest(&body));
+
+    let canonical_request = format!(
+        "{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+        method, url.path(), host, payload_hash, amz_date, payload_hash
+    );
+    let scope = format!("{}/{}/s3/aws4_request", date, region);
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date, scope, hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let mut signing_key = hmac(format!("AWS4{}", secret_key).as_bytes(), date.as_bytes());
+    for part in [region.as_str(), "s3", "aws4_request"] {
+        signing_key = hmac(&signing_key, part.as_bytes());
+    }
+    let signature = hex::encode(hmac(&signing_key, string_to_sign.as_bytes()));
+
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+        access_key, scope, signature
+    );
+
+    let mut req = http.request(method, url)
+        .header("x-amz-date", &amz_date)
+        .header("x-amz-content-sha256", &payload_hash)
+        .header("Authorization", authorization);
+    if let Some(ct) = content_type {
+        req = req.header("Content-Type", ct);
+    }
+    Ok(req.body(body).send().await?)
+}
+
+pub(crate) fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff_image() {
+        assert_eq!(sniff_image(b"\x89PNG\r\n\x1a\nrest"), Some(("image/png", "png")));
+        assert_eq!(sniff_image(&[0xFF, 0xD8, 0xFF, 0xE0]), Some(("image/jpeg", "jpg")));
+        assert_eq!(sniff_image(b"RIFF\x00\x00\x00\x00WEBPVP8 "), Some(("image/webp", "webp")));
+        assert_eq!(sniff_image(b"<svg xmlns=..."), None);
+    }
+
+    #[test]
+    fn test_is_valid_key() {
+        assert!(is_valid_key("3f1c2a9e-8d4b-4e7a-9c1d-2b5e6f7a8c90.png"));
+        assert!(!is_valid_key("../cipherpay.db"));
+        assert!(!is_valid_key("a/b.png"));
+        assert!(!is_valid_key(".env"));
+    }
+}
@@ -0,0 +1,264 @@
+//! The merchant's address book: named addresses of their own wallets. `payout` marks where
+//! they sweep funds to, `refund_source` the wallet refunds are sent from (shown on refund
+//! requests), and `settlement_source` the wallets fee settlements are paid from. Addresses
+//! are encrypted at rest like the UFVK; a hash of each keeps entries unique.
+
+use serde::Serialize;
+use sqlx::SqlitePool;
+use uuid::Uuid;
+
+use super::{hash_key, MerchantError};
+
+pub const PURPOSES: [&str; 3] = ["payout", "refund_source", "settlement_source"];
+/// Entries one merchant can keep.
+pub const MAX_ADDRESSES: i64 = 50;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BookAddress {
+    pub id: String,
+    pub label: String,
+    pub address: String,
+    pub purpose: String,
+    pub is_default: bool,
+    pub created_at: String,
+}
+
+#[derive(sqlx::FromRow)]
+struct AddressRow {
+    id: String,
+    label: String,
+    address: String,
+    purpose: String,
+    is_default: bool,
+    created_at: String,
+}
+
+const COLUMNS: &str = "id, label, address, purpose, is_default, created_at";
+
+fn seal(address: &str, encryption_key: &str) -> Result<String, MerchantError> {
+    if encryption_key.is_empty() {
+        return Ok(address.to_string());
+    }
+    crate::crypto::encrypt(address, encryption_key).map_err(MerchantError::Encryption)
+}
+
+/// Zcash addresses are never valid hex, so anything that is was sealed.
+fn open(row: AddressRow, encryption_key: &str) -> Result<BookAddress, MerchantError> {
+    let address = if encryption_key.is_empty() || hex::decode(&row.address).is_err() {
+        row.address
+    } else {
+        crate::crypto::decrypt(&row.address, encryption_key).map_err(MerchantError::Encryption)?
+    };
+    Ok(BookAddress {
+        id: row.id,
+        label: row.label,
+        address,
+        purpose: row.purpose,
+        is_default: row.is_default,
+        created_at: row.created_at,
+    })
+}
+
+/// The merchant's addresses, defaults first; only those for `purpose` when given.
+pub async fn list(
+    pool: &SqlitePool,
+    merchant_id: &str,
+    purpose: Option<&str>,
+    encryption_key: &str,
+) -> Result<Vec<BookAddress>, MerchantError> {
+    let rows = sqlx::query_as::<_, AddressRow>(&format!(
+        "SELECT {COLUMNS} FROM merchant_addresses
+         WHERE merchant_id = ? AND (? IS NULL OR purpose = ?)
+         ORDER BY purpose, is_default DESC, created_at"
+    ))
+    .bind(merchant_id)
+    .bind(purpose)
+    .bind(purpose)
+    .fetch_all(pool)
+    .await?;
+    rows.into_iter().map(|r| open(r, encryption_key)).collect()
+}
+
+pub async fn get(
+    pool: &SqlitePool,
+    merchant_id: &str,
+    id: &str,
+    encryption_key: &str,
+) -> Result<Option<BookAddress>, MerchantError> {
+    let row = sqlx::query_as::<_, AddressRow>(&format!(
+        "SELECT {COLUMNS} FROM merchant_addresses WHERE id = ? AND merchant_id = ?"
+    ))
+    .bind(id)
+    .bind(merchant_id)
+    .fetch_optional(pool)
+    .await?;
+    row.map(|r| open(r, encryption_key)).transpose()
+}
+
+/// The default address for `purpose`, if the merchant has marked one.
+pub async fn default_for(
+    pool: &SqlitePool,
+    merchant_id: &str,
+    purpose: &str,
+    encryption_key: &str,
+) -> Result<Option<BookAddress>, MerchantError> {
+    let row = sqlx::query_as::<_, AddressRow>(&format!(
+        "SELECT {COLUMNS} FROM merchant_addresses WHERE merchant_id = ? AND purpose = ? AND is_default = 1"
+    ))
+    .bind(merchant_id)
+    .bind(purpose)
+    .fetch_optional(pool)
+    .await?;
+    row.map(|r| open(r, encryption_key)).transpose()
+}
+
+/// Add an entry, already validated. Returns `None` once the merchant has `MAX_ADDRESSES`.
+/// The first address for a purpose becomes its default.
+pub async fn add(
+    pool: &SqlitePool,
+    merchant_id: &str,
+    label: &str,
+    address: &str,
+    purpose: &str,
+    is_default: bool,
+    encryption_key: &str,
+) -> Result<Option<BookAddress>, MerchantError> {
+    let mut tx = crate::db::begin_write(pool).await?;
+    let (total, for_purpose): (i64, i64) = sqlx::query_as(
+        "SELECT COUNT(*), COALESCE(SUM(purpose = ?), 0) FROM merchant_addresses WHERE merchant_id = ?"
+    )
+    .bind(purpose)
+    .bind(merchant_id)
+    .fetch_one(&mut *tx)
+    .await?;
+    if total >= MAX_ADDRESSES {
+        return Ok(None);
+    }
+
+    let is_default = is_default || for_purpose == 0;
+    if is_default {
+        sqlx::query("UPDATE merchant_addresses SET is_default = 0 WHERE merchant_id = ? AND purpose = ?")
+            .bind(merchant_id)
+            .bind(purpose)
+            .execute(&mut *tx)
+            .await?;
+    }
+    let id = Uuid::new_v4().to_string();
+    sqlx::query(
+        "INSERT INTO merchant_addresses (id, merchant_id, label, address, address_hash, purpose, is_default)
+         VALUES (?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(&id)
+    .bind(merchant_id)
+    .bind(label)
+    .bind(seal(address, encryption_key)?)
+    .bind(hash_key(address))
+    .bind(purpose)
+    .bind(is_default)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    tracing::info!(merchant_id, address_id = %id, purpose, "Address book entry added");
+    get(pool, merchant_id, &id, encryption_key).await
+}
+
+/// Rename an entry and/or make it the default for its purpose. `None` if it is not the
+/// merchant's.
+pub async fn update(
+    pool: &SqlitePool,
+    merchant_id: &str,
+    id: &str,
+    label: Option<&str>,
+    make_default: bool,
+    encryption_key: &str,
+) -> Result<Option<BookAddress>, MerchantError> {
+    let mut tx = crate::db::begin_write(pool).await?;
+    let purpose: Option<String> = sqlx::query_scalar(
+        "SELECT purpose FROM merchant_addresses WHERE id = ? AND merchant_id = ?"
+    )
+    .bind(id)
+    .bind(merchant_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some(purpose) = purpose else {
+        return Ok(None);
+    };
+
+    if let Some(label) = label {
+        sqlx::query("UPDATE merchant_addresses SET label = ? WHERE id = ?")
+            .bind(label)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    if make_default {
+        sqlx::query(
+            "UPDATE merchant_addresses SET is_default = (id = ?) WHERE merchant_id = ? AND purpose = ?"
+        )
+        .bind(id)
+        .bind(merchant_id)
+        .bind(&purpose)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    get(pool, merchant_id, id, encryption_key).await
+}
+
+/// Remove an entry. False if it is not the merchant's.
+pub async fn remove(pool: &SqlitePool, merchant_id: &str, id: &str) -> Result<bool, MerchantError> {
+    let removed = sqlx::query("DELETE FROM merchant_addresses WHERE id = ? AND merchant_id = ?")
+        .bind(id)
+        .bind(merchant_id)
+        .execute(pool)
+        .await?
+        .rows_affected() > 0;
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merchants::{create_merchant, CreateMerchantRequest};
+
+    const KEY: &str = "0101010101010101010101010101010101010101010101010101010101010101";
+
+    #[tokio::test]
+    async fn test_defaults_and_encryption() {
+        let pool = crate::db::test_pool().await;
+        let merchant = create_merchant(&pool, &CreateMerchantRequest {
+            name: None,
+            ufvk: crate::scanner::fixtures::test_ufvk(1),
+            webhook_url: None,
+            email: None,
+            verify_blocks: None,
+        }, KEY).await.unwrap();
+        let id = merchant.merchant_id.as_str();
+
+        let first = add(&pool, id, "Cold", "tmFirst", "refund_source", false, KEY).await.unwrap().unwrap();
+        assert!(first.is_default, "first address for a purpose is its default");
+        let second = add(&pool, id, "Hot", "tmSecond", "refund_source", true, KEY).await.unwrap().unwrap();
+        assert!(second.is_default);
+        assert!(add(&pool, id, "Again", "tmSecond", "refund_source", false, KEY).await.is_err());
+        add(&pool, id, "Payout", "tmSecond", "payout", false, KEY).await.unwrap().unwrap();
+
+        assert_eq!(default_for(&pool, id, "refund_source", KEY).await.unwrap().unwrap().id, second.id);
+        update(&pool, id, &first.id, Some("Cold storage"), true, KEY).await.unwrap().unwrap();
+        let refund_sources = list(&pool, id, Some("refund_source"), KEY).await.unwrap();
+        assert_eq!(refund_sources.len(), 2);
+        assert_eq!(refund_sources[0].label, "Cold storage");
+        assert!(refund_sources[0].is_default && !refund_sources[1].is_default);
+
+        let stored: String = sqlx::query_scalar("SELECT address FROM merchant_addresses WHERE id = ?")
+            .bind(&first.id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_ne!(stored, "tmFirst");
+
+        assert!(remove(&pool, id, &first.id).await.unwrap());
+        assert!(!remove(&pool, "other", &second.id).await.unwrap());
+        assert_eq!(list(&pool, id, None, KEY).await.unwrap().len(), 2);
+    }
+}
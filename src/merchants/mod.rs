@@ -1,15 +1,48 @@
+pub mod address_book;
+
+use std::collections::HashSet;
+
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use sqlx::SqlitePool;
 use uuid::Uuid;
 
+#[derive(Debug, thiserror::Error)]
+pub enum MerchantError {
+    #[error("Merchant not found")]
+    NotFound,
+    #[error("Invalid UFVK: could not derive address: {0}")]
+    InvalidUfvk(#[source] anyhow::Error),
+    #[error("This slug is already taken")]
+    SlugTaken,
+    #[error("A merchant account already uses this viewing key. Sign in with its dashboard token, or recover it with its recovery email")]
+    UfvkInUse,
+    #[error("No unused payment addresses are left for this wallet")]
+    DiversifierExhausted,
+    #[error("Failed to encrypt merchant secret: {0}")]
+    Encryption(#[source] anyhow::Error),
+    #[error("Database error: {0}")]
+    Database(#[source] sqlx::Error),
+}
+
+impl From<sqlx::Error> for MerchantError {
+    fn from(e: sqlx::Error) -> Self {
+        match e {
+            sqlx::Error::RowNotFound => MerchantError::NotFound,
+            e => MerchantError::Database(e),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Merchant {
     pub id: String,
     pub name: String,
     #[serde(skip_serializing)]
+    #[allow(dead_code)]
     pub api_key_hash: String,
     #[serde(skip_serializing)]
+    #[allow(dead_code)]
     pub dashboard_token_hash: String,
     #[serde(skip_serializing)]
     pub ufvk: String,
@@ -20,6 +53,8 @@ pub struct Merchant {
     pub created_at: String,
     #[serde(skip_serializing)]
     pub diversifier_index: i64,
+    /// Network of the UFVK (`mainnet` or `testnet`); invoices and payments follow it.
+    pub network: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -28,6 +63,8 @@ pub struct CreateMerchantRequest {
     pub ufvk: String,
     pub webhook_url: Option<String>,
     pub email: Option<String>,
+    /// Shadow-scan this many recent blocks with the UFVK after registering.
+    pub verify_blocks: Option<u64>,
 }
 
 #[derive(Debug, Serialize)]
@@ -36,6 +73,7 @@ pub struct CreateMerchantResponse {
     pub api_key: String,
     pub dashboard_token: String,
     pub webhook_secret: String,
+    pub network: String,
 }
 
 fn generate_api_key() -> String {
@@ -63,10 +101,11 @@ pub async fn create_merchant(
     pool: &SqlitePool,
     req: &CreateMerchantRequest,
     encryption_key: &str,
-) -> anyhow::Result<CreateMerchantResponse> {
+) -> Result<CreateMerchantResponse, MerchantError> {
     let derived = crate::addresses::derive_invoice_address(&req.ufvk, 0)
-        .map_err(|e| anyhow::anyhow!("Invalid UFVK — could not derive address: {}", e))?;
+        .map_err(MerchantError::InvalidUfvk)?;
     let payment_address = derived.ua_string;
+    let network = crate::addresses::ufvk_network(&req.ufvk).map_err(MerchantError::InvalidUfvk)?;
 
     let id = Uuid::new_v4().to_string();
     let api_key = generate_api_key();
@@ -80,18 +119,33 @@ pub async fn create_merchant(
     let stored_ufvk = if encryption_key.is_empty() {
         req.ufvk.clone()
     } else {
-        crate::crypto::encrypt(&req.ufvk, encryption_key)?
+        crate::crypto::encrypt(&req.ufvk, encryption_key).map_err(MerchantError::Encryption)?
     };
 
     let stored_webhook_secret = if encryption_key.is_empty() {
         webhook_secret.clone()
     } else {
-        crate::crypto::encrypt(&webhook_secret, encryption_key)?
+        crate::crypto::encrypt(&webhook_secret, encryption_key).map_err(MerchantError::Encryption)?
     };
 
+    // Stored UFVKs are encrypted with a fresh nonce, so duplicates are found by the
+    // address they derive at index 0. A deleted account with the same key hands over its
+    // next diversifier index, so old invoice addresses are never reused.
+    let mut tx = crate::db::begin_write(pool).await?;
+    let existing: Vec<(Option<String>, i64)> = sqlx::query_as(
+        "SELECT deleted_at, diversifier_index FROM merchants WHERE payment_address = ?"
+    )
+    .bind(&payment_address)
+    .fetch_all(&mut *tx)
+    .await?;
+    if existing.iter().any(|(deleted_at, _)| deleted_at.is_none()) {
+        return Err(MerchantError::UfvkInUse);
+    }
+    let diversifier_index = existing.iter().map(|(_, index)| *index).max().unwrap_or(0).max(1);
+
     sqlx::query(
-        "INSERT INTO merchants (id, name, api_key_hash, dashboard_token_hash, ufvk, payment_address, webhook_url, webhook_secret, recovery_email, diversifier_index)
-         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, 1)"
+        "INSERT INTO merchants (id, name, api_key_hash, dashboard_token_hash, ufvk, payment_address, webhook_url, webhook_secret, recovery_email, diversifier_index, network)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
     )
     .bind(&id)
     .bind(&name)
@@ -102,22 +156,26 @@ pub async fn create_merchant(
     .bind(&req.webhook_url)
     .bind(&stored_webhook_secret)
     .bind(&req.email)
-    .execute(pool)
+    .bind(diversifier_index)
+    .bind(network)
+    .execute(&mut *tx)
     .await?;
+    tx.commit().await?;
 
-    tracing::info!(merchant_id = %id, "Merchant created with derived address");
+    tracing::info!(merchant_id = %id, diversifier_index, network, "Merchant created with derived address");
 
     Ok(CreateMerchantResponse {
         merchant_id: id,
         api_key,
         dashboard_token,
         webhook_secret,
+        network: network.to_string(),
     })
 }
 
-type MerchantRow = (String, String, String, String, String, String, Option<String>, String, Option<String>, String, i64);
+type MerchantRow = (String, String, String, String, String, String, Option<String>, String, Option<String>, String, i64, String);
 
-const MERCHANT_COLS: &str = "id, name, api_key_hash, dashboard_token_hash, ufvk, payment_address, webhook_url, webhook_secret, recovery_email, created_at, diversifier_index";
+const MERCHANT_COLS: &str = "id, name, api_key_hash, dashboard_token_hash, ufvk, payment_address, webhook_url, webhook_secret, recovery_email, created_at, diversifier_index, network";
 
 fn row_to_merchant(r: MerchantRow, encryption_key: &str) -> Merchant {
     let ufvk = crate::crypto::decrypt_or_plaintext(&r.4, encryption_key)
@@ -134,13 +192,13 @@ fn row_to_merchant(r: MerchantRow, encryption_key: &str) -> Merchant {
         id: r.0, name: r.1, api_key_hash: r.2, dashboard_token_hash: r.3,
         ufvk, payment_address: r.5, webhook_url: r.6,
         webhook_secret, recovery_email: r.8, created_at: r.9,
-        diversifier_index: r.10,
+        diversifier_index: r.10, network: r.11,
     }
 }
 
-pub async fn get_all_merchants(pool: &SqlitePool, encryption_key: &str) -> anyhow::Result<Vec<Merchant>> {
+pub async fn get_all_merchants(pool: &SqlitePool, encryption_key: &str) -> Result<Vec<Merchant>, MerchantError> {
     let rows = sqlx::query_as::<_, MerchantRow>(
-        &format!("SELECT {MERCHANT_COLS} FROM merchants")
+        &format!("SELECT {MERCHANT_COLS} FROM merchants WHERE deleted_at IS NULL")
     )
     .fetch_all(pool)
     .await?;
@@ -148,7 +206,26 @@ pub async fn get_all_merchants(pool: &SqlitePool, encryption_key: &str) -> anyho
     Ok(rows.into_iter().map(|r| row_to_merchant(r, encryption_key)).collect())
 }
 
-pub async fn authenticate(pool: &SqlitePool, api_key: &str, encryption_key: &str) -> anyhow::Result<Option<Merchant>> {
+/// The network a merchant's addresses and invoices are on, None if it is gone.
+pub async fn get_network(pool: &SqlitePool, merchant_id: &str) -> Result<Option<String>, MerchantError> {
+    let network = sqlx::query_scalar("SELECT network FROM merchants WHERE id = ? AND deleted_at IS NULL")
+        .bind(merchant_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(network)
+}
+
+/// Merchants in strict address mode, whose payments never match an invoice by memo.
+pub async fn strict_address_merchants(pool: &SqlitePool) -> Result<HashSet<String>, MerchantError> {
+    let ids: Vec<String> = sqlx::query_scalar(
+        "SELECT id FROM merchants WHERE strict_address_mode = 1 AND deleted_at IS NULL"
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(ids.into_iter().collect())
+}
+
+pub async fn authenticate(pool: &SqlitePool, api_key: &str, encryption_key: &str) -> Result<Option<Merchant>, MerchantError> {
     let key_hash = hash_key(api_key);
 
     let row = sqlx::query_as::<_, MerchantRow>(
@@ -161,7 +238,7 @@ pub async fn authenticate(pool: &SqlitePool, api_key: &str, encryption_key: &str
     Ok(row.map(|r| row_to_merchant(r, encryption_key)))
 }
 
-pub async fn authenticate_dashboard(pool: &SqlitePool, token: &str, encryption_key: &str) -> anyhow::Result<Option<Merchant>> {
+pub async fn authenticate_dashboard(pool: &SqlitePool, token: &str, encryption_key: &str) -> Result<Option<Merchant>, MerchantError> {
     let token_hash = hash_key(token);
 
     let row = sqlx::query_as::<_, MerchantRow>(
@@ -174,23 +251,87 @@ pub async fn authenticate_dashboard(pool: &SqlitePool, token: &str, encryption_k
     Ok(row.map(|r| row_to_merchant(r, encryption_key)))
 }
 
-pub async fn get_by_session(pool: &SqlitePool, session_id: &str, encryption_key: &str) -> anyhow::Result<Option<Merchant>> {
-    let cols = MERCHANT_COLS.replace("id,", "m.id,").replace(", ", ", m.").replacen("m.id", "m.id", 1);
+/// The merchant behind a session, unless it has expired or was started more than
+/// `max_age_hours` ago. The age check is absolute: lowering `SESSION_MAX_AGE_HOURS` ends
+/// older sessions at once, whatever their `expires_at` and however active they are.
+pub async fn get_by_session(
+    pool: &SqlitePool,
+    session_id: &str,
+    max_age_hours: i64,
+    encryption_key: &str,
+) -> Result<Option<Merchant>, MerchantError> {
+    let cols = MERCHANT_COLS.replace("id,", "m.id,").replace(", ", ", m.");
     let row = sqlx::query_as::<_, MerchantRow>(
         &format!(
             "SELECT {} FROM merchants m JOIN sessions s ON s.merchant_id = m.id
-             WHERE s.id = ? AND s.expires_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now')",
+             WHERE s.id = ? AND s.expires_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now')
+             AND s.created_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now', ?)",
             cols
         )
     )
     .bind(session_id)
+    .bind(format!("-{} hours", max_age_hours))
     .fetch_optional(pool)
     .await?;
 
     Ok(row.map(|r| row_to_merchant(r, encryption_key)))
 }
 
-pub async fn regenerate_api_key(pool: &SqlitePool, merchant_id: &str) -> anyhow::Result<String> {
+/// Start a dashboard session lasting `max_age_hours`, returning its ID.
+pub async fn create_session(
+    pool: &SqlitePool,
+    merchant_id: &str,
+    client_ip: Option<&str>,
+    max_age_hours: i64,
+) -> Result<String, MerchantError> {
+    let session_id = Uuid::new_v4().to_string();
+    let expires_at = (chrono::Utc::now() + chrono::Duration::hours(max_age_hours))
+        .format("%Y-%m-%dT%H:%M:%SZ")
+        .to_string();
+
+    sqlx::query("INSERT INTO sessions (id, merchant_id, expires_at, ip_address) VALUES (?, ?, ?, ?)")
+        .bind(&session_id)
+        .bind(merchant_id)
+        .bind(&expires_at)
+        .bind(client_ip)
+        .execute(pool)
+        .await?;
+    Ok(session_id)
+}
+
+/// Move a session to a fresh ID after a privilege change, so an ID planted or seen
+/// before it is worth nothing after. The merchant, creation time and expiry carry over,
+/// which keeps the absolute age limit counting from the original login; `elevated_until`
+/// is replaced. `None` if the session no longer exists.
+pub async fn rotate_session(
+    pool: &SqlitePool,
+    session_id: &str,
+    elevated_until: Option<&str>,
+) -> Result<Option<String>, MerchantError> {
+    let new_id = Uuid::new_v4().to_string();
+    let mut tx = crate::db::begin_write(pool).await?;
+
+    let copied = sqlx::query(
+        "INSERT INTO sessions (id, merchant_id, expires_at, created_at, ip_address, elevated_until)
+         SELECT ?, merchant_id, expires_at, created_at, ip_address, ? FROM sessions
+         WHERE id = ? AND expires_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now')"
+    )
+    .bind(&new_id)
+    .bind(elevated_until)
+    .bind(session_id)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    sqlx::query("DELETE FROM sessions WHERE id = ?")
+        .bind(session_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok((copied > 0).then_some(new_id))
+}
+
+pub async fn regenerate_api_key(pool: &SqlitePool, merchant_id: &str) -> Result<String, MerchantError> {
     let new_key = generate_api_key();
     let new_hash = hash_key(&new_key);
     sqlx::query("UPDATE merchants SET api_key_hash = ? WHERE id = ?")
@@ -202,7 +343,7 @@ pub async fn regenerate_api_key(pool: &SqlitePool, merchant_id: &str) -> anyhow:
     Ok(new_key)
 }
 
-pub async fn regenerate_dashboard_token(pool: &SqlitePool, merchant_id: &str) -> anyhow::Result<String> {
+pub async fn regenerate_dashboard_token(pool: &SqlitePool, merchant_id: &str) -> Result<String, MerchantError> {
     let new_token = generate_dashboard_token();
     let new_hash = hash_key(&new_token);
     sqlx::query("UPDATE merchants SET dashboard_token_hash = ? WHERE id = ?")
@@ -221,12 +362,12 @@ pub async fn regenerate_dashboard_token(pool: &SqlitePool, merchant_id: &str) ->
     Ok(new_token)
 }
 
-pub async fn regenerate_webhook_secret(pool: &SqlitePool, merchant_id: &str, encryption_key: &str) -> anyhow::Result<String> {
+pub async fn regenerate_webhook_secret(pool: &SqlitePool, merchant_id: &str, encryption_key: &str) -> Result<String, MerchantError> {
     let new_secret = generate_webhook_secret();
     let stored = if encryption_key.is_empty() {
         new_secret.clone()
     } else {
-        crate::crypto::encrypt(&new_secret, encryption_key)?
+        crate::crypto::encrypt(&new_secret, encryption_key).map_err(MerchantError::Encryption)?
     };
     sqlx::query("UPDATE merchants SET webhook_secret = ? WHERE id = ?")
         .bind(&stored)
@@ -237,20 +378,104 @@ pub async fn regenerate_webhook_secret(pool: &SqlitePool, merchant_id: &str, enc
     Ok(new_secret)
 }
 
-/// Atomically increment the merchant's diversifier_index and return the index to use.
-/// The returned value is the index BEFORE the increment (i.e., the one to use for this invoice).
-pub async fn next_diversifier_index(pool: &SqlitePool, merchant_id: &str) -> anyhow::Result<u32> {
-    let row: (i64,) = sqlx::query_as(
-        "UPDATE merchants SET diversifier_index = diversifier_index + 1 WHERE id = ? RETURNING diversifier_index - 1"
+/// Claim a vanity slug. Slugs can only be set once; returns false if the merchant already has one.
+/// Fails with `SlugTaken` if another merchant holds the slug in any case.
+pub async fn set_slug(pool: &SqlitePool, merchant_id: &str, slug: &str) -> Result<bool, MerchantError> {
+    let result = sqlx::query("UPDATE merchants SET slug = ? WHERE id = ? AND slug IS NULL")
+        .bind(slug)
+        .bind(merchant_id)
+        .execute(pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(ref db) if db.is_unique_violation() => MerchantError::SlugTaken,
+            e => e.into(),
+        })?;
+
+    if result.rows_affected() > 0 {
+        tracing::info!(merchant_id, slug, "Merchant slug set");
+    }
+    Ok(result.rows_affected() > 0)
+}
+
+/// Highest diversifier index an invoice address can use. Index 0 is the base address.
+pub const MAX_DIVERSIFIER_INDEX: i64 = u32::MAX as i64;
+
+/// Claim the merchant's next diversifier index for an invoice address and advance the
+/// counter past it. If the counter is behind an index its invoices already use (a restored
+/// backup, a hand-edited row) it jumps ahead of them rather than derive a used address;
+/// once every index is used this fails instead of wrapping around.
+pub async fn next_diversifier_index(pool: &SqlitePool, merchant_id: &str) -> Result<u32, MerchantError> {
+    let mut tx = crate::db::begin_write(pool).await?;
+    let stored: i64 = sqlx::query_scalar("SELECT diversifier_index FROM merchants WHERE id = ?")
+        .bind(merchant_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(MerchantError::NotFound)?;
+    // Settlement invoices carry indices of the operator's fee wallet.
+    let highest_used: Option<i64> = sqlx::query_scalar(
+        "SELECT MAX(diversifier_index) FROM invoices WHERE merchant_id = ? AND memo_code NOT LIKE 'SETTLE-%'"
     )
     .bind(merchant_id)
-    .fetch_one(pool)
+    .fetch_one(&mut *tx)
     .await?;
 
-    Ok(row.0 as u32)
+    let mut index = stored.max(1);
+    if let Some(used) = highest_used.filter(|used| *used >= index) {
+        tracing::warn!(merchant_id, stored, highest_used = used, "Diversifier index behind issued invoices, jumping ahead");
+        index = used + 1;
+    }
+    if index > MAX_DIVERSIFIER_INDEX {
+        tracing::error!(merchant_id, index, "Diversifier indices exhausted");
+        return Err(MerchantError::DiversifierExhausted);
+    }
+
+    sqlx::query("UPDATE merchants SET diversifier_index = ? WHERE id = ?")
+        .bind(index + 1)
+        .bind(merchant_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(index as u32)
+}
+
+/// How far a merchant has got through its diversifier indices.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct DiversifierUsage {
+    pub merchant_id: String,
+    pub name: String,
+    /// Index the next invoice gets.
+    pub next_index: i64,
+    /// Invoices holding an index of the merchant's wallet.
+    pub invoices: i64,
+    /// Indices consumed without an invoice: failed creations, jumps ahead, and those
+    /// handed over by a deleted account with the same key.
+    pub skipped: i64,
+    pub remaining: i64,
+}
+
+/// Merchants by diversifier indices consumed, most first.
+pub async fn diversifier_usage(pool: &SqlitePool, limit: i64) -> Result<Vec<DiversifierUsage>, MerchantError> {
+    let rows = sqlx::query_as(
+        "SELECT m.id AS merchant_id, m.name, m.diversifier_index AS next_index,
+                COUNT(i.id) AS invoices,
+                MAX(m.diversifier_index - 1 - COUNT(i.id), 0) AS skipped,
+                MAX(?1 + 1 - m.diversifier_index, 0) AS remaining
+         FROM merchants m
+         LEFT JOIN invoices i ON i.merchant_id = m.id AND i.diversifier_index IS NOT NULL
+              AND i.memo_code NOT LIKE 'SETTLE-%'
+         WHERE m.deleted_at IS NULL
+         GROUP BY m.id
+         ORDER BY m.diversifier_index DESC
+         LIMIT ?2"
+    )
+    .bind(MAX_DIVERSIFIER_INDEX)
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
 }
 
-pub async fn find_by_email(pool: &SqlitePool, email: &str, encryption_key: &str) -> anyhow::Result<Option<Merchant>> {
+pub async fn find_by_email(pool: &SqlitePool, email: &str, encryption_key: &str) -> Result<Option<Merchant>, MerchantError> {
     let row = sqlx::query_as::<_, MerchantRow>(
         &format!("SELECT {MERCHANT_COLS} FROM merchants WHERE recovery_email = ?")
     )
@@ -261,7 +486,7 @@ pub async fn find_by_email(pool: &SqlitePool, email: &str, encryption_key: &str)
     Ok(row.map(|r| row_to_merchant(r, encryption_key)))
 }
 
-pub async fn create_recovery_token(pool: &SqlitePool, merchant_id: &str) -> anyhow::Result<String> {
+pub async fn create_recovery_token(pool: &SqlitePool, merchant_id: &str) -> Result<String, MerchantError> {
     let token = Uuid::new_v4().to_string();
     let token_hash = hash_key(&token);
     let id = Uuid::new_v4().to_string();
@@ -288,7 +513,7 @@ pub async fn create_recovery_token(pool: &SqlitePool, merchant_id: &str) -> anyh
     Ok(token)
 }
 
-pub async fn has_outstanding_balance(pool: &SqlitePool, merchant_id: &str) -> anyhow::Result<bool> {
+pub async fn has_outstanding_balance(pool: &SqlitePool, merchant_id: &str) -> Result<bool, MerchantError> {
     let row: Option<(f64,)> = sqlx::query_as(
         "SELECT COALESCE(SUM(outstanding_zec), 0) FROM billing_cycles
          WHERE merchant_id = ? AND outstanding_zec > 0.0001"
@@ -300,7 +525,7 @@ pub async fn has_outstanding_balance(pool: &SqlitePool, merchant_id: &str) -> an
     Ok(row.map(|r| r.0 > 0.0001).unwrap_or(false))
 }
 
-pub async fn delete_merchant(pool: &SqlitePool, merchant_id: &str) -> anyhow::Result<()> {
+pub async fn delete_merchant(pool: &SqlitePool, merchant_id: &str) -> Result<(), MerchantError> {
     sqlx::query("DELETE FROM sessions WHERE merchant_id = ?")
         .bind(merchant_id).execute(pool).await?;
     sqlx::query("DELETE FROM recovery_tokens WHERE merchant_id = ?")
@@ -309,16 +534,102 @@ pub async fn delete_merchant(pool: &SqlitePool, merchant_id: &str) -> anyhow::Re
         .bind(merchant_id).execute(pool).await?;
     sqlx::query("DELETE FROM billing_cycles WHERE merchant_id = ?")
         .bind(merchant_id).execute(pool).await?;
-    sqlx::query("UPDATE products SET active = 0 WHERE merchant_id = ?")
+    sqlx::query("DELETE FROM ufvk_checks WHERE merchant_id = ?")
+        .bind(merchant_id).execute(pool).await?;
+    sqlx::query("DELETE FROM unmatched_payments WHERE merchant_id = ?")
+        .bind(merchant_id).execute(pool).await?;
+    sqlx::query("DELETE FROM checkout_sessions WHERE merchant_id = ?")
+        .bind(merchant_id).execute(pool).await?;
+    sqlx::query("DELETE FROM merchant_addresses WHERE merchant_id = ?")
+        .bind(merchant_id).execute(pool).await?;
+    sqlx::query("DELETE FROM invoice_templates WHERE merchant_id = ?")
         .bind(merchant_id).execute(pool).await?;
-    sqlx::query("DELETE FROM merchants WHERE id = ?")
+    sqlx::query("DELETE FROM payment_requests WHERE merchant_id = ?")
         .bind(merchant_id).execute(pool).await?;
+    sqlx::query("UPDATE products SET active = 0 WHERE merchant_id = ?")
+        .bind(merchant_id).execute(pool).await?;
+    // The row stays behind as a tombstone for its invoices and for `create_merchant`,
+    // keeping only the public payment address and diversifier index. Keys, secrets and
+    // contact details are wiped.
+    sqlx::query(
+        "UPDATE merchants SET deleted_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now'),
+         name = '', api_key_hash = 'deleted:' || id, dashboard_token_hash = '',
+         ufvk = 'deleted:' || id, webhook_url = NULL, webhook_secret = '', recovery_email = NULL,
+         slug = NULL, store_about = NULL, tex_enabled = 0,
+         nostr_relays = NULL, nostr_secret_key = NULL, nostr_pubkey = NULL, nostr_notify_pubkey = NULL,
+         slack_webhook_url = NULL, discord_webhook_url = NULL,
+         matrix_homeserver = NULL, matrix_room_id = NULL, matrix_access_token = NULL
+         WHERE id = ?"
+    )
+    .bind(merchant_id).execute(pool).await?;
 
     tracing::info!(merchant_id, "Merchant account deleted");
     Ok(())
 }
 
-pub async fn confirm_recovery_token(pool: &SqlitePool, token: &str) -> anyhow::Result<Option<String>> {
+/// What a sandbox reset removed.
+#[derive(Debug, Default, Serialize)]
+pub struct SandboxReset {
+    pub invoices: u64,
+    pub products: u64,
+    pub webhook_deliveries: u64,
+    /// Storage keys of the deleted product images, which the caller removes from storage.
+    #[serde(skip)]
+    pub image_keys: Vec<String>,
+}
+
+/// Delete the merchant's invoices with everything hanging off them (payments, timeline,
+/// webhook deliveries, fee ledger and billing cycles, payment requests, checkout sessions),
+/// their products and x402 history, in one transaction. The account, its keys, settings,
+/// templates and address book stay, and so does the diversifier index, so new invoices
+/// never reuse an address. Stats are computed from invoices and start again from zero.
+pub async fn reset_sandbox(pool: &SqlitePool, merchant_id: &str) -> Result<SandboxReset, MerchantError> {
+    let mut tx = crate::db::begin_write(pool).await?;
+    let mut reset = SandboxReset::default();
+
+    for (table, filter) in [
+        ("invoice_events", "invoice_id IN (SELECT id FROM invoices WHERE merchant_id = ?)"),
+        ("invoice_payments", "invoice_id IN (SELECT id FROM invoices WHERE merchant_id = ?)"),
+        ("webhook_deliveries", "merchant_id = ?"),
+        ("unmatched_payments", "merchant_id = ?"),
+        ("fee_ledger", "merchant_id = ?"),
+        ("billing_cycles", "merchant_id = ?"),
+        ("payment_requests", "merchant_id = ?"),
+        ("checkout_sessions", "merchant_id = ?"),
+        ("checkout_tokens", "product_id IN (SELECT id FROM products WHERE merchant_id = ?)"),
+        ("x402_verifications", "merchant_id = ?"),
+        ("invoices", "merchant_id = ?"),
+    ] {
+        let deleted = sqlx::query(&format!("DELETE FROM {} WHERE {}", table, filter))
+            .bind(merchant_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        match table {
+            "invoices" => reset.invoices = deleted,
+            "webhook_deliveries" => reset.webhook_deliveries = deleted,
+            _ => {}
+        }
+    }
+
+    reset.image_keys = sqlx::query_scalar("DELETE FROM product_images WHERE merchant_id = ? RETURNING storage_key")
+        .bind(merchant_id)
+        .fetch_all(&mut *tx)
+        .await?;
+    reset.products = sqlx::query("DELETE FROM products WHERE merchant_id = ?")
+        .bind(merchant_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+
+    tx.commit().await?;
+    tracing::info!(merchant_id, invoices = reset.invoices, products = reset.products, "Sandbox data reset");
+    Ok(reset)
+}
+
+/// Redeem a recovery token: the merchant's dashboard token is replaced and every session
+/// ended. Returns the merchant ID and the new dashboard token.
+pub async fn confirm_recovery_token(pool: &SqlitePool, token: &str) -> Result<Option<(String, String)>, MerchantError> {
     let token_hash = hash_key(token);
 
     let row = sqlx::query_as::<_, (String, String)>(
@@ -342,5 +653,32 @@ pub async fn confirm_recovery_token(pool: &SqlitePool, token: &str) -> anyhow::R
         .await?;
 
     tracing::info!(merchant_id = %merchant_id, "Account recovered via email token");
-    Ok(Some(new_token))
+    Ok(Some((merchant_id, new_token)))
+}
+
+/// Invoice counts and confirmed volume shown on the dashboard.
+#[derive(Debug, Default, Serialize)]
+pub struct MerchantStats {
+    pub total_invoices: i64,
+    pub confirmed: i64,
+    pub total_zec: f64,
+}
+
+/// Zeros if the query fails: stats decorate a response, they never fail it.
+pub async fn stats(pool: &SqlitePool, merchant_id: &str) -> MerchantStats {
+    let row = sqlx::query_as::<_, (i64, i64, f64)>(
+        "SELECT
+            COUNT(*) as total,
+            COUNT(CASE WHEN status = 'confirmed' THEN 1 END) as confirmed,
+            COALESCE(SUM(CASE WHEN status = 'confirmed' THEN price_zec ELSE 0.0 END), 0.0) as total_zec
+         FROM invoices WHERE merchant_id = ?"
+    )
+    .bind(merchant_id)
+    .fetch_optional(pool)
+    .await
+    .ok()
+    .flatten()
+    .unwrap_or((0, 0, 0.0));
+
+    MerchantStats { total_invoices: row.0, confirmed: row.1, total_zec: row.2 }
 }
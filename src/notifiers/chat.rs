@@ -0,0 +1,214 @@
+//! Chat notifications: Slack and Discord incoming webhooks and Matrix rooms. Messages are
+//! queued in `webhook_deliveries` under their own channel, so they are claimed, retried
+//! and expired by the same outbox as webhooks (`webhooks::retry_failed`).
+
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use uuid::Uuid;
+
+use crate::invoices::Invoice;
+use crate::validation::{self, ValidationError};
+
+/// Settings a merchant submits via `PATCH /api/merchants/me`. Omitted fields are left
+/// alone; an empty string clears one.
+#[derive(Debug, Deserialize)]
+pub struct ChatSettings {
+    pub slack_webhook_url: Option<String>,
+    pub discord_webhook_url: Option<String>,
+    /// Homeserver base URL, e.g. `https://matrix.org`.
+    pub matrix_homeserver: Option<String>,
+    /// Room to post in (`!opaque:server`); the bot account must already be joined.
+    pub matrix_room_id: Option<String>,
+    pub matrix_access_token: Option<String>,
+}
+
+/// What the dashboard shows. Webhook URLs and the Matrix token are secrets, so only
+/// whether they are set is returned.
+#[derive(Debug, Default, Serialize)]
+pub struct ChatProfile {
+    pub slack: bool,
+    pub discord: bool,
+    pub matrix_homeserver: Option<String>,
+    pub matrix_room_id: Option<String>,
+    pub matrix: bool,
+}
+
+type ChatRow = (Option<String>, Option<String>, Option<String>, Option<String>, Option<String>);
+
+const CHAT_COLUMNS: &str =
+    "slack_webhook_url, discord_webhook_url, matrix_homeserver, matrix_room_id, matrix_access_token";
+
+fn set(value: &Option<String>) -> Option<&str> {
+    value.as_deref().filter(|s| !s.is_empty())
+}
+
+pub fn validate(settings: &ChatSettings, is_testnet: bool) -> Result<(), ValidationError> {
+    if let Some(url) = set(&settings.slack_webhook_url) {
+        validation::validate_webhook_url("chat.slack_webhook_url", url, is_testnet)?;
+    }
+    if let Some(url) = set(&settings.discord_webhook_url) {
+        validation::validate_webhook_url("chat.discord_webhook_url", url, is_testnet)?;
+    }
+    if let Some(url) = set(&settings.matrix_homeserver) {
+        validation::validate_webhook_url("chat.matrix_homeserver", url, is_testnet)?;
+    }
+    if let Some(room) = set(&settings.matrix_room_id) {
+        validation::validate_length("chat.matrix_room_id", room, 255)?;
+        let valid = room.starts_with('!')
+            && room.split_once(':').is_some_and(|(local, server)| local.len() > 1 && !server.is_empty())
+            && !room.chars().any(|c| c.is_whitespace() || c == '/');
+        if !valid {
+            return Err(ValidationError::invalid("chat.matrix_room_id", "must be a room ID such as !abc123:matrix.org"));
+        }
+    }
+    if let Some(token) = set(&settings.matrix_access_token) {
+        validation::validate_length("chat.matrix_access_token", token, 512)?;
+    }
+    Ok(())
+}
+
+pub async fn get_profile(pool: &SqlitePool, merchant_id: &str) -> anyhow::Result<ChatProfile> {
+    let row: Option<ChatRow> = sqlx::query_as(&format!("SELECT {} FROM merchants WHERE id = ?", CHAT_COLUMNS))
+        .bind(merchant_id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(match row {
+        Some((slack, discord, homeserver, room_id, token)) => ChatProfile {
+            slack: slack.is_some(),
+            discord: discord.is_some(),
+            matrix: homeserver.is_some() && room_id.is_some() && token.is_some(),
+            matrix_homeserver: homeserver,
+            matrix_room_id: room_id,
+        },
+        None => ChatProfile::default(),
+    })
+}
+
+/// Save the channels that were submitted. The Matrix access token is encrypted at rest
+/// like UFVKs and webhook secrets.
+pub async fn update_settings(
+    pool: &SqlitePool,
+    merchant_id: &str,
+    settings: &ChatSettings,
+    encryption_key: &str,
+) -> anyhow::Result<()> {
+    let token = match settings.matrix_access_token.as_deref() {
+        Some(t) if !t.is_empty() && !encryption_key.is_empty() => Some(crate::crypto::encrypt(t, encryption_key)?),
+        other => other.map(str::to_string),
+    };
+    let updates = [
+        ("slack_webhook_url", settings.slack_webhook_url.clone()),
+        ("discord_webhook_url", settings.discord_webhook_url.clone()),
+        ("matrix_homeserver", settings.matrix_homeserver.as_deref().map(|h| h.trim_end_matches('/').to_string())),
+        ("matrix_room_id", settings.matrix_room_id.clone()),
+        ("matrix_access_token", token),
+    ];
+
+    for (column, value) in updates {
+        let Some(value) = value else { continue };
+        sqlx::query(&format!("UPDATE merchants SET {} = ? WHERE id = ?", column))
+            .bind(if value.is_empty() { None } else { Some(value) })
+            .bind(merchant_id)
+            .execute(pool)
+            .await?;
+    }
+
+    tracing::info!(merchant_id, "Chat notification settings updated");
+    Ok(())
+}
+
+/// Request body for a message on each channel.
+pub fn message_body(channel: &str, text: &str) -> serde_json::Value {
+    match channel {
+        "slack" => serde_json::json!({ "text": text }),
+        "discord" => serde_json::json!({ "content": text }),
+        _ => serde_json::json!({ "msgtype": "m.text", "body": text }),
+    }
+}
+
+/// Matrix send endpoint. The transaction ID is the delivery ID, so a retry of a message
+/// the homeserver already accepted is deduplicated instead of posted twice.
+pub fn matrix_send_url(homeserver: &str, room_id: &str, txn_id: &str) -> anyhow::Result<String> {
+    let mut url = url::Url::parse(homeserver)?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("homeserver URL cannot have a path"))?
+        .pop_if_empty()
+        .extend(["_matrix", "client", "v3", "rooms", room_id, "send", "m.room.message", txn_id]);
+    Ok(url.to_string())
+}
+
+/// Queue `text` on every channel the invoice's merchant has configured.
+pub async fn enqueue(pool: &SqlitePool, invoice_id: &str, text: &str) -> anyhow::Result<usize> {
+    let row: Option<ChatRow> = sqlx::query_as(&format!(
+        "SELECT {} FROM merchants m JOIN invoices i ON i.merchant_id = m.id WHERE i.id = ?",
+        CHAT_COLUMNS
+    ))
+    .bind(invoice_id)
+    .fetch_optional(pool)
+    .await?;
+    let Some((slack, discord, homeserver, room_id, token)) = row else {
+        return Ok(0);
+    };
+
+    let mut targets = Vec::new();
+    if let Some(url) = slack {
+        targets.push(("slack", Uuid::new_v4().to_string(), url));
+    }
+    if let Some(url) = discord {
+        targets.push(("discord", Uuid::new_v4().to_string(), url));
+    }
+    if let (Some(homeserver), Some(room_id), Some(_)) = (homeserver, room_id, token) {
+        let id = Uuid::new_v4().to_string();
+        let url = matrix_send_url(&homeserver, &room_id, &id)?;
+        targets.push(("matrix", id, url));
+    }
+
+    for (channel, id, url) in &targets {
+        crate::webhooks::enqueue_message(pool, id, invoice_id, channel, url, &message_body(channel, text)).await?;
+    }
+    Ok(targets.len())
+}
+
+fn describe(inv: &Invoice) -> String {
+    let display = crate::invoices::display::DisplayPrice::of(inv);
+    let item = match (&inv.product_name, inv.quantity) {
+        (Some(name), q) if q > 1 => format!("{} x {}", name, q),
+        (Some(name), _) => name.clone(),
+        (None, _) => "Payment".to_string(),
+    };
+    format!(
+        "{}: {} ({:.8} ZEC), invoice {}",
+        item,
+        display.formatted,
+        crate::invoices::zatoshis_to_zec(inv.received_zatoshis),
+        inv.memo_code,
+    )
+}
+
+pub fn confirmed_text(inv: &Invoice) -> String {
+    format!("Payment confirmed. {}", describe(inv))
+}
+
+pub fn paid_late_text(inv: &Invoice) -> String {
+    format!(
+        "Late payment received after expiry. {}. Fulfil or refund it from the dashboard.",
+        describe(inv)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_bodies() {
+        assert_eq!(message_body("slack", "hi")["text"], "hi");
+        assert_eq!(message_body("discord", "hi")["content"], "hi");
+        assert_eq!(message_body("matrix", "hi")["msgtype"], "m.text");
+        assert_eq!(
+            matrix_send_url("https://matrix.example.org/", "!room:example.org", "txn1").unwrap(),
+            "https://matrix.example.org/_matrix/client/v3/rooms/!room:example.org/send/m.room.message/txn1"
+        );
+    }
+}
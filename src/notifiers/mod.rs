@@ -0,0 +1,50 @@
+//! Outbound notifications besides webhooks and email. Each notifier is opt-in per
+//! merchant and runs in the background so a slow destination never holds up the scanner.
+
+pub mod chat;
+pub mod nostr;
+
+use sqlx::SqlitePool;
+
+use crate::config::Config;
+
+/// Fan an invoice confirmation out to every notifier the merchant has enabled.
+pub fn invoice_confirmed(pool: &SqlitePool, config: &Config, invoice_id: &str) {
+    let pool = pool.clone();
+    let config = config.clone();
+    let invoice_id = invoice_id.to_string();
+    tokio::spawn(async move {
+        chat_invoice(&pool, &invoice_id, chat::confirmed_text).await;
+        if let Err(e) = nostr::notify_confirmed(&pool, &config, &invoice_id).await {
+            tracing::warn!(invoice_id, error = %e, "Nostr notification failed");
+        }
+    });
+}
+
+/// A payment landed after expiry; the merchant has to resolve it by hand.
+pub fn invoice_paid_late(pool: &SqlitePool, invoice_id: &str) {
+    let pool = pool.clone();
+    let invoice_id = invoice_id.to_string();
+    tokio::spawn(async move {
+        chat_invoice(&pool, &invoice_id, chat::paid_late_text).await;
+    });
+}
+
+/// Post a billing message to the merchant's chat channels. Billing messages hang off the
+/// settlement invoice, which belongs to the merchant being billed.
+pub async fn billing_event(pool: &SqlitePool, settlement_invoice_id: &str, text: &str) {
+    if let Err(e) = chat::enqueue(pool, settlement_invoice_id, text).await {
+        tracing::warn!(invoice_id = settlement_invoice_id, error = %e, "Failed to queue billing chat message");
+    }
+}
+
+async fn chat_invoice(pool: &SqlitePool, invoice_id: &str, text: fn(&crate::invoices::Invoice) -> String) {
+    let queued = match crate::invoices::get_invoice(pool, invoice_id).await {
+        Ok(Some(inv)) => chat::enqueue(pool, invoice_id, &text(&inv)).await,
+        Ok(None) => Ok(0),
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = queued {
+        tracing::warn!(invoice_id, error = %e, "Failed to queue chat message");
+    }
+}
@@ -0,0 +1,271 @@
+//! Nostr notes for confirmed invoices (NIP-01). Each merchant that enables it gets a
+//! signing key generated here; confirmations are published as kind-1 notes to the
+//! merchant's relays, optionally tagging the merchant's own pubkey so clients notify them.
+
+use std::time::Duration;
+
+use bech32::{Bech32, Hrp};
+use futures::{SinkExt, StreamExt};
+use k256::schnorr::SigningKey;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use tokio_tungstenite::tungstenite::Message;
+
+use crate::config::Config;
+
+pub const MAX_RELAYS: usize = 10;
+const RELAY_TIMEOUT: Duration = Duration::from_secs(10);
+const KIND_TEXT_NOTE: u32 = 1;
+
+/// Settings a merchant submits via `PATCH /api/merchants/me`. An empty relay list disables notes.
+#[derive(Debug, Deserialize)]
+pub struct NostrSettings {
+    pub relays: Vec<String>,
+    /// Pubkey (hex or npub) tagged in each note, usually the merchant's own account.
+    pub notify_pubkey: Option<String>,
+}
+
+/// What the dashboard shows: relays plus the key notes are signed with.
+#[derive(Debug, Serialize)]
+pub struct NostrProfile {
+    pub relays: Vec<String>,
+    pub pubkey: String,
+    pub npub: String,
+    pub notify_pubkey: Option<String>,
+}
+
+/// Decode a pubkey given as 64 hex characters or an `npub1...` string into hex.
+pub fn parse_pubkey(value: &str) -> Option<String> {
+    let value = value.trim();
+    if value.starts_with("npub1") {
+        let (hrp, data) = bech32::decode(value).ok()?;
+        return (hrp.as_str() == "npub" && data.len() == 32).then(|| hex::encode(data));
+    }
+    (value.len() == 64 && value.chars().all(|c| c.is_ascii_hexdigit()))
+        .then(|| value.to_ascii_lowercase())
+}
+
+pub fn to_npub(pubkey_hex: &str) -> Option<String> {
+    let bytes = hex::decode(pubkey_hex).ok()?;
+    bech32::encode::<Bech32>(Hrp::parse("npub").ok()?, &bytes).ok()
+}
+
+pub async fn get_profile(pool: &SqlitePool, merchant_id: &str) -> anyhow::Result<Option<NostrProfile>> {
+    let row: Option<(Option<String>, Option<String>, Option<String>)> = sqlx::query_as(
+        "SELECT nostr_relays, nostr_pubkey, nostr_notify_pubkey FROM merchants WHERE id = ?"
+    )
+    .bind(merchant_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(match row {
+        Some((Some(relays), Some(pubkey), notify_pubkey)) => Some(NostrProfile {
+            relays: serde_json::from_str(&relays).unwrap_or_default(),
+            npub: to_npub(&pubkey).unwrap_or_default(),
+            pubkey,
+            notify_pubkey,
+        }),
+        _ => None,
+    })
+}
+
+/// Save relays and the tagged pubkey. The signing key is generated on first use and
+/// kept when settings change, so followers of the merchant's notifier key stay valid.
+pub async fn update_settings(
+    pool: &SqlitePool,
+    merchant_id: &str,
+    settings: &NostrSettings,
+    encryption_key: &str,
+) -> anyhow::Result<()> {
+    if settings.relays.is_empty() {
+        sqlx::query("UPDATE merchants SET nostr_relays = NULL WHERE id = ?")
+            .bind(merchant_id)
+            .execute(pool)
+            .await?;
+        tracing::info!(merchant_id, "Nostr notifications disabled");
+        return Ok(());
+    }
+
+    let has_key: Option<String> = sqlx::query_scalar("SELECT nostr_pubkey FROM merchants WHERE id = ?")
+        .bind(merchant_id)
+        .fetch_optional(pool)
+        .await?
+        .flatten();
+    if has_key.is_none() {
+        let key = SigningKey::random(&mut rand::rngs::OsRng);
+        let secret_hex = hex::encode(key.to_bytes());
+        let stored = if encryption_key.is_empty() {
+            secret_hex
+        } else {
+            crate::crypto::encrypt(&secret_hex, encryption_key)?
+        };
+        sqlx::query("UPDATE merchants SET nostr_secret_key = ?, nostr_pubkey = ? WHERE id = ?")
+            .bind(&stored)
+            .bind(hex::encode(key.verifying_key().to_bytes()))
+            .bind(merchant_id)
+            .execute(pool)
+            .await?;
+    }
+
+    sqlx::query("UPDATE merchants SET nostr_relays = ?, nostr_notify_pubkey = ? WHERE id = ?")
+        .bind(serde_json::to_string(&settings.relays)?)
+        .bind(settings.notify_pubkey.as_deref().and_then(parse_pubkey))
+        .bind(merchant_id)
+        .execute(pool)
+        .await?;
+
+    tracing::info!(merchant_id, relays = settings.relays.len(), "Nostr settings updated");
+    Ok(())
+}
+
+/// Build a signed NIP-01 event. The id is the SHA-256 of the canonical
+/// `[0, pubkey, created_at, kind, tags, content]` serialization, signed with BIP-340 Schnorr.
+pub fn sign_event(
+    key: &SigningKey,
+    created_at: i64,
+    kind: u32,
+    tags: Vec<Vec<String>>,
+    content: &str,
+) -> anyhow::Result<serde_json::Value> {
+    let pubkey = hex::encode(key.verifying_key().to_bytes());
+    let canonical = serde_json::to_string(&serde_json::json!([0, pubkey, created_at, kind, tags, content]))?;
+    let id: [u8; 32] = Sha256::digest(canonical.as_bytes()).into();
+    let aux: [u8; 32] = rand::random();
+    let sig = key.sign_prehash_with_aux_rand(&id, &aux)
+        .map_err(|e| anyhow::anyhow!("signing failed: {}", e))?;
+
+    Ok(serde_json::json!({
+        "id": hex::encode(id),
+        "pubkey": pubkey,
+        "created_at": created_at,
+        "kind": kind,
+        "tags": tags,
+        "content": content,
+        "sig": hex::encode(sig.to_bytes()),
+    }))
+}
+
+/// Send an event to one relay and wait for its `OK` verdict.
+async fn publish_to_relay(relay: &str, event: &serde_json::Value) -> anyhow::Result<()> {
+    crate::validation::resolve_and_check_host(relay).map_err(|e| anyhow::anyhow!(e))?;
+
+    let (mut ws, _) = tokio::time::timeout(RELAY_TIMEOUT, tokio_tungstenite::connect_async(relay)).await??;
+    ws.send(Message::Text(serde_json::json!(["EVENT", event]).to_string())).await?;
+
+    let event_id = event["id"].as_str().unwrap_or_default();
+    let verdict = tokio::time::timeout(RELAY_TIMEOUT, async {
+        while let Some(msg) = ws.next().await {
+            let Message::Text(text) = msg? else { continue };
+            let Ok(serde_json::Value::Array(parts)) = serde_json::from_str::<serde_json::Value>(&text) else { continue };
+            if parts.first().and_then(|v| v.as_str()) == Some("OK")
+                && parts.get(1).and_then(|v| v.as_str()) == Some(event_id)
+            {
+                let accepted = parts.get(2).and_then(|v| v.as_bool()).unwrap_or(false);
+                let reason = parts.get(3).and_then(|v| v.as_str()).unwrap_or("").to_string();
+                return Ok::<_, anyhow::Error>((accepted, reason));
+            }
+        }
+        anyhow::bail!("connection closed before OK")
+    }).await??;
+    let _ = ws.close(None).await;
+
+    match verdict {
+        (true, _) => Ok(()),
+        (false, reason) => anyhow::bail!("rejected: {}", reason),
+    }
+}
+
+/// Publish a note for a confirmed invoice to every relay of the merchant. Succeeds if
+/// at least one relay accepted it.
+pub async fn notify_confirmed(pool: &SqlitePool, config: &Config, invoice_id: &str) -> anyhow::Result<()> {
+    let invoice = match crate::invoices::get_invoice(pool, invoice_id).await? {
+        Some(inv) => inv,
+        None => return Ok(()),
+    };
+
+    let row: Option<(Option<String>, Option<String>, Option<String>)> = sqlx::query_as(
+        "SELECT nostr_relays, nostr_secret_key, nostr_notify_pubkey FROM merchants WHERE id = ?"
+    )
+    .bind(&invoice.merchant_id)
+    .fetch_optional(pool)
+    .await?;
+    let (relays, secret, notify_pubkey) = match row {
+        Some((Some(relays), Some(secret), notify_pubkey)) => (relays, secret, notify_pubkey),
+        _ => return Ok(()),
+    };
+    let relays: Vec<String> = serde_json::from_str(&relays)?;
+
+    let secret_hex = if config.encryption_key.is_empty() {
+        secret
+    } else {
+        crate::crypto::decrypt(&secret, &config.encryption_key)?
+    };
+    let key = SigningKey::from_bytes(&hex::decode(secret_hex)?)
+        .map_err(|e| anyhow::anyhow!("invalid nostr key: {}", e))?;
+
+    let mut tags = vec![vec!["t".to_string(), "cipherpay".to_string()]];
+    if let Some(p) = notify_pubkey {
+        tags.push(vec!["p".to_string(), p]);
+    }
+    let event = sign_event(&key, chrono::Utc::now().timestamp(), KIND_TEXT_NOTE, tags, &receipt_text(&invoice))?;
+
+    let mut accepted = 0;
+    for relay in &relays {
+        match publish_to_relay(relay, &event).await {
+            Ok(()) => accepted += 1,
+            Err(e) => tracing::debug!(relay, invoice_id, error = %e, "Relay did not accept note"),
+        }
+    }
+    if accepted == 0 {
+        anyhow::bail!("no relay accepted the note");
+    }
+    tracing::info!(invoice_id, accepted, relays = relays.len(), "Nostr note published");
+    Ok(())
+}
+
+/// Note body: what was bought and for how much. No addresses or txids, since notes are public.
+fn receipt_text(inv: &crate::invoices::Invoice) -> String {
+    let display = crate::invoices::display::DisplayPrice::of(inv);
+    let item = match (&inv.product_name, inv.quantity) {
+        (Some(name), q) if q > 1 => format!("{} x {}", name, q),
+        (Some(name), _) => name.clone(),
+        (None, _) => "Payment".to_string(),
+    };
+    format!(
+        "Payment confirmed: {}\n{} ({:.8} ZEC)\nInvoice {}",
+        item,
+        display.formatted,
+        crate::invoices::zatoshis_to_zec(inv.received_zatoshis),
+        inv.memo_code,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signed_event_verifies() {
+        let key = SigningKey::from_bytes(&[7u8; 32]).unwrap();
+        let event = sign_event(&key, 1_700_000_000, 1, vec![vec!["t".into(), "cipherpay".into()]], "hi \"there\"\n").unwrap();
+
+        let canonical = serde_json::to_string(&serde_json::json!([
+            0, event["pubkey"], 1_700_000_000, 1, event["tags"], event["content"],
+        ])).unwrap();
+        let id: [u8; 32] = Sha256::digest(canonical.as_bytes()).into();
+        assert_eq!(event["id"], hex::encode(id));
+
+        let sig = k256::schnorr::Signature::try_from(hex::decode(event["sig"].as_str().unwrap()).unwrap().as_slice()).unwrap();
+        key.verifying_key().verify_raw(&id, &sig).unwrap();
+    }
+
+    #[test]
+    fn test_npub_roundtrip() {
+        let hex = "3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d";
+        let npub = to_npub(hex).unwrap();
+        assert_eq!(npub, "npub180cvv07tjdrrgpa0j7j7tmnyl2yr6yr7l8j4s3evf6u64th6gkwsyjh6w6");
+        assert_eq!(parse_pubkey(&npub).as_deref(), Some(hex));
+        assert!(parse_pubkey("npub1invalid").is_none());
+    }
+}
@@ -0,0 +1,152 @@
+//! Custom checkout fields: questions a merchant asks the buyer of a product (a Discord
+//! username, the email a license goes to). Definitions are stored on the product; the
+//! buyer's answers are validated against them at checkout and stored on the invoice
+//! encrypted with `ENCRYPTION_KEY`. Only the owning merchant sees them, in the invoice
+//! detail and in webhooks.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::validation::{self, ValidationError};
+
+/// Most fields one product can define.
+pub const MAX_FIELDS: usize = 10;
+/// Longest answer accepted for a `text` field.
+const MAX_VALUE_LEN: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    Text,
+    Email,
+    Number,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckoutField {
+    /// Shown to the buyer and used as the key of the answer.
+    pub name: String,
+    #[serde(rename = "type")]
+    pub field_type: FieldType,
+    #[serde(default)]
+    pub required: bool,
+}
+
+/// Check a product's field definitions: at most `MAX_FIELDS`, names non-empty and unique.
+pub fn validate_definitions(fields: &[CheckoutField]) -> Result<(), ValidationError> {
+    if fields.len() > MAX_FIELDS {
+        return Err(ValidationError::invalid("checkout_fields", &format!("at most {} fields", MAX_FIELDS)));
+    }
+    for (i, field) in fields.iter().enumerate() {
+        let name = field.name.trim();
+        if name.is_empty() {
+            return Err(ValidationError::invalid("checkout_fields", "field names cannot be empty"));
+        }
+        validation::validate_length("checkout_fields.name", name, 60)?;
+        if fields[..i].iter().any(|f| f.name.trim() == name) {
+            return Err(ValidationError::invalid("checkout_fields", "field names must be unique"));
+        }
+    }
+    Ok(())
+}
+
+/// Check a buyer's answers against the product's fields. Unknown fields are refused,
+/// blank answers to optional fields are dropped. Returns the answers to store.
+pub fn validate_values(
+    fields: &[CheckoutField],
+    values: &HashMap<String, String>,
+) -> Result<BTreeMap<String, String>, ValidationError> {
+    if let Some(unknown) = values.keys().find(|k| !fields.iter().any(|f| &f.name == *k)) {
+        return Err(ValidationError::invalid(unknown, "not a checkout field of this product"));
+    }
+
+    let mut answers = BTreeMap::new();
+    for field in fields {
+        let value = values.get(&field.name).map(|v| v.trim()).unwrap_or_default();
+        if value.is_empty() {
+            if field.required {
+                return Err(ValidationError::invalid(&field.name, "is required"));
+            }
+            continue;
+        }
+        match field.field_type {
+            FieldType::Text => validation::validate_length(&field.name, value, MAX_VALUE_LEN)?,
+            FieldType::Email => validation::validate_email_format(&field.name, value)?,
+            FieldType::Number => {
+                if !value.parse::<f64>().is_ok_and(f64::is_finite) {
+                    return Err(ValidationError::invalid(&field.name, "must be a number"));
+                }
+            }
+        }
+        answers.insert(field.name.clone(), value.to_string());
+    }
+    Ok(answers)
+}
+
+/// Answers as stored on the invoice: JSON, encrypted unless no key is configured.
+pub fn seal(answers: &BTreeMap<String, String>, encryption_key: &str) -> anyhow::Result<String> {
+    let json = serde_json::to_string(answers)?;
+    if encryption_key.is_empty() {
+        return Ok(json);
+    }
+    crate::crypto::encrypt(&json, encryption_key)
+}
+
+/// Read stored answers back.
+pub fn open(stored: &str, encryption_key: &str) -> anyhow::Result<serde_json::Value> {
+    let json = if stored.starts_with('{') {
+        stored.to_string()
+    } else {
+        crate::crypto::decrypt(stored, encryption_key)?
+    };
+    Ok(serde_json::from_str(&json)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> Vec<CheckoutField> {
+        serde_json::from_value(serde_json::json!([
+            { "name": "Discord username", "type": "text", "required": true },
+            { "name": "License email", "type": "email" },
+            { "name": "Seats", "type": "number" },
+        ]))
+        .unwrap()
+    }
+
+    fn values(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_validate_values() {
+        let answers = validate_values(&fields(), &values(&[
+            ("Discord username", " satoshi#1 "),
+            ("License email", ""),
+            ("Seats", "3"),
+        ])).unwrap();
+        assert_eq!(answers.len(), 2);
+        assert_eq!(answers["Discord username"], "satoshi#1");
+
+        assert!(validate_values(&fields(), &values(&[])).is_err());
+        assert!(validate_values(&fields(), &values(&[("Discord username", "a"), ("License email", "nope")])).is_err());
+        assert!(validate_values(&fields(), &values(&[("Discord username", "a"), ("Seats", "NaN")])).is_err());
+        assert!(validate_values(&fields(), &values(&[("Discord username", "a"), ("Shoe size", "42")])).is_err());
+    }
+
+    #[test]
+    fn test_seal_round_trip() {
+        let answers = validate_values(&fields(), &values(&[("Discord username", "satoshi")])).unwrap();
+        let key = "ab".repeat(32);
+        let sealed = seal(&answers, &key).unwrap();
+        assert!(!sealed.contains("satoshi"));
+        assert_eq!(open(&sealed, &key).unwrap()["Discord username"], "satoshi");
+        assert_eq!(open(&seal(&answers, "").unwrap(), "").unwrap()["Discord username"], "satoshi");
+
+        let mut duplicate = fields();
+        duplicate[1].name = "Discord username".into();
+        assert!(validate_definitions(&duplicate).is_err());
+    }
+}
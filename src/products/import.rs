@@ -0,0 +1,256 @@
+//! Bulk product import and export, for merchants moving a catalog from another platform.
+//! A CSV file has one product per line under a header naming the columns of `CsvProduct`;
+//! variants and tags are `|`-separated. JSON is an array of `CreateProductRequest`.
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use super::{CreateProductRequest, Product};
+use crate::validation::ValidationError;
+
+/// Products one import can create.
+pub const MAX_IMPORT_ROWS: usize = 1000;
+/// Largest import body accepted.
+pub const MAX_IMPORT_BYTES: usize = 2 * 1024 * 1024;
+/// Separates variants and tags within a CSV cell.
+const LIST_SEPARATOR: char = '|';
+
+/// A product as one CSV line. Checkout fields only travel in JSON.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CsvProduct {
+    pub slug: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub price_eur: f64,
+    pub currency: Option<String>,
+    pub variants: Option<String>,
+    pub category: Option<String>,
+    pub tags: Option<String>,
+    pub max_quantity: Option<i64>,
+}
+
+/// Why one row of an import was rejected. Rows count from 1, after any CSV header.
+#[derive(Debug, Serialize)]
+pub struct RowError {
+    pub row: usize,
+    pub field: Option<String>,
+    pub error: String,
+}
+
+impl RowError {
+    fn new(row: usize, field: Option<&str>, error: impl ToString) -> Self {
+        Self { row, field: field.map(String::from), error: error.to_string() }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    /// Products created, or that would be on a dry run. 0 whenever a row failed.
+    pub imported: usize,
+    pub errors: Vec<RowError>,
+    /// IDs of the products created, for the caller's catalog webhooks.
+    #[serde(skip)]
+    pub created: Vec<String>,
+}
+
+fn split_list(cell: Option<String>) -> Option<Vec<String>> {
+    cell.map(|c| c.split(LIST_SEPARATOR).map(|v| v.trim().to_string()).filter(|v| !v.is_empty()).collect())
+}
+
+fn join_list(items: Vec<String>) -> Option<String> {
+    (!items.is_empty()).then(|| items.join(&LIST_SEPARATOR.to_string()))
+}
+
+impl From<CsvProduct> for CreateProductRequest {
+    fn from(row: CsvProduct) -> Self {
+        CreateProductRequest {
+            slug: row.slug,
+            name: row.name,
+            description: row.description,
+            price_eur: row.price_eur,
+            currency: row.currency,
+            variants: split_list(row.variants),
+            category: row.category,
+            tags: split_list(row.tags),
+            max_quantity: row.max_quantity,
+            checkout_fields: None,
+        }
+    }
+}
+
+impl From<&Product> for CreateProductRequest {
+    fn from(p: &Product) -> Self {
+        let fields = p.checkout_fields_list();
+        CreateProductRequest {
+            slug: p.slug.clone(),
+            name: p.name.clone(),
+            description: p.description.clone(),
+            price_eur: p.price_eur,
+            currency: Some(p.currency.clone()),
+            variants: Some(p.variants_list()).filter(|v| !v.is_empty()),
+            category: p.category.clone(),
+            tags: Some(p.tags_list()).filter(|t| !t.is_empty()),
+            max_quantity: p.max_quantity,
+            checkout_fields: (!fields.is_empty()).then_some(fields),
+        }
+    }
+}
+
+/// Parse CSV rows into product requests, collecting the rows that do not parse.
+pub fn parse_csv(data: &[u8]) -> (Vec<CreateProductRequest>, Vec<RowError>) {
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(data);
+    let mut rows = Vec::new();
+    let mut errors = Vec::new();
+    for (i, record) in reader.deserialize::<CsvProduct>().enumerate() {
+        match record {
+            Ok(row) => rows.push(row.into()),
+            Err(e) => errors.push(RowError::new(i + 1, None, e)),
+        }
+    }
+    (rows, errors)
+}
+
+/// Parse a JSON array of product requests, collecting the elements that do not parse.
+pub fn parse_json(data: &[u8]) -> Result<(Vec<CreateProductRequest>, Vec<RowError>), serde_json::Error> {
+    let values: Vec<serde_json::Value> = serde_json::from_slice(data)?;
+    let mut rows = Vec::new();
+    let mut errors = Vec::new();
+    for (i, value) in values.into_iter().enumerate() {
+        match serde_json::from_value(value) {
+            Ok(row) => rows.push(row),
+            Err(e) => errors.push(RowError::new(i + 1, None, e)),
+        }
+    }
+    Ok((rows, errors))
+}
+
+/// The products as CSV, in the import format.
+pub fn to_csv(products: &[Product]) -> anyhow::Result<String> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for p in products {
+        let req = CreateProductRequest::from(p);
+        writer.serialize(CsvProduct {
+            slug: req.slug,
+            name: req.name,
+            description: req.description,
+            price_eur: req.price_eur,
+            currency: req.currency,
+            variants: req.variants.and_then(join_list),
+            category: req.category,
+            tags: req.tags.and_then(join_list),
+            max_quantity: req.max_quantity,
+        })?;
+    }
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+/// Check every row with `validate` and the rules `create_product` applies, and against the
+/// merchant's existing slugs and each other. Unless a row failed or this is a dry run, create
+/// them all in one transaction. `errors` are rows that already failed to parse.
+pub async fn import_products(
+    pool: &SqlitePool,
+    merchant_id: &str,
+    rows: &[CreateProductRequest],
+    mut errors: Vec<RowError>,
+    dry_run: bool,
+    validate: fn(&CreateProductRequest) -> Result<(), ValidationError>,
+) -> anyhow::Result<ImportReport> {
+    let mut tx = crate::db::begin_write(pool).await?;
+    let mut slugs: HashSet<String> = sqlx::query_scalar("SELECT slug FROM products WHERE merchant_id = ?")
+        .bind(merchant_id)
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .collect();
+
+    // Rows that failed to parse are skipped by `rows`; number the rest around them.
+    let failed: HashSet<usize> = errors.iter().map(|e| e.row).collect();
+    let numbers = (1..).filter(|n| !failed.contains(n));
+    for (row, req) in numbers.zip(rows) {
+        if let Err(e) = validate(req) {
+            errors.push(RowError::new(row, Some(&e.field), e.message));
+        } else if let Err(e) = super::check_new_product(req) {
+            errors.push(RowError::new(row, None, e));
+        } else if !slugs.insert(req.slug.clone()) {
+            errors.push(RowError::new(row, Some("slug"), "a product with this slug already exists"));
+        }
+    }
+    errors.sort_by_key(|e| e.row);
+
+    if !errors.is_empty() || dry_run {
+        let imported = if errors.is_empty() { rows.len() } else { 0 };
+        return Ok(ImportReport { dry_run, imported, errors, created: Vec::new() });
+    }
+    let mut created = Vec::with_capacity(rows.len());
+    for req in rows {
+        created.push(super::insert_product(&mut tx, merchant_id, req).await?);
+    }
+    tx.commit().await?;
+
+    tracing::info!(merchant_id, count = rows.len(), "Products imported");
+    Ok(ImportReport { dry_run, imported: rows.len(), errors, created })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::test_pool;
+
+    const CSV: &str = "slug,name,description,price_eur,currency,variants,category,tags,max_quantity
+tee,T-Shirt,Organic cotton,25,EUR,S|M|L,Apparel,cotton | Summer,
+mug,Mug,,12.5,USD,,,,10
+";
+
+    fn no_validation(_: &CreateProductRequest) -> Result<(), ValidationError> {
+        Ok(())
+    }
+
+    async fn merchant(pool: &SqlitePool) -> String {
+        let req = crate::merchants::CreateMerchantRequest {
+            name: Some("Shop".into()),
+            ufvk: crate::scanner::fixtures::test_ufvk(1),
+            webhook_url: None,
+            email: None,
+            verify_blocks: None,
+        };
+        crate::merchants::create_merchant(pool, &req, "").await.unwrap().merchant_id
+    }
+
+    #[tokio::test]
+    async fn test_import_is_all_or_nothing_and_round_trips() {
+        let pool = test_pool().await;
+        let merchant_id = merchant(&pool).await;
+
+        let bad = format!("{CSV}tee,Duplicate,,5,EUR,,,,\nhat,Hat,,free,EUR,,,,\nscarf,Scarf,,9,GBP,,,,\n");
+        let (rows, errors) = parse_csv(bad.as_bytes());
+        let report = import_products(&pool, &merchant_id, &rows, errors, false, no_validation).await.unwrap();
+        let failed: Vec<_> = report.errors.iter().map(|e| (e.row, e.field.as_deref())).collect();
+        assert_eq!(failed, [(3, Some("slug")), (4, None), (5, None)]);
+        assert_eq!(report.imported, 0);
+        assert!(crate::products::list_products(&pool, &merchant_id, true).await.unwrap().is_empty());
+
+        let (rows, errors) = parse_csv(CSV.as_bytes());
+        let dry = import_products(&pool, &merchant_id, &rows, errors, true, no_validation).await.unwrap();
+        assert_eq!((dry.imported, dry.errors.len()), (2, 0));
+        assert!(crate::products::list_products(&pool, &merchant_id, true).await.unwrap().is_empty());
+
+        let (rows, errors) = parse_csv(CSV.as_bytes());
+        let report = import_products(&pool, &merchant_id, &rows, errors, false, no_validation).await.unwrap();
+        assert_eq!((report.imported, report.created.len()), (2, 2));
+        let mut products = crate::products::list_products(&pool, &merchant_id, true).await.unwrap();
+        products.sort_by(|a, b| b.slug.cmp(&a.slug));
+        assert_eq!(products[0].variants_list(), ["S", "M", "L"]);
+        assert_eq!(products[0].tags_list(), ["cotton", "summer"]);
+        assert_eq!(products[1].description, None);
+        assert_eq!(products[1].max_quantity, Some(10));
+
+        let exported = to_csv(&products).unwrap();
+        assert!(exported.contains("tee,T-Shirt,Organic cotton,25.0,EUR,S|M|L,Apparel,cotton|summer,"));
+        let (again, errors) = parse_csv(exported.as_bytes());
+        let report = import_products(&pool, &merchant_id, &again, errors, true, no_validation).await.unwrap();
+        assert_eq!(report.errors.len(), 2, "exported slugs already exist");
+    }
+}
@@ -1,5 +1,12 @@
+pub mod fields;
+pub mod import;
+pub mod sessions;
+pub mod tokens;
+
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, SqlitePool};
+use sqlx::{FromRow, QueryBuilder, Sqlite, SqlitePool};
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
@@ -12,11 +19,18 @@ pub struct Product {
     pub price_eur: f64,
     pub currency: String,
     pub variants: Option<String>,
+    pub category: Option<String>,
+    pub tags: Option<String>,
+    /// Most units a buyer can order at once; None means `DEFAULT_MAX_QUANTITY`.
+    pub max_quantity: Option<i64>,
+    /// JSON array of `fields::CheckoutField`.
+    pub checkout_fields: Option<String>,
     pub active: i32,
+    pub archived_at: Option<String>,
     pub created_at: String,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct CreateProductRequest {
     pub slug: String,
     pub name: String,
@@ -24,6 +38,10 @@ pub struct CreateProductRequest {
     pub price_eur: f64,
     pub currency: Option<String>,
     pub variants: Option<Vec<String>>,
+    pub category: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub max_quantity: Option<i64>,
+    pub checkout_fields: Option<Vec<fields::CheckoutField>>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -33,7 +51,41 @@ pub struct UpdateProductRequest {
     pub price_eur: Option<f64>,
     pub currency: Option<String>,
     pub variants: Option<Vec<String>>,
+    /// Empty string clears the category.
+    pub category: Option<String>,
+    pub tags: Option<Vec<String>>,
+    /// 0 resets to the default cap.
+    pub max_quantity: Option<i64>,
+    /// Replaces the product's checkout fields; an empty list removes them.
+    pub checkout_fields: Option<Vec<fields::CheckoutField>>,
     pub active: Option<bool>,
+    /// `false` restores an archived product (it stays inactive until re-activated).
+    pub archived: Option<bool>,
+}
+
+/// Per-order unit cap for products that don't set `max_quantity`.
+pub const DEFAULT_MAX_QUANTITY: i64 = 100;
+
+/// Maximum number of images a single product can carry.
+pub const MAX_IMAGES_PER_PRODUCT: i64 = 10;
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct ProductImage {
+    pub id: String,
+    pub product_id: String,
+    #[serde(skip_serializing)]
+    pub storage_key: String,
+    pub content_type: String,
+    pub size_bytes: i64,
+    pub position: i64,
+    pub created_at: String,
+}
+
+impl ProductImage {
+    /// Public URL, relative to the API host.
+    pub fn url(&self) -> String {
+        format!("/api/v1/media/{}", self.storage_key)
+    }
 }
 
 impl Product {
@@ -43,6 +95,52 @@ impl Product {
             .and_then(|v| serde_json::from_str(v).ok())
             .unwrap_or_default()
     }
+
+    pub fn quantity_limit(&self) -> i64 {
+        self.max_quantity.unwrap_or(DEFAULT_MAX_QUANTITY)
+    }
+
+    pub fn tags_list(&self) -> Vec<String> {
+        self.tags
+            .as_ref()
+            .and_then(|v| serde_json::from_str(v).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn checkout_fields_list(&self) -> Vec<fields::CheckoutField> {
+        self.checkout_fields
+            .as_ref()
+            .and_then(|v| serde_json::from_str(v).ok())
+            .unwrap_or_default()
+    }
+}
+
+/// Trim a category, treating blank as none.
+fn normalize_category(category: Option<&str>) -> Option<String> {
+    category.map(str::trim).filter(|c| !c.is_empty()).map(String::from)
+}
+
+/// Checkout fields as stored: names trimmed, none at all stored as NULL.
+fn checkout_fields_json(fields: &[fields::CheckoutField]) -> Option<String> {
+    if fields.is_empty() {
+        return None;
+    }
+    let fields: Vec<_> = fields.iter()
+        .map(|f| fields::CheckoutField { name: f.name.trim().to_string(), ..f.clone() })
+        .collect();
+    serde_json::to_string(&fields).ok()
+}
+
+/// Lowercase, trim and dedupe tags so catalog filtering is predictable.
+fn normalize_tags(tags: &[String]) -> Vec<String> {
+    let mut out: Vec<String> = Vec::new();
+    for tag in tags {
+        let t = tag.trim().to_lowercase();
+        if !t.is_empty() && !out.contains(&t) {
+            out.push(t);
+        }
+    }
+    out
 }
 
 pub async fn create_product(
@@ -50,6 +148,15 @@ pub async fn create_product(
     merchant_id: &str,
     req: &CreateProductRequest,
 ) -> anyhow::Result<Product> {
+    let id = insert_product(&mut *pool.acquire().await?, merchant_id, req).await?;
+
+    get_product(pool, &id)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Product not found after insert"))
+}
+
+/// The rules a new product must meet beyond field lengths, which the API checks.
+pub fn check_new_product(req: &CreateProductRequest) -> anyhow::Result<()> {
     if req.slug.is_empty() || req.name.is_empty() || req.price_eur <= 0.0 {
         anyhow::bail!("slug, name required and price must be > 0");
     }
@@ -62,13 +169,29 @@ pub async fn create_product(
     if currency != "EUR" && currency != "USD" {
         anyhow::bail!("currency must be EUR or USD");
     }
+    Ok(())
+}
+
+/// Check and insert a product on `conn`, returning its id.
+async fn insert_product(
+    conn: &mut sqlx::SqliteConnection,
+    merchant_id: &str,
+    req: &CreateProductRequest,
+) -> anyhow::Result<String> {
+    check_new_product(req)?;
 
     let id = Uuid::new_v4().to_string();
+    let currency = req.currency.as_deref().unwrap_or("EUR");
     let variants_json = req.variants.as_ref().map(|v| serde_json::to_string(v).unwrap_or_default());
+    let category = normalize_category(req.category.as_deref());
+    let tags_json = req.tags.as_ref()
+        .map(|t| serde_json::to_string(&normalize_tags(t)).unwrap_or_default());
+
+    let checkout_fields = req.checkout_fields.as_deref().and_then(checkout_fields_json);
 
     sqlx::query(
-        "INSERT INTO products (id, merchant_id, slug, name, description, price_eur, currency, variants)
-         VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
+        "INSERT INTO products (id, merchant_id, slug, name, description, price_eur, currency, variants, category, tags, max_quantity, checkout_fields)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
     )
     .bind(&id)
     .bind(merchant_id)
@@ -78,22 +201,25 @@ pub async fn create_product(
     .bind(req.price_eur)
     .bind(currency)
     .bind(&variants_json)
-    .execute(pool)
+    .bind(&category)
+    .bind(&tags_json)
+    .bind(req.max_quantity)
+    .bind(&checkout_fields)
+    .execute(&mut *conn)
     .await?;
 
     tracing::info!(product_id = %id, slug = %req.slug, "Product created");
-
-    get_product(pool, &id)
-        .await?
-        .ok_or_else(|| anyhow::anyhow!("Product not found after insert"))
+    Ok(id)
 }
 
-pub async fn list_products(pool: &SqlitePool, merchant_id: &str) -> anyhow::Result<Vec<Product>> {
+pub async fn list_products(pool: &SqlitePool, merchant_id: &str, include_archived: bool) -> anyhow::Result<Vec<Product>> {
     let rows = sqlx::query_as::<_, Product>(
-        "SELECT id, merchant_id, slug, name, description, price_eur, currency, variants, active, created_at
-         FROM products WHERE merchant_id = ? ORDER BY created_at DESC"
+        "SELECT id, merchant_id, slug, name, description, price_eur, currency, variants, category, tags, max_quantity, checkout_fields, active, archived_at, created_at
+         FROM products WHERE merchant_id = ? AND (? OR archived_at IS NULL)
+         ORDER BY created_at DESC"
     )
     .bind(merchant_id)
+    .bind(include_archived)
     .fetch_all(pool)
     .await?;
 
@@ -102,7 +228,7 @@ pub async fn list_products(pool: &SqlitePool, merchant_id: &str) -> anyhow::Resu
 
 pub async fn get_product(pool: &SqlitePool, id: &str) -> anyhow::Result<Option<Product>> {
     let row = sqlx::query_as::<_, Product>(
-        "SELECT id, merchant_id, slug, name, description, price_eur, currency, variants, active, created_at
+        "SELECT id, merchant_id, slug, name, description, price_eur, currency, variants, category, tags, max_quantity, checkout_fields, active, archived_at, created_at
          FROM products WHERE id = ?"
     )
     .bind(id)
@@ -112,13 +238,14 @@ pub async fn get_product(pool: &SqlitePool, id: &str) -> anyhow::Result<Option<P
     Ok(row)
 }
 
+#[allow(dead_code)]
 pub async fn get_product_by_slug(
     pool: &SqlitePool,
     merchant_id: &str,
     slug: &str,
 ) -> anyhow::Result<Option<Product>> {
     let row = sqlx::query_as::<_, Product>(
-        "SELECT id, merchant_id, slug, name, description, price_eur, currency, variants, active, created_at
+        "SELECT id, merchant_id, slug, name, description, price_eur, currency, variants, category, tags, max_quantity, checkout_fields, active, archived_at, created_at
          FROM products WHERE merchant_id = ? AND slug = ?"
     )
     .bind(merchant_id)
@@ -152,13 +279,37 @@ pub async fn update_product(
     let variants_json = req.variants.as_ref()
         .map(|v| serde_json::to_string(v).unwrap_or_default())
         .or(existing.variants);
+    let category = match req.category.as_deref() {
+        Some(c) => normalize_category(Some(c)),
+        None => existing.category,
+    };
+    let tags_json = req.tags.as_ref()
+        .map(|t| serde_json::to_string(&normalize_tags(t)).unwrap_or_default())
+        .or(existing.tags);
+    let max_quantity = match req.max_quantity {
+        Some(0) => None,
+        Some(q) => Some(q),
+        None => existing.max_quantity,
+    };
+    let checkout_fields = match req.checkout_fields.as_deref() {
+        Some(f) => checkout_fields_json(f),
+        None => existing.checkout_fields,
+    };
+    let archived_at = match req.archived {
+        Some(false) => None,
+        _ => existing.archived_at,
+    };
+    if archived_at.is_some() && active == 1 {
+        anyhow::bail!("Archived products cannot be activated; restore with archived: false first");
+    }
 
     if price_eur <= 0.0 {
         anyhow::bail!("Price must be > 0");
     }
 
     sqlx::query(
-        "UPDATE products SET name = ?, description = ?, price_eur = ?, currency = ?, variants = ?, active = ?
+        "UPDATE products SET name = ?, description = ?, price_eur = ?, currency = ?, variants = ?,
+         category = ?, tags = ?, max_quantity = ?, checkout_fields = ?, active = ?, archived_at = ?
          WHERE id = ? AND merchant_id = ?"
     )
     .bind(name)
@@ -166,7 +317,12 @@ pub async fn update_product(
     .bind(price_eur)
     .bind(currency)
     .bind(&variants_json)
+    .bind(&category)
+    .bind(&tags_json)
+    .bind(max_quantity)
+    .bind(&checkout_fields)
     .bind(active)
+    .bind(&archived_at)
     .bind(id)
     .bind(merchant_id)
     .execute(pool)
@@ -196,3 +352,298 @@ pub async fn deactivate_product(
         Ok(false)
     }
 }
+
+/// Archive products: they are deactivated and hidden from listings by default, but kept
+/// because past invoices reference them. Returns the products archived.
+pub async fn archive_products(
+    pool: &SqlitePool,
+    ids: &[String],
+    merchant_id: &str,
+) -> anyhow::Result<Vec<Product>> {
+    let now = chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+    let mut archived = Vec::new();
+    for id in ids {
+        let product = sqlx::query_as::<_, Product>(
+            "UPDATE products SET active = 0, archived_at = ?
+             WHERE id = ? AND merchant_id = ? AND archived_at IS NULL
+             RETURNING id, merchant_id, slug, name, description, price_eur, currency, variants, category, tags, max_quantity, checkout_fields, active, archived_at, created_at"
+        )
+        .bind(&now)
+        .bind(id)
+        .bind(merchant_id)
+        .fetch_optional(pool)
+        .await?;
+        archived.extend(product);
+    }
+
+    if !archived.is_empty() {
+        tracing::info!(merchant_id, count = archived.len(), "Products archived");
+    }
+    Ok(archived)
+}
+
+/// Hard-delete archived products that no invoice references.
+/// Returns the number deleted and the storage keys of their images, which the caller removes from storage.
+pub async fn purge_archived_products(pool: &SqlitePool, merchant_id: &str) -> anyhow::Result<(u64, Vec<String>)> {
+    let purgeable = "merchant_id = ? AND archived_at IS NOT NULL
+         AND NOT EXISTS (SELECT 1 FROM invoices WHERE invoices.product_id = products.id)";
+
+    let mut tx = pool.begin().await?;
+
+    let image_keys: Vec<String> = sqlx::query_scalar(&format!(
+        "DELETE FROM product_images WHERE product_id IN (SELECT id FROM products WHERE {})
+         RETURNING storage_key",
+        purgeable
+    ))
+    .bind(merchant_id)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let result = sqlx::query(&format!("DELETE FROM products WHERE {}", purgeable))
+        .bind(merchant_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    let count = result.rows_affected();
+    if count > 0 {
+        tracing::info!(merchant_id, count, images = image_keys.len(), "Archived products purged");
+    }
+    Ok((count, image_keys))
+}
+
+pub async fn list_images(pool: &SqlitePool, product_id: &str) -> anyhow::Result<Vec<ProductImage>> {
+    let rows = sqlx::query_as::<_, ProductImage>(
+        "SELECT id, product_id, storage_key, content_type, size_bytes, position, created_at
+         FROM product_images WHERE product_id = ?
+         ORDER BY position ASC, created_at ASC"
+    )
+    .bind(product_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows)
+}
+
+/// Images of several products in one query, grouped by product ID, each in display order.
+pub async fn list_images_for(pool: &SqlitePool, product_ids: &[String]) -> anyhow::Result<HashMap<String, Vec<ProductImage>>> {
+    let mut grouped: HashMap<String, Vec<ProductImage>> = HashMap::new();
+    if product_ids.is_empty() {
+        return Ok(grouped);
+    }
+
+    let mut query: QueryBuilder<Sqlite> = QueryBuilder::new(
+        "SELECT id, product_id, storage_key, content_type, size_bytes, position, created_at
+         FROM product_images WHERE product_id IN ("
+    );
+    let mut ids = query.separated(", ");
+    for id in product_ids {
+        ids.push_bind(id);
+    }
+    query.push(") ORDER BY position ASC, created_at ASC");
+
+    for image in query.build_query_as::<ProductImage>().fetch_all(pool).await? {
+        grouped.entry(image.product_id.clone()).or_default().push(image);
+    }
+    Ok(grouped)
+}
+
+/// Record an uploaded image against a product. The file must already be stored under `storage_key`.
+pub async fn add_image(
+    pool: &SqlitePool,
+    product_id: &str,
+    merchant_id: &str,
+    storage_key: &str,
+    content_type: &str,
+    size_bytes: usize,
+) -> anyhow::Result<ProductImage> {
+    let id = Uuid::new_v4().to_string();
+
+    let result = sqlx::query(
+        "INSERT INTO product_images (id, product_id, merchant_id, storage_key, content_type, size_bytes, position)
+         SELECT ?, ?, ?, ?, ?, ?, COALESCE(MAX(position) + 1, 0)
+         FROM product_images WHERE product_id = ?
+         HAVING COUNT(*) < ?"
+    )
+    .bind(&id)
+    .bind(product_id)
+    .bind(merchant_id)
+    .bind(storage_key)
+    .bind(content_type)
+    .bind(size_bytes as i64)
+    .bind(product_id)
+    .bind(MAX_IMAGES_PER_PRODUCT)
+    .execute(pool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        anyhow::bail!("Products can have at most {} images", MAX_IMAGES_PER_PRODUCT);
+    }
+
+    tracing::info!(product_id, image_id = %id, "Product image added");
+
+    sqlx::query_as::<_, ProductImage>(
+        "SELECT id, product_id, storage_key, content_type, size_bytes, position, created_at
+         FROM product_images WHERE id = ?"
+    )
+    .bind(&id)
+    .fetch_one(pool)
+    .await
+    .map_err(Into::into)
+}
+
+/// Remove an image row. Returns its storage key so the caller can delete the file.
+pub async fn delete_image(
+    pool: &SqlitePool,
+    image_id: &str,
+    product_id: &str,
+    merchant_id: &str,
+) -> anyhow::Result<Option<String>> {
+    let key: Option<String> = sqlx::query_scalar(
+        "DELETE FROM product_images WHERE id = ? AND product_id = ? AND merchant_id = ?
+         RETURNING storage_key"
+    )
+    .bind(image_id)
+    .bind(product_id)
+    .bind(merchant_id)
+    .fetch_optional(pool)
+    .await?;
+
+    if key.is_some() {
+        tracing::info!(product_id, image_id, "Product image deleted");
+    }
+    Ok(key)
+}
+
+/// URL of the first image of the product an invoice was created for, if any.
+pub async fn invoice_image_url(pool: &SqlitePool, invoice_id: &str) -> anyhow::Result<Option<String>> {
+    let key: Option<String> = sqlx::query_scalar(
+        "SELECT pi.storage_key FROM invoices i
+         JOIN product_images pi ON pi.product_id = i.product_id
+         WHERE i.id = ?
+         ORDER BY pi.position ASC, pi.created_at ASC LIMIT 1"
+    )
+    .bind(invoice_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(key.map(|k| format!("/api/v1/media/{}", k)))
+}
+
+/// One page of a merchant's public catalog: active, non-archived products ordered by
+/// category (uncategorized last) then name, optionally filtered by category or tag.
+/// Returns the page and the total number of matching products.
+pub async fn list_catalog(
+    pool: &SqlitePool,
+    merchant_id: &str,
+    category: Option<&str>,
+    tag: Option<&str>,
+    limit: i64,
+    offset: i64,
+) -> anyhow::Result<(Vec<Product>, i64)> {
+    let filter = "merchant_id = ? AND active = 1 AND archived_at IS NULL
+         AND (? IS NULL OR category = ?)
+         AND (? IS NULL OR EXISTS (SELECT 1 FROM json_each(products.tags) WHERE json_each.value = ?))";
+
+    let tag = tag.map(|t| t.trim().to_lowercase());
+
+    let (total,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(*) FROM products WHERE {}", filter))
+        .bind(merchant_id)
+        .bind(category)
+        .bind(category)
+        .bind(&tag)
+        .bind(&tag)
+        .fetch_one(pool)
+        .await?;
+
+    let rows = sqlx::query_as::<_, Product>(&format!(
+        "SELECT id, merchant_id, slug, name, description, price_eur, currency, variants, category, tags, max_quantity, checkout_fields, active, archived_at, created_at
+         FROM products WHERE {}
+         ORDER BY category IS NULL, category, name
+         LIMIT ? OFFSET ?",
+        filter
+    ))
+    .bind(merchant_id)
+    .bind(category)
+    .bind(category)
+    .bind(&tag)
+    .bind(&tag)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await?;
+
+    Ok((rows, total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn product(pool: &SqlitePool, merchant_id: &str, slug: &str, category: Option<&str>, tags: &[&str]) -> Product {
+        create_product(pool, merchant_id, &CreateProductRequest {
+            slug: slug.into(),
+            name: slug.to_uppercase(),
+            description: None,
+            price_eur: 10.0,
+            currency: None,
+            variants: None,
+            category: category.map(String::from),
+            tags: Some(tags.iter().map(|t| t.to_string()).collect()),
+            max_quantity: None,
+            checkout_fields: None,
+        }).await.unwrap()
+    }
+
+    fn slugs(products: &[Product]) -> Vec<&str> {
+        products.iter().map(|p| p.slug.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_archived_products_are_hidden_and_purged_once_unreferenced() {
+        let pool = crate::db::test_pool().await;
+        let merchant_id = crate::db::test_merchant(&pool, 1).await.merchant_id;
+        let other = crate::db::test_merchant(&pool, 2).await.merchant_id;
+        let mug = product(&pool, &merchant_id, "mug", None, &[]).await;
+        let tee = product(&pool, &merchant_id, "tee", None, &[]).await;
+        let cap = product(&pool, &merchant_id, "cap", None, &[]).await;
+
+        // A sold product stays referenced by its invoice.
+        let invoice_id = crate::db::test_invoice(&pool, &merchant_id, 1).await.invoice_id;
+        sqlx::query("UPDATE invoices SET product_id = ? WHERE id = ?")
+            .bind(&tee.id)
+            .bind(&invoice_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        add_image(&pool, &mug.id, &merchant_id, "mug.png", "image/png", 10).await.unwrap();
+
+        // Another merchant's product, or one already archived, is skipped.
+        let ids = [mug.id.clone(), tee.id.clone(), cap.id.clone()];
+        assert!(archive_products(&pool, &ids, &other).await.unwrap().is_empty());
+        assert_eq!(slugs(&archive_products(&pool, &ids[..2], &merchant_id).await.unwrap()), ["mug", "tee"]);
+        assert_eq!(slugs(&archive_products(&pool, &ids, &merchant_id).await.unwrap()), ["cap"]);
+        assert!(list_products(&pool, &merchant_id, false).await.unwrap().is_empty());
+        let archived = list_products(&pool, &merchant_id, true).await.unwrap();
+        assert_eq!(archived.len(), 3);
+        assert!(archived.iter().all(|p| p.active == 0 && p.archived_at.is_some()));
+
+        // Restoring leaves the product inactive; it cannot be activated while archived.
+        let activate = UpdateProductRequest {
+            name: None, description: None, price_eur: None, currency: None, variants: None, category: None,
+            tags: None, max_quantity: None, checkout_fields: None, active: Some(true), archived: None,
+        };
+        assert!(update_product(&pool, &cap.id, &merchant_id, &activate).await.is_err());
+        let restore = UpdateProductRequest { active: None, archived: Some(false), ..activate };
+        let restored = update_product(&pool, &cap.id, &merchant_id, &restore).await.unwrap().unwrap();
+        assert_eq!((restored.active, restored.archived_at), (0, None));
+        assert_eq!(slugs(&list_products(&pool, &merchant_id, false).await.unwrap()), ["cap"]);
+
+        assert_eq!(purge_archived_products(&pool, &other).await.unwrap(), (0, vec![]));
+        assert_eq!(purge_archived_products(&pool, &merchant_id).await.unwrap(), (1, vec!["mug.png".to_string()]));
+        assert!(get_product(&pool, &mug.id).await.unwrap().is_none());
+        assert!(get_product(&pool, &tee.id).await.unwrap().is_some());
+        assert!(list_images(&pool, &mug.id).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,197 @@
+//! Checkout sessions: a buyer's cart for one product (variant, quantity, country, refund
+//! address, checkout field answers) kept server-side while they configure it, and turned
+//! into an invoice only when they pay. A session reserves no diversifier index and gives
+//! the scanner nothing to watch, so carts that are abandoned cost a row until they expire.
+//! The session id is the buyer's bearer secret and is stored hashed like checkout tokens.
+
+use chrono::{Duration, Utc};
+use sqlx::SqlitePool;
+
+use crate::merchants::hash_key;
+
+pub const SESSION_TTL_HOURS: i64 = 24;
+/// Sessions one IP can open per hour.
+const MAX_SESSIONS_PER_IP_PER_HOUR: i64 = 60;
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct CheckoutSession {
+    pub product_id: String,
+    pub merchant_id: String,
+    pub variant: Option<String>,
+    pub quantity: i64,
+    pub country: Option<String>,
+    pub refund_address: Option<String>,
+    pub display_currency: Option<String>,
+    pub locale: Option<String>,
+    /// Answers to the product's checkout fields, sealed like an invoice's.
+    pub custom_fields: Option<String>,
+    /// `open`, `paying` while its invoice is being created, or `converted`.
+    pub status: String,
+    pub invoice_id: Option<String>,
+    pub expires_at: String,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl CheckoutSession {
+    pub fn cart(&self) -> Cart {
+        Cart {
+            variant: self.variant.clone(),
+            quantity: self.quantity,
+            country: self.country.clone(),
+            refund_address: self.refund_address.clone(),
+            display_currency: self.display_currency.clone(),
+            locale: self.locale.clone(),
+            custom_fields: self.custom_fields.clone(),
+        }
+    }
+}
+
+/// The buyer's choices, already validated against the product.
+#[derive(Debug, Clone, Default)]
+pub struct Cart {
+    pub variant: Option<String>,
+    pub quantity: i64,
+    pub country: Option<String>,
+    pub refund_address: Option<String>,
+    pub display_currency: Option<String>,
+    pub locale: Option<String>,
+    pub custom_fields: Option<String>,
+}
+
+const COLUMNS: &str = "product_id, merchant_id, variant, quantity, country, refund_address, display_currency,
+     locale, custom_fields, status, invoice_id, expires_at, created_at, updated_at";
+
+fn generate_id() -> String {
+    let bytes: [u8; 32] = rand::random();
+    format!("cpay_cs_{}", hex::encode(bytes))
+}
+
+fn now() -> String {
+    Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string()
+}
+
+/// Open a session for `product_id` and return its id, or `None` once the IP has had its
+/// hourly share.
+pub async fn create(
+    pool: &SqlitePool,
+    product_id: &str,
+    merchant_id: &str,
+    client_ip: Option<&str>,
+    cart: &Cart,
+) -> sqlx::Result<Option<String>> {
+    let mut tx = crate::db::begin_write(pool).await?;
+    let opened: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM checkout_sessions WHERE client_ip IS ?
+         AND created_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now', '-1 hour')"
+    )
+    .bind(client_ip)
+    .fetch_one(&mut *tx)
+    .await?;
+    if opened >= MAX_SESSIONS_PER_IP_PER_HOUR {
+        return Ok(None);
+    }
+
+    let id = generate_id();
+    let created_at = now();
+    let expires_at = (Utc::now() + Duration::hours(SESSION_TTL_HOURS))
+        .format("%Y-%m-%dT%H:%M:%SZ")
+        .to_string();
+    sqlx::query(
+        "INSERT INTO checkout_sessions (id_hash, product_id, merchant_id, client_ip, variant, quantity, country,
+         refund_address, display_currency, locale, custom_fields, expires_at, created_at, updated_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(hash_key(&id))
+    .bind(product_id)
+    .bind(merchant_id)
+    .bind(client_ip)
+    .bind(&cart.variant)
+    .bind(cart.quantity)
+    .bind(&cart.country)
+    .bind(&cart.refund_address)
+    .bind(&cart.display_currency)
+    .bind(&cart.locale)
+    .bind(&cart.custom_fields)
+    .bind(&expires_at)
+    .bind(&created_at)
+    .bind(&created_at)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(Some(id))
+}
+
+/// The session, unless it is unknown or expired.
+pub async fn get(pool: &SqlitePool, id: &str) -> sqlx::Result<Option<CheckoutSession>> {
+    sqlx::query_as::<_, CheckoutSession>(&format!(
+        "SELECT {COLUMNS} FROM checkout_sessions
+         WHERE id_hash = ? AND expires_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now')"
+    ))
+    .bind(hash_key(id))
+    .fetch_optional(pool)
+    .await
+}
+
+/// Replace the cart of an open session. False once it is being paid, converted or expired.
+pub async fn update(pool: &SqlitePool, id: &str, cart: &Cart) -> sqlx::Result<bool> {
+    let updated = sqlx::query(
+        "UPDATE checkout_sessions SET variant = ?, quantity = ?, country = ?, refund_address = ?,
+         display_currency = ?, locale = ?, custom_fields = ?, updated_at = ?
+         WHERE id_hash = ? AND status = 'open' AND expires_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now')"
+    )
+    .bind(&cart.variant)
+    .bind(cart.quantity)
+    .bind(&cart.country)
+    .bind(&cart.refund_address)
+    .bind(&cart.display_currency)
+    .bind(&cart.locale)
+    .bind(&cart.custom_fields)
+    .bind(now())
+    .bind(hash_key(id))
+    .execute(pool)
+    .await?
+    .rows_affected() > 0;
+    Ok(updated)
+}
+
+/// Move an open session to `paying`, so two pay requests cannot both create an invoice.
+pub async fn begin_payment(pool: &SqlitePool, id: &str) -> sqlx::Result<bool> {
+    let claimed = sqlx::query(
+        "UPDATE checkout_sessions SET status = 'paying', updated_at = ?
+         WHERE id_hash = ? AND status = 'open' AND expires_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now')"
+    )
+    .bind(now())
+    .bind(hash_key(id))
+    .execute(pool)
+    .await?
+    .rows_affected() > 0;
+    Ok(claimed)
+}
+
+/// Record the invoice a session was paid with.
+pub async fn finish_payment(pool: &SqlitePool, id: &str, invoice_id: &str) -> sqlx::Result<()> {
+    sqlx::query(
+        "UPDATE checkout_sessions SET status = 'converted', invoice_id = ?, updated_at = ?
+         WHERE id_hash = ? AND status = 'paying'"
+    )
+    .bind(invoice_id)
+    .bind(now())
+    .bind(hash_key(id))
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Reopen a session whose invoice could not be created, so the buyer can retry.
+pub async fn abandon_payment(pool: &SqlitePool, id: &str) -> sqlx::Result<()> {
+    sqlx::query(
+        "UPDATE checkout_sessions SET status = 'open', updated_at = ? WHERE id_hash = ? AND status = 'paying'"
+    )
+    .bind(now())
+    .bind(hash_key(id))
+    .execute(pool)
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,132 @@
+//! Checkout tokens: `GET /api/products/{id}/public` hands one out and `POST /api/checkout`
+//! requires it, so invoices for a product can only be created by someone who loaded it.
+//! A token is random, stored hashed, bound to its product and the IP it was issued to,
+//! and good for a few invoices within half an hour. Issuing and redeeming are capped
+//! per IP and redeeming per product too, which keeps a bot from creating invoices (and
+//! burning diversified addresses) without limit.
+
+use chrono::{Duration, Utc};
+use sqlx::SqlitePool;
+
+use crate::config::Config;
+use crate::merchants::hash_key;
+
+const TOKEN_TTL_MINUTES: i64 = 30;
+/// Invoices one token can create (a buyer retrying or changing the variant).
+pub const MAX_INVOICES_PER_TOKEN: i64 = 3;
+/// Tokens one IP can be issued per hour.
+const MAX_TOKENS_PER_IP_PER_HOUR: i64 = 60;
+
+pub struct IssuedToken {
+    pub token: String,
+    pub expires_at: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Redemption {
+    Accepted,
+    /// Unknown, expired, for another product, or issued to another IP.
+    Invalid,
+    /// The token has created `MAX_INVOICES_PER_TOKEN` invoices.
+    Exhausted,
+    /// The IP has created its `CHECKOUT_IP_LIMIT_PER_HOUR` invoices.
+    IpLimited,
+    /// The product has had its `CHECKOUT_PRODUCT_LIMIT_PER_HOUR` checkouts.
+    ProductLimited,
+}
+
+fn generate_token() -> String {
+    let bytes: [u8; 32] = rand::random();
+    format!("cpay_ct_{}", hex::encode(bytes))
+}
+
+/// Issue a token for `product_id`, or `None` once the IP has had its hourly share.
+pub async fn issue(pool: &SqlitePool, product_id: &str, client_ip: Option<&str>) -> sqlx::Result<Option<IssuedToken>> {
+    let mut tx = crate::db::begin_write(pool).await?;
+    let issued: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM checkout_tokens WHERE client_ip IS ?
+         AND created_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now', '-1 hour')"
+    )
+    .bind(client_ip)
+    .fetch_one(&mut *tx)
+    .await?;
+    if issued >= MAX_TOKENS_PER_IP_PER_HOUR {
+        return Ok(None);
+    }
+
+    let token = generate_token();
+    let expires_at = (Utc::now() + Duration::minutes(TOKEN_TTL_MINUTES))
+        .format("%Y-%m-%dT%H:%M:%SZ")
+        .to_string();
+    sqlx::query(
+        "INSERT INTO checkout_tokens (token_hash, product_id, client_ip, expires_at) VALUES (?, ?, ?, ?)"
+    )
+    .bind(hash_key(&token))
+    .bind(product_id)
+    .bind(client_ip)
+    .bind(&expires_at)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(Some(IssuedToken { token, expires_at }))
+}
+
+/// Count one checkout against `token` if it may create another invoice for `product_id`
+/// within the hourly per-IP and per-product limits.
+pub async fn redeem(
+    pool: &SqlitePool,
+    config: &Config,
+    token: &str,
+    product_id: &str,
+    client_ip: Option<&str>,
+) -> sqlx::Result<Redemption> {
+    let mut tx = crate::db::begin_write(pool).await?;
+    let row: Option<(i64,)> = sqlx::query_as(
+        "SELECT invoices_created FROM checkout_tokens
+         WHERE token_hash = ? AND product_id = ? AND client_ip IS ?
+         AND expires_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now')"
+    )
+    .bind(hash_key(token))
+    .bind(product_id)
+    .bind(client_ip)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let Some((invoices_created,)) = row else {
+        return Ok(Redemption::Invalid);
+    };
+    if invoices_created >= MAX_INVOICES_PER_TOKEN {
+        return Ok(Redemption::Exhausted);
+    }
+
+    let ip_checkouts: i64 = sqlx::query_scalar(
+        "SELECT COALESCE(SUM(invoices_created), 0) FROM checkout_tokens WHERE client_ip IS ?
+         AND created_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now', '-1 hour')"
+    )
+    .bind(client_ip)
+    .fetch_one(&mut *tx)
+    .await?;
+    if ip_checkouts >= config.checkout_ip_limit_per_hour {
+        return Ok(Redemption::IpLimited);
+    }
+
+    if config.checkout_product_limit_per_hour > 0 {
+        let product_checkouts: i64 = sqlx::query_scalar(
+            "SELECT COALESCE(SUM(invoices_created), 0) FROM checkout_tokens WHERE product_id = ?
+             AND created_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now', '-1 hour')"
+        )
+        .bind(product_id)
+        .fetch_one(&mut *tx)
+        .await?;
+        if product_checkouts >= config.checkout_product_limit_per_hour {
+            return Ok(Redemption::ProductLimited);
+        }
+    }
+
+    sqlx::query("UPDATE checkout_tokens SET invoices_created = invoices_created + 1 WHERE token_hash = ?")
+        .bind(hash_key(token))
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(Redemption::Accepted)
+}
@@ -0,0 +1,160 @@
+use std::time::Instant;
+
+use actix_web::body::{to_bytes, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use actix_web::middleware::Next;
+use actix_web::{HttpMessage, HttpRequest};
+use tracing::Instrument;
+use uuid::Uuid;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Merchant resolved by a handler's auth logic, picked up by the access log.
+#[derive(Clone, Debug)]
+struct MerchantTag(String);
+
+/// Record which merchant a request was authenticated as, so the access log can include it.
+pub fn tag_merchant(req: &HttpRequest, merchant_id: &str) {
+    req.extensions_mut().insert(MerchantTag(merchant_id.to_string()));
+}
+
+/// Accept a caller-supplied ID only if it is short and unambiguous in logs.
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Access-log middleware: assigns an X-Request-Id (or keeps a valid incoming one),
+/// runs the request inside a `request` span carrying it, so every log line the handler
+/// emits can be correlated, emits one structured event per request, echoes the ID in the
+/// response header, and adds `request_id` to JSON error bodies so merchants can quote it
+/// in support requests.
+pub async fn middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| is_valid_request_id(v))
+        .map(String::from)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let span = tracing::info_span!("request", request_id = %request_id);
+    handle(req, next, request_id).instrument(span).await
+}
+
+async fn handle(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+    request_id: String,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let method = req.method().to_string();
+    let path = req.path().to_string();
+    let start = Instant::now();
+
+    // Errors that escape the service stack are rendered by actix itself; we can only log them.
+    let res = match next.call(req).await {
+        Ok(res) => res.map_into_boxed_body(),
+        Err(e) => {
+            let status = e.as_response_error().status_code().as_u16();
+            let duration_ms = start.elapsed().as_millis() as u64;
+            tracing::warn!(method = %method, path = %path, status, duration_ms, error = %e, "Request failed");
+            return Err(e);
+        }
+    };
+
+    let status = res.status().as_u16();
+    let duration_ms = start.elapsed().as_millis() as u64;
+    let merchant_id = res.request().extensions().get::<MerchantTag>().map(|m| m.0.clone());
+
+    if status >= 500 {
+        tracing::warn!(method = %method, path = %path, status, duration_ms, merchant_id = ?merchant_id, "Request failed");
+    } else {
+        tracing::info!(method = %method, path = %path, status, duration_ms, merchant_id = ?merchant_id, "Request completed");
+    }
+
+    let mut res = if status >= 400 && is_json(&res) {
+        attach_request_id(res, &request_id).await
+    } else {
+        res
+    };
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        res.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+    Ok(res)
+}
+
+fn is_json(res: &ServiceResponse<BoxBody>) -> bool {
+    res.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|ct| ct.starts_with("application/json"))
+}
+
+/// Rewrite a JSON object error body to include the request ID.
+async fn attach_request_id(res: ServiceResponse<BoxBody>, request_id: &str) -> ServiceResponse<BoxBody> {
+    let (http_req, http_res) = res.into_parts();
+    let (head, body) = http_res.into_parts();
+
+    let bytes = match to_bytes(body).await {
+        Ok(b) => b,
+        Err(_) => return ServiceResponse::new(http_req, head.set_body(BoxBody::new(()))),
+    };
+
+    let new_body = match serde_json::from_slice::<serde_json::Value>(&bytes) {
+        Ok(serde_json::Value::Object(mut obj)) => {
+            obj.insert("request_id".into(), serde_json::Value::String(request_id.to_string()));
+            serde_json::Value::Object(obj).to_string().into_bytes()
+        }
+        _ => bytes.to_vec(),
+    };
+
+    let mut http_res = head.set_body(BoxBody::new(new_body));
+    http_res.headers_mut().remove(actix_web::http::header::CONTENT_LENGTH);
+    ServiceResponse::new(http_req, http_res)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{middleware::from_fn, test, web, App, HttpResponse};
+
+    /// Answers with the name of the span it runs in.
+    async fn current_span() -> HttpResponse {
+        let name = tracing::Span::current().metadata().map(|m| m.name()).unwrap_or("none");
+        HttpResponse::Ok().body(name)
+    }
+
+    async fn not_found() -> HttpResponse {
+        HttpResponse::NotFound().json(serde_json::json!({ "error": "Not found" }))
+    }
+
+    #[actix_web::test]
+    async fn test_request_id_is_echoed_and_spans_the_handler() {
+        // Spans are only entered when a subscriber wants them.
+        let _guard = tracing::subscriber::set_default(tracing_subscriber::registry());
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(middleware))
+                .route("/span", web::get().to(current_span))
+                .route("/missing", web::get().to(not_found)),
+        )
+        .await;
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/span").insert_header((REQUEST_ID_HEADER, "abc-123")).to_request()).await;
+        assert_eq!(res.headers().get(REQUEST_ID_HEADER).unwrap(), "abc-123");
+        assert_eq!(test::read_body(res).await, "request");
+
+        let res = test::call_service(&app, test::TestRequest::get().uri("/missing").insert_header((REQUEST_ID_HEADER, "bad id!")).to_request()).await;
+        let id = res.headers().get(REQUEST_ID_HEADER).unwrap().to_str().unwrap().to_string();
+        assert!(Uuid::parse_str(&id).is_ok());
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["request_id"], id.as_str());
+    }
+}
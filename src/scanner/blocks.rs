@@ -1,5 +1,7 @@
 use serde::Deserialize;
 
+use super::cipherscan::{CipherScan, Endpoint};
+
 #[derive(Debug, Deserialize)]
 struct BlockchainInfoResponse {
     blocks: Option<u64>,
@@ -7,48 +9,41 @@ struct BlockchainInfoResponse {
 }
 
 /// Gets the current chain tip height from CipherScan API.
-pub async fn get_chain_height(
-    http: &reqwest::Client,
-    api_url: &str,
-) -> anyhow::Result<u64> {
-    let url = format!("{}/api/blockchain-info", api_url);
-    let resp: BlockchainInfoResponse = http.get(&url).send().await?.json().await?;
+pub async fn get_chain_height(cipherscan: &CipherScan) -> anyhow::Result<u64> {
+    let resp: BlockchainInfoResponse = cipherscan.get_json(Endpoint::BlockchainInfo, "/api/blockchain-info").await?;
 
     resp.blocks
         .or(resp.headers)
         .ok_or_else(|| anyhow::anyhow!("No block height in response"))
 }
 
-/// Fetches transaction IDs from a range of blocks.
+/// Fetches transaction IDs from a range of blocks, paired with the height they were mined at.
+/// A block that can't be fetched fails the whole range, so the caller retries it rather
+/// than skipping past its payments.
 pub async fn fetch_block_txids(
-    http: &reqwest::Client,
-    api_url: &str,
+    cipherscan: &CipherScan,
     start_height: u64,
     end_height: u64,
-) -> anyhow::Result<Vec<String>> {
+) -> anyhow::Result<Vec<(String, u64)>> {
     let mut all_txids = Vec::new();
 
     for height in start_height..=end_height {
-        let url = format!("{}/api/block/{}", api_url, height);
-        let resp: serde_json::Value = match http.get(&url).send().await {
-            Ok(r) => r.json().await?,
-            Err(e) => {
-                tracing::warn!(height, error = %e, "Failed to fetch block");
-                continue;
-            }
-        };
+        let resp: serde_json::Value = cipherscan
+            .get_json(Endpoint::Block, &format!("/api/block/{}", height))
+            .await
+            .map_err(|e| anyhow::anyhow!("block {}: {}", height, e))?;
 
         // Extract txids from block response
         if let Some(txs) = resp["transactions"].as_array() {
             for tx in txs {
                 if let Some(txid) = tx["txid"].as_str() {
-                    all_txids.push(txid.to_string());
+                    all_txids.push((txid.to_string(), height));
                 }
             }
         } else if let Some(txs) = resp["tx"].as_array() {
             for tx in txs {
                 if let Some(txid) = tx.as_str() {
-                    all_txids.push(txid.to_string());
+                    all_txids.push((txid.to_string(), height));
                 }
             }
         }
@@ -57,19 +52,103 @@ pub async fn fetch_block_txids(
     Ok(all_txids)
 }
 
+/// A transaction that has been included in a block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxConfirmation {
+    /// Height of the containing block, when the API reports it.
+    pub block_height: Option<u64>,
+    /// Hash of the containing block, when the API reports it.
+    pub block_hash: Option<String>,
+}
+
+impl TxConfirmation {
+    /// Whether `other`, from a second source, puts the transaction in the same block: the
+    /// heights must both be known and equal, and the hashes equal wherever both are given.
+    fn agrees_with(&self, other: &TxConfirmation) -> bool {
+        let same_height = self.block_height.is_some() && self.block_height == other.block_height;
+        let same_hash = match (&self.block_hash, &other.block_hash) {
+            (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+            _ => true,
+        };
+        same_height && same_hash
+    }
+}
+
+/// Where `/api/tx/{txid}` says the transaction was mined, or `None` while it is unmined.
+async fn fetch_confirmation(cipherscan: &CipherScan, txid: &str) -> anyhow::Result<Option<TxConfirmation>> {
+    let resp: serde_json::Value = cipherscan.get_json(Endpoint::Tx, &format!("/api/tx/{}", txid)).await?;
+    Ok(parse_confirmation(&resp))
+}
+
+fn parse_confirmation(resp: &serde_json::Value) -> Option<TxConfirmation> {
+    // If the tx has a block_height field, it's confirmed
+    let block_height = resp["block_height"].as_u64().or_else(|| resp["blockHeight"].as_u64());
+    let block_hash = resp["block_hash"].as_str().or_else(|| resp["blockHash"].as_str()).map(str::to_string);
+    let confirmed = block_height.is_some()
+        || resp["confirmations"].as_u64().is_some_and(|c| c >= 1);
+
+    confirmed.then_some(TxConfirmation { block_height, block_hash })
+}
+
 /// Checks if a transaction has been confirmed (included in a block).
+/// Returns `None` while the transaction is still unmined, or while the verification
+/// source (if configured) does not yet agree on the block it was mined in.
 pub async fn check_tx_confirmed(
-    http: &reqwest::Client,
-    api_url: &str,
+    cipherscan: &CipherScan,
     txid: &str,
-) -> anyhow::Result<bool> {
-    let url = format!("{}/api/tx/{}", api_url, txid);
-    let resp: serde_json::Value = http.get(&url).send().await?.json().await?;
+) -> anyhow::Result<Option<TxConfirmation>> {
+    let Some(confirmation) = fetch_confirmation(cipherscan, txid).await? else {
+        return Ok(None);
+    };
+    Ok(corroborate(cipherscan, txid, &confirmation).await.then_some(confirmation))
+}
 
-    // If the tx has a block_height field, it's confirmed
-    let confirmed = resp["block_height"].as_u64().is_some()
-        || resp["blockHeight"].as_u64().is_some()
-        || resp["confirmations"].as_u64().map_or(false, |c| c >= 1);
+/// Without a verification source, true. With one, true only if it independently reports
+/// `txid` mined in the same block. A source that is down or disagrees defers the
+/// confirmation to a later scan rather than failing it: the payment is still there.
+pub async fn corroborate(cipherscan: &CipherScan, txid: &str, confirmation: &TxConfirmation) -> bool {
+    let Some(verifier) = cipherscan.verifier() else {
+        return true;
+    };
+    match fetch_confirmation(verifier, txid).await {
+        Ok(Some(other)) if confirmation.agrees_with(&other) => true,
+        Ok(other) => {
+            tracing::warn!(
+                txid,
+                height = ?confirmation.block_height,
+                hash = ?confirmation.block_hash,
+                verifier_height = ?other.as_ref().and_then(|o| o.block_height),
+                verifier_hash = ?other.as_ref().and_then(|o| o.block_hash.as_deref()),
+                "Confirmation sources disagree, deferring"
+            );
+            false
+        }
+        Err(e) => {
+            tracing::debug!(txid, error = %e, "Confirmation verification failed, deferring");
+            false
+        }
+    }
+}
 
-    Ok(confirmed)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_confirmations_must_name_the_same_block() {
+        let primary = parse_confirmation(&serde_json::json!({ "block_height": 100, "block_hash": "00ab" })).unwrap();
+        let same = parse_confirmation(&serde_json::json!({ "blockHeight": 100, "blockHash": "00AB" })).unwrap();
+        let no_hash = parse_confirmation(&serde_json::json!({ "block_height": 100 })).unwrap();
+        let other_hash = parse_confirmation(&serde_json::json!({ "block_height": 100, "block_hash": "00cd" })).unwrap();
+        let other_height = parse_confirmation(&serde_json::json!({ "block_height": 101, "block_hash": "00ab" })).unwrap();
+        let depth_only = parse_confirmation(&serde_json::json!({ "confirmations": 3 })).unwrap();
+
+        assert!(primary.agrees_with(&same));
+        assert!(primary.agrees_with(&no_hash));
+        assert!(!primary.agrees_with(&other_hash));
+        assert!(!primary.agrees_with(&other_height));
+        assert!(!primary.agrees_with(&depth_only));
+        assert!(!depth_only.agrees_with(&depth_only));
+        assert!(parse_confirmation(&serde_json::json!({ "confirmations": 0 })).is_none());
+    }
 }
@@ -0,0 +1,334 @@
+//! CipherScan API client. Every call to the explorer goes through here: each request has
+//! a timeout and is retried with exponential backoff on timeouts, connection errors, 5xx
+//! and 429. After `BREAKER_THRESHOLD` calls in a row fail their retries the circuit opens
+//! and calls fail fast for `BREAKER_COOLDOWN`, so a flapping upstream costs one log line
+//! instead of one per scan cycle; calls after the cooldown probe it again.
+//!
+//! Latency and failures are counted per endpoint and reported by `GET /api/admin/cipherscan`.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::config::Config;
+
+/// Consecutive failed calls that open the circuit.
+const BREAKER_THRESHOLD: u32 = 5;
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(30);
+const BACKOFF_BASE: Duration = Duration::from_millis(250);
+const BACKOFF_MAX: Duration = Duration::from_secs(4);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Endpoint {
+    BlockchainInfo,
+    Block,
+    Mempool,
+    Tx,
+    RawTx,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CipherScanError {
+    #[error("CipherScan unavailable, circuit open for {0}s")]
+    CircuitOpen(u64),
+    #[error("CipherScan returned HTTP {0}")]
+    Status(u16),
+    #[error("CipherScan request failed: {0}")]
+    Transport(#[from] reqwest::Error),
+}
+
+impl CipherScanError {
+    /// Worth another attempt: the upstream may answer next time.
+    fn is_transient(&self) -> bool {
+        match self {
+            CipherScanError::CircuitOpen(_) => false,
+            CipherScanError::Status(code) => *code >= 500 || *code == 429,
+            CipherScanError::Transport(e) => !e.is_decode(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct EndpointStats {
+    pub requests: u64,
+    pub failures: u64,
+    pub retries: u64,
+    pub avg_latency_ms: u64,
+    pub max_latency_ms: u64,
+    #[serde(skip)]
+    total_latency_ms: u64,
+}
+
+impl EndpointStats {
+    fn record(&mut self, latency: Duration, ok: bool) {
+        let ms = latency.as_millis() as u64;
+        self.requests += 1;
+        if !ok {
+            self.failures += 1;
+        }
+        self.total_latency_ms += ms;
+        self.avg_latency_ms = self.total_latency_ms / self.requests;
+        self.max_latency_ms = self.max_latency_ms.max(ms);
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Status {
+    /// `closed`, `open` or `half_open` (cooldown over, next call probes the upstream).
+    pub circuit: &'static str,
+    pub consecutive_failures: u32,
+    pub endpoints: BTreeMap<Endpoint, EndpointStats>,
+    /// The confirmation verification source, when one is configured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verifier: Option<Box<Status>>,
+}
+
+#[derive(Default)]
+struct Breaker {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl Breaker {
+    /// Seconds left if calls should fail fast.
+    fn check(&self, now: Instant) -> Option<u64> {
+        self.open_until
+            .filter(|until| *until > now)
+            .map(|until| (until - now).as_secs().max(1))
+    }
+
+    fn on_success(&mut self) {
+        if self.open_until.is_some() {
+            tracing::info!("CipherScan reachable again, circuit closed");
+        }
+        self.consecutive_failures = 0;
+        self.open_until = None;
+    }
+
+    fn on_failure(&mut self, now: Instant) {
+        self.consecutive_failures += 1;
+        // A failed probe after the cooldown reopens at once.
+        if self.consecutive_failures >= BREAKER_THRESHOLD || self.open_until.is_some() {
+            if self.check(now).is_none() {
+                tracing::warn!(
+                    failures = self.consecutive_failures,
+                    cooldown_secs = BREAKER_COOLDOWN.as_secs(),
+                    "CipherScan failing, circuit open"
+                );
+            }
+            self.open_until = Some(now + BREAKER_COOLDOWN);
+        }
+    }
+}
+
+struct Inner {
+    http: reqwest::Client,
+    base_url: String,
+    retries: u32,
+    breaker: Mutex<Breaker>,
+    stats: Mutex<BTreeMap<Endpoint, EndpointStats>>,
+    /// Second source that confirmations are checked against, with its own circuit.
+    verifier: Option<CipherScan>,
+}
+
+/// Shared handle; clones use the same circuit and metrics.
+#[derive(Clone)]
+pub struct CipherScan {
+    inner: Arc<Inner>,
+}
+
+/// One client per network the server scans, each with its own circuit and metrics.
+#[derive(Clone)]
+pub struct CipherScans {
+    primary: String,
+    clients: Vec<(String, CipherScan)>,
+}
+
+impl CipherScans {
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
+        let clients = config
+            .networks
+            .iter()
+            .map(|n| {
+                let verifier = match &n.confirmation_verify_url {
+                    Some(url) => Some(CipherScan::new(config, url, None)?),
+                    None => None,
+                };
+                Ok((n.network.clone(), CipherScan::new(config, &n.cipherscan_api_url, verifier)?))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { primary: config.network.clone(), clients })
+    }
+
+    /// The client for `network`, or the primary network's if it is not served.
+    pub fn for_network(&self, network: &str) -> &CipherScan {
+        let found = self.clients.iter().find(|(n, _)| n == network)
+            .or_else(|| self.clients.iter().find(|(n, _)| *n == self.primary));
+        &found.expect("primary network has a client").1
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &CipherScan)> {
+        self.clients.iter().map(|(n, c)| (n.as_str(), c))
+    }
+
+    /// The primary network's status, with every network's under `networks`.
+    pub fn status(&self) -> NetworksStatus {
+        NetworksStatus {
+            primary: self.for_network(&self.primary).status(),
+            networks: self.iter().map(|(n, c)| (n.to_string(), c.status())).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct NetworksStatus {
+    #[serde(flatten)]
+    pub primary: Status,
+    pub networks: BTreeMap<String, Status>,
+}
+
+impl CipherScan {
+    pub fn new(config: &Config, base_url: &str, verifier: Option<CipherScan>) -> anyhow::Result<Self> {
+        let timeout = Duration::from_secs(config.cipherscan_timeout_secs.max(1));
+        let http = reqwest::Client::builder()
+            .timeout(timeout)
+            .connect_timeout(timeout.min(Duration::from_secs(5)))
+            .build()?;
+        Ok(Self {
+            inner: Arc::new(Inner {
+                http,
+                base_url: base_url.trim_end_matches('/').to_string(),
+                retries: config.cipherscan_retries,
+                breaker: Mutex::new(Breaker::default()),
+                stats: Mutex::new(BTreeMap::new()),
+                verifier,
+            }),
+        })
+    }
+
+    /// The independent source confirmations must agree with, if any.
+    pub fn verifier(&self) -> Option<&CipherScan> {
+        self.inner.verifier.as_ref()
+    }
+
+    /// GET `path` (e.g. `/api/mempool`) and parse the JSON body.
+    pub async fn get_json<T: serde::de::DeserializeOwned>(
+        &self,
+        endpoint: Endpoint,
+        path: &str,
+    ) -> Result<T, CipherScanError> {
+        if let Some(secs) = self.inner.breaker.lock().unwrap().check(Instant::now()) {
+            return Err(CipherScanError::CircuitOpen(secs));
+        }
+
+        let url = format!("{}{}", self.inner.base_url, path);
+        let mut attempt = 0;
+        loop {
+            let started = Instant::now();
+            let result = self.attempt(&url).await;
+            self.record(endpoint, started.elapsed(), result.is_ok(), attempt > 0);
+
+            match result {
+                Ok(body) => {
+                    self.inner.breaker.lock().unwrap().on_success();
+                    return Ok(body);
+                }
+                Err(e) if e.is_transient() && attempt < self.inner.retries => {
+                    let delay = backoff(attempt);
+                    tracing::debug!(path, attempt, error = %e, delay_ms = delay.as_millis() as u64, "Retrying CipherScan request");
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    // Only an unreachable upstream counts towards the circuit; a 404 is an answer.
+                    if e.is_transient() {
+                        self.inner.breaker.lock().unwrap().on_failure(Instant::now());
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    async fn attempt<T: serde::de::DeserializeOwned>(&self, url: &str) -> Result<T, CipherScanError> {
+        let resp = self.inner.http.get(url).send().await?;
+        if !resp.status().is_success() {
+            return Err(CipherScanError::Status(resp.status().as_u16()));
+        }
+        Ok(resp.json().await?)
+    }
+
+    fn record(&self, endpoint: Endpoint, latency: Duration, ok: bool, retry: bool) {
+        let mut stats = self.inner.stats.lock().unwrap();
+        let entry = stats.entry(endpoint).or_default();
+        entry.record(latency, ok);
+        if retry {
+            entry.retries += 1;
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        let breaker = self.inner.breaker.lock().unwrap();
+        let circuit = match breaker.open_until {
+            None => "closed",
+            Some(until) if until > Instant::now() => "open",
+            Some(_) => "half_open",
+        };
+        Status {
+            circuit,
+            consecutive_failures: breaker.consecutive_failures,
+            endpoints: self.inner.stats.lock().unwrap().clone(),
+            verifier: self.verifier().map(|v| Box::new(v.status())),
+        }
+    }
+}
+
+/// `BACKOFF_BASE` doubled per attempt up to `BACKOFF_MAX`, with up to 50% jitter so
+/// concurrent fetches don't retry in lockstep.
+fn backoff(attempt: u32) -> Duration {
+    let delay = BACKOFF_BASE.saturating_mul(1 << attempt.min(10)).min(BACKOFF_MAX);
+    delay + delay.mul_f64(rand::random::<f64>() * 0.5)
+}
+
+/// True if `e` (from a scan) only says the circuit is open, which was logged when it opened.
+pub fn is_circuit_open(e: &anyhow::Error) -> bool {
+    matches!(e.downcast_ref::<CipherScanError>(), Some(CipherScanError::CircuitOpen(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_breaker_opens_and_recovers() {
+        let now = Instant::now();
+        let mut breaker = Breaker::default();
+        for _ in 1..BREAKER_THRESHOLD {
+            breaker.on_failure(now);
+        }
+        assert!(breaker.check(now).is_none());
+        breaker.on_failure(now);
+        assert!(breaker.check(now).is_some());
+
+        // After the cooldown one call probes; a failure reopens straight away.
+        let later = now + BREAKER_COOLDOWN;
+        assert!(breaker.check(later).is_none());
+        breaker.on_failure(later);
+        assert!(breaker.check(later).is_some());
+
+        breaker.on_success();
+        assert!(breaker.check(later).is_none());
+        assert_eq!(breaker.consecutive_failures, 0);
+    }
+
+    #[test]
+    fn test_backoff_is_bounded() {
+        assert!(backoff(0) >= BACKOFF_BASE && backoff(0) <= BACKOFF_BASE.mul_f64(1.5));
+        assert!(backoff(30) <= BACKOFF_MAX.mul_f64(1.5));
+        assert!(CipherScanError::Status(503).is_transient());
+        assert!(!CipherScanError::Status(404).is_transient());
+    }
+}
@@ -1,9 +1,9 @@
 use anyhow::Result;
 use std::io::Cursor;
 
-use zcash_note_encryption::try_note_decryption;
+use zcash_note_encryption::{try_note_decryption, try_output_recovery_with_ovk};
 use orchard::{
-    keys::{FullViewingKey, Scope, PreparedIncomingViewingKey},
+    keys::{FullViewingKey, IncomingViewingKey, Scope, PreparedIncomingViewingKey},
     note_encryption::OrchardDomain,
 };
 use zcash_address::unified::{Container, Encoding, Fvk, Ufvk};
@@ -18,25 +18,49 @@ pub const SLIPPAGE_TOLERANCE: f64 = 0.995;
 pub const DUST_THRESHOLD_FRACTION: f64 = 0.01; // 1% of invoice price
 pub const DUST_THRESHOLD_MIN_ZATOSHIS: i64 = 10_000; // 0.0001 ZEC absolute floor
 
+/// A payment too small to count towards an invoice: under both dust thresholds, unless it
+/// covers the whole (tiny) price.
+pub fn is_dust(amount_zatoshis: i64, price_zatoshis: i64) -> bool {
+    let dust_min = std::cmp::max(
+        (price_zatoshis as f64 * DUST_THRESHOLD_FRACTION) as i64,
+        DUST_THRESHOLD_MIN_ZATOSHIS,
+    );
+    amount_zatoshis < dust_min && amount_zatoshis < price_zatoshis
+}
+
+#[derive(Clone)]
 pub struct DecryptedOutput {
     pub memo: String,
     pub amount_zec: f64,
     pub amount_zatoshis: u64,
     pub recipient_raw: [u8; 43],
+    /// Position of the Orchard action in the transaction's bundle.
+    pub action_index: usize,
 }
 
 /// Pre-computed keys for a merchant, avoiding repeated curve operations.
 pub struct CachedKeys {
     pub pivk_external: PreparedIncomingViewingKey,
     pub pivk_internal: PreparedIncomingViewingKey,
+    ivk_external: IncomingViewingKey,
+}
+
+impl CachedKeys {
+    /// Diversifier index of an external address of this key, or None for change
+    /// (internal) and other keys' addresses. Index 0 is the wallet's base address.
+    pub fn external_index(&self, recipient_raw: &[u8; 43]) -> Option<u64> {
+        let address = Option::<orchard::Address>::from(orchard::Address::from_raw_address_bytes(recipient_raw))?;
+        self.ivk_external.diversifier_index(&address).and_then(|j| u64::try_from(j).ok())
+    }
 }
 
 /// Prepare cached keys from a UFVK string. Call once per merchant, reuse across scans.
 pub fn prepare_keys(ufvk_str: &str) -> Result<CachedKeys> {
     let fvk = parse_orchard_fvk(ufvk_str)?;
-    let pivk_external = PreparedIncomingViewingKey::new(&fvk.to_ivk(Scope::External));
+    let ivk_external = fvk.to_ivk(Scope::External);
+    let pivk_external = PreparedIncomingViewingKey::new(&ivk_external);
     let pivk_internal = PreparedIncomingViewingKey::new(&fvk.to_ivk(Scope::Internal));
-    Ok(CachedKeys { pivk_external, pivk_internal })
+    Ok(CachedKeys { pivk_external, pivk_internal, ivk_external })
 }
 
 /// Trial-decrypt all Orchard outputs using pre-computed keys (fast path).
@@ -60,7 +84,7 @@ pub fn try_decrypt_with_keys(raw_hex: &str, keys: &CachedKeys) -> Result<Vec<Dec
     let actions: Vec<_> = bundle.actions().iter().collect();
     let mut outputs = Vec::new();
 
-    for action in &actions {
+    for (action_index, action) in actions.iter().enumerate() {
         let domain = OrchardDomain::for_action(*action);
 
         for pivk in [&keys.pivk_external, &keys.pivk_internal] {
@@ -94,6 +118,7 @@ pub fn try_decrypt_with_keys(raw_hex: &str, keys: &CachedKeys) -> Result<Vec<Dec
                     amount_zec,
                     amount_zatoshis,
                     recipient_raw,
+                    action_index,
                 });
             }
         }
@@ -109,7 +134,7 @@ pub(crate) fn parse_orchard_fvk(ufvk_str: &str) -> Result<FullViewingKey> {
 
     let orchard_fvk_bytes = ufvk.items().iter().find_map(|fvk| {
         match fvk {
-            Fvk::Orchard(data) => Some(data.clone()),
+            Fvk::Orchard(data) => Some(*data),
             _ => None,
         }
     }).ok_or_else(|| anyhow::anyhow!("No Orchard FVK found in UFVK"))?;
@@ -118,6 +143,81 @@ pub(crate) fn parse_orchard_fvk(ufvk_str: &str) -> Result<FullViewingKey> {
         .ok_or_else(|| anyhow::anyhow!("Failed to parse Orchard FVK from bytes"))
 }
 
+/// A P2PKH output of a transaction.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TransparentOutput {
+    /// Key hash the output pays.
+    pub receiver: [u8; 20],
+    pub amount_zatoshis: u64,
+    pub vout: usize,
+}
+
+/// P2PKH outputs of a raw transaction. Transparent outputs are public, so no key is
+/// needed; used to match payments to TEX addresses.
+pub fn transparent_outputs(raw_hex: &str) -> Vec<TransparentOutput> {
+    let Ok(tx_bytes) = hex::decode(raw_hex) else {
+        return vec![];
+    };
+    let mut cursor = Cursor::new(&tx_bytes[..]);
+    let Ok(tx) = Transaction::read(&mut cursor, zcash_primitives::consensus::BranchId::Nu5) else {
+        return vec![];
+    };
+    let Some(bundle) = tx.transparent_bundle() else {
+        return vec![];
+    };
+
+    bundle.vout.iter().enumerate().filter_map(|(vout, out)| {
+        // OP_DUP OP_HASH160 <20 bytes> OP_EQUALVERIFY OP_CHECKSIG
+        let script = &out.script_pubkey().0 .0;
+        match script.as_slice() {
+            [0x76, 0xa9, 0x14, hash @ .., 0x88, 0xac] if hash.len() == 20 => {
+                let mut receiver = [0u8; 20];
+                receiver.copy_from_slice(hash);
+                Some(TransparentOutput { receiver, amount_zatoshis: out.value().into_u64(), vout })
+            }
+            _ => None,
+        }
+    }).collect()
+}
+
+/// What anyone can read from a transaction without keys, for explaining short payments.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TxShape {
+    /// Miner fee, from the value balances. None when the transaction spends transparent
+    /// inputs, whose values are not in the transaction itself.
+    pub fee_zatoshis: Option<i64>,
+    /// Orchard actions (padded to at least two), Sapling outputs and transparent outputs.
+    pub outputs: i64,
+}
+
+/// Fee and output count of a raw transaction, or None if it does not parse.
+pub fn tx_shape(raw_hex: &str) -> Option<TxShape> {
+    let tx_bytes = hex::decode(raw_hex).ok()?;
+    let tx = Transaction::read(&tx_bytes[..], zcash_primitives::consensus::BranchId::Nu5).ok()?;
+
+    let transparent = tx.transparent_bundle();
+    let orchard = tx.orchard_bundle();
+    let sapling = tx.sapling_bundle();
+
+    let outputs = orchard.map_or(0, |b| b.actions().len())
+        + sapling.map_or(0, |b| b.shielded_outputs().len())
+        + transparent.map_or(0, |b| b.vout.len());
+
+    let spends_transparent = transparent.is_some_and(|b| !b.vin.is_empty());
+    let fee = (!spends_transparent).then(|| {
+        let shielded = orchard.map_or(0, |b| i64::from(*b.value_balance()))
+            + sapling.map_or(0, |b| i64::from(*b.value_balance()));
+        let transparent_out: i64 = transparent
+            .map_or(0, |b| b.vout.iter().map(|o| o.value().into_u64() as i64).sum());
+        shielded - transparent_out
+    });
+
+    Some(TxShape {
+        fee_zatoshis: fee.filter(|f| *f >= 0),
+        outputs: outputs as i64,
+    })
+}
+
 /// Trial-decrypt all Orchard outputs in a raw transaction hex using the
 /// provided UFVK. Returns the first successfully decrypted output with
 /// its memo text and amount.
@@ -157,7 +257,7 @@ pub fn try_decrypt_all_outputs(raw_hex: &str, ufvk_str: &str) -> Result<Vec<Decr
     let actions: Vec<_> = bundle.actions().iter().collect();
     let mut outputs = Vec::new();
 
-    for action in &actions {
+    for (action_index, action) in actions.iter().enumerate() {
         let domain = OrchardDomain::for_action(*action);
 
         for scope in [Scope::External, Scope::Internal] {
@@ -194,6 +294,7 @@ pub fn try_decrypt_all_outputs(raw_hex: &str, ufvk_str: &str) -> Result<Vec<Decr
                     amount_zec,
                     amount_zatoshis,
                     recipient_raw,
+                    action_index,
                 });
             }
         }
@@ -202,6 +303,57 @@ pub fn try_decrypt_all_outputs(raw_hex: &str, ufvk_str: &str) -> Result<Vec<Decr
     Ok(outputs)
 }
 
+/// Recover Orchard outputs *sent* by the wallet behind this UFVK, using its outgoing
+/// viewing key. Used to verify merchant refunds, whose outputs are encrypted to the buyer.
+/// Returns nothing if the sender used a different wallet or discarded the OVK.
+pub fn try_recover_outgoing(raw_hex: &str, ufvk_str: &str) -> Result<Vec<DecryptedOutput>> {
+    let tx_bytes = hex::decode(raw_hex)?;
+    if tx_bytes.len() < 4 {
+        return Ok(vec![]);
+    }
+
+    let fvk = parse_orchard_fvk(ufvk_str)?;
+
+    let mut cursor = Cursor::new(&tx_bytes[..]);
+    let tx = match Transaction::read(&mut cursor, zcash_primitives::consensus::BranchId::Nu5) {
+        Ok(tx) => tx,
+        Err(_) => return Ok(vec![]),
+    };
+
+    let bundle = match tx.orchard_bundle() {
+        Some(b) => b,
+        None => return Ok(vec![]),
+    };
+
+    let mut outputs = Vec::new();
+    for (action_index, action) in bundle.actions().iter().enumerate() {
+        let domain = OrchardDomain::for_action(action);
+
+        for scope in [Scope::External, Scope::Internal] {
+            let ovk = fvk.to_ovk(scope);
+            if let Some((note, _recipient, memo)) = try_output_recovery_with_ovk(
+                &domain,
+                &ovk,
+                action,
+                action.cv_net(),
+                &action.encrypted_note().out_ciphertext,
+            ) {
+                let amount_zatoshis = note.value().inner();
+                outputs.push(DecryptedOutput {
+                    memo: memo_bytes_to_text(&memo).unwrap_or_default(),
+                    amount_zec: amount_zatoshis as f64 / 100_000_000.0,
+                    amount_zatoshis,
+                    recipient_raw: note.recipient().to_raw_address_bytes(),
+                    action_index,
+                });
+                break;
+            }
+        }
+    }
+
+    Ok(outputs)
+}
+
 /// Returns just the memo string (convenience wrapper).
 #[allow(dead_code)]
 pub fn try_decrypt_memo(raw_hex: &str, ufvk: &str) -> Result<Option<String>> {
@@ -212,7 +364,6 @@ pub fn try_decrypt_memo(raw_hex: &str, ufvk: &str) -> Result<Option<String>> {
 }
 
 /// Extracts memo text from raw memo bytes (512 bytes in Zcash).
-#[allow(dead_code)]
 fn memo_bytes_to_text(memo_bytes: &[u8]) -> Option<String> {
     if memo_bytes.is_empty() {
         return None;
@@ -235,6 +386,29 @@ fn memo_bytes_to_text(memo_bytes: &[u8]) -> Option<String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::invoices::{matching, test_invoice, Invoice};
+    use crate::scanner::fixtures::{self, Output};
+
+    const MERCHANT: u8 = 1;
+    const OTHER: u8 = 2;
+
+    fn receiver(wallet: u8, index: u32) -> [u8; 43] {
+        fixtures::test_fvk(wallet).address_at(index, Scope::External).to_raw_address_bytes()
+    }
+
+    fn decrypt(outputs: &[Output], wallet: u8) -> Vec<DecryptedOutput> {
+        let raw = hex::encode(fixtures::transaction(outputs, 7));
+        let keys = prepare_keys(&fixtures::test_ufvk(wallet)).unwrap();
+        try_decrypt_with_keys(&raw, &keys).unwrap()
+    }
+
+    fn invoice(memo_code: &str, wallet: u8, index: u32) -> Invoice {
+        Invoice {
+            memo_code: memo_code.into(),
+            orchard_receiver_hex: Some(hex::encode(receiver(wallet, index))),
+            ..test_invoice()
+        }
+    }
 
     #[test]
     fn test_memo_bytes_to_text() {
@@ -256,4 +430,139 @@ mod tests {
         let result = try_decrypt_memo("deadbeef", "uviewtest1dummy").unwrap();
         assert_eq!(result, None);
     }
+
+    #[test]
+    fn test_decrypt_fixture_payment() {
+        let tx = fixtures::transaction(&[Output::to_wallet(MERCHANT, 3, 25_000_000, "CP-00000001")], 7);
+        let ufvk = fixtures::test_ufvk(MERCHANT);
+        let raw = hex::encode(&tx);
+
+        let keys = prepare_keys(&ufvk).unwrap();
+        let outputs = try_decrypt_with_keys(&raw, &keys).unwrap();
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].amount_zatoshis, 25_000_000);
+        assert_eq!(outputs[0].amount_zec, 0.25);
+        assert_eq!(outputs[0].memo, "CP-00000001");
+        assert_eq!(outputs[0].recipient_raw, receiver(MERCHANT, 3));
+        assert_eq!(keys.external_index(&outputs[0].recipient_raw), Some(3));
+        let internal = fixtures::test_fvk(MERCHANT).address_at(3u32, Scope::Internal).to_raw_address_bytes();
+        assert_eq!(keys.external_index(&internal), None);
+
+        // The uncached path and the memo helper agree, and fixtures are deterministic.
+        assert_eq!(try_decrypt_all_outputs(&raw, &ufvk).unwrap().len(), 1);
+        assert_eq!(try_decrypt_memo(&raw, &ufvk).unwrap().as_deref(), Some("CP-00000001"));
+        assert_eq!(tx, fixtures::transaction(&[Output::to_wallet(MERCHANT, 3, 25_000_000, "CP-00000001")], 7));
+        assert_eq!(fixtures::txid(&tx).len(), 64);
+    }
+
+    #[test]
+    fn test_decrypt_payment_to_invoice_address() {
+        let ufvk = fixtures::test_ufvk(MERCHANT);
+        let derived = crate::addresses::derive_invoice_address(&ufvk, 4).unwrap();
+        let outputs = decrypt(&[Output::to_address(&derived.ua_string, 100_000, "")], MERCHANT);
+        assert_eq!(hex::encode(outputs[0].recipient_raw), derived.orchard_receiver_hex);
+    }
+
+    #[test]
+    fn test_decrypt_only_own_outputs() {
+        let outputs = [
+            Output::to_wallet(OTHER, 0, 1_000_000, "CP-00000002"),
+            Output::to_wallet(MERCHANT, 0, 2_000_000, ""),
+        ];
+        let mine = decrypt(&outputs, MERCHANT);
+        assert_eq!(mine.len(), 1);
+        assert_eq!(mine[0].amount_zatoshis, 2_000_000);
+        assert_eq!(mine[0].memo, "");
+        assert_eq!(decrypt(&outputs, OTHER)[0].memo, "CP-00000002");
+        assert!(decrypt(&outputs, 3).is_empty());
+
+        // Change sent to the wallet's internal address is seen through the internal IVK.
+        let change = fixtures::test_fvk(MERCHANT).address_at(0u32, Scope::Internal);
+        let outputs = decrypt(&[Output::new(change, 5_000, "")], MERCHANT);
+        assert_eq!(outputs[0].amount_zatoshis, 5_000);
+    }
+
+    #[test]
+    fn test_decrypt_memo_parsing() {
+        let memo_of = |memo: Vec<u8>| {
+            let output = Output { memo, ..Output::to_wallet(MERCHANT, 0, 100_000, "") };
+            decrypt(&[output], MERCHANT).remove(0).memo
+        };
+        assert_eq!(memo_of(b"Order CP-00000001 thanks".to_vec()), "Order CP-00000001 thanks");
+        // "No memo" marker and non-UTF-8 bytes read as empty; text stops at the padding.
+        assert_eq!(memo_of(vec![0xF6]), "");
+        assert_eq!(memo_of(vec![0xFF, 0xFE, 0x01]), "");
+        assert_eq!(memo_of(b"CP-00000001\0trailing".to_vec()), "CP-00000001");
+    }
+
+    #[test]
+    fn test_transparent_outputs() {
+        let tx = fixtures::transparent_transaction(&[([7; 20], 150_000), ([9; 20], 2_000)]);
+        let outputs = transparent_outputs(&hex::encode(tx));
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(outputs[1], TransparentOutput { receiver: [9; 20], amount_zatoshis: 2_000, vout: 1 });
+
+        let invoices = [Invoice { transparent_receiver_hex: Some(hex::encode([9u8; 20])), ..test_invoice() }];
+        assert!(matching::find_by_transparent(&invoices, &hex::encode(outputs[1].receiver)).is_some());
+        assert!(matching::find_by_transparent(&invoices, &hex::encode(outputs[0].receiver)).is_none());
+
+        // Shielded-only transactions have none.
+        let tx = fixtures::transaction(&[Output::to_wallet(MERCHANT, 0, 100_000, "")], 1);
+        assert!(transparent_outputs(&hex::encode(tx)).is_empty());
+    }
+
+    #[test]
+    fn test_tx_shape() {
+        let outputs = [Output::to_wallet(MERCHANT, 0, 100_000, ""), Output::to_wallet(2, 0, 5_000, "")];
+        let tx = fixtures::transaction_with_fee(&outputs, 1, 15_000);
+        assert_eq!(tx_shape(&hex::encode(tx)), Some(TxShape { fee_zatoshis: Some(15_000), outputs: 2 }));
+
+        // Transparent outputs count too; values that do not balance give no fee.
+        let tx = fixtures::transparent_transaction(&[([7; 20], 150_000)]);
+        assert_eq!(tx_shape(&hex::encode(tx)), Some(TxShape { fee_zatoshis: None, outputs: 1 }));
+        assert_eq!(tx_shape("00"), None);
+    }
+
+    #[test]
+    fn test_dust_thresholds() {
+        // 1% of the price when that exceeds the absolute floor.
+        assert!(is_dust(249_999, 25_000_000));
+        assert!(!is_dust(250_000, 25_000_000));
+        // Otherwise the floor applies, unless the payment covers a tiny price.
+        assert!(is_dust(9_999, 500_000));
+        assert!(!is_dust(10_000, 500_000));
+        assert!(!is_dust(5_000, 5_000));
+
+        let paid = decrypt(&[Output::to_wallet(MERCHANT, 0, 9_999, "CP-00000001")], MERCHANT);
+        assert!(is_dust(paid[0].amount_zatoshis as i64, 500_000));
+    }
+
+    #[test]
+    fn test_address_match_beats_memo() {
+        let invoices = [invoice("CP-AAAAAAAA", MERCHANT, 1), invoice("CP-BBBBBBBB", MERCHANT, 2)];
+        let matched = |output: Output| {
+            let decrypted = decrypt(&[output], MERCHANT).remove(0);
+            let recipient_hex = hex::encode(decrypted.recipient_raw);
+            matching::find_matching_invoice(&invoices, &recipient_hex, &decrypted.memo)
+                .map(|i| i.memo_code.clone())
+        };
+
+        // Paid to A's address with B's memo: the address wins.
+        assert_eq!(matched(Output::to_wallet(MERCHANT, 1, 100_000, "CP-BBBBBBBB")).as_deref(), Some("CP-AAAAAAAA"));
+        // Unknown address (e.g. the wallet's default one): memo fallback.
+        assert_eq!(matched(Output::to_wallet(MERCHANT, 9, 100_000, "CP-BBBBBBBB")).as_deref(), Some("CP-BBBBBBBB"));
+        assert_eq!(matched(Output::to_wallet(MERCHANT, 9, 100_000, "")), None);
+
+        // The scanner's index agrees, and finds a memo code inside longer memo text.
+        let index = matching::PendingIndex::new(&invoices);
+        let indexed = |output: Output| {
+            let decrypted = decrypt(&[output], MERCHANT).remove(0);
+            let recipient_hex = hex::encode(decrypted.recipient_raw);
+            index.by_address(&recipient_hex).or_else(|| index.by_memo(&decrypted.memo))
+                .map(|i| i.memo_code.clone())
+        };
+        assert_eq!(indexed(Output::to_wallet(MERCHANT, 1, 100_000, "CP-BBBBBBBB")).as_deref(), Some("CP-AAAAAAAA"));
+        assert_eq!(indexed(Output::to_wallet(MERCHANT, 9, 100_000, "Order CP-BBBBBBBB, thanks")).as_deref(), Some("CP-BBBBBBBB"));
+        assert_eq!(indexed(Output::to_wallet(MERCHANT, 9, 100_000, "CP-CCCCCCCC")), None);
+    }
 }
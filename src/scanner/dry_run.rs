@@ -0,0 +1,179 @@
+//! Shadow scan: trial-decrypt recent blocks with a merchant's UFVK and report what it sees,
+//! so a merchant registering mid-operation can confirm the key matches their wallet before
+//! going live. Nothing found here is matched to invoices or recorded as a payment.
+
+use sqlx::SqlitePool;
+use uuid::Uuid;
+
+use super::cipherscan::CipherScan;
+use super::{blocks, decrypt, mempool};
+
+pub const DEFAULT_BLOCKS: u64 = 100;
+pub const MAX_BLOCKS: u64 = 1000;
+/// Outputs listed in the report; the totals count all of them.
+const MAX_SAMPLES: usize = 10;
+
+#[derive(Debug, Default)]
+struct Report {
+    from_height: u64,
+    to_height: u64,
+    txs_scanned: i64,
+    outputs_found: i64,
+    received_zatoshis: i64,
+    samples: Vec<serde_json::Value>,
+}
+
+/// Start a check of the last `blocks` blocks in the background. Replaces the merchant's
+/// previous result. Returns `None` while another check is still running.
+pub async fn start(
+    pool: &SqlitePool,
+    cipherscan: &CipherScan,
+    merchant_id: &str,
+    ufvk: &str,
+    blocks: u64,
+) -> anyhow::Result<Option<String>> {
+    let keys = decrypt::prepare_keys(ufvk)?;
+
+    let running: Option<(String,)> = sqlx::query_as(
+        "SELECT id FROM ufvk_checks WHERE merchant_id = ? AND status = 'running'
+         AND created_at > strftime('%Y-%m-%dT%H:%M:%SZ', 'now', '-1 hour')"
+    )
+    .bind(merchant_id)
+    .fetch_optional(pool)
+    .await?;
+    if running.is_some() {
+        return Ok(None);
+    }
+
+    sqlx::query("DELETE FROM ufvk_checks WHERE merchant_id = ?")
+        .bind(merchant_id)
+        .execute(pool)
+        .await?;
+    let id = Uuid::new_v4().to_string();
+    sqlx::query(
+        "INSERT INTO ufvk_checks (id, merchant_id, blocks, status) VALUES (?, ?, ?, 'running')"
+    )
+    .bind(&id)
+    .bind(merchant_id)
+    .bind(blocks as i64)
+    .execute(pool)
+    .await?;
+
+    let (pool, cipherscan, check_id) = (pool.clone(), cipherscan.clone(), id.clone());
+    tokio::spawn(async move {
+        let result = scan(&cipherscan, &keys, blocks).await;
+        if let Err(e) = finish(&pool, &check_id, result).await {
+            tracing::error!(check_id, error = %e, "Failed to store UFVK check result");
+        }
+    });
+
+    Ok(Some(id))
+}
+
+async fn scan(
+    cipherscan: &CipherScan,
+    keys: &decrypt::CachedKeys,
+    blocks: u64,
+) -> anyhow::Result<Report> {
+    let to_height = blocks::get_chain_height(cipherscan).await?;
+    let from_height = to_height.saturating_sub(blocks.saturating_sub(1));
+    let mut report = Report { from_height, to_height, ..Default::default() };
+
+    let txids = blocks::fetch_block_txids(cipherscan, from_height, to_height).await?;
+    for (txid, height) in &txids {
+        let raw_hex = match mempool::fetch_raw_tx(cipherscan, txid).await {
+            Ok(hex) => hex,
+            Err(_) => continue,
+        };
+        report.txs_scanned += 1;
+
+        for output in decrypt::try_decrypt_with_keys(&raw_hex, keys).unwrap_or_default() {
+            report.outputs_found += 1;
+            report.received_zatoshis += output.amount_zatoshis as i64;
+            if report.samples.len() < MAX_SAMPLES {
+                report.samples.push(serde_json::json!({
+                    "txid": txid,
+                    "block_height": height,
+                    "amount_zatoshis": output.amount_zatoshis,
+                    "memo": output.memo,
+                }));
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+async fn finish(pool: &SqlitePool, id: &str, result: anyhow::Result<Report>) -> anyhow::Result<()> {
+    match result {
+        Ok(report) => {
+            tracing::info!(check_id = id, outputs = report.outputs_found, "UFVK check finished");
+            sqlx::query(
+                "UPDATE ufvk_checks SET status = 'done', from_height = ?, to_height = ?,
+                 txs_scanned = ?, outputs_found = ?, received_zatoshis = ?, samples = ?,
+                 finished_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now')
+                 WHERE id = ?"
+            )
+            .bind(report.from_height as i64)
+            .bind(report.to_height as i64)
+            .bind(report.txs_scanned)
+            .bind(report.outputs_found)
+            .bind(report.received_zatoshis)
+            .bind(serde_json::Value::from(report.samples).to_string())
+            .bind(id)
+            .execute(pool)
+            .await?;
+        }
+        Err(e) => {
+            tracing::warn!(check_id = id, error = %e, "UFVK check failed");
+            sqlx::query(
+                "UPDATE ufvk_checks SET status = 'failed', error = ?,
+                 finished_at = strftime('%Y-%m-%dT%H:%M:%SZ', 'now')
+                 WHERE id = ?"
+            )
+            .bind(e.to_string())
+            .bind(id)
+            .execute(pool)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+type CheckRow = (
+    String, String, i64, Option<i64>, Option<i64>, i64, i64, i64,
+    Option<String>, Option<String>, String, Option<String>,
+);
+
+/// The merchant's latest check, as returned under `scan` by `GET /api/merchants/me/ufvk/check`.
+pub async fn latest(pool: &SqlitePool, merchant_id: &str) -> anyhow::Result<Option<serde_json::Value>> {
+    let row: Option<CheckRow> = sqlx::query_as(
+        "SELECT id, status, blocks, from_height, to_height, txs_scanned, outputs_found,
+                received_zatoshis, samples, error, created_at, finished_at
+         FROM ufvk_checks WHERE merchant_id = ? ORDER BY created_at DESC LIMIT 1"
+    )
+    .bind(merchant_id)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|r| {
+        let samples: serde_json::Value = r.8.as_deref()
+            .and_then(|s| serde_json::from_str(s).ok())
+            .unwrap_or_else(|| serde_json::json!([]));
+        serde_json::json!({
+            "id": r.0,
+            "status": r.1,
+            "blocks": r.2,
+            "from_height": r.3,
+            "to_height": r.4,
+            "txs_scanned": r.5,
+            "outputs_found": r.6,
+            "received_zatoshis": r.7,
+            "payments_seen": r.6 > 0,
+            "samples": samples,
+            "error": r.9,
+            "created_at": r.10,
+            "finished_at": r.11,
+        })
+    }))
+}
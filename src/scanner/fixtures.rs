@@ -0,0 +1,194 @@
+//! Deterministic Orchard test fixtures: viewing keys derived from fixed spending keys, and
+//! NU5 transactions whose outputs decrypt like real payments. Proofs and signatures are
+//! filler bytes; the scanner only trial-decrypts, it never verifies.
+//!
+//! Self-contained so the integration tests can include it too (`tests/common`).
+
+use orchard::keys::{FullViewingKey, OutgoingViewingKey, Scope, SpendingKey};
+use orchard::note::{ExtractedNoteCommitment, Nullifier, RandomSeed, Rho};
+use orchard::note_encryption::{OrchardDomain, OrchardNoteEncryption};
+use orchard::value::{NoteValue, ValueCommitTrapdoor, ValueCommitment};
+use orchard::{Address, Note};
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use zcash_address::unified::{self, Container, Encoding, Fvk, Ufvk};
+use zcash_note_encryption::Domain;
+use zcash_primitives::consensus::BranchId;
+use zcash_primitives::transaction::Transaction;
+use zcash_protocol::consensus::NetworkType;
+
+const TX_VERSION_V5: u32 = 5 | (1 << 31);
+const V5_VERSION_GROUP_ID: u32 = 0x26A7_270A;
+const NU5_BRANCH_ID: u32 = 0xC2D6_D0B4;
+
+/// Orchard full viewing key of the test wallet numbered `seed`.
+pub fn test_fvk(seed: u8) -> FullViewingKey {
+    let sk = Option::<SpendingKey>::from(SpendingKey::from_bytes([seed; 32]))
+        .expect("valid spending key seed");
+    FullViewingKey::from(&sk)
+}
+
+/// Testnet UFVK string (Orchard only) of the test wallet numbered `seed`.
+pub fn test_ufvk(seed: u8) -> String {
+    Ufvk::try_from_items(vec![Fvk::Orchard(test_fvk(seed).to_bytes())])
+        .unwrap()
+        .encode(&NetworkType::Test)
+}
+
+/// Testnet unified address (Orchard only) of test wallet `seed` at diversifier `index`.
+pub fn test_address(seed: u8, index: u32) -> String {
+    let raw = test_fvk(seed).address_at(index, Scope::External).to_raw_address_bytes();
+    unified::Address::try_from_items(vec![unified::Receiver::Orchard(raw)])
+        .unwrap()
+        .encode(&NetworkType::Test)
+}
+
+/// One Orchard output to include in a transaction.
+pub struct Output {
+    pub recipient: Address,
+    pub zatoshis: u64,
+    /// Raw memo field, zero-padded to 512 bytes.
+    pub memo: Vec<u8>,
+    /// Sender's OVK; outputs encrypted to it can be recovered as outgoing.
+    pub ovk: Option<OutgoingViewingKey>,
+}
+
+impl Output {
+    pub fn new(recipient: Address, zatoshis: u64, memo: &str) -> Self {
+        Self { recipient, zatoshis, memo: memo.as_bytes().to_vec(), ovk: None }
+    }
+
+    /// Payment to the Orchard receiver of a unified address.
+    pub fn to_address(address: &str, zatoshis: u64, memo: &str) -> Self {
+        Self::new(orchard_receiver(address), zatoshis, memo)
+    }
+
+    /// Output of test wallet `seed`'s external address at diversifier `index`.
+    pub fn to_wallet(seed: u8, index: u32, zatoshis: u64, memo: &str) -> Self {
+        Self::new(test_fvk(seed).address_at(index, Scope::External), zatoshis, memo)
+    }
+}
+
+/// The Orchard receiver of a unified address.
+pub fn orchard_receiver(address: &str) -> Address {
+    let (_, ua) = unified::Address::decode(address).expect("unified address");
+    let raw = ua.items().into_iter().find_map(|r| match r {
+        unified::Receiver::Orchard(raw) => Some(raw),
+        _ => None,
+    }).expect("address has an Orchard receiver");
+    Address::from_raw_address_bytes(&raw).unwrap()
+}
+
+/// Canonical Pallas base element, usable as a nullifier or rho.
+fn base_element(rng: &mut StdRng) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    rng.fill_bytes(&mut bytes);
+    bytes[31] &= 0x3f;
+    bytes
+}
+
+/// Serialized action (without its spend auth signature) carrying `output`.
+fn action(output: &Output, rng: &mut StdRng) -> Vec<u8> {
+    let nf = base_element(rng);
+    let rho = Rho::from_bytes(&nf).unwrap();
+    let note = loop {
+        let mut seed = [0u8; 32];
+        rng.fill_bytes(&mut seed);
+        let Some(rseed) = Option::<RandomSeed>::from(RandomSeed::from_bytes(seed, &rho)) else { continue };
+        if let Some(note) = Option::<Note>::from(Note::from_parts(
+            output.recipient,
+            NoteValue::from_raw(output.zatoshis),
+            rho,
+            rseed,
+        )) {
+            break note;
+        }
+    };
+
+    let mut memo = [0u8; 512];
+    memo[..output.memo.len()].copy_from_slice(&output.memo);
+    let cv = ValueCommitment::derive(
+        NoteValue::from_raw(0) - NoteValue::from_raw(output.zatoshis),
+        ValueCommitTrapdoor::from_bytes([0; 32]).unwrap(),
+    );
+    let cmx = ExtractedNoteCommitment::from(note.commitment());
+    let encryptor = OrchardNoteEncryption::new(output.ovk.clone(), note, memo);
+
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&cv.to_bytes());
+    bytes.extend_from_slice(&Nullifier::from_bytes(&nf).unwrap().to_bytes());
+    // Any valid point will do for rk.
+    bytes.extend_from_slice(&cv.to_bytes());
+    bytes.extend_from_slice(&cmx.to_bytes());
+    bytes.extend_from_slice(&OrchardDomain::epk_bytes(encryptor.epk()).0);
+    bytes.extend_from_slice(&encryptor.encrypt_note_plaintext());
+    bytes.extend_from_slice(&encryptor.encrypt_outgoing_plaintext(&cv, &cmx, rng));
+    bytes
+}
+
+fn write_compact_size(bytes: &mut Vec<u8>, n: usize) {
+    assert!(n < 0xfd);
+    bytes.push(n as u8);
+}
+
+/// A v5 transaction whose only contents are Orchard actions carrying `outputs`. The same
+/// outputs and `seed` always give the same bytes.
+pub fn transaction(outputs: &[Output], seed: u64) -> Vec<u8> {
+    transaction_with_fee(outputs, seed, 0)
+}
+
+/// `transaction`, with a value balance paying `fee` zatoshis to the miner.
+pub fn transaction_with_fee(outputs: &[Output], seed: u64, fee: i64) -> Vec<u8> {
+    assert!(!outputs.is_empty());
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut tx = Vec::new();
+    tx.extend_from_slice(&TX_VERSION_V5.to_le_bytes());
+    tx.extend_from_slice(&V5_VERSION_GROUP_ID.to_le_bytes());
+    tx.extend_from_slice(&NU5_BRANCH_ID.to_le_bytes());
+    tx.extend_from_slice(&0u32.to_le_bytes()); // lock_time
+    tx.extend_from_slice(&0u32.to_le_bytes()); // expiry_height
+    tx.extend_from_slice(&[0, 0]); // transparent inputs, outputs
+    tx.extend_from_slice(&[0, 0]); // Sapling spends, outputs
+
+    write_compact_size(&mut tx, outputs.len());
+    for output in outputs {
+        tx.extend_from_slice(&action(output, &mut rng));
+    }
+    tx.push(0x03); // spends and outputs enabled
+    tx.extend_from_slice(&fee.to_le_bytes()); // value balance
+    tx.extend_from_slice(&[0; 32]); // anchor
+    write_compact_size(&mut tx, 64);
+    tx.extend_from_slice(&[0; 64]); // proof
+    for _ in outputs {
+        tx.extend_from_slice(&[0; 64]); // spend auth signature
+    }
+    tx.extend_from_slice(&[0; 64]); // binding signature
+    tx
+}
+
+/// A v5 transaction with only transparent P2PKH outputs, as (key hash, zatoshis).
+pub fn transparent_transaction(outputs: &[([u8; 20], u64)]) -> Vec<u8> {
+    let mut tx = Vec::new();
+    tx.extend_from_slice(&TX_VERSION_V5.to_le_bytes());
+    tx.extend_from_slice(&V5_VERSION_GROUP_ID.to_le_bytes());
+    tx.extend_from_slice(&NU5_BRANCH_ID.to_le_bytes());
+    tx.extend_from_slice(&0u32.to_le_bytes()); // lock_time
+    tx.extend_from_slice(&0u32.to_le_bytes()); // expiry_height
+    tx.push(0); // transparent inputs
+    write_compact_size(&mut tx, outputs.len());
+    for (receiver, zatoshis) in outputs {
+        tx.extend_from_slice(&zatoshis.to_le_bytes());
+        write_compact_size(&mut tx, 25);
+        tx.extend_from_slice(&[0x76, 0xa9, 0x14]);
+        tx.extend_from_slice(receiver);
+        tx.extend_from_slice(&[0x88, 0xac]);
+    }
+    tx.extend_from_slice(&[0, 0]); // Sapling spends, outputs
+    tx.push(0); // Orchard actions
+    tx
+}
+
+/// Transaction ID as block explorers display it.
+pub fn txid(tx: &[u8]) -> String {
+    Transaction::read(tx, BranchId::Nu5).unwrap().txid().to_string()
+}
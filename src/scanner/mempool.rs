@@ -1,6 +1,8 @@
 use futures::future::join_all;
 use serde::Deserialize;
 
+use super::cipherscan::{CipherScan, Endpoint};
+
 const BATCH_SIZE: usize = 20;
 
 #[derive(Debug, Deserialize)]
@@ -14,12 +16,8 @@ struct MempoolTx {
 }
 
 /// Fetches current mempool transaction IDs from CipherScan API.
-pub async fn fetch_mempool_txids(
-    http: &reqwest::Client,
-    api_url: &str,
-) -> anyhow::Result<Vec<String>> {
-    let url = format!("{}/api/mempool", api_url);
-    let resp: MempoolResponse = http.get(&url).send().await?.json().await?;
+pub async fn fetch_mempool_txids(cipherscan: &CipherScan) -> anyhow::Result<Vec<String>> {
+    let resp: MempoolResponse = cipherscan.get_json(Endpoint::Mempool, "/api/mempool").await?;
 
     Ok(resp
         .transactions
@@ -30,13 +28,8 @@ pub async fn fetch_mempool_txids(
 }
 
 /// Fetches raw transaction hex from CipherScan API.
-pub async fn fetch_raw_tx(
-    http: &reqwest::Client,
-    api_url: &str,
-    txid: &str,
-) -> anyhow::Result<String> {
-    let url = format!("{}/api/tx/{}/raw", api_url, txid);
-    let resp: serde_json::Value = http.get(&url).send().await?.json().await?;
+pub async fn fetch_raw_tx(cipherscan: &CipherScan, txid: &str) -> anyhow::Result<String> {
+    let resp: serde_json::Value = cipherscan.get_json(Endpoint::RawTx, &format!("/api/tx/{}/raw", txid)).await?;
 
     resp["hex"]
         .as_str()
@@ -46,30 +39,12 @@ pub async fn fetch_raw_tx(
 
 /// Fetches raw transaction hex for multiple txids concurrently, in batches.
 /// Returns (txid, hex) pairs for successful fetches.
-pub async fn fetch_raw_txs_batch(
-    http: &reqwest::Client,
-    api_url: &str,
-    txids: &[String],
-) -> Vec<(String, String)> {
+pub async fn fetch_raw_txs_batch(cipherscan: &CipherScan, txids: &[String]) -> Vec<(String, String)> {
     let mut results = Vec::with_capacity(txids.len());
 
     for chunk in txids.chunks(BATCH_SIZE) {
-        let futures: Vec<_> = chunk.iter().map(|txid| {
-            let http = http.clone();
-            let url = format!("{}/api/tx/{}/raw", api_url, txid);
-            let txid = txid.clone();
-            async move {
-                let resp: Result<serde_json::Value, _> = async {
-                    Ok(http.get(&url).send().await?.json().await?)
-                }.await;
-
-                match resp {
-                    Ok(val) => val["hex"]
-                        .as_str()
-                        .map(|hex| (txid, hex.to_string())),
-                    Err::<_, anyhow::Error>(_) => None,
-                }
-            }
+        let futures: Vec<_> = chunk.iter().map(|txid| async move {
+            fetch_raw_tx(cipherscan, txid).await.ok().map(|hex| (txid.clone(), hex))
         }).collect();
 
         let batch_results = join_all(futures).await;
@@ -1,42 +1,104 @@
 pub mod mempool;
 pub mod blocks;
+pub mod cipherscan;
 pub mod decrypt;
+pub mod dry_run;
+pub mod proof;
+pub mod simulate;
+pub mod txcache;
+pub mod unmatched;
+#[cfg(test)]
+pub(crate) mod fixtures;
 
-use std::collections::HashMap;
-use std::sync::Arc;
-use std::time::Instant;
-use tokio::sync::RwLock;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
 use sqlx::SqlitePool;
 
 use crate::billing;
-use crate::config::Config;
+use crate::config::{Config, FeeCurrency};
 use crate::invoices;
 use crate::invoices::matching;
+use crate::invoices::pricing::PriceService;
+use crate::jobs;
 use crate::webhooks;
+use cipherscan::CipherScan;
 
+/// Transactions whose payments were applied, so neither pass applies them again.
 pub type SeenTxids = Arc<RwLock<HashMap<String, Instant>>>;
 
+/// Trial-decryption results of recent transactions, see `decrypt_cached`.
+pub type DecryptCache = Arc<Mutex<txcache::TxCache>>;
+
 const SEEN_TXID_TTL_SECS: u64 = 3600; // 1 hour
 const SEEN_TXID_EVICT_INTERVAL: u64 = 300; // run eviction every 5 minutes
+const DECRYPT_CACHE_CAPACITY: usize = 10_000;
+
+/// Unix time of the last mempool or block pass that completed, 0 before the first.
+static LAST_PASS: AtomicI64 = AtomicI64::new(0);
 
-/// Pre-computed decryption keys for all merchants, refreshed when the merchant set changes.
+fn record_pass() {
+    LAST_PASS.store(chrono::Utc::now().timestamp(), Ordering::Relaxed);
+}
+
+/// When the scanner last completed a pass, for stall alerts.
+pub fn last_pass() -> Option<chrono::DateTime<chrono::Utc>> {
+    match LAST_PASS.load(Ordering::Relaxed) {
+        0 => None,
+        ts => chrono::DateTime::from_timestamp(ts, 0),
+    }
+}
+
+/// Unix time of each network's last completed mempool pass.
+static MEMPOOL_PASSES: LazyLock<std::sync::Mutex<HashMap<String, i64>>> = LazyLock::new(Default::default);
+
+fn record_mempool_pass(network: &str) {
+    if let Ok(mut passes) = MEMPOOL_PASSES.lock() {
+        passes.insert(network.to_string(), chrono::Utc::now().timestamp());
+    }
+}
+
+/// When `network`'s mempool was last scanned, for the public status endpoint.
+pub fn last_mempool_pass(network: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    let ts = *MEMPOOL_PASSES.lock().ok()?.get(network)?;
+    chrono::DateTime::from_timestamp(ts, 0)
+}
+
+/// Pre-computed decryption keys for all merchants, refreshed when the merchant set changes,
+/// and for the operator's fee wallet that settlement invoices are paid to.
 struct KeyCache {
     keys: Vec<(String, decrypt::CachedKeys)>,
     merchant_ids: Vec<String>,
+    fee: Option<decrypt::CachedKeys>,
 }
 
-pub async fn run(config: Config, pool: SqlitePool, http: reqwest::Client) {
+/// Scan `network` for payments to its merchants' invoices, as the jobs
+/// `scanner.mempool.{network}`, `scanner.blocks.{network}` and `scanner.evict.{network}`.
+/// Started once per network the server serves; the primary network's block job also
+/// expires, requotes and settles invoices of every network.
+pub async fn start(
+    config: Config,
+    network: String,
+    pool: SqlitePool,
+    http: reqwest::Client,
+    cipherscan: CipherScan,
+    prices: PriceService,
+) {
     let seen_txids: SeenTxids = Arc::new(RwLock::new(HashMap::new()));
+    let decrypt_cache: DecryptCache = Arc::new(Mutex::new(txcache::TxCache::new(DECRYPT_CACHE_CAPACITY)));
+    let primary = network == config.network;
 
-    let persisted_height = crate::db::get_scanner_state(&pool, "last_height").await
+    let persisted_height = crate::db::get_scanner_state(&pool, &crate::db::height_key(&network)).await
         .and_then(|v| v.parse::<u64>().ok());
     if let Some(h) = persisted_height {
-        tracing::info!(height = h, "Resumed scanner from persisted block height");
+        tracing::info!(network, height = h, "Resumed scanner from persisted block height");
     }
     let last_height: Arc<RwLock<Option<u64>>> = Arc::new(RwLock::new(persisted_height));
 
     tracing::info!(
-        api = %config.cipherscan_api_url,
+        network,
         mempool_interval = config.mempool_poll_interval_secs,
         block_interval = config.block_poll_interval_secs,
         "Scanner started"
@@ -45,64 +107,108 @@ pub async fn run(config: Config, pool: SqlitePool, http: reqwest::Client) {
     let mempool_config = config.clone();
     let mempool_pool = pool.clone();
     let mempool_http = http.clone();
+    let mempool_cipherscan = cipherscan.clone();
     let mempool_seen = seen_txids.clone();
-
-    let mempool_handle = tokio::spawn(async move {
-        let mut key_cache: Option<KeyCache> = None;
-        let mut interval = tokio::time::interval(
-            std::time::Duration::from_secs(mempool_config.mempool_poll_interval_secs),
-        );
-        loop {
-            interval.tick().await;
-            if let Err(e) = scan_mempool(&mempool_config, &mempool_pool, &mempool_http, &mempool_seen, &mut key_cache).await {
-                tracing::error!(error = %e, "Mempool scan error");
+    let mempool_decrypted = decrypt_cache.clone();
+    let mempool_network = network.clone();
+    let mempool_keys: Arc<Mutex<Option<KeyCache>>> = Arc::default();
+    let every = Duration::from_secs(config.mempool_poll_interval_secs);
+    jobs::Job::new(format!("scanner.mempool.{}", network), every).logs_own_errors().spawn(move || {
+        let config = mempool_config.clone();
+        let pool = mempool_pool.clone();
+        let http = mempool_http.clone();
+        let cipherscan = mempool_cipherscan.clone();
+        let seen = mempool_seen.clone();
+        let decrypted = mempool_decrypted.clone();
+        let network = mempool_network.clone();
+        let keys = mempool_keys.clone();
+        async move {
+            let mut key_cache = keys.lock().await;
+            let scanned = scan_mempool(&config, &network, &pool, &http, &cipherscan, &seen, &decrypted, &mut key_cache).await;
+            match &scanned {
+                Ok(()) => {
+                    record_pass();
+                    record_mempool_pass(&network);
+                }
+                Err(e) => log_scan_error("Mempool scan error", e),
             }
 
-            if mempool_config.fee_enabled() {
-                let _ = billing::check_settlement_payments(&mempool_pool).await;
+            if primary && config.fee_enabled() {
+                let _ = billing::check_settlement_payments(&pool).await;
             }
+            scanned
         }
     });
 
     let block_config = config.clone();
     let block_pool = pool.clone();
     let block_http = http.clone();
+    let block_cipherscan = cipherscan.clone();
     let block_seen = seen_txids.clone();
+    let block_decrypted = decrypt_cache;
+    let block_network = network.clone();
+    let block_keys: Arc<Mutex<Option<KeyCache>>> = Arc::default();
+    let every = Duration::from_secs(config.block_poll_interval_secs);
+    jobs::Job::new(format!("scanner.blocks.{}", network), every).logs_own_errors().spawn(move || {
+        let config = block_config.clone();
+        let pool = block_pool.clone();
+        let http = block_http.clone();
+        let cipherscan = block_cipherscan.clone();
+        let seen = block_seen.clone();
+        let decrypted = block_decrypted.clone();
+        let network = block_network.clone();
+        let last_height = last_height.clone();
+        let prices = prices.clone();
+        let keys = block_keys.clone();
+        async move {
+            if primary {
+                if let Err(e) = requote_expired(&config, &pool, &http, &prices).await {
+                    tracing::error!(error = %e, "Requote error");
+                }
+                if let Err(e) = expire_invoices(&config, &pool, &http).await {
+                    tracing::error!(error = %e, "Expiry error");
+                }
+            }
+
+            let mut key_cache = keys.lock().await;
+            let scanned = scan_blocks(&config, &network, &pool, &http, &cipherscan, &seen, &decrypted, &last_height, &mut key_cache).await;
+            match &scanned {
+                Ok(()) => record_pass(),
+                Err(e) => log_scan_error("Block scan error", e),
+            }
 
-    let block_handle = tokio::spawn(async move {
-        let mut key_cache: Option<KeyCache> = None;
-        let mut interval = tokio::time::interval(
-            std::time::Duration::from_secs(block_config.block_poll_interval_secs),
-        );
-        loop {
-            interval.tick().await;
-            let _ = invoices::expire_old_invoices(&block_pool).await;
-
-            if let Err(e) = scan_blocks(&block_config, &block_pool, &block_http, &block_seen, &last_height, &mut key_cache).await {
-                tracing::error!(error = %e, "Block scan error");
+            let verified = verify_refunds(&config, &network, &pool, &http, &cipherscan).await;
+            if let Err(e) = &verified {
+                log_scan_error("Refund verification error", e);
             }
+            scanned.and(verified)
         }
     });
 
-    let evict_seen = seen_txids.clone();
-    let evict_handle = tokio::spawn(async move {
-        let mut interval = tokio::time::interval(
-            std::time::Duration::from_secs(SEEN_TXID_EVICT_INTERVAL),
-        );
-        loop {
-            interval.tick().await;
-            let cutoff = Instant::now() - std::time::Duration::from_secs(SEEN_TXID_TTL_SECS);
-            let mut set = evict_seen.write().await;
+    let every = Duration::from_secs(SEEN_TXID_EVICT_INTERVAL);
+    jobs::Job::new(format!("scanner.evict.{}", network), every).delay_first().spawn(move || {
+        let seen = seen_txids.clone();
+        async move {
+            let cutoff = Instant::now() - Duration::from_secs(SEEN_TXID_TTL_SECS);
+            let mut set = seen.write().await;
             let before = set.len();
             set.retain(|_, ts| *ts > cutoff);
             let evicted = before - set.len();
             if evicted > 0 {
                 tracing::debug!(evicted, remaining = set.len(), "Evicted stale seen_txids");
             }
+            Ok(())
         }
     });
+}
 
-    let _ = tokio::join!(mempool_handle, block_handle, evict_handle);
+/// An open circuit was logged once when it opened; repeating it every cycle is noise.
+fn log_scan_error(what: &str, e: &anyhow::Error) {
+    if cipherscan::is_circuit_open(e) {
+        tracing::debug!(error = %e, "{}", what);
+    } else {
+        tracing::error!(error = %e, "{}", what);
+    }
 }
 
 /// Build or refresh the PIVK cache when the merchant set changes.
@@ -111,7 +217,8 @@ pub async fn run(config: Config, pool: SqlitePool, http: reqwest::Client) {
 fn refresh_key_cache<'a>(
     cache: &'a mut Option<KeyCache>,
     merchants: &[crate::merchants::Merchant],
-) -> &'a [(String, decrypt::CachedKeys)] {
+    fee_ufvk: Option<&str>,
+) -> &'a KeyCache {
     let current_ids: Vec<String> = merchants.iter().map(|m| m.id.clone()).collect();
 
     let needs_refresh = match cache {
@@ -127,76 +234,264 @@ fn refresh_key_cache<'a>(
                 Err(e) => tracing::warn!(merchant_id = %m.id, error = %e, "Failed to prepare PIVK"),
             }
         }
+        let fee = fee_ufvk.and_then(|ufvk| match decrypt::prepare_keys(ufvk) {
+            Ok(k) => Some(k),
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to prepare fee wallet PIVK");
+                None
+            }
+        });
         tracing::info!(merchants = keys.len(), "PIVK cache refreshed");
-        *cache = Some(KeyCache { merchant_ids: current_ids, keys });
+        *cache = Some(KeyCache { merchant_ids: current_ids, keys, fee });
     }
 
-    &cache.as_ref().unwrap().keys
+    cache.as_ref().unwrap()
+}
+
+/// The keys a pass trial-decrypts with, and their merchants as the decryption cache
+/// records them.
+struct PassKeys<'a> {
+    keys: Vec<(&'a str, &'a decrypt::CachedKeys)>,
+    merchants: txcache::KeySet,
 }
 
-/// Fire a webhook without blocking the scan loop.
-fn spawn_webhook(pool: &SqlitePool, http: &reqwest::Client, invoice_id: &str, event: &str, txid: &str, encryption_key: &str) {
-    let pool = pool.clone();
-    let http = http.clone();
-    let invoice_id = invoice_id.to_string();
-    let event = event.to_string();
-    let txid = txid.to_string();
-    let enc_key = encryption_key.to_string();
-    tokio::spawn(async move {
-        if let Err(e) = webhooks::dispatch(&pool, &http, &invoice_id, &event, &txid, &enc_key).await {
-            tracing::error!(invoice_id, event, error = %e, "Async webhook failed");
+impl KeyCache {
+    fn merchant_keys(&self, merchant_id: &str) -> Option<&decrypt::CachedKeys> {
+        self.keys.iter().find(|(id, _)| id == merchant_id).map(|(_, k)| k)
+    }
+
+    /// Every merchant's key, for the block pass: a payment to a merchant with nothing
+    /// pending still belongs in its unmatched inbox.
+    fn all_keys(&self) -> PassKeys<'_> {
+        self.keys_for(|_| true)
+    }
+
+    /// Keys for the mempool pass, which runs every few seconds: those of the merchants
+    /// with an invoice in `pending` (diversified addresses tie every payment to its
+    /// merchant's key, so the other keys cannot match one) and of strict address mode
+    /// merchants, who are emailed about an unmatched payment as soon as it is seen. Other
+    /// merchants' unmatched payments wait for the block pass.
+    fn keys_for_pending(&self, pending: &[invoices::Invoice], strict: &HashSet<String>) -> PassKeys<'_> {
+        let merchants: HashSet<&str> = pending.iter().map(|i| i.merchant_id.as_str()).collect();
+        let pass = self.keys_for(|merchant_id| merchants.contains(merchant_id) || strict.contains(merchant_id));
+        tracing::debug!(active = pass.keys.len(), skipped = self.keys.len() - pass.keys.len(), "Decryption keys for pending invoices");
+        pass
+    }
+
+    fn keys_for(&self, include: impl Fn(&str) -> bool) -> PassKeys<'_> {
+        let keys: Vec<_> = self.keys
+            .iter()
+            .filter(|(merchant_id, _)| include(merchant_id))
+            .map(|(merchant_id, k)| (merchant_id.as_str(), k))
+            .collect();
+        PassKeys {
+            merchants: Arc::new(keys.iter().map(|(merchant_id, _)| merchant_id.to_string()).collect()),
+            keys,
         }
+    }
+}
+
+/// Trial-decrypt a transaction with the pass's keys. Keys the transaction was already
+/// decrypted with (by the mempool pass, for a transaction now mined) are answered from
+/// `cache`, so only merchants new since then cost a trial decryption.
+async fn decrypt_cached(cache: &DecryptCache, pass: &PassKeys<'_>, txid: &str, raw_hex: &str) -> Arc<txcache::Decryption> {
+    let cached = cache.lock().await.get(txid);
+    if let Some((tried, decryption)) = &cached {
+        if pass.merchants.is_subset(tried) {
+            return decryption.clone();
+        }
+    }
+
+    let tried = cached.as_ref().map(|(tried, _)| tried.as_ref());
+    let mut outputs: Vec<(String, Vec<decrypt::DecryptedOutput>)> = cached
+        .as_ref()
+        .map(|(_, decryption)| decryption.outputs().to_vec())
+        .unwrap_or_default();
+    for (merchant_id, keys) in &pass.keys {
+        if tried.is_some_and(|t| t.contains(*merchant_id)) {
+            continue;
+        }
+        match decrypt::try_decrypt_with_keys(raw_hex, keys) {
+            Ok(found) if !found.is_empty() => outputs.push((merchant_id.to_string(), found)),
+            _ => {}
+        }
+    }
+
+    let key_set = match tried {
+        Some(tried) if !tried.is_subset(&pass.merchants) => Arc::new(tried.union(&pass.merchants).cloned().collect()),
+        _ => pass.merchants.clone(),
+    };
+    let decryption = Arc::new(if outputs.is_empty() {
+        txcache::Decryption::DecryptionFailed
+    } else {
+        txcache::Decryption::Outputs(outputs)
     });
+    cache.lock().await.insert(txid, key_set, decryption.clone());
+    decryption
+}
+
+/// A transaction's decrypted outputs sorted by `match_output`: each matched invoice with
+/// the total the transaction paid it, and the outputs that matched none.
+#[derive(Default)]
+struct TxMatches<'a> {
+    invoice_totals: HashMap<String, (invoices::Invoice, i64)>,
+    unmatched: Vec<(&'a str, &'a decrypt::DecryptedOutput)>,
+}
+
+async fn match_outputs<'a>(
+    pool: &SqlitePool,
+    index: &matching::PendingIndex<'_>,
+    strict: &HashSet<String>,
+    decrypted: &'a [(String, Vec<decrypt::DecryptedOutput>)],
+) -> anyhow::Result<TxMatches<'a>> {
+    let mut matches = TxMatches::default();
+    for (merchant_id, outputs) in decrypted {
+        for output in outputs {
+            let recipient_hex = hex::encode(output.recipient_raw);
+            match match_output(pool, index, strict, merchant_id, &recipient_hex, &output.memo).await? {
+                Some(invoice) => {
+                    let entry = matches.invoice_totals.entry(invoice.id.clone())
+                        .or_insert((invoice, 0));
+                    entry.1 += output.amount_zatoshis as i64;
+                }
+                None => matches.unmatched.push((merchant_id, output)),
+            }
+        }
+    }
+    Ok(matches)
+}
+
+/// Record each output of `txid` that matched no invoice; see `report_unmatched`.
+async fn report_all_unmatched(
+    pool: &SqlitePool,
+    config: &Config,
+    strict: &HashSet<String>,
+    key_cache: &KeyCache,
+    txid: &str,
+    unmatched: &[(&str, &decrypt::DecryptedOutput)],
+    block_height: Option<u64>,
+) -> anyhow::Result<()> {
+    for (merchant_id, output) in unmatched {
+        if let Some(keys) = key_cache.merchant_keys(merchant_id) {
+            report_unmatched(pool, config, strict.contains(*merchant_id), merchant_id, keys, txid, output, block_height).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Reprice `on_expiry = requote` invoices that ran out of time. Without a price
+/// they are left alone and expire as usual.
+async fn requote_expired(config: &Config, pool: &SqlitePool, http: &reqwest::Client, prices: &PriceService) -> anyhow::Result<()> {
+    let due: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM invoices WHERE status = 'pending' AND on_expiry = 'requote'
+         AND expires_at < strftime('%Y-%m-%dT%H:%M:%SZ', 'now')"
+    )
+    .fetch_one(pool)
+    .await?;
+    if due == 0 {
+        return Ok(());
+    }
+
+    let rates = prices.get_rates().await?;
+    let fee_config = invoices::FeeConfig::from_config(config);
+    let requoted = invoices::requote_expired(
+        pool, rates.zec_eur, rates.zec_usd, config.invoice_expiry_minutes, fee_config.as_ref(),
+    ).await?;
+
+    for r in &requoted {
+        match webhooks::enqueue_requote(pool, &r.invoice_id, r.price_zatoshis, &r.expires_at).await {
+            Ok(Some(merchant_id)) => webhooks::spawn_delivery(pool, http, merchant_id, &config.encryption_key),
+            Ok(None) => {}
+            Err(e) => tracing::error!(invoice_id = %r.invoice_id, error = %e, "Failed to queue requote webhook"),
+        }
+    }
+    Ok(())
+}
+
+/// Expire invoices that ran out of time and send each an `expired` webhook.
+async fn expire_invoices(config: &Config, pool: &SqlitePool, http: &reqwest::Client) -> anyhow::Result<()> {
+    for expired in invoices::expire_old_invoices(pool).await? {
+        match webhooks::enqueue_expired(pool, &expired).await {
+            Ok(Some(merchant_id)) => webhooks::spawn_delivery(pool, http, merchant_id, &config.encryption_key),
+            Ok(None) => {}
+            Err(e) => tracing::error!(invoice_id = %expired.invoice_id, error = %e, "Failed to queue expiry webhook"),
+        }
+    }
+    Ok(())
 }
 
-/// Fire a payment webhook without blocking the scan loop.
-fn spawn_payment_webhook(
+/// Queue a webhook (fixing its sequence number now, in event order) and
+/// deliver it without blocking the scan loop.
+async fn spawn_webhook(pool: &SqlitePool, http: &reqwest::Client, invoice_id: &str, event: &str, txid: &str, encryption_key: &str) {
+    match webhooks::enqueue(pool, invoice_id, event, txid).await {
+        Ok(Some(merchant_id)) => webhooks::spawn_delivery(pool, http, merchant_id, encryption_key),
+        Ok(None) => {}
+        Err(e) => tracing::error!(invoice_id, event, error = %e, "Failed to queue webhook"),
+    }
+}
+
+/// Queue a payment webhook and deliver it without blocking the scan loop.
+#[allow(clippy::too_many_arguments)]
+async fn spawn_payment_webhook(
     pool: &SqlitePool, http: &reqwest::Client,
     invoice_id: &str, event: &str, txid: &str,
     price_zatoshis: i64, received_zatoshis: i64, overpaid: bool,
     encryption_key: &str,
 ) {
-    let pool = pool.clone();
-    let http = http.clone();
-    let invoice_id = invoice_id.to_string();
-    let event = event.to_string();
-    let txid = txid.to_string();
-    let enc_key = encryption_key.to_string();
-    tokio::spawn(async move {
-        if let Err(e) = webhooks::dispatch_payment(
-            &pool, &http, &invoice_id, &event, &txid,
-            price_zatoshis, received_zatoshis, overpaid,
-            &enc_key,
-        ).await {
-            tracing::error!(invoice_id, event, error = %e, "Async payment webhook failed");
-        }
-    });
+    match webhooks::enqueue_payment(pool, invoice_id, event, txid, price_zatoshis, received_zatoshis, overpaid).await {
+        Ok(Some(merchant_id)) => webhooks::spawn_delivery(pool, http, merchant_id, encryption_key),
+        Ok(None) => {}
+        Err(e) => tracing::error!(invoice_id, event, error = %e, "Failed to queue payment webhook"),
+    }
 }
 
+/// The network's merchants, and the fee wallet's UFVK if settlement invoices are paid on it.
+async fn network_merchants<'a>(
+    config: &'a Config,
+    network: &str,
+    pool: &SqlitePool,
+) -> anyhow::Result<(Vec<crate::merchants::Merchant>, Option<&'a str>)> {
+    let mut merchants = crate::merchants::get_all_merchants(pool, &config.encryption_key).await?;
+    merchants.retain(|m| m.network == network);
+    let fee_ufvk = config.fee_ufvk.as_deref().filter(|_| network == config.network);
+    Ok((merchants, fee_ufvk))
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn scan_mempool(
     config: &Config,
+    network: &str,
     pool: &SqlitePool,
     http: &reqwest::Client,
+    cipherscan: &CipherScan,
     seen: &SeenTxids,
+    decrypt_cache: &DecryptCache,
     key_cache: &mut Option<KeyCache>,
 ) -> anyhow::Result<()> {
-    let pending = invoices::get_pending_invoices(pool).await?;
-    if pending.is_empty() {
-        return Ok(());
-    }
+    let mut pending = invoices::get_pending_invoices(pool, network).await?;
+    pending.extend(invoices::get_recently_expired(pool, network, config.late_payment_grace_minutes).await?);
 
-    let merchants = crate::merchants::get_all_merchants(pool, &config.encryption_key).await?;
+    let (merchants, fee_ufvk) = network_merchants(config, network, pool).await?;
     if merchants.is_empty() {
         return Ok(());
     }
 
-    let cached_keys = refresh_key_cache(key_cache, &merchants);
+    let key_cache = refresh_key_cache(key_cache, &merchants, fee_ufvk);
+    let strict = crate::merchants::strict_address_merchants(pool).await?;
+    let pass_keys = key_cache.keys_for_pending(&pending, &strict);
+    if pending.is_empty() && pass_keys.keys.is_empty() {
+        return Ok(());
+    }
 
-    let mempool_txids = mempool::fetch_mempool_txids(http, &config.cipherscan_api_url).await?;
+    let mempool_txids = mempool::fetch_mempool_txids(cipherscan).await?;
 
     let new_txids: Vec<String> = {
         let seen_set = seen.read().await;
-        mempool_txids.into_iter().filter(|txid| !seen_set.contains_key(txid)).collect()
+        let decrypted = decrypt_cache.lock().await;
+        mempool_txids
+            .into_iter()
+            .filter(|txid| !seen_set.contains_key(txid) && !decrypted.covers(txid, &pass_keys.merchants))
+            .collect()
     };
 
     if new_txids.is_empty() {
@@ -205,107 +500,254 @@ async fn scan_mempool(
 
     tracing::debug!(count = new_txids.len(), "New mempool transactions");
 
-    {
-        let mut seen_set = seen.write().await;
-        let now = Instant::now();
-        for txid in &new_txids {
-            seen_set.insert(txid.clone(), now);
-        }
-    }
-
-    let raw_txs = mempool::fetch_raw_txs_batch(http, &config.cipherscan_api_url, &new_txids).await;
+    let index = matching::PendingIndex::new(&pending);
+    let raw_txs = mempool::fetch_raw_txs_batch(cipherscan, &new_txids).await;
     tracing::debug!(fetched = raw_txs.len(), total = new_txids.len(), "Batch fetched raw txs");
 
     for (txid, raw_hex) in &raw_txs {
+        let decryption = decrypt_cached(decrypt_cache, &pass_keys, txid, raw_hex).await;
+        for output in decryption.outputs().iter().flat_map(|(_, outputs)| outputs) {
+            tracing::info!(txid, memo = %output.memo, amount = output.amount_zec, "Decrypted mempool tx");
+        }
+
         // Aggregate all outputs per invoice across all merchants in this tx
-        let mut invoice_totals: HashMap<String, (invoices::Invoice, i64)> = HashMap::new();
-
-        for (_merchant_id, keys) in cached_keys {
-            match decrypt::try_decrypt_with_keys(raw_hex, keys) {
-                Ok(outputs) => {
-                    for output in &outputs {
-                        let recipient_hex = hex::encode(output.recipient_raw);
-                        tracing::info!(txid, memo = %output.memo, amount = output.amount_zec, "Decrypted mempool tx");
-
-                        if let Some(invoice) = matching::find_matching_invoice(&pending, &recipient_hex, &output.memo) {
-                            let entry = invoice_totals.entry(invoice.id.clone())
-                                .or_insert((invoice.clone(), 0));
-                            entry.1 += output.amount_zatoshis as i64;
-                        }
-                    }
-                }
-                Err(_) => {}
-            }
+        let TxMatches { mut invoice_totals, unmatched } = match_outputs(pool, &index, &strict, decryption.outputs()).await?;
+        report_all_unmatched(pool, config, &strict, key_cache, txid, &unmatched, None).await?;
+        add_transparent_payments(&index, raw_hex, &mut invoice_totals);
+        add_settlement_payments(&index, key_cache.fee.as_ref(), raw_hex, &mut invoice_totals);
+        if !invoice_totals.is_empty() {
+            seen.write().await.insert(txid.clone(), Instant::now());
         }
 
         for (invoice_id, (invoice, tx_total)) in &invoice_totals {
-            let dust_min = std::cmp::max(
-                (invoice.price_zatoshis as f64 * decrypt::DUST_THRESHOLD_FRACTION) as i64,
-                decrypt::DUST_THRESHOLD_MIN_ZATOSHIS,
-            );
-            if *tx_total < dust_min && *tx_total < invoice.price_zatoshis {
-                tracing::debug!(invoice_id, tx_total, dust_min, "Ignoring dust payment");
+            if decrypt::is_dust(*tx_total, invoice.price_zatoshis) {
+                tracing::debug!(invoice_id, tx_total, "Ignoring dust payment");
                 continue;
             }
 
-            let new_received = if invoice.status == "underpaid" {
-                invoices::accumulate_payment(pool, invoice_id, *tx_total).await?
-            } else {
-                *tx_total
-            };
+            if apply_mempool_payment(config, pool, http, invoice, txid, *tx_total).await? {
+                try_detect_fee(pool, config, raw_hex, invoice_id).await;
+            }
+        }
+        if !invoice_totals.is_empty() {
+            record_tx_shape(pool, txid, raw_hex).await;
+        }
+    }
 
-            let min = (invoice.price_zatoshis as f64 * decrypt::SLIPPAGE_TOLERANCE) as i64;
+    Ok(())
+}
 
-            if new_received >= min {
-                let changed = invoices::mark_detected(pool, invoice_id, txid, new_received).await?;
-                if changed {
-                    let overpaid = new_received > invoice.price_zatoshis + 1000;
-                    spawn_payment_webhook(pool, http, invoice_id, "detected", txid,
-                        invoice.price_zatoshis, new_received, overpaid, &config.encryption_key);
-                    try_detect_fee(pool, config, raw_hex, invoice_id).await;
-                }
-            } else if invoice.status == "pending" {
-                invoices::mark_underpaid(pool, invoice_id, new_received, txid).await?;
-                spawn_payment_webhook(pool, http, invoice_id, "underpaid", txid,
-                    invoice.price_zatoshis, new_received, false, &config.encryption_key);
+/// Match a decrypted output: the cycle's pending set by address, then the database by
+/// address (an invoice created since the set was loaded), then the memo fallback. An
+/// address match always beats a memo pointing elsewhere. The memo fallback is skipped
+/// when either the paid merchant (`merchant_id`, whose key decrypted the output) or the
+/// invoice's merchant is in strict address mode.
+async fn match_output(
+    pool: &SqlitePool,
+    index: &matching::PendingIndex<'_>,
+    strict: &HashSet<String>,
+    merchant_id: &str,
+    recipient_hex: &str,
+    memo: &str,
+) -> anyhow::Result<Option<invoices::Invoice>> {
+    if let Some(invoice) = index.by_address(recipient_hex) {
+        return Ok(Some(invoice.clone()));
+    }
+    if let Some(invoice) = invoices::find_by_orchard_receiver(pool, recipient_hex).await? {
+        return Ok(Some(invoice));
+    }
+    if strict.contains(merchant_id) {
+        return Ok(None);
+    }
+    // A settlement invoice is paid to the fee wallet; a memo naming it in a payment to the
+    // merchant's own wallet pays nothing.
+    Ok(index.by_memo(memo).filter(|i| !strict.contains(&i.merchant_id) && !i.is_settlement()).cloned())
+}
+
+/// Record a decrypted output that matched no invoice in the merchant's unmatched inbox.
+/// Strict-mode merchants are also emailed about each new one, since for them a memo
+/// never rescues a payment to the wrong address.
+#[allow(clippy::too_many_arguments)]
+async fn report_unmatched(
+    pool: &SqlitePool,
+    config: &Config,
+    strict: bool,
+    merchant_id: &str,
+    keys: &decrypt::CachedKeys,
+    txid: &str,
+    output: &decrypt::DecryptedOutput,
+    block_height: Option<u64>,
+) -> anyhow::Result<()> {
+    let Some(payment) = unmatched::record(pool, merchant_id, keys, txid, output, block_height).await? else {
+        return Ok(());
+    };
+    if !strict || !config.smtp_configured() {
+        return Ok(());
+    }
+    let to: Option<String> = sqlx::query_scalar::<_, Option<String>>("SELECT recovery_email FROM merchants WHERE id = ?")
+        .bind(merchant_id)
+        .fetch_optional(pool)
+        .await?
+        .flatten();
+    if let Some(to) = to {
+        let pool = pool.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Err(e) = crate::email::send_unmatched_payment_notice(&pool, &config, &to, &payment).await {
+                tracing::error!(txid = %payment.txid, error = %e, "Failed to send unmatched payment notice");
             }
+        });
+    }
+    Ok(())
+}
+
+/// Add the transaction's P2PKH outputs paying an invoice's TEX address to its total.
+/// Transparent outputs need no viewing key, so this runs once per transaction.
+fn add_transparent_payments(
+    index: &matching::PendingIndex<'_>,
+    raw_hex: &str,
+    invoice_totals: &mut HashMap<String, (invoices::Invoice, i64)>,
+) {
+    if !index.has_transparent() {
+        return;
+    }
+    for output in decrypt::transparent_outputs(raw_hex) {
+        if let Some(invoice) = index.by_transparent(&hex::encode(output.receiver)) {
+            let entry = invoice_totals.entry(invoice.id.clone())
+                .or_insert((invoice.clone(), 0));
+            entry.1 += output.amount_zatoshis as i64;
+        }
+    }
+}
+
+/// Add the transaction's outputs to the operator's fee wallet that pay a settlement
+/// invoice's address to its total. Fee outputs of ordinary invoices land in the same
+/// wallet; they match no settlement address and are left to `try_detect_fee`.
+fn add_settlement_payments(
+    index: &matching::PendingIndex<'_>,
+    fee_keys: Option<&decrypt::CachedKeys>,
+    raw_hex: &str,
+    invoice_totals: &mut HashMap<String, (invoices::Invoice, i64)>,
+) {
+    let Some(keys) = fee_keys.filter(|_| index.has_settlement()) else {
+        return;
+    };
+    let Ok(outputs) = decrypt::try_decrypt_with_keys(raw_hex, keys) else {
+        return;
+    };
+    for output in &outputs {
+        let recipient_hex = hex::encode(output.recipient_raw);
+        if let Some(invoice) = index.by_address(&recipient_hex).filter(|i| i.is_settlement()) {
+            let entry = invoice_totals.entry(invoice.id.clone())
+                .or_insert((invoice.clone(), 0));
+            entry.1 += output.amount_zatoshis as i64;
         }
     }
+}
+
+/// Keep the miner fee and output count of a transaction that paid invoices, so merchants
+/// can tell a wallet taking its fee out of the amount from a short payment.
+async fn record_tx_shape(pool: &SqlitePool, txid: &str, raw_hex: &str) {
+    let Some(shape) = decrypt::tx_shape(raw_hex) else {
+        return;
+    };
+    if let Err(e) = invoices::record_payment_shape(pool, txid, shape.fee_zatoshis, shape.outputs).await {
+        tracing::warn!(txid, error = %e, "Failed to record transaction fee");
+    }
+}
+
+/// Apply a mempool payment of `amount_zatoshis` to a matched invoice: record it, then mark
+/// the invoice detected, underpaid or paid late and queue the webhook. Returns true if
+/// the invoice became detected.
+async fn apply_mempool_payment(
+    config: &Config,
+    pool: &SqlitePool,
+    http: &reqwest::Client,
+    invoice: &invoices::Invoice,
+    txid: &str,
+    amount_zatoshis: i64,
+) -> anyhow::Result<bool> {
+    let invoice_id = invoice.id.as_str();
+    invoices::events::record(pool, invoice_id, "mempool_seen", Some(txid), None, Some(serde_json::json!({
+        "amount_zatoshis": amount_zatoshis,
+    }))).await;
+    invoices::record_payment(pool, invoice_id, txid, amount_zatoshis, None).await?;
+
+    if invoice.status == "expired" {
+        on_paid_late(pool, config, http, invoice, txid, amount_zatoshis).await?;
+        return Ok(false);
+    }
 
+    let min = (invoice.price_zatoshis as f64 * decrypt::SLIPPAGE_TOLERANCE) as i64;
+
+    let (new_received, detected) = if invoice.status == "underpaid" {
+        invoices::accumulate_payment(pool, invoice_id, txid, amount_zatoshis, min).await?
+    } else if amount_zatoshis >= min {
+        (amount_zatoshis, invoices::mark_detected(pool, invoice_id, txid, amount_zatoshis).await?)
+    } else {
+        (amount_zatoshis, false)
+    };
+
+    if detected {
+        let overpaid = new_received > invoice.price_zatoshis + 1000;
+        spawn_payment_webhook(pool, http, invoice_id, "detected", txid,
+            invoice.price_zatoshis, new_received, overpaid, &config.encryption_key).await;
+        return Ok(true);
+    } else if new_received < min && invoice.status == "pending"
+        && invoices::mark_underpaid(pool, invoice_id, new_received, txid).await?
+    {
+        spawn_payment_webhook(pool, http, invoice_id, "underpaid", txid,
+            invoice.price_zatoshis, new_received, false, &config.encryption_key).await;
+    }
+    Ok(false)
+}
+
+/// Confirm a detected invoice whose transaction was mined.
+async fn apply_confirmation(
+    config: &Config,
+    pool: &SqlitePool,
+    http: &reqwest::Client,
+    invoice: &invoices::Invoice,
+    txid: &str,
+    block_height: Option<u64>,
+) -> anyhow::Result<()> {
+    let changed = invoices::mark_confirmed(pool, &invoice.id, txid, block_height).await?;
+    if changed {
+        spawn_webhook(pool, http, &invoice.id, "confirmed", txid, &config.encryption_key).await;
+        on_invoice_confirmed(pool, config, invoice).await;
+    }
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn scan_blocks(
     config: &Config,
+    network: &str,
     pool: &SqlitePool,
     http: &reqwest::Client,
+    cipherscan: &CipherScan,
     seen: &SeenTxids,
+    decrypt_cache: &DecryptCache,
     last_height: &Arc<RwLock<Option<u64>>>,
     key_cache: &mut Option<KeyCache>,
 ) -> anyhow::Result<()> {
-    let pending = invoices::get_pending_invoices(pool).await?;
-    if pending.is_empty() {
-        return Ok(());
-    }
+    let mut pending = invoices::get_pending_invoices(pool, network).await?;
+    pending.extend(invoices::get_recently_expired(pool, network, config.late_payment_grace_minutes).await?);
 
     let detected: Vec<_> = pending.iter().filter(|i| i.status == "detected").cloned().collect();
     for invoice in &detected {
         if let Some(txid) = &invoice.detected_txid {
-            match blocks::check_tx_confirmed(http, &config.cipherscan_api_url, txid).await {
-                Ok(true) => {
-                    let changed = invoices::mark_confirmed(pool, &invoice.id).await?;
-                    if changed {
-                        spawn_webhook(pool, http, &invoice.id, "confirmed", txid, &config.encryption_key);
-                        on_invoice_confirmed(pool, config, invoice).await;
-                    }
+            match blocks::check_tx_confirmed(cipherscan, txid).await {
+                Ok(Some(confirmation)) => {
+                    apply_confirmation(config, pool, http, invoice, txid, confirmation.block_height).await?;
                 }
-                Ok(false) => {}
+                Ok(None) => {}
                 Err(e) => tracing::debug!(txid, error = %e, "Confirmation check failed"),
             }
         }
     }
 
-    let current_height = blocks::get_chain_height(http, &config.cipherscan_api_url).await?;
+    let current_height = blocks::get_chain_height(cipherscan).await?;
     let start_height = {
         let last = last_height.read().await;
         match *last {
@@ -315,89 +757,244 @@ async fn scan_blocks(
     };
 
     if start_height <= current_height && start_height < current_height {
-        let merchants = crate::merchants::get_all_merchants(pool, &config.encryption_key).await?;
-        let cached_keys = refresh_key_cache(key_cache, &merchants);
-        let block_txids = blocks::fetch_block_txids(http, &config.cipherscan_api_url, start_height, current_height).await?;
+        let (merchants, fee_ufvk) = network_merchants(config, network, pool).await?;
+        let key_cache = refresh_key_cache(key_cache, &merchants, fee_ufvk);
+        let pass_keys = key_cache.all_keys();
+        let strict = crate::merchants::strict_address_merchants(pool).await?;
+        let block_txids = blocks::fetch_block_txids(cipherscan, start_height, current_height).await?;
+        let index = matching::PendingIndex::new(&pending);
 
-        for txid in &block_txids {
+        for (txid, height) in &block_txids {
+            // Transactions whose payments the mempool pass applied are skipped, since
+            // `accumulate_payment` is not idempotent; their confirmation comes from
+            // `check_tx_confirmed` above. The rest it decrypted are matched again from the
+            // cache without a second trial decryption.
             if seen.read().await.contains_key(txid) {
                 continue;
             }
 
-            let raw_hex = match mempool::fetch_raw_tx(http, &config.cipherscan_api_url, txid).await {
+            let raw_hex = match mempool::fetch_raw_tx(cipherscan, txid).await {
                 Ok(hex) => hex,
                 Err(_) => continue,
             };
 
-            let mut invoice_totals: HashMap<String, (invoices::Invoice, i64)> = HashMap::new();
-            for (_merchant_id, keys) in cached_keys.iter() {
-                if let Ok(outputs) = decrypt::try_decrypt_with_keys(&raw_hex, keys) {
-                    for output in &outputs {
-                        let recipient_hex = hex::encode(output.recipient_raw);
-                        if let Some(invoice) = matching::find_matching_invoice(&pending, &recipient_hex, &output.memo) {
-                            let entry = invoice_totals.entry(invoice.id.clone())
-                                .or_insert((invoice.clone(), 0));
-                            entry.1 += output.amount_zatoshis as i64;
-                        }
-                    }
-                }
-            }
+            let decryption = decrypt_cached(decrypt_cache, &pass_keys, txid, &raw_hex).await;
+            let TxMatches { mut invoice_totals, unmatched } = match_outputs(pool, &index, &strict, decryption.outputs()).await?;
+            report_all_unmatched(pool, config, &strict, key_cache, txid, &unmatched, Some(*height)).await?;
+            add_transparent_payments(&index, &raw_hex, &mut invoice_totals);
+            add_settlement_payments(&index, key_cache.fee.as_ref(), &raw_hex, &mut invoice_totals);
 
             for (invoice_id, (invoice, tx_total)) in &invoice_totals {
-                let dust_min = std::cmp::max(
-                    (invoice.price_zatoshis as f64 * decrypt::DUST_THRESHOLD_FRACTION) as i64,
-                    decrypt::DUST_THRESHOLD_MIN_ZATOSHIS,
-                );
-                if *tx_total < dust_min && *tx_total < invoice.price_zatoshis {
-                    tracing::debug!(invoice_id, tx_total, dust_min, "Ignoring dust payment in block");
+                if decrypt::is_dust(*tx_total, invoice.price_zatoshis) {
+                    tracing::debug!(invoice_id, tx_total, "Ignoring dust payment in block");
                     continue;
                 }
 
-                let new_received = if invoice.status == "underpaid" {
-                    invoices::accumulate_payment(pool, invoice_id, *tx_total).await?
-                } else {
-                    *tx_total
-                };
+                invoices::events::record(pool, invoice_id, "block_seen", Some(txid), Some(*height), Some(serde_json::json!({
+                    "amount_zatoshis": tx_total,
+                }))).await;
+                invoices::record_payment(pool, invoice_id, txid, *tx_total, Some(*height)).await?;
+
+                if invoice.status == "expired" {
+                    on_paid_late(pool, config, http, invoice, txid, *tx_total).await?;
+                    continue;
+                }
 
                 let min = (invoice.price_zatoshis as f64 * decrypt::SLIPPAGE_TOLERANCE) as i64;
 
-                if new_received >= min && (invoice.status == "pending" || invoice.status == "underpaid") {
-                    let detected = invoices::mark_detected(pool, invoice_id, txid, new_received).await?;
-                    if detected {
-                        let confirmed = invoices::mark_confirmed(pool, invoice_id).await?;
-                        if confirmed {
-                            let overpaid = new_received > invoice.price_zatoshis + 1000;
-                            spawn_payment_webhook(pool, http, invoice_id, "confirmed", txid,
-                                invoice.price_zatoshis, new_received, overpaid, &config.encryption_key);
-                            on_invoice_confirmed(pool, config, invoice).await;
-                        }
-                        try_detect_fee(pool, config, &raw_hex, invoice_id).await;
+                let (new_received, detected) = if invoice.status == "underpaid" {
+                    invoices::accumulate_payment(pool, invoice_id, txid, *tx_total, min).await?
+                } else if *tx_total >= min && invoice.status == "pending" {
+                    (*tx_total, invoices::mark_detected(pool, invoice_id, txid, *tx_total).await?)
+                } else {
+                    (*tx_total, false)
+                };
+
+                if detected {
+                    // Without agreement from the verification source the invoice stays
+                    // detected; the `check_tx_confirmed` pass above retries it next cycle.
+                    let corroborated = blocks::corroborate(cipherscan, txid, &blocks::TxConfirmation {
+                        block_height: Some(*height),
+                        block_hash: None,
+                    }).await;
+                    if corroborated && invoices::mark_confirmed(pool, invoice_id, txid, Some(*height)).await? {
+                        let overpaid = new_received > invoice.price_zatoshis + 1000;
+                        spawn_payment_webhook(pool, http, invoice_id, "confirmed", txid,
+                            invoice.price_zatoshis, new_received, overpaid, &config.encryption_key).await;
+                        on_invoice_confirmed(pool, config, invoice).await;
                     }
-                } else if new_received < min && invoice.status == "pending" {
-                    invoices::mark_underpaid(pool, invoice_id, new_received, txid).await?;
+                    try_detect_fee(pool, config, &raw_hex, invoice_id).await;
+                } else if new_received < min && invoice.status == "pending"
+                    && invoices::mark_underpaid(pool, invoice_id, new_received, txid).await?
+                {
                     spawn_payment_webhook(pool, http, invoice_id, "underpaid", txid,
-                        invoice.price_zatoshis, new_received, false, &config.encryption_key);
+                        invoice.price_zatoshis, new_received, false, &config.encryption_key).await;
                 }
             }
+            if !invoice_totals.is_empty() {
+                record_tx_shape(pool, txid, &raw_hex).await;
+            }
 
             seen.write().await.insert(txid.clone(), Instant::now());
         }
     }
 
     *last_height.write().await = Some(current_height);
-    if let Err(e) = crate::db::set_scanner_state(pool, "last_height", &current_height.to_string()).await {
-        tracing::warn!(error = %e, "Failed to persist last_height");
+    if let Err(e) = crate::db::set_scanner_state(pool, &crate::db::height_key(network), &current_height.to_string()).await {
+        tracing::warn!(network, error = %e, "Failed to persist last_height");
+    }
+    Ok(())
+}
+
+/// Outcome of checking a mined refund against the merchant's outgoing outputs.
+#[derive(Debug, PartialEq)]
+enum RefundCheck {
+    Verified,
+    Rejected(&'static str),
+    Unverifiable(&'static str),
+}
+
+/// Recover the transaction's outgoing Orchard outputs with the merchant's OVK and
+/// require ones to the buyer's refund receiver totalling at least the refund amount.
+fn check_refund(raw_hex: &str, ufvk: &str, receiver: &[u8; 43], refund_zatoshis: i64) -> RefundCheck {
+    let outputs = decrypt::try_recover_outgoing(raw_hex, ufvk).unwrap_or_default();
+    if outputs.is_empty() {
+        return RefundCheck::Unverifiable("no outgoing outputs recoverable with the merchant's viewing key");
+    }
+    let sent: u64 = outputs.iter()
+        .filter(|o| o.recipient_raw == *receiver)
+        .map(|o| o.amount_zatoshis)
+        .sum();
+    if (sent as i64) < refund_zatoshis {
+        return RefundCheck::Rejected("transaction does not pay the refund amount to the buyer's refund address");
+    }
+    RefundCheck::Verified
+}
+
+/// Check merchant-submitted refund txids. Once mined, the refund is verified by
+/// recovering the merchant's outgoing Orchard outputs (OVK) and requiring one to the
+/// buyer's refund address of at least the refund amount. A mined tx that cannot be
+/// checked (different wallet, non-Orchard refund address) is never confirmed: it is
+/// flagged for the merchant to resolve by hand.
+async fn verify_refunds(
+    config: &Config,
+    network: &str,
+    pool: &SqlitePool,
+    http: &reqwest::Client,
+    cipherscan: &CipherScan,
+) -> anyhow::Result<()> {
+    let refunds = invoices::get_pending_refunds(pool, network).await?;
+    if refunds.is_empty() {
+        return Ok(());
+    }
+
+    let merchants = crate::merchants::get_all_merchants(pool, &config.encryption_key).await?;
+
+    for refund in &refunds {
+        let confirmation = match blocks::check_tx_confirmed(cipherscan, &refund.refund_txid).await {
+            Ok(Some(c)) => c,
+            Ok(None) => continue,
+            Err(e) => {
+                tracing::debug!(txid = %refund.refund_txid, error = %e, "Refund confirmation check failed");
+                continue;
+            }
+        };
+
+        let receiver = refund.refund_address.as_deref().and_then(crate::addresses::orchard_receiver);
+        let ufvk = merchants.iter().find(|m| m.id == refund.merchant_id).map(|m| m.ufvk.as_str());
+
+        let check = match (receiver, ufvk) {
+            (None, _) => RefundCheck::Unverifiable("refund address has no Orchard receiver"),
+            (_, None) => RefundCheck::Unverifiable("merchant has no viewing key"),
+            (Some(receiver), Some(ufvk)) => {
+                let raw_hex = match mempool::fetch_raw_tx(cipherscan, &refund.refund_txid).await {
+                    Ok(hex) => hex,
+                    Err(_) => continue,
+                };
+                check_refund(&raw_hex, ufvk, &receiver, refund.refund_zatoshis)
+            }
+        };
+
+        match check {
+            RefundCheck::Verified => {
+                if invoices::confirm_refund(pool, &refund.id, &refund.refund_txid, confirmation.block_height).await? {
+                    spawn_webhook(pool, http, &refund.id, "refund_confirmed", &refund.refund_txid, &config.encryption_key).await;
+                }
+            }
+            RefundCheck::Rejected(reason) => {
+                invoices::reject_refund(pool, &refund.id, &refund.refund_txid, reason).await?;
+            }
+            RefundCheck::Unverifiable(reason) => {
+                invoices::flag_refund_for_review(pool, &refund.id, &refund.refund_txid, reason).await?;
+            }
+        }
     }
+
     Ok(())
 }
 
-/// When an invoice is confirmed, create a fee ledger entry and ensure a billing cycle exists.
+/// A payment matched an invoice inside its late-payment grace window. The invoice
+/// becomes `paid_late` and the merchant is told to fulfil or refund it by hand.
+async fn on_paid_late(
+    pool: &SqlitePool,
+    config: &Config,
+    http: &reqwest::Client,
+    invoice: &invoices::Invoice,
+    txid: &str,
+    amount_zatoshis: i64,
+) -> anyhow::Result<()> {
+    let received = match invoices::mark_paid_late(pool, &invoice.id, txid, amount_zatoshis).await? {
+        Some(total) => total,
+        None => return Ok(()),
+    };
+
+    let overpaid = received > invoice.price_zatoshis + 1000;
+    spawn_payment_webhook(pool, http, &invoice.id, "paid_late", txid,
+        invoice.price_zatoshis, received, overpaid, &config.encryption_key).await;
+    crate::notifiers::invoice_paid_late(pool, &invoice.id);
+
+    if !config.smtp_configured() {
+        return Ok(());
+    }
+    let to: Option<String> = sqlx::query_scalar::<_, Option<String>>("SELECT recovery_email FROM merchants WHERE id = ?")
+        .bind(&invoice.merchant_id)
+        .fetch_optional(pool)
+        .await?
+        .flatten();
+    if let Some(to) = to {
+        let pool = pool.clone();
+        let config = config.clone();
+        let invoice = invoice.clone();
+        let txid = txid.to_string();
+        tokio::spawn(async move {
+            if let Err(e) = crate::email::send_late_payment_notice(&pool, &config, &to, &invoice, received, &txid).await {
+                tracing::error!(invoice_id = %invoice.id, error = %e, "Failed to send late payment notice");
+            }
+        });
+    }
+    Ok(())
+}
+
+/// When an invoice is confirmed, notify the merchant's extra channels, create a fee
+/// ledger entry and ensure a billing cycle exists.
 async fn on_invoice_confirmed(pool: &SqlitePool, config: &Config, invoice: &invoices::Invoice) {
-    if !config.fee_enabled() {
+    crate::notifiers::invoice_confirmed(pool, config, &invoice.id);
+
+    // Paying fees accrues no fee, and fees are only charged on the primary network.
+    if !config.fee_enabled() || invoice.is_settlement() || invoice.network != config.network {
         return;
     }
 
-    let fee_amount = invoice.price_zec * config.fee_rate;
+    let share = match billing::billable_share(pool, &invoice.merchant_id, invoice.price_zatoshis).await {
+        Ok(share) => share,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to apply fee waivers");
+            1.0
+        }
+    };
+    let fee_rate = config.fee_rate * share;
+
+    let fee_amount = invoice.price_zec * fee_rate;
     if fee_amount < 0.00000001 {
         return;
     }
@@ -406,8 +1003,22 @@ async fn on_invoice_confirmed(pool: &SqlitePool, config: &Config, invoice: &invo
         tracing::error!(error = %e, "Failed to ensure billing cycle");
     }
 
-    if let Err(e) = billing::create_fee_entry(pool, &invoice.id, &invoice.merchant_id, fee_amount).await {
+    let fee_fiat = match config.fee_currency {
+        FeeCurrency::Zec => None,
+        FeeCurrency::Eur => Some(invoice.price_eur * fee_rate),
+        FeeCurrency::Usd => invoice.price_usd.map(|usd| usd * fee_rate),
+    };
+
+    if let Err(e) = billing::create_fee_entry(
+        pool, &invoice.id, &invoice.merchant_id, fee_amount, config.fee_currency, fee_fiat,
+    ).await {
         tracing::error!(error = %e, "Failed to create fee entry");
+        return;
+    }
+
+    // A fee output seen while the payment was in the mempool arrived before the ledger entry.
+    if invoices::fee_output_seen(pool, &invoice.id).await.unwrap_or(false) {
+        let _ = billing::mark_fee_collected(pool, &invoice.id).await;
     }
 }
 
@@ -430,6 +1041,7 @@ async fn try_detect_fee(pool: &SqlitePool, config: &Config, raw_hex: &str, invoi
                         fee_zec = output.amount_zec,
                         "Fee auto-collected via ZIP 321"
                     );
+                    let _ = invoices::mark_fee_output_seen(pool, invoice_id).await;
                     let _ = billing::mark_fee_collected(pool, invoice_id).await;
                     return;
                 }
@@ -440,3 +1052,117 @@ async fn try_detect_fee(pool: &SqlitePool, config: &Config, raw_hex: &str, invoi
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::fixtures::{self, Output};
+    use orchard::keys::Scope;
+
+    const MERCHANT: u8 = 1;
+    const BUYER: u8 = 2;
+
+    fn refund_tx(zatoshis: u64, sender: Option<u8>) -> String {
+        let mut output = Output::to_wallet(BUYER, 0, zatoshis, "REFUND-CP-1");
+        output.ovk = sender.map(|seed| fixtures::test_fvk(seed).to_ovk(Scope::External));
+        hex::encode(fixtures::transaction(&[output], 11))
+    }
+
+    #[test]
+    fn test_refunds_are_only_verified_from_recovered_outputs() {
+        let ufvk = fixtures::test_ufvk(MERCHANT);
+        let buyer = crate::addresses::orchard_receiver(&fixtures::test_address(BUYER, 0)).unwrap();
+        let elsewhere = crate::addresses::orchard_receiver(&fixtures::test_address(BUYER, 1)).unwrap();
+
+        assert_eq!(check_refund(&refund_tx(25_000_000, Some(MERCHANT)), &ufvk, &buyer, 25_000_000), RefundCheck::Verified);
+        assert!(matches!(check_refund(&refund_tx(24_000_000, Some(MERCHANT)), &ufvk, &buyer, 25_000_000), RefundCheck::Rejected(_)));
+        assert!(matches!(check_refund(&refund_tx(25_000_000, Some(MERCHANT)), &ufvk, &elsewhere, 25_000_000), RefundCheck::Rejected(_)));
+
+        // Sent from another wallet, or with the OVK discarded: nothing to check against.
+        assert!(matches!(check_refund(&refund_tx(25_000_000, Some(BUYER)), &ufvk, &buyer, 25_000_000), RefundCheck::Unverifiable(_)));
+        assert!(matches!(check_refund(&refund_tx(25_000_000, None), &ufvk, &buyer, 25_000_000), RefundCheck::Unverifiable(_)));
+    }
+
+    fn merchant_keys(seeds: &[u8]) -> Vec<(String, decrypt::CachedKeys)> {
+        seeds.iter()
+            .map(|&seed| (format!("merchant-{}", seed), decrypt::prepare_keys(&fixtures::test_ufvk(seed)).unwrap()))
+            .collect()
+    }
+
+    fn pass_keys(keys: &[(String, decrypt::CachedKeys)]) -> PassKeys<'_> {
+        PassKeys {
+            keys: keys.iter().map(|(id, k)| (id.as_str(), k)).collect(),
+            merchants: Arc::new(keys.iter().map(|(id, _)| id.clone()).collect()),
+        }
+    }
+
+    #[test]
+    fn test_mempool_pass_only_decrypts_for_pending_and_strict_merchants() {
+        let key_cache = KeyCache {
+            keys: merchant_keys(&[1, 2, 3]),
+            merchant_ids: vec!["merchant-1".into(), "merchant-2".into(), "merchant-3".into()],
+            fee: None,
+        };
+        let pending = vec![invoices::Invoice { merchant_id: "merchant-1".into(), ..invoices::test_invoice() }];
+        let strict = HashSet::from(["merchant-3".to_string()]);
+
+        let mempool = key_cache.keys_for_pending(&pending, &strict);
+        let ids: Vec<&str> = mempool.keys.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, ["merchant-1", "merchant-3"]);
+        assert!(key_cache.keys_for_pending(&[], &HashSet::new()).keys.is_empty());
+
+        let blocks = key_cache.all_keys();
+        assert_eq!(blocks.keys.len(), 3);
+        assert!(blocks.merchants.is_superset(&mempool.merchants));
+    }
+
+    #[tokio::test]
+    async fn test_cached_txid_skips_decryption() {
+        let cache: DecryptCache = Arc::new(Mutex::new(txcache::TxCache::new(10)));
+        let keys = merchant_keys(&[MERCHANT, BUYER]);
+        let merchant_only = pass_keys(&keys[..1]);
+        let paid = hex::encode(fixtures::transaction(&[Output::to_wallet(MERCHANT, 0, 25_000_000, "")], 13));
+        let other = hex::encode(fixtures::transaction(&[Output::to_wallet(BUYER, 0, 25_000_000, "")], 14));
+
+        let first = decrypt_cached(&cache, &merchant_only, "paid", &paid).await;
+        assert_eq!(first.outputs()[0].1[0].amount_zatoshis, 25_000_000);
+        // The block pass hands over the same txid: answered without decrypting `other`.
+        let again = decrypt_cached(&cache, &merchant_only, "paid", &other).await;
+        assert!(Arc::ptr_eq(&first, &again));
+
+        let failed = decrypt_cached(&cache, &merchant_only, "other", &other).await;
+        assert!(matches!(*failed, txcache::Decryption::DecryptionFailed));
+
+        // With another merchant's key as well, only that key is tried: the first merchant's
+        // outputs still come from the cache, not from the transaction handed over.
+        let all = pass_keys(&keys);
+        let both = decrypt_cached(&cache, &all, "paid", &other).await;
+        let merchants: Vec<&str> = both.outputs().iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(merchants, ["merchant-1", "merchant-2"]);
+        assert!(cache.lock().await.covers("paid", &all.merchants));
+    }
+
+    #[tokio::test]
+    async fn test_first_invoice_created_mid_cycle_matches_by_address() {
+        let pool = crate::db::test_pool().await;
+        let merchant = crate::db::test_merchant(&pool, MERCHANT).await;
+
+        // The cycle starts with nothing pending for the merchant...
+        let pending = invoices::get_pending_invoices(&pool, "testnet").await.unwrap();
+        assert!(pending.is_empty());
+        let merchants = crate::merchants::get_all_merchants(&pool, "").await.unwrap();
+        let mut cache = None;
+        let key_cache = refresh_key_cache(&mut cache, &merchants, None);
+
+        // ...and its first invoice is created and paid before the next one.
+        let created = crate::db::test_invoice(&pool, &merchant.merchant_id, MERCHANT).await;
+        let raw = hex::encode(fixtures::transaction(&[Output::to_address(&created.payment_address, 25_000_000, "")], 12));
+
+        let cache: DecryptCache = Arc::new(Mutex::new(txcache::TxCache::new(10)));
+        let decryption = decrypt_cached(&cache, &key_cache.all_keys(), "paid", &raw).await;
+        let index = matching::PendingIndex::new(&pending);
+        let matches = match_outputs(&pool, &index, &HashSet::new(), decryption.outputs()).await.unwrap();
+        assert!(matches.unmatched.is_empty());
+        assert_eq!(matches.invoice_totals[&created.invoice_id].1, 25_000_000);
+    }
+}
@@ -0,0 +1,182 @@
+//! Viewing proofs: for each payment counted towards an invoice, the on-chain output that
+//! carried it, found by re-fetching the transaction and trial-decrypting it with the
+//! merchant's UFVK. An auditor holding the same UFVK can decrypt the same actions and
+//! check every amount without any spending key.
+
+use std::collections::HashSet;
+
+use serde::Serialize;
+use sqlx::SqlitePool;
+use zcash_address::unified::{Encoding, Ufvk};
+use zcash_protocol::consensus::NetworkType;
+
+use super::cipherscan::CipherScan;
+use super::{decrypt, mempool};
+use crate::invoices::{self, matching, Invoice};
+
+#[derive(Debug, Serialize)]
+pub struct ProofOutput {
+    pub txid: String,
+    pub block_height: Option<i64>,
+    /// `orchard` or `transparent`.
+    pub pool: &'static str,
+    /// Action index in the Orchard bundle, or the transparent output's vout.
+    pub output_index: usize,
+    pub address: String,
+    pub amount_zatoshis: u64,
+    /// Decrypted memo; transparent outputs have none.
+    pub memo: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ViewingProof {
+    pub invoice_id: String,
+    pub memo_code: String,
+    pub network: &'static str,
+    /// Index the invoice address was derived at; re-derive it from the UFVK to check it.
+    pub diversifier_index: Option<i64>,
+    pub payment_address: String,
+    pub tex_address: Option<String>,
+    pub status: String,
+    pub price_zatoshis: i64,
+    pub received_zatoshis: i64,
+    pub outputs: Vec<ProofOutput>,
+    /// Sum of `outputs`.
+    pub verified_zatoshis: u64,
+    /// Recorded payments with no output found on-chain: not fetchable (yet), or simulated.
+    pub unverified_txids: Vec<String>,
+    pub generated_at: String,
+}
+
+/// Build the proof for `invoice` from its recorded payments.
+pub async fn build(
+    pool: &SqlitePool,
+    cipherscan: &CipherScan,
+    invoice: &Invoice,
+    ufvk: &str,
+) -> anyhow::Result<ViewingProof> {
+    let (network, _) = Ufvk::decode(ufvk)
+        .map_err(|e| anyhow::anyhow!("UFVK decode failed: {:?}", e))?;
+    let payments = invoices::get_payments(pool, &invoice.id).await?;
+
+    let mut outputs = Vec::new();
+    let mut unverified_txids = Vec::new();
+    let mut seen = HashSet::new();
+    for payment in &payments {
+        if !seen.insert(payment.txid.as_str()) {
+            continue;
+        }
+        let found = match mempool::fetch_raw_tx(cipherscan, &payment.txid).await {
+            Ok(raw_hex) => invoice_outputs(&raw_hex, invoice, ufvk, network)?,
+            Err(e) => {
+                tracing::debug!(txid = %payment.txid, error = %e, "Proof: transaction not fetched");
+                Vec::new()
+            }
+        };
+        if found.is_empty() {
+            unverified_txids.push(payment.txid.clone());
+        }
+        outputs.extend(found.into_iter().map(|mut output| {
+            output.txid = payment.txid.clone();
+            output.block_height = payment.block_height;
+            output
+        }));
+    }
+
+    Ok(ViewingProof {
+        invoice_id: invoice.id.clone(),
+        memo_code: invoice.memo_code.clone(),
+        network: match network {
+            NetworkType::Main => "mainnet",
+            NetworkType::Test => "testnet",
+            NetworkType::Regtest => "regtest",
+        },
+        diversifier_index: invoice.diversifier_index,
+        payment_address: invoice.payment_address.clone(),
+        tex_address: invoice.tex_address.clone(),
+        status: invoice.status.clone(),
+        price_zatoshis: invoice.price_zatoshis,
+        received_zatoshis: invoice.received_zatoshis,
+        verified_zatoshis: outputs.iter().map(|o| o.amount_zatoshis).sum(),
+        outputs,
+        unverified_txids,
+        generated_at: chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+    })
+}
+
+/// Outputs of one transaction that pay `invoice`, matched the way the scanner matches them.
+fn invoice_outputs(
+    raw_hex: &str,
+    invoice: &Invoice,
+    ufvk: &str,
+    network: NetworkType,
+) -> anyhow::Result<Vec<ProofOutput>> {
+    let mut outputs = Vec::new();
+    for output in decrypt::try_decrypt_all_outputs(raw_hex, ufvk)? {
+        let recipient_hex = hex::encode(output.recipient_raw);
+        if matching::find_matching_invoice(std::slice::from_ref(invoice), &recipient_hex, &output.memo).is_none() {
+            continue;
+        }
+        outputs.push(ProofOutput {
+            txid: String::new(),
+            block_height: None,
+            pool: "orchard",
+            output_index: output.action_index,
+            address: crate::addresses::orchard_address(output.recipient_raw, network)?,
+            amount_zatoshis: output.amount_zatoshis,
+            memo: output.memo,
+        });
+    }
+
+    if let Some(tex_address) = &invoice.tex_address {
+        for output in decrypt::transparent_outputs(raw_hex) {
+            if matching::find_by_transparent(std::slice::from_ref(invoice), &hex::encode(output.receiver)).is_some() {
+                outputs.push(ProofOutput {
+                    txid: String::new(),
+                    block_height: None,
+                    pool: "transparent",
+                    output_index: output.vout,
+                    address: tex_address.clone(),
+                    amount_zatoshis: output.amount_zatoshis,
+                    memo: String::new(),
+                });
+            }
+        }
+    }
+    Ok(outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::invoices::test_invoice;
+    use crate::scanner::fixtures::{self, Output};
+
+    #[test]
+    fn test_invoice_outputs() {
+        let ufvk = fixtures::test_ufvk(1);
+        let derived = crate::addresses::derive_invoice_address(&ufvk, 4).unwrap();
+        let invoice = Invoice {
+            payment_address: derived.ua_string.clone(),
+            orchard_receiver_hex: Some(derived.orchard_receiver_hex.clone()),
+            ..test_invoice()
+        };
+        let tx = fixtures::transaction(&[
+            Output::to_wallet(1, 0, 5_000, ""),
+            Output::to_address(&derived.ua_string, 25_000_000, "CP-00000001"),
+        ], 3);
+
+        let outputs = invoice_outputs(&hex::encode(tx), &invoice, &ufvk, NetworkType::Test).unwrap();
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].output_index, 1);
+        assert_eq!(outputs[0].address, derived.ua_string);
+        assert_eq!(outputs[0].amount_zatoshis, 25_000_000);
+        assert_eq!(outputs[0].memo, "CP-00000001");
+
+        // Another wallet's key sees nothing.
+        let other = invoice_outputs(&hex::encode(fixtures::transaction(&[
+            Output::to_address(&derived.ua_string, 25_000_000, "CP-00000001"),
+        ], 3)), &invoice, &fixtures::test_ufvk(2), NetworkType::Test).unwrap();
+        assert!(other.is_empty());
+    }
+}
@@ -0,0 +1,53 @@
+//! Testnet stand-ins for the chain: apply a made-up payment, confirmation or expiry to an
+//! invoice through the same transitions and webhooks the scanner uses for real ones.
+
+use sqlx::SqlitePool;
+
+use super::{apply_confirmation, apply_mempool_payment, expire_invoices, requote_expired};
+use crate::config::Config;
+use crate::invoices::{self, pricing::PriceService, Invoice};
+
+/// Random txid, so each simulated payment is a separate transaction.
+pub fn fake_txid() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+/// A mempool payment of `amount_zatoshis` to the invoice.
+pub async fn payment(
+    config: &Config,
+    pool: &SqlitePool,
+    http: &reqwest::Client,
+    invoice: &Invoice,
+    txid: &str,
+    amount_zatoshis: i64,
+) -> anyhow::Result<()> {
+    apply_mempool_payment(config, pool, http, invoice, txid, amount_zatoshis).await?;
+    Ok(())
+}
+
+/// The detected payment is mined.
+pub async fn confirmation(
+    config: &Config,
+    pool: &SqlitePool,
+    http: &reqwest::Client,
+    invoice: &Invoice,
+) -> anyhow::Result<()> {
+    let txid = invoice.detected_txid.as_deref().unwrap_or_default();
+    apply_confirmation(config, pool, http, invoice, txid, None).await
+}
+
+/// The invoice runs out of time: it is requoted or expired as its `on_expiry` says.
+pub async fn expiry(
+    config: &Config,
+    pool: &SqlitePool,
+    http: &reqwest::Client,
+    prices: &PriceService,
+    invoice: &Invoice,
+) -> anyhow::Result<()> {
+    if !invoices::backdate_expiry(pool, &invoice.id).await? {
+        return Ok(());
+    }
+    requote_expired(config, pool, http, prices).await?;
+    expire_invoices(config, pool, http).await?;
+    Ok(())
+}
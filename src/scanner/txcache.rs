@@ -0,0 +1,110 @@
+//! Trial-decryption results by txid, shared by the mempool and block passes so a
+//! transaction decrypted while unconfirmed is not decrypted again once it is mined.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+
+use super::decrypt::DecryptedOutput;
+
+/// Merchants whose keys a transaction was trial-decrypted with. A pass builds one and
+/// shares it between all the transactions it decrypts.
+pub type KeySet = Arc<HashSet<String>>;
+
+/// Result of trial-decrypting one transaction with a set of merchant keys.
+pub enum Decryption {
+    /// The outputs each merchant's key decrypted, for the merchants with any.
+    Outputs(Vec<(String, Vec<DecryptedOutput>)>),
+    /// No merchant key decrypted an output.
+    DecryptionFailed,
+}
+
+impl Decryption {
+    pub fn outputs(&self) -> &[(String, Vec<DecryptedOutput>)] {
+        match self {
+            Decryption::Outputs(outputs) => outputs,
+            Decryption::DecryptionFailed => &[],
+        }
+    }
+}
+
+struct Entry {
+    key_set: KeySet,
+    decryption: Arc<Decryption>,
+    used: u64,
+}
+
+/// The `capacity` most recently used results, each with the merchants it was decrypted
+/// for. A result only answers for those merchants: the keys of merchants who joined or
+/// got a pending invoice since still have to be tried.
+pub struct TxCache {
+    capacity: usize,
+    entries: HashMap<String, Entry>,
+    by_use: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+impl TxCache {
+    pub fn new(capacity: usize) -> Self {
+        TxCache { capacity, entries: HashMap::new(), by_use: BTreeMap::new(), clock: 0 }
+    }
+
+    /// Whether the transaction was decrypted with every key in `key_set`.
+    pub fn covers(&self, txid: &str, key_set: &HashSet<String>) -> bool {
+        self.entries.get(txid).is_some_and(|e| key_set.is_subset(&e.key_set))
+    }
+
+    pub fn get(&mut self, txid: &str) -> Option<(KeySet, Arc<Decryption>)> {
+        let entry = self.entries.get_mut(txid)?;
+        self.clock += 1;
+        self.by_use.remove(&entry.used);
+        self.by_use.insert(self.clock, txid.to_string());
+        entry.used = self.clock;
+        Some((entry.key_set.clone(), entry.decryption.clone()))
+    }
+
+    pub fn insert(&mut self, txid: &str, key_set: KeySet, decryption: Arc<Decryption>) {
+        self.clock += 1;
+        let entry = Entry { key_set, decryption, used: self.clock };
+        if let Some(old) = self.entries.insert(txid.to_string(), entry) {
+            self.by_use.remove(&old.used);
+        }
+        self.by_use.insert(self.clock, txid.to_string());
+        while self.entries.len() > self.capacity {
+            let Some((_, oldest)) = self.by_use.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key_set(ids: &[&str]) -> KeySet {
+        Arc::new(ids.iter().map(|id| id.to_string()).collect())
+    }
+
+    #[test]
+    fn test_least_recently_used_is_evicted() {
+        let mut cache = TxCache::new(2);
+        let merchants = key_set(&["m1"]);
+        cache.insert("a", merchants.clone(), Arc::new(Decryption::DecryptionFailed));
+        cache.insert("b", merchants.clone(), Arc::new(Decryption::DecryptionFailed));
+        assert!(cache.get("a").is_some());
+
+        cache.insert("c", merchants.clone(), Arc::new(Decryption::DecryptionFailed));
+        assert!(cache.covers("a", &merchants));
+        assert!(!cache.covers("b", &merchants));
+        assert!(cache.covers("c", &merchants));
+    }
+
+    #[test]
+    fn test_result_covers_only_its_merchants() {
+        let mut cache = TxCache::new(2);
+        cache.insert("a", key_set(&["m1", "m2"]), Arc::new(Decryption::DecryptionFailed));
+        assert!(cache.covers("a", &key_set(&["m2"])));
+        assert!(!cache.covers("a", &key_set(&["m1", "m3"])));
+    }
+}
@@ -0,0 +1,187 @@
+//! Payments the scanner decrypted for a merchant but could not match to an open invoice:
+//! a wrong or missing memo, an invoice that has closed, a transfer to the base address.
+//! Kept per merchant so they can be attached to an invoice by hand instead of being lost.
+
+use chrono::Utc;
+use serde::Serialize;
+use sqlx::SqlitePool;
+use uuid::Uuid;
+
+use super::{apply_confirmation, apply_mempool_payment, decrypt};
+use crate::config::Config;
+use crate::invoices::{self, Invoice, InvoiceError};
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct UnmatchedPayment {
+    pub id: String,
+    pub txid: String,
+    /// Orchard action the output was in, telling apart several outputs of one transaction.
+    pub action_index: i64,
+    pub amount_zatoshis: i64,
+    pub memo: String,
+    /// Index of the wallet address it was sent to; 0 is the base address.
+    pub diversifier_index: i64,
+    /// The invoice that address was issued for, when it no longer takes payments.
+    pub address_invoice_id: Option<String>,
+    pub block_height: Option<i64>,
+    pub seen_at: String,
+    pub attached_invoice_id: Option<String>,
+    pub attached_at: Option<String>,
+}
+
+const COLUMNS: &str = "id, txid, action_index, amount_zatoshis, memo, diversifier_index, address_invoice_id,
+     block_height, seen_at, attached_invoice_id, attached_at";
+
+/// Record an unmatched output paid to `merchant_id`. Change outputs (which no external
+/// address index decodes) and dust are skipped. Returns the payment the first time an
+/// output is recorded; seeing it again only fills in the block height.
+pub async fn record(
+    pool: &SqlitePool,
+    merchant_id: &str,
+    keys: &decrypt::CachedKeys,
+    txid: &str,
+    output: &decrypt::DecryptedOutput,
+    block_height: Option<u64>,
+) -> anyhow::Result<Option<UnmatchedPayment>> {
+    let Some(diversifier_index) = keys.external_index(&output.recipient_raw) else {
+        return Ok(None);
+    };
+    if (output.amount_zatoshis as i64) < decrypt::DUST_THRESHOLD_MIN_ZATOSHIS {
+        return Ok(None);
+    }
+
+    let address_invoice_id: Option<String> = sqlx::query_scalar(
+        "SELECT id FROM invoices WHERE merchant_id = ? AND orchard_receiver_hex = ?"
+    )
+    .bind(merchant_id)
+    .bind(hex::encode(output.recipient_raw))
+    .fetch_optional(pool)
+    .await?;
+
+    let payment = UnmatchedPayment {
+        id: Uuid::new_v4().to_string(),
+        txid: txid.to_string(),
+        action_index: output.action_index as i64,
+        amount_zatoshis: output.amount_zatoshis as i64,
+        memo: output.memo.trim().to_string(),
+        diversifier_index: diversifier_index as i64,
+        address_invoice_id,
+        block_height: block_height.map(|h| h as i64),
+        seen_at: Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+        attached_invoice_id: None,
+        attached_at: None,
+    };
+    let inserted = sqlx::query(
+        "INSERT INTO unmatched_payments (id, merchant_id, txid, action_index, amount_zatoshis, memo,
+         diversifier_index, address_invoice_id, block_height, seen_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+         ON CONFLICT(merchant_id, txid, action_index) DO NOTHING"
+    )
+    .bind(&payment.id)
+    .bind(merchant_id)
+    .bind(&payment.txid)
+    .bind(payment.action_index)
+    .bind(payment.amount_zatoshis)
+    .bind(&payment.memo)
+    .bind(payment.diversifier_index)
+    .bind(&payment.address_invoice_id)
+    .bind(payment.block_height)
+    .bind(&payment.seen_at)
+    .execute(pool)
+    .await?
+    .rows_affected() > 0;
+
+    if !inserted {
+        sqlx::query(
+            "UPDATE unmatched_payments SET block_height = COALESCE(block_height, ?)
+             WHERE merchant_id = ? AND txid = ? AND action_index = ?"
+        )
+        .bind(payment.block_height)
+        .bind(merchant_id)
+        .bind(txid)
+        .bind(payment.action_index)
+        .execute(pool)
+        .await?;
+        return Ok(None);
+    }
+    tracing::warn!(
+        merchant_id, txid, diversifier_index,
+        amount_zatoshis = payment.amount_zatoshis,
+        "Unmatched payment recorded"
+    );
+    Ok(Some(payment))
+}
+
+/// The merchant's unmatched payments, newest first; attached ones only when asked for.
+pub async fn list(
+    pool: &SqlitePool,
+    merchant_id: &str,
+    include_attached: bool,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<UnmatchedPayment>, InvoiceError> {
+    let rows = sqlx::query_as::<_, UnmatchedPayment>(&format!(
+        "SELECT {COLUMNS} FROM unmatched_payments
+         WHERE merchant_id = ? AND (? OR attached_invoice_id IS NULL)
+         ORDER BY seen_at DESC LIMIT ? OFFSET ?"
+    ))
+    .bind(merchant_id)
+    .bind(include_attached)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows)
+}
+
+pub async fn get(pool: &SqlitePool, merchant_id: &str, id: &str) -> Result<Option<UnmatchedPayment>, InvoiceError> {
+    let row = sqlx::query_as::<_, UnmatchedPayment>(&format!(
+        "SELECT {COLUMNS} FROM unmatched_payments WHERE id = ? AND merchant_id = ?"
+    ))
+    .bind(id)
+    .bind(merchant_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row)
+}
+
+/// Mark the payment as attached to `invoice_id`. False if it already was (or is not the
+/// merchant's), so two attach requests cannot both apply it.
+pub async fn claim(pool: &SqlitePool, merchant_id: &str, id: &str, invoice_id: &str) -> Result<bool, InvoiceError> {
+    let claimed = sqlx::query(
+        "UPDATE unmatched_payments SET attached_invoice_id = ?, attached_at = ?
+         WHERE id = ? AND merchant_id = ? AND attached_invoice_id IS NULL"
+    )
+    .bind(invoice_id)
+    .bind(Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string())
+    .bind(id)
+    .bind(merchant_id)
+    .execute(pool)
+    .await?
+    .rows_affected() > 0;
+    Ok(claimed)
+}
+
+/// Apply a claimed payment to the invoice as if the scanner had matched it: a pending or
+/// underpaid invoice is detected (or stays underpaid), an expired one becomes `paid_late`,
+/// and a mined payment confirms it. Same webhooks as a match.
+pub async fn apply(
+    config: &Config,
+    pool: &SqlitePool,
+    http: &reqwest::Client,
+    invoice: &Invoice,
+    payment: &UnmatchedPayment,
+) -> anyhow::Result<()> {
+    let block_height = payment.block_height.map(|h| h as u64);
+    invoices::events::record(pool, &invoice.id, "payment_attached", Some(&payment.txid), block_height, Some(serde_json::json!({
+        "amount_zatoshis": payment.amount_zatoshis,
+    }))).await;
+    apply_mempool_payment(config, pool, http, invoice, &payment.txid, payment.amount_zatoshis).await?;
+
+    if block_height.is_some() {
+        if let Some(updated) = invoices::get_invoice(pool, &invoice.id).await?.filter(|i| i.status == "detected") {
+            apply_confirmation(config, pool, http, &updated, &payment.txid, block_height).await?;
+        }
+    }
+    Ok(())
+}
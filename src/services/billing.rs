@@ -0,0 +1,104 @@
+use sqlx::SqlitePool;
+
+use crate::billing::{self, BillingCycle, BillingError, BillingSummary};
+use crate::config::Config;
+use crate::invoices::pricing::PriceService;
+use crate::merchants::address_book::{self, BookAddress};
+
+/// Settlement invoices are due within this many days before the account is suspended.
+const SETTLEMENT_GRACE_DAYS: i64 = 7;
+
+/// Balances below this are treated as settled.
+const DUST_ZEC: f64 = 0.00001;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SettleError {
+    #[error("Billing not enabled")]
+    NotEnabled,
+    #[error("Exchange rate unavailable, try again shortly")]
+    RateUnavailable,
+    #[error(transparent)]
+    Billing(#[from] BillingError),
+}
+
+#[derive(Debug)]
+pub enum Settlement {
+    NothingOwed,
+    /// `pay_from` lists the merchant's `settlement_source` addresses, to pay from.
+    Invoiced { invoice_id: String, outstanding_zec: f64, pay_from: Vec<BookAddress> },
+}
+
+#[derive(Clone)]
+pub struct BillingService {
+    pool: SqlitePool,
+    config: Config,
+    prices: PriceService,
+}
+
+impl BillingService {
+    pub fn new(pool: SqlitePool, config: Config, prices: PriceService) -> Self {
+        Self { pool, config, prices }
+    }
+
+    /// The merchant's fee summary, or None when the instance charges no fees.
+    pub async fn summary(&self, merchant_id: &str) -> Result<Option<BillingSummary>, BillingError> {
+        if !self.config.fee_enabled() {
+            return Ok(None);
+        }
+        billing::get_billing_summary(&self.pool, merchant_id, &self.config).await.map(Some)
+    }
+
+    pub async fn history(&self, merchant_id: &str) -> Result<Vec<BillingCycle>, BillingError> {
+        billing::get_billing_history(&self.pool, merchant_id).await
+    }
+
+    /// The billing status (`past_due` or `suspended`) that stops the merchant taking new
+    /// invoices, if any. A failed lookup does not block.
+    pub async fn blocking_status(&self, merchant_id: &str) -> Option<String> {
+        if !self.config.fee_enabled() {
+            return None;
+        }
+        billing::get_merchant_billing_status(&self.pool, merchant_id)
+            .await
+            .ok()
+            .filter(|status| status == "past_due" || status == "suspended")
+    }
+
+    /// Invoice the merchant's outstanding fees, converted at today's rate when the cycle
+    /// accrued in fiat.
+    pub async fn settle(&self, merchant_id: &str) -> Result<Settlement, SettleError> {
+        let fee_ufvk = self.config.fee_ufvk.clone().ok_or(SettleError::NotEnabled)?;
+        let summary = billing::get_billing_summary(&self.pool, merchant_id, &self.config).await?;
+
+        let (zec_eur, zec_usd) = match self.prices.get_rates().await {
+            Ok(rates) => (rates.zec_eur, rates.zec_usd),
+            Err(_) => (0.0, 0.0),
+        };
+        let outstanding_zec = match &summary.current_cycle {
+            Some(cycle) => cycle.settlement_zec(zec_eur, zec_usd).ok_or(SettleError::RateUnavailable)?,
+            None => summary.outstanding_zec,
+        };
+        if outstanding_zec < DUST_ZEC {
+            return Ok(Settlement::NothingOwed);
+        }
+
+        let grace_until = (chrono::Utc::now() + chrono::Duration::days(SETTLEMENT_GRACE_DAYS))
+            .format("%Y-%m-%dT%H:%M:%SZ")
+            .to_string();
+        let cycle_id = summary.current_cycle.as_ref().map(|c| c.id.as_str());
+        let invoice_id = billing::create_settlement_invoice(
+            &self.pool, merchant_id, cycle_id, outstanding_zec, &fee_ufvk,
+            zec_eur, zec_usd, &grace_until,
+        )
+        .await?;
+        // Advisory only: a shielded payment does not reveal its sender, so the scanner
+        // cannot hold a settlement to these. A failed lookup does not block settling.
+        let pay_from = address_book::list(&self.pool, merchant_id, Some("settlement_source"), &self.config.encryption_key)
+            .await
+            .unwrap_or_else(|e| {
+                tracing::warn!(merchant_id, error = %e, "Failed to load settlement source addresses");
+                Vec::new()
+            });
+        Ok(Settlement::Invoiced { invoice_id, outstanding_zec, pay_from })
+    }
+}
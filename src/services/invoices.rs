@@ -0,0 +1,603 @@
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use super::BillingService;
+use crate::config::Config;
+use crate::invoices::events::InvoiceEvent;
+use crate::invoices::pricing::PriceService;
+use crate::invoices::requests::{self, EmailInvoiceRequest, PaymentRequest};
+use crate::invoices::templates::{self, InvoiceTemplate, TemplateFields, UpdateTemplateRequest};
+use crate::invoices::{self, CreateInvoiceRequest, CreateInvoiceResponse, Invoice, InvoiceError, InvoiceStatus};
+use crate::merchants::address_book::{self, BookAddress};
+use crate::merchants::{Merchant, MerchantError};
+use crate::scanner::unmatched::{self, UnmatchedPayment};
+use crate::validation::{self, ValidationError};
+
+#[derive(Debug, thiserror::Error)]
+pub enum CreateInvoiceError {
+    #[error("{}", .0.message)]
+    Validation(ValidationError),
+    /// Carries the merchant's billing status (`past_due` or `suspended`).
+    #[error("Merchant account has outstanding fees")]
+    BillingPastDue(String),
+    #[error("Price feed unavailable")]
+    PriceUnavailable,
+    #[error(transparent)]
+    Invoice(#[from] InvoiceError),
+}
+
+impl From<ValidationError> for CreateInvoiceError {
+    fn from(e: ValidationError) -> Self {
+        CreateInvoiceError::Validation(e)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RefundUriError {
+    #[error("Only confirmed, expired or paid_late invoices with received funds can be refunded")]
+    NotRefundable,
+    #[error("Buyer has not provided a refund address")]
+    NoRefundAddress,
+    #[error("amount must be positive and no more than the received amount")]
+    InvalidAmount { received_zatoshis: i64 },
+    #[error("source must be one of the merchant's refund_source addresses")]
+    UnknownSource,
+    #[error(transparent)]
+    Invoice(#[from] InvoiceError),
+    #[error(transparent)]
+    Merchant(#[from] MerchantError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AttachPaymentError {
+    #[error("Unmatched payment not found")]
+    NotFound,
+    #[error("Payment is already attached to an invoice")]
+    AlreadyAttached,
+    #[error("Only pending, underpaid or expired invoices can take a payment")]
+    NotPayable,
+    #[error(transparent)]
+    Invoice(#[from] InvoiceError),
+    #[error("Failed to apply the payment: {0}")]
+    Apply(#[source] anyhow::Error),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TemplateError {
+    #[error("{}", .0.message)]
+    Validation(ValidationError),
+    #[error("Invoice template not found")]
+    NotFound,
+    #[error("A merchant can keep at most {} invoice templates", templates::MAX_TEMPLATES)]
+    Full,
+    #[error("An invoice template with this name already exists")]
+    Duplicate,
+    #[error(transparent)]
+    Issue(#[from] CreateInvoiceError),
+    #[error(transparent)]
+    Invoice(#[from] InvoiceError),
+}
+
+impl From<ValidationError> for TemplateError {
+    fn from(e: ValidationError) -> Self {
+        TemplateError::Validation(e)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PaymentRequestError {
+    #[error("{}", .0.message)]
+    Validation(ValidationError),
+    #[error("Email is not configured on this server")]
+    EmailUnavailable,
+    #[error("At most {} payment requests can be emailed per hour", requests::MAX_PER_HOUR)]
+    TooMany,
+    #[error("Payment request not found")]
+    NotFound,
+    #[error(transparent)]
+    Create(#[from] CreateInvoiceError),
+    #[error(transparent)]
+    Invoice(#[from] InvoiceError),
+    #[error("Failed to send the payment request: {0}")]
+    Email(#[source] anyhow::Error),
+}
+
+impl From<ValidationError> for PaymentRequestError {
+    fn from(e: ValidationError) -> Self {
+        PaymentRequestError::Validation(e)
+    }
+}
+
+/// ZIP-321 request paying a buyer back, for the merchant's wallet.
+#[derive(Debug, Serialize)]
+pub struct RefundUri {
+    pub invoice_id: String,
+    pub refund_address: String,
+    pub amount_zec: f64,
+    pub amount_zatoshis: i64,
+    pub memo: String,
+    pub zcash_uri: String,
+    /// The merchant's wallet to send the refund from, from their address book.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pay_from: Option<BookAddress>,
+}
+
+#[derive(Clone)]
+pub struct InvoiceService {
+    pool: SqlitePool,
+    config: Config,
+    prices: PriceService,
+    billing: BillingService,
+    /// Delivers the `created` webhook and those of attached payments.
+    http: reqwest::Client,
+}
+
+impl InvoiceService {
+    pub fn new(pool: SqlitePool, config: Config, prices: PriceService, http: reqwest::Client) -> Self {
+        let billing = BillingService::new(pool.clone(), config.clone(), prices.clone());
+        Self { pool, config, prices, billing, http }
+    }
+
+    /// Validate, price and store a new invoice for `merchant`.
+    pub async fn create(
+        &self,
+        merchant: &Merchant,
+        mut req: CreateInvoiceRequest,
+    ) -> Result<CreateInvoiceResponse, CreateInvoiceError> {
+        validate_create(&req, &merchant.network)?;
+        let (display_currency, locale) =
+            invoices::display::validate(req.display_currency.as_deref(), req.locale.as_deref())?;
+        req.display_currency = display_currency;
+        req.locale = locale;
+
+        if let Some(status) = self.billing.blocking_status(&merchant.id).await {
+            return Err(CreateInvoiceError::BillingPastDue(status));
+        }
+        self.issue(merchant, &req).await
+    }
+
+    /// Price and store a request the caller has already validated and cleared with
+    /// billing; checkout builds its own from the product and checks billing before it
+    /// spends the buyer's checkout token.
+    pub async fn issue(
+        &self,
+        merchant: &Merchant,
+        req: &CreateInvoiceRequest,
+    ) -> Result<CreateInvoiceResponse, CreateInvoiceError> {
+        let rates = self.prices.get_rates().await.map_err(|e| {
+            tracing::error!(error = %e, "Failed to fetch ZEC rate");
+            CreateInvoiceError::PriceUnavailable
+        })?;
+
+        let fee_config = invoices::FeeConfig::for_merchant(&self.pool, &self.config, merchant).await;
+
+        let created = invoices::create_invoice(
+            &self.pool,
+            &merchant.id,
+            &merchant.ufvk,
+            req,
+            rates.zec_eur,
+            rates.zec_usd,
+            req.expiry_minutes.unwrap_or(self.config.invoice_expiry_minutes),
+            fee_config.as_ref(),
+            &invoices::InvoiceQuotas::from_config(&self.config),
+        )
+        .await?;
+
+        match crate::webhooks::enqueue_created(&self.pool, &created).await {
+            Ok(Some(merchant_id)) => {
+                crate::webhooks::spawn_delivery(&self.pool, &self.http, merchant_id, &self.config.encryption_key)
+            }
+            Ok(None) => {}
+            Err(e) => tracing::error!(invoice_id = %created.invoice_id, error = %e, "Failed to queue created webhook"),
+        }
+        Ok(created)
+    }
+
+    /// One of `merchant`'s invoices. Someone else's invoice is `NotFound`, not a distinct error.
+    pub async fn get(&self, merchant: &Merchant, invoice_id: &str) -> Result<Invoice, InvoiceError> {
+        invoices::get_invoice(&self.pool, invoice_id)
+            .await?
+            .filter(|inv| inv.merchant_id == merchant.id)
+            .ok_or(InvoiceError::NotFound)
+    }
+
+    /// Payment status of one of `merchant`'s invoices.
+    pub async fn status(&self, merchant: &Merchant, invoice_id: &str) -> Result<InvoiceStatus, InvoiceError> {
+        self.get(merchant, invoice_id).await?;
+        invoices::get_invoice_status(&self.pool, invoice_id)
+            .await?
+            .ok_or(InvoiceError::NotFound)
+    }
+
+    /// Lifecycle timeline of one of `merchant`'s invoices.
+    pub async fn events(&self, merchant: &Merchant, invoice_id: &str) -> Result<Vec<InvoiceEvent>, InvoiceError> {
+        self.get(merchant, invoice_id).await?;
+        invoices::events::list(&self.pool, invoice_id).await
+    }
+
+    /// `merchant`'s payments the scanner could not match, newest first.
+    pub async fn unmatched_payments(
+        &self,
+        merchant: &Merchant,
+        include_attached: bool,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<UnmatchedPayment>, InvoiceError> {
+        unmatched::list(&self.pool, &merchant.id, include_attached, limit, offset).await
+    }
+
+    /// Settle one of `merchant`'s unmatched payments against one of their invoices.
+    /// Returns the invoice as it stands afterwards.
+    pub async fn attach_payment(
+        &self,
+        merchant: &Merchant,
+        payment_id: &str,
+        invoice_id: &str,
+    ) -> Result<Invoice, AttachPaymentError> {
+        let payment = unmatched::get(&self.pool, &merchant.id, payment_id)
+            .await?
+            .ok_or(AttachPaymentError::NotFound)?;
+        if payment.attached_invoice_id.is_some() {
+            return Err(AttachPaymentError::AlreadyAttached);
+        }
+        let invoice = self.get(merchant, invoice_id).await?;
+        if !matches!(invoice.status.as_str(), "pending" | "underpaid" | "expired") {
+            return Err(AttachPaymentError::NotPayable);
+        }
+        if !unmatched::claim(&self.pool, &merchant.id, payment_id, invoice_id).await? {
+            return Err(AttachPaymentError::AlreadyAttached);
+        }
+
+        unmatched::apply(&self.config, &self.pool, &self.http, &invoice, &payment)
+            .await
+            .map_err(AttachPaymentError::Apply)?;
+        tracing::info!(merchant_id = %merchant.id, payment_id, invoice_id, "Unmatched payment attached");
+        self.get(merchant, invoice_id).await.map_err(Into::into)
+    }
+
+    /// Refund request for the buyer's refund address: the full received amount, or
+    /// `amount_zec` of it, sent from the `source` address book entry or the default
+    /// refund source.
+    pub async fn refund_uri(
+        &self,
+        merchant: &Merchant,
+        invoice_id: &str,
+        amount_zec: Option<f64>,
+        source: Option<&str>,
+    ) -> Result<RefundUri, RefundUriError> {
+        let inv = self.get(merchant, invoice_id).await?;
+        if !matches!(inv.status.as_str(), "confirmed" | "expired" | "paid_late") || inv.received_zatoshis <= 0 {
+            return Err(RefundUriError::NotRefundable);
+        }
+        let refund_address = inv
+            .refund_address
+            .filter(|a| !a.is_empty())
+            .ok_or(RefundUriError::NoRefundAddress)?;
+
+        let amount_zatoshis = match amount_zec {
+            None => inv.received_zatoshis,
+            Some(zec) => {
+                let z = invoices::amounts::zec_to_zatoshis(zec);
+                if z <= 0 || z > inv.received_zatoshis {
+                    return Err(RefundUriError::InvalidAmount { received_zatoshis: inv.received_zatoshis });
+                }
+                z
+            }
+        };
+
+        let key = &self.config.encryption_key;
+        let pay_from = match source {
+            Some(id) => Some(
+                address_book::get(&self.pool, &merchant.id, id, key)
+                    .await?
+                    .filter(|a| a.purpose == "refund_source")
+                    .ok_or(RefundUriError::UnknownSource)?,
+            ),
+            None => address_book::default_for(&self.pool, &merchant.id, "refund_source", key).await?,
+        };
+
+        Ok(RefundUri {
+            zcash_uri: invoices::build_refund_uri(&refund_address, amount_zatoshis, &inv.memo_code),
+            memo: format!("REFUND-{}", inv.memo_code),
+            invoice_id: inv.id,
+            refund_address,
+            amount_zec: invoices::zatoshis_to_zec(amount_zatoshis),
+            amount_zatoshis,
+            pay_from,
+        })
+    }
+
+    pub async fn templates(&self, merchant: &Merchant) -> Result<Vec<InvoiceTemplate>, TemplateError> {
+        Ok(templates::list(&self.pool, &merchant.id).await?)
+    }
+
+    pub async fn add_template(&self, merchant: &Merchant, mut fields: TemplateFields) -> Result<InvoiceTemplate, TemplateError> {
+        normalize_template(&mut fields)?;
+        match templates::create(&self.pool, &merchant.id, &fields).await {
+            Ok(Some(template)) => Ok(template),
+            Ok(None) => Err(TemplateError::Full),
+            Err(e) => Err(duplicate_template(e)),
+        }
+    }
+
+    pub async fn update_template(
+        &self,
+        merchant: &Merchant,
+        id: &str,
+        changes: UpdateTemplateRequest,
+    ) -> Result<InvoiceTemplate, TemplateError> {
+        let existing = templates::get(&self.pool, &merchant.id, id)
+            .await?
+            .ok_or(TemplateError::NotFound)?;
+        let mut fields = changes.apply(&existing);
+        normalize_template(&mut fields)?;
+        templates::update(&self.pool, &merchant.id, id, &fields)
+            .await
+            .map_err(duplicate_template)?
+            .ok_or(TemplateError::NotFound)
+    }
+
+    pub async fn remove_template(&self, merchant: &Merchant, id: &str) -> Result<(), TemplateError> {
+        if !templates::remove(&self.pool, &merchant.id, id).await? {
+            return Err(TemplateError::NotFound);
+        }
+        Ok(())
+    }
+
+    /// Create an invoice from one of `merchant`'s templates, at the current rate.
+    pub async fn issue_template(&self, merchant: &Merchant, id: &str) -> Result<CreateInvoiceResponse, TemplateError> {
+        let template = templates::get(&self.pool, &merchant.id, id)
+            .await?
+            .ok_or(TemplateError::NotFound)?;
+        let created = self.create(merchant, template.to_request()).await?;
+        tracing::info!(merchant_id = %merchant.id, template_id = id, invoice_id = %created.invoice_id, "Invoice issued from template");
+        Ok(created)
+    }
+
+    /// Create an invoice for `req` and email the buyer a link to pay it.
+    pub async fn email_invoice(&self, merchant: &Merchant, req: EmailInvoiceRequest) -> Result<PaymentRequest, PaymentRequestError> {
+        if !self.config.smtp_configured() {
+            return Err(PaymentRequestError::EmailUnavailable);
+        }
+        let email = req.email.trim();
+        validation::validate_email_format("email", email)?;
+        validation::validate_optional_length("message", &req.message, requests::MAX_MESSAGE_LEN)?;
+        if req.expiry_minutes.is_some_and(|m| !(1..=templates::MAX_EXPIRY_MINUTES).contains(&m)) {
+            return Err(ValidationError::invalid(
+                "expiry_minutes",
+                &format!("must be between 1 and {}", templates::MAX_EXPIRY_MINUTES),
+            ).into());
+        }
+        if requests::sent_last_hour(&self.pool, &merchant.id).await? >= requests::MAX_PER_HOUR {
+            return Err(PaymentRequestError::TooMany);
+        }
+
+        let created = self.create(merchant, req.to_invoice_request()).await?;
+        let message = req.message.as_deref().map(str::trim).filter(|m| !m.is_empty());
+        let key = &self.config.encryption_key;
+        let id = requests::create(&self.pool, &merchant.id, &created.invoice_id, email, message, key).await?;
+
+        let invoice = invoices::get_invoice(&self.pool, &created.invoice_id)
+            .await?
+            .ok_or(InvoiceError::NotFound)?;
+        let price = invoices::display::DisplayPrice::of(&invoice);
+        let sent = crate::email::send_payment_request(&self.pool, &self.config, email, &crate::email::PaymentRequestEmail {
+            request_id: &id,
+            merchant_name: &merchant.name,
+            message,
+            memo_code: &invoice.memo_code,
+            product_name: invoice.product_name.as_deref(),
+            price_fiat: price.amount,
+            currency: &price.currency,
+            locale: price.locale.as_deref(),
+            price_zec: invoice.price_zec,
+            expires_at: &invoice.expires_at,
+        })
+        .await;
+        if let Err(e) = sent {
+            tracing::error!(merchant_id = %merchant.id, request_id = %id, error = %e, "Failed to send payment request");
+            return Err(PaymentRequestError::Email(e));
+        }
+
+        tracing::info!(merchant_id = %merchant.id, request_id = %id, invoice_id = %invoice.id, "Payment request emailed");
+        requests::get(&self.pool, &merchant.id, &id, key)
+            .await?
+            .ok_or(PaymentRequestError::NotFound)
+    }
+
+    pub async fn payment_requests(
+        &self,
+        merchant: &Merchant,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<PaymentRequest>, PaymentRequestError> {
+        Ok(requests::list(&self.pool, &merchant.id, limit, offset, &self.config.encryption_key).await?)
+    }
+
+    /// Count an opening of a payment request's email and return the invoice it is for.
+    pub async fn open_payment_request(&self, id: &str) -> Result<Invoice, PaymentRequestError> {
+        let invoice_id = requests::record_open(&self.pool, id)
+            .await?
+            .ok_or(PaymentRequestError::NotFound)?;
+        Ok(invoices::get_invoice(&self.pool, &invoice_id)
+            .await?
+            .ok_or(InvoiceError::NotFound)?)
+    }
+}
+
+fn duplicate_template(e: InvoiceError) -> TemplateError {
+    match e {
+        InvoiceError::Database(sqlx::Error::Database(db)) if db.is_unique_violation() => TemplateError::Duplicate,
+        e => e.into(),
+    }
+}
+
+/// Validate a template and put it in the form it is stored in: trimmed name, upper-case
+/// memo prefix, and the defaults filled in.
+fn normalize_template(fields: &mut TemplateFields) -> Result<(), ValidationError> {
+    fields.name = fields.name.trim().to_string();
+    validation::validate_length("name", &fields.name, 100)?;
+    if fields.name.is_empty() {
+        return Err(ValidationError::invalid("name", "must not be empty"));
+    }
+    if !(fields.price_eur.is_finite() && fields.price_eur > 0.0) {
+        return Err(ValidationError::invalid("price_eur", "must be positive"));
+    }
+    let currency = fields.currency.get_or_insert_with(|| "EUR".into());
+    if currency != "EUR" && currency != "USD" {
+        return Err(ValidationError::invalid("currency", "must be EUR or USD"));
+    }
+    validation::validate_optional_length("product_name", &fields.product_name, 200)?;
+    if let Some(prefix) = fields.memo_prefix.as_mut() {
+        *prefix = prefix.trim().to_ascii_uppercase();
+        if prefix.is_empty() || prefix.len() > 12 || !prefix.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(ValidationError::invalid("memo_prefix", "must be 1 to 12 letters or digits"));
+        }
+        if templates::RESERVED_MEMO_PREFIXES.contains(&prefix.as_str()) {
+            return Err(ValidationError::invalid("memo_prefix", "is reserved"));
+        }
+    }
+    if fields.expiry_minutes.is_some_and(|m| !(1..=templates::MAX_EXPIRY_MINUTES).contains(&m)) {
+        return Err(ValidationError::invalid(
+            "expiry_minutes",
+            &format!("must be between 1 and {}", templates::MAX_EXPIRY_MINUTES),
+        ));
+    }
+    let on_expiry = fields.on_expiry.get_or_insert_with(|| "expire".into());
+    if on_expiry != "expire" && on_expiry != "requote" {
+        return Err(ValidationError::invalid("on_expiry", "must be expire or requote"));
+    }
+    Ok(())
+}
+
+fn validate_create(req: &CreateInvoiceRequest, network: &str) -> Result<(), ValidationError> {
+    validation::validate_optional_length("product_id", &req.product_id, 100)?;
+    validation::validate_optional_length("product_name", &req.product_name, 200)?;
+    validation::validate_optional_length("size", &req.size, 100)?;
+    if req.quantity.is_some_and(|q| q < 1) {
+        return Err(ValidationError::invalid("quantity", "must be at least 1"));
+    }
+    validation::validate_optional_length("currency", &req.currency, 10)?;
+    if let Some(ref addr) = req.refund_address {
+        if !addr.is_empty() {
+            validation::validate_zcash_address_network("refund_address", addr, network == "testnet")?;
+        }
+    }
+    if let Some(ref on_expiry) = req.on_expiry {
+        if on_expiry != "expire" && on_expiry != "requote" {
+            return Err(ValidationError::invalid("on_expiry", "must be expire or requote"));
+        }
+    }
+    if req.price_eur < 0.0 {
+        return Err(ValidationError::invalid("price_eur", "must be non-negative"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(price_eur: f64) -> CreateInvoiceRequest {
+        CreateInvoiceRequest {
+            product_id: None,
+            product_name: Some("T-Shirt".into()),
+            size: None,
+            quantity: Some(2),
+            price_eur,
+            currency: None,
+            refund_address: None,
+            tax: None,
+            on_expiry: Some("requote".into()),
+            display_currency: None,
+            locale: None,
+            custom_fields: None,
+            memo_prefix: None,
+            expiry_minutes: None,
+            claim_code: false,
+        }
+    }
+
+    #[test]
+    fn test_validate_create() {
+        assert!(validate_create(&request(65.0), "testnet").is_ok());
+        assert_eq!(validate_create(&request(-1.0), "testnet").unwrap_err().field, "price_eur");
+        let mut req = request(65.0);
+        req.quantity = Some(0);
+        assert_eq!(validate_create(&req, "testnet").unwrap_err().field, "quantity");
+        let mut req = request(65.0);
+        req.on_expiry = Some("refund".into());
+        assert_eq!(validate_create(&req, "testnet").unwrap_err().field, "on_expiry");
+
+        // A refund goes back on the merchant's network.
+        let mut req = request(65.0);
+        req.refund_address = Some(crate::scanner::fixtures::test_address(2, 0));
+        assert!(validate_create(&req, "testnet").is_ok());
+        assert_eq!(validate_create(&req, "mainnet").unwrap_err().field, "refund_address");
+    }
+
+    /// An invoice of `test_invoice` that asked to be requoted and ran out of time.
+    async fn lapsed_requote_invoice(pool: &SqlitePool, merchant_id: &str) -> String {
+        let id = crate::db::test_invoice(pool, merchant_id, 1).await.invoice_id;
+        sqlx::query("UPDATE invoices SET on_expiry = 'requote', expires_at = '2020-01-01T00:00:00Z' WHERE id = ?")
+            .bind(&id)
+            .execute(pool)
+            .await
+            .unwrap();
+        id
+    }
+
+    #[tokio::test]
+    async fn test_lapsed_invoice_is_requoted_at_the_current_rate() {
+        let pool = crate::db::test_pool().await;
+        let merchant = crate::db::test_merchant(&pool, 1).await;
+        let id = lapsed_requote_invoice(&pool, &merchant.merchant_id).await;
+        let partly_paid = lapsed_requote_invoice(&pool, &merchant.merchant_id).await;
+        invoices::mark_underpaid(&pool, &partly_paid, 10_000_000, "tx-1").await.unwrap();
+
+        // 10 EUR was 0.25 ZEC at 40 EUR; at 50 EUR it is 0.2 ZEC, due within a fresh window.
+        let requoted = invoices::requote_expired(&pool, 50.0, 55.0, 30, None).await.unwrap();
+        assert_eq!(requoted.len(), 1);
+        assert_eq!((requoted[0].invoice_id.as_str(), requoted[0].price_zatoshis), (id.as_str(), 20_000_000));
+        let invoice = invoices::get_invoice(&pool, &id).await.unwrap().unwrap();
+        assert_eq!((invoice.status.as_str(), invoice.price_zatoshis), ("pending", 20_000_000));
+        assert_eq!((invoice.zec_rate_at_creation, invoice.requote_count), (50.0, 1));
+        assert!(invoice.expires_at > chrono::Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string());
+        assert!(invoice.zcash_uri.contains("amount=0.20000000&"), "{}", invoice.zcash_uri);
+
+        // The old quote is gone: the invoice is not due again, and one with a payment
+        // towards its quote keeps it.
+        assert!(invoices::requote_expired(&pool, 60.0, 66.0, 30, None).await.unwrap().is_empty());
+        let kept = invoices::get_invoice(&pool, &partly_paid).await.unwrap().unwrap();
+        assert_eq!((kept.price_zatoshis, kept.requote_count), (25_000_000, 0));
+    }
+
+    #[test]
+    fn test_normalize_template() {
+        let template = || TemplateFields {
+            name: " Hosting ".into(),
+            price_eur: 20.0,
+            currency: None,
+            product_name: None,
+            memo_prefix: Some("host".into()),
+            expiry_minutes: None,
+            on_expiry: None,
+        };
+        let mut fields = template();
+        normalize_template(&mut fields).unwrap();
+        assert_eq!(fields.name, "Hosting");
+        assert_eq!(fields.memo_prefix.as_deref(), Some("HOST"));
+        assert_eq!(fields.currency.as_deref(), Some("EUR"));
+        assert_eq!(fields.on_expiry.as_deref(), Some("expire"));
+
+        for prefix in ["fee", "HOST-1", "", "ABCDEFGHIJKLM"] {
+            let mut fields = TemplateFields { memo_prefix: Some(prefix.into()), ..template() };
+            assert_eq!(normalize_template(&mut fields).unwrap_err().field, "memo_prefix", "{prefix}");
+        }
+        let mut fields = TemplateFields { expiry_minutes: Some(0), ..template() };
+        assert_eq!(normalize_template(&mut fields).unwrap_err().field, "expiry_minutes");
+        let mut fields = TemplateFields { price_eur: 0.0, ..template() };
+        assert_eq!(normalize_template(&mut fields).unwrap_err().field, "price_eur");
+    }
+}
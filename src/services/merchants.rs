@@ -0,0 +1,218 @@
+use sqlx::SqlitePool;
+
+use crate::config::Config;
+use crate::merchants::address_book::{self, BookAddress};
+use crate::merchants::{self, CreateMerchantRequest, CreateMerchantResponse, Merchant, MerchantError, MerchantStats, SandboxReset};
+use crate::scanner::cipherscan::CipherScans;
+use crate::scanner::dry_run;
+use crate::validation::{self, ValidationError};
+
+#[derive(Debug, thiserror::Error)]
+pub enum RegisterError {
+    #[error("{}", .0.message)]
+    Validation(ValidationError),
+    #[error(transparent)]
+    Merchant(#[from] MerchantError),
+}
+
+impl From<ValidationError> for RegisterError {
+    fn from(e: ValidationError) -> Self {
+        RegisterError::Validation(e)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeleteAccountError {
+    #[error("Cannot delete account with outstanding billing balance. Please settle your fees first.")]
+    OutstandingBalance,
+    #[error(transparent)]
+    Merchant(#[from] MerchantError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ResetSandboxError {
+    #[error("Only testnet accounts can be reset")]
+    NotTestnet,
+    #[error(transparent)]
+    Merchant(#[from] MerchantError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AddressBookError {
+    #[error("{}", .0.message)]
+    Validation(ValidationError),
+    #[error("Address book entry not found")]
+    NotFound,
+    #[error("The address book holds at most {} addresses", address_book::MAX_ADDRESSES)]
+    Full,
+    #[error("This address is already in the address book for that purpose")]
+    Duplicate,
+    #[error(transparent)]
+    Merchant(#[from] MerchantError),
+}
+
+impl From<ValidationError> for AddressBookError {
+    fn from(e: ValidationError) -> Self {
+        AddressBookError::Validation(e)
+    }
+}
+
+#[derive(Clone)]
+pub struct MerchantService {
+    pool: SqlitePool,
+    config: Config,
+    cipherscans: CipherScans,
+}
+
+impl MerchantService {
+    pub fn new(pool: SqlitePool, config: Config, cipherscans: CipherScans) -> Self {
+        Self { pool, config, cipherscans }
+    }
+
+    /// Register a merchant and, if asked, start a shadow scan of recent blocks with its UFVK.
+    pub async fn register(&self, req: &CreateMerchantRequest) -> Result<CreateMerchantResponse, RegisterError> {
+        validate_registration(req, &self.config)?;
+        let created = merchants::create_merchant(&self.pool, req, &self.config.encryption_key).await?;
+
+        if let Some(blocks) = req.verify_blocks.filter(|b| *b > 0) {
+            if let Err(e) = dry_run::start(&self.pool, self.cipherscans.for_network(&created.network), &created.merchant_id, &req.ufvk, blocks).await {
+                tracing::warn!(merchant_id = %created.merchant_id, error = %e, "Failed to start UFVK check");
+            }
+        }
+        Ok(created)
+    }
+
+    pub async fn stats(&self, merchant: &Merchant) -> MerchantStats {
+        merchants::stats(&self.pool, &merchant.id).await
+    }
+
+    pub async fn addresses(&self, merchant: &Merchant, purpose: Option<&str>) -> Result<Vec<BookAddress>, AddressBookError> {
+        if let Some(purpose) = purpose {
+            validate_purpose(purpose)?;
+        }
+        Ok(address_book::list(&self.pool, &merchant.id, purpose, &self.config.encryption_key).await?)
+    }
+
+    /// Add one of the merchant's own addresses, checked against the server's network.
+    pub async fn add_address(
+        &self,
+        merchant: &Merchant,
+        label: &str,
+        address: &str,
+        purpose: &str,
+        is_default: bool,
+    ) -> Result<BookAddress, AddressBookError> {
+        validate_label(label)?;
+        validate_purpose(purpose)?;
+        validation::validate_zcash_address_network("address", address, merchant.network == "testnet")?;
+        match address_book::add(&self.pool, &merchant.id, label.trim(), address, purpose, is_default, &self.config.encryption_key).await {
+            Ok(Some(entry)) => Ok(entry),
+            Ok(None) => Err(AddressBookError::Full),
+            Err(MerchantError::Database(sqlx::Error::Database(db))) if db.is_unique_violation() => {
+                Err(AddressBookError::Duplicate)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub async fn update_address(
+        &self,
+        merchant: &Merchant,
+        id: &str,
+        label: Option<&str>,
+        make_default: bool,
+    ) -> Result<BookAddress, AddressBookError> {
+        if let Some(label) = label {
+            validate_label(label)?;
+        }
+        address_book::update(&self.pool, &merchant.id, id, label.map(str::trim), make_default, &self.config.encryption_key)
+            .await?
+            .ok_or(AddressBookError::NotFound)
+    }
+
+    pub async fn remove_address(&self, merchant: &Merchant, id: &str) -> Result<(), AddressBookError> {
+        if !address_book::remove(&self.pool, &merchant.id, id).await? {
+            return Err(AddressBookError::NotFound);
+        }
+        Ok(())
+    }
+
+    /// Delete the merchant's account, leaving the tombstone `merchants::delete_merchant`
+    /// describes. Refused while fees are owed.
+    pub async fn delete(&self, merchant: &Merchant) -> Result<(), DeleteAccountError> {
+        if self.config.fee_enabled() && merchants::has_outstanding_balance(&self.pool, &merchant.id).await? {
+            return Err(DeleteAccountError::OutstandingBalance);
+        }
+        merchants::delete_merchant(&self.pool, &merchant.id).await?;
+        Ok(())
+    }
+
+    /// Wipe a testnet merchant's invoices and products, keeping the account. Mainnet
+    /// invoices are payment records and are never bulk deleted.
+    pub async fn reset_sandbox(&self, merchant: &Merchant) -> Result<SandboxReset, ResetSandboxError> {
+        if merchant.network != "testnet" {
+            return Err(ResetSandboxError::NotTestnet);
+        }
+        Ok(merchants::reset_sandbox(&self.pool, &merchant.id).await?)
+    }
+}
+
+/// The network a new merchant's UFVK is checked against: the one its prefix names when
+/// this server serves it, otherwise the primary network.
+fn registration_network<'a>(config: &'a Config, ufvk: &str) -> &'a str {
+    let named = if ufvk.starts_with("uviewtest") { "testnet" } else { "mainnet" };
+    config
+        .networks
+        .iter()
+        .find(|n| n.network == named)
+        .map_or(config.network.as_str(), |n| n.network.as_str())
+}
+
+fn validate_registration(req: &CreateMerchantRequest, config: &Config) -> Result<(), ValidationError> {
+    if let Some(ref name) = req.name {
+        validation::validate_length("name", name, 100)?;
+    }
+    validation::validate_length("ufvk", &req.ufvk, 2000)?;
+    let network = registration_network(config, &req.ufvk);
+    validation::validate_ufvk_network("ufvk", &req.ufvk, network == "testnet")?;
+    if let Ok(network) = crate::addresses::ufvk_network(&req.ufvk) {
+        if !config.serves(network) {
+            return Err(ValidationError::invalid(
+                "ufvk",
+                &format!("is a {} viewing key, and this server does not scan {}", network, network),
+            ));
+        }
+    }
+    if req.verify_blocks.is_some_and(|b| b > dry_run::MAX_BLOCKS) {
+        return Err(ValidationError::invalid(
+            "verify_blocks",
+            &format!("must be at most {}", dry_run::MAX_BLOCKS),
+        ));
+    }
+    if let Some(ref url) = req.webhook_url {
+        if !url.is_empty() {
+            validation::validate_webhook_url("webhook_url", url, network == "testnet")?;
+        }
+    }
+    if let Some(ref email) = req.email {
+        if !email.is_empty() {
+            validation::validate_email_format("email", email)?;
+        }
+    }
+    Ok(())
+}
+
+fn validate_label(label: &str) -> Result<(), ValidationError> {
+    validation::validate_length("label", label, 100)?;
+    if label.trim().is_empty() {
+        return Err(ValidationError::invalid("label", "must not be empty"));
+    }
+    Ok(())
+}
+
+fn validate_purpose(purpose: &str) -> Result<(), ValidationError> {
+    if !address_book::PURPOSES.contains(&purpose) {
+        return Err(ValidationError::invalid("purpose", "must be payout, refund_source or settlement_source"));
+    }
+    Ok(())
+}
@@ -0,0 +1,12 @@
+//! Business operations, independent of any transport. A service holds what its operations
+//! need (pool, config, price feed) and returns domain results and typed errors; the REST
+//! handlers, the GraphQL resolvers and the gRPC server only authenticate, decode the
+//! request and map the outcome to their own responses (`api::error` for HTTP).
+
+pub mod billing;
+pub mod invoices;
+pub mod merchants;
+
+pub use billing::BillingService;
+pub use invoices::InvoiceService;
+pub use merchants::MerchantService;
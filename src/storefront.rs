@@ -0,0 +1,162 @@
+use actix_web::{web, HttpResponse};
+use sqlx::SqlitePool;
+
+use crate::products::{self, fields::FieldType, Product};
+
+/// Most products rendered on a storefront page.
+const STORE_PRODUCT_LIMIT: i64 = 200;
+
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+fn render_product(product: &Product, image_url: Option<&str>) -> String {
+    let mut html = String::from("<div class=\"product\">");
+
+    if let Some(url) = image_url {
+        html.push_str(&format!("<img src=\"{}\" alt=\"{}\">", escape_html(url), escape_html(&product.name)));
+    }
+    html.push_str(&format!("<div class=\"product-name\">{}</div>", escape_html(&product.name)));
+    if let Some(ref desc) = product.description {
+        html.push_str(&format!("<div class=\"product-desc\">{}</div>", escape_html(desc)));
+    }
+    html.push_str(&format!(
+        "<div class=\"product-price\">{:.2} {}</div>",
+        product.price_eur,
+        escape_html(&product.currency)
+    ));
+
+    html.push_str(&format!("<form data-product-id=\"{}\">", escape_html(&product.id)));
+    let variants = product.variants_list();
+    if !variants.is_empty() {
+        html.push_str("<select name=\"variant\">");
+        for v in &variants {
+            let v = escape_html(v);
+            html.push_str(&format!("<option value=\"{}\">{}</option>", v, v));
+        }
+        html.push_str("</select>");
+    }
+    if product.quantity_limit() > 1 {
+        html.push_str(&format!(
+            "<input type=\"number\" name=\"quantity\" value=\"1\" min=\"1\" max=\"{}\" aria-label=\"Quantity\">",
+            product.quantity_limit()
+        ));
+    }
+    for field in product.checkout_fields_list() {
+        let input_type = match field.field_type {
+            FieldType::Text => "text",
+            FieldType::Email => "email",
+            FieldType::Number => "number",
+        };
+        let name = escape_html(&field.name);
+        html.push_str(&format!(
+            "<input type=\"{}\" data-field=\"{}\" placeholder=\"{}\" aria-label=\"{}\"{}>",
+            input_type, name, name, name,
+            if field.required { " required" } else { "" }
+        ));
+    }
+    html.push_str("<button type=\"submit\">Buy with ZEC</button><div class=\"error\"></div></form></div>");
+
+    html
+}
+
+/// Hosted storefront: a merchant's active products with buy buttons that go through
+/// the public checkout endpoint and open the payment widget in place.
+pub async fn page(
+    pool: web::Data<SqlitePool>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    let merchant_ref = path.into_inner();
+
+    let merchant: Option<(String, String, Option<String>)> = match sqlx::query_as(
+        "SELECT id, name, store_about FROM merchants WHERE id = ? OR slug = ? COLLATE NOCASE"
+    )
+    .bind(&merchant_ref)
+    .bind(&merchant_ref)
+    .fetch_optional(pool.get_ref())
+    .await
+    {
+        Ok(row) => row,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to look up merchant for storefront");
+            return HttpResponse::InternalServerError().body("Internal error");
+        }
+    };
+    let (merchant_id, name, about) = match merchant {
+        Some(m) => m,
+        None => return HttpResponse::NotFound().content_type("text/html").body("<h1>Store not found</h1>"),
+    };
+
+    let (items, _) = match products::list_catalog(pool.get_ref(), &merchant_id, None, None, STORE_PRODUCT_LIMIT, 0).await {
+        Ok(r) => r,
+        Err(e) => {
+            tracing::error!(error = %e, "Failed to list storefront products");
+            return HttpResponse::InternalServerError().body("Internal error");
+        }
+    };
+
+    let mut products_html = String::new();
+    if items.is_empty() {
+        products_html.push_str("<p class=\"empty\">No products available right now.</p>");
+    }
+    let mut current: Option<Option<String>> = None;
+    for product in &items {
+        if current.as_ref() != Some(&product.category) {
+            if current.is_some() {
+                products_html.push_str("</div>");
+            }
+            if let Some(ref category) = product.category {
+                products_html.push_str(&format!("<h2>{}</h2>", escape_html(category)));
+            } else if current.is_some() {
+                products_html.push_str("<h2>Other</h2>");
+            }
+            products_html.push_str("<div class=\"grid\">");
+            current = Some(product.category.clone());
+        }
+        let image = products::list_images(pool.get_ref(), &product.id)
+            .await
+            .unwrap_or_default()
+            .first()
+            .map(|i| i.url());
+        products_html.push_str(&render_product(product, image.as_deref()));
+    }
+    if current.is_some() {
+        products_html.push_str("</div>");
+    }
+
+    let title = if name.is_empty() { "Store".to_string() } else { escape_html(&name) };
+    let about_html = about
+        .filter(|a| !a.is_empty())
+        .map(|a| format!("<p class=\"about\">{}</p>", escape_html(&a)))
+        .unwrap_or_default();
+
+    let html = include_str!("../ui/store.html")
+        .replace("{{merchant_name}}", &title)
+        .replace("{{about}}", &about_html)
+        .replace("{{products}}", &products_html);
+
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(html)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(escape_html("<b>\"Tom's\" & co</b>"), "&lt;b&gt;&quot;Tom&#39;s&quot; &amp; co&lt;/b&gt;");
+    }
+}
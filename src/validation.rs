@@ -1,6 +1,20 @@
 use std::net::{IpAddr, ToSocketAddrs};
-use zcash_address::ZcashAddress;
+use std::sync::atomic::{AtomicBool, Ordering};
+use zcash_address::{unified, ConversionError, ZcashAddress};
+use zcash_protocol::consensus::NetworkType;
 
+static ALLOW_PRIVATE_HOSTS: AtomicBool = AtomicBool::new(false);
+
+/// Disable the SSRF checks on outbound URLs (ALLOW_PRIVATE_WEBHOOKS, testnet only).
+pub fn allow_private_hosts() {
+    ALLOW_PRIVATE_HOSTS.store(true, Ordering::Relaxed);
+}
+
+fn private_hosts_allowed() -> bool {
+    ALLOW_PRIVATE_HOSTS.load(Ordering::Relaxed)
+}
+
+#[derive(Debug)]
 pub struct ValidationError {
     pub field: String,
     pub message: String,
@@ -47,6 +61,32 @@ pub fn validate_optional_length(
     Ok(())
 }
 
+/// Slugs that would collide with routes, look official, or invite phishing.
+const RESERVED_SLUGS: &[&str] = &[
+    "admin", "api", "app", "auth", "billing", "checkout", "cipherpay", "cipherscan",
+    "dashboard", "docs", "help", "invoice", "invoices", "login", "logout", "me",
+    "media", "merchant", "merchants", "official", "pay", "payment", "payments",
+    "root", "security", "settings", "signup", "status", "store", "support",
+    "system", "widget", "www", "zcash",
+];
+
+/// Merchant vanity slug: 3-40 lowercase letters, digits and single hyphens, not reserved.
+pub fn validate_merchant_slug(field: &str, slug: &str) -> Result<(), ValidationError> {
+    if slug.len() < 3 || slug.len() > 40 {
+        return Err(ValidationError::invalid(field, "must be 3 to 40 characters"));
+    }
+    if !slug.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-') {
+        return Err(ValidationError::invalid(field, "may only contain lowercase letters, digits and hyphens"));
+    }
+    if slug.starts_with('-') || slug.ends_with('-') || slug.contains("--") {
+        return Err(ValidationError::invalid(field, "hyphens must separate words"));
+    }
+    if RESERVED_SLUGS.contains(&slug) {
+        return Err(ValidationError::invalid(field, "is reserved"));
+    }
+    Ok(())
+}
+
 pub fn validate_email_format(field: &str, email: &str) -> Result<(), ValidationError> {
     validate_length(field, email, 254)?;
 
@@ -96,13 +136,42 @@ pub fn validate_webhook_url(
         return Err(ValidationError::invalid(field, "URL must not contain credentials"));
     }
 
-    if is_private_host(&host) {
+    if is_private_host(&host) && !private_hosts_allowed() {
         return Err(ValidationError::invalid(field, "internal/private addresses are not allowed"));
     }
 
     Ok(())
 }
 
+/// Nostr relay URL: `wss://` (plain `ws://` allowed on testnet), public host, no credentials.
+pub fn validate_relay_url(
+    field: &str,
+    url_str: &str,
+    is_testnet: bool,
+) -> Result<(), ValidationError> {
+    validate_length(field, url_str, 500)?;
+
+    if is_testnet {
+        if !url_str.starts_with("wss://") && !url_str.starts_with("ws://") {
+            return Err(ValidationError::invalid(field, "must start with ws:// or wss://"));
+        }
+    } else if !url_str.starts_with("wss://") {
+        return Err(ValidationError::invalid(field, "must start with wss:// in production"));
+    }
+
+    let parsed = url::Url::parse(url_str)
+        .map_err(|_| ValidationError::invalid(field, "invalid URL"))?;
+    let host = parsed.host_str()
+        .ok_or_else(|| ValidationError::invalid(field, "missing hostname"))?;
+    if parsed.username() != "" || parsed.password().is_some() {
+        return Err(ValidationError::invalid(field, "URL must not contain credentials"));
+    }
+    if is_private_host(host) {
+        return Err(ValidationError::invalid(field, "internal/private addresses are not allowed"));
+    }
+    Ok(())
+}
+
 pub fn validate_ufvk_network(
     field: &str,
     ufvk: &str,
@@ -145,6 +214,53 @@ pub fn validate_zcash_address(field: &str, addr: &str) -> Result<(), ValidationE
     Ok(())
 }
 
+/// Reads the network an address was encoded for, whatever its kind.
+struct AddressNetwork;
+
+impl zcash_address::Converter<NetworkType> for AddressNetwork {
+    type Error = std::convert::Infallible;
+
+    fn convert_sapling(&self, net: NetworkType, _: [u8; 43]) -> Result<NetworkType, ConversionError<Self::Error>> {
+        Ok(net)
+    }
+
+    fn convert_unified(&self, net: NetworkType, _: unified::Address) -> Result<NetworkType, ConversionError<Self::Error>> {
+        Ok(net)
+    }
+
+    fn convert_transparent_p2pkh(&self, net: NetworkType, _: [u8; 20]) -> Result<NetworkType, ConversionError<Self::Error>> {
+        Ok(net)
+    }
+
+    fn convert_transparent_p2sh(&self, net: NetworkType, _: [u8; 20]) -> Result<NetworkType, ConversionError<Self::Error>> {
+        Ok(net)
+    }
+
+    fn convert_tex(&self, net: NetworkType, _: [u8; 20]) -> Result<NetworkType, ConversionError<Self::Error>> {
+        Ok(net)
+    }
+}
+
+/// A valid Zcash address for the network this server runs on (Sprout is refused).
+pub fn validate_zcash_address_network(field: &str, addr: &str, is_testnet: bool) -> Result<(), ValidationError> {
+    validate_zcash_address(field, addr)?;
+    let network = ZcashAddress::try_from_encoded(addr)
+        .map_err(|_| ValidationError::invalid(field, "must be a valid Zcash address"))?
+        .convert_with(AddressNetwork)
+        .map_err(|_| ValidationError::invalid(field, "Sprout addresses are not supported"))?;
+    match (network, is_testnet) {
+        (NetworkType::Main, true) => Err(ValidationError::invalid(
+            field,
+            "this server is running on testnet — please use a testnet address",
+        )),
+        (NetworkType::Test | NetworkType::Regtest, false) => Err(ValidationError::invalid(
+            field,
+            "this server is running on mainnet — please use a mainnet address",
+        )),
+        _ => Ok(()),
+    }
+}
+
 fn is_private_host(host: &str) -> bool {
     let lower = host.to_lowercase();
     if lower == "localhost" || lower.ends_with(".local") || lower.ends_with(".internal") {
@@ -206,7 +322,7 @@ pub fn resolve_and_check_host(url: &str) -> Result<(), String> {
                 return Err("DNS resolved to no addresses".to_string());
             }
             for addr in &addrs {
-                if is_private_ip(&addr.ip()) {
+                if is_private_ip(&addr.ip()) && !private_hosts_allowed() {
                     return Err(format!("webhook URL resolves to private IP: {}", addr.ip()));
                 }
             }
@@ -219,6 +335,7 @@ pub fn resolve_and_check_host(url: &str) -> Result<(), String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use zcash_address::ToAddress;
 
     #[test]
     fn test_validate_length() {
@@ -237,6 +354,16 @@ mod tests {
         assert!(validate_email_format("email", "user@.domain.com").is_err());
     }
 
+    #[test]
+    fn test_validate_merchant_slug() {
+        assert!(validate_merchant_slug("slug", "acme-coffee").is_ok());
+        assert!(validate_merchant_slug("slug", "ab").is_err());
+        assert!(validate_merchant_slug("slug", "Acme").is_err());
+        assert!(validate_merchant_slug("slug", "-acme").is_err());
+        assert!(validate_merchant_slug("slug", "ac--me").is_err());
+        assert!(validate_merchant_slug("slug", "admin").is_err());
+    }
+
     #[test]
     fn test_validate_webhook_url() {
         assert!(validate_webhook_url("url", "https://example.com/hook", false).is_ok());
@@ -276,6 +403,22 @@ mod tests {
         assert!(validate_zcash_address("addr", "t1000000000000000000000000000000000").is_err());
     }
 
+    #[test]
+    fn test_validate_zcash_address_network() {
+        let testnet = crate::addresses::derive_invoice_address(&crate::scanner::fixtures::test_ufvk(0), 1)
+            .unwrap()
+            .ua_string;
+        let mainnet = ZcashAddress::from_transparent_p2pkh(NetworkType::Main, [7; 20]).encode();
+        let testnet_t = ZcashAddress::from_transparent_p2pkh(NetworkType::Test, [7; 20]).encode();
+
+        assert!(validate_zcash_address_network("address", &testnet, true).is_ok());
+        assert!(validate_zcash_address_network("address", &testnet_t, true).is_ok());
+        assert!(validate_zcash_address_network("address", &mainnet, true).is_err());
+        assert!(validate_zcash_address_network("address", &mainnet, false).is_ok());
+        assert!(validate_zcash_address_network("address", &testnet, false).is_err());
+        assert!(validate_zcash_address_network("address", "u1abc123", false).is_err());
+    }
+
     #[test]
     fn test_is_private_ip() {
         assert!(is_private_ip(&"127.0.0.1".parse().unwrap()));
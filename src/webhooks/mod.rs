@@ -1,17 +1,136 @@
-use hmac::{Hmac, Mac};
-use sha2::Sha256;
-use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock};
+
+use cipherpay_client::webhook::{sign, signatures_header, Scheme};
+use futures::StreamExt;
+use sqlx::{FromRow, SqlitePool};
 use uuid::Uuid;
 use chrono::Utc;
 
-type HmacSha256 = Hmac<Sha256>;
+/// Receivers should reject webhooks whose timestamp is further than this from their clock.
+pub use cipherpay_client::webhook::TIMESTAMP_TOLERANCE_SECS;
+
+/// Scheme receivers are encouraged to verify. v1 is still sent for existing integrations.
+pub const ACTIVE_SIGNATURE_SCHEME: Scheme = Scheme::V2;
+
+/// Every webhook event: an invoice's, in the order it goes through them, then the catalog's.
+pub const EVENTS: &[&str] = &[
+    "created", "detected", "underpaid", "confirmed", "paid_late", "requoted", "expired", "refund_confirmed",
+    "product.created", "product.updated", "product.deactivated",
+];
+
+/// Events sent to merchants who never chose: the invoice events except `created`, which
+/// receivers written before it existed would not expect.
+pub const DEFAULT_EVENTS: &[&str] = &[
+    "detected", "underpaid", "confirmed", "paid_late", "requoted", "expired", "refund_confirmed",
+];
+
+/// Whether a merchant's `webhook_events` setting (NULL for the defaults) includes `event`.
+fn subscribed(setting: Option<&str>, event: &str) -> bool {
+    match setting {
+        Some(events) => events.split(',').any(|e| e == event),
+        None => DEFAULT_EVENTS.contains(&event),
+    }
+}
+
+/// The events a merchant's webhook receives.
+pub async fn get_events(pool: &SqlitePool, merchant_id: &str) -> anyhow::Result<Vec<String>> {
+    let setting: Option<String> = sqlx::query_scalar("SELECT webhook_events FROM merchants WHERE id = ?")
+        .bind(merchant_id)
+        .fetch_optional(pool)
+        .await?
+        .flatten();
+    Ok(match setting {
+        Some(events) => events.split(',').filter(|e| !e.is_empty()).map(String::from).collect(),
+        None => DEFAULT_EVENTS.iter().map(|e| e.to_string()).collect(),
+    })
+}
+
+/// Replace a merchant's subscriptions. Names must come from `EVENTS`; an empty list
+/// turns every event off while keeping the webhook URL.
+pub async fn set_events(pool: &SqlitePool, merchant_id: &str, events: &[String]) -> anyhow::Result<()> {
+    let setting: Vec<&str> = EVENTS.iter().copied().filter(|e| events.iter().any(|s| s == e)).collect();
+    sqlx::query("UPDATE merchants SET webhook_events = ? WHERE id = ?")
+        .bind(setting.join(","))
+        .bind(merchant_id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
 
-fn sign_payload(secret: &str, timestamp: &str, payload: &str) -> String {
-    let message = format!("{}.{}", timestamp, payload);
-    let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
-        .expect("HMAC accepts any key length");
-    mac.update(message.as_bytes());
-    hex::encode(mac.finalize().into_bytes())
+/// Public description of how webhooks are signed, with a worked example using a dummy secret.
+pub fn signing_info() -> serde_json::Value {
+    let secret = "whsec_example_do_not_use";
+    let event_id = "3f1c2a9e-8d4b-4e7a-9c1d-2b5e6f7a8c90";
+    let timestamp = "2025-01-01T00:00:00Z";
+    let body = serde_json::json!({
+        "event": "confirmed",
+        "invoice_id": "6a0f7c1e-2b3d-4e5f-8a9b-0c1d2e3f4a5b",
+        "txid": "0000000000000000000000000000000000000000000000000000000000000000",
+        "timestamp": timestamp,
+        "sequence": 1,
+    })
+    .to_string();
+
+    serde_json::json!({
+        "active_scheme": ACTIVE_SIGNATURE_SCHEME.as_str(),
+        "timestamp_tolerance_secs": TIMESTAMP_TOLERANCE_SECS,
+        "schemes": [
+            {
+                "version": "v1",
+                "algorithm": "HMAC-SHA256",
+                "signed_content": "{timestamp}.{body}",
+                "header": "X-CipherPay-Signature",
+                "deprecated": true,
+            },
+            {
+                "version": "v2",
+                "algorithm": "HMAC-SHA256",
+                "signed_content": "{event_id}.{timestamp}.{body}",
+                "header": "X-CipherPay-Signatures",
+                "deprecated": false,
+            },
+        ],
+        "headers": {
+            "X-CipherPay-Event-Id": "Unique delivery ID; stable across retries, use it to deduplicate",
+            "X-CipherPay-Timestamp": "UTC send time (RFC 3339); reject if outside the tolerance window",
+            "X-CipherPay-Signature": "v1 signature (hex)",
+            "X-CipherPay-Signatures": "All signatures as version=hex pairs, comma-separated",
+        },
+        "example": {
+            "secret": secret,
+            "event_id": event_id,
+            "timestamp": timestamp,
+            "body": body,
+            "signatures": signatures_header(secret, event_id, timestamp, body.as_bytes()),
+        },
+    })
+}
+
+/// Add a delivery attempt to the invoice timeline.
+async fn record_attempt(pool: &SqlitePool, invoice_id: &str, event: &str, attempt: i64, error: Option<String>) {
+    let (event_type, detail) = match error {
+        None => ("webhook_sent", serde_json::json!({ "event": event, "attempt": attempt })),
+        Some(e) => ("webhook_failed", serde_json::json!({ "event": event, "attempt": attempt, "error": e })),
+    };
+    crate::invoices::events::record(pool, invoice_id, event_type, None, None, Some(detail)).await;
+}
+
+type DeliveryLocks = std::sync::Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>;
+
+static LOCKS: LazyLock<DeliveryLocks> = LazyLock::new(Default::default);
+
+/// One delivery in flight per merchant and channel, so a merchant never receives two webhooks
+/// concurrently and first attempts go out in sequence order. A slow chat service does not
+/// hold up webhooks. Locks nobody holds any more are dropped, so the map only grows with
+/// the deliveries in flight.
+fn delivery_lock(merchant_id: &str, channel: &str) -> Arc<tokio::sync::Mutex<()>> {
+    let mut locks = LOCKS.lock().unwrap_or_else(|e| e.into_inner());
+    locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+    locks
+        .entry(format!("{}:{}", merchant_id, channel))
+        .or_default()
+        .clone()
 }
 
 fn retry_delay_secs(attempt: i64) -> i64 {
@@ -24,257 +143,729 @@ fn retry_delay_secs(attempt: i64) -> i64 {
     }
 }
 
-pub async fn dispatch(
+/// Queue a webhook for an invoice event. Returns the merchant ID to pass to
+/// `deliver_pending`, or None if the merchant has no (allowed) webhook URL.
+pub async fn enqueue(
     pool: &SqlitePool,
-    http: &reqwest::Client,
     invoice_id: &str,
     event: &str,
     txid: &str,
-    encryption_key: &str,
-) -> anyhow::Result<()> {
-    let merchant_row = sqlx::query_as::<_, (Option<String>, String)>(
-        "SELECT m.webhook_url, m.webhook_secret FROM invoices i
-         JOIN merchants m ON i.merchant_id = m.id
-         WHERE i.id = ?"
+) -> anyhow::Result<Option<String>> {
+    let payload = serde_json::json!({
+        "event": event,
+        "invoice_id": invoice_id,
+        "txid": txid,
+    });
+    enqueue_payload(pool, invoice_id, payload).await
+}
+
+/// Queue a payment webhook, which additionally carries the amounts.
+#[allow(clippy::too_many_arguments)]
+pub async fn enqueue_payment(
+    pool: &SqlitePool,
+    invoice_id: &str,
+    event: &str,
+    txid: &str,
+    price_zatoshis: i64,
+    received_zatoshis: i64,
+    overpaid: bool,
+) -> anyhow::Result<Option<String>> {
+    let payload = serde_json::json!({
+        "event": event,
+        "invoice_id": invoice_id,
+        "txid": txid,
+        "price_zec": crate::invoices::zatoshis_to_zec(price_zatoshis),
+        "received_zec": crate::invoices::zatoshis_to_zec(received_zatoshis),
+        "overpaid": overpaid,
+    });
+    enqueue_payload(pool, invoice_id, payload).await
+}
+
+/// Queue a requote webhook: the invoice was repriced at the current rate instead of expiring.
+pub async fn enqueue_requote(
+    pool: &SqlitePool,
+    invoice_id: &str,
+    price_zatoshis: i64,
+    expires_at: &str,
+) -> anyhow::Result<Option<String>> {
+    let payload = serde_json::json!({
+        "event": "requoted",
+        "invoice_id": invoice_id,
+        "price_zec": crate::invoices::zatoshis_to_zec(price_zatoshis),
+        "expires_at": expires_at,
+    });
+    enqueue_payload(pool, invoice_id, payload).await
+}
+
+/// Queue a created webhook with everything needed to show the buyer how to pay, for
+/// platforms that create invoices in the background.
+pub async fn enqueue_created(
+    pool: &SqlitePool,
+    invoice: &crate::invoices::CreateInvoiceResponse,
+) -> anyhow::Result<Option<String>> {
+    let payload = serde_json::json!({
+        "event": "created",
+        "invoice_id": invoice.invoice_id,
+        "memo_code": invoice.memo_code,
+        "price_zec": invoice.price_zec,
+        "payment_address": invoice.payment_address,
+        "zcash_uri": invoice.zcash_uri,
+        "tex_address": invoice.tex_address,
+        "expires_at": invoice.expires_at,
+    });
+    enqueue_payload(pool, &invoice.invoice_id, payload).await
+}
+
+/// Queue an expiry webhook. `received_zec` is what the buyer sent before the invoice ran
+/// out (nonzero for an expired underpaid invoice), which the merchant has to refund.
+pub async fn enqueue_expired(pool: &SqlitePool, expired: &crate::invoices::ExpiredInvoice) -> anyhow::Result<Option<String>> {
+    let payload = serde_json::json!({
+        "event": "expired",
+        "invoice_id": expired.invoice_id,
+        "price_zec": crate::invoices::zatoshis_to_zec(expired.price_zatoshis),
+        "received_zec": crate::invoices::zatoshis_to_zec(expired.received_zatoshis),
+        "received_zatoshis": expired.received_zatoshis,
+        "has_refund_address": expired.has_refund_address,
+    });
+    enqueue_payload(pool, &expired.invoice_id, payload).await
+}
+
+/// Queue a product event for storefronts that mirror the catalog. `product` is the product
+/// as the products API returns it, after the change; `product.deactivated` covers archiving too.
+pub async fn enqueue_product(
+    pool: &SqlitePool,
+    event: &str,
+    product: &crate::products::Product,
+) -> anyhow::Result<Option<String>> {
+    let payload = serde_json::json!({
+        "event": event,
+        "product_id": product.id,
+        "product": product,
+    });
+    enqueue_for_merchant(pool, &product.merchant_id, None, payload).await
+}
+
+/// Queue an invoice event for the invoice's merchant, with the invoice's quantity.
+async fn enqueue_payload(
+    pool: &SqlitePool,
+    invoice_id: &str,
+    mut payload: serde_json::Value,
+) -> anyhow::Result<Option<String>> {
+    let invoice: Option<(String, i64)> = sqlx::query_as("SELECT merchant_id, quantity FROM invoices WHERE id = ?")
+        .bind(invoice_id)
+        .fetch_optional(pool)
+        .await?;
+    let Some((merchant_id, quantity)) = invoice else {
+        return Ok(None);
+    };
+    payload["quantity"] = serde_json::json!(quantity);
+    enqueue_for_merchant(pool, &merchant_id, Some(invoice_id), payload).await
+}
+
+/// Persist a delivery with the merchant's next sequence number. Sequence numbers are
+/// assigned here, at the moment the event happens, so they reflect event order even
+/// if delivery is delayed or retried. Returns None if the merchant has no (allowed)
+/// webhook URL or is not subscribed to the event.
+async fn enqueue_for_merchant(
+    pool: &SqlitePool,
+    merchant_id: &str,
+    invoice_id: Option<&str>,
+    mut payload: serde_json::Value,
+) -> anyhow::Result<Option<String>> {
+    let merchant_row = sqlx::query_as::<_, (Option<String>, Option<String>)>(
+        "SELECT webhook_url, webhook_events FROM merchants WHERE id = ?"
     )
-    .bind(invoice_id)
+    .bind(merchant_id)
     .fetch_optional(pool)
     .await?;
 
-    let (webhook_url, raw_secret) = match merchant_row {
-        Some((Some(url), secret)) if !url.is_empty() => (url, secret),
-        _ => return Ok(()),
+    let webhook_url = match merchant_row {
+        Some((Some(url), events))
+            if !url.is_empty() && subscribed(events.as_deref(), payload["event"].as_str().unwrap_or_default()) =>
+        {
+            url
+        }
+        _ => return Ok(None),
     };
-    let webhook_secret = crate::crypto::decrypt_webhook_secret(&raw_secret, encryption_key)?;
 
     if let Err(reason) = crate::validation::resolve_and_check_host(&webhook_url) {
-        tracing::warn!(invoice_id, url = %webhook_url, %reason, "Webhook blocked: SSRF protection");
-        return Ok(());
+        tracing::warn!(merchant_id, invoice_id, url = %webhook_url, %reason, "Webhook blocked: SSRF protection");
+        return Ok(None);
     }
 
-    let timestamp = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
-
-    let payload = serde_json::json!({
-        "event": event,
-        "invoice_id": invoice_id,
-        "txid": txid,
-        "timestamp": &timestamp,
-    });
-
-    let payload_str = payload.to_string();
-    let signature = sign_payload(&webhook_secret, &timestamp, &payload_str);
+    let (sequence,): (i64,) = sqlx::query_as(
+        "UPDATE merchants SET webhook_seq = webhook_seq + 1 WHERE id = ? RETURNING webhook_seq"
+    )
+    .bind(merchant_id)
+    .fetch_one(pool)
+    .await?;
 
-    let delivery_id = Uuid::new_v4().to_string();
-    let next_retry = (Utc::now() + chrono::Duration::seconds(retry_delay_secs(1)))
-        .format("%Y-%m-%dT%H:%M:%SZ")
-        .to_string();
+    let timestamp = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+    payload["timestamp"] = serde_json::json!(timestamp);
+    payload["sequence"] = serde_json::json!(sequence);
 
     sqlx::query(
-        "INSERT INTO webhook_deliveries (id, invoice_id, url, payload, status, attempts, last_attempt_at, next_retry_at)
-         VALUES (?, ?, ?, ?, 'pending', 1, ?, ?)"
+        "INSERT INTO webhook_deliveries (id, invoice_id, merchant_id, url, payload, status, attempts, sequence)
+         VALUES (?, ?, ?, ?, ?, 'pending', 0, ?)"
     )
-    .bind(&delivery_id)
+    .bind(Uuid::new_v4().to_string())
     .bind(invoice_id)
+    .bind(merchant_id)
     .bind(&webhook_url)
-    .bind(&payload_str)
-    .bind(&timestamp)
-    .bind(&next_retry)
+    .bind(payload.to_string())
+    .bind(sequence)
     .execute(pool)
     .await?;
 
-    match http.post(&webhook_url)
-        .header("X-CipherPay-Signature", &signature)
-        .header("X-CipherPay-Timestamp", &timestamp)
-        .json(&payload)
-        .timeout(std::time::Duration::from_secs(10))
-        .send()
-        .await
-    {
-        Ok(resp) if resp.status().is_success() => {
-            sqlx::query("UPDATE webhook_deliveries SET status = 'delivered' WHERE id = ?")
-                .bind(&delivery_id)
-                .execute(pool)
-                .await?;
-            tracing::info!(invoice_id, event, "Webhook delivered");
-        }
-        Ok(resp) => {
-            tracing::warn!(invoice_id, event, status = %resp.status(), "Webhook rejected, will retry");
-        }
-        Err(e) => {
-            tracing::warn!(invoice_id, event, error = %e, "Webhook failed, will retry");
-        }
-    }
-
-    Ok(())
+    Ok(Some(merchant_id.to_string()))
 }
 
-pub async fn dispatch_payment(
+/// Queue a chat message (see `notifiers::chat`) in the same outbox. The caller picks the
+/// ID because Matrix uses it as the transaction ID in the URL. Chat messages are neither
+/// signed nor sequenced, and are sent by the retry loop.
+pub async fn enqueue_message(
     pool: &SqlitePool,
-    http: &reqwest::Client,
+    id: &str,
     invoice_id: &str,
-    event: &str,
-    txid: &str,
-    price_zatoshis: i64,
-    received_zatoshis: i64,
-    overpaid: bool,
-    encryption_key: &str,
+    channel: &str,
+    url: &str,
+    body: &serde_json::Value,
 ) -> anyhow::Result<()> {
-    let merchant_row = sqlx::query_as::<_, (Option<String>, String)>(
-        "SELECT m.webhook_url, m.webhook_secret FROM invoices i
-         JOIN merchants m ON i.merchant_id = m.id
-         WHERE i.id = ?"
+    if let Err(reason) = crate::validation::resolve_and_check_host(url) {
+        tracing::warn!(invoice_id, channel, %reason, "Chat message blocked: SSRF protection");
+        return Ok(());
+    }
+
+    sqlx::query(
+        "INSERT INTO webhook_deliveries (id, invoice_id, merchant_id, url, payload, status, attempts, channel)
+         VALUES (?, ?, (SELECT merchant_id FROM invoices WHERE id = ?), ?, ?, 'pending', 0, ?)"
     )
+    .bind(id)
     .bind(invoice_id)
-    .fetch_optional(pool)
+    .bind(invoice_id)
+    .bind(url)
+    .bind(body.to_string())
+    .bind(channel)
+    .execute(pool)
     .await?;
+    Ok(())
+}
 
-    let (webhook_url, raw_secret) = match merchant_row {
-        Some((Some(url), secret)) if !url.is_empty() => (url, secret),
-        _ => return Ok(()),
-    };
-    let webhook_secret = crate::crypto::decrypt_webhook_secret(&raw_secret, encryption_key)?;
-
-    if let Err(reason) = crate::validation::resolve_and_check_host(&webhook_url) {
-        tracing::warn!(invoice_id, url = %webhook_url, %reason, "Webhook blocked: SSRF protection");
-        return Ok(());
-    }
+#[derive(FromRow)]
+struct DeliveryRow {
+    id: String,
+    /// None for catalog events.
+    invoice_id: Option<String>,
+    merchant_id: String,
+    channel: String,
+    url: String,
+    payload: String,
+    webhook_secret: String,
+    matrix_access_token: Option<String>,
+    attempts: i64,
+    custom_fields: Option<String>,
+}
 
-    let timestamp = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+const DELIVERY_SELECT: &str =
+    "SELECT wd.id, wd.invoice_id, m.id AS merchant_id, wd.channel, wd.url, wd.payload, m.webhook_secret,
+            m.matrix_access_token, wd.attempts, i.custom_fields
+     FROM webhook_deliveries wd
+     JOIN merchants m ON wd.merchant_id = m.id
+     LEFT JOIN invoices i ON wd.invoice_id = i.id";
 
-    let payload = serde_json::json!({
-        "event": event,
-        "invoice_id": invoice_id,
-        "txid": txid,
-        "timestamp": &timestamp,
-        "price_zec": crate::invoices::zatoshis_to_zec(price_zatoshis),
-        "received_zec": crate::invoices::zatoshis_to_zec(received_zatoshis),
-        "overpaid": overpaid,
+/// Deliver a merchant's queued webhooks in the background.
+pub fn spawn_delivery(pool: &SqlitePool, http: &reqwest::Client, merchant_id: String, encryption_key: &str) {
+    let pool = pool.clone();
+    let http = http.clone();
+    let enc_key = encryption_key.to_string();
+    tokio::spawn(async move {
+        if let Err(e) = deliver_pending(&pool, &http, &merchant_id, &enc_key).await {
+            tracing::error!(merchant_id, error = %e, "Async webhook delivery failed");
+        }
     });
+}
 
-    let payload_str = payload.to_string();
-    let signature = sign_payload(&webhook_secret, &timestamp, &payload_str);
+/// Send every not-yet-attempted webhook for a merchant, in sequence order.
+pub async fn deliver_pending(
+    pool: &SqlitePool,
+    http: &reqwest::Client,
+    merchant_id: &str,
+    encryption_key: &str,
+) -> anyhow::Result<()> {
+    let lock = delivery_lock(merchant_id, "webhook");
+    let _guard = lock.lock().await;
 
-    let delivery_id = Uuid::new_v4().to_string();
-    let next_retry = (Utc::now() + chrono::Duration::seconds(retry_delay_secs(1)))
+    let rows = sqlx::query_as::<_, DeliveryRow>(&format!(
+        "{} WHERE m.id = ? AND wd.channel = 'webhook' AND wd.status = 'pending' AND wd.attempts = 0
+         ORDER BY wd.sequence ASC",
+        DELIVERY_SELECT
+    ))
+    .bind(merchant_id)
+    .fetch_all(pool)
+    .await?;
+
+    for row in rows {
+        attempt_delivery(pool, http, &row, encryption_key).await?;
+    }
+    Ok(())
+}
+
+/// Claim and send one delivery. The claim (bumping `attempts` from the value we read)
+/// makes sure the immediate sender and the retry loop never both send the same row.
+async fn attempt_delivery(
+    pool: &SqlitePool,
+    http: &reqwest::Client,
+    row: &DeliveryRow,
+    encryption_key: &str,
+) -> anyhow::Result<()> {
+    let attempt = row.attempts + 1;
+    let ts = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
+    let next_retry = (Utc::now() + chrono::Duration::seconds(retry_delay_secs(attempt)))
         .format("%Y-%m-%dT%H:%M:%SZ")
         .to_string();
 
-    sqlx::query(
-        "INSERT INTO webhook_deliveries (id, invoice_id, url, payload, status, attempts, last_attempt_at, next_retry_at)
-         VALUES (?, ?, ?, ?, 'pending', 1, ?, ?)"
+    let claimed = sqlx::query(
+        "UPDATE webhook_deliveries SET attempts = ?, last_attempt_at = ?, next_retry_at = ?
+         WHERE id = ? AND status = 'pending' AND attempts = ?"
     )
-    .bind(&delivery_id)
-    .bind(invoice_id)
-    .bind(&webhook_url)
-    .bind(&payload_str)
-    .bind(&timestamp)
+    .bind(attempt)
+    .bind(&ts)
     .bind(&next_retry)
+    .bind(&row.id)
+    .bind(row.attempts)
     .execute(pool)
-    .await?;
+    .await?
+    .rows_affected() > 0;
+    if !claimed {
+        return Ok(());
+    }
+
+    if let Err(reason) = crate::validation::resolve_and_check_host(&row.url) {
+        tracing::warn!(delivery_id = %row.id, channel = %row.channel, %reason, "Delivery blocked: SSRF protection");
+        sqlx::query("UPDATE webhook_deliveries SET status = 'failed' WHERE id = ?")
+            .bind(&row.id)
+            .execute(pool)
+            .await?;
+        return Ok(());
+    }
+
+    let mut body: serde_json::Value = serde_json::from_str(&row.payload)?;
+    let event = body["event"].as_str().unwrap_or(&row.channel).to_string();
+    let request = match row.channel.as_str() {
+        "webhook" => {
+            // Checkout field answers are added when sending rather than queued with the
+            // payload, so the outbox never holds them unencrypted.
+            if let Some(stored) = row.custom_fields.as_deref() {
+                match crate::products::fields::open(stored, encryption_key) {
+                    Ok(fields) => body["custom_fields"] = fields,
+                    Err(e) => tracing::warn!(delivery_id = %row.id, error = %e, "Failed to read checkout fields"),
+                }
+            }
+            let payload = body.to_string();
+            let secret = match crate::crypto::decrypt_webhook_secret(&row.webhook_secret, encryption_key) {
+                Ok(secret) => secret,
+                Err(e) => {
+                    // Retrying cannot help, and a webhook signed with anything else would be forged.
+                    tracing::error!(delivery_id = %row.id, error = %e, "Webhook secret could not be decrypted");
+                    sqlx::query("UPDATE webhook_deliveries SET status = 'failed' WHERE id = ?")
+                        .bind(&row.id)
+                        .execute(pool)
+                        .await?;
+                    if let Some(invoice_id) = row.invoice_id.as_deref() {
+                        record_attempt(pool, invoice_id, &event, attempt, Some("webhook secret could not be decrypted".into())).await;
+                    }
+                    return Ok(());
+                }
+            };
+            http.post(&row.url)
+                .header("X-CipherPay-Event-Id", &row.id)
+                .header("X-CipherPay-Signature", sign(Scheme::V1, &secret, &row.id, &ts, payload.as_bytes()))
+                .header("X-CipherPay-Signatures", signatures_header(&secret, &row.id, &ts, payload.as_bytes()))
+                .header("X-CipherPay-Timestamp", &ts)
+        }
+        "matrix" => {
+            let token = row.matrix_access_token.as_deref()
+                .and_then(|t| crate::crypto::decrypt_or_plaintext(t, encryption_key).ok())
+                .unwrap_or_default();
+            http.put(&row.url).bearer_auth(token)
+        }
+        _ => http.post(&row.url),
+    };
 
-    match http.post(&webhook_url)
-        .header("X-CipherPay-Signature", &signature)
-        .header("X-CipherPay-Timestamp", &timestamp)
-        .json(&payload)
+    let result = request
+        .json(&body)
         .timeout(std::time::Duration::from_secs(10))
         .send()
-        .await
-    {
-        Ok(resp) if resp.status().is_success() => {
+        .await;
+
+    let error = match result {
+        Ok(resp) if resp.status().is_success() => None,
+        Ok(resp) => Some(format!("HTTP {}", resp.status())),
+        Err(e) => Some(e.to_string()),
+    };
+
+    match error {
+        None => {
             sqlx::query("UPDATE webhook_deliveries SET status = 'delivered' WHERE id = ?")
-                .bind(&delivery_id)
+                .bind(&row.id)
                 .execute(pool)
                 .await?;
-            tracing::info!(invoice_id, event, "Payment webhook delivered");
+            tracing::info!(delivery_id = %row.id, invoice_id = ?row.invoice_id, channel = %row.channel, event, attempt, "Delivery sent");
         }
-        Ok(resp) => {
-            tracing::warn!(invoice_id, event, status = %resp.status(), "Payment webhook rejected, will retry");
+        Some(ref e) if attempt >= 5 => {
+            sqlx::query("UPDATE webhook_deliveries SET status = 'failed' WHERE id = ?")
+                .bind(&row.id)
+                .execute(pool)
+                .await?;
+            tracing::warn!(delivery_id = %row.id, invoice_id = ?row.invoice_id, channel = %row.channel, event, error = %e, "Delivery permanently failed after 5 attempts");
         }
-        Err(e) => {
-            tracing::warn!(invoice_id, event, error = %e, "Payment webhook failed, will retry");
+        Some(ref e) => {
+            tracing::warn!(delivery_id = %row.id, invoice_id = ?row.invoice_id, channel = %row.channel, event, attempt, error = %e, next_retry = %next_retry, "Delivery failed, will retry");
         }
     }
 
+    if let (Some(invoice_id), "webhook") = (row.invoice_id.as_deref(), row.channel.as_str()) {
+        record_attempt(pool, invoice_id, &event, attempt, error).await;
+    }
     Ok(())
 }
 
+/// Merchants and channels `retry_failed` sends to at once.
+const RETRY_CONCURRENCY: usize = 8;
+
+/// Send due deliveries: webhook retries and queued chat messages. Each merchant's deliveries
+/// on a channel are sent one at a time, oldest sequence first; up to `RETRY_CONCURRENCY`
+/// merchants and channels are served side by side, so one slow endpoint does not hold up
+/// the rest. A delivery that errors is logged and left for the next run.
 pub async fn retry_failed(pool: &SqlitePool, http: &reqwest::Client, encryption_key: &str) -> anyhow::Result<()> {
     let now = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
 
-    let rows = sqlx::query_as::<_, (String, String, String, String, i64)>(
-        "SELECT wd.id, wd.url, wd.payload, m.webhook_secret, wd.attempts
-         FROM webhook_deliveries wd
-         JOIN invoices i ON wd.invoice_id = i.id
-         JOIN merchants m ON i.merchant_id = m.id
-         WHERE wd.status = 'pending'
+    let rows = sqlx::query_as::<_, DeliveryRow>(&format!(
+        "{} WHERE wd.status = 'pending'
          AND wd.attempts < 5
-         AND (wd.next_retry_at IS NULL OR wd.next_retry_at <= ?)"
-    )
+         AND (wd.next_retry_at IS NULL OR wd.next_retry_at <= ?)
+         ORDER BY m.id, wd.channel, wd.sequence ASC, wd.created_at ASC",
+        DELIVERY_SELECT
+    ))
     .bind(&now)
     .fetch_all(pool)
     .await?;
 
-    for (id, url, payload, raw_secret, attempts) in rows {
-        let secret = crate::crypto::decrypt_webhook_secret(&raw_secret, encryption_key)
-            .unwrap_or(raw_secret);
-        if let Err(reason) = crate::validation::resolve_and_check_host(&url) {
-            tracing::warn!(delivery_id = %id, %url, %reason, "Webhook retry blocked: SSRF protection");
-            sqlx::query("UPDATE webhook_deliveries SET status = 'failed' WHERE id = ?")
-                .bind(&id)
-                .execute(pool)
-                .await?;
-            continue;
+    let mut queues: Vec<Vec<DeliveryRow>> = Vec::new();
+    for row in rows {
+        match queues.last_mut() {
+            Some(queue) if queue[0].merchant_id == row.merchant_id && queue[0].channel == row.channel => queue.push(row),
+            _ => queues.push(vec![row]),
         }
+    }
 
-        let body: serde_json::Value = serde_json::from_str(&payload)?;
-        let ts = Utc::now().format("%Y-%m-%dT%H:%M:%SZ").to_string();
-        let signature = sign_payload(&secret, &ts, &payload);
-
-        match http.post(&url)
-            .header("X-CipherPay-Signature", &signature)
-            .header("X-CipherPay-Timestamp", &ts)
-            .json(&body)
-            .timeout(std::time::Duration::from_secs(10))
-            .send()
-            .await
-        {
-            Ok(resp) if resp.status().is_success() => {
-                sqlx::query("UPDATE webhook_deliveries SET status = 'delivered' WHERE id = ?")
-                    .bind(&id)
-                    .execute(pool)
-                    .await?;
-                tracing::info!(delivery_id = %id, "Webhook retry delivered");
-            }
-            _ => {
-                let new_attempts = attempts + 1;
-                if new_attempts >= 5 {
-                    sqlx::query(
-                        "UPDATE webhook_deliveries SET status = 'failed', attempts = ?, last_attempt_at = ? WHERE id = ?"
-                    )
-                    .bind(new_attempts)
-                    .bind(&ts)
-                    .bind(&id)
-                    .execute(pool)
-                    .await?;
-                    tracing::warn!(delivery_id = %id, "Webhook permanently failed after 5 attempts");
-                } else {
-                    let next = (Utc::now() + chrono::Duration::seconds(retry_delay_secs(new_attempts)))
-                        .format("%Y-%m-%dT%H:%M:%SZ")
-                        .to_string();
-                    sqlx::query(
-                        "UPDATE webhook_deliveries SET attempts = ?, last_attempt_at = ?, next_retry_at = ? WHERE id = ?"
-                    )
-                    .bind(new_attempts)
-                    .bind(&ts)
-                    .bind(&next)
-                    .bind(&id)
-                    .execute(pool)
-                    .await?;
-                    tracing::info!(delivery_id = %id, attempt = new_attempts, next_retry = %next, "Webhook retry scheduled");
+    futures::stream::iter(queues)
+        .for_each_concurrent(RETRY_CONCURRENCY, |queue| async move {
+            let lock = delivery_lock(&queue[0].merchant_id, &queue[0].channel);
+            let _guard = lock.lock().await;
+            for row in &queue {
+                if let Err(e) = attempt_delivery(pool, http, row, encryption_key).await {
+                    tracing::error!(delivery_id = %row.id, channel = %row.channel, error = %e, "Delivery attempt failed");
                 }
             }
+        })
+        .await;
+
+    Ok(())
+}
+
+/// Deliveries last attempted since `since`, as (failing, total). Failing counts those that
+/// gave up and those still waiting on a retry.
+pub async fn recent_outcomes(pool: &SqlitePool, since: &str) -> anyhow::Result<(i64, i64)> {
+    let row: (i64, i64) = sqlx::query_as(
+        "SELECT COALESCE(SUM(status != 'delivered'), 0), COUNT(*) FROM webhook_deliveries
+         WHERE last_attempt_at >= ?"
+    )
+    .bind(since)
+    .fetch_one(pool)
+    .await?;
+    Ok(row)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_subscriptions() {
+        assert!(subscribed(None, "confirmed"));
+        assert!(!subscribed(None, "created"));
+        assert!(subscribed(Some("created,confirmed"), "created"));
+        assert!(!subscribed(Some("created,confirmed"), "expired"));
+        assert!(!subscribed(Some(""), "confirmed"));
+        assert!(DEFAULT_EVENTS.iter().all(|e| EVENTS.contains(e)));
+    }
+
+    #[tokio::test]
+    async fn test_product_events_are_queued_for_the_merchant() {
+        let pool = crate::db::test_pool().await;
+        let merchant_id = crate::merchants::create_merchant(&pool, &crate::merchants::CreateMerchantRequest {
+            name: Some("Shop".into()),
+            ufvk: crate::scanner::fixtures::test_ufvk(1),
+            webhook_url: None,
+            email: None,
+            verify_blocks: None,
+        }, "").await.unwrap().merchant_id;
+        sqlx::query("UPDATE merchants SET webhook_url = 'https://1.1.1.1/hook' WHERE id = ?")
+            .bind(&merchant_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let product = crate::products::create_product(&pool, &merchant_id, &crate::products::CreateProductRequest {
+            slug: "mug".into(),
+            name: "Mug".into(),
+            description: None,
+            price_eur: 12.0,
+            currency: None,
+            variants: None,
+            category: None,
+            tags: None,
+            max_quantity: None,
+            checkout_fields: None,
+        }).await.unwrap();
+
+        // Catalog events are opt-in.
+        assert_eq!(enqueue_product(&pool, "product.created", &product).await.unwrap(), None);
+        set_events(&pool, &merchant_id, &["product.created".into()]).await.unwrap();
+        assert_eq!(enqueue_product(&pool, "product.created", &product).await.unwrap(), Some(merchant_id.clone()));
+        assert_eq!(enqueue_product(&pool, "product.updated", &product).await.unwrap(), None);
+
+        let rows = sqlx::query_as::<_, DeliveryRow>(&format!("{} WHERE m.id = ?", DELIVERY_SELECT))
+            .bind(&merchant_id)
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].invoice_id, None);
+        let payload: serde_json::Value = serde_json::from_str(&rows[0].payload).unwrap();
+        assert_eq!((payload["event"].as_str(), payload["sequence"].as_i64()), (Some("product.created"), Some(1)));
+        assert_eq!(payload["product"]["slug"], "mug");
+    }
+
+    async fn merchant_with_webhook(pool: &SqlitePool, n: u8, url: &str) -> (String, String) {
+        let merchant_id = crate::db::test_merchant(pool, n).await.merchant_id;
+        sqlx::query("UPDATE merchants SET webhook_url = ? WHERE id = ?")
+            .bind(url)
+            .bind(&merchant_id)
+            .execute(pool)
+            .await
+            .unwrap();
+        let invoice_id = crate::db::test_invoice(pool, &merchant_id, n).await.invoice_id;
+        (merchant_id, invoice_id)
+    }
+
+    async fn received(receiver: &wiremock::MockServer, path: &str) -> Vec<serde_json::Value> {
+        receiver.received_requests().await.unwrap().iter()
+            .filter(|r| r.url.path() == path)
+            .map(|r| serde_json::from_slice(&r.body).unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_each_merchant_receives_its_webhooks_in_sequence() {
+        use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+        crate::validation::allow_private_hosts();
+        let receiver = MockServer::start().await;
+        Mock::given(method("POST")).respond_with(ResponseTemplate::new(200)).mount(&receiver).await;
+        let pool = crate::db::test_pool().await;
+        let http = reqwest::Client::new();
+        let (shop, shop_invoice) = merchant_with_webhook(&pool, 1, &format!("{}/shop", receiver.uri())).await;
+        let (cafe, cafe_invoice) = merchant_with_webhook(&pool, 2, &format!("{}/cafe", receiver.uri())).await;
+
+        enqueue(&pool, &shop_invoice, "detected", "tx-1").await.unwrap();
+        enqueue(&pool, &cafe_invoice, "detected", "tx-2").await.unwrap();
+        enqueue(&pool, &shop_invoice, "confirmed", "tx-1").await.unwrap();
+        enqueue(&pool, &shop_invoice, "refund_confirmed", "tx-3").await.unwrap();
+        enqueue(&pool, &cafe_invoice, "confirmed", "tx-2").await.unwrap();
+
+        // Racing senders (an immediate send and the retry loop) still send each once, in order.
+        let _ = tokio::join!(
+            deliver_pending(&pool, &http, &shop, ""),
+            deliver_pending(&pool, &http, &shop, ""),
+            retry_failed(&pool, &http, ""),
+            deliver_pending(&pool, &http, &cafe, ""),
+        );
+        let shop_events = received(&receiver, "/shop").await;
+        let sequence: Vec<_> = shop_events.iter().map(|e| (e["event"].as_str().unwrap(), e["sequence"].as_i64().unwrap())).collect();
+        assert_eq!(sequence, [("detected", 1), ("confirmed", 2), ("refund_confirmed", 3)]);
+        let sequence: Vec<_> = received(&receiver, "/cafe").await.iter().map(|e| e["sequence"].as_i64().unwrap()).collect();
+        assert_eq!(sequence, [1, 2]);
+
+        // Sequence numbers keep counting per merchant, whatever the event.
+        enqueue(&pool, &shop_invoice, "expired", "").await.unwrap();
+        deliver_pending(&pool, &http, &shop, "").await.unwrap();
+        assert_eq!(received(&receiver, "/shop").await[3]["sequence"], 4);
+
+        // Finished deliveries leave no lock behind.
+        drop(delivery_lock("someone-else", "webhook"));
+        let locks = LOCKS.lock().unwrap();
+        assert!(!locks.contains_key(&format!("{}:webhook", shop)));
+        assert!(!locks.contains_key(&format!("{}:webhook", cafe)));
+    }
+
+    #[tokio::test]
+    async fn test_retry_carries_on_past_a_delivery_that_errors() {
+        use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+        crate::validation::allow_private_hosts();
+        let receiver = MockServer::start().await;
+        Mock::given(method("POST")).respond_with(ResponseTemplate::new(200)).mount(&receiver).await;
+        let pool = crate::db::test_pool().await;
+        let (shop, shop_invoice) = merchant_with_webhook(&pool, 1, &format!("{}/shop", receiver.uri())).await;
+        let (_, cafe_invoice) = merchant_with_webhook(&pool, 2, &format!("{}/cafe", receiver.uri())).await;
+
+        enqueue(&pool, &shop_invoice, "detected", "tx-1").await.unwrap();
+        enqueue(&pool, &shop_invoice, "confirmed", "tx-1").await.unwrap();
+        enqueue(&pool, &cafe_invoice, "detected", "tx-2").await.unwrap();
+        sqlx::query("UPDATE webhook_deliveries SET payload = 'not json' WHERE merchant_id = ? AND sequence = 1")
+            .bind(&shop)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        retry_failed(&pool, &reqwest::Client::new(), "").await.unwrap();
+        let shop_events = received(&receiver, "/shop").await;
+        assert_eq!(shop_events.len(), 1);
+        assert_eq!(shop_events[0]["event"], "confirmed");
+        assert_eq!(received(&receiver, "/cafe").await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_expiring_a_partly_paid_invoice_reports_what_was_received() {
+        use crate::invoices::{self, state::InvoiceState, InvoiceFilter};
+        use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+        crate::validation::allow_private_hosts();
+        let receiver = MockServer::start().await;
+        Mock::given(method("POST")).respond_with(ResponseTemplate::new(200)).mount(&receiver).await;
+        let pool = crate::db::test_pool().await;
+        let (shop, partly_paid) = merchant_with_webhook(&pool, 1, &format!("{}/shop", receiver.uri())).await;
+        let unpaid = crate::db::test_invoice(&pool, &shop, 1).await.invoice_id;
+
+        invoices::mark_underpaid(&pool, &partly_paid, 5_000_000, "tx-1").await.unwrap();
+        for id in [&partly_paid, &unpaid] {
+            invoices::backdate_expiry(&pool, id).await.unwrap();
         }
+        for expired in invoices::expire_old_invoices(&pool).await.unwrap() {
+            enqueue_expired(&pool, &expired).await.unwrap();
+        }
+        deliver_pending(&pool, &reqwest::Client::new(), &shop, "").await.unwrap();
+
+        let events = received(&receiver, "/shop").await;
+        assert_eq!(events.len(), 2);
+        let payload = events.iter().find(|e| e["invoice_id"] == partly_paid.as_str()).unwrap();
+        assert_eq!(payload["event"], "expired");
+        assert_eq!(payload["received_zatoshis"], 5_000_000);
+        assert_eq!(payload["received_zec"], 0.05);
+        assert_eq!(payload["price_zec"], 0.25);
+        assert_eq!(payload["has_refund_address"], false);
+        let payload = events.iter().find(|e| e["invoice_id"] == unpaid.as_str()).unwrap();
+        assert_eq!(payload["received_zatoshis"], 0);
+
+        // Only the invoice holding funds is listed for refunding.
+        let filter = InvoiceFilter { status: Some(InvoiceState::Expired), received_gt: Some(0) };
+        let to_refund = invoices::list_for_merchant(&pool, &shop, &filter, 10).await.unwrap();
+        assert_eq!(to_refund.iter().map(|i| i.id.as_str()).collect::<Vec<_>>(), [partly_paid.as_str()]);
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn test_webhook_fails_when_its_secret_cannot_be_decrypted() {
+        use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+        crate::validation::allow_private_hosts();
+        let receiver = MockServer::start().await;
+        Mock::given(method("POST")).respond_with(ResponseTemplate::new(200)).mount(&receiver).await;
+        let pool = crate::db::test_pool().await;
+        let (merchant_id, invoice_id) = merchant_with_webhook(&pool, 1, &format!("{}/hook", receiver.uri())).await;
+        sqlx::query("UPDATE merchants SET webhook_secret = 'corrupted' WHERE id = ?")
+            .bind(&merchant_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        enqueue(&pool, &invoice_id, "confirmed", "tx-1").await.unwrap();
+        deliver_pending(&pool, &reqwest::Client::new(), &merchant_id, &"a".repeat(64)).await.unwrap();
+
+        assert!(received(&receiver, "/hook").await.is_empty());
+        let status: String = sqlx::query_scalar("SELECT status FROM webhook_deliveries WHERE merchant_id = ?")
+            .bind(&merchant_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(status, "failed");
+        let events = crate::invoices::events::list(&pool, &invoice_id).await.unwrap();
+        assert_eq!(events.last().unwrap().event_type, "webhook_failed");
+    }
+
+    #[tokio::test]
+    async fn test_deliveries_are_signed_for_replay_protection() {
+        use cipherpay_client::webhook::{verify_signature, WebhookHeaders};
+        use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+        crate::validation::allow_private_hosts();
+        let receiver = MockServer::start().await;
+        Mock::given(method("POST")).respond_with(ResponseTemplate::new(503)).up_to_n_times(1).mount(&receiver).await;
+        Mock::given(method("POST")).respond_with(ResponseTemplate::new(200)).mount(&receiver).await;
+        let pool = crate::db::test_pool().await;
+        let http = reqwest::Client::new();
+        let (merchant_id, invoice_id) = merchant_with_webhook(&pool, 1, &format!("{}/hook", receiver.uri())).await;
+        let secret: String = sqlx::query_scalar("SELECT webhook_secret FROM merchants WHERE id = ?")
+            .bind(&merchant_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+
+        enqueue(&pool, &invoice_id, "confirmed", "tx-1").await.unwrap();
+        deliver_pending(&pool, &http, &merchant_id, "").await.unwrap();
+        sqlx::query("UPDATE webhook_deliveries SET next_retry_at = NULL").execute(&pool).await.unwrap();
+        retry_failed(&pool, &http, "").await.unwrap();
+
+        let requests = receiver.received_requests().await.unwrap();
+        assert_eq!(requests.len(), 2);
+        let header = |i: usize, name: &str| requests[i].headers.get(name).unwrap().to_str().unwrap().to_string();
+        // A retry is the same event, so receivers can deduplicate on its ID.
+        assert_eq!(header(0, "X-CipherPay-Event-Id"), header(1, "X-CipherPay-Event-Id"));
+
+        for (i, request) in requests.iter().enumerate() {
+            let (event_id, timestamp) = (header(i, "X-CipherPay-Event-Id"), header(i, "X-CipherPay-Timestamp"));
+            let (signatures, v1) = (header(i, "X-CipherPay-Signatures"), header(i, "X-CipherPay-Signature"));
+            let now = chrono::DateTime::parse_from_rfc3339(&timestamp).unwrap().to_utc();
+            let headers = WebhookHeaders { event_id: &event_id, timestamp: &timestamp, signatures: Some(&signatures), signature: None };
+            assert_eq!(verify_signature(&secret, &headers, &request.body, now), Ok(ACTIVE_SIGNATURE_SCHEME));
+            let legacy = WebhookHeaders { signatures: None, signature: Some(&v1), ..headers };
+            assert_eq!(verify_signature(&secret, &legacy, &request.body, now), Ok(Scheme::V1));
+
+            // Replaying the body under another event ID, or outside the window, is refused.
+            let replayed = WebhookHeaders { event_id: "another-event", ..headers };
+            assert!(verify_signature(&secret, &replayed, &request.body, now).is_err());
+            let late = now + chrono::Duration::seconds(TIMESTAMP_TOLERANCE_SECS + 1);
+            assert!(verify_signature(&secret, &headers, &request.body, late).is_err());
+        }
+    }
+
+    #[test]
+    fn test_signing_info_example_verifies() {
+        use cipherpay_client::webhook::{verify_signature, WebhookHeaders};
+
+        let info = signing_info();
+        assert_eq!(info["active_scheme"], ACTIVE_SIGNATURE_SCHEME.as_str());
+        assert_eq!(info["timestamp_tolerance_secs"], TIMESTAMP_TOLERANCE_SECS);
+        let example = &info["example"];
+        let timestamp = example["timestamp"].as_str().unwrap();
+        let headers = WebhookHeaders {
+            event_id: example["event_id"].as_str().unwrap(),
+            timestamp,
+            signatures: example["signatures"].as_str(),
+            signature: None,
+        };
+        let now = chrono::DateTime::parse_from_rfc3339(timestamp).unwrap().to_utc();
+        let body = example["body"].as_str().unwrap().as_bytes();
+        assert_eq!(verify_signature(example["secret"].as_str().unwrap(), &headers, body, now), Ok(Scheme::V2));
+    }
 }
@@ -0,0 +1,137 @@
+//! Runs the server binary against a throwaway database and drives it through
+//! `cipherpay-client`, so the SDK's types are checked against the real handlers.
+
+mod common;
+
+use std::time::Duration;
+
+use cipherpay_client::{Client, CreateInvoice, CreateMerchant, Error, InvoiceStatus, Simulation};
+use common::{orchard_tx, start_server, TEST_UFVK};
+
+#[tokio::test]
+async fn test_client_against_server() {
+    let server = start_server(&[]).await;
+    let public = Client::new(&server.base_url);
+
+    let creds = public.register_merchant(&CreateMerchant {
+        ufvk: TEST_UFVK.into(),
+        name: Some("Integration Shop".into()),
+        ..Default::default()
+    }).await.unwrap();
+    assert!(creds.api_key.starts_with("cpay_sk_"));
+    let merchant = Client::new(&server.base_url).with_api_key(&creds.api_key);
+
+    let rates = public.rates().await.unwrap();
+    assert_eq!(rates.zec_eur, 40.0);
+    assert_eq!(rates.source.as_deref(), Some("feed"));
+    let history = public.rate_history("24h").await.unwrap();
+    assert_eq!(history.points.len(), 1);
+    assert_eq!(public.rates_at(&rates.updated_at).await.unwrap().zec_usd, 44.0);
+
+    let created = merchant.create_invoice(&CreateInvoice::new(20.0).product_name("Mug")).await.unwrap();
+    assert_eq!(created.price_zec, 0.5);
+
+    let invoice = merchant.get_invoice(&created.invoice_id).await.unwrap();
+    assert_eq!(invoice.status, InvoiceStatus::Pending);
+    assert_eq!(invoice.merchant_id.as_deref(), Some(creds.merchant_id.as_str()));
+    let public_view = public.get_invoice(&created.memo_code).await.unwrap();
+    assert!(public_view.merchant_id.is_none());
+
+    let status = public.invoice_status(&created.invoice_id).await.unwrap();
+    assert_eq!(status.price_zatoshis, 50_000_000);
+    assert_eq!(merchant.list_invoices().await.unwrap().len(), 1);
+
+    let events = merchant.invoice_events(&created.invoice_id).await.unwrap();
+    assert_eq!(events[0].event_type, "created");
+
+    let mut stream = public.subscribe(&created.invoice_id, None).await.unwrap();
+    let first = tokio::time::timeout(Duration::from_secs(5), stream.next_event())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(first.status().unwrap().status, InvoiceStatus::Pending);
+    assert_eq!(stream.last_event_id(), Some(events[0].id));
+
+    let info = public.signing_info().await.unwrap();
+    let example = &info["example"];
+    let headers = cipherpay_client::webhook::WebhookHeaders {
+        event_id: example["event_id"].as_str().unwrap(),
+        timestamp: example["timestamp"].as_str().unwrap(),
+        signatures: example["signatures"].as_str(),
+        signature: None,
+    };
+    let signed_at = chrono::DateTime::parse_from_rfc3339(headers.timestamp).unwrap().to_utc();
+    let event = cipherpay_client::webhook::verify_at(
+        example["secret"].as_str().unwrap(),
+        &headers,
+        example["body"].as_str().unwrap().as_bytes(),
+        signed_at,
+    ).unwrap();
+    assert_eq!(event.event, "confirmed");
+
+    let abandoned = merchant.create_invoice(&CreateInvoice::new(5.0)).await.unwrap();
+    merchant.cancel_invoice(&abandoned.invoice_id).await.unwrap();
+    assert_eq!(merchant.get_invoice(&abandoned.invoice_id).await.unwrap().status, InvoiceStatus::Expired);
+    match merchant.mark_refunded(&abandoned.invoice_id).await {
+        Err(Error::Api { status, .. }) => assert_eq!(status, 400),
+        other => panic!("expected 400, got {:?}", other),
+    }
+    match public.cancel_invoice(&created.invoice_id).await {
+        Err(Error::Api { status, .. }) => assert_eq!(status, 401),
+        other => panic!("expected 401, got {:?}", other),
+    }
+    let wrong_key = Client::new(&server.base_url).with_api_key("cpay_sk_wrong");
+    match wrong_key.list_invoices().await {
+        Err(Error::Api { status, message, .. }) => assert_eq!((status, message.as_str()), (401, "Invalid API key")),
+        other => panic!("expected 401, got {:?}", other.map(|i| i.len())),
+    }
+
+    match public.get_invoice("no-such-invoice").await {
+        Err(Error::Api { status, .. }) => assert_eq!(status, 404),
+        other => panic!("expected 404, got {:?}", other.map(|i| i.id)),
+    }
+}
+
+fn status<T>(result: Result<T, Error>) -> u16 {
+    match result {
+        Ok(_) => 200,
+        Err(Error::Api { status, .. }) => status,
+        Err(e) => panic!("request failed: {}", e),
+    }
+}
+
+#[tokio::test]
+async fn test_cancel_and_refund_by_api_key() {
+    let server = start_server(&[]).await;
+    let register = |ufvk: String| {
+        let url = server.base_url.clone();
+        async move {
+            let creds = Client::new(&url).register_merchant(&CreateMerchant { ufvk, ..Default::default() }).await.unwrap();
+            Client::new(&url).with_api_key(&creds.api_key)
+        }
+    };
+    let merchant = register(TEST_UFVK.into()).await;
+    let other = register(orchard_tx::test_ufvk(21)).await;
+
+    let pending = merchant.create_invoice(&CreateInvoice::new(5.0)).await.unwrap();
+    let paid = merchant.create_invoice(&CreateInvoice::new(5.0)).await.unwrap();
+    merchant.simulate_detect(&paid.invoice_id, &Simulation::default()).await.unwrap();
+    merchant.simulate_confirm(&paid.invoice_id, &Simulation::default()).await.unwrap();
+
+    // Another merchant's key cannot tell the invoices exist, let alone change them.
+    assert_eq!(status(other.cancel_invoice(&pending.invoice_id).await), 404);
+    assert_eq!(status(other.mark_refunded(&paid.invoice_id).await), 404);
+    assert_eq!(merchant.get_invoice(&pending.invoice_id).await.unwrap().status, InvoiceStatus::Pending);
+    assert_eq!(merchant.get_invoice(&paid.invoice_id).await.unwrap().status, InvoiceStatus::Confirmed);
+
+    // Pending is cancelled and confirmed refunded, each once; not the other way round.
+    assert_eq!(status(merchant.mark_refunded(&pending.invoice_id).await), 400);
+    assert_eq!(status(merchant.cancel_invoice(&paid.invoice_id).await), 400);
+    assert_eq!(status(merchant.cancel_invoice(&pending.invoice_id).await), 200);
+    assert_eq!(merchant.get_invoice(&pending.invoice_id).await.unwrap().status, InvoiceStatus::Expired);
+    assert_eq!(status(merchant.cancel_invoice(&pending.invoice_id).await), 400);
+    assert_eq!(status(merchant.mark_refunded(&paid.invoice_id).await), 200);
+    assert_eq!(merchant.get_invoice(&paid.invoice_id).await.unwrap().status, InvoiceStatus::Refunded);
+    assert_eq!(status(merchant.mark_refunded(&paid.invoice_id).await), 400);
+}
@@ -0,0 +1,128 @@
+//! Shared harness for the integration tests: runs the server binary against a throwaway
+//! database, with CoinGecko and CipherScan replaced by local mock servers.
+
+#![allow(dead_code)]
+
+#[path = "../../src/scanner/fixtures.rs"]
+pub mod orchard_tx;
+
+use std::net::TcpListener;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use serde_json::json;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+use zcash_address::unified::{Encoding, Fvk, Ufvk};
+use zcash_protocol::consensus::NetworkType;
+
+pub const TEST_UFVK: &str = "uviewtest104pdexy7tg998p6023wpglsj478szyn5vh2224prnqsl6zh9weq3k9antlww9khe790uxmpwvcswyycjhkmpc26vk7z7r0wrl8lxjvvcztzzqsxc9e0qwvamfh78fzl5jgvwaly3avj7d4k6t7ke8m42ukn9ukc9xjl4mhqpqspr308m7xh9587m93rpkwplvv2zh9lw9ddr0kq5yvu649f2ldsraphru6a0c950uwcpa4jz4g99z5t9msdw065h8rm3h7p28gfz6pr2gx3hzg93reg8t96m6dx4ztk55wy0qqpkp9qj736f5rfnz0vd6ykw7jlrqgs00575tutpeplfjv9czfu9dhllp5q7c0nxun30xn48cgav34lfl2lny8lg6dsmpvmrw";
+
+pub struct TestServer {
+    child: Child,
+    dir: std::path::PathBuf,
+    pub base_url: String,
+    /// Stand-in for CipherScan. Unmounted routes answer 404, which the scanner logs and skips.
+    pub cipherscan: MockServer,
+    pub prices: MockServer,
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// CoinGecko stand-in with fixed prices: 1 ZEC = 40 EUR = 44 USD.
+async fn mock_price_feed() -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "zcash": { "eur": 40.0, "usd": 44.0 },
+        })))
+        .mount(&server)
+        .await;
+    server
+}
+
+/// Start the server on testnet. `env` is applied last, overriding the defaults.
+pub async fn start_server(env: &[(&str, &str)]) -> TestServer {
+    let port = free_port();
+    let dir = std::env::temp_dir().join(format!("cipherpay-it-{}-{}", std::process::id(), port));
+    std::fs::create_dir_all(&dir).unwrap();
+    let prices = mock_price_feed().await;
+    let cipherscan = MockServer::start().await;
+
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_cipherpay"));
+    cmd.env("DATABASE_URL", format!("sqlite:{}/test.db?mode=rwc", dir.display()))
+        .env("API_HOST", "127.0.0.1")
+        .env("API_PORT", port.to_string())
+        .env("NETWORK", "testnet")
+        .env("COINGECKO_API_URL", prices.uri())
+        .env("CIPHERSCAN_API_URL", cipherscan.uri())
+        .env("MEDIA_DIR", dir.join("media"))
+        .env("ENCRYPTION_KEY", "")
+        .env("RUST_LOG", "error")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    for (key, value) in env {
+        cmd.env(key, value);
+    }
+    let child = cmd.spawn().expect("failed to start cipherpay");
+    let server = TestServer {
+        child,
+        dir,
+        base_url: format!("http://127.0.0.1:{}", port),
+        cipherscan,
+        prices,
+    };
+
+    let health = format!("{}/api/health", server.base_url);
+    for _ in 0..100 {
+        if reqwest::get(&health).await.is_ok_and(|r| r.status().is_success()) {
+            return server;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("server did not become healthy");
+}
+
+/// Answer GET `route` on `server` with `body`.
+pub async fn mount_json(server: &MockServer, route: &str, body: serde_json::Value) {
+    Mock::given(method("GET"))
+        .and(path(route))
+        .respond_with(ResponseTemplate::new(200).set_body_json(body))
+        .mount(server)
+        .await;
+}
+
+/// The mainnet encoding of `orchard_tx::test_ufvk(seed)`.
+pub fn test_mainnet_ufvk(seed: u8) -> String {
+    Ufvk::try_from_items(vec![Fvk::Orchard(orchard_tx::test_fvk(seed).to_bytes())])
+        .unwrap()
+        .encode(&NetworkType::Main)
+}
+
+/// Poll `check` every 200ms until it returns `Some`, failing the test after `timeout`.
+pub async fn wait_for<T, F, Fut>(what: &str, timeout: Duration, mut check: F) -> T
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Option<T>>,
+{
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        if let Some(value) = check().await {
+            return value;
+        }
+        if tokio::time::Instant::now() > deadline {
+            panic!("timed out waiting for {}", what);
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+}
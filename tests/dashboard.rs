@@ -0,0 +1,165 @@
+//! Dashboard sessions: destructive account actions need a session re-confirmed with the
+//! dashboard token through `POST /api/auth/elevate`, and the merchant event stream.
+
+mod common;
+
+use std::time::Duration;
+
+use cipherpay_client::{Client, CreateInvoice, CreateMerchant};
+use common::{orchard_tx, start_server};
+use serde_json::json;
+
+fn session_cookie(resp: &reqwest::Response) -> String {
+    resp.headers()["set-cookie"].to_str().unwrap().split(';').next().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn test_destructive_actions_need_an_elevated_session() {
+    let server = start_server(&[
+        ("ALLOW_PRIVATE_WEBHOOKS", "true"),
+        ("AUTH_RATE_LIMIT_BURST", "100"),
+    ]).await;
+    let creds = Client::new(&server.base_url).register_merchant(&CreateMerchant {
+        ufvk: orchard_tx::test_ufvk(11),
+        ..Default::default()
+    }).await.unwrap();
+
+    let http = reqwest::Client::new();
+    let url = |path: &str| format!("{}/api{}", server.base_url, path);
+    let login = http.post(url("/auth/session"))
+        .json(&json!({ "token": creds.dashboard_token }))
+        .send().await.unwrap();
+    let cookie = session_cookie(&login);
+
+    let actions = [
+        ("POST", "/merchants/me/regenerate-api-key", json!({})),
+        ("POST", "/merchants/me/regenerate-dashboard-token", json!({})),
+        ("PATCH", "/merchants/me", json!({ "webhook_url": "http://127.0.0.1:9/hook" })),
+        ("POST", "/merchants/me/delete", json!({})),
+    ];
+    let send = |method: &str, path: &str, body: &serde_json::Value, cookie: &str| {
+        http.request(method.parse().unwrap(), url(path)).header("Cookie", cookie).json(body).send()
+    };
+
+    for (method, path, body) in &actions {
+        let resp = send(method, path, body, &cookie).await.unwrap();
+        assert_eq!(resp.status(), 403, "{} {}", method, path);
+        let error: serde_json::Value = resp.json().await.unwrap();
+        assert_eq!(error["code"], "elevation_required");
+    }
+
+    let wrong = http.post(url("/auth/elevate")).header("Cookie", &cookie)
+        .json(&json!({ "token": "cpay_dash_wrong" }))
+        .send().await.unwrap();
+    assert_eq!(wrong.status(), 401);
+    let elevate = |cookie: String, token: String| {
+        let http = http.clone();
+        let url = url("/auth/elevate");
+        async move {
+            let resp = http.post(url).header("Cookie", &cookie)
+                .json(&json!({ "token": token }))
+                .send().await.unwrap();
+            assert_eq!(resp.status(), 200);
+            session_cookie(&resp)
+        }
+    };
+    let elevated = elevate(cookie.clone(), creds.dashboard_token.clone()).await;
+    assert_ne!(elevated, cookie);
+
+    // The session the elevation replaced is gone rather than elevated.
+    let (method, path, body) = &actions[0];
+    assert_eq!(send(method, path, body, &cookie).await.unwrap().status(), 401);
+
+    for (method, path, body) in [&actions[0], &actions[2]] {
+        let resp = send(method, path, body, &elevated).await.unwrap();
+        assert_eq!(resp.status(), 200, "{} {}", method, path);
+    }
+
+    // A new dashboard token signs out every session, so deleting needs a fresh one.
+    let (method, path, body) = &actions[1];
+    let resp = send(method, path, body, &elevated).await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let token = resp.json::<serde_json::Value>().await.unwrap()["dashboard_token"].as_str().unwrap().to_string();
+    let login = http.post(url("/auth/session")).json(&json!({ "token": token })).send().await.unwrap();
+    let elevated = elevate(session_cookie(&login), token).await;
+
+    let (method, path, body) = &actions[3];
+    assert_eq!(send(method, path, body, &elevated).await.unwrap().status(), 200);
+    let me = http.get(url("/merchants/me")).header("Cookie", &elevated).send().await.unwrap();
+    assert_eq!(me.status(), 401);
+}
+
+/// Read server-sent events until `n` have arrived, as (event, id, data).
+async fn read_events(resp: &mut reqwest::Response, buf: &mut String, n: usize) -> Vec<(String, String, serde_json::Value)> {
+    let mut events = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while events.len() < n {
+            while let Some(end) = buf.find("\n\n") {
+                let frame: String = buf.drain(..end + 2).collect();
+                let field = |name: &str| frame.lines()
+                    .find_map(|l| l.strip_prefix(name).map(|v| v.trim().to_string()));
+                if let (Some(event), Some(data)) = (field("event:"), field("data:")) {
+                    events.push((event, field("id:").unwrap_or_default(), serde_json::from_str(&data).unwrap()));
+                }
+            }
+            if events.len() < n {
+                let chunk = resp.chunk().await.unwrap().expect("stream ended");
+                buf.push_str(&String::from_utf8_lossy(&chunk));
+            }
+        }
+    }).await.expect("timed out waiting for events");
+    events
+}
+
+#[tokio::test]
+async fn test_merchant_event_stream() {
+    let server = start_server(&[("AUTH_RATE_LIMIT_BURST", "100")]).await;
+    let register = |n| {
+        let url = server.base_url.clone();
+        async move {
+            Client::new(&url).register_merchant(&CreateMerchant {
+                ufvk: orchard_tx::test_ufvk(n),
+                ..Default::default()
+            }).await.unwrap()
+        }
+    };
+    let (ours, theirs) = (register(13).await, register(14).await);
+    let merchant = Client::new(&server.base_url).with_api_key(&ours.api_key);
+    let other = Client::new(&server.base_url).with_api_key(&theirs.api_key);
+    let first = merchant.create_invoice(&CreateInvoice::new(10.0)).await.unwrap();
+    other.create_invoice(&CreateInvoice::new(10.0)).await.unwrap();
+
+    let http = reqwest::Client::new();
+    let login = http.post(format!("{}/api/auth/session", server.base_url))
+        .json(&json!({ "token": ours.dashboard_token }))
+        .send().await.unwrap();
+    let cookie = session_cookie(&login);
+    let stream_url = format!("{}/api/merchants/me/events/stream", server.base_url);
+    let open = |last_event_id: String| http.get(&stream_url)
+        .header("Cookie", &cookie)
+        .header("Last-Event-ID", last_event_id)
+        .send();
+
+    assert_eq!(http.get(&stream_url).send().await.unwrap().status(), 401);
+
+    // Resuming from the start replays only this merchant's invoices, then follows live.
+    let mut resp = open("0".into()).await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let mut buf = String::new();
+    let replayed = read_events(&mut resp, &mut buf, 1).await;
+    assert_eq!(replayed[0].0, "invoice");
+    assert_eq!(replayed[0].2["invoice_id"], first.invoice_id);
+    assert_eq!(replayed[0].2["event_type"], "created");
+
+    merchant.cancel_invoice(&first.invoice_id).await.unwrap();
+    let live = read_events(&mut resp, &mut buf, 1).await;
+    assert_eq!(live[0].2["event_type"], "cancelled");
+    assert_eq!(live[0].2["invoice_status"], "expired");
+    drop(resp);
+
+    let second = merchant.create_invoice(&CreateInvoice::new(10.0)).await.unwrap();
+    let mut resp = open(live[0].1.clone()).await.unwrap();
+    let resumed = read_events(&mut resp, &mut String::new(), 1).await;
+    assert_eq!(resumed[0].2["invoice_id"], second.invoice_id);
+    assert_eq!(resumed[0].2["event_type"], "created");
+}
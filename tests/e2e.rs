@@ -0,0 +1,555 @@
+//! End-to-end payment flow: a fake Orchard payment appears in the mocked CipherScan
+//! mempool, is mined, and the merchant receives signed webhooks for each step.
+
+mod common;
+
+use std::time::Duration;
+
+use cipherpay_client::webhook::{self, WebhookEvent, WebhookHeaders};
+use cipherpay_client::{Client, CreateInvoice, CreateMerchant, Error, InvoiceStatus, Simulation};
+use common::{mount_json, orchard_tx, start_server, wait_for, TEST_UFVK};
+use serde_json::json;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+/// Verified webhooks received so far, in arrival order.
+async fn received_webhooks(receiver: &MockServer, secret: &str) -> Vec<WebhookEvent> {
+    let requests = receiver.received_requests().await.unwrap_or_default();
+    requests.iter().map(|req| {
+        let header = |name: &str| req.headers.get(name).and_then(|v| v.to_str().ok());
+        let headers = WebhookHeaders {
+            event_id: header("X-CipherPay-Event-Id").unwrap(),
+            timestamp: header("X-CipherPay-Timestamp").unwrap(),
+            signatures: header("X-CipherPay-Signatures"),
+            signature: header("X-CipherPay-Signature"),
+        };
+        webhook::verify(secret, &headers, &req.body).expect("webhook signature")
+    }).collect()
+}
+
+#[tokio::test]
+async fn test_payment_detected_confirmed_and_delivered() {
+    let server = start_server(&[
+        ("MEMPOOL_POLL_INTERVAL_SECS", "1"),
+        ("BLOCK_POLL_INTERVAL_SECS", "1"),
+        ("ALLOW_PRIVATE_WEBHOOKS", "true"),
+    ]).await;
+    let receiver = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&receiver)
+        .await;
+
+    let creds = Client::new(&server.base_url).register_merchant(&CreateMerchant {
+        ufvk: TEST_UFVK.into(),
+        webhook_url: Some(format!("{}/hook", receiver.uri())),
+        ..Default::default()
+    }).await.unwrap();
+    let merchant = Client::new(&server.base_url).with_api_key(&creds.api_key);
+    let created = merchant.create_invoice(&CreateInvoice::new(20.0)).await.unwrap();
+
+    // The buyer's wallet broadcasts the payment.
+    let tx = orchard_tx::transaction(&[
+        orchard_tx::Output::to_address(&created.payment_address, 50_000_000, &created.memo_code),
+    ], 1);
+    let (txid, raw) = (orchard_tx::txid(&tx), hex::encode(&tx));
+    mount_json(&server.cipherscan, "/api/blockchain-info", json!({ "blocks": 100 })).await;
+    mount_json(&server.cipherscan, "/api/mempool", json!({ "transactions": [{ "txid": txid }] })).await;
+    mount_json(&server.cipherscan, &format!("/api/tx/{}/raw", txid), json!({ "hex": raw })).await;
+    mount_json(&server.cipherscan, &format!("/api/tx/{}", txid), json!({})).await;
+
+    let detected = wait_for("detection", Duration::from_secs(15), || async {
+        let invoice = merchant.get_invoice(&created.invoice_id).await.unwrap();
+        (invoice.status == InvoiceStatus::Detected).then_some(invoice)
+    }).await;
+    assert_eq!(detected.detected_txid.as_deref(), Some(txid.as_str()));
+    assert_eq!(detected.received_zatoshis, 50_000_000);
+
+    // Mined in the next block.
+    server.cipherscan.reset().await;
+    mount_json(&server.cipherscan, "/api/blockchain-info", json!({ "blocks": 101 })).await;
+    mount_json(&server.cipherscan, "/api/mempool", json!({ "transactions": [] })).await;
+    mount_json(&server.cipherscan, &format!("/api/tx/{}", txid), json!({ "block_height": 101 })).await;
+
+    let confirmed = wait_for("confirmation", Duration::from_secs(15), || async {
+        let invoice = merchant.get_invoice(&created.invoice_id).await.unwrap();
+        (invoice.status == InvoiceStatus::Confirmed).then_some(invoice)
+    }).await;
+    assert!(confirmed.confirmed_at.is_some());
+
+    let events = wait_for("webhooks", Duration::from_secs(10), || async {
+        let events = received_webhooks(&receiver, &creds.webhook_secret).await;
+        (events.len() >= 2).then_some(events)
+    }).await;
+    assert_eq!(events[0].event, "detected");
+    assert_eq!(events[1].event, "confirmed");
+    assert!(events[0].sequence < events[1].sequence);
+    for event in &events {
+        assert_eq!(event.invoice_id, created.invoice_id);
+        assert_eq!(event.txid.as_deref(), Some(txid.as_str()));
+    }
+
+    let timeline: Vec<String> = merchant.invoice_events(&created.invoice_id).await.unwrap()
+        .into_iter()
+        .map(|e| e.event_type)
+        .collect();
+    for expected in ["created", "mempool_seen", "detected", "confirmed"] {
+        assert!(timeline.iter().any(|t| t == expected), "missing {} in {:?}", expected, timeline);
+    }
+
+    let status: serde_json::Value = reqwest::get(format!("{}/api/system/status", server.base_url))
+        .await.unwrap()
+        .json().await.unwrap();
+    assert!(status["networks"][0]["chain_height"].as_u64().is_some_and(|h| h >= 100), "{}", status);
+    assert!(status["networks"][0]["mempool_scanned_at"].is_string(), "{}", status);
+}
+
+#[tokio::test]
+async fn test_system_status_reports_scanner_and_price_freshness() {
+    let server = start_server(&[
+        ("MEMPOOL_POLL_INTERVAL_SECS", "1"),
+        ("BLOCK_POLL_INTERVAL_SECS", "1"),
+    ]).await;
+    mount_json(&server.cipherscan, "/api/blockchain-info", json!({ "blocks": 120 })).await;
+    mount_json(&server.cipherscan, "/api/mempool", json!({ "transactions": [] })).await;
+    // Let both loops complete a pass against the mounted routes before the status is cached.
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    let url = format!("{}/api/system/status", server.base_url);
+    let resp = reqwest::get(&url).await.unwrap();
+    assert_eq!(resp.headers()["cache-control"], "public, max-age=15");
+    let status: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(status["status"], "operational", "{}", status);
+    assert_eq!(status["server_version"], env!("CARGO_PKG_VERSION"));
+    assert!(status["api_version"].is_string());
+    assert_eq!(status["network"], "testnet");
+
+    let testnet = &status["networks"][0];
+    assert_eq!(testnet["network"], "testnet");
+    assert_eq!(testnet["chain_height"], 120);
+    assert!(testnet["mempool_scanned_at"].is_string(), "{}", status);
+    assert!(testnet["mempool_scan_age_secs"].as_i64().is_some_and(|age| (0..=5).contains(&age)), "{}", status);
+
+    let price_feed = &status["price_feed"];
+    assert_eq!(price_feed["source"], "feed");
+    assert!(price_feed["updated_at"].is_string());
+    assert!(price_feed["age_secs"].as_i64().is_some_and(|age| (0..=10).contains(&age)), "{}", status);
+
+    // Within the cache window the same snapshot is served, though the mempool was scanned since.
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let again: serde_json::Value = reqwest::get(&url).await.unwrap().json().await.unwrap();
+    assert_eq!(again, status);
+}
+
+#[tokio::test]
+async fn test_health_leaves_job_names_to_operators() {
+    let server = start_server(&[("ADMIN_TOKEN", "operator-token")]).await;
+
+    let health: serde_json::Value = reqwest::get(format!("{}/api/health", server.base_url))
+        .await.unwrap().json().await.unwrap();
+    assert_eq!(health["status"], "ok");
+    assert!(health["jobs"]["total"].as_u64().is_some_and(|n| n > 0), "{}", health);
+    assert!(health["jobs"]["healthy"].is_boolean(), "{}", health);
+    assert!(health["jobs"].get("unhealthy").is_none(), "{}", health);
+
+    let jobs: serde_json::Value = reqwest::Client::new()
+        .get(format!("{}/api/admin/jobs", server.base_url))
+        .bearer_auth("operator-token")
+        .send().await.unwrap().json().await.unwrap();
+    assert_eq!(jobs["jobs"].as_array().map(Vec::len), health["jobs"]["total"].as_u64().map(|n| n as usize));
+    assert!(jobs["unhealthy"].is_array(), "{}", jobs);
+}
+
+#[tokio::test]
+async fn test_unmatched_payment_attached_to_invoice() {
+    let server = start_server(&[
+        ("MEMPOOL_POLL_INTERVAL_SECS", "1"),
+        // The inbox lives under /merchants; polling it would trip the auth limit.
+        ("AUTH_RATE_LIMIT_BURST", "100"),
+    ]).await;
+    let creds = Client::new(&server.base_url).register_merchant(&CreateMerchant {
+        ufvk: orchard_tx::test_ufvk(9),
+        ..Default::default()
+    }).await.unwrap();
+    let merchant = Client::new(&server.base_url).with_api_key(&creds.api_key);
+    let created = merchant.create_invoice(&CreateInvoice::new(20.0)).await.unwrap();
+
+    // The buyer pays the wallet's base address with a memo that names no invoice.
+    let tx = orchard_tx::transaction(&[orchard_tx::Output::to_wallet(9, 0, 50_000_000, "order for Alice")], 4);
+    let (txid, raw) = (orchard_tx::txid(&tx), hex::encode(&tx));
+    mount_json(&server.cipherscan, "/api/blockchain-info", json!({ "blocks": 100 })).await;
+    mount_json(&server.cipherscan, "/api/mempool", json!({ "transactions": [{ "txid": txid }] })).await;
+    mount_json(&server.cipherscan, &format!("/api/tx/{}/raw", txid), json!({ "hex": raw })).await;
+
+    let http = reqwest::Client::new();
+    let inbox = format!("{}/api/merchants/me/unmatched-payments", server.base_url);
+    let payment = wait_for("unmatched payment", Duration::from_secs(15), || async {
+        let body: serde_json::Value = http.get(&inbox).bearer_auth(&creds.api_key)
+            .send().await.unwrap()
+            .json().await.unwrap();
+        body["unmatched_payments"].get(0).cloned()
+    }).await;
+    assert_eq!(payment["txid"], txid);
+    assert_eq!(payment["amount_zatoshis"], 50_000_000);
+    assert_eq!(payment["memo"], "order for Alice");
+    assert_eq!(payment["diversifier_index"], 0);
+    assert_eq!(merchant.get_invoice(&created.invoice_id).await.unwrap().status, InvoiceStatus::Pending);
+
+    let attach = format!("{}/{}/attach", inbox, payment["id"].as_str().unwrap());
+    let resp = http.post(&attach).bearer_auth(&creds.api_key)
+        .json(&json!({ "invoice_id": created.invoice_id }))
+        .send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let invoice = merchant.get_invoice(&created.invoice_id).await.unwrap();
+    assert_eq!(invoice.status, InvoiceStatus::Detected);
+    assert_eq!(invoice.detected_txid.as_deref(), Some(txid.as_str()));
+
+    let again = http.post(&attach).bearer_auth(&creds.api_key)
+        .json(&json!({ "invoice_id": created.invoice_id }))
+        .send().await.unwrap();
+    assert_eq!(again.status(), 409);
+    let body: serde_json::Value = http.get(&inbox).bearer_auth(&creds.api_key)
+        .send().await.unwrap()
+        .json().await.unwrap();
+    assert_eq!(body["unmatched_payments"].as_array().unwrap().len(), 0);
+}
+
+#[tokio::test]
+async fn test_unmatched_payment_without_pending_invoices() {
+    let server = start_server(&[
+        ("MEMPOOL_POLL_INTERVAL_SECS", "1"),
+        ("BLOCK_POLL_INTERVAL_SECS", "1"),
+        ("AUTH_RATE_LIMIT_BURST", "100"),
+    ]).await;
+    let creds = Client::new(&server.base_url).register_merchant(&CreateMerchant {
+        ufvk: orchard_tx::test_ufvk(11),
+        ..Default::default()
+    }).await.unwrap();
+
+    // No invoice is open, so the mempool pass leaves this merchant's key out...
+    let tx = orchard_tx::transaction(&[orchard_tx::Output::to_wallet(11, 0, 30_000_000, "tip")], 5);
+    let (txid, raw) = (orchard_tx::txid(&tx), hex::encode(&tx));
+    mount_json(&server.cipherscan, "/api/blockchain-info", json!({ "blocks": 100 })).await;
+    mount_json(&server.cipherscan, "/api/mempool", json!({ "transactions": [{ "txid": txid }] })).await;
+    mount_json(&server.cipherscan, &format!("/api/tx/{}/raw", txid), json!({ "hex": raw })).await;
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let raw_path = format!("/api/tx/{}/raw", txid);
+    let requests = server.cipherscan.received_requests().await.unwrap();
+    assert!(!requests.iter().any(|r| r.url.path() == raw_path));
+
+    // ...and the block pass, which decrypts with every key, finds it once mined.
+    server.cipherscan.reset().await;
+    mount_json(&server.cipherscan, "/api/blockchain-info", json!({ "blocks": 102 })).await;
+    mount_json(&server.cipherscan, "/api/mempool", json!({ "transactions": [] })).await;
+    mount_json(&server.cipherscan, "/api/block/101", json!({ "tx": [txid] })).await;
+    mount_json(&server.cipherscan, "/api/block/102", json!({ "tx": [] })).await;
+    mount_json(&server.cipherscan, &format!("/api/tx/{}/raw", txid), json!({ "hex": raw })).await;
+
+    let http = reqwest::Client::new();
+    let inbox = format!("{}/api/merchants/me/unmatched-payments", server.base_url);
+    let payment = wait_for("unmatched payment", Duration::from_secs(15), || async {
+        let body: serde_json::Value = http.get(&inbox).bearer_auth(&creds.api_key)
+            .send().await.unwrap()
+            .json().await.unwrap();
+        body["unmatched_payments"].get(0).cloned()
+    }).await;
+    assert_eq!(payment["txid"], txid);
+    assert_eq!(payment["amount_zatoshis"], 30_000_000);
+    assert_eq!(payment["diversifier_index"], 0);
+    assert_eq!(payment["block_height"], 101);
+}
+
+#[tokio::test]
+async fn test_strict_merchant_notified_without_pending_invoices() {
+    let smtp_port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port().to_string();
+    let server = start_server(&[
+        ("MEMPOOL_POLL_INTERVAL_SECS", "1"),
+        ("ADMIN_TOKEN", "admin"),
+        // Nothing listens there: the notice is queued and its delivery fails.
+        ("SMTP_HOST", "127.0.0.1"),
+        ("SMTP_PORT", &smtp_port),
+        ("SMTP_TLS", "none"),
+        ("SMTP_FROM", "pay@example.com"),
+    ]).await;
+    let creds = Client::new(&server.base_url).register_merchant(&CreateMerchant {
+        ufvk: orchard_tx::test_ufvk(12),
+        email: Some("shop@example.com".into()),
+        ..Default::default()
+    }).await.unwrap();
+
+    let http = reqwest::Client::new();
+    let login = http.post(format!("{}/api/auth/session", server.base_url))
+        .json(&json!({ "token": creds.dashboard_token }))
+        .send().await.unwrap();
+    let cookie = login.headers()["set-cookie"].to_str().unwrap().split(';').next().unwrap().to_string();
+    let resp = http.patch(format!("{}/api/merchants/me", server.base_url))
+        .header("Cookie", &cookie)
+        .json(&json!({ "strict_address_mode": true }))
+        .send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+
+    let tx = orchard_tx::transaction(&[orchard_tx::Output::to_wallet(12, 0, 30_000_000, "")], 6);
+    let (txid, raw) = (orchard_tx::txid(&tx), hex::encode(&tx));
+    mount_json(&server.cipherscan, "/api/blockchain-info", json!({ "blocks": 100 })).await;
+    mount_json(&server.cipherscan, "/api/mempool", json!({ "transactions": [{ "txid": txid }] })).await;
+    mount_json(&server.cipherscan, &format!("/api/tx/{}/raw", txid), json!({ "hex": raw })).await;
+
+    let notice = wait_for("unmatched payment notice", Duration::from_secs(15), || async {
+        for status in ["pending", "failed"] {
+            let body: serde_json::Value = http.get(format!("{}/api/admin/emails?status={}", server.base_url, status))
+                .bearer_auth("admin")
+                .send().await.unwrap()
+                .json().await.unwrap();
+            if let Some(email) = body["emails"].as_array().unwrap().iter().find(|e| e["kind"] == "unmatched_payment") {
+                return Some(email.clone());
+            }
+        }
+        None
+    }).await;
+    assert_eq!(notice["to"], "shop@example.com");
+}
+
+#[tokio::test]
+async fn test_checkout_session_converts_once() {
+    let server = start_server(&[]).await;
+    let creds = Client::new(&server.base_url).register_merchant(&CreateMerchant {
+        ufvk: orchard_tx::test_ufvk(10),
+        ..Default::default()
+    }).await.unwrap();
+
+    let http = reqwest::Client::new();
+    let login = http.post(format!("{}/api/auth/session", server.base_url))
+        .json(&json!({ "token": creds.dashboard_token }))
+        .send().await.unwrap();
+    let cookie = login.headers()["set-cookie"].to_str().unwrap().split(';').next().unwrap().to_string();
+    let product: serde_json::Value = http.post(format!("{}/api/products", server.base_url))
+        .header("Cookie", &cookie)
+        .json(&json!({
+            "slug": "tee", "name": "Tee", "price_eur": 10.0, "variants": ["S", "M"], "max_quantity": 5,
+            "checkout_fields": [{ "name": "Discord", "type": "text", "required": true }],
+        }))
+        .send().await.unwrap()
+        .json().await.unwrap();
+    let product_id = product["id"].as_str().unwrap();
+
+    // Configuring the cart creates no invoice; required answers can wait until payment.
+    let resp = http.post(format!("{}/api/checkout/sessions", server.base_url))
+        .json(&json!({ "product_id": product_id, "variant": "S" }))
+        .send().await.unwrap();
+    assert_eq!(resp.status(), 201);
+    let session: serde_json::Value = resp.json().await.unwrap();
+    assert_eq!(session["status"], "open");
+    let url = format!("{}/api/checkout/sessions/{}", server.base_url, session["id"].as_str().unwrap());
+
+    let bad = http.patch(&url).json(&json!({ "variant": "XL" })).send().await.unwrap();
+    assert_eq!(bad.status(), 400);
+    let updated: serde_json::Value = http.patch(&url).json(&json!({ "quantity": 3 }))
+        .send().await.unwrap()
+        .json().await.unwrap();
+    assert_eq!(updated["variant"], "S");
+    assert_eq!(updated["total"], 30.0);
+
+    let public: serde_json::Value = http.get(format!("{}/api/products/{}/public", server.base_url, product_id))
+        .send().await.unwrap()
+        .json().await.unwrap();
+    let pay = json!({ "checkout_token": public["checkout_token"] });
+    let missing = http.post(format!("{}/pay", url)).json(&pay).send().await.unwrap();
+    assert_eq!(missing.status(), 400);
+    http.patch(&url).json(&json!({ "custom_fields": { "Discord": "satoshi" } })).send().await.unwrap();
+
+    let resp = http.post(format!("{}/pay", url)).json(&pay).send().await.unwrap();
+    assert_eq!(resp.status(), 201);
+    let created: serde_json::Value = resp.json().await.unwrap();
+    let invoice = Client::new(&server.base_url).with_api_key(&creds.api_key)
+        .get_invoice(created["invoice_id"].as_str().unwrap()).await.unwrap();
+    assert_eq!(invoice.price_eur, 30.0);
+
+    let again = http.post(format!("{}/pay", url)).json(&pay).send().await.unwrap();
+    assert_eq!(again.status(), 409);
+    let body: serde_json::Value = again.json().await.unwrap();
+    assert_eq!(body["invoice_id"], created["invoice_id"]);
+    let converted: serde_json::Value = http.get(&url).send().await.unwrap().json().await.unwrap();
+    assert_eq!(converted["status"], "converted");
+    assert_eq!(converted["custom_fields"]["Discord"], "satoshi");
+    assert_eq!(http.patch(&url).json(&json!({ "quantity": 1 })).send().await.unwrap().status(), 409);
+}
+
+#[tokio::test]
+async fn test_ufvk_check_finds_recent_payments() {
+    let server = start_server(&[]).await;
+    let ours = orchard_tx::transaction(&[orchard_tx::Output::to_wallet(7, 0, 30_000_000, "")], 2);
+    let theirs = orchard_tx::transaction(&[orchard_tx::Output::to_wallet(8, 0, 10_000_000, "")], 3);
+    mount_json(&server.cipherscan, "/api/blockchain-info", json!({ "blocks": 101 })).await;
+    mount_json(&server.cipherscan, "/api/block/100", json!({ "tx": [orchard_tx::txid(&theirs)] })).await;
+    mount_json(&server.cipherscan, "/api/block/101", json!({ "tx": [orchard_tx::txid(&ours)] })).await;
+    for tx in [&ours, &theirs] {
+        let route = format!("/api/tx/{}/raw", orchard_tx::txid(tx));
+        mount_json(&server.cipherscan, &route, json!({ "hex": hex::encode(tx) })).await;
+    }
+
+    let creds = Client::new(&server.base_url).register_merchant(&CreateMerchant {
+        ufvk: orchard_tx::test_ufvk(7),
+        verify_blocks: Some(2),
+        ..Default::default()
+    }).await.unwrap();
+
+    let http = reqwest::Client::new();
+    let login = http.post(format!("{}/api/auth/session", server.base_url))
+        .json(&json!({ "token": creds.dashboard_token }))
+        .send().await.unwrap();
+    let cookie = login.headers()["set-cookie"].to_str().unwrap().split(';').next().unwrap().to_string();
+
+    let check = wait_for("UFVK check", Duration::from_secs(15), || async {
+        let check: serde_json::Value = http.get(format!("{}/api/merchants/me/ufvk/check", server.base_url))
+            .header("Cookie", &cookie)
+            .send().await.unwrap()
+            .json().await.unwrap();
+        (check["scan"]["status"] != "running").then_some(check)
+    }).await;
+    assert_eq!(check["healthy"], true, "{}", check);
+    let check = &check["scan"];
+    assert_eq!(check["status"], "done", "{}", check);
+    assert_eq!(check["from_height"], 100);
+    assert_eq!(check["to_height"], 101);
+    assert_eq!(check["txs_scanned"], 2);
+    assert_eq!(check["outputs_found"], 1);
+    assert_eq!(check["received_zatoshis"], 30_000_000);
+    assert_eq!(check["payments_seen"], true);
+    assert_eq!(check["samples"][0]["txid"], orchard_tx::txid(&ours));
+
+    // The same resource runs it again.
+    let rerun = http.post(format!("{}/api/merchants/me/ufvk/check", server.base_url))
+        .header("Cookie", &cookie)
+        .json(&json!({ "blocks": 2 }))
+        .send().await.unwrap();
+    assert_eq!(rerun.status(), 202);
+}
+
+#[tokio::test]
+async fn test_payment_after_expiry_is_paid_late() {
+    let server = start_server(&[
+        ("MEMPOOL_POLL_INTERVAL_SECS", "1"),
+        ("ALLOW_PRIVATE_WEBHOOKS", "true"),
+    ]).await;
+    let receiver = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&receiver)
+        .await;
+
+    let creds = Client::new(&server.base_url).register_merchant(&CreateMerchant {
+        ufvk: TEST_UFVK.into(),
+        webhook_url: Some(format!("{}/hook", receiver.uri())),
+        ..Default::default()
+    }).await.unwrap();
+    let merchant = Client::new(&server.base_url).with_api_key(&creds.api_key);
+    let created = merchant.create_invoice(&CreateInvoice::new(20.0)).await.unwrap();
+    assert_eq!(merchant.simulate_expire(&created.invoice_id, &Simulation::default()).await.unwrap().status, "expired");
+
+    // The full amount arrives within the grace window, but after the deadline.
+    let tx = orchard_tx::transaction(&[
+        orchard_tx::Output::to_address(&created.payment_address, 50_000_000, &created.memo_code),
+    ], 9);
+    let (txid, raw) = (orchard_tx::txid(&tx), hex::encode(&tx));
+    mount_json(&server.cipherscan, "/api/blockchain-info", json!({ "blocks": 100 })).await;
+    mount_json(&server.cipherscan, "/api/mempool", json!({ "transactions": [{ "txid": txid }] })).await;
+    mount_json(&server.cipherscan, &format!("/api/tx/{}/raw", txid), json!({ "hex": raw })).await;
+
+    wait_for("late payment", Duration::from_secs(15), || async {
+        let invoice = merchant.get_invoice(&created.invoice_id).await.unwrap();
+        (invoice.status == InvoiceStatus::PaidLate).then_some(())
+    }).await;
+    let late = wait_for("paid_late webhook", Duration::from_secs(10), || async {
+        received_webhooks(&receiver, &creds.webhook_secret).await
+            .into_iter()
+            .find(|e| e.event == "paid_late")
+    }).await;
+    assert_eq!(late.invoice_id, created.invoice_id);
+    assert_eq!(late.txid.as_deref(), Some(txid.as_str()));
+    assert_eq!(late.received_zec, Some(0.5));
+
+    let events: Vec<_> = received_webhooks(&receiver, &creds.webhook_secret).await
+        .into_iter()
+        .map(|e| e.event)
+        .collect();
+    assert!(!events.iter().any(|e| e == "detected" || e == "confirmed"), "{:?}", events);
+}
+
+#[tokio::test]
+async fn test_simulated_payments() {
+    let server = start_server(&[
+        ("BLOCK_POLL_INTERVAL_SECS", "1"),
+        ("ALLOW_PRIVATE_WEBHOOKS", "true"),
+    ]).await;
+    let receiver = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&receiver)
+        .await;
+
+    let creds = Client::new(&server.base_url).register_merchant(&CreateMerchant {
+        ufvk: TEST_UFVK.into(),
+        webhook_url: Some(format!("{}/hook", receiver.uri())),
+        ..Default::default()
+    }).await.unwrap();
+    let merchant = Client::new(&server.base_url).with_api_key(&creds.api_key);
+
+    // The same viewing key cannot register a second account.
+    let duplicate = CreateMerchant { ufvk: TEST_UFVK.into(), ..Default::default() };
+    match Client::new(&server.base_url).register_merchant(&duplicate).await {
+        Err(Error::Api { status, .. }) => assert_eq!(status, 409),
+        other => panic!("expected 409, got {:?}", other),
+    }
+
+    // Underpay, top up the rest, then confirm.
+    let paid = merchant.create_invoice(&CreateInvoice::new(20.0)).await.unwrap();
+    let partial = Simulation { amount_zec: Some(0.2), ..Default::default() };
+    let result = merchant.simulate_detect(&paid.invoice_id, &partial).await.unwrap();
+    assert_eq!(result.status, "underpaid");
+    assert_eq!(result.received_zatoshis, Some(20_000_000));
+    let result = merchant.simulate_detect(&paid.invoice_id, &Simulation::default()).await.unwrap();
+    assert_eq!(result.status, "detected");
+    assert_eq!(result.received_zatoshis, Some(50_000_000));
+    assert_eq!(merchant.simulate_confirm(&paid.invoice_id, &Simulation::default()).await.unwrap().status, "confirmed");
+
+    let events = wait_for("webhooks", Duration::from_secs(10), || async {
+        let events = received_webhooks(&receiver, &creds.webhook_secret).await;
+        (events.len() >= 3).then_some(events)
+    }).await;
+    let names: Vec<_> = events.iter().map(|e| e.event.as_str()).collect();
+    assert_eq!(names, ["underpaid", "detected", "confirmed"]);
+
+    // Underpay then expire, after which nothing can be confirmed. The merchant learns of
+    // the partial payment to refund.
+    let abandoned = merchant.create_invoice(&CreateInvoice::new(5.0)).await.unwrap();
+    let partial = Simulation { amount_zec: Some(0.05), ..Default::default() };
+    assert_eq!(merchant.simulate_detect(&abandoned.invoice_id, &partial).await.unwrap().status, "underpaid");
+    assert_eq!(merchant.simulate_expire(&abandoned.invoice_id, &Simulation::default()).await.unwrap().status, "expired");
+    let expired = wait_for("expired webhook", Duration::from_secs(10), || async {
+        received_webhooks(&receiver, &creds.webhook_secret).await
+            .into_iter()
+            .find(|e| e.event == "expired")
+    }).await;
+    assert_eq!(expired.invoice_id, abandoned.invoice_id);
+    assert_eq!(expired.received_zatoshis, Some(5_000_000));
+    assert_eq!(expired.has_refund_address, Some(false));
+    let to_refund = merchant.list_expired_with_payments().await.unwrap();
+    assert_eq!(to_refund.len(), 1);
+    assert_eq!(to_refund[0].id, abandoned.invoice_id);
+    match merchant.simulate_confirm(&abandoned.invoice_id, &Simulation::default()).await {
+        Err(Error::Api { status, .. }) => assert_eq!(status, 409),
+        other => panic!("expected 409, got {:?}", other),
+    }
+
+    // A delayed payment lands later.
+    let later = merchant.create_invoice(&CreateInvoice::new(5.0)).await.unwrap();
+    let delayed = Simulation { delay_secs: Some(1), ..Default::default() };
+    assert_eq!(merchant.simulate_detect(&later.invoice_id, &delayed).await.unwrap().status, "scheduled");
+    wait_for("delayed detection", Duration::from_secs(10), || async {
+        let invoice = merchant.get_invoice(&later.invoice_id).await.unwrap();
+        (invoice.status == InvoiceStatus::Detected).then_some(())
+    }).await;
+}
@@ -0,0 +1,127 @@
+//! One instance serving testnet and mainnet: merchants take the network of their UFVK, and
+//! each network is scanned by its own loop through its own CipherScan API.
+
+mod common;
+
+use std::time::Duration;
+
+use cipherpay_client::{Client, CreateInvoice, CreateMerchant, Error, InvoiceStatus};
+use common::{mount_json, orchard_tx, start_server, test_mainnet_ufvk, wait_for};
+use serde_json::json;
+use wiremock::MockServer;
+
+async fn requested(server: &MockServer, route: &str) -> bool {
+    server.received_requests().await.unwrap().iter().any(|r| r.url.path() == route)
+}
+
+#[tokio::test]
+async fn test_testnet_ufvk_rejected_for_mainnet_merchant() {
+    let server = start_server(&[("NETWORK", "mainnet")]).await;
+    let client = Client::new(&server.base_url);
+
+    let rejected = client.register_merchant(&CreateMerchant {
+        ufvk: orchard_tx::test_ufvk(17),
+        ..Default::default()
+    }).await;
+    match rejected {
+        Err(Error::Api { status, field, .. }) => assert_eq!((status, field.as_deref()), (400, Some("ufvk"))),
+        other => panic!("expected a validation error, got {:?}", other.map(|c| c.merchant_id)),
+    }
+
+    let creds = client.register_merchant(&CreateMerchant {
+        ufvk: test_mainnet_ufvk(17),
+        ..Default::default()
+    }).await.unwrap();
+    let created = client.clone().with_api_key(&creds.api_key).create_invoice(&CreateInvoice::new(10.0)).await.unwrap();
+    assert!(created.payment_address.starts_with("u1"), "{}", created.payment_address);
+}
+
+#[tokio::test]
+async fn test_webhook_url_follows_the_merchant_network() {
+    let testnet = MockServer::start().await;
+    let server = start_server(&[
+        ("NETWORK", "mainnet"),
+        ("EXTRA_NETWORKS", "testnet"),
+        ("CIPHERSCAN_API_URL_TESTNET", &testnet.uri()),
+    ]).await;
+    let client = Client::new(&server.base_url);
+    let webhook_url = Some("http://shop.example.com/hook".to_string());
+
+    // Plain HTTP is only for testnet merchants, whatever the server's primary network.
+    client.register_merchant(&CreateMerchant {
+        ufvk: orchard_tx::test_ufvk(20),
+        webhook_url: webhook_url.clone(),
+        ..Default::default()
+    }).await.unwrap();
+
+    let rejected = client.register_merchant(&CreateMerchant {
+        ufvk: test_mainnet_ufvk(20),
+        webhook_url,
+        ..Default::default()
+    }).await;
+    match rejected {
+        Err(Error::Api { status, field, .. }) => assert_eq!((status, field.as_deref()), (400, Some("webhook_url"))),
+        other => panic!("expected a validation error, got {:?}", other.map(|c| c.merchant_id)),
+    }
+}
+
+#[tokio::test]
+async fn test_each_network_scans_its_own_invoices() {
+    let mainnet = MockServer::start().await;
+    let server = start_server(&[
+        ("MEMPOOL_POLL_INTERVAL_SECS", "1"),
+        ("EXTRA_NETWORKS", "mainnet"),
+        ("CIPHERSCAN_API_URL_MAINNET", &mainnet.uri()),
+    ]).await;
+    let testnet = &server.cipherscan;
+
+    let register = |ufvk: String| {
+        let url = server.base_url.clone();
+        async move {
+            let creds = Client::new(&url).register_merchant(&CreateMerchant { ufvk, ..Default::default() }).await.unwrap();
+            Client::new(&url).with_api_key(&creds.api_key)
+        }
+    };
+    let test_merchant = register(orchard_tx::test_ufvk(18)).await;
+    let main_merchant = register(test_mainnet_ufvk(19)).await;
+    let test_invoice = test_merchant.create_invoice(&CreateInvoice::new(10.0)).await.unwrap();
+    let main_invoice = main_merchant.create_invoice(&CreateInvoice::new(10.0)).await.unwrap();
+    assert!(test_invoice.payment_address.starts_with("utest1"));
+    assert!(main_invoice.payment_address.starts_with("u1"));
+
+    let payment = |address: &str, seed| {
+        let tx = orchard_tx::transaction(&[orchard_tx::Output::to_address(address, 25_000_000, "")], seed);
+        (orchard_tx::txid(&tx), hex::encode(&tx))
+    };
+    let (test_txid, test_raw) = payment(&test_invoice.payment_address, 7);
+    let (main_txid, main_raw) = payment(&main_invoice.payment_address, 8);
+
+    // Testnet's mempool carries both payments; only its own merchant's is picked up there.
+    mount_json(testnet, "/api/blockchain-info", json!({ "blocks": 100 })).await;
+    mount_json(testnet, "/api/mempool", json!({ "transactions": [{ "txid": test_txid }, { "txid": main_txid }] })).await;
+    mount_json(testnet, &format!("/api/tx/{}/raw", test_txid), json!({ "hex": test_raw })).await;
+    mount_json(testnet, &format!("/api/tx/{}/raw", main_txid), json!({ "hex": main_raw })).await;
+    mount_json(&mainnet, "/api/blockchain-info", json!({ "blocks": 200 })).await;
+
+    wait_for("testnet payment", Duration::from_secs(15), || async {
+        let invoice = test_merchant.get_invoice(&test_invoice.invoice_id).await.unwrap();
+        (invoice.status == InvoiceStatus::Detected).then_some(())
+    }).await;
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(main_merchant.get_invoice(&main_invoice.invoice_id).await.unwrap().status, InvoiceStatus::Pending);
+
+    // Mainnet's loop asks the mainnet CipherScan API.
+    mount_json(&mainnet, "/api/mempool", json!({ "transactions": [{ "txid": main_txid }] })).await;
+    mount_json(&mainnet, &format!("/api/tx/{}/raw", main_txid), json!({ "hex": main_raw })).await;
+    wait_for("mainnet payment", Duration::from_secs(15), || async {
+        let invoice = main_merchant.get_invoice(&main_invoice.invoice_id).await.unwrap();
+        (invoice.status == InvoiceStatus::Detected).then_some(())
+    }).await;
+
+    // Each raw transaction was fetched from its own network's API only.
+    let test_route = format!("/api/tx/{}/raw", test_txid);
+    let main_route = format!("/api/tx/{}/raw", main_txid);
+    assert!(requested(&mainnet, &main_route).await);
+    assert!(!requested(&mainnet, &test_route).await);
+    assert!(requested(testnet, &test_route).await);
+}
@@ -0,0 +1,92 @@
+//! Refunds: the ZIP-321 URI a merchant pays a refund with, and registering the txid of a
+//! refund sent.
+
+mod common;
+
+use cipherpay_client::{Client, CreateInvoice, CreateMerchant, Simulation};
+use common::{orchard_tx, start_server};
+
+#[tokio::test]
+async fn test_refund_uri_pays_back_the_buyer() {
+    let server = start_server(&[]).await;
+    let creds = Client::new(&server.base_url).register_merchant(&CreateMerchant {
+        ufvk: orchard_tx::test_ufvk(12),
+        ..Default::default()
+    }).await.unwrap();
+    let merchant = Client::new(&server.base_url).with_api_key(&creds.api_key);
+    let buyer = orchard_tx::test_address(13, 0);
+
+    let http = reqwest::Client::new();
+    let refund_uri = |id: &str, query: &str| {
+        http.get(format!("{}/api/invoices/{}/refund-uri{}", server.base_url, id, query))
+            .bearer_auth(&creds.api_key)
+            .send()
+    };
+
+    // Nothing received yet, so nothing to refund.
+    let invoice = merchant.create_invoice(&CreateInvoice::new(20.0).refund_address(&buyer)).await.unwrap();
+    assert_eq!(refund_uri(&invoice.invoice_id, "").await.unwrap().status(), 400);
+
+    merchant.simulate_detect(&invoice.invoice_id, &Simulation::default()).await.unwrap();
+    merchant.simulate_confirm(&invoice.invoice_id, &Simulation::default()).await.unwrap();
+    let full: serde_json::Value = refund_uri(&invoice.invoice_id, "").await.unwrap().json().await.unwrap();
+    assert_eq!(full["refund_address"], buyer.as_str());
+    assert_eq!(full["amount_zatoshis"], 50_000_000);
+    assert_eq!(full["memo"], format!("REFUND-{}", invoice.memo_code));
+    let uri = full["zcash_uri"].as_str().unwrap();
+    assert!(uri.starts_with(&format!("zcash:{}?amount=0.50000000&memo=", buyer)), "{}", uri);
+
+    let partial: serde_json::Value = refund_uri(&invoice.invoice_id, "?amount=0.1").await.unwrap().json().await.unwrap();
+    assert_eq!(partial["amount_zatoshis"], 10_000_000);
+    assert_eq!(refund_uri(&invoice.invoice_id, "?amount=0.6").await.unwrap().status(), 400);
+    assert_eq!(refund_uri(&invoice.invoice_id, "?amount=0").await.unwrap().status(), 400);
+
+    // Without a refund address there is nowhere to send it.
+    let anonymous = merchant.create_invoice(&CreateInvoice::new(20.0)).await.unwrap();
+    merchant.simulate_detect(&anonymous.invoice_id, &Simulation::default()).await.unwrap();
+    merchant.simulate_confirm(&anonymous.invoice_id, &Simulation::default()).await.unwrap();
+    assert_eq!(refund_uri(&anonymous.invoice_id, "").await.unwrap().status(), 400);
+
+    // Another merchant's invoice is not found.
+    let other = Client::new(&server.base_url).register_merchant(&CreateMerchant {
+        ufvk: orchard_tx::test_ufvk(14),
+        ..Default::default()
+    }).await.unwrap();
+    let resp = http.get(format!("{}/api/invoices/{}/refund-uri", server.base_url, invoice.invoice_id))
+        .bearer_auth(&other.api_key)
+        .send().await.unwrap();
+    assert_eq!(resp.status(), 404);
+}
+
+#[tokio::test]
+async fn test_register_refund_txid() {
+    let server = start_server(&[]).await;
+    let creds = Client::new(&server.base_url).register_merchant(&CreateMerchant {
+        ufvk: orchard_tx::test_ufvk(15),
+        ..Default::default()
+    }).await.unwrap();
+    let merchant = Client::new(&server.base_url).with_api_key(&creds.api_key);
+    let invoice = merchant.create_invoice(&CreateInvoice::new(20.0).refund_address(orchard_tx::test_address(16, 0))).await.unwrap();
+    let refund_txid = "ab".repeat(32);
+
+    let status = |r: cipherpay_client::Result<_>| match r {
+        Err(cipherpay_client::Error::Api { status, .. }) => status,
+        other => panic!("expected an API error, got {:?}", other),
+    };
+
+    // Nothing to refund until the invoice has been paid.
+    assert_eq!(status(merchant.register_refund(&invoice.invoice_id, &refund_txid, None).await), 400);
+
+    merchant.simulate_detect(&invoice.invoice_id, &Simulation::default()).await.unwrap();
+    let paid = merchant.simulate_confirm(&invoice.invoice_id, &Simulation::default()).await.unwrap();
+
+    assert_eq!(status(merchant.register_refund(&invoice.invoice_id, "not-a-txid", None).await), 400);
+    assert_eq!(status(merchant.register_refund(&invoice.invoice_id, &refund_txid, Some(0.6)).await), 400);
+    // The buyer's own payment is not a refund.
+    assert_eq!(status(merchant.register_refund(&invoice.invoice_id, &paid.txid.unwrap(), None).await), 400);
+
+    let submitted = merchant.register_refund(&invoice.invoice_id, &refund_txid.to_uppercase(), Some(0.2)).await.unwrap();
+    assert_eq!(submitted.status, "refund_pending");
+    assert_eq!(submitted.refund_txid, refund_txid);
+    assert_eq!(submitted.refund_zec, 0.2);
+}
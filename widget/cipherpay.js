@@ -16,6 +16,7 @@
     detected: 'Payment detected! Confirming...',
     confirmed: 'Payment confirmed!',
     expired: 'Invoice expired',
+    paid_late: 'Payment received after expiry. The merchant will follow up.',
     refunded: 'Payment refunded',
   };
 
@@ -38,19 +39,61 @@
     return parseFloat(amount).toFixed(4);
   }
 
+  // Underpaid invoices show how much has arrived and what is left to send.
+  function statusLabel(status, info) {
+    if (status === 'underpaid' && info && info.remaining_zatoshis > 0) {
+      return 'Partial payment received: ' + formatZec(info.received_zatoshis / 1e8) + ' of ' +
+        formatZec(info.price_zatoshis / 1e8) + ' ZEC. Send the remaining ' +
+        formatZec(info.remaining_zatoshis / 1e8) + ' ZEC.';
+    }
+    return STATUS_LABELS[status] || status;
+  }
+
   async function fetchInvoice(apiUrl, invoiceId) {
-    var resp = await fetch(apiUrl + '/api/invoices/' + invoiceId);
+    var resp = await fetch(apiUrl + '/api/v1/invoices/' + invoiceId);
     if (!resp.ok) throw new Error('Failed to fetch invoice');
     return resp.json();
   }
 
+  // schema.org markup of the invoice, so browser wallets and crawlers can find the payment request.
+  async function embedStructuredData(apiUrl, invoiceId) {
+    var resp = await fetch(apiUrl + '/api/v1/invoices/' + invoiceId, {
+      headers: { Accept: 'application/ld+json' },
+    });
+    if (!resp.ok) return;
+    var script = document.getElementById('cipherpay-ld');
+    if (!script) {
+      script = document.createElement('script');
+      script.id = 'cipherpay-ld';
+      script.type = 'application/ld+json';
+      document.head.appendChild(script);
+    }
+    script.textContent = await resp.text();
+  }
+
   async function fetchStatus(apiUrl, invoiceId) {
-    var resp = await fetch(apiUrl + '/api/invoices/' + invoiceId + '/status');
+    var resp = await fetch(apiUrl + '/api/v1/invoices/' + invoiceId + '/status');
     if (!resp.ok) throw new Error('Failed to fetch status');
     return resp.json();
   }
 
-  function renderWidget(container, invoice) {
+  function formatFiat(invoice) {
+    // Display currency/locale chosen at checkout; the ZEC amount is the same either way.
+    if (invoice.display) return invoice.display.formatted;
+    return parseFloat(invoice.price_eur).toFixed(2) + ' EUR';
+  }
+
+  function formatTax(invoice) {
+    // The displayed total always includes tax; exclusive pricing just means it was added on top.
+    var cur = invoice.currency || 'EUR';
+    var amount = invoice.display && invoice.display.tax_formatted
+      ? invoice.display.tax_formatted
+      : parseFloat(invoice.tax.amount).toFixed(2) + ' ' + cur;
+    return 'incl. ' + amount +
+      ' tax (' + invoice.tax.rate + '%' + (invoice.tax.country ? ', ' + invoice.tax.country : '') + ')';
+  }
+
+  function renderWidget(container, invoice, apiUrl) {
     var expiresAt = new Date(invoice.expires_at);
     var now = new Date();
     var remainingSecs = Math.max(0, Math.floor((expiresAt - now) / 1000));
@@ -67,7 +110,8 @@
 
       '<div class="cipherpay-amount">' +
         '<div class="cipherpay-amount-zec">' + formatZec(invoice.price_zec) + '<span>ZEC</span></div>' +
-        '<div class="cipherpay-amount-fiat">' + parseFloat(invoice.price_eur).toFixed(2) + ' EUR</div>' +
+        '<div class="cipherpay-amount-fiat">' + formatFiat(invoice) + '</div>' +
+        (invoice.tax ? '<div class="cipherpay-amount-tax">' + formatTax(invoice) + '</div>' : '') +
       '</div>' +
 
       '<div class="cipherpay-qr" id="cipherpay-qr"></div>' +
@@ -83,13 +127,21 @@
       '</div>' +
 
       '<div class="cipherpay-status cipherpay-status-' + invoice.status + '" id="cipherpay-status">' +
-        STATUS_LABELS[invoice.status] +
+        statusLabel(invoice.status, invoice) +
       '</div>' +
 
       '<div class="cipherpay-footer">' +
         'Powered by <a href="https://cipherscan.app" target="_blank">CipherScan</a>' +
       '</div>';
 
+    if (invoice.product_image_url) {
+      var img = document.createElement('img');
+      img.className = 'cipherpay-product-image';
+      img.src = apiUrl + invoice.product_image_url;
+      img.alt = invoice.product_name || '';
+      widget.insertBefore(img, widget.querySelector('.cipherpay-amount'));
+    }
+
     container.appendChild(widget);
 
     // Copy memo on click
@@ -112,7 +164,7 @@
         remainingSecs--;
         if (remainingSecs <= 0) {
           clearInterval(timerInterval);
-          timerEl.textContent = 'Expired';
+          timerEl.textContent = invoice.on_expiry === 'requote' ? 'Updating rate…' : 'Expired';
           return;
         }
         timerEl.textContent = formatTime(remainingSecs);
@@ -122,11 +174,11 @@
     return widget;
   }
 
-  function updateStatus(widget, status) {
+  function updateStatus(widget, status, info) {
     var el = document.getElementById('cipherpay-status');
     if (!el) return;
     el.className = 'cipherpay-status cipherpay-status-' + status;
-    el.innerHTML = STATUS_LABELS[status] || status;
+    el.innerHTML = statusLabel(status, info);
   }
 
   async function init() {
@@ -145,17 +197,24 @@
 
     try {
       var invoice = await fetchInvoice(apiUrl, invoiceId);
-      var widget = renderWidget(container, invoice);
+      var widget = renderWidget(container, invoice, apiUrl);
+      embedStructuredData(apiUrl, invoiceId).catch(function () {});
 
       if (invoice.status === 'pending' || invoice.status === 'detected' || invoice.status === 'underpaid') {
         var pollInterval = setInterval(async function () {
           try {
             var statusResp = await fetchStatus(apiUrl, invoiceId);
-            if (statusResp.status !== invoice.status) {
+            if (statusResp.price_zatoshis !== invoice.price_zatoshis && statusResp.status === 'pending') {
+              // Requoted at a new rate: same memo and address, new amount and timer
+              invoice = await fetchInvoice(apiUrl, invoiceId);
+              widget = renderWidget(container, invoice, apiUrl);
+            }
+            if (statusResp.status !== invoice.status || statusResp.received_zatoshis !== invoice.received_zatoshis) {
               invoice.status = statusResp.status;
-              updateStatus(widget, statusResp.status);
+              invoice.received_zatoshis = statusResp.received_zatoshis;
+              updateStatus(widget, statusResp.status, statusResp);
 
-              if (statusResp.status === 'confirmed' || statusResp.status === 'expired') {
+              if (statusResp.status === 'confirmed' || statusResp.status === 'expired' || statusResp.status === 'paid_late') {
                 clearInterval(pollInterval);
               }
             }